@@ -0,0 +1,50 @@
+//! Plan and rewrite throughput on synthetic modules of increasing size.
+//!
+//! Run with `cargo bench --bench minify`; compare against a saved baseline with
+//! `cargo bench --bench minify -- --baseline <name>` to spot regressions.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::fmt::Write;
+use tsrs::Minifier;
+
+/// A module of `functions` functions, each with a handful of locals read several times.
+fn synthetic_module(functions: usize) -> String {
+    let mut source = String::from("import math\n\nSCALE = 3\n\n");
+    for index in 0..functions {
+        writeln!(
+            source,
+            "def compute_{index}(first_value, second_value, factor=SCALE):\n    \
+             running_total = first_value + second_value\n    \
+             scaled_total = running_total * factor\n    \
+             if scaled_total > {index}:\n        \
+             adjusted_total = math.sqrt(scaled_total) + running_total\n    \
+             else:\n        \
+             adjusted_total = scaled_total - first_value\n    \
+             for offset in range(second_value):\n        \
+             adjusted_total += offset * factor\n    \
+             return adjusted_total + running_total + scaled_total\n"
+        )
+        .unwrap();
+    }
+    source
+}
+
+fn plan_and_rewrite(c: &mut Criterion) {
+    let mut group = c.benchmark_group("plan_and_rewrite");
+    group.sample_size(10);
+    for (label, functions) in [("small", 10), ("medium", 300), ("large", 3000)] {
+        let source = synthetic_module(functions);
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_with_input(BenchmarkId::new("plan", label), &source, |b, source| {
+            b.iter(|| Minifier::plan_from_source("bench", source).unwrap());
+        });
+        let plan = Minifier::plan_from_source("bench", &source).unwrap();
+        group.bench_with_input(BenchmarkId::new("rewrite", label), &source, |b, source| {
+            b.iter(|| Minifier::rewrite_with_plan("bench", source, &plan).unwrap());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, plan_and_rewrite);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+#!/usr/bin/env python3
+"""Check that every supported cargo feature combination builds and passes its tests.
+
+The lean build (``--no-default-features``) is what embedders such as WASM tools use: it
+exposes only the minifier, its plan types, and the error type. Besides checking and
+testing each combination on the host, the script checks that the lean build compiles for
+``wasm32-unknown-unknown`` when that target is installed (``rustup target add
+wasm32-unknown-unknown``), and fails if it is not unless ``--skip-wasm`` is given.
+
+Usage:
+    python scripts/check_features.py [--skip-wasm]
+"""
+
+from __future__ import annotations
+
+import argparse
+import subprocess
+import sys
+
+# Feature flags of each combination, from leanest to the default build.
+COMBINATIONS: list[list[str]] = [
+    ["--no-default-features"],
+    ["--no-default-features", "--features", "batch"],
+    ["--no-default-features", "--features", "venv"],
+    [],
+]
+
+WASM_TARGET = "wasm32-unknown-unknown"
+
+
+def run(args: list[str]) -> None:
+    print("+ " + " ".join(args), flush=True)
+    subprocess.run(args, check=True)
+
+
+def wasm_target_installed() -> bool:
+    result = subprocess.run(
+        ["rustup", "target", "list", "--installed"],
+        capture_output=True,
+        text=True,
+        check=False,
+    )
+    return result.returncode == 0 and WASM_TARGET in result.stdout.split()
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument(
+        "--skip-wasm",
+        action="store_true",
+        help=f"do not require the {WASM_TARGET} target",
+    )
+    args = parser.parse_args()
+
+    for flags in COMBINATIONS:
+        run(["cargo", "clippy", "--all-targets", *flags, "--", "-D", "warnings"])
+        run(["cargo", "test", *flags])
+
+    if wasm_target_installed():
+        run(["cargo", "check", "--lib", "--no-default-features", "--target", WASM_TARGET])
+    elif args.skip_wasm:
+        print(f"skipping {WASM_TARGET}: target not installed")
+    else:
+        sys.exit(f"{WASM_TARGET} is not installed; run `rustup target add {WASM_TARGET}`")
+
+
+if __name__ == "__main__":
+    main()
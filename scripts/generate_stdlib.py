@@ -0,0 +1,132 @@
+#!/usr/bin/env python3
+"""Regenerate ``src/stdlib/tables.rs`` from the running interpreter.
+
+``sys.stdlib_module_names`` (Python 3.10+) lists the top-level standard library
+modules of the running interpreter on every platform. The other supported minor
+versions are reconstructed from it with the additions and removals recorded in
+``CHANGES`` below, so the script gives the same output under any 3.10+ interpreter.
+When a new Python release adds or removes modules, record them in ``CHANGES`` and
+add the version to ``VERSIONS``.
+
+Usage:
+    python scripts/generate_stdlib.py [--output PATH]
+"""
+
+from __future__ import annotations
+
+import argparse
+import sys
+from pathlib import Path
+
+VERSIONS = [(3, 8), (3, 9), (3, 10), (3, 11), (3, 12), (3, 13)]
+
+# Modules each release added and removed compared to the previous minor version.
+CHANGES: dict[tuple[int, int], tuple[set[str], set[str]]] = {
+    (3, 9): (
+        {"_aix_support", "_bootsubprocess", "_peg_parser", "_zoneinfo", "graphlib", "zoneinfo"},
+        {"_dummy_thread", "dummy_threading"},
+    ),
+    (3, 10): (
+        {"_typing"},
+        {"_bootlocale", "_peg_parser", "formatter", "parser", "symbol"},
+    ),
+    (3, 11): (
+        {"_tokenize", "tomllib"},
+        {"binhex"},
+    ),
+    (3, 12): (
+        {"_pydatetime", "_pylong", "_sha2", "_wmi"},
+        {"_sha256", "_sha512", "asynchat", "asyncore", "distutils", "imp", "smtpd"},
+    ),
+    (3, 13): (
+        {
+            "_android_support",
+            "_apple_support",
+            "_colorize",
+            "_interpchannels",
+            "_interpqueues",
+            "_interpreters",
+            "_ios_support",
+            "_opcode_metadata",
+            "_pyrepl",
+            "_suggestions",
+            "_sysconfig",
+        },
+        {
+            "_crypt",
+            "_msi",
+            "aifc",
+            "audioop",
+            "cgi",
+            "cgitb",
+            "chunk",
+            "crypt",
+            "imghdr",
+            "lib2to3",
+            "mailcap",
+            "msilib",
+            "nis",
+            "nntplib",
+            "ossaudiodev",
+            "pipes",
+            "sndhdr",
+            "spwd",
+            "sunau",
+            "telnetlib",
+            "uu",
+            "xdrlib",
+        },
+    ),
+}
+
+
+def module_sets() -> dict[tuple[int, int], set[str]]:
+    current = sys.version_info[:2]
+    if current not in VERSIONS:
+        sys.exit(f"run this script with one of Python {', '.join(map(dotted, VERSIONS))}")
+
+    sets = {current: set(sys.stdlib_module_names)}
+    index = VERSIONS.index(current)
+    for newer, older in zip(VERSIONS[index:0:-1], VERSIONS[index - 1 :: -1]):
+        added, removed = CHANGES[newer]
+        sets[older] = (sets[newer] - added) | removed
+    for older, newer in zip(VERSIONS[index:], VERSIONS[index + 1 :]):
+        added, removed = CHANGES[newer]
+        sets[newer] = (sets[older] - removed) | added
+    return sets
+
+
+def dotted(version: tuple[int, int]) -> str:
+    return f"{version[0]}.{version[1]}"
+
+
+def render(sets: dict[tuple[int, int], set[str]]) -> str:
+    lines = [
+        "//! Top-level standard library modules of each supported Python minor version",
+        "//!",
+        "//! Generated by `scripts/generate_stdlib.py`; do not edit by hand.",
+    ]
+    for version in VERSIONS:
+        major, minor = version
+        lines.append("")
+        lines.append(f"/// Python {dotted(version)}, sorted")
+        lines.append(f"pub(super) const PY{major}_{minor}: &[&str] = &[")
+        lines.extend(f'    "{name}",' for name in sorted(sets[version]))
+        lines.append("];")
+    return "\n".join(lines) + "\n"
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument(
+        "--output",
+        type=Path,
+        default=Path(__file__).resolve().parent.parent / "src" / "stdlib" / "tables.rs",
+    )
+    args = parser.parse_args()
+    args.output.parent.mkdir(parents=True, exist_ok=True)
+    args.output.write_text(render(module_sets()))
+
+
+if __name__ == "__main__":
+    main()
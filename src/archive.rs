@@ -0,0 +1,568 @@
+//! In-memory source archives: sdist tarballs and wheels
+//!
+//! Entries are read into memory in archive order so their contents can be rewritten and
+//! written back out without unpacking anything to disk.
+
+use crate::error::{IoResultExt, Result};
+use base64::Engine;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+
+/// Container format of a source archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    /// Zip files, including wheels
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Format implied by a file name: `.tar.gz`/`.tgz`, `.tar`, or `.zip`/`.whl`
+    #[must_use]
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".zip") || name.ends_with(".whl") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+
+    /// Format of the archive at `path`, by name or else by its leading magic bytes;
+    /// anything unrecognised is read as a plain tar
+    ///
+    /// # Errors
+    ///
+    /// Returns [`crate::TsrsError::Io`] if the file cannot be read.
+    pub fn detect(path: &Path) -> Result<Self> {
+        if let Some(format) = Self::from_path(path) {
+            return Ok(format);
+        }
+        let mut magic = Vec::with_capacity(ZIP_MAGIC.len());
+        File::open(path)
+            .with_path(path)?
+            .take(ZIP_MAGIC.len() as u64)
+            .read_to_end(&mut magic)
+            .with_path(path)?;
+        Ok(if magic.starts_with(&GZIP_MAGIC) {
+            ArchiveFormat::TarGz
+        } else if magic.starts_with(&ZIP_MAGIC) {
+            ArchiveFormat::Zip
+        } else {
+            ArchiveFormat::Tar
+        })
+    }
+}
+
+/// What an archive entry holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic or hard link to the given target
+    Link(String),
+    /// Any other tar entry (devices, FIFOs, pax global headers), copied as-is
+    Other,
+}
+
+/// One archive entry with its contents held in memory
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Path inside the archive, `/`-separated
+    pub path: String,
+    pub kind: EntryKind,
+    pub data: Vec<u8>,
+    /// Unix permission bits, when the archive records them
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: Option<u64>,
+    /// The original tar header, reused so ownership and entry type survive a rewrite
+    header: Option<tar::Header>,
+}
+
+impl ArchiveEntry {
+    #[must_use]
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+}
+
+/// An archive read fully into memory, entries in their original order
+#[derive(Debug, Clone)]
+pub struct SourceArchive {
+    pub format: ArchiveFormat,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+impl SourceArchive {
+    /// Read every entry of the archive at `path`
+    ///
+    /// # Errors
+    ///
+    /// Returns [`crate::TsrsError::Io`] if the file cannot be read or is not a valid
+    /// archive of `format`.
+    pub fn read(path: &Path, format: ArchiveFormat) -> Result<Self> {
+        let reader = BufReader::new(File::open(path).with_path(path)?);
+        let entries = match format {
+            ArchiveFormat::Tar => read_tar(reader),
+            ArchiveFormat::TarGz => read_tar(MultiGzDecoder::new(reader)),
+            ArchiveFormat::Zip => read_zip(reader),
+        }
+        .with_path(path)?;
+        Ok(SourceArchive { format, entries })
+    }
+
+    /// Write the entries to `path` as `format`, in order
+    ///
+    /// `mtime` replaces every entry's modification time so repeated builds produce
+    /// identical archives. Zip timestamps cannot predate 1980 and are clamped to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`crate::TsrsError::Io`] if the archive cannot be written.
+    pub fn write(&self, path: &Path, format: ArchiveFormat, mtime: Option<u64>) -> Result<()> {
+        let writer = BufWriter::new(File::create(path).with_path(path)?);
+        let mut writer = match format {
+            ArchiveFormat::Tar => write_tar(&self.entries, writer, mtime),
+            ArchiveFormat::TarGz => {
+                let encoder = GzEncoder::new(writer, Compression::default());
+                write_tar(&self.entries, encoder, mtime).and_then(GzEncoder::finish)
+            }
+            ArchiveFormat::Zip => write_zip(&self.entries, writer, mtime),
+        }
+        .with_path(path)?;
+        writer.flush().with_path(path)
+    }
+
+    /// Recompute the hash and size that a wheel's `*.dist-info/RECORD` lists for each
+    /// entry in `modified`, leaving every other row untouched
+    ///
+    /// Returns whether a `RECORD` was found.
+    pub fn refresh_wheel_record(&mut self, modified: &HashSet<String>) -> bool {
+        let Some(record_index) = self.entries.iter().position(is_wheel_record) else {
+            return false;
+        };
+        if modified.is_empty() {
+            return true;
+        }
+
+        let record = String::from_utf8_lossy(&self.entries[record_index].data).into_owned();
+        let mut refreshed = String::with_capacity(record.len());
+        for line in record.split_inclusive('\n') {
+            let row = line.trim_end_matches(['\r', '\n']);
+            let (path, field) = record_path(row);
+            let entry = modified
+                .contains(&path)
+                .then(|| self.entries.iter().find(|entry| entry.path == path))
+                .flatten();
+            match entry {
+                Some(entry) => {
+                    refreshed.push_str(&format!(
+                        "{},{},{}",
+                        field,
+                        record_hash(&entry.data),
+                        entry.data.len()
+                    ));
+                    refreshed.push_str(&line[row.len()..]);
+                }
+                None => refreshed.push_str(line),
+            }
+        }
+        self.entries[record_index].data = refreshed.into_bytes();
+        true
+    }
+}
+
+/// `RECORD` hash of `data`: `sha256=` and the URL-safe, unpadded base64 digest
+#[must_use]
+pub fn record_hash(data: &[u8]) -> String {
+    let digest = Sha256::digest(data);
+    format!(
+        "sha256={}",
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(digest)
+    )
+}
+
+fn is_wheel_record(entry: &ArchiveEntry) -> bool {
+    entry
+        .path
+        .strip_suffix("/RECORD")
+        .is_some_and(|dir| dir.ends_with(".dist-info") && !dir.contains('/'))
+}
+
+/// Split a `RECORD` row into its unquoted path and the path field as written
+pub(crate) fn record_path(row: &str) -> (String, &str) {
+    let Some(quoted) = row.strip_prefix('"') else {
+        let end = row.find(',').unwrap_or(row.len());
+        return (row[..end].to_string(), &row[..end]);
+    };
+    let mut path = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((index, c)) = chars.next() {
+        if c != '"' {
+            path.push(c);
+        } else if quoted[index + 1..].starts_with('"') {
+            path.push('"');
+            chars.next();
+        } else {
+            return (path, &row[..index + 2]);
+        }
+    }
+    (path, row)
+}
+
+fn read_tar<R: Read>(reader: R) -> std::io::Result<Vec<ArchiveEntry>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header().clone();
+        let path = entry.path()?.to_string_lossy().replace('\\', "/");
+        let entry_type = header.entry_type();
+        let kind = if entry_type.is_file() {
+            EntryKind::File
+        } else if entry_type.is_dir() {
+            EntryKind::Directory
+        } else if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let target = entry
+                .link_name()?
+                .map(|target| target.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            EntryKind::Link(target)
+        } else {
+            EntryKind::Other
+        };
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        entries.push(ArchiveEntry {
+            path,
+            kind,
+            data,
+            mode: header.mode().ok(),
+            mtime: header.mtime().ok(),
+            header: Some(header),
+        });
+    }
+    Ok(entries)
+}
+
+fn write_tar<W: Write>(
+    entries: &[ArchiveEntry],
+    writer: W,
+    mtime: Option<u64>,
+) -> std::io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for entry in entries {
+        let mut header = entry.header.clone().unwrap_or_else(|| tar_header(entry));
+        if let Some(mtime) = mtime {
+            header.set_mtime(mtime);
+        }
+        header.set_size(entry.data.len() as u64);
+        match &entry.kind {
+            EntryKind::Link(target) => builder.append_link(&mut header, &entry.path, target)?,
+            EntryKind::Other => {
+                header.set_cksum();
+                builder.append(&header, entry.data.as_slice())?;
+            }
+            EntryKind::File | EntryKind::Directory => {
+                builder.append_data(&mut header, &entry.path, entry.data.as_slice())?;
+            }
+        }
+    }
+    builder.into_inner()
+}
+
+/// Header for an entry that did not come from a tar, e.g. when a zip is written as one
+fn tar_header(entry: &ArchiveEntry) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    let (entry_type, default_mode) = match entry.kind {
+        EntryKind::Directory => (tar::EntryType::Directory, 0o755),
+        EntryKind::Link(_) => (tar::EntryType::Symlink, 0o777),
+        EntryKind::File | EntryKind::Other => (tar::EntryType::Regular, 0o644),
+    };
+    header.set_entry_type(entry_type);
+    header.set_mode(entry.mode.unwrap_or(default_mode));
+    header.set_mtime(entry.mtime.unwrap_or(0));
+    header
+}
+
+fn read_zip<R: Read + Seek>(reader: R) -> std::io::Result<Vec<ArchiveEntry>> {
+    let mut archive = zip::ZipArchive::new(reader).map_err(std::io::Error::from)?;
+    let mut entries = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(std::io::Error::from)?;
+        let kind = if file.is_dir() {
+            EntryKind::Directory
+        } else {
+            EntryKind::File
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        entries.push(ArchiveEntry {
+            path: file.name().to_string(),
+            kind,
+            data,
+            mode: file.unix_mode(),
+            mtime: file.last_modified().map(zip_to_unix),
+            header: None,
+        });
+    }
+    Ok(entries)
+}
+
+fn write_zip<W: Write + Seek>(
+    entries: &[ArchiveEntry],
+    writer: W,
+    mtime: Option<u64>,
+) -> std::io::Result<W> {
+    let mut zip = zip::ZipWriter::new(writer);
+    for entry in entries {
+        let mut options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(unix_to_zip(mtime.or(entry.mtime).unwrap_or(0)));
+        if let Some(mode) = entry.mode {
+            options = options.unix_permissions(mode);
+        }
+        if entry.kind == EntryKind::Directory {
+            zip.add_directory(entry.path.clone(), options)
+                .map_err(std::io::Error::from)?;
+        } else {
+            zip.start_file(entry.path.clone(), options)
+                .map_err(std::io::Error::from)?;
+            zip.write_all(&entry.data)?;
+        }
+    }
+    zip.finish().map_err(std::io::Error::from)
+}
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Seconds since the Unix epoch for a zip timestamp, read as UTC
+fn zip_to_unix(time: zip::DateTime) -> u64 {
+    let days = days_from_civil(
+        i64::from(time.year()),
+        u32::from(time.month()),
+        u32::from(time.day()),
+    );
+    let seconds =
+        u64::from(time.hour()) * 3600 + u64::from(time.minute()) * 60 + u64::from(time.second());
+    u64::try_from(days).unwrap_or(0) * SECONDS_PER_DAY + seconds
+}
+
+/// Zip timestamp for seconds since the Unix epoch; earlier than 1980 becomes 1980-01-01
+fn unix_to_zip(seconds: u64) -> zip::DateTime {
+    let days = i64::try_from(seconds / SECONDS_PER_DAY).unwrap_or(i64::MAX);
+    let (year, month, day) = civil_from_days(days);
+    let time = seconds % SECONDS_PER_DAY;
+    #[allow(clippy::cast_possible_truncation)]
+    let converted = u16::try_from(year).ok().and_then(|year| {
+        zip::DateTime::from_date_and_time(
+            year,
+            month as u8,
+            day as u8,
+            (time / 3600) as u8,
+            (time % 3600 / 60) as u8,
+            (time % 60) as u8,
+        )
+        .ok()
+    });
+    converted.unwrap_or_default()
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian `(year, month, day)` of a day count since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    (year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn file_entry(path: &str, data: &str, mode: u32) -> ArchiveEntry {
+        ArchiveEntry {
+            path: path.to_string(),
+            kind: EntryKind::File,
+            data: data.as_bytes().to_vec(),
+            mode: Some(mode),
+            mtime: Some(1_700_000_000),
+            header: None,
+        }
+    }
+
+    #[test]
+    fn formats_are_detected_by_name_and_magic_bytes() {
+        let tmp = TempDir::new().unwrap();
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("pkg-1.0.tar.gz")),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("pkg-1.0-py3-none-any.whl")),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(ArchiveFormat::from_path(Path::new("pkg")), None);
+
+        let archive = SourceArchive {
+            format: ArchiveFormat::Zip,
+            entries: vec![file_entry("pkg/mod.py", "x = 1\n", 0o644)],
+        };
+        let blob = tmp.path().join("blob");
+        archive.write(&blob, ArchiveFormat::TarGz, None).unwrap();
+        assert_eq!(ArchiveFormat::detect(&blob).unwrap(), ArchiveFormat::TarGz);
+        archive.write(&blob, ArchiveFormat::Zip, None).unwrap();
+        assert_eq!(ArchiveFormat::detect(&blob).unwrap(), ArchiveFormat::Zip);
+    }
+
+    #[test]
+    fn round_trips_keep_order_modes_and_fixed_mtime() {
+        let tmp = TempDir::new().unwrap();
+        let mut entries = vec![
+            file_entry("pkg-1.0/setup.py", "setup()\n", 0o644),
+            file_entry("pkg-1.0/bin/run", "#!/bin/sh\n", 0o755),
+            file_entry("pkg-1.0/a.py", "a = 1\n", 0o600),
+        ];
+        entries.insert(
+            1,
+            ArchiveEntry {
+                path: "pkg-1.0/bin/".to_string(),
+                kind: EntryKind::Directory,
+                data: Vec::new(),
+                mode: Some(0o755),
+                mtime: Some(1_700_000_000),
+                header: None,
+            },
+        );
+        let archive = SourceArchive {
+            format: ArchiveFormat::TarGz,
+            entries,
+        };
+
+        for format in [ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+            let path = tmp.path().join(format!("out-{format:?}"));
+            archive.write(&path, format, Some(1_600_000_000)).unwrap();
+            let read = SourceArchive::read(&path, format).unwrap();
+            let files: Vec<(&str, Option<u32>, Option<u64>)> = read
+                .entries
+                .iter()
+                .filter(|entry| entry.is_file())
+                .map(|entry| {
+                    (
+                        entry.path.as_str(),
+                        entry.mode.map(|m| m & 0o777),
+                        entry.mtime,
+                    )
+                })
+                .collect();
+            assert_eq!(
+                files,
+                vec![
+                    ("pkg-1.0/setup.py", Some(0o644), Some(1_600_000_000)),
+                    ("pkg-1.0/bin/run", Some(0o755), Some(1_600_000_000)),
+                    ("pkg-1.0/a.py", Some(0o600), Some(1_600_000_000)),
+                ],
+                "{format:?}"
+            );
+            assert_eq!(read.entries[3].data, b"a = 1\n");
+        }
+    }
+
+    #[test]
+    fn wheel_record_rows_are_refreshed_for_modified_entries() {
+        let record = "pkg/__init__.py,sha256=old,3\r\n\"pkg/odd,name.py\",sha256=old,5\r\npkg-1.0.dist-info/RECORD,,\r\n";
+        let mut archive = SourceArchive {
+            format: ArchiveFormat::Zip,
+            entries: vec![
+                file_entry(
+                    "pkg/__init__.py",
+                    "def f(value):\n    return value\n",
+                    0o644,
+                ),
+                file_entry("pkg/odd,name.py", "y = 2\n", 0o644),
+                file_entry("pkg-1.0.dist-info/RECORD", record, 0o644),
+            ],
+        };
+
+        let modified: HashSet<String> = ["pkg/__init__.py", "pkg/odd,name.py"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(archive.refresh_wheel_record(&modified));
+
+        let refreshed = String::from_utf8(archive.entries[2].data.clone()).unwrap();
+        let init = "def f(value):\n    return value\n";
+        assert_eq!(
+            refreshed,
+            format!(
+                "pkg/__init__.py,{},{}\r\n\"pkg/odd,name.py\",{},6\r\npkg-1.0.dist-info/RECORD,,\r\n",
+                record_hash(init.as_bytes()),
+                init.len(),
+                record_hash(b"y = 2\n"),
+            )
+        );
+        // The digest of empty input is well known
+        assert_eq!(
+            record_hash(b""),
+            "sha256=47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU"
+        );
+
+        archive.entries.pop();
+        assert!(!archive.refresh_wheel_record(&modified));
+    }
+
+    #[test]
+    fn zip_timestamps_convert_through_the_civil_calendar() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+
+        let time = unix_to_zip(1_700_000_000);
+        assert_eq!(
+            (time.year(), time.month(), time.day(), time.hour()),
+            (2023, 11, 14, 22)
+        );
+        assert_eq!(zip_to_unix(time), 1_700_000_000);
+        assert_eq!(unix_to_zip(0), zip::DateTime::default());
+    }
+}
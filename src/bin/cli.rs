@@ -17,28 +17,58 @@
 use anyhow::{bail, Context};
 use clap::{ArgAction, Parser, Subcommand};
 use dunce::canonicalize as dunce_canonicalize;
-use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
-use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
-use ignore::WalkBuilder;
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use notify::{RecursiveMode, Watcher};
 use num_cpus;
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
 use serde::{Deserialize, Serialize};
 use similar::TextDiff;
 use std::borrow::Cow;
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::ffi::OsString;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::filter::EnvFilter;
-use tsrs::{CallGraphAnalyzer, Minifier, MinifyPlan, VenvAnalyzer, VenvSlimmer};
+use tsrs::fswalk::{
+    case_folded_collisions, default_extensions, derive_module_name, normalize_rel_path,
+    portable_path, resolve_extensions, strip_module_root,
+};
+use tsrs::imports::{is_stdlib_module, DetailedImport};
+use tsrs::pipeline::{
+    merge_stage_stats, NameUsageIndex, RemoveDeadAssignments, RemoveUnusedImports,
+};
+use tsrs::sourcemap::SOURCE_MAP_SCHEMA;
+use tsrs::stdlib;
+use tsrs::textio::{self, encode_python, TextMetadata};
+use tsrs::{
+    ArchiveFormat, CallGraphAnalyzer, Candidate, CandidateFilter, CandidateWalker, Config,
+    DuplicateCandidate, EnvironmentKind, FileSourceMap, FunctionFilter, ImportCollector,
+    ImportCycle, ImportGraph, LevelSettings, Minifier, MinifyFunctionPlan, MinifyLevel, MinifyPlan,
+    ModuleImports, NameCollision, NamingConfig, NamingMode, PackageCallGraph, PackageDecision,
+    PackageMetadata, PackagePolicy, PipelineOutput, PlanLimit, PlanLimits, PlanOptions,
+    PlanSummary, PythonVersion, RenameEntry, RewriteOptions, RewriteOutcome, RootReason,
+    SlimDecision, SlimFormat, SlimOptions, SourceArchive, SourceMap, StageStats, SyntaxIssue,
+    Transform, TransformContext, TransformPipeline, TsrsError, VenvAnalyzer, VenvSlimmer,
+    WalkPatterns,
+};
 use walkdir;
 
-const DEFAULT_EXCLUDES: &[&str] = &["**/.git/**", "**/__pycache__/**", "**/.venv/**"];
+/// Log a message about the file being processed; see [`record_file_log`].
+macro_rules! file_log {
+    ($level:ident, $($arg:tt)+) => {
+        record_file_log(tracing::Level::$level, format!($($arg)+))
+    };
+}
 
 #[derive(Parser)]
 #[command(name = "tsrs")]
@@ -54,6 +84,74 @@ struct Cli {
     /// Increase logging verbosity (-v, -vv)
     #[arg(global = true, short = 'v', long = "verbose", action = ArgAction::Count)]
     verbose: u8,
+
+    /// Decode every Python input with this encoding, ignoring BOMs and coding cookies
+    #[arg(global = true, long, value_name = "LABEL")]
+    encoding: Option<String>,
+
+    /// On bytes invalid in the file's encoding, fail the file (strict) or replace them
+    /// with U+FFFD and write the output as UTF-8 (lossy)
+    #[arg(
+        global = true,
+        long,
+        value_name = "MODE",
+        value_parser = ["strict", "lossy"],
+        default_value = "strict"
+    )]
+    decode: String,
+
+    /// When planning, leave locals shorter than N characters unrenamed (default: 1,
+    /// rename every local)
+    #[arg(global = true, long, value_name = "N", default_value_t = 1)]
+    min_name_length: usize,
+
+    /// When planning, leave locals that are bound once and read once unrenamed
+    #[arg(global = true, long)]
+    skip_single_use: bool,
+
+    /// When planning, rename locals such as `T = TypeVar("T")` together with the string
+    /// naming them instead of leaving them unrenamed
+    #[arg(global = true, long)]
+    rename_typevars: bool,
+
+    /// When planning, start every generated name with this prefix (e.g. `_t` for `_ta`,
+    /// `_tb`, ...)
+    #[arg(global = true, long, value_name = "STR")]
+    rename_prefix: Option<String>,
+
+    /// When planning, spell generated names with these characters, in counting order
+    /// (default: a-z)
+    #[arg(global = true, long, value_name = "CHARS")]
+    rename_alphabet: Option<String>,
+
+    /// When planning, only plan functions whose module-qualified name matches one of
+    /// these globs (e.g. `*.Model.*`, `mypkg.utils.*`)
+    #[arg(global = true, long, value_name = "GLOB")]
+    include_function: Vec<String>,
+
+    /// When planning, never plan functions whose module-qualified name matches one of
+    /// these globs (e.g. `*.__*__`)
+    #[arg(global = true, long, value_name = "GLOB")]
+    exclude_function: Vec<String>,
+
+    /// When planning, skip files whose plan would rename more than N locals, stopping as
+    /// soon as the count is passed (default: unlimited)
+    #[arg(global = true, long, value_name = "N")]
+    max_renames_per_file: Option<usize>,
+
+    /// When planning, skip files with more than N functions to plan, stopping as soon as
+    /// the count is passed (default: unlimited)
+    #[arg(global = true, long, value_name = "N")]
+    max_plan_functions: Option<usize>,
+
+    /// When rewriting, add a `# tsrs: a=original, ...` comment after each renamed
+    /// function's `def` line (or its docstring) listing the renames applied there
+    #[arg(global = true, long)]
+    annotate_renames: bool,
+
+    /// When rewriting with `--annotate-renames`, wrap the comments at this many columns
+    #[arg(global = true, long, value_name = "N", default_value_t = 88)]
+    annotate_width: usize,
 }
 
 #[derive(Subcommand)]
@@ -63,28 +161,257 @@ enum Commands {
         /// Path to the virtual environment
         #[arg(value_name = "VENV_PATH")]
         venv_path: PathBuf,
+
+        /// Print the analysis, including each distribution's license and project
+        /// metadata, as JSON
+        #[arg(long)]
+        json: bool,
     },
 
     /// Create a slim version of a virtual environment based on code imports
     Slim {
-        /// Path to the Python code directory to analyze
-        #[arg(value_name = "PYTHON_DIRECTORY")]
-        code_path: PathBuf,
+        /// Python code directories to analyze (imports from all of them are kept)
+        #[arg(value_name = "PYTHON_DIRECTORY", required = true, num_args = 1..)]
+        code_paths: Vec<PathBuf>,
 
         /// Path to the source virtual environment
         #[arg(value_name = "VENV_PATH")]
         venv_path: PathBuf,
 
-        /// Path for the output slim venv (default: .venv-slim)
+        /// Path for the output slim venv (default: .venv-slim, or slim.pyz with
+        /// --format zipapp)
         #[arg(short, long, value_name = "OUTPUT_PATH")]
         output: Option<PathBuf>,
+
+        /// Write the slim report (kept packages and the roots importing them) as JSON
+        #[arg(long, value_name = "JSON_FILE")]
+        report: Option<PathBuf>,
+
+        /// Byte-compile kept packages with the venv's own interpreter
+        #[arg(long)]
+        compile_bytecode: bool,
+
+        /// Replace .py files that have compiled bytecode with sourceless .pyc files
+        #[arg(long, requires = "compile_bytecode")]
+        strip_sources: bool,
+
+        /// Never strip sources matching this glob (package name or path in site-packages)
+        #[arg(long, value_name = "GLOB", requires = "strip_sources")]
+        strip_sources_except: Vec<String>,
+
+        /// Leave code files matching this glob (relative path or file name) out of the
+        /// import scan, e.g. "tests/**" or "conftest.py" (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        code_exclude: Vec<String>,
+
+        /// Rewrite script shebangs to `/usr/bin/env python3` so the venv can be moved
+        #[arg(long)]
+        relocatable: bool,
+
+        /// Write a venv directory or a single .pyz zipapp of the kept packages and code
+        #[arg(long, value_name = "FORMAT", value_parser = ["venv", "zipapp"], default_value = "venv")]
+        format: String,
+
+        /// Zipapp entry point (default: the code directory's __main__.py)
+        #[arg(long, value_name = "MODULE:FUNCTION")]
+        main: Option<String>,
+
+        /// Keep packages matching this name or glob even when nothing imports them
+        /// (repeatable; case-insensitive, with `-`, `_` and `.` treated alike)
+        #[arg(long, value_name = "NAME_OR_GLOB")]
+        keep_package: Vec<String>,
+
+        /// Leave out packages matching this name or glob even when imported; wins over
+        /// --keep-package (repeatable)
+        #[arg(long, value_name = "NAME_OR_GLOB")]
+        drop_package: Vec<String>,
+
+        /// TOML file with `keep = [...]` and `drop = [...]` package lists, added to the
+        /// --keep-package and --drop-package flags
+        #[arg(long, value_name = "TOML")]
+        policy_file: Option<PathBuf>,
+
+        /// Treat imports of this top-level module as part of the code, never a venv
+        /// package (repeatable; packages and modules under each code directory or its
+        /// src/ are detected already)
+        #[arg(long, value_name = "NAME")]
+        first_party: Vec<String>,
+
+        /// Keep packages imported only under `if TYPE_CHECKING:`
+        #[arg(long)]
+        keep_type_checking_imports: bool,
+
+        /// Python version whose standard library imports are never looked up in the venv
+        /// (default: the venv's own version)
+        #[arg(long, value_name = "X.Y")]
+        python_version: Option<String>,
+
+        /// Limit parallel workers when scanning code for imports
+        #[arg(long, value_name = "N")]
+        jobs: Option<usize>,
+
+        /// Import every kept top-level module with the slim venv's interpreter and fail
+        /// if any import raises
+        #[arg(long)]
+        verify_imports: bool,
+
+        /// Leave this top-level module out of --verify-imports (repeatable)
+        #[arg(long, value_name = "MODULE", requires = "verify_imports")]
+        verify_skip: Vec<String>,
+
+        /// Abort before writing anything if a kept distribution's license matches this
+        /// SPDX id or license name, e.g. GPL-3.0 (repeatable or comma-separated)
+        #[arg(long, value_name = "LICENSE", value_delimiter = ',')]
+        fail_on_license: Vec<String>,
     },
 
-    /// Print a planned rename map for locals in a Python file
-    MinifyPlan {
+    /// List the packages imported by the Python files in a directory tree
+    Imports {
+        /// Directory containing Python sources to scan
+        #[arg(value_name = "INPUT_DIR")]
+        input_dir: PathBuf,
+
+        /// Print per-file import detail (aliases, relative, deferred, TYPE_CHECKING) as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Leave standard library modules out of the listing
+        #[arg(long)]
+        third_party_only: bool,
+
+        /// Virtual environment to check imports against; packages it provides are marked
+        /// as installed and never counted as standard library
+        #[arg(long, value_name = "VENV_PATH")]
+        venv: Option<PathBuf>,
+
+        /// Python version whose standard library decides which imports are stdlib
+        /// (default: the --venv's own version, otherwise any of 3.8 through 3.13)
+        #[arg(long, value_name = "X.Y")]
+        python_version: Option<String>,
+
+        /// Glob pattern to include (repeatable). Defaults to "**/*.py"
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// File containing newline-delimited include globs
+        #[arg(long, value_name = "FILE")]
+        include_file: Option<PathBuf>,
+
+        /// Glob pattern to exclude (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// File containing newline-delimited exclude globs
+        #[arg(long, value_name = "FILE")]
+        exclude_file: Option<PathBuf>,
+
+        /// Include hidden files and directories
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        include_hidden: Option<bool>,
+
+        /// Follow symlinks when traversing directories
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        follow_symlinks: Option<bool>,
+
+        /// Force case-insensitive glob matching (defaults to on for Windows)
+        #[arg(long, value_name = "BOOL")]
+        glob_case_insensitive: Option<bool>,
+
+        /// Maximum directory depth to traverse (root depth = 1)
+        #[arg(long, value_name = "N")]
+        max_depth: Option<usize>,
+
+        /// Respect .gitignore files when scanning
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        respect_gitignore: Option<bool>,
+
+        /// Source file extensions to process, comma-separated (default: py)
+        #[arg(long, value_name = "EXT", value_delimiter = ',')]
+        extensions: Vec<String>,
+
+        /// Do not load exclude patterns from .tsrsignore files
+        #[arg(long)]
+        no_tsrsignore: bool,
+    },
+
+    /// Print the import graph between the modules of a directory and the import cycles
+    /// in it
+    ImportGraph {
+        /// Directory containing the Python sources; if it has an `__init__.py`, module
+        /// names start with its name
+        #[arg(value_name = "INPUT_DIR")]
+        input_dir: PathBuf,
+
+        /// Output format: a summary with the cycles, Graphviz DOT with cycle edges in red,
+        /// or JSON
+        #[arg(long, value_name = "FORMAT", value_parser = ["text", "dot", "json"], default_value = "text")]
+        format: String,
+
+        /// Exit with status 1 when the graph has an import cycle
+        #[arg(long)]
+        fail_on_cycles: bool,
+
+        /// Only show the modules MODULE imports, directly or through others
+        #[arg(long, value_name = "MODULE")]
+        max_depth_from: Option<String>,
+
+        /// Also count imports inside functions and under `if TYPE_CHECKING:`, which do not
+        /// run when the module is imported
+        #[arg(long)]
+        include_deferred: bool,
+
+        /// Glob pattern to include (repeatable). Defaults to "**/*.py"
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// Glob pattern to exclude (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+    },
+
+    /// Print the calls between the functions and methods of a Python file
+    CallGraph {
         /// Path to the Python source file
         #[arg(value_name = "PYTHON_FILE")]
         python_file: PathBuf,
+
+        /// Print the nodes and edges, with each call site, as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Print a planned rename map for locals in a Python file
+    MinifyPlan {
+        /// Path to the Python source file
+        #[arg(value_name = "PYTHON_FILE", required_unless_present = "stdin")]
+        python_file: Option<PathBuf>,
+
+        /// Read Python source from stdin instead of a file
+        #[arg(long, conflicts_with = "python_file")]
+        stdin: bool,
+
+        /// Module name recorded in the plan (default: the file stem, or "stdin")
+        #[arg(long, value_name = "NAME")]
+        module_name: Option<String>,
+
+        /// Print the source followed by the plan JSON, ready for
+        /// `apply-plan --stdin --plan-stdin`
+        #[arg(long)]
+        combined: bool,
+
+        /// Derive short names from a hash of the function and original name
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        stable_names: Option<bool>,
+
+        /// Also shorten `_`-prefixed module-level functions, classes, and constants that
+        /// are never named in `__all__` or in a string
+        #[arg(long)]
+        rename_module_privates: bool,
+
+        /// Never rename locals that are the target of an annotated assignment, keeping
+        /// them stable for runtime annotation inspection
+        #[arg(long)]
+        preserve_annotated_locals: bool,
     },
 
     /// Generate rename plans for every Python file in a directory tree
@@ -93,7 +420,8 @@ enum Commands {
         #[arg(value_name = "INPUT_DIR")]
         input_dir: PathBuf,
 
-        /// Path where the plan bundle JSON should be written
+        /// Path where the plan bundle JSON (or, with `--out-format dir`, the plan
+        /// directory) should be written
         #[arg(long, value_name = "PLAN_FILE")]
         out: PathBuf,
 
@@ -117,13 +445,18 @@ enum Commands {
         #[arg(long, value_name = "N")]
         jobs: Option<usize>,
 
+        /// Lower the worker count so the largest files in flight fit in this many
+        /// megabytes, assuming each takes about 4x its size
+        #[arg(long, value_name = "MB")]
+        max_memory: Option<u64>,
+
         /// Include hidden files and directories
-        #[arg(long)]
-        include_hidden: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        include_hidden: Option<bool>,
 
         /// Follow symlinks when traversing directories
-        #[arg(long)]
-        follow_symlinks: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        follow_symlinks: Option<bool>,
 
         /// Force case-insensitive glob matching (defaults to on for Windows)
         #[arg(long, value_name = "BOOL")]
@@ -134,8 +467,112 @@ enum Commands {
         max_depth: Option<usize>,
 
         /// Respect .gitignore files when scanning
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        respect_gitignore: Option<bool>,
+
+        /// Source file extensions to process, comma-separated (default: py)
+        #[arg(long, value_name = "EXT", value_delimiter = ',')]
+        extensions: Vec<String>,
+
+        /// Do not load exclude patterns from .tsrsignore files
         #[arg(long)]
-        respect_gitignore: bool,
+        no_tsrsignore: bool,
+
+        /// Do not apply the built-in excludes (.git, __pycache__, .venv)
+        #[arg(long)]
+        no_default_excludes: bool,
+
+        /// Directory, relative to the input, that module names start from (repeatable);
+        /// each file is named from the deepest root containing it
+        #[arg(long, value_name = "DIR")]
+        module_root: Vec<PathBuf>,
+
+        /// Only process files git reports changed between REF and the working tree
+        /// (untracked files included, deletions ignored)
+        #[arg(long, value_name = "REF")]
+        changed_since: Option<String>,
+
+        /// With --changed-since, process every file instead of failing when the input
+        /// is not inside a git work tree
+        #[arg(long, requires = "changed_since")]
+        changed_since_optional: bool,
+
+        /// Derive short names from a hash of the function and original name
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        stable_names: Option<bool>,
+
+        /// Also shorten `_`-prefixed module-level functions, classes, and constants that
+        /// are never named in `__all__` or in a string
+        #[arg(long)]
+        rename_module_privates: bool,
+
+        /// Never rename locals that are the target of an annotated assignment, keeping
+        /// them stable for runtime annotation inspection
+        #[arg(long)]
+        preserve_annotated_locals: bool,
+
+        /// Write one bundle file, or a directory with `<rel_path>.plan.json` per planned
+        /// file mirroring the input layout
+        #[arg(long, value_name = "FORMAT", value_parser = ["bundle", "dir"], default_value = "bundle")]
+        out_format: String,
+
+        /// Write one JSON object per line to stderr as the run progresses, as described
+        /// by `schema progress-events`
+        #[arg(long)]
+        progress_json: bool,
+
+        /// With --progress-json, write the events to FILE instead of stderr
+        #[arg(long, value_name = "FILE", requires = "progress_json")]
+        progress_file: Option<PathBuf>,
+
+        /// Exit with a non-zero status if any file could not be read or planned
+        #[arg(long)]
+        fail_on_error: bool,
+
+        /// Also write the files that could not be read or planned to FILE, as a JSON array
+        /// of `{path, kind, message}`
+        #[arg(long, value_name = "FILE")]
+        errors_out: Option<PathBuf>,
+    },
+
+    /// Estimate the bytes minify-dir would save in a directory tree, without writing anything
+    SizeAudit {
+        /// Directory containing Python sources to audit
+        #[arg(value_name = "INPUT_DIR")]
+        input_dir: PathBuf,
+
+        /// Glob pattern to include (repeatable). Defaults to "**/*.py"
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// Glob pattern to exclude (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Limit parallel workers when planning
+        #[arg(long, value_name = "N")]
+        jobs: Option<usize>,
+
+        /// Maximum directory depth to traverse (root depth = 1)
+        #[arg(long, value_name = "N")]
+        max_depth: Option<usize>,
+
+        /// Source file extensions to process, comma-separated (default: py)
+        #[arg(long, value_name = "EXT", value_delimiter = ',')]
+        extensions: Vec<String>,
+
+        /// Derive short names from a hash of the function and original name
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        stable_names: Option<bool>,
+
+        /// Also count shortening `_`-prefixed module-level names, as with
+        /// `minify-dir --rename-module-privates`
+        #[arg(long)]
+        rename_module_privates: bool,
+
+        /// Print the report as JSON instead of a table
+        #[arg(long)]
+        json: bool,
     },
 
     /// Apply a precomputed rename plan to a Python file
@@ -196,6 +633,18 @@ enum Commands {
         #[arg(long, value_name = "N", default_value_t = 3)]
         diff_context: usize,
 
+        /// Write the diff to FILE (.diff/.patch), or into DIR as <name>.patch
+        #[arg(long, value_name = "DIR|FILE")]
+        diff_out: Option<PathBuf>,
+
+        /// Re-parse rewritten output and fail the file instead of writing invalid Python
+        #[arg(long)]
+        verify: bool,
+
+        /// Also byte-compile rewritten output with PYTHON (`PYTHON -m py_compile`); implies --verify
+        #[arg(long, value_name = "PYTHON")]
+        verify_exec: Option<PathBuf>,
+
         /// Read Python source from stdin instead of a file
         #[arg(long, conflicts_with_all = ["in_place", "backup_ext"])]
         stdin: bool,
@@ -203,6 +652,16 @@ enum Commands {
         /// Write rewritten source to stdout regardless of quiet mode
         #[arg(long)]
         stdout: bool,
+
+        /// Warn about and skip plan renames that fail validation instead of failing the run
+        #[arg(long)]
+        allow_partial_plan: bool,
+
+        /// When a function's recorded range no longer lines up with its definition (e.g.
+        /// a line was added above it after planning), find the function by qualified name
+        /// and apply its renames there instead of bailing out
+        #[arg(long)]
+        fuzzy_apply: bool,
     },
 
     /// Apply precomputed rename plans to every file in a directory tree
@@ -211,10 +670,16 @@ enum Commands {
         #[arg(value_name = "INPUT_DIR")]
         input_dir: PathBuf,
 
-        /// Path to the JSON plan bundle produced by `minify-plan-dir`
+        /// Path to the JSON plan bundle produced by `minify-plan-dir`, or to a plan
+        /// directory written with `--out-format dir`
         #[arg(long, value_name = "PLAN_FILE")]
         plan: PathBuf,
 
+        /// Only report totals for the bundle (files, renames, bailout flags, planned paths
+        /// present under INPUT_DIR) without reading or rewriting any source
+        #[arg(long, conflicts_with_all = ["out_dir", "in_place", "backup_ext"])]
+        summary_only: bool,
+
         /// Directory where rewritten files should be written
         #[arg(long, value_name = "OUTPUT_DIR")]
         out_dir: Option<PathBuf>,
@@ -231,6 +696,11 @@ enum Commands {
         #[arg(long, value_name = "EXT")]
         backup_ext: Option<String>,
 
+        /// Write over files that changed on disk after they were read (with --in-place);
+        /// by default they are left alone and reported as changed_during_run
+        #[arg(long)]
+        overwrite_changed: bool,
+
         /// Glob pattern to include (repeatable). Defaults to "**/*.py"
         #[arg(long, value_name = "GLOB")]
         include: Vec<String>,
@@ -248,12 +718,30 @@ enum Commands {
         exclude_file: Option<PathBuf>,
 
         /// Print per-file rename counts and totals in the summary
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        stats: Option<bool>,
+
+        /// Also total files, renames, and bytes saved per leading directory, grouped by
+        /// the first DEPTH path components (requires --stats)
+        #[arg(long, value_name = "DEPTH", num_args = 0..=1, require_equals = true, default_missing_value = "1")]
+        summary_by_dir: Option<usize>,
+
+        /// Also list the N files with the largest byte savings (requires --stats)
+        #[arg(long, value_name = "N")]
+        top: Option<usize>,
+
+        /// If another run holds the output directory's .tsrs.lock, poll for up to SECS
+        /// seconds for it to finish instead of failing immediately
+        #[arg(long, value_name = "SECS")]
+        wait: Option<u64>,
+
+        /// Take over a .tsrs.lock left behind by a run that died or started over an hour ago
         #[arg(long)]
-        stats: bool,
+        force_lock: bool,
 
         /// Emit stats summary as JSON (requires --stats)
-        #[arg(long)]
-        json: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        json: Option<bool>,
 
         /// Write stats summary to a JSON file
         #[arg(long, value_name = "JSON_FILE")]
@@ -263,6 +751,11 @@ enum Commands {
         #[arg(long, value_name = "N")]
         jobs: Option<usize>,
 
+        /// Lower the worker count so the largest files in flight fit in this many
+        /// megabytes, assuming each takes about 4x its size
+        #[arg(long, value_name = "MB")]
+        max_memory: Option<u64>,
+
         /// Exit with a non-zero status if any bailouts occur
         #[arg(long)]
         fail_on_bailout: bool,
@@ -275,21 +768,60 @@ enum Commands {
         #[arg(long)]
         fail_on_change: bool,
 
+        /// Exit with a non-zero status if any plan bundle entry matches no file on disk
+        #[arg(long)]
+        require_all_plans: bool,
+
+        /// How bundle entries find their files: by relative path, or also by module name
+        /// so files moved since planning still get their plan (exact paths win)
+        #[arg(long, value_name = "MODE", value_parser = ["path", "module"], default_value = "path")]
+        match_by: String,
+
         /// Show unified diffs for rewritten files
         #[arg(long)]
         diff: bool,
 
         /// Number of context lines to include in diffs (default: 3)
-        #[arg(long, value_name = "N", default_value_t = 3)]
-        diff_context: usize,
+        #[arg(long, value_name = "N")]
+        diff_context: Option<usize>,
+
+        /// Write diffs to FILE as one multi-file patch (.diff/.patch), or into DIR as one
+        /// <name>.patch per rewritten file
+        #[arg(long, value_name = "DIR|FILE")]
+        diff_out: Option<PathBuf>,
+
+        /// Write a manifest of the applied renames into the output tree: one
+        /// _tsrs_manifest file at its root, or one per top-level package directory
+        #[arg(
+            long,
+            value_name = "LAYOUT",
+            value_parser = ["root", "per-package"],
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "root"
+        )]
+        embed_manifest: Option<String>,
+
+        /// Write the --embed-manifest manifest as JSON, or as a Python module defining a
+        /// MANIFEST dict
+        #[arg(long, value_name = "FORMAT", value_parser = ["json", "py"], default_value = "json")]
+        manifest_format: String,
+
+        /// Re-parse rewritten output and fail the file instead of writing invalid Python
+        #[arg(long)]
+        verify: bool,
+
+        /// Also byte-compile rewritten output with PYTHON (`PYTHON -m py_compile`); implies --verify
+        #[arg(long, value_name = "PYTHON")]
+        verify_exec: Option<PathBuf>,
 
         /// Include hidden files and directories
-        #[arg(long)]
-        include_hidden: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        include_hidden: Option<bool>,
 
         /// Follow symlinks when traversing directories
-        #[arg(long)]
-        follow_symlinks: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        follow_symlinks: Option<bool>,
 
         /// Force case-insensitive glob matching (defaults to on for Windows)
         #[arg(long, value_name = "BOOL")]
@@ -300,8 +832,88 @@ enum Commands {
         max_depth: Option<usize>,
 
         /// Respect .gitignore files when scanning
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        respect_gitignore: Option<bool>,
+
+        /// Source file extensions to process, comma-separated (default: py)
+        #[arg(long, value_name = "EXT", value_delimiter = ',')]
+        extensions: Vec<String>,
+
+        /// Do not load exclude patterns from .tsrsignore files
         #[arg(long)]
-        respect_gitignore: bool,
+        no_tsrsignore: bool,
+
+        /// Do not apply the built-in excludes (.git, __pycache__, .venv)
+        #[arg(long)]
+        no_default_excludes: bool,
+
+        /// Directory, relative to the input, that module names start from (repeatable);
+        /// each file is named from the deepest root containing it
+        #[arg(long, value_name = "DIR")]
+        module_root: Vec<PathBuf>,
+
+        /// Skip files larger than BYTES; out-dir runs copy them through unchanged
+        #[arg(long, value_name = "BYTES")]
+        max_file_size: Option<u64>,
+
+        /// Warn about and skip plan renames that fail validation instead of failing the file
+        #[arg(long)]
+        allow_partial_plan: bool,
+
+        /// When a function's recorded range no longer lines up with its definition (e.g.
+        /// a line was added above it after planning), find the function by qualified name
+        /// and apply its renames there instead of bailing out
+        #[arg(long)]
+        fuzzy_apply: bool,
+
+        /// Print the peak memory usage of the run to stderr
+        #[arg(long)]
+        report_memory: bool,
+
+        /// Time the walk, plan+rewrite, and write phases and each file, adding them to the
+        /// summary and --output-json along with the slowest files
+        #[arg(long)]
+        timings: bool,
+
+        /// Write one JSON object per line to stderr as the run progresses, as described
+        /// by `schema progress-events`
+        #[arg(long)]
+        progress_json: bool,
+
+        /// With --progress-json, write the events to FILE instead of stderr
+        #[arg(long, value_name = "FILE", requires = "progress_json")]
+        progress_file: Option<PathBuf>,
+    },
+
+    /// Print a readable summary of a plan bundle: each function's renames, exclusions,
+    /// and bailout flags
+    PlanShow {
+        /// Path to the plan bundle produced by `minify-plan-dir`
+        #[arg(long, value_name = "PLAN_FILE")]
+        plan: PathBuf,
+
+        /// Only show the entry for this bundle path (relative to the planned directory)
+        #[arg(long, value_name = "REL_PATH")]
+        file: Option<String>,
+    },
+
+    /// Write a copy of a plan bundle with selected functions or renames removed
+    PlanFilter {
+        /// Path to the plan bundle produced by `minify-plan-dir`
+        #[arg(long, value_name = "PLAN_FILE")]
+        plan: PathBuf,
+
+        /// Path where the filtered bundle should be written
+        #[arg(long, value_name = "PLAN_FILE")]
+        out: PathBuf,
+
+        /// Drop the plan for the function with this qualified name (repeatable)
+        #[arg(long, value_name = "QUALIFIED_NAME")]
+        drop_function: Vec<String>,
+
+        /// Keep NAME unrenamed in the function FUNC (repeatable)
+        #[arg(long, value_name = "FUNC:NAME")]
+        drop_rename: Vec<String>,
     },
 
     /// Rewrite a Python file using safe local renames
@@ -323,12 +935,12 @@ enum Commands {
         backup_ext: Option<String>,
 
         /// Print rename statistics for the file
-        #[arg(long)]
-        stats: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        stats: Option<bool>,
 
         /// Emit rename statistics in JSON format (requires --stats)
-        #[arg(long)]
-        json: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        json: Option<bool>,
 
         /// Write stats summary to a JSON file
         #[arg(long, value_name = "JSON_FILE")]
@@ -351,8 +963,20 @@ enum Commands {
         diff: bool,
 
         /// Number of context lines to include in diffs (default: 3)
-        #[arg(long, value_name = "N", default_value_t = 3)]
-        diff_context: usize,
+        #[arg(long, value_name = "N")]
+        diff_context: Option<usize>,
+
+        /// Write the diff to FILE (.diff/.patch), or into DIR as <name>.patch
+        #[arg(long, value_name = "DIR|FILE")]
+        diff_out: Option<PathBuf>,
+
+        /// Re-parse rewritten output and fail the file instead of writing invalid Python
+        #[arg(long)]
+        verify: bool,
+
+        /// Also byte-compile rewritten output with PYTHON (`PYTHON -m py_compile`); implies --verify
+        #[arg(long, value_name = "PYTHON")]
+        verify_exec: Option<PathBuf>,
 
         /// Read Python source from stdin instead of a file
         #[arg(long, conflicts_with_all = ["in_place", "backup_ext"])]
@@ -363,8 +987,74 @@ enum Commands {
         stdout: bool,
 
         /// Remove dead code (unreachable functions) in addition to minification
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        remove_dead_code: Option<bool>,
+
+        /// Count functions referenced only in `assert` statements or `if __debug__:` blocks
+        /// as live for --remove-dead-code (default: true)
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        asserts_are_roots: Option<bool>,
+
+        /// Drop imports nothing in the module references before planning renames
         #[arg(long)]
-        remove_dead_code: bool,
+        remove_unused_imports: bool,
+
+        /// Preset: 0 validates the plan without rewriting, 1 renames locals (the default),
+        /// 2 also drops unused imports, strips docstrings and comments, and compacts blank
+        /// lines, 3 also renames module privates and removes dead code
+        #[arg(long, value_name = "0-3", value_parser = clap::value_parser!(u8).range(0..=3))]
+        level: Option<u8>,
+
+        /// Keep docstrings, including in files that get renames
+        #[arg(long)]
+        no_strip_docstrings: bool,
+
+        /// Keep comments at --level 2 and above
+        #[arg(long)]
+        no_strip_comments: bool,
+
+        /// Keep blank lines at --level 2 and above
+        #[arg(long)]
+        no_compact_blank_lines: bool,
+
+        /// Derive short names from a hash of the function and original name so unrelated
+        /// edits do not shift every other rename
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        stable_names: Option<bool>,
+
+        /// Also shorten `_`-prefixed module-level functions, classes, and constants that
+        /// are never named in `__all__` or in a string
+        #[arg(long)]
+        rename_module_privates: bool,
+
+        /// Never rename locals that are the target of an annotated assignment, keeping
+        /// them stable for runtime annotation inspection
+        #[arg(long)]
+        preserve_annotated_locals: bool,
+
+        /// Warn when a generated short name also appears in its function as an attribute,
+        /// a keyword argument, or a word in a string literal
+        #[arg(long)]
+        warn_collisions: bool,
+
+        /// Only rename locals inside this function (qualified name such as Class.method,
+        /// optionally prefixed with the module name), leaving the rest of the file
+        /// byte-identical (repeatable)
+        #[arg(
+            long,
+            value_name = "QUALIFIED_NAME",
+            conflicts_with_all = ["remove_unused_imports", "level"]
+        )]
+        only_function: Vec<String>,
+
+        /// Write a source map of applied renames to FILE (or into DIR as <name>.map.json)
+        #[arg(long, value_name = "DIR|FILE")]
+        source_map: Option<PathBuf>,
+
+        /// Leave a file that does not parse (Python 2 or newer-than-supported syntax)
+        /// unchanged instead of failing [default: false]
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        skip_parse_errors: Option<bool>,
     },
 
     /// Rewrite all Python files in a directory tree using safe local renames
@@ -385,10 +1075,30 @@ enum Commands {
         #[arg(long)]
         dry_run: bool,
 
-        /// Create a backup of rewritten files with the given suffix (requires --in-place)
-        #[arg(long, value_name = "EXT")]
+        /// Write into a non-empty output directory, overwriting only the outputs of files
+        /// processed this run and leaving everything else in place
+        #[arg(long, conflicts_with = "in_place")]
+        merge_out_dir: bool,
+
+        /// Like --merge-out-dir, then delete outputs whose sources are gone; files the
+        /// include/exclude filters would never select are reported instead of deleted
+        #[arg(long, conflicts_with = "in_place")]
+        prune_out_dir: bool,
+
+        /// Also delete files the include/exclude filters would never select (with
+        /// --prune-out-dir)
+        #[arg(long, requires = "prune_out_dir")]
+        prune_force: bool,
+
+        /// Create a backup of rewritten files with the given suffix (requires --in-place)
+        #[arg(long, value_name = "EXT")]
         backup_ext: Option<String>,
 
+        /// Write over files that changed on disk after they were read (with --in-place);
+        /// by default they are left alone and reported as changed_during_run
+        #[arg(long)]
+        overwrite_changed: bool,
+
         /// Glob pattern to include (repeatable). Defaults to "**/*.py"
         #[arg(long, value_name = "GLOB")]
         include: Vec<String>,
@@ -406,12 +1116,30 @@ enum Commands {
         exclude_file: Option<PathBuf>,
 
         /// Print per-file rename counts and totals in the summary
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        stats: Option<bool>,
+
+        /// Also total files, renames, and bytes saved per leading directory, grouped by
+        /// the first DEPTH path components (requires --stats)
+        #[arg(long, value_name = "DEPTH", num_args = 0..=1, require_equals = true, default_missing_value = "1")]
+        summary_by_dir: Option<usize>,
+
+        /// Also list the N files with the largest byte savings (requires --stats)
+        #[arg(long, value_name = "N")]
+        top: Option<usize>,
+
+        /// If another run holds the output directory's .tsrs.lock, poll for up to SECS
+        /// seconds for it to finish instead of failing immediately
+        #[arg(long, value_name = "SECS")]
+        wait: Option<u64>,
+
+        /// Take over a .tsrs.lock left behind by a run that died or started over an hour ago
         #[arg(long)]
-        stats: bool,
+        force_lock: bool,
 
         /// Emit stats summary as JSON (requires --stats)
-        #[arg(long)]
-        json: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        json: Option<bool>,
 
         /// Write stats summary to a JSON file
         #[arg(long, value_name = "JSON_FILE")]
@@ -421,6 +1149,11 @@ enum Commands {
         #[arg(long, value_name = "N")]
         jobs: Option<usize>,
 
+        /// Lower the worker count so the largest files in flight fit in this many
+        /// megabytes, assuming each takes about 4x its size
+        #[arg(long, value_name = "MB")]
+        max_memory: Option<u64>,
+
         /// Exit with a non-zero status if any bailouts occur
         #[arg(long)]
         fail_on_bailout: bool,
@@ -438,16 +1171,46 @@ enum Commands {
         diff: bool,
 
         /// Number of context lines to include in diffs (default: 3)
-        #[arg(long, value_name = "N", default_value_t = 3)]
-        diff_context: usize,
+        #[arg(long, value_name = "N")]
+        diff_context: Option<usize>,
+
+        /// Write diffs to FILE as one multi-file patch (.diff/.patch), or into DIR as one
+        /// <name>.patch per rewritten file
+        #[arg(long, value_name = "DIR|FILE")]
+        diff_out: Option<PathBuf>,
+
+        /// Write a manifest of the applied renames into the output tree: one
+        /// _tsrs_manifest file at its root, or one per top-level package directory
+        #[arg(
+            long,
+            value_name = "LAYOUT",
+            value_parser = ["root", "per-package"],
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "root"
+        )]
+        embed_manifest: Option<String>,
+
+        /// Write the --embed-manifest manifest as JSON, or as a Python module defining a
+        /// MANIFEST dict
+        #[arg(long, value_name = "FORMAT", value_parser = ["json", "py"], default_value = "json")]
+        manifest_format: String,
+
+        /// Re-parse rewritten output and fail the file instead of writing invalid Python
+        #[arg(long)]
+        verify: bool,
+
+        /// Also byte-compile rewritten output with PYTHON (`PYTHON -m py_compile`); implies --verify
+        #[arg(long, value_name = "PYTHON")]
+        verify_exec: Option<PathBuf>,
 
         /// Include hidden files and directories
-        #[arg(long)]
-        include_hidden: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        include_hidden: Option<bool>,
 
         /// Follow symlinks when traversing directories
-        #[arg(long)]
-        follow_symlinks: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        follow_symlinks: Option<bool>,
 
         /// Force case-insensitive glob matching (defaults to on for Windows)
         #[arg(long, value_name = "BOOL")]
@@ -458,12 +1221,146 @@ enum Commands {
         max_depth: Option<usize>,
 
         /// Respect .gitignore files when scanning
-        #[arg(long)]
-        respect_gitignore: bool,
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        respect_gitignore: Option<bool>,
 
         /// Remove dead code (unreachable functions) in addition to minification
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        remove_dead_code: Option<bool>,
+
+        /// Count functions referenced only in `assert` statements or `if __debug__:` blocks
+        /// as live for --remove-dead-code (default: true)
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        asserts_are_roots: Option<bool>,
+
+        /// Drop imports nothing in the module references before planning renames
+        #[arg(long)]
+        remove_unused_imports: bool,
+
+        /// Drop module-level `NAME = value` constants that no file under the input
+        /// directory references
+        #[arg(long, conflicts_with = "watch")]
+        remove_dead_assignments: bool,
+
+        /// Preset: 0 validates the plan without rewriting, 1 renames locals (the default),
+        /// 2 also drops unused imports, strips docstrings and comments, and compacts blank
+        /// lines, 3 also renames module privates and removes dead code
+        #[arg(long, value_name = "0-3", value_parser = clap::value_parser!(u8).range(0..=3))]
+        level: Option<u8>,
+
+        /// Keep docstrings, including in files that get renames
+        #[arg(long)]
+        no_strip_docstrings: bool,
+
+        /// Keep comments at --level 2 and above
+        #[arg(long)]
+        no_strip_comments: bool,
+
+        /// Keep blank lines at --level 2 and above
+        #[arg(long)]
+        no_compact_blank_lines: bool,
+
+        /// Derive short names from a hash of the function and original name so unrelated
+        /// edits do not shift every other rename
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        stable_names: Option<bool>,
+
+        /// Also shorten `_`-prefixed module-level functions, classes, and constants that
+        /// are never named in `__all__` or in a string
+        #[arg(long)]
+        rename_module_privates: bool,
+
+        /// Never rename locals that are the target of an annotated assignment, keeping
+        /// them stable for runtime annotation inspection
+        #[arg(long)]
+        preserve_annotated_locals: bool,
+
+        /// Warn when a generated short name also appears in its function as an attribute,
+        /// a keyword argument, or a word in a string literal
+        #[arg(long)]
+        warn_collisions: bool,
+
+        /// Write source maps of applied renames: a single FILE ending in .json, or a DIR
+        /// mirroring the input tree with <name>.map.json files
+        #[arg(long, value_name = "DIR|FILE")]
+        source_map: Option<PathBuf>,
+
+        /// Keep running after the initial pass and re-minify sources as they change
+        #[arg(long, conflicts_with = "in_place")]
+        watch: bool,
+
+        /// Skip files that do not parse (Python 2 or newer-than-supported syntax) instead
+        /// of counting them as errors [default: true]
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        skip_parse_errors: Option<bool>,
+
+        /// Source file extensions to process, comma-separated (default: py)
+        #[arg(long, value_name = "EXT", value_delimiter = ',')]
+        extensions: Vec<String>,
+
+        /// Do not load exclude patterns from .tsrsignore files
+        #[arg(long)]
+        no_tsrsignore: bool,
+
+        /// Do not apply the built-in excludes (.git, __pycache__, .venv)
+        #[arg(long)]
+        no_default_excludes: bool,
+
+        /// Directory, relative to the input, that module names start from (repeatable);
+        /// each file is named from the deepest root containing it
+        #[arg(long, value_name = "DIR")]
+        module_root: Vec<PathBuf>,
+
+        /// Only process files git reports changed between REF and the working tree
+        /// (untracked files included, deletions ignored)
+        #[arg(long, value_name = "REF")]
+        changed_since: Option<String>,
+
+        /// With --changed-since, process every file instead of failing when the input
+        /// is not inside a git work tree
+        #[arg(long, requires = "changed_since")]
+        changed_since_optional: bool,
+
+        /// Skip files larger than BYTES; out-dir runs copy them through unchanged
+        #[arg(long, value_name = "BYTES")]
+        max_file_size: Option<u64>,
+
+        /// Print the peak memory usage of the run to stderr
+        #[arg(long)]
+        report_memory: bool,
+
+        /// Time the walk, plan+rewrite, and write phases and each file, adding them to the
+        /// summary and --output-json along with the slowest files
+        #[arg(long)]
+        timings: bool,
+
+        /// Write bailed-out files and functions to FILE as a SARIF 2.1.0 log
+        #[arg(long, value_name = "FILE")]
+        sarif: Option<PathBuf>,
+
+        /// Read INPUT_DIR as a .tar.gz, .tar, or .zip/.whl archive (implied by those
+        /// extensions when it is a file)
         #[arg(long)]
-        remove_dead_code: bool,
+        input_archive: bool,
+
+        /// Write the minified archive to PATH, in the format its extension names (default:
+        /// the input's format); required for archive input unless --dry-run
+        #[arg(long, value_name = "PATH")]
+        output_archive: Option<PathBuf>,
+
+        /// Set every entry's modification time in --output-archive to SECONDS since the
+        /// Unix epoch, for reproducible archives
+        #[arg(long, value_name = "SECONDS", requires = "output_archive")]
+        archive_mtime: Option<u64>,
+
+        /// Write one JSON object per line to stderr as the run progresses, as described
+        /// by `schema progress-events`
+        #[arg(long)]
+        progress_json: bool,
+
+        /// With --progress-json, write the events to FILE instead of stderr
+        #[arg(long, value_name = "FILE", requires = "progress_json")]
+        progress_file: Option<PathBuf>,
     },
 
     /// End-to-end optimization: analyze code, detect dead code, create slim venv, and minify
@@ -500,6 +1397,29 @@ enum Commands {
         #[arg(long)]
         print_report: bool,
 
+        /// Write dead functions to FILE as a SARIF 2.1.0 log
+        #[arg(long, value_name = "FILE")]
+        sarif: Option<PathBuf>,
+
+        /// Treat every top-level function and class of modules matching MODULE_GLOB
+        /// (relative to CODE_DIR, e.g. `mypkg/api/*.py`) as live (repeatable)
+        #[arg(long, value_name = "MODULE_GLOB")]
+        public_roots: Vec<String>,
+
+        /// Count functions referenced only in `assert` statements or `if __debug__:` blocks
+        /// as live (default: true)
+        #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        asserts_are_roots: Option<bool>,
+
+        /// Report only dead functions at least this sure to be unused
+        #[arg(
+            long,
+            value_name = "LEVEL",
+            value_parser = ["high", "medium", "low"],
+            default_value = "low"
+        )]
+        min_confidence: String,
+
         /// Print summary statistics
         #[arg(long)]
         stats: bool,
@@ -512,6 +1432,60 @@ enum Commands {
         #[arg(long)]
         dry_run: bool,
     },
+
+    /// Inspect settings loaded from tsrs.toml or pyproject.toml [tool.tsrs]
+    Config {
+        /// Print the effective configuration as TOML
+        #[arg(long)]
+        show: bool,
+
+        /// Directory to start configuration discovery from (default: current directory)
+        #[arg(value_name = "DIR")]
+        path: Option<PathBuf>,
+    },
+
+    /// Restore original local names in a Python traceback read from stdin
+    Unmangle {
+        /// Source map file or directory of maps written by --source-map (repeatable)
+        #[arg(long, value_name = "FILE", required = true)]
+        source_map: Vec<PathBuf>,
+    },
+
+    /// Compare the --output-json stats of two minify-dir or apply-plan-dir runs
+    StatsDiff {
+        /// Stats written by the earlier run (with --stats for per-file changes)
+        #[arg(value_name = "OLD_JSON")]
+        old: PathBuf,
+
+        /// Stats written by the later run
+        #[arg(value_name = "NEW_JSON")]
+        new: PathBuf,
+
+        /// Print the comparison as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Exit with status 1 when bailouts or errors increased or a minified file now bails
+        /// out
+        #[arg(long)]
+        fail_on_regression: bool,
+    },
+
+    /// Print the JSON Schema for a tsrs file format
+    Schema {
+        /// Format to describe
+        #[arg(value_name = "FORMAT", value_parser = ["source-map", "progress-events"])]
+        format: String,
+    },
+
+    /// Plan, rewrite, and re-parse the bundled corpus of tricky snippets and report any
+    /// case whose result changed
+    SelfTest {
+        /// Also check the cases in this directory: each `NAME.py`, with the golden output
+        /// in `NAME.expected.py` or a first line `# self-test: bail REASON`
+        #[arg(long, value_name = "DIR")]
+        corpus: Option<PathBuf>,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
@@ -532,20 +1506,220 @@ fn main() -> anyhow::Result<()> {
         .with_writer(std::io::stderr)
         .with_target(false)
         .init();
+    let forced = cli
+        .encoding
+        .as_deref()
+        .map(|label| {
+            Encoding::for_label(label.trim().as_bytes())
+                .with_context(|| format!("unknown encoding '{}'", label))
+        })
+        .transpose()?;
+    let naming_config =
+        NamingConfig::new(cli.rename_prefix.as_deref(), cli.rename_alphabet.as_deref())?;
+    let function_filter =
+        FunctionFilter::new(cli.include_function.clone(), cli.exclude_function.clone())?;
+    let mut ctx = CommandContext {
+        verbose_stats: cli.verbose > 0,
+        decode: DecodeOptions {
+            forced,
+            lossy: cli.decode == "lossy",
+        },
+        plan: PlanOptions {
+            naming_config,
+            min_name_length: cli.min_name_length,
+            skip_single_use: cli.skip_single_use,
+            rename_typevars: cli.rename_typevars,
+            function_filter,
+            limits: PlanLimits {
+                max_renames: cli.max_renames_per_file,
+                max_functions: cli.max_plan_functions,
+            },
+            ..PlanOptions::default()
+        },
+        rewrite: RewriteOptions {
+            annotate_renames: cli.annotate_renames.then(|| cli.annotate_width.max(1)),
+            ..RewriteOptions::default()
+        },
+        ..CommandContext::default()
+    };
 
     match cli.command {
-        Commands::Analyze { venv_path } => {
-            analyze(&venv_path)?;
+        Commands::Analyze { venv_path, json } => {
+            analyze(&venv_path, json)?;
         }
         Commands::Slim {
-            code_path,
+            code_paths,
             venv_path,
             output,
+            report,
+            compile_bytecode,
+            strip_sources,
+            strip_sources_except,
+            code_exclude,
+            relocatable,
+            format,
+            main,
+            keep_package,
+            drop_package,
+            policy_file,
+            first_party,
+            keep_type_checking_imports,
+            python_version,
+            jobs,
+            verify_imports,
+            verify_skip,
+            fail_on_license,
+        } => {
+            let format = match format.as_str() {
+                "zipapp" => SlimFormat::Zipapp,
+                _ => SlimFormat::Venv,
+            };
+            if format == SlimFormat::Zipapp && compile_bytecode {
+                bail!("--compile-bytecode only applies to venv output, not --format zipapp");
+            }
+            if format == SlimFormat::Zipapp && verify_imports {
+                bail!("--verify-imports only applies to venv output, not --format zipapp");
+            }
+            if format == SlimFormat::Venv && main.is_some() {
+                bail!("--main requires --format zipapp");
+            }
+            let mut policy = match &policy_file {
+                Some(path) => PackagePolicy::from_file(path)?,
+                None => PackagePolicy::default(),
+            };
+            policy.keep.extend(keep_package);
+            policy.drop.extend(drop_package);
+            let options = SlimOptions {
+                compile_bytecode,
+                strip_sources,
+                strip_sources_except,
+                code_exclude,
+                relocatable,
+                format,
+                main,
+                policy,
+                first_party,
+                keep_type_checking_imports,
+                python_version: python_version_arg(python_version.as_deref())?,
+                jobs: resolve_jobs(jobs)?,
+                verify_imports,
+                verify_skip,
+                fail_on_license,
+            };
+            slim(&code_paths, &venv_path, output, report.as_deref(), options)?;
+        }
+        Commands::Imports {
+            input_dir,
+            json,
+            third_party_only,
+            venv,
+            python_version,
+            include,
+            include_file,
+            exclude,
+            exclude_file,
+            include_hidden,
+            follow_symlinks,
+            glob_case_insensitive,
+            max_depth,
+            respect_gitignore,
+            extensions,
+            no_tsrsignore,
         } => {
-            slim(&code_path, &venv_path, output)?;
+            let config = load_config(&input_dir)?;
+            let report = collect_imports_report(
+                &input_dir,
+                &merge_patterns(include, config.include),
+                include_file.or(config.include_file).as_ref(),
+                &merge_patterns(exclude, config.exclude),
+                exclude_file.or(config.exclude_file).as_ref(),
+                merge_flag(include_hidden, config.include_hidden),
+                merge_flag(follow_symlinks, config.follow_symlinks),
+                glob_case_insensitive.or(config.glob_case_insensitive),
+                max_depth.or(config.max_depth),
+                merge_flag(respect_gitignore, config.respect_gitignore),
+                &merge_patterns(extensions, config.extensions),
+                no_tsrsignore,
+                third_party_only,
+                venv.as_deref(),
+                python_version_arg(python_version.as_deref())?,
+                &ctx,
+            )?;
+            print_imports_report(&report, json)?;
+        }
+        Commands::ImportGraph {
+            input_dir,
+            format,
+            fail_on_cycles,
+            max_depth_from,
+            include_deferred,
+            include,
+            exclude,
+        } => {
+            let config = load_config(&input_dir)?;
+            let mut graph = build_import_graph(
+                &input_dir,
+                &merge_patterns(include, config.include),
+                &merge_patterns(exclude, config.exclude),
+                include_deferred,
+                &ctx,
+            )?;
+            if let Some(root) = &max_depth_from {
+                graph = graph
+                    .cone(root)
+                    .with_context(|| format!("no module named '{}' in the graph", root))?;
+            }
+            let cycles = graph.cycles();
+            match format.as_str() {
+                "dot" => print!("{}", graph.to_dot(&cycles)),
+                "json" => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&ImportGraphOutput {
+                        graph: &graph,
+                        cycles: &cycles,
+                    })?
+                ),
+                _ => print!("{}", render_import_graph(&graph, &cycles)),
+            }
+            if fail_on_cycles && !cycles.is_empty() {
+                process::exit(1);
+            }
         }
-        Commands::MinifyPlan { python_file } => {
-            minify_plan(&python_file)?;
+        Commands::CallGraph { python_file, json } => {
+            let package = python_file
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .unwrap_or("module")
+                .to_string();
+            let mut analyzer = call_graph_analyzer(&ctx);
+            analyzer.analyze_file(&python_file, &package)?;
+            let graph = analyzer.graph();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&graph.export())?);
+            } else {
+                print!("{}", render_call_graph(graph));
+            }
+        }
+        Commands::MinifyPlan {
+            python_file,
+            stdin,
+            module_name,
+            combined,
+            stable_names,
+            rename_module_privates,
+            preserve_annotated_locals,
+        } => {
+            let python_file = if stdin { None } else { python_file };
+            let config = load_config(python_file.as_deref().unwrap_or(Path::new(".")))?;
+            minify_plan(
+                python_file.as_deref(),
+                module_name.as_deref(),
+                combined,
+                naming_mode(merge_flag(stable_names, config.stable_names)),
+                rename_module_privates,
+                preserve_annotated_locals,
+                &ctx,
+            )?;
         }
         Commands::MinifyPlanDir {
             input_dir,
@@ -555,26 +1729,86 @@ fn main() -> anyhow::Result<()> {
             exclude,
             exclude_file,
             jobs,
+            max_memory,
             include_hidden,
             follow_symlinks,
             glob_case_insensitive,
             max_depth,
             respect_gitignore,
+            extensions,
+            no_tsrsignore,
+            no_default_excludes,
+            module_root,
+            changed_since,
+            changed_since_optional,
+            stable_names,
+            rename_module_privates,
+            preserve_annotated_locals,
+            out_format,
+            progress_json,
+            progress_file,
+            fail_on_error,
+            errors_out,
         } => {
-            minify_plan_dir_with_depth(
+            ctx.max_memory_mb = max_memory.unwrap_or(0);
+            ctx.no_default_excludes = no_default_excludes;
+            ctx.module_roots = resolve_module_roots(&input_dir, &module_root)?;
+            let config = load_config(&input_dir)?;
+            if progress_json {
+                ctx.progress = ProgressStream::open(progress_file.as_deref())?;
+            }
+            let errors = minify_plan_dir_with_depth(
                 &input_dir,
                 &out,
-                &include,
-                include_file.as_ref(),
-                &exclude,
-                exclude_file.as_ref(),
-                jobs,
-                include_hidden,
-                follow_symlinks,
-                glob_case_insensitive,
-                max_depth,
-                respect_gitignore,
+                &merge_patterns(include, config.include),
+                include_file.or(config.include_file).as_ref(),
+                &merge_patterns(exclude, config.exclude),
+                exclude_file.or(config.exclude_file).as_ref(),
+                jobs.or(config.jobs),
+                merge_flag(include_hidden, config.include_hidden),
+                merge_flag(follow_symlinks, config.follow_symlinks),
+                glob_case_insensitive.or(config.glob_case_insensitive),
+                max_depth.or(config.max_depth),
+                merge_flag(respect_gitignore, config.respect_gitignore),
+                merge_flag(stable_names, config.stable_names),
+                rename_module_privates,
+                preserve_annotated_locals,
+                &merge_patterns(extensions, config.extensions),
+                no_tsrsignore,
+                changed_since.as_deref(),
+                changed_since_optional,
                 cli.quiet,
+                out_format == "dir",
+                errors_out.as_deref(),
+                &ctx,
+            )?;
+            if fail_on_error && errors > 0 {
+                process::exit(1);
+            }
+        }
+        Commands::SizeAudit {
+            input_dir,
+            include,
+            exclude,
+            jobs,
+            max_depth,
+            extensions,
+            stable_names,
+            rename_module_privates,
+            json,
+        } => {
+            let config = load_config(&input_dir)?;
+            size_audit(
+                &input_dir,
+                &merge_patterns(include, config.include),
+                &merge_patterns(exclude, config.exclude),
+                jobs.or(config.jobs),
+                max_depth.or(config.max_depth),
+                &merge_patterns(extensions, config.extensions),
+                merge_flag(stable_names, config.stable_names),
+                rename_module_privates,
+                json,
+                &ctx,
             )?;
         }
         Commands::Minify {
@@ -590,10 +1824,59 @@ fn main() -> anyhow::Result<()> {
             fail_on_change,
             diff,
             diff_context,
+            diff_out,
+            verify,
+            verify_exec,
             stdin,
             stdout,
             remove_dead_code,
+            asserts_are_roots,
+            remove_unused_imports,
+            level,
+            no_strip_docstrings,
+            no_strip_comments,
+            no_compact_blank_lines,
+            stable_names,
+            rename_module_privates,
+            preserve_annotated_locals,
+            warn_collisions,
+            only_function,
+            source_map,
+            skip_parse_errors,
         } => {
+            ctx.asserts_are_roots = asserts_are_roots.unwrap_or(true);
+            let verify = verify || verify_exec.is_some();
+            let skip_parse_errors = skip_parse_errors.unwrap_or(false);
+            let config = if stdin {
+                load_config(Path::new("."))?
+            } else {
+                load_config(&python_file)?
+            };
+            let stats = merge_flag(stats, config.stats);
+            let json = merge_flag(json, config.json);
+            let settings = level_settings(
+                level,
+                remove_unused_imports,
+                rename_module_privates,
+                merge_flag(remove_dead_code, config.remove_dead_code),
+                no_strip_docstrings,
+                no_strip_comments,
+                no_compact_blank_lines,
+            );
+            let remove_dead_code = settings.remove_dead_code;
+            let rename_module_privates = settings.rename_module_privates;
+            ctx.rewrite.keep_docstrings = settings.keep_docstrings;
+            let validate_only = !settings.rewrite;
+            if validate_only && stdout {
+                anyhow::bail!(
+                    "--level 0 only validates the plan and cannot be combined with --stdout"
+                );
+            }
+            let dry_run = dry_run || validate_only;
+            let pipeline = settings.pipeline();
+            let naming = naming_mode(merge_flag(stable_names, config.stable_names));
+            let diff_context = diff_context.or(config.diff_context).unwrap_or(3);
+
             let (stats_result, stdout_bytes) = if stdin {
                 if in_place {
                     anyhow::bail!("--stdin cannot be combined with --in-place");
@@ -604,76 +1887,167 @@ fn main() -> anyhow::Result<()> {
 
                 let mut buffer = Vec::new();
                 std::io::stdin().read_to_end(&mut buffer)?;
-                let (source, metadata) = decode_python_bytes(&buffer, "stdin")?;
-
-                // Generate minification plan
-                let mut plan = Minifier::plan_from_source("stdin", &source)?;
-
-                // Filter plan if --remove-dead-code is requested
-                if remove_dead_code {
-                    let dead_code = detect_dead_code(&source, "stdin", cli.quiet)?;
-                    plan = filter_plan_for_dead_code(plan, &dead_code);
-                }
-
+                let (source, metadata) = decode_python_bytes(&buffer, "stdin", ctx.decode)?;
                 let fake_path = PathBuf::from("stdin");
-                let (stats, bytes) = apply_plan_to_file(
-                    &fake_path,
+
+                match plan_single_file(
+                    "stdin",
                     &source,
-                    &metadata,
-                    &plan,
+                    &pipeline,
                     false,
-                    dry_run,
-                    None,
-                    stats,
-                    json,
-                    cli.quiet,
-                    output_json.as_deref(),
-                    fail_on_bailout,
-                    fail_on_error,
-                    fail_on_change,
-                    diff,
-                    diff_context,
-                    stdout,
-                )?;
-                (stats, bytes)
+                    plan_options(
+                        &fake_path,
+                        &ctx.plan,
+                        naming,
+                        rename_module_privates,
+                        preserve_annotated_locals,
+                    ),
+                ) {
+                    Err(err) if skip_parse_errors && err.syntax_issue(&source).is_some() => {
+                        skip_unparsable_file(
+                            &fake_path,
+                            &source,
+                            &metadata,
+                            &err,
+                            false,
+                            dry_run,
+                            stats,
+                            json,
+                            cli.quiet,
+                            output_json.as_deref(),
+                            stdout,
+                            &ctx,
+                        )?
+                    }
+                    Err(err) => return Err(err.into()),
+                    Ok((transformed, mut plan)) => {
+                        let planned = transformed
+                            .as_ref()
+                            .map_or(source.as_str(), |output| output.source.as_str());
+
+                        // Filter plan if --remove-dead-code is requested
+                        if remove_dead_code {
+                            let dead_code = detect_dead_code(planned, "stdin", cli.quiet, &ctx)?;
+                            plan = filter_plan_for_dead_code(plan, &dead_code);
+                        }
+                        if !only_function.is_empty() {
+                            plan = plan.only_functions(&only_function)?;
+                        }
+
+                        let (stats, bytes) = apply_plan_to_file(
+                            &fake_path,
+                            &source,
+                            &metadata,
+                            &plan,
+                            validate_only,
+                            dry_run,
+                            None,
+                            stats,
+                            json,
+                            cli.quiet,
+                            output_json.as_deref(),
+                            fail_on_bailout,
+                            fail_on_error,
+                            fail_on_change,
+                            diff,
+                            diff_context,
+                            stdout,
+                            diff_out.as_deref(),
+                            source_map.as_deref(),
+                            verify,
+                            verify_exec.as_deref(),
+                            transformed.as_ref(),
+                            false,
+                            warn_collisions,
+                            &ctx,
+                        )?;
+                        (stats, bytes)
+                    }
+                }
             } else {
                 // Read source code
-                let (source, metadata) = read_python(&python_file)?;
+                let (source, metadata) = read_python(&python_file, ctx.decode)?;
                 let module_name = python_file
                     .file_stem()
                     .and_then(|stem| stem.to_str())
                     .map(|s| s.to_string())
                     .unwrap_or_else(|| python_file.to_string_lossy().to_string());
 
-                // Generate minification plan
-                let mut plan = Minifier::plan_from_source(&module_name, &source)?;
+                match plan_single_file(
+                    &module_name,
+                    &source,
+                    &pipeline,
+                    is_package_init(&python_file),
+                    plan_options(
+                        &python_file,
+                        &ctx.plan,
+                        naming,
+                        rename_module_privates,
+                        preserve_annotated_locals,
+                    ),
+                ) {
+                    Err(err) if skip_parse_errors && err.syntax_issue(&source).is_some() => {
+                        skip_unparsable_file(
+                            &python_file,
+                            &source,
+                            &metadata,
+                            &err,
+                            in_place,
+                            dry_run,
+                            stats,
+                            json,
+                            cli.quiet,
+                            output_json.as_deref(),
+                            stdout,
+                            &ctx,
+                        )?
+                    }
+                    Err(err) => return Err(err.into()),
+                    Ok((transformed, mut plan)) => {
+                        let planned = transformed
+                            .as_ref()
+                            .map_or(source.as_str(), |output| output.source.as_str());
+
+                        // Filter plan if --remove-dead-code is requested
+                        if remove_dead_code {
+                            let dead_code =
+                                detect_dead_code(planned, &module_name, cli.quiet, &ctx)?;
+                            plan = filter_plan_for_dead_code(plan, &dead_code);
+                        }
+                        if !only_function.is_empty() {
+                            plan = plan.only_functions(&only_function)?;
+                        }
 
-                // Filter plan if --remove-dead-code is requested
-                if remove_dead_code {
-                    let dead_code = detect_dead_code(&source, &module_name, cli.quiet)?;
-                    plan = filter_plan_for_dead_code(plan, &dead_code);
+                        let (stats, bytes) = apply_plan_to_file(
+                            &python_file,
+                            &source,
+                            &metadata,
+                            &plan,
+                            in_place || validate_only,
+                            dry_run,
+                            backup_ext.as_deref(),
+                            stats,
+                            json,
+                            cli.quiet,
+                            output_json.as_deref(),
+                            fail_on_bailout,
+                            fail_on_error,
+                            fail_on_change,
+                            diff,
+                            diff_context,
+                            stdout,
+                            diff_out.as_deref(),
+                            source_map.as_deref(),
+                            verify,
+                            verify_exec.as_deref(),
+                            transformed.as_ref(),
+                            false,
+                            warn_collisions,
+                            &ctx,
+                        )?;
+                        (stats, bytes)
+                    }
                 }
-
-                let (stats, bytes) = apply_plan_to_file(
-                    &python_file,
-                    &source,
-                    &metadata,
-                    &plan,
-                    in_place,
-                    dry_run,
-                    backup_ext.as_deref(),
-                    stats,
-                    json,
-                    cli.quiet,
-                    output_json.as_deref(),
-                    fail_on_bailout,
-                    fail_on_error,
-                    fail_on_change,
-                    diff,
-                    diff_context,
-                    stdout,
-                )?;
-                (stats, bytes)
             };
 
             if let Some(bytes) = stdout_bytes {
@@ -686,6 +2060,7 @@ fn main() -> anyhow::Result<()> {
                     fail_on_bailout,
                     fail_on_error,
                     fail_on_change,
+                    false,
                 );
                 process::exit(code);
             }
@@ -705,9 +2080,16 @@ fn main() -> anyhow::Result<()> {
             fail_on_change,
             diff,
             diff_context,
+            diff_out,
+            verify,
+            verify_exec,
             stdin,
             stdout,
+            allow_partial_plan,
+            fuzzy_apply,
         } => {
+            let verify = verify || verify_exec.is_some();
+            ctx.rewrite.fuzzy_ranges = fuzzy_apply;
             let plan_from_stdin = plan_stdin || plan.as_ref().is_some_and(|p| p.as_os_str() == "-");
             let plan_path = plan.as_ref().and_then(|p| {
                 if p.as_os_str() == "-" {
@@ -732,7 +2114,8 @@ fn main() -> anyhow::Result<()> {
                 if plan_from_stdin {
                     let mut buffer = Vec::new();
                     std::io::stdin().read_to_end(&mut buffer)?;
-                    let (source, metadata, plan_bundle) = split_source_and_plan(&buffer)?;
+                    let (source, metadata, plan_bundle) =
+                        split_source_and_plan(&buffer, ctx.decode)?;
                     let fake_path = PathBuf::from("stdin");
                     apply_plan_to_file(
                         &fake_path,
@@ -752,12 +2135,21 @@ fn main() -> anyhow::Result<()> {
                         diff,
                         diff_context,
                         stdout,
+                        diff_out.as_deref(),
+                        None,
+                        verify,
+                        verify_exec.as_deref(),
+                        None,
+                        allow_partial_plan,
+                        false,
+                        &ctx,
                     )?
                 } else {
                     let plan_path = plan_path.expect("plan path available");
                     let mut buffer = Vec::new();
                     std::io::stdin().read_to_end(&mut buffer)?;
-                    let (source, metadata) = decode_python_bytes(&buffer, "stdin source")?;
+                    let (source, metadata) =
+                        decode_python_bytes(&buffer, "stdin source", ctx.decode)?;
                     let plan_json = fs::read_to_string(&plan_path)?;
                     let plan_bundle: MinifyPlan =
                         serde_json::from_str(&plan_json).context("failed to parse plan JSON")?;
@@ -780,11 +2172,19 @@ fn main() -> anyhow::Result<()> {
                         diff,
                         diff_context,
                         stdout,
+                        diff_out.as_deref(),
+                        None,
+                        verify,
+                        verify_exec.as_deref(),
+                        None,
+                        allow_partial_plan,
+                        false,
+                        &ctx,
                     )?
                 }
             } else {
                 if plan_from_stdin {
-                    let (source, metadata) = read_python(&python_file)?;
+                    let (source, metadata) = read_python(&python_file, ctx.decode)?;
                     let mut plan_bytes = Vec::new();
                     std::io::stdin().read_to_end(&mut plan_bytes)?;
                     if plan_bytes.is_empty() {
@@ -810,6 +2210,14 @@ fn main() -> anyhow::Result<()> {
                         diff,
                         diff_context,
                         stdout,
+                        diff_out.as_deref(),
+                        None,
+                        verify,
+                        verify_exec.as_deref(),
+                        None,
+                        allow_partial_plan,
+                        false,
+                        &ctx,
                     )?
                 } else {
                     let plan_path = plan_path.expect("plan path available");
@@ -829,6 +2237,11 @@ fn main() -> anyhow::Result<()> {
                         diff,
                         diff_context,
                         stdout,
+                        diff_out.as_deref(),
+                        verify,
+                        verify_exec.as_deref(),
+                        allow_partial_plan,
+                        &ctx,
                     )?
                 }
             };
@@ -843,6 +2256,7 @@ fn main() -> anyhow::Result<()> {
                     fail_on_bailout,
                     fail_on_error,
                     fail_on_change,
+                    false,
                 );
                 process::exit(code);
             }
@@ -852,6 +2266,9 @@ fn main() -> anyhow::Result<()> {
             out_dir,
             in_place,
             dry_run,
+            merge_out_dir,
+            prune_out_dir,
+            prune_force,
             backup_ext,
             include,
             include_file,
@@ -861,58 +2278,290 @@ fn main() -> anyhow::Result<()> {
             json,
             output_json,
             jobs,
+            max_memory,
             fail_on_bailout,
             fail_on_error,
             fail_on_change,
             diff,
             diff_context,
+            diff_out,
+            embed_manifest,
+            manifest_format,
+            verify,
+            verify_exec,
             include_hidden,
             follow_symlinks,
             glob_case_insensitive,
             max_depth,
             respect_gitignore,
             remove_dead_code,
+            asserts_are_roots,
+            remove_unused_imports,
+            remove_dead_assignments,
+            level,
+            no_strip_docstrings,
+            no_strip_comments,
+            no_compact_blank_lines,
+            stable_names,
+            rename_module_privates,
+            preserve_annotated_locals,
+            warn_collisions,
+            source_map,
+            watch,
+            summary_by_dir,
+            top,
+            wait,
+            force_lock,
+            skip_parse_errors,
+            extensions,
+            no_tsrsignore,
+            no_default_excludes,
+            module_root,
+            changed_since,
+            changed_since_optional,
+            max_file_size,
+            report_memory,
+            sarif,
+            input_archive,
+            output_archive,
+            archive_mtime,
+            progress_json,
+            progress_file,
+            timings,
+            overwrite_changed,
         } => {
-            let stats_result = minify_dir_with_depth(
-                &input_dir,
-                out_dir,
-                &include,
-                include_file.as_ref(),
-                &exclude,
-                exclude_file.as_ref(),
-                backup_ext.as_deref(),
-                in_place,
-                dry_run,
-                stats,
-                json,
-                include_hidden,
-                follow_symlinks,
-                glob_case_insensitive,
-                cli.quiet,
-                output_json.as_deref(),
-                jobs,
-                fail_on_bailout,
-                fail_on_error,
-                fail_on_change,
-                diff,
-                diff_context,
-                respect_gitignore,
-                max_depth,
-                remove_dead_code,
-            )?;
+            ctx.timings = timings;
+            ctx.overwrite_changed = overwrite_changed;
+            ctx.max_memory_mb = max_memory.unwrap_or(0);
+            ctx.no_default_excludes = no_default_excludes;
+            ctx.module_roots = resolve_module_roots(&input_dir, &module_root)?;
+            ctx.asserts_are_roots = asserts_are_roots.unwrap_or(true);
+            let config = load_config(&input_dir)?;
+            let includes = merge_patterns(include, config.include);
+            let include_file = include_file.or(config.include_file);
+            let excludes = merge_patterns(exclude, config.exclude);
+            let exclude_file = exclude_file.or(config.exclude_file);
+            let show_stats = merge_flag(stats, config.stats);
+            let json = merge_flag(json, config.json);
+            let include_hidden = merge_flag(include_hidden, config.include_hidden);
+            let follow_symlinks = merge_flag(follow_symlinks, config.follow_symlinks);
+            let glob_case_insensitive = glob_case_insensitive.or(config.glob_case_insensitive);
+            let jobs = jobs.or(config.jobs);
+            let diff_context = diff_context.or(config.diff_context).unwrap_or(3);
+            let respect_gitignore = merge_flag(respect_gitignore, config.respect_gitignore);
+            let max_depth = max_depth.or(config.max_depth);
+            let settings = level_settings(
+                level,
+                remove_unused_imports,
+                rename_module_privates,
+                merge_flag(remove_dead_code, config.remove_dead_code),
+                no_strip_docstrings,
+                no_strip_comments,
+                no_compact_blank_lines,
+            );
+            let remove_dead_code = settings.remove_dead_code;
+            let rename_module_privates = settings.rename_module_privates;
+            ctx.rewrite.keep_docstrings = settings.keep_docstrings;
+            let dry_run = dry_run || !settings.rewrite;
+            let pipeline = settings.pipeline();
+            let stable_names = merge_flag(stable_names, config.stable_names);
+            let max_file_size = max_file_size.or(config.max_file_size);
+            let extensions = merge_patterns(extensions, config.extensions);
+            let verify = verify || verify_exec.is_some();
+            let skip_parse_errors = skip_parse_errors.unwrap_or(true);
+            let embed_manifest = embed_manifest.map(|layout| EmbedManifest {
+                per_package: layout == "per-package",
+                python: manifest_format == "py",
+            });
+            let out_dir_mode = if prune_out_dir {
+                OutDirMode::Prune { force: prune_force }
+            } else if merge_out_dir {
+                OutDirMode::Merge
+            } else {
+                OutDirMode::Fresh
+            };
 
-            if fail_on_bailout || fail_on_error || fail_on_change {
-                let code = compute_exit_code(
-                    &stats_result,
+            let archive_input = input_archive
+                || (input_dir.is_file() && ArchiveFormat::from_path(&input_dir).is_some());
+            if output_archive.is_some() && !archive_input {
+                bail!("--output-archive requires an archive input (see --input-archive)");
+            }
+            if archive_input && (in_place || out_dir.is_some() || backup_ext.is_some() || watch) {
+                bail!(
+                    "archive input cannot be combined with --in-place, --out-dir, --backup-ext, or --watch"
+                );
+            }
+            if archive_input && changed_since.is_some() {
+                bail!("--changed-since cannot be combined with archive input");
+            }
+            if archive_input && embed_manifest.is_some() {
+                bail!("--embed-manifest cannot be combined with archive input");
+            }
+            if archive_input && out_dir_mode != OutDirMode::Fresh {
+                bail!("--merge-out-dir and --prune-out-dir cannot be combined with archive input");
+            }
+            if archive_input && remove_dead_assignments {
+                bail!("--remove-dead-assignments cannot be combined with archive input");
+            }
+            if archive_input && progress_json {
+                bail!("--progress-json cannot be combined with archive input");
+            }
+            if progress_json {
+                ctx.progress = ProgressStream::open(progress_file.as_deref())?;
+            }
+
+            let run_pass = |watch_pass: Option<&HashSet<PathBuf>>| {
+                minify_dir_with_depth(
+                    &input_dir,
+                    out_dir.clone(),
+                    &includes,
+                    include_file.as_ref(),
+                    &excludes,
+                    exclude_file.as_ref(),
+                    backup_ext.as_deref(),
+                    in_place,
+                    dry_run,
+                    show_stats,
+                    json,
+                    include_hidden,
+                    follow_symlinks,
+                    glob_case_insensitive,
+                    cli.quiet,
+                    output_json.as_deref(),
+                    jobs,
                     fail_on_bailout,
                     fail_on_error,
                     fail_on_change,
-                );
-                process::exit(code);
-            }
-        }
-        Commands::Optimize {
-            code_dir,
+                    diff,
+                    diff_context,
+                    respect_gitignore,
+                    max_depth,
+                    remove_dead_code,
+                    stable_names,
+                    rename_module_privates,
+                    preserve_annotated_locals,
+                    warn_collisions,
+                    max_file_size,
+                    &extensions,
+                    no_tsrsignore,
+                    changed_since.as_deref(),
+                    changed_since_optional,
+                    source_map.as_deref(),
+                    embed_manifest,
+                    diff_out.as_deref(),
+                    verify,
+                    verify_exec.as_deref(),
+                    &pipeline,
+                    remove_dead_assignments,
+                    skip_parse_errors,
+                    summary_by_dir,
+                    top,
+                    wait,
+                    force_lock,
+                    out_dir_mode,
+                    watch_pass,
+                    &ctx,
+                )
+            };
+
+            let mut stats_result = if archive_input {
+                minify_archive(
+                    &input_dir,
+                    output_archive.as_deref(),
+                    archive_mtime,
+                    &includes,
+                    include_file.as_ref(),
+                    &excludes,
+                    exclude_file.as_ref(),
+                    dry_run,
+                    show_stats,
+                    json,
+                    include_hidden,
+                    glob_case_insensitive,
+                    cli.quiet,
+                    output_json.as_deref(),
+                    jobs,
+                    fail_on_bailout,
+                    fail_on_error,
+                    fail_on_change,
+                    diff,
+                    diff_context,
+                    remove_dead_code,
+                    stable_names,
+                    rename_module_privates,
+                    preserve_annotated_locals,
+                    warn_collisions,
+                    max_file_size,
+                    &extensions,
+                    source_map.as_deref(),
+                    diff_out.as_deref(),
+                    verify,
+                    verify_exec.as_deref(),
+                    &pipeline,
+                    skip_parse_errors,
+                    summary_by_dir,
+                    top,
+                    &ctx,
+                )?
+            } else {
+                run_pass(None)?
+            };
+
+            if watch {
+                let resolved_out_dir = match &out_dir {
+                    Some(dir) => dir.clone(),
+                    None => default_output_dir(&canonicalize_directory(&input_dir)?),
+                };
+                let (shutdown_tx, shutdown_rx) = mpsc::channel();
+                if let Ok(mut forward) = INTERRUPT_FORWARD.lock() {
+                    *forward = Some(shutdown_tx);
+                }
+                install_interrupt_handler()?;
+
+                stats_result = watch_minify_dir(
+                    &input_dir,
+                    Some(resolved_out_dir.as_path()),
+                    dry_run,
+                    show_stats,
+                    cli.quiet,
+                    stats_result,
+                    &shutdown_rx,
+                    |changed| run_pass(Some(changed)),
+                )?;
+
+                stats_result.summarize(summary_by_dir, top);
+                print_summary(
+                    &stats_result,
+                    show_stats,
+                    json,
+                    ctx.verbose_stats,
+                    dry_run,
+                    &resolved_out_dir.display().to_string(),
+                    output_json.as_deref(),
+                )?;
+            }
+
+            if let Some(path) = &sarif {
+                write_sarif(path, &stats_result.findings)?;
+            }
+
+            if report_memory {
+                report_peak_memory();
+            }
+
+            if fail_on_bailout || fail_on_error || fail_on_change {
+                let code = compute_exit_code(
+                    &stats_result,
+                    fail_on_bailout,
+                    fail_on_error,
+                    fail_on_change,
+                    false,
+                );
+                process::exit(code);
+            }
+        }
+        Commands::Optimize {
+            code_dir,
             venv_path,
             output,
             create_slim_venv,
@@ -920,10 +2569,15 @@ fn main() -> anyhow::Result<()> {
             generate_reports,
             reports_dir,
             print_report,
+            sarif,
+            public_roots,
+            asserts_are_roots,
+            min_confidence,
             stats,
             jobs,
             dry_run,
         } => {
+            ctx.asserts_are_roots = asserts_are_roots.unwrap_or(true);
             optimize(
                 &code_dir,
                 &venv_path,
@@ -933,15 +2587,20 @@ fn main() -> anyhow::Result<()> {
                 generate_reports,
                 reports_dir,
                 print_report,
+                sarif.as_deref(),
+                &public_roots,
+                tsrs::Confidence::parse(&min_confidence).unwrap_or_default(),
                 stats,
                 jobs,
                 dry_run,
                 cli.quiet,
+                &ctx,
             )?;
         }
         Commands::ApplyPlanDir {
             input_dir,
             plan,
+            summary_only,
             out_dir,
             in_place,
             dry_run,
@@ -954,68 +2613,504 @@ fn main() -> anyhow::Result<()> {
             json,
             output_json,
             jobs,
+            max_memory,
             fail_on_bailout,
             fail_on_error,
             fail_on_change,
+            require_all_plans,
+            match_by,
             diff,
             diff_context,
+            diff_out,
+            embed_manifest,
+            manifest_format,
+            verify,
+            verify_exec,
             include_hidden,
             follow_symlinks,
             glob_case_insensitive,
             max_depth,
             respect_gitignore,
+            extensions,
+            no_tsrsignore,
+            no_default_excludes,
+            module_root,
+            max_file_size,
+            allow_partial_plan,
+            report_memory,
+            summary_by_dir,
+            top,
+            wait,
+            force_lock,
+            progress_json,
+            progress_file,
+            fuzzy_apply,
+            timings,
+            overwrite_changed,
         } => {
+            let config = load_config(&input_dir)?;
+            if summary_only {
+                let summary = summarize_plan_bundle(&input_dir, &plan)?;
+                let json = merge_flag(json, config.json);
+                print_bundle_summary(&summary, json, output_json.as_deref())?;
+                return Ok(());
+            }
+            let verify = verify || verify_exec.is_some();
+            let embed_manifest = embed_manifest.map(|layout| EmbedManifest {
+                per_package: layout == "per-package",
+                python: manifest_format == "py",
+            });
+            ctx.rewrite.fuzzy_ranges = fuzzy_apply;
+            ctx.timings = timings;
+            ctx.overwrite_changed = overwrite_changed;
+            ctx.max_memory_mb = max_memory.unwrap_or(0);
+            ctx.no_default_excludes = no_default_excludes;
+            if !module_root.is_empty() {
+                ctx.module_roots = resolve_module_roots(&input_dir, &module_root)?;
+            }
+            if progress_json {
+                ctx.progress = ProgressStream::open(progress_file.as_deref())?;
+            }
             let stats_result = apply_plan_dir_with_depth(
                 &input_dir,
                 &plan,
                 out_dir,
-                &include,
-                include_file.as_ref(),
-                &exclude,
-                exclude_file.as_ref(),
+                &merge_patterns(include, config.include),
+                include_file.or(config.include_file).as_ref(),
+                &merge_patterns(exclude, config.exclude),
+                exclude_file.or(config.exclude_file).as_ref(),
                 backup_ext.as_deref(),
                 in_place,
                 dry_run,
-                stats,
-                json,
-                include_hidden,
-                follow_symlinks,
-                glob_case_insensitive,
+                merge_flag(stats, config.stats),
+                merge_flag(json, config.json),
+                merge_flag(include_hidden, config.include_hidden),
+                merge_flag(follow_symlinks, config.follow_symlinks),
+                glob_case_insensitive.or(config.glob_case_insensitive),
                 cli.quiet,
                 output_json.as_deref(),
-                jobs,
+                jobs.or(config.jobs),
                 fail_on_bailout,
                 fail_on_error,
                 fail_on_change,
                 diff,
-                diff_context,
-                respect_gitignore,
-                max_depth,
+                diff_context.or(config.diff_context).unwrap_or(3),
+                merge_flag(respect_gitignore, config.respect_gitignore),
+                max_depth.or(config.max_depth),
+                max_file_size.or(config.max_file_size),
+                &merge_patterns(extensions, config.extensions),
+                no_tsrsignore,
+                diff_out.as_deref(),
+                embed_manifest,
+                verify,
+                verify_exec.as_deref(),
+                allow_partial_plan,
+                require_all_plans,
+                summary_by_dir,
+                top,
+                wait,
+                force_lock,
+                match_by == "module",
+                &ctx,
             )?;
 
-            if fail_on_bailout || fail_on_error || fail_on_change {
+            if report_memory {
+                report_peak_memory();
+            }
+
+            if fail_on_bailout || fail_on_error || fail_on_change || require_all_plans {
                 let code = compute_exit_code(
                     &stats_result,
                     fail_on_bailout,
                     fail_on_error,
                     fail_on_change,
+                    require_all_plans,
                 );
                 process::exit(code);
             }
         }
+        Commands::PlanShow { plan, file } => {
+            plan_show(&plan, file.as_deref())?;
+        }
+        Commands::PlanFilter {
+            plan,
+            out,
+            drop_function,
+            drop_rename,
+        } => {
+            plan_filter(&plan, &out, &drop_function, &drop_rename)?;
+        }
+        Commands::Config { show, path } => {
+            let start = path.unwrap_or_else(|| PathBuf::from("."));
+            show_config(&start, show)?;
+        }
+        Commands::Unmangle { source_map } => {
+            unmangle(&source_map)?;
+        }
+        Commands::StatsDiff {
+            old,
+            new,
+            json,
+            fail_on_regression,
+        } => {
+            let diff = diff_stats(&read_dir_stats(&old)?, &read_dir_stats(&new)?);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&diff)?);
+            } else {
+                print!("{}", diff.render());
+            }
+            if fail_on_regression && !diff.regressions.is_empty() {
+                process::exit(1);
+            }
+        }
+        Commands::Schema { format } => match format.as_str() {
+            "source-map" => print!("{}", SOURCE_MAP_SCHEMA),
+            "progress-events" => print!("{}", PROGRESS_EVENTS_SCHEMA),
+            other => bail!("unknown schema format '{}'", other),
+        },
+        Commands::SelfTest { corpus } => {
+            let mut cases = bundled_self_test_cases();
+            if let Some(dir) = &corpus {
+                cases.extend(load_self_test_corpus(dir, ctx.decode)?);
+            }
+            let results = run_self_test(&cases);
+            print!("{}", render_self_test(&results));
+            if results.iter().any(|result| result.failure.is_some()) {
+                process::exit(1);
+            }
+        }
     }
 
     Ok(())
 }
 
-fn analyze(venv_path: &PathBuf) -> anyhow::Result<()> {
-    println!("Analyzing venv at: {}", venv_path.display());
+fn load_config(start: &Path) -> anyhow::Result<Config> {
+    match Config::discover(start)? {
+        Some(loaded) => {
+            debug!("loaded configuration from {}", loaded.path.display());
+            Ok(loaded.config)
+        }
+        None => Ok(Config::default()),
+    }
+}
+
+/// An explicit `--flag` or `--flag=false` wins over the configured value.
+fn merge_flag(cli: Option<bool>, config: Option<bool>) -> bool {
+    cli.or(config).unwrap_or(false)
+}
+
+fn naming_mode(stable_names: bool) -> NamingMode {
+    if stable_names {
+        NamingMode::Stable
+    } else {
+        NamingMode::Sequential
+    }
+}
+
+/// Peak resident set size of this process, where the platform reports it.
+fn peak_memory_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+fn report_peak_memory() {
+    match peak_memory_bytes() {
+        Some(bytes) => eprintln!("peak memory: {} MiB", bytes / (1024 * 1024)),
+        None => eprintln!("peak memory: unavailable on this platform"),
+    }
+}
+
+fn merge_patterns(cli: Vec<String>, config: Option<Vec<String>>) -> Vec<String> {
+    if cli.is_empty() {
+        config.unwrap_or_default()
+    } else {
+        cli
+    }
+}
+
+fn show_config(start: &Path, show: bool) -> anyhow::Result<()> {
+    let loaded = Config::discover(start)?;
+    let (source, config) = match loaded {
+        Some(loaded) => (Some(loaded.path), loaded.config),
+        None => (None, Config::default()),
+    };
+
+    match &source {
+        Some(path) => println!("# Loaded from {}", path.display()),
+        None => println!("# No tsrs.toml or [tool.tsrs] found; showing defaults"),
+    }
+
+    if show {
+        print!("{}", config.with_defaults().to_toml()?);
+    }
+
+    Ok(())
+}
+
+fn source_map_display_path(path: &Path) -> String {
+    let cleaned: PathBuf = path
+        .components()
+        .filter(|component| !matches!(component, std::path::Component::CurDir))
+        .collect();
+    normalize_rel_path(&cleaned)
+}
+
+fn write_source_map(path: &Path, map: &SourceMap) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+    }
+    fs::write(path, map.to_json()?)
+        .with_context(|| format!("failed to write source map {}", path.display()))?;
+    debug!("wrote source map {}", path.display());
+    Ok(())
+}
+
+fn write_file_source_map(
+    target: &Path,
+    python_file: &Path,
+    entry: FileSourceMap,
+) -> anyhow::Result<()> {
+    let map_path = if target.is_dir() {
+        let file_name = python_file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "stdin".to_string());
+        target.join(format!("{}.map.json", file_name))
+    } else {
+        target.to_path_buf()
+    };
+    write_source_map(&map_path, &SourceMap::new(vec![entry]))
+}
+
+fn write_dir_source_maps(target: &Path, maps: Vec<FileSourceMap>) -> anyhow::Result<()> {
+    let single_file = target
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("json"))
+        == Some(true);
+
+    if single_file {
+        write_source_map(target, &SourceMap::new(maps))
+    } else {
+        for entry in maps {
+            let map_path = target.join(format!("{}.map.json", entry.path));
+            write_source_map(&map_path, &SourceMap::new(vec![entry]))?;
+        }
+        Ok(())
+    }
+}
+
+/// Base name of the rename manifests `--embed-manifest` writes into the output tree
+const MANIFEST_STEM: &str = "_tsrs_manifest";
 
+/// Assignment that precedes the dict literal in a `--manifest-format py` manifest
+const MANIFEST_PY_ASSIGNMENT: &str = "MANIFEST = ";
+
+/// Where `--embed-manifest` writes its manifests and in which format
+#[derive(Clone, Copy, Debug)]
+struct EmbedManifest {
+    /// One manifest inside each top-level package directory instead of one at the root
+    per_package: bool,
+    /// A Python module defining `MANIFEST` instead of JSON
+    python: bool,
+}
+
+impl EmbedManifest {
+    fn file_name(self) -> String {
+        let extension = if self.python { "py" } else { "json" };
+        format!("{MANIFEST_STEM}.{extension}")
+    }
+
+    /// Directory of the manifest covering `rel_norm` (empty for the output root) and the
+    /// path of `rel_norm` relative to it. Files outside any package go in the root one.
+    fn locate(self, rel_norm: &str) -> (&str, &str) {
+        match rel_norm.split_once('/') {
+            Some((package, rest)) if self.per_package => (package, rest),
+            _ => ("", rel_norm),
+        }
+    }
+}
+
+/// Whether `path` names a manifest `--embed-manifest` writes.
+fn is_manifest_file(path: &Path) -> bool {
+    path.file_stem().is_some_and(|stem| stem == MANIFEST_STEM)
+        && path
+            .extension()
+            .is_some_and(|ext| ext == "json" || ext == "py")
+}
+
+/// An `--embed-manifest` manifest: applied renames (renamed → original) of each covered
+/// file, keyed by its path relative to the manifest and then by function.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct RenameManifest {
+    tsrs_version: String,
+    files: BTreeMap<String, BTreeMap<String, BTreeMap<String, String>>>,
+}
+
+impl RenameManifest {
+    fn read(path: &Path, python: bool) -> anyhow::Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        // The dict literal of a Python manifest is written as JSON, so both parse alike.
+        let json = if python {
+            text.split_once(MANIFEST_PY_ASSIGNMENT)
+                .map_or("", |(_, literal)| literal)
+        } else {
+            text.as_str()
+        };
+        serde_json::from_str(json)
+            .map(Some)
+            .with_context(|| format!("invalid manifest {}", path.display()))
+    }
+
+    fn render(&self, python: bool) -> anyhow::Result<String> {
+        let json = serde_json::to_string_pretty(self)?;
+        Ok(if python {
+            format!(
+                "# Generated by tsrs --embed-manifest: applied renames (renamed -> original).\n{MANIFEST_PY_ASSIGNMENT}{json}\n"
+            )
+        } else {
+            format!("{json}\n")
+        })
+    }
+}
+
+/// Paths of the files a pass processed, for [`write_manifests`]; empty without
+/// `--embed-manifest`.
+fn manifest_files(embed_manifest: Option<EmbedManifest>, results: &[FileResult]) -> Vec<String> {
+    if embed_manifest.is_none() {
+        return Vec::new();
+    }
+    results
+        .iter()
+        .map(|result| result.candidate.rel_norm.clone())
+        .collect()
+}
+
+/// Write the `--embed-manifest` manifests covering `processed` under `out_dir`.
+///
+/// Manifests already on disk are updated rather than replaced: entries of the processed
+/// files are swapped for their applied renames in `maps`, and entries whose output file is
+/// gone are dropped, so watch passes keep them current.
+fn write_manifests(
+    out_dir: &Path,
+    embed: EmbedManifest,
+    processed: &[String],
+    maps: &[FileSourceMap],
+) -> anyhow::Result<()> {
+    let file_name = embed.file_name();
+    let mut dirs: BTreeSet<&str> = processed
+        .iter()
+        .map(|rel_norm| embed.locate(rel_norm).0)
+        .collect();
+    if !embed.per_package {
+        dirs.insert("");
+    }
+
+    for dir in dirs {
+        let manifest_dir = out_dir.join(dir);
+        let path = manifest_dir.join(&file_name);
+        let mut manifest = RenameManifest::read(&path, embed.python)?.unwrap_or_default();
+        manifest.tsrs_version = env!("CARGO_PKG_VERSION").to_string();
+        manifest
+            .files
+            .retain(|file, _| manifest_dir.join(file).is_file());
+        for rel_norm in processed {
+            let (owner, file) = embed.locate(rel_norm);
+            if owner == dir {
+                manifest.files.remove(file);
+            }
+        }
+        for map in maps.iter().filter(|map| !map.functions.is_empty()) {
+            let (owner, file) = embed.locate(&map.path);
+            if owner == dir {
+                manifest
+                    .files
+                    .insert(file.to_string(), map.functions.clone());
+            }
+        }
+
+        fs::create_dir_all(&manifest_dir)
+            .with_context(|| format!("failed to create directory {}", manifest_dir.display()))?;
+        fs::write(&path, manifest.render(embed.python)?)
+            .with_context(|| format!("failed to write manifest {}", path.display()))?;
+        debug!("wrote rename manifest {}", path.display());
+    }
+    Ok(())
+}
+
+fn load_source_maps(paths: &[PathBuf]) -> anyhow::Result<SourceMap> {
+    let mut combined = SourceMap::new(Vec::new());
+    for path in paths {
+        if path.is_dir() {
+            let mut map_files: Vec<PathBuf> = walkdir::WalkDir::new(path)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file())
+                .map(|entry| entry.into_path())
+                .filter(|p| p.to_string_lossy().ends_with(".map.json"))
+                .collect();
+            map_files.sort();
+            for map_file in map_files {
+                let json = fs::read_to_string(&map_file)
+                    .with_context(|| format!("failed to read {}", map_file.display()))?;
+                combined.extend(
+                    SourceMap::from_json(&json)
+                        .with_context(|| format!("invalid source map {}", map_file.display()))?,
+                );
+            }
+        } else {
+            let json = fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            combined.extend(
+                SourceMap::from_json(&json)
+                    .with_context(|| format!("invalid source map {}", path.display()))?,
+            );
+        }
+    }
+    Ok(combined)
+}
+
+fn unmangle(source_maps: &[PathBuf]) -> anyhow::Result<()> {
+    let map = load_source_maps(source_maps)?;
+    let mut traceback = String::new();
+    std::io::stdin().read_to_string(&mut traceback)?;
+    print!("{}", map.unmangle_traceback(&traceback));
+    Ok(())
+}
+
+fn analyze(venv_path: &PathBuf, json: bool) -> anyhow::Result<()> {
     let analyzer = VenvAnalyzer::new(venv_path)?;
     let info = analyzer.analyze()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    println!("Analyzing venv at: {}", venv_path.display());
 
     println!("\nVenv Information:");
     println!("  Path: {}", info.path.display());
+    let layout = match info.layout {
+        EnvironmentKind::Venv => "venv",
+        EnvironmentKind::Conda => "conda env",
+        EnvironmentKind::SitePackages => "site-packages directory",
+    };
+    println!("  Layout: {layout}");
+    for site_packages in &info.site_packages {
+        println!("  Site-packages: {}", site_packages.display());
+    }
     if let Some(version) = info.python_version {
         println!("  Python Version: {}", version);
     }
@@ -1027,38 +3122,502 @@ fn analyze(venv_path: &PathBuf) -> anyhow::Result<()> {
         } else {
             println!("  - {}", package.name);
         }
+        print_package_notes(
+            package.editable,
+            package.editable_target.as_deref(),
+            &package.namespace_distributions,
+        );
+        if let Some(license) = package
+            .metadata
+            .as_ref()
+            .and_then(PackageMetadata::license_name)
+        {
+            println!("      license: {license}");
+        }
     }
 
     Ok(())
 }
 
-fn slim(code_path: &PathBuf, venv_path: &PathBuf, output: Option<PathBuf>) -> anyhow::Result<()> {
+/// Flag editable installs and namespace packages under a package line.
+fn print_package_notes(editable: bool, editable_target: Option<&Path>, namespace: &[String]) {
+    if editable {
+        match editable_target {
+            Some(target) => println!("      editable install from {}", target.display()),
+            None => println!("      editable install"),
+        }
+    }
+    if !namespace.is_empty() {
+        println!("      namespace package from {}", namespace.join(", "));
+    }
+}
+
+fn slim(
+    code_paths: &[PathBuf],
+    venv_path: &PathBuf,
+    output: Option<PathBuf>,
+    report_path: Option<&Path>,
+    options: SlimOptions,
+) -> anyhow::Result<()> {
+    let zipapp = options.format == SlimFormat::Zipapp;
     let output_path = output.unwrap_or_else(|| {
         let parent = venv_path
             .parent()
             .map(|p| p.to_path_buf())
             .unwrap_or_else(|| std::env::current_dir().unwrap_or_default());
         let mut path = parent;
-        path.push(".venv-slim");
+        path.push(if zipapp { "slim.pyz" } else { ".venv-slim" });
         path
     });
 
-    println!("Creating slim venv...");
-    println!("  Code directory: {}", code_path.display());
+    println!(
+        "Creating slim {}...",
+        if zipapp { "zipapp" } else { "venv" }
+    );
+    for code_path in code_paths {
+        println!("  Code directory: {}", code_path.display());
+    }
     println!("  Source venv: {}", venv_path.display());
-    println!("  Output venv: {}", output_path.display());
+    println!("  Output: {}", output_path.display());
+
+    let slimmer =
+        VenvSlimmer::new_with_roots(code_paths, venv_path, &output_path)?.with_options(options);
+    let report = slimmer.slim()?;
+    if !report.first_party.is_empty() {
+        println!("  First-party modules: {}", report.first_party.join(", "));
+    }
+    if !report.scan_errors.is_empty() {
+        println!(
+            "  Files that failed to parse: {} (their imports keep nothing)",
+            report.scan_errors.len()
+        );
+    }
+    if !report.unresolved.is_empty() {
+        println!(
+            "  Imports no venv package provides: {}",
+            report.unresolved.join(", ")
+        );
+    }
+
+    let decision_rule = |name: &str, kind: SlimDecision| {
+        report
+            .decisions
+            .iter()
+            .find(|d| d.name == name && d.decision == kind)
+            .map(|d| d.rule.clone().unwrap_or_default())
+    };
+
+    println!("\nKept packages: {}", report.kept.len());
+    for package in &report.kept {
+        let site = package
+            .imported_at
+            .as_ref()
+            .map(|site| format!("e.g. {}", site));
+        if let Some(rule) = decision_rule(&package.name, SlimDecision::KeptByPolicy) {
+            println!("  - {} (kept by policy: {})", package.name, rule);
+        } else if code_paths.len() > 1 {
+            let roots: Vec<String> = package
+                .roots
+                .iter()
+                .map(|root| root.display().to_string())
+                .collect();
+            match site {
+                Some(site) => println!(
+                    "  - {} (imported by: {}; {})",
+                    package.name,
+                    roots.join(", "),
+                    site
+                ),
+                None => println!("  - {} (imported by: {})", package.name, roots.join(", ")),
+            }
+        } else {
+            match site {
+                Some(site) => println!("  - {} ({})", package.name, site),
+                None => println!("  - {}", package.name),
+            }
+        }
+        print_package_notes(
+            package.editable,
+            package.editable_target.as_deref(),
+            &package.namespace_distributions,
+        );
+    }
+
+    if !report.licenses.is_empty() {
+        println!("\nLicenses:");
+        for (license, count) in &report.licenses.counts {
+            println!("  {count:>4}  {license}");
+        }
+        if !report.licenses.unknown.is_empty() {
+            println!("  Unknown license: {}", report.licenses.unknown.join(", "));
+        }
+    }
+
+    let dropped: Vec<&PackageDecision> = report
+        .decisions
+        .iter()
+        .filter(|d| d.decision == SlimDecision::DroppedByPolicy)
+        .collect();
+    if !dropped.is_empty() {
+        println!("\nDropped by policy: {}", dropped.len());
+        for decision in dropped {
+            let rule = decision.rule.as_deref().unwrap_or_default();
+            if decision.required_by.is_empty() {
+                println!("  - {} ({})", decision.name, rule);
+            } else {
+                println!(
+                    "  - {} ({}; required by {})",
+                    decision.name,
+                    rule,
+                    decision.required_by.join(", ")
+                );
+            }
+        }
+    }
+
+    for step in &report.steps {
+        match &step.note {
+            Some(note) => println!("{}: {} bytes saved ({})", step.step, step.bytes_saved, note),
+            None => println!("{}: {} bytes saved", step.step, step.bytes_saved),
+        }
+    }
+
+    if let Some(verification) = &report.verification {
+        println!(
+            "\nImport check: {} passed, {} failed",
+            verification.passed.len(),
+            verification.failed.len()
+        );
+        for module in &verification.passed {
+            println!("  ok   {}", module);
+        }
+        for failed in &verification.failed {
+            println!("  FAIL {}: {}", failed.module, failed.error);
+        }
+        if !verification.skipped.is_empty() {
+            println!("  Skipped: {}", verification.skipped.join(", "));
+        }
+    }
+
+    if let Some(path) = report_path {
+        let mut json = serde_json::to_string_pretty(&report)?;
+        json.push('\n');
+        fs::write(path, json)
+            .with_context(|| format!("failed to write slim report {}", path.display()))?;
+    }
 
-    let slimmer = VenvSlimmer::new_with_output(code_path, venv_path, &output_path)?;
-    slimmer.slim()?;
+    if let Some(verification) = &report.verification {
+        if !verification.failed.is_empty() {
+            bail!(
+                "{} kept module(s) failed to import in {}",
+                verification.failed.len(),
+                output_path.display()
+            );
+        }
+    }
 
-    println!("\nSlim venv created successfully!");
+    println!(
+        "\nSlim {} created successfully!",
+        if zipapp { "zipapp" } else { "venv" }
+    );
     println!("Output: {}", output_path.display());
 
     Ok(())
 }
 
-fn optimize(
-    code_dir: &PathBuf,
+/// `tsrs imports` output: the packages a tree imports plus per-file detail.
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportsReport {
+    /// Top-level imported packages, sorted and de-duplicated.
+    packages: Vec<ImportedPackage>,
+    /// Imports of each scanned file, sorted by path.
+    files: Vec<FileImports>,
+    /// Files that could not be read or parsed.
+    errors: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportedPackage {
+    name: String,
+    stdlib: bool,
+    /// Whether the `--venv` environment provides the package; absent without `--venv`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    in_venv: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FileImports {
+    path: String,
+    /// Absolute and relative imports in source order.
+    imports: Vec<DetailedImport>,
+}
+
+/// Scan a directory tree with the usual walker options and collect its imports.
+#[allow(clippy::too_many_arguments)]
+/// Import graph of the modules under `input_dir` that the globs select; files that fail
+/// to parse are logged and left out.
+fn build_import_graph(
+    input_dir: &Path,
+    includes: &[String],
+    excludes: &[String],
+    include_deferred: bool,
+    ctx: &CommandContext,
+) -> anyhow::Result<ImportGraph> {
+    let input_dir = canonicalize_directory(input_dir)?;
+    if !input_dir.is_dir() {
+        anyhow::bail!("Input '{}' is not a directory", input_dir.display());
+    }
+    let root_package = input_dir
+        .join("__init__.py")
+        .is_file()
+        .then(|| input_dir.file_name().and_then(OsStr::to_str))
+        .flatten();
+
+    let walk = candidate_walker(
+        &input_dir,
+        includes,
+        None,
+        excludes,
+        None,
+        &[],
+        false,
+        false,
+        None,
+        None,
+        false,
+        false,
+        ctx.no_default_excludes,
+    )
+    .walk()?;
+    let mut modules = Vec::new();
+    for candidate in walk.candidates {
+        let Some((module, is_package)) =
+            ModuleImports::module_name(&candidate.rel_norm, root_package)
+        else {
+            debug!("{} is not an importable module", candidate.rel_norm);
+            continue;
+        };
+        let scanned = read_python(&candidate.abs_path, ctx.decode).and_then(|(source, _)| {
+            ModuleImports::from_source(&module, &candidate.rel_norm, is_package, &source)
+                .map_err(anyhow::Error::new)
+        });
+        match scanned {
+            Ok(imports) => modules.push(imports),
+            Err(err) => error!("failed to scan {}: {}", candidate.abs_path.display(), err),
+        }
+    }
+    Ok(ImportGraph::build(&modules, include_deferred))
+}
+
+#[derive(Serialize)]
+struct ImportGraphOutput<'a> {
+    #[serde(flatten)]
+    graph: &'a ImportGraph,
+    cycles: &'a [ImportCycle],
+}
+
+fn render_call_graph(graph: &PackageCallGraph) -> String {
+    let mut out = format!(
+        "{} functions, {} calls between them\n",
+        graph.nodes().count(),
+        graph.edges().count()
+    );
+    for (caller, callee, site) in graph.edges() {
+        let (Some(caller), Some(callee)) = (graph.node(caller), graph.node(callee)) else {
+            continue;
+        };
+        out.push_str(&format!(
+            "  {} -> {} (line {})\n",
+            caller.name, callee.name, site.location.line
+        ));
+    }
+    out
+}
+
+fn render_import_graph(graph: &ImportGraph, cycles: &[ImportCycle]) -> String {
+    let mut out = format!(
+        "{} modules, {} imports between them\n",
+        graph.modules.len(),
+        graph.edges.len()
+    );
+    if cycles.is_empty() {
+        out.push_str("No import cycles\n");
+    }
+    for (number, cycle) in cycles.iter().enumerate() {
+        out.push_str(&format!(
+            "Import cycle {}: {}\n",
+            number + 1,
+            cycle.modules.join(", ")
+        ));
+        for edge in &cycle.edges {
+            let sites: Vec<String> = edge.sites.iter().map(ToString::to_string).collect();
+            out.push_str(&format!(
+                "  {} -> {} ({})\n",
+                edge.from,
+                edge.to,
+                sites.join(", ")
+            ));
+        }
+    }
+    out
+}
+
+fn collect_imports_report(
+    input_dir: &Path,
+    includes: &[String],
+    include_file: Option<&PathBuf>,
+    excludes: &[String],
+    exclude_file: Option<&PathBuf>,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    glob_case_insensitive: Option<bool>,
+    max_depth: Option<usize>,
+    respect_gitignore: bool,
+    extensions: &[String],
+    no_tsrsignore: bool,
+    third_party_only: bool,
+    venv: Option<&Path>,
+    python_version: Option<PythonVersion>,
+    ctx: &CommandContext,
+) -> anyhow::Result<ImportsReport> {
+    let input_dir = canonicalize_directory(input_dir)?;
+    if !input_dir.is_dir() {
+        anyhow::bail!("Input '{}' is not a directory", input_dir.display());
+    }
+
+    // Same matching as `slim`: a package directory or dist-info named `name-...`
+    // provides the import `name`.
+    let venv_info = match venv {
+        Some(path) => Some(VenvAnalyzer::new(path)?.analyze()?),
+        None => None,
+    };
+    let venv_packages: Option<HashSet<String>> = venv_info.as_ref().map(|info| {
+        info.packages
+            .iter()
+            .map(|package| {
+                let name = package.name.split('-').next().unwrap_or(&package.name);
+                name.trim_end_matches(".py").to_string()
+            })
+            .collect()
+    });
+    let python_version = python_version.or_else(|| {
+        venv_info
+            .as_ref()
+            .and_then(|info| info.python_version.as_deref())
+            .and_then(PythonVersion::parse)
+    });
+    let is_stdlib = |module: &str| {
+        let top_level = module.split('.').next().unwrap_or(module);
+        python_version.map_or_else(
+            || is_stdlib_module(top_level),
+            |version| stdlib::is_stdlib(top_level, version),
+        ) && !venv_packages
+            .as_ref()
+            .is_some_and(|packages| packages.contains(top_level))
+    };
+
+    let walk = candidate_walker(
+        &input_dir,
+        includes,
+        include_file,
+        excludes,
+        exclude_file,
+        extensions,
+        include_hidden,
+        follow_symlinks,
+        glob_case_insensitive,
+        max_depth,
+        respect_gitignore,
+        no_tsrsignore,
+        ctx.no_default_excludes,
+    )
+    .walk()?;
+    let mut errors = walk.errors;
+    let candidates = walk.candidates;
+
+    let mut packages: BTreeSet<String> = BTreeSet::new();
+    let mut files = Vec::new();
+    for candidate in candidates {
+        let mut collector = ImportCollector::new();
+        let collected = read_python(&candidate.abs_path, ctx.decode).and_then(|(source, _)| {
+            collector
+                .collect_from_source(&source)
+                .map_err(|err| anyhow::Error::new(err.with_file(&candidate.abs_path)))
+        });
+        if let Err(err) = collected {
+            errors += 1;
+            error!("failed to scan {}: {}", candidate.abs_path.display(), err);
+            continue;
+        }
+
+        let mut imports: Vec<DetailedImport> = collector
+            .get_detailed_imports()
+            .into_iter()
+            .filter(|import| !(third_party_only && is_stdlib(&import.module)))
+            .chain(collector.get_relative_imports())
+            .collect();
+        imports.sort_by_key(|import| import.lineno);
+
+        packages.extend(
+            collector
+                .get_imports()
+                .get_imports()
+                .into_iter()
+                .filter(|name| !(third_party_only && is_stdlib(name))),
+        );
+        files.push(FileImports {
+            path: candidate.rel_norm,
+            imports,
+        });
+    }
+
+    let packages = packages
+        .into_iter()
+        .map(|name| ImportedPackage {
+            stdlib: is_stdlib(&name),
+            in_venv: venv_packages
+                .as_ref()
+                .map(|provided| provided.contains(&name)),
+            name,
+        })
+        .collect();
+
+    Ok(ImportsReport {
+        packages,
+        files,
+        errors,
+    })
+}
+
+/// Parse a `--python-version` value such as `3.11`.
+fn python_version_arg(value: Option<&str>) -> anyhow::Result<Option<PythonVersion>> {
+    value
+        .map(|text| {
+            PythonVersion::parse(text)
+                .with_context(|| format!("invalid --python-version '{text}' (expected X.Y)"))
+        })
+        .transpose()
+}
+
+/// Print one package per line (flagging ones `--venv` lacks), or the full report as JSON.
+fn print_imports_report(report: &ImportsReport, json: bool) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(report)?);
+        return Ok(());
+    }
+
+    for package in &report.packages {
+        if package.in_venv == Some(false) && !package.stdlib {
+            println!("{} (not in venv)", package.name);
+        } else {
+            println!("{}", package.name);
+        }
+    }
+    Ok(())
+}
+
+fn optimize(
+    code_dir: &PathBuf,
     venv_path: &PathBuf,
     output: Option<PathBuf>,
     create_slim_venv: bool,
@@ -1066,10 +3625,14 @@ fn optimize(
     generate_reports: bool,
     reports_dir: Option<PathBuf>,
     print_report: bool,
+    sarif: Option<&Path>,
+    public_roots: &[String],
+    min_confidence: tsrs::Confidence,
     stats: bool,
     jobs: Option<usize>,
     dry_run: bool,
     quiet: bool,
+    ctx: &CommandContext,
 ) -> anyhow::Result<()> {
     let output_dir = output.unwrap_or_else(|| {
         let mut path = code_dir
@@ -1094,7 +3657,8 @@ fn optimize(
         println!("Step 1: Analyzing code structure...");
     }
 
-    let mut analyzer = CallGraphAnalyzer::new();
+    let mut analyzer = call_graph_analyzer(ctx);
+    analyzer.set_public_roots(public_roots)?;
 
     // Scan all Python files and build call graph
     for entry in walkdir::WalkDir::new(code_dir)
@@ -1102,27 +3666,44 @@ fn optimize(
         .filter_map(|e| e.ok())
         .filter(|e| e.path().extension().and_then(|s| s.to_str()) == Some("py"))
     {
-        if let Ok((source, _)) = read_python(entry.path()) {
-            let package = entry
-                .path()
-                .strip_prefix(code_dir)
-                .ok()
+        if let Ok((source, _)) = read_python(entry.path(), ctx.decode) {
+            let relative = entry.path().strip_prefix(code_dir).ok();
+            let package = relative
                 .and_then(|p| p.parent())
                 .map(|p| p.display().to_string())
                 .unwrap_or_else(|| "root".to_string());
+            let file =
+                relative.map_or_else(|| entry.path().display().to_string(), normalize_rel_path);
 
-            let _ = analyzer.analyze_source(&package, &source);
+            let _ = analyzer.analyze_source_in_file(&package, &source, &file);
         }
     }
 
-    let dead_code = analyzer.find_dead_code();
+    let dead_code: Vec<_> = analyzer
+        .find_dead_code()
+        .into_iter()
+        .filter(|(id, _)| analyzer.dead_code_confidence(*id) >= min_confidence)
+        .collect();
+    let suppressed = analyzer.find_suppressed_dead_code();
+    let roots = analyzer.find_declared_roots();
     let reachable_count = analyzer.compute_reachable().len();
 
     if !quiet && !dead_code.is_empty() {
-        println!("  ✓ Found {} dead functions", dead_code.len());
+        let (methods, functions) = split_dead_methods(&analyzer, &dead_code);
+        println!(
+            "  ✓ Found {} dead functions and {} dead methods",
+            functions.len(),
+            methods.len()
+        );
     }
     if !quiet {
         println!("  ✓ {} reachable functions", reachable_count);
+        if !roots.is_empty() {
+            println!(
+                "  ✓ {} uncalled functions kept live as re-exports or public roots",
+                roots.len()
+            );
+        }
     }
 
     // Step 2: Create slim venv if requested
@@ -1182,6 +3763,30 @@ fn optimize(
                 false,
                 None,
                 true,
+                false,
+                false,
+                false,
+                false,
+                None,
+                &[],
+                false,
+                None,
+                false,
+                None,
+                None,
+                None,
+                false,
+                None,
+                &TransformPipeline::new(),
+                false,
+                true,
+                None,
+                None,
+                None,
+                false,
+                OutDirMode::Fresh,
+                None,
+                ctx,
             )?;
         }
 
@@ -1234,7 +3839,10 @@ fn optimize(
                     .map(|(s, _)| s)
                     .collect::<Vec<_>>(),
                 all_exports,
-            );
+            )
+            .with_dead_details(dead_code_details(&analyzer, &dead_code))
+            .with_suppressed(suppressed_report_entries(&suppressed))
+            .with_roots(root_report_entries(&roots));
 
             // Write JSON report
             let json_path = report_dir.join("dead_code.json");
@@ -1261,12 +3869,41 @@ fn optimize(
                 vec![],
                 vec![],
                 vec![],
-            );
+            )
+            .with_dead_details(dead_code_details(&analyzer, &dead_code))
+            .with_suppressed(suppressed_report_entries(&suppressed))
+            .with_roots(root_report_entries(&roots));
 
             println!("\n{}", report.to_json());
         }
     }
 
+    if let Some(path) = sarif {
+        let report = tsrs::DeadCodeReport::new(
+            "root".to_string(),
+            reachable_count + dead_code.len(),
+            dead_code
+                .iter()
+                .map(|(_, name)| (name.clone(), "Unreachable from entry points".to_string()))
+                .collect(),
+            vec![],
+            vec![],
+            vec![],
+        )
+        .with_dead_locations(
+            dead_code
+                .iter()
+                .map(|(id, _)| dead_code_location(&analyzer, *id))
+                .collect(),
+        )
+        .with_dead_details(dead_code_details(&analyzer, &dead_code));
+        fs::write(path, report.to_sarif())
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        if !quiet {
+            println!("  ✓ SARIF report: {}", path.display());
+        }
+    }
+
     if !quiet {
         println!("\n✅ Optimization complete!");
         println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
@@ -1279,38 +3916,114 @@ fn optimize(
     Ok(())
 }
 
-fn minify_plan(file_path: &PathBuf) -> anyhow::Result<()> {
-    let (source, _) = read_python(file_path)?;
-    let module_name = file_path
-        .file_stem()
-        .and_then(|stem| stem.to_str())
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| file_path.to_string_lossy().to_string());
+/// Print the plan for `file_path`, or for source read from stdin when it is `None`.
+///
+/// With `combined`, the source bytes are echoed unchanged and the plan JSON follows them
+/// directly, which is the layout `apply-plan --stdin --plan-stdin` splits apart.
+fn minify_plan(
+    file_path: Option<&Path>,
+    module_name: Option<&str>,
+    combined: bool,
+    naming: NamingMode,
+    rename_module_privates: bool,
+    preserve_annotated_locals: bool,
+    ctx: &CommandContext,
+) -> anyhow::Result<()> {
+    let bytes = match file_path {
+        Some(path) => {
+            fs::read(path).with_context(|| format!("failed to read {}", path.display()))?
+        }
+        None => {
+            let mut buffer = Vec::new();
+            std::io::stdin().read_to_end(&mut buffer)?;
+            buffer
+        }
+    };
+    let label = file_path.map_or_else(|| "stdin".to_string(), |path| path.display().to_string());
+    let (source, _) = decode_python_bytes(&bytes, &label, ctx.decode)?;
+    let module_name = match (module_name, file_path) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(path)) => path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| path.to_string_lossy().to_string()),
+        (None, None) => "stdin".to_string(),
+    };
 
-    let plan = Minifier::plan_from_source(&module_name, &source)?;
+    let plan = Minifier::plan_from_source_with_options(
+        &module_name,
+        &source,
+        plan_options(
+            file_path.unwrap_or(Path::new("stdin")),
+            &ctx.plan,
+            naming,
+            rename_module_privates,
+            preserve_annotated_locals,
+        ),
+    )?;
+    log_plan_warnings(&plan, &module_name);
     let plan_json = serde_json::to_string_pretty(&plan)?;
-    println!("{}", plan_json);
+    let mut stdout = std::io::stdout().lock();
+    if combined {
+        stdout.write_all(&bytes)?;
+    }
+    writeln!(stdout, "{}", plan_json)?;
 
     Ok(())
 }
 
+fn root_report_entries(
+    roots: &[(tsrs::callgraph::FunctionId, String, RootReason)],
+) -> Vec<(String, String)> {
+    roots
+        .iter()
+        .map(|(_, name, reason)| (name.clone(), reason.describe().to_string()))
+        .collect()
+}
+
+fn suppressed_report_entries(
+    suppressed: &[(tsrs::callgraph::FunctionId, String)],
+) -> Vec<(String, String)> {
+    suppressed
+        .iter()
+        .map(|(_, name)| (name.clone(), "Kept by # tsrs: keep".to_string()))
+        .collect()
+}
+
 /// Detect and report dead code in Python source
+///
+/// Functions marked with `# tsrs: keep` on their `def` or decorator line are never
+/// returned, so [`filter_plan_for_dead_code`] leaves them in the plan.
 fn detect_dead_code(
     source: &str,
     package_name: &str,
     quiet: bool,
+    ctx: &CommandContext,
 ) -> anyhow::Result<Vec<(usize, String)>> {
-    let mut analyzer = CallGraphAnalyzer::new();
+    let mut analyzer = call_graph_analyzer(ctx);
     analyzer.analyze_source(package_name, source)?;
 
     let dead_code = analyzer.find_dead_code();
 
     if !dead_code.is_empty() && !quiet {
-        info!("Found {} unreachable function(s):", dead_code.len());
-        for (_, func_name) in &dead_code {
-            info!("  - {}", func_name);
+        let (methods, functions) = split_dead_methods(&analyzer, &dead_code);
+        if !functions.is_empty() {
+            info!("Found {} unreachable function(s):", functions.len());
+            for func_name in &functions {
+                info!("  - {}", func_name);
+            }
+        }
+        if !methods.is_empty() {
+            info!("Found {} unreachable method(s):", methods.len());
+            for method_name in &methods {
+                info!("  - {}", method_name);
+            }
         }
     }
+    for (_, func_name) in analyzer.find_suppressed_dead_code() {
+        debug!("  - {} kept by # tsrs: keep", func_name);
+    }
 
     // Convert FunctionId to usize for return
     let result = dead_code
@@ -1321,6 +4034,68 @@ fn detect_dead_code(
     Ok(result)
 }
 
+/// Split dead code names into (methods, functions) using each node's enclosing class
+fn split_dead_methods<'a>(
+    analyzer: &CallGraphAnalyzer,
+    dead_code: &'a [(tsrs::callgraph::FunctionId, String)],
+) -> (Vec<&'a str>, Vec<&'a str>) {
+    let mut methods = Vec::new();
+    let mut functions = Vec::new();
+    for (id, name) in dead_code {
+        let in_class = analyzer
+            .get_nodes()
+            .get(id)
+            .is_some_and(|node| node.class.is_some());
+        if in_class {
+            methods.push(name.as_str());
+        } else {
+            functions.push(name.as_str());
+        }
+    }
+    (methods, functions)
+}
+
+/// Where the call graph saw `id` defined, if it came from a file
+fn dead_code_location(
+    analyzer: &CallGraphAnalyzer,
+    id: tsrs::callgraph::FunctionId,
+) -> Option<tsrs::FindingLocation> {
+    let node = analyzer.get_nodes().get(&id)?;
+    Some(tsrs::FindingLocation {
+        file: node.file.clone()?,
+        line: node.location.line,
+        column: node.location.col,
+    })
+}
+
+/// Confidence and removal span of each of `dead_code`, in order
+fn dead_code_details(
+    analyzer: &CallGraphAnalyzer,
+    dead_code: &[(tsrs::callgraph::FunctionId, String)],
+) -> Vec<(tsrs::Confidence, Option<tsrs::RemovalSpan>)> {
+    dead_code
+        .iter()
+        .map(|(id, _)| {
+            let span = analyzer
+                .get_nodes()
+                .get(id)
+                .and_then(|node| node.removal_span);
+            (analyzer.dead_code_confidence(*id), span)
+        })
+        .collect()
+}
+
+/// Write `findings` to `path` as a SARIF 2.1.0 log
+fn write_sarif(path: &Path, findings: &[tsrs::SarifFinding]) -> anyhow::Result<()> {
+    fs::write(path, tsrs::sarif_log(findings))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// SARIF rule id for a bailout reason such as `match_statement`
+fn bailout_rule(reason: &str) -> String {
+    format!("tsrs/bailout-{}", reason.replace('_', "-"))
+}
+
 /// Filter a MinifyPlan to exclude dead code functions
 fn filter_plan_for_dead_code(mut plan: MinifyPlan, dead_code: &[(usize, String)]) -> MinifyPlan {
     // Create set of dead function names for fast lookup
@@ -1335,8 +4110,9 @@ fn filter_plan_for_dead_code(mut plan: MinifyPlan, dead_code: &[(usize, String)]
             .last()
             .unwrap_or(&func.qualified_name);
 
-        // Keep function if it's not in the dead code list
-        !dead_names.contains(simple_name)
+        // Keep function if it's not in the dead code list; methods are reported
+        // class-qualified, which matches the plan's qualified name
+        !dead_names.contains(simple_name) && !dead_names.contains(func.qualified_name.as_str())
     });
 
     plan
@@ -1392,6 +4168,11 @@ fn apply_plan(
     diff: bool,
     diff_context: usize,
     force_stdout: bool,
+    diff_out: Option<&Path>,
+    verify: bool,
+    verify_exec: Option<&Path>,
+    allow_partial_plan: bool,
+    ctx: &CommandContext,
 ) -> anyhow::Result<(DirStats, Option<Vec<u8>>)> {
     if json_output && !show_stats {
         anyhow::bail!("--json requires --stats");
@@ -1399,8 +4180,12 @@ fn apply_plan(
 
     let plan_file = fs::read_to_string(plan_path)?;
     let plan: MinifyPlan = serde_json::from_str(&plan_file)?;
+    let options = plan_option_labels(&plan);
+    if !options.is_empty() {
+        info!("Plan built with {}", options.join(", "));
+    }
 
-    let (source, metadata) = read_python(file_path)?;
+    let (source, metadata) = read_python(file_path, ctx.decode)?;
 
     apply_plan_to_file(
         file_path,
@@ -1420,6 +4205,14 @@ fn apply_plan(
         diff,
         diff_context,
         force_stdout,
+        diff_out,
+        None,
+        verify,
+        verify_exec,
+        None,
+        allow_partial_plan,
+        false,
+        ctx,
     )
 }
 
@@ -1431,10 +4224,145 @@ struct DirStats {
     bailouts: usize,
     errors: usize,
     total_renames: usize,
+    /// One entry per processed file, sorted by `/`-separated path whatever order the
+    /// workers finished in.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     files: Vec<FileStats>,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     reasons: BTreeMap<String, usize>,
+    /// Files whose rewritten output failed `--verify`; also counted in `errors`.
+    #[serde(default)]
+    verify_failures: usize,
+    /// Import bindings dropped by `--remove-unused-imports` in written files.
+    #[serde(default)]
+    imports_removed: usize,
+    /// What each `--level` source transform did in written files, in pipeline order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    stages: Vec<StageStats>,
+    /// Where `--diff-out` wrote the run's diffs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    diff_out: Option<String>,
+    /// Totals per leading directory, from `--summary-by-dir`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    by_dir: Vec<DirSummary>,
+    /// Files with the largest byte savings, from `--top`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    top_files: Vec<TopFile>,
+    /// Plan bundle entries that matched no candidate file in `apply-plan-dir`, sorted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    unmatched_plans: Vec<String>,
+    /// Candidates left out by `--changed-since` because git reports them unchanged.
+    #[serde(default)]
+    unchanged_skipped: usize,
+    /// Phase and per-file times, from `--timings`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timings: Option<RunTimings>,
+    /// How an existing output directory changed, with `--merge-out-dir` or
+    /// `--prune-out-dir`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    out_dir: Option<OutDirChanges>,
+    /// Worker threads the run used, after `--max-memory`.
+    #[serde(default)]
+    effective_jobs: usize,
+    /// Why `effective_jobs` is below `--jobs`, `TSRS_JOBS`, or the CPU count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jobs_reduced: Option<String>,
+    /// Include and exclude globs the input was walked with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<WalkPatterns>,
+    /// Bailed-out files and functions, for `--sarif`.
+    #[serde(skip)]
+    findings: Vec<tsrs::SarifFinding>,
+}
+
+impl DirStats {
+    /// Fold the results of another pass into these totals.
+    fn merge(&mut self, other: DirStats) {
+        self.processed += other.processed;
+        self.rewritten += other.rewritten;
+        self.skipped_no_change += other.skipped_no_change;
+        self.bailouts += other.bailouts;
+        self.errors += other.errors;
+        self.verify_failures += other.verify_failures;
+        self.imports_removed += other.imports_removed;
+        merge_stage_stats(&mut self.stages, &other.stages);
+        self.total_renames += other.total_renames;
+        self.files.extend(other.files);
+        self.files.sort_by(|a, b| a.path.cmp(&b.path));
+        for (reason, count) in other.reasons {
+            *self.reasons.entry(reason).or_insert(0) += count;
+        }
+        self.unmatched_plans.extend(other.unmatched_plans);
+        self.unmatched_plans.sort_unstable();
+        self.unchanged_skipped += other.unchanged_skipped;
+        self.findings.extend(other.findings);
+        if other.diff_out.is_some() {
+            self.diff_out = other.diff_out;
+        }
+        if other.timings.is_some() {
+            self.timings = other.timings;
+        }
+        if other.effective_jobs > 0 {
+            self.effective_jobs = other.effective_jobs;
+            self.jobs_reduced = other.jobs_reduced;
+        }
+        if other.options.is_some() {
+            self.options = other.options;
+        }
+        if let Some(changes) = other.out_dir {
+            self.out_dir
+                .get_or_insert_with(OutDirChanges::default)
+                .merge(changes);
+        }
+    }
+
+    /// Fill `by_dir` and `top_files` from `files`, replacing any earlier summary.
+    ///
+    /// Files are grouped by the first `by_dir_depth` components of their directory; files
+    /// directly under the input are grouped as `.`. Ties in savings are broken by path.
+    fn summarize(&mut self, by_dir_depth: Option<usize>, top: Option<usize>) {
+        self.by_dir.clear();
+        if let Some(depth) = by_dir_depth {
+            let mut groups: BTreeMap<String, DirSummary> = BTreeMap::new();
+            for file in &self.files {
+                let dir = summary_dir(&file.path, depth);
+                let group = groups.entry(dir.clone()).or_insert_with(|| DirSummary {
+                    dir,
+                    ..DirSummary::default()
+                });
+                group.processed += 1;
+                if file.status == "minified" {
+                    group.rewritten += 1;
+                    group.renames += file.renames;
+                    group.bytes_saved += file.bytes_saved;
+                }
+            }
+            self.by_dir = groups.into_values().collect();
+        }
+
+        self.top_files.clear();
+        if let Some(limit) = top {
+            let mut ranked: Vec<&FileStats> = self
+                .files
+                .iter()
+                .filter(|file| file.bytes_saved > 0)
+                .collect();
+            ranked.sort_by(|a, b| {
+                b.bytes_saved
+                    .cmp(&a.bytes_saved)
+                    .then_with(|| a.path.cmp(&b.path))
+            });
+            self.top_files = ranked
+                .into_iter()
+                .take(limit)
+                .map(|file| TopFile {
+                    path: file.path.clone(),
+                    renames: file.renames,
+                    bytes_saved: file.bytes_saved,
+                })
+                .collect();
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1442,31 +4370,411 @@ struct FileStats {
     path: String,
     renames: usize,
     status: String,
+    /// Bytes the written output is smaller than the source; negative if it grew.
+    #[serde(default)]
+    bytes_saved: i64,
+    /// Functions left as they were, in plan order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skipped_functions: Vec<SkippedFunction>,
+    /// Per-function detail, present when `--stats` is on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    functions: Option<Vec<FunctionStats>>,
+    /// Generated names that also appear unrenamed in their function, with
+    /// `--warn-collisions`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<NameCollision>,
+    /// Invalid bytes in the source were replaced with U+FFFD under `--decode lossy`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    lossy_decode: bool,
+    /// Functions renamed at a range re-resolved under `--fuzzy-apply`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fuzzy_matched: Vec<String>,
+    /// Milliseconds spent reading, planning, rewriting, and writing the file, with
+    /// `--timings`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    elapsed_ms: Option<u64>,
+    /// Bytes of constants `--remove-dead-assignments` dropped from the file.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    dead_assignment_bytes: usize,
+    /// Bytes of `--annotate-renames` comments in the written output, already counted in
+    /// `bytes_saved`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    annotation_bytes: usize,
 }
 
-fn canonicalize_directory(path: &Path) -> anyhow::Result<PathBuf> {
-    dunce_canonicalize(path).with_context(|| format!("failed to canonicalize {}", path.display()))
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
-fn normalize_output_path_guard(path: &Path) -> anyhow::Result<PathBuf> {
-    let cwd = std::env::current_dir().with_context(|| "failed to resolve current directory")?;
-    let abs = if path.is_absolute() {
-        path.to_path_buf()
-    } else {
-        cwd.join(path)
-    };
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
 
-    let mut cursor = abs.as_path();
-    let mut suffix: Vec<OsString> = Vec::new();
+/// One row of `--summary-by-dir`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DirSummary {
+    dir: String,
+    processed: usize,
+    rewritten: usize,
+    renames: usize,
+    bytes_saved: i64,
+}
 
-    while !cursor.exists() {
-        if let Some(name) = cursor.file_name() {
-            suffix.push(name.to_os_string());
-        }
-        match cursor.parent() {
-            Some(parent) => cursor = parent,
-            None => break,
-        }
+/// One row of `--top`.
+#[derive(Debug, Serialize, Deserialize)]
+struct TopFile {
+    path: String,
+    renames: usize,
+    bytes_saved: i64,
+}
+
+/// Files written into or deleted from an existing output directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OutDirChanges {
+    created: usize,
+    overwritten: usize,
+    pruned: usize,
+    /// Files the include/exclude filters would never select, left in place by
+    /// `--prune-out-dir`; sorted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    foreign: Vec<String>,
+}
+
+impl OutDirChanges {
+    /// Count the written outputs of `results` as overwritten if they were among `existing`.
+    fn from_results(results: &[FileResult], existing: &BTreeSet<String>) -> Self {
+        let mut changes = Self::default();
+        // Every ready file is written: rewritten, or copied through unchanged.
+        for result in results {
+            if matches!(result.outcome, FileOutcome::Ready(_)) {
+                if existing.contains(&result.candidate.rel_norm) {
+                    changes.overwritten += 1;
+                } else {
+                    changes.created += 1;
+                }
+            }
+        }
+        changes
+    }
+
+    fn merge(&mut self, other: OutDirChanges) {
+        self.created += other.created;
+        self.overwritten += other.overwritten;
+        self.pruned += other.pruned;
+        self.foreign.extend(other.foreign);
+        self.foreign.sort_unstable();
+        self.foreign.dedup();
+    }
+}
+
+/// Files listed in `--timings` output.
+const SLOWEST_FILES: usize = 10;
+
+/// Where a directory run spent its time, from `--timings`.
+///
+/// Per-file phases are summed over files, so with several jobs they add up to more than
+/// `total_ms`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RunTimings {
+    /// Wall time from walking the input to printing the summary.
+    total_ms: u64,
+    /// Finding candidate files.
+    walk_ms: u64,
+    /// Reading, planning, and rewriting files.
+    plan_rewrite_ms: u64,
+    /// Writing outputs, diffs, and source maps.
+    write_ms: u64,
+    /// The [`SLOWEST_FILES`] files that took longest, slowest first.
+    #[serde(default)]
+    slowest: Vec<SlowFile>,
+    #[serde(skip)]
+    plan_rewrite: Duration,
+    #[serde(skip)]
+    write: Duration,
+}
+
+/// One row of `--timings` `slowest`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SlowFile {
+    path: String,
+    elapsed_ms: u64,
+}
+
+/// Time spent on one file by a dir command's worker.
+#[derive(Debug, Default, Clone, Copy)]
+struct FileTiming {
+    /// The whole file, measured around the worker's processor.
+    elapsed: Duration,
+    /// The part of `elapsed` spent writing output.
+    write: Duration,
+}
+
+impl RunTimings {
+    fn record_file(&mut self, path: &str, timing: FileTiming) {
+        let write = timing.write.min(timing.elapsed);
+        self.plan_rewrite += timing.elapsed - write;
+        self.write += write;
+        self.slowest.push(SlowFile {
+            path: path.to_string(),
+            elapsed_ms: duration_ms(timing.elapsed),
+        });
+        self.slowest.sort_by(|a, b| {
+            b.elapsed_ms
+                .cmp(&a.elapsed_ms)
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        self.slowest.truncate(SLOWEST_FILES);
+    }
+
+    /// Fill the millisecond totals once the run is over.
+    fn finish(&mut self, total: Duration) {
+        self.total_ms = duration_ms(total);
+        self.plan_rewrite_ms = duration_ms(self.plan_rewrite);
+        self.write_ms = duration_ms(self.write);
+    }
+
+    /// The summary line, e.g. `walk 0.4s, plan+rewrite 12.3s, write 1.1s, total 13.9s`.
+    fn describe(&self) -> String {
+        let seconds = |ms: u64| format!("{}.{}s", ms / 1000, ms % 1000 / 100);
+        format!(
+            "walk {}, plan+rewrite {}, write {}, total {}",
+            seconds(self.walk_ms),
+            seconds(self.plan_rewrite_ms),
+            seconds(self.write_ms),
+            seconds(self.total_ms)
+        )
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// The first `depth` directory components of a `/`-separated relative path, or `.`.
+fn summary_dir(path: &str, depth: usize) -> String {
+    let mut components: Vec<&str> = path.split('/').collect();
+    components.pop();
+    components.truncate(depth);
+    if components.is_empty() {
+        ".".to_string()
+    } else {
+        components.join("/")
+    }
+}
+
+/// Source bytes removed by a rewrite; negative if the output grew.
+fn bytes_saved(original: &str, rewritten: &str) -> i64 {
+    original.len() as i64 - rewritten.len() as i64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SkippedFunction {
+    name: String,
+    reason: String,
+    /// 1-based line of the `def`, when the plan recorded one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FunctionStats {
+    /// Qualified function name.
+    name: String,
+    /// Locals considered for renaming.
+    locals: usize,
+    /// Renames applied in the written output.
+    renames: usize,
+    /// Size of the function's source range before the rewrite.
+    bytes_before: usize,
+    /// Size of the same range in the written output, counting renames only.
+    bytes_after: usize,
+    bailed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bailout_reason: Option<String>,
+    /// 1-based line of the `def`, when the plan recorded one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    /// Bytes of the `--annotate-renames` comment added to the function; not counted in
+    /// `bytes_after`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    annotation_bytes: usize,
+}
+
+/// Bytes of `--annotate-renames` comments across a file's per-function stats.
+fn annotation_bytes(functions: Option<&[FunctionStats]>) -> usize {
+    functions
+        .into_iter()
+        .flatten()
+        .map(|function| function.annotation_bytes)
+        .sum()
+}
+
+/// 1-based line at which a planned function starts; plans saved before positions were
+/// recorded report none.
+fn function_line(function: &MinifyFunctionPlan) -> Option<usize> {
+    function
+        .range
+        .map(|range| range.start_line)
+        .filter(|&line| line > 0)
+}
+
+/// 1-based line in `original` for each line of `transformed`, which the source transforms
+/// only ever cut text from: a kept line maps to where it was, an edited one to the first
+/// line of the text it replaced.
+fn original_line_numbers(original: &str, transformed: &str) -> Vec<usize> {
+    let diff = TextDiff::from_lines(original, transformed);
+    let mut lines = Vec::new();
+    for op in diff.ops() {
+        let (old, new) = (op.old_range(), op.new_range());
+        lines.extend(
+            (0..new.len()).map(|offset| old.start + offset.min(old.len().saturating_sub(1)) + 1),
+        );
+    }
+    lines
+}
+
+/// Point the function lines reported for a file planned from its `transformed` source
+/// back at the file as it was read.
+fn restore_original_lines(
+    original: &str,
+    transformed: &str,
+    functions: Option<&mut Vec<FunctionStats>>,
+    skipped: &mut [SkippedFunction],
+) {
+    let lines = original_line_numbers(original, transformed);
+    let original = |line: usize| lines.get(line - 1).copied().unwrap_or(line);
+    for function in functions.into_iter().flatten() {
+        function.line = function.line.map(original);
+    }
+    for function in skipped {
+        function.line = function.line.map(original);
+    }
+}
+
+/// Per-function stats for a file; `written` is false when the rewrite was not kept.
+fn function_stats(
+    plan: &MinifyPlan,
+    outcome: Option<&RewriteOutcome>,
+    written: bool,
+) -> Vec<FunctionStats> {
+    plan.functions
+        .iter()
+        .map(|function| {
+            let name = &function.qualified_name;
+            let bytes_before = function.range.map_or(0, |range| range.end - range.start);
+            let rewrite = outcome
+                .filter(|_| written)
+                .and_then(|outcome| outcome.functions.iter().find(|f| &f.qualified_name == name));
+            let bailout_reason = outcome
+                .and_then(|outcome| outcome.skipped.iter().find(|(skipped, _)| skipped == name))
+                .map(|(_, reason)| reason.clone())
+                .or_else(|| function.bailout_reason.map(|reason| reason.to_string()));
+            FunctionStats {
+                name: name.clone(),
+                locals: function.locals.len(),
+                renames: rewrite.map_or(0, |_| function.renames.len()),
+                bytes_before,
+                bytes_after: rewrite.map_or(bytes_before, |rewrite| rewrite.bytes_after),
+                bailed: bailout_reason.is_some(),
+                bailout_reason,
+                line: function_line(function),
+                annotation_bytes: rewrite.map_or(0, |rewrite| rewrite.annotation_bytes),
+            }
+        })
+        .collect()
+}
+
+/// Log each place a generated name in `plan` also appears unrenamed in `source`, for
+/// `--warn-collisions`.
+fn collision_warnings(plan: &MinifyPlan, source: &str, display_path: &str) -> Vec<NameCollision> {
+    if plan.skip_file {
+        return Vec::new();
+    }
+    let collisions = plan.name_collisions(source);
+    for collision in &collisions {
+        file_log!(
+            WARN,
+            "{}:{}: {} `{}` matches the name generated for `{}` in {}",
+            display_path,
+            collision.line,
+            collision.kind,
+            collision.name,
+            collision.original,
+            collision.function
+        );
+    }
+    collisions
+}
+
+/// Attach the line each skipped function starts on, looked up from its plan.
+fn locate_skipped(plan: &MinifyPlan, skipped: Vec<(String, String)>) -> Vec<SkippedFunction> {
+    skipped
+        .into_iter()
+        .map(|(name, reason)| {
+            let line = plan
+                .functions
+                .iter()
+                .find(|function| function.qualified_name == name)
+                .and_then(function_line);
+            SkippedFunction { name, reason, line }
+        })
+        .collect()
+}
+
+fn record_skipped_functions(
+    stats: &mut DirStats,
+    path: &str,
+    skipped: Vec<SkippedFunction>,
+) -> Vec<SkippedFunction> {
+    for function in &skipped {
+        bump_reason(stats, &format!("function:{}", function.reason));
+        stats.findings.push(tsrs::SarifFinding {
+            rule_id: bailout_rule(&function.reason),
+            message: format!("`{}` was not minified: {}", function.name, function.reason),
+            location: Some(tsrs::FindingLocation {
+                file: path.to_string(),
+                line: function.line.unwrap_or(0),
+                column: 0,
+            }),
+            properties: None,
+        });
+        match function.line {
+            Some(line) => debug!(
+                "{} skipped ({}) at {}:{}",
+                function.name, function.reason, path, line
+            ),
+            None => debug!(
+                "{} skipped ({}) in {}",
+                function.name, function.reason, path
+            ),
+        }
+    }
+    skipped
+}
+
+fn canonicalize_directory(path: &Path) -> anyhow::Result<PathBuf> {
+    dunce_canonicalize(path).with_context(|| format!("failed to canonicalize {}", path.display()))
+}
+
+fn normalize_output_path_guard(path: &Path) -> anyhow::Result<PathBuf> {
+    let cwd = std::env::current_dir().with_context(|| "failed to resolve current directory")?;
+    let abs = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        cwd.join(path)
+    };
+
+    let mut cursor = abs.as_path();
+    let mut suffix: Vec<OsString> = Vec::new();
+
+    while !cursor.exists() {
+        if let Some(name) = cursor.file_name() {
+            suffix.push(name.to_os_string());
+        }
+        match cursor.parent() {
+            Some(parent) => cursor = parent,
+            None => break,
+        }
     }
 
     let base = if cursor.exists() {
@@ -1484,20 +4792,6 @@ fn normalize_output_path_guard(path: &Path) -> anyhow::Result<PathBuf> {
     Ok(normalized)
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum LineEnding {
-    Lf,
-    Crlf,
-}
-
-#[derive(Clone, Copy, Debug)]
-struct TextMetadata {
-    encoding: Option<&'static Encoding>,
-    line_ending: LineEnding,
-    had_trailing_newline: bool,
-    had_bom: bool,
-}
-
 fn print_file_status(path: &str, status: &str, renames: usize, show_stats: bool, quiet: bool) {
     if quiet {
         return;
@@ -1509,10 +4803,77 @@ fn print_file_status(path: &str, status: &str, renames: usize, show_stats: bool,
     }
 }
 
+/// Per-function breakdown of each file, printed ahead of the summary line.
+fn print_function_stats(stats: &DirStats) {
+    for file in &stats.files {
+        let Some(functions) = file.functions.as_ref().filter(|f| !f.is_empty()) else {
+            continue;
+        };
+        println!("{}:", file.path);
+        for function in functions {
+            match (&function.bailout_reason, function.line) {
+                (Some(reason), Some(line)) => println!(
+                    "  {} → bailed ({}) at {}:{}, {} locals",
+                    function.name, reason, file.path, line, function.locals
+                ),
+                (Some(reason), None) => println!(
+                    "  {} → bailed ({}), {} locals",
+                    function.name, reason, function.locals
+                ),
+                (None, _) => println!(
+                    "  {} → {}/{} locals renamed, {} → {} bytes",
+                    function.name,
+                    function.renames,
+                    function.locals,
+                    function.bytes_before,
+                    function.bytes_after
+                ),
+            }
+        }
+    }
+}
+
+/// `--summary-by-dir` and `--top` tables, printed ahead of the summary line.
+fn print_dir_summary(stats: &DirStats) {
+    if !stats.by_dir.is_empty() {
+        let width = stats
+            .by_dir
+            .iter()
+            .map(|row| row.dir.len())
+            .max()
+            .unwrap_or(0)
+            .max("directory".len());
+        println!(
+            "{:<width$}  {:>9}  {:>9}  {:>7}  {:>11}",
+            "directory", "processed", "rewritten", "renames", "bytes saved"
+        );
+        for row in &stats.by_dir {
+            println!(
+                "{:<width$}  {:>9}  {:>9}  {:>7}  {:>11}",
+                row.dir, row.processed, row.rewritten, row.renames, row.bytes_saved
+            );
+        }
+    }
+    if !stats.top_files.is_empty() {
+        println!("Largest savings:");
+        for (rank, file) in stats.top_files.iter().enumerate() {
+            println!(
+                "  {}. {} → {} bytes saved ({} renames)",
+                rank + 1,
+                file.path,
+                file.bytes_saved,
+                file.renames
+            );
+        }
+    }
+}
+
+/// With `verbose`, the per-function breakdown is printed ahead of the summary.
 fn print_summary(
     stats: &DirStats,
     show_stats: bool,
     json_output: bool,
+    verbose: bool,
     dry_run: bool,
     output_label: &str,
     output_json: Option<&Path>,
@@ -1562,9 +4923,59 @@ fn print_summary(
             output_label,
         )
     };
+    let message = if stats.imports_removed > 0 {
+        format!(
+            "{}. Removed {} unused imports",
+            message, stats.imports_removed
+        )
+    } else {
+        message
+    };
+    let message = if stats.unmatched_plans.is_empty() {
+        message
+    } else {
+        format!(
+            "{}. {} plan entries matched no file",
+            message,
+            stats.unmatched_plans.len()
+        )
+    };
+    let message = if stats.unchanged_skipped > 0 {
+        format!(
+            "{}. {} unchanged files skipped (--changed-since)",
+            message, stats.unchanged_skipped
+        )
+    } else {
+        message
+    };
+    let message = match &stats.out_dir {
+        Some(changes) => format!(
+            "{}. Output directory: {} created, {} overwritten, {} pruned, {} foreign files kept",
+            message,
+            changes.created,
+            changes.overwritten,
+            changes.pruned,
+            changes.foreign.len()
+        ),
+        None => message,
+    };
+    let message = match &stats.diff_out {
+        Some(target) => format!("{}. Diffs: {}", message, target),
+        None => message,
+    };
+
+    if show_stats && !json_output && verbose {
+        print_function_stats(stats);
+    }
+    if show_stats && !json_output {
+        print_dir_summary(stats);
+    }
 
     println!("{}", message);
     info!("{}", message);
+    if let Some(timings) = &stats.timings {
+        println!("Timings: {}", timings.describe());
+    }
 
     if show_stats && json_output {
         println!("{}", serde_json::to_string_pretty(stats)?);
@@ -1576,8 +4987,9 @@ fn print_summary(
                 fs::create_dir_all(parent)?;
             }
         }
-        let file = fs::File::create(path)?;
-        serde_json::to_writer_pretty(file, stats)?;
+        let mut json = serde_json::to_string_pretty(stats)?;
+        json.push('\n');
+        fs::write(path, json)?;
     }
 
     Ok(())
@@ -1588,6 +5000,7 @@ fn compute_exit_code(
     fail_on_bailout: bool,
     fail_on_error: bool,
     fail_on_change: bool,
+    require_all_plans: bool,
 ) -> i32 {
     let mut code = 0;
     if fail_on_error && stats.errors > 0 {
@@ -1599,6 +5012,9 @@ fn compute_exit_code(
     if fail_on_change && stats.rewritten > 0 {
         code |= 4;
     }
+    if require_all_plans && !stats.unmatched_plans.is_empty() {
+        code |= 8;
+    }
     code
 }
 
@@ -1606,249 +5022,460 @@ fn bump_reason(stats: &mut DirStats, reason: &str) {
     *stats.reasons.entry(reason.to_string()).or_insert(0) += 1;
 }
 
-fn detect_pep263_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
-    fn extract(line: &str) -> Option<&'static Encoding> {
-        if !line.trim_start().starts_with('#') {
-            return None;
+/// Comparison of two runs' stats, from `stats-diff`.
+#[derive(Debug, Serialize)]
+struct StatsDiff {
+    totals: Vec<TotalDelta>,
+    /// Whether both runs listed their files, i.e. were written with `--stats`.
+    files_compared: bool,
+    /// Files whose status differs, sorted by path; a missing status means the run has no
+    /// entry for the file.
+    changed_files: Vec<StatusChange>,
+    /// Why `--fail-on-regression` would fail, empty when nothing regressed.
+    regressions: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TotalDelta {
+    name: &'static str,
+    old: usize,
+    new: usize,
+    delta: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct StatusChange {
+    path: String,
+    old: Option<String>,
+    new: Option<String>,
+    /// Minified before and a bailout now.
+    regressed: bool,
+}
+
+impl StatsDiff {
+    fn render(&self) -> String {
+        let mut out = String::from("Totals (old → new):\n");
+        for total in &self.totals {
+            out.push_str(&format!(
+                "  {:<14} {} → {} ({:+})\n",
+                total.name, total.old, total.new, total.delta
+            ));
         }
-        let lower = line.to_lowercase();
-        if let Some(idx) = lower.find("coding") {
-            let mut rest = &line[idx + "coding".len()..];
-            rest =
-                rest.trim_start_matches(|c: char| matches!(c, ' ' | '\t' | ':' | '=' | '-' | '*'));
-            let label: String = rest
-                .chars()
-                .take_while(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
-                .collect();
-            if !label.is_empty() {
-                let trimmed = label.trim();
-                if let Some(enc) = Encoding::for_label(trimmed.as_bytes()) {
-                    return Some(enc);
-                }
-                let fallback: String = trimmed.chars().filter(|c| *c != '-' && *c != '_').collect();
-                if !fallback.is_empty() {
-                    if let Some(enc) = Encoding::for_label(fallback.as_bytes()) {
-                        return Some(enc);
+        if !self.files_compared {
+            out.push_str("Per-file statuses not compared (write both runs with --stats)\n");
+        } else if self.changed_files.is_empty() {
+            out.push_str("No file changed status\n");
+        } else {
+            out.push_str("Status changes:\n");
+            for change in &self.changed_files {
+                out.push_str(&format!(
+                    "  {}: {} → {}{}\n",
+                    change.path,
+                    change.old.as_deref().unwrap_or("(absent)"),
+                    change.new.as_deref().unwrap_or("(absent)"),
+                    if change.regressed {
+                        " [regression]"
+                    } else {
+                        ""
                     }
-                }
+                ));
             }
         }
-        None
-    }
-
-    let mut lines = bytes.split(|&b| b == b'\n');
-    for _ in 0..2 {
-        if let Some(line_bytes) = lines.next() {
-            if let Ok(line_str) = std::str::from_utf8(line_bytes) {
-                if let Some(enc) = extract(line_str) {
-                    return Some(enc);
-                }
+        if !self.regressions.is_empty() {
+            out.push_str("Regressions:\n");
+            for regression in &self.regressions {
+                out.push_str(&format!("  - {}\n", regression));
             }
         }
+        out
     }
-    None
 }
 
-fn decode_python_bytes(bytes: &[u8], label: &str) -> anyhow::Result<(String, TextMetadata)> {
-    let encoding = if bytes.starts_with(b"\xEF\xBB\xBF") {
-        Some(UTF_8)
-    } else if bytes.starts_with(&[0xFF, 0xFE]) {
-        Some(UTF_16LE)
-    } else if bytes.starts_with(&[0xFE, 0xFF]) {
-        Some(UTF_16BE)
-    } else {
-        detect_pep263_encoding(bytes)
-    };
-
-    let effective = encoding.unwrap_or(UTF_8);
-    let (decoded, had_errors) = effective.decode_without_bom_handling(bytes);
-    if had_errors {
-        anyhow::bail!("failed to decode {} using {}", label, effective.name());
+fn read_dir_stats(path: &Path) -> anyhow::Result<DirStats> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let stats: DirStats = serde_json::from_str(&text)
+        .with_context(|| format!("{} is not --output-json stats", path.display()))?;
+    if stats.files.is_empty() && stats.processed > 0 {
+        warn!(
+            "{} has no per-file entries (write it with --stats); comparing totals only",
+            path.display()
+        );
     }
+    Ok(stats)
+}
 
-    let mut content = decoded.into_owned();
+/// Compare two runs: totals always, per-file statuses when both runs listed their files.
+fn diff_stats(old: &DirStats, new: &DirStats) -> StatsDiff {
+    let total = |name: &'static str, pick: fn(&DirStats) -> usize| TotalDelta {
+        name,
+        old: pick(old),
+        new: pick(new),
+        delta: pick(new) as i64 - pick(old) as i64,
+    };
+    let totals = vec![
+        total("processed", |stats| stats.processed),
+        total("rewritten", |stats| stats.rewritten),
+        total("bailouts", |stats| stats.bailouts),
+        total("errors", |stats| stats.errors),
+        total("total_renames", |stats| stats.total_renames),
+    ];
+
+    let mut regressions = Vec::new();
+    for total in &totals {
+        if matches!(total.name, "bailouts" | "errors") && total.delta > 0 {
+            regressions.push(format!(
+                "{} increased from {} to {}",
+                total.name, total.old, total.new
+            ));
+        }
+    }
 
-    let mut has_crlf = false;
-    let mut has_plain_lf = false;
-    let bytes_view = content.as_bytes();
-    let mut i = 0;
-    while i < bytes_view.len() {
-        if bytes_view[i] == b'\r' {
-            if i + 1 < bytes_view.len() && bytes_view[i + 1] == b'\n' {
-                has_crlf = true;
-                i += 1;
-            } else {
-                has_plain_lf = true;
+    let files_compared = !old.files.is_empty() && !new.files.is_empty();
+    let mut changed_files = Vec::new();
+    if files_compared {
+        let mut statuses: BTreeMap<&str, (Option<&str>, Option<&str>)> = BTreeMap::new();
+        for file in &old.files {
+            statuses.entry(file.path.as_str()).or_default().0 = Some(file.status.as_str());
+        }
+        for file in &new.files {
+            statuses.entry(file.path.as_str()).or_default().1 = Some(file.status.as_str());
+        }
+        let minified = FinalStatusKind::Minified.label();
+        for (path, (old_status, new_status)) in statuses {
+            if old_status == new_status {
+                continue;
             }
-        } else if bytes_view[i] == b'\n' {
-            if i == 0 || bytes_view[i - 1] != b'\r' {
-                has_plain_lf = true;
+            let regressed = old_status == Some(minified)
+                && new_status.is_some_and(|status| {
+                    FinalStatusKind::BAILOUTS
+                        .iter()
+                        .any(|kind| kind.label() == status)
+                });
+            if regressed {
+                regressions.push(format!(
+                    "{}: {} → {}",
+                    path,
+                    minified,
+                    new_status.unwrap_or_default()
+                ));
             }
+            changed_files.push(StatusChange {
+                path: path.to_string(),
+                old: old_status.map(str::to_string),
+                new: new_status.map(str::to_string),
+                regressed,
+            });
         }
-        i += 1;
     }
 
-    let line_ending = if has_crlf && !has_plain_lf {
-        LineEnding::Crlf
-    } else {
-        LineEnding::Lf
-    };
-
-    if matches!(line_ending, LineEnding::Crlf) {
-        content = content.replace("\r\n", "\n");
+    StatsDiff {
+        totals,
+        files_compared,
+        changed_files,
+        regressions,
     }
+}
 
-    let had_trailing_newline = content.ends_with('\n');
+/// Bundled `self-test` cases as (name, source, golden output), embedded so an installed
+/// binary can run them.
+const BUNDLED_SELF_TEST: &[(&str, &str, Option<&str>)] = &[
+    (
+        "async_def",
+        include_str!("../../testdata/self_test/async_def.py"),
+        Some(include_str!(
+            "../../testdata/self_test/async_def.expected.py"
+        )),
+    ),
+    (
+        "capturing_lambda",
+        include_str!("../../testdata/self_test/capturing_lambda.py"),
+        None,
+    ),
+    (
+        "comprehension",
+        include_str!("../../testdata/self_test/comprehension.py"),
+        None,
+    ),
+    (
+        "conditional_def",
+        include_str!("../../testdata/self_test/conditional_def.py"),
+        None,
+    ),
+    (
+        "decorated",
+        include_str!("../../testdata/self_test/decorated.py"),
+        Some(include_str!(
+            "../../testdata/self_test/decorated.expected.py"
+        )),
+    ),
+    (
+        "fstring_nested_spec",
+        include_str!("../../testdata/self_test/fstring_nested_spec.py"),
+        Some(include_str!(
+            "../../testdata/self_test/fstring_nested_spec.expected.py"
+        )),
+    ),
+    (
+        "global_nonlocal",
+        include_str!("../../testdata/self_test/global_nonlocal.py"),
+        Some(include_str!(
+            "../../testdata/self_test/global_nonlocal.expected.py"
+        )),
+    ),
+    (
+        "match_statement",
+        include_str!("../../testdata/self_test/match_statement.py"),
+        None,
+    ),
+    (
+        "sort_key_lambda",
+        include_str!("../../testdata/self_test/sort_key_lambda.py"),
+        Some(include_str!(
+            "../../testdata/self_test/sort_key_lambda.expected.py"
+        )),
+    ),
+    (
+        "unicode_identifiers",
+        include_str!("../../testdata/self_test/unicode_identifiers.py"),
+        Some(include_str!(
+            "../../testdata/self_test/unicode_identifiers.expected.py"
+        )),
+    ),
+    (
+        "walrus",
+        include_str!("../../testdata/self_test/walrus.py"),
+        None,
+    ),
+];
+
+/// First-line marker of a case expected to bail out, followed by the bailout reason.
+const SELF_TEST_BAIL_MARKER: &str = "# self-test: bail ";
+
+struct SelfTestCase {
+    name: String,
+    source: String,
+    /// Expected rewrite output; without it, and without a bail marker, the output only has
+    /// to re-parse.
+    golden: Option<String>,
+}
 
-    let had_bom = match encoding {
-        Some(enc) if enc == UTF_8 && bytes.starts_with(b"\xEF\xBB\xBF") => true,
-        Some(enc) if enc == UTF_16LE && bytes.starts_with(&[0xFF, 0xFE]) => true,
-        Some(enc) if enc == UTF_16BE && bytes.starts_with(&[0xFE, 0xFF]) => true,
-        _ => false,
-    };
+impl SelfTestCase {
+    fn expected_bailout(&self) -> Option<&str> {
+        let first_line = self.source.lines().next()?;
+        first_line
+            .strip_prefix(SELF_TEST_BAIL_MARKER)
+            .map(str::trim)
+            .filter(|reason| !reason.is_empty())
+    }
 
-    let metadata = TextMetadata {
-        encoding,
-        line_ending,
-        had_trailing_newline,
-        had_bom,
-    };
+    fn expectation(&self) -> String {
+        match (self.expected_bailout(), &self.golden) {
+            (Some(reason), _) => format!("bail {}", reason),
+            (None, Some(_)) => "golden".to_string(),
+            (None, None) => "reparse".to_string(),
+        }
+    }
+}
 
-    Ok((content, metadata))
+struct SelfTestResult {
+    name: String,
+    expectation: String,
+    /// Why the case failed, `None` when it passed.
+    failure: Option<String>,
 }
 
-fn read_python(path: &Path) -> anyhow::Result<(String, TextMetadata)> {
-    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
-    decode_python_bytes(&bytes, &path.display().to_string())
+fn bundled_self_test_cases() -> Vec<SelfTestCase> {
+    BUNDLED_SELF_TEST
+        .iter()
+        .map(|(name, source, golden)| SelfTestCase {
+            name: (*name).to_string(),
+            source: (*source).to_string(),
+            golden: golden.map(str::to_string),
+        })
+        .collect()
 }
 
-fn split_source_and_plan(buffer: &[u8]) -> anyhow::Result<(String, TextMetadata, MinifyPlan)> {
-    for (idx, byte) in buffer.iter().enumerate() {
-        if *byte == b'{' {
-            if let Ok(plan) = serde_json::from_slice::<MinifyPlan>(&buffer[idx..]) {
-                let python_bytes = &buffer[..idx];
-                let (source, metadata) =
-                    decode_python_bytes(python_bytes, "stdin source with plan")?;
-                return Ok((source, metadata, plan));
-            }
+/// Cases in `dir`: every `.py` file other than the `.expected.py` goldens, by name.
+fn load_self_test_corpus(dir: &Path, decode: DecodeOptions) -> anyhow::Result<Vec<SelfTestCase>> {
+    let entries = fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        if path.is_file() && name.ends_with(".py") && !name.ends_with(".expected.py") {
+            paths.push(path);
         }
     }
-    bail!("failed to split source and plan from stdin; provide valid plan JSON after the source");
+    if paths.is_empty() {
+        bail!("no .py cases found in {}", dir.display());
+    }
+    paths.sort();
+
+    let mut cases = Vec::new();
+    for path in paths {
+        let label = path.display().to_string();
+        let bytes = fs::read(&path).with_context(|| format!("failed to read {}", label))?;
+        let (source, _) = decode_python_bytes(&bytes, &label, decode)?;
+        let golden_path = path.with_extension("expected.py");
+        let golden = if golden_path.is_file() {
+            let bytes = fs::read(&golden_path)
+                .with_context(|| format!("failed to read {}", golden_path.display()))?;
+            Some(decode_python_bytes(&bytes, &golden_path.display().to_string(), decode)?.0)
+        } else {
+            None
+        };
+        cases.push(SelfTestCase {
+            name: label,
+            source,
+            golden,
+        });
+    }
+    Ok(cases)
 }
 
-fn read_pattern_file(path: &Path) -> anyhow::Result<Vec<String>> {
-    let contents = fs::read_to_string(path)
-        .with_context(|| format!("failed to read pattern file {}", path.display()))?;
-    let mut patterns = Vec::new();
-    for line in contents.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            continue;
+fn run_self_test(cases: &[SelfTestCase]) -> Vec<SelfTestResult> {
+    cases
+        .iter()
+        .map(|case| SelfTestResult {
+            name: case.name.clone(),
+            expectation: case.expectation(),
+            failure: check_self_test_case(case).err(),
+        })
+        .collect()
+}
+
+/// Plan and rewrite `case`, then check the output re-parses and matches its expectation.
+fn check_self_test_case(case: &SelfTestCase) -> Result<(), String> {
+    let module_name = Path::new(&case.name)
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .unwrap_or("self_test");
+    let plan = Minifier::plan_from_source(module_name, &case.source)
+        .map_err(|err| format!("planning failed: {}", err))?;
+    let outcome = Minifier::rewrite_with_plan_detailed(module_name, &case.source, &plan)
+        .map_err(|err| format!("rewrite failed: {}", err))?;
+    Minifier::check_syntax(module_name, &outcome.source)
+        .map_err(|err| format!("output does not re-parse: {}", err))?;
+
+    if let Some(expected) = case.expected_bailout() {
+        if outcome.skipped.iter().any(|(_, reason)| reason == expected) {
+            return Ok(());
+        }
+        let reasons: Vec<&str> = outcome
+            .skipped
+            .iter()
+            .map(|(_, reason)| reason.as_str())
+            .collect();
+        return Err(if reasons.is_empty() {
+            "rewrote without bailing out".to_string()
+        } else {
+            format!("bailed out with {}", reasons.join(", "))
+        });
+    }
+    if let Some(golden) = &case.golden {
+        if outcome.source != *golden {
+            let line = outcome
+                .source
+                .lines()
+                .zip(golden.lines())
+                .position(|(actual, expected)| actual != expected)
+                .unwrap_or_else(|| outcome.source.lines().count().min(golden.lines().count()));
+            return Err(format!("output differs from golden at line {}", line + 1));
         }
-        patterns.push(trimmed.to_string());
     }
-    Ok(patterns)
+    Ok(())
 }
 
-fn build_walker(
-    root: &Path,
-    include_hidden: bool,
-    follow_symlinks: bool,
-    max_depth: Option<usize>,
-    respect_gitignore: bool,
-) -> ignore::Walk {
-    let mut builder = WalkBuilder::new(root);
-    builder.follow_links(follow_symlinks);
-    builder.standard_filters(false);
-    builder.hidden(!include_hidden);
-    builder.max_depth(max_depth);
-    builder.require_git(false);
-
-    if respect_gitignore {
-        builder
-            .git_ignore(true)
-            .git_global(true)
-            .git_exclude(true)
-            .parents(true)
-            .ignore(true);
-    } else {
-        builder
-            .git_ignore(false)
-            .git_global(false)
-            .git_exclude(false)
-            .parents(false)
-            .ignore(false);
+fn render_self_test(results: &[SelfTestResult]) -> String {
+    let name_width = results
+        .iter()
+        .map(|result| result.name.chars().count())
+        .chain(["CASE".len()])
+        .max()
+        .unwrap_or_default();
+    let expect_width = results
+        .iter()
+        .map(|result| result.expectation.len())
+        .chain(["EXPECT".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut out = format!(
+        "{:<name_width$}  {:<expect_width$}  RESULT\n",
+        "CASE", "EXPECT"
+    );
+    for result in results {
+        let status = match &result.failure {
+            None => "ok".to_string(),
+            Some(reason) => format!("FAIL: {}", reason),
+        };
+        out.push_str(&format!(
+            "{:<name_width$}  {:<expect_width$}  {}\n",
+            result.name, result.expectation, status
+        ));
     }
+    let failed = results
+        .iter()
+        .filter(|result| result.failure.is_some())
+        .count();
+    out.push_str(&format!("{} cases, {} failed\n", results.len(), failed));
+    out
+}
 
-    builder.build()
+/// How Python inputs are decoded, from `--encoding` and `--decode`.
+#[derive(Debug, Clone, Copy, Default)]
+struct DecodeOptions {
+    /// Replaces BOM and PEP 263 detection for every input.
+    forced: Option<&'static Encoding>,
+    /// Replace bytes invalid in the file's encoding with U+FFFD instead of failing.
+    lossy: bool,
 }
 
-fn encode_python(content: &str, metadata: &TextMetadata, label: &str) -> anyhow::Result<Vec<u8>> {
-    let mut adjusted = content.replace("\r\n", "\n");
-    if matches!(metadata.line_ending, LineEnding::Crlf) {
-        adjusted = adjusted.replace("\n", "\r\n");
+/// Decode Python source with [`textio::decode_python_bytes`], pointing failures at
+/// `--decode lossy` and logging when bytes were replaced.
+fn decode_python_bytes(
+    bytes: &[u8],
+    label: &str,
+    decode: DecodeOptions,
+) -> anyhow::Result<(String, TextMetadata)> {
+    let DecodeOptions { forced, lossy } = decode;
+    let (content, metadata) = textio::decode_python_bytes(bytes, label, forced, lossy)
+        .map_err(|err| anyhow::anyhow!("{err} (pass --decode lossy to replace invalid bytes)"))?;
+    if metadata.lossy {
+        file_log!(
+            WARN,
+            "{}: replaced bytes that are not valid {} with U+FFFD; output will be UTF-8",
+            label,
+            forced
+                .or_else(|| textio::detect_encoding(bytes))
+                .unwrap_or(UTF_8)
+                .name()
+        );
     }
+    Ok((content, metadata))
+}
 
-    let newline = match metadata.line_ending {
-        LineEnding::Lf => "\n",
-        LineEnding::Crlf => "\r\n",
-    };
+fn read_python(path: &Path, decode: DecodeOptions) -> anyhow::Result<(String, TextMetadata)> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    decode_python_bytes(&bytes, &path.display().to_string(), decode)
+}
 
-    if metadata.had_trailing_newline {
-        if !adjusted.ends_with(newline) {
-            while adjusted.ends_with('\n') || adjusted.ends_with('\r') {
-                adjusted.pop();
+fn split_source_and_plan(
+    buffer: &[u8],
+    decode: DecodeOptions,
+) -> anyhow::Result<(String, TextMetadata, MinifyPlan)> {
+    for (idx, byte) in buffer.iter().enumerate() {
+        if *byte == b'{' {
+            if let Ok(plan) = serde_json::from_slice::<MinifyPlan>(&buffer[idx..]) {
+                let python_bytes = &buffer[..idx];
+                let (source, metadata) =
+                    decode_python_bytes(python_bytes, "stdin source with plan", decode)?;
+                return Ok((source, metadata, plan));
             }
-            adjusted.push_str(newline);
-        }
-    } else if matches!(metadata.line_ending, LineEnding::Crlf) {
-        if adjusted.ends_with("\r\n") {
-            adjusted.truncate(adjusted.len() - 2);
-        } else if adjusted.ends_with('\n') {
-            adjusted.pop();
         }
-    } else {
-        while adjusted.ends_with('\n') || adjusted.ends_with('\r') {
-            adjusted.pop();
-        }
-    }
-
-    let encoder = metadata.encoding.unwrap_or(UTF_8);
-    let mut output: Vec<u8> = Vec::new();
-    if std::ptr::eq(encoder, UTF_16LE) || std::ptr::eq(encoder, UTF_16BE) {
-        if metadata.had_bom {
-            if std::ptr::eq(encoder, UTF_16LE) {
-                output.extend_from_slice(&[0xFF, 0xFE]);
-            } else {
-                output.extend_from_slice(&[0xFE, 0xFF]);
-            }
-        }
-        for unit in adjusted.encode_utf16() {
-            let bytes = if std::ptr::eq(encoder, UTF_16LE) {
-                unit.to_le_bytes()
-            } else {
-                unit.to_be_bytes()
-            };
-            output.extend_from_slice(&bytes);
-        }
-        return Ok(output);
-    }
-
-    let (encoded, output_encoding, had_errors) = encoder.encode(&adjusted);
-    if had_errors || !std::ptr::eq(output_encoding, encoder) {
-        anyhow::bail!("failed to encode {} using {}", label, encoder.name());
-    }
-
-    if metadata.had_bom {
-        if std::ptr::eq(encoder, UTF_8) {
-            output.extend_from_slice(b"\xEF\xBB\xBF");
-        }
-    }
-    match encoded {
-        Cow::Borrowed(bytes) => output.extend_from_slice(bytes),
-        Cow::Owned(buffer) => output.extend_from_slice(&buffer),
     }
-    Ok(output)
+    bail!("failed to split source and plan from stdin; provide valid plan JSON after the source");
 }
 
 fn write_python(path: &Path, content: &str, metadata: &TextMetadata) -> anyhow::Result<()> {
@@ -1857,6 +5484,55 @@ fn write_python(path: &Path, content: &str, metadata: &TextMetadata) -> anyhow::
     Ok(())
 }
 
+/// Check rewritten output before it is written: it must parse, and with `verify_exec` it must
+/// also byte-compile under that interpreter.
+///
+/// The byte-compile runs on a copy in a scratch directory, so `__pycache__` never lands in the
+/// user's tree and nothing invalid is written next to the original.
+fn verify_rewritten(
+    path: &Path,
+    content: &str,
+    metadata: &TextMetadata,
+    verify_exec: Option<&Path>,
+) -> Result<(), String> {
+    let label = path.display().to_string();
+    Minifier::check_syntax(&label, content).map_err(|err| err.with_file(path).to_string())?;
+
+    let Some(python) = verify_exec else {
+        return Ok(());
+    };
+    let bytes = encode_python(content, metadata, &label).map_err(|err| err.to_string())?;
+    let scratch = std::env::temp_dir().join(format!(
+        "tsrs-verify-{}-{}",
+        process::id(),
+        VERIFY_SCRATCH_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let copy = scratch.join(path.file_name().unwrap_or_else(|| OsStr::new("stdin.py")));
+    let output = fs::create_dir_all(&scratch)
+        .and_then(|()| fs::write(&copy, bytes))
+        .and_then(|()| {
+            process::Command::new(python)
+                .args(["-m", "py_compile"])
+                .arg(&copy)
+                .output()
+        });
+    let _ = fs::remove_dir_all(&scratch);
+
+    let output = output.map_err(|err| format!("failed to run {}: {}", python.display(), err))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} -m py_compile rejected {}: {}",
+            python.display(),
+            label,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+static VERIFY_SCRATCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 fn make_unified_diff(path: &str, original: &str, rewritten: &str, context: usize) -> String {
     let diff = TextDiff::from_lines(original, rewritten);
     diff.unified_diff()
@@ -1865,2410 +5541,8475 @@ fn make_unified_diff(path: &str, original: &str, rewritten: &str, context: usize
         .to_string()
 }
 
-const PLAN_BUNDLE_VERSION: u32 = 1;
+/// Whether `--diff-out` names a single patch file (`.diff`/`.patch`) rather than a directory.
+fn diff_out_is_file(target: &Path) -> bool {
+    target
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("diff") || ext.eq_ignore_ascii_case("patch"))
+}
+
+fn write_patch(path: &Path, contents: &str) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+        }
+    }
+    fs::write(path, contents)
+        .with_context(|| format!("failed to write diff {}", path.display()))?;
+    debug!("wrote diff {}", path.display());
+    Ok(())
+}
+
+/// Write a single-file command's diff, returning the path it was written to.
+fn write_file_diff(target: &Path, python_file: &Path, diff: &str) -> anyhow::Result<PathBuf> {
+    let patch_path = if diff_out_is_file(target) {
+        target.to_path_buf()
+    } else {
+        let file_name = python_file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "stdin".to_string());
+        target.join(format!("{}.patch", file_name))
+    };
+    write_patch(&patch_path, diff)?;
+    Ok(patch_path)
+}
+
+/// Write a directory run's diffs as one patch bundle or one `<path>.patch` per file.
+///
+/// Bundles are concatenated in sorted path order so they are deterministic and apply
+/// with `git apply` from the input directory.
+fn write_dir_diffs(target: &Path, mut diffs: Vec<(String, String)>) -> anyhow::Result<()> {
+    diffs.sort_by(|a, b| a.0.cmp(&b.0));
+    if diff_out_is_file(target) {
+        let mut bundle = String::new();
+        for (_, diff) in &diffs {
+            bundle.push_str(diff);
+            if !bundle.ends_with('\n') {
+                bundle.push('\n');
+            }
+        }
+        write_patch(target, &bundle)
+    } else {
+        for (path, diff) in &diffs {
+            write_patch(&target.join(format!("{}.patch", path)), diff)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bundle format written by `minify-plan-dir`.
+///
+/// v1 is a single JSON document holding a `files` array. v2 is JSON Lines: a
+/// [`PlanBundleHeader`] line followed by one [`PlanFile`] per line, so it can be applied
+/// without loading every plan at once.
+const PLAN_BUNDLE_VERSION: u32 = 2;
 
+/// Leading bytes of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A v1 plan bundle, read whole.
 #[derive(Debug, Serialize, Deserialize)]
-struct PlanBundle {
+struct PlanBundle<F = PlanFile> {
     #[serde(default = "default_plan_version")]
     version: u32,
-    files: Vec<PlanFile>,
+    /// Source extensions the bundle was planned for.
+    #[serde(default = "default_extensions")]
+    extensions: Vec<String>,
+    /// Fields this version does not know about, kept so curation round-trips them.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+    files: Vec<F>,
 }
 
+/// Bundles predating the `version` field are v1.
 fn default_plan_version() -> u32 {
-    PLAN_BUNDLE_VERSION
+    1
 }
 
+/// First line of a v2 plan bundle.
 #[derive(Debug, Serialize, Deserialize)]
-struct PlanFile {
+struct PlanBundleHeader {
+    version: u32,
+    /// Source extensions the bundle was planned for.
+    #[serde(default = "default_extensions")]
+    extensions: Vec<String>,
+    /// Files `minify-plan-dir` could not read or plan, which have no entry.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<PlanFileError>,
+    /// `--module-root` directories the modules were named from, relative to the input.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    module_roots: Vec<String>,
+    /// Fields this version does not know about, kept so curation round-trips them.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A file left out of a plan bundle, and why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PlanFileError {
     path: String,
-    plan: MinifyPlan,
+    /// `read_error` or `plan_error`, as in `--progress-json` events
+    kind: String,
+    message: String,
 }
 
-fn minify_file(
-    file_path: &PathBuf,
-    in_place: bool,
-    dry_run: bool,
-    backup_ext: Option<&str>,
-    show_stats: bool,
-    json_output: bool,
-    quiet: bool,
-    output_json: Option<&Path>,
-    fail_on_bailout: bool,
-    fail_on_error: bool,
-    fail_on_change: bool,
-    diff: bool,
-    diff_context: usize,
-    force_stdout: bool,
-) -> anyhow::Result<(DirStats, Option<Vec<u8>>)> {
-    minify_file_impl(
-        file_path,
-        in_place,
-        dry_run,
-        backup_ext,
-        show_stats,
-        json_output,
-        quiet,
-        output_json,
-        fail_on_bailout,
-        fail_on_error,
-        fail_on_change,
-        diff,
-        diff_context,
-        force_stdout,
-        false, // remove_dead_code defaults to false
-    )
+/// Plan entries of a bundle in path order; v2 bundles yield them as they are read.
+type PlanFileStream = PlanEntryStream<PlanFile>;
+
+/// Bundle entries parsed as `F`, in path order
+type PlanEntryStream<F> = Box<dyn Iterator<Item = anyhow::Result<F>> + Send>;
+
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
 }
 
-fn minify_file_impl(
-    file_path: &PathBuf,
-    in_place: bool,
-    dry_run: bool,
-    backup_ext: Option<&str>,
-    show_stats: bool,
-    json_output: bool,
-    quiet: bool,
-    output_json: Option<&Path>,
-    fail_on_bailout: bool,
-    fail_on_error: bool,
-    fail_on_change: bool,
-    diff: bool,
-    diff_context: usize,
-    force_stdout: bool,
-    remove_dead_code: bool,
-) -> anyhow::Result<(DirStats, Option<Vec<u8>>)> {
-    if json_output && !show_stats {
-        anyhow::bail!("--json requires --stats");
+/// Write a v2 plan bundle, gzip-compressed when `out_path` ends in `.gz`.
+///
+/// The bundle is written beside `out_path` under a temporary name and renamed into place,
+/// so an interrupted run never leaves a partial bundle under the final name.
+fn write_plan_bundle<F: Serialize>(
+    out_path: &Path,
+    header: &PlanBundleHeader,
+    files: &[F],
+) -> anyhow::Result<()> {
+    let file_name = out_path
+        .file_name()
+        .with_context(|| format!("{} is not a file path", out_path.display()))?;
+    let temp_path = out_path.with_file_name(format!(
+        ".{}.tmp-{}",
+        file_name.to_string_lossy(),
+        process::id()
+    ));
+    let written = write_plan_bundle_file(&temp_path, is_gzip_path(out_path), header, files)
+        .and_then(|()| {
+            fs::rename(&temp_path, out_path)
+                .with_context(|| format!("failed to write {}", out_path.display()))
+        });
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
     }
+    written
+}
 
-    let (source, metadata) = read_python(file_path)?;
-    let module_name = file_path
-        .file_stem()
-        .and_then(|stem| stem.to_str())
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| file_path.to_string_lossy().to_string());
-
-    let mut plan = Minifier::plan_from_source(&module_name, &source)?;
+fn write_plan_bundle_file<F: Serialize>(
+    path: &Path,
+    gzip: bool,
+    header: &PlanBundleHeader,
+    files: &[F],
+) -> anyhow::Result<()> {
+    let file =
+        fs::File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    if gzip {
+        let mut encoder = GzEncoder::new(&mut writer, Compression::default());
+        write_plan_bundle_lines(&mut encoder, header, files)?;
+        encoder.finish()?;
+    } else {
+        write_plan_bundle_lines(&mut writer, header, files)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
 
-    // Filter plan if --remove-dead-code is requested
-    if remove_dead_code {
-        let dead_code = detect_dead_code(&source, &module_name, quiet)?;
-        plan = filter_plan_for_dead_code(plan, &dead_code);
+fn write_plan_bundle_lines<W: Write, F: Serialize>(
+    writer: &mut W,
+    header: &PlanBundleHeader,
+    files: &[F],
+) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *writer, header)?;
+    writer.write_all(b"\n")?;
+    for file in files {
+        serde_json::to_writer(&mut *writer, file)?;
+        writer.write_all(b"\n")?;
     }
+    Ok(())
+}
 
-    apply_plan_to_file(
-        file_path,
-        &source,
-        &metadata,
-        &plan,
-        in_place,
-        dry_run,
-        backup_ext,
-        show_stats,
-        json_output,
-        quiet,
-        output_json,
-        fail_on_bailout,
-        fail_on_error,
-        fail_on_change,
-        diff,
-        diff_context,
-        force_stdout,
-    )
+/// Suffix of the per-file plans in a plan directory.
+const PLAN_DIR_SUFFIX: &str = ".plan.json";
+
+/// One file of a plan directory written by `minify-plan-dir --out-format dir`: a bundle
+/// entry with the bundle version it was written under.
+#[derive(Debug, Serialize, Deserialize)]
+struct PlanDirFile<F = PlanFile> {
+    version: u32,
+    #[serde(flatten)]
+    entry: F,
 }
 
-fn apply_plan_to_file(
-    file_path: &PathBuf,
-    source: &str,
-    metadata: &TextMetadata,
-    plan: &MinifyPlan,
-    in_place: bool,
-    dry_run: bool,
-    backup_ext: Option<&str>,
-    show_stats: bool,
-    json_output: bool,
-    quiet: bool,
-    output_json: Option<&Path>,
-    fail_on_bailout: bool,
-    fail_on_error: bool,
-    fail_on_change: bool,
-    diff: bool,
-    diff_context: usize,
-    force_stdout: bool,
-) -> anyhow::Result<(DirStats, Option<Vec<u8>>)> {
-    if json_output && !show_stats {
-        anyhow::bail!("--json requires --stats");
+/// Write each entry to `<out_dir>/<path>.plan.json`. Plans of files not planned this time
+/// are left in place.
+fn write_plan_dir(out_dir: &Path, files: &[PlanFile]) -> anyhow::Result<()> {
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("failed to create {}", out_dir.display()))?;
+    for file in files {
+        let target = out_dir.join(format!("{}{}", file.path, PLAN_DIR_SUFFIX));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let document = PlanDirFile {
+            version: PLAN_BUNDLE_VERSION,
+            entry: file,
+        };
+        let mut json = serde_json::to_string_pretty(&document)?;
+        json.push('\n');
+        fs::write(&target, json)
+            .with_context(|| format!("failed to write {}", target.display()))?;
     }
+    Ok(())
+}
 
-    if backup_ext.is_some() && !in_place {
-        anyhow::bail!("--backup-ext requires --in-place");
+/// `/`-separated paths of the files planned in a plan directory, sorted, without opening
+/// any plan.
+fn plan_dir_paths(root: &Path) -> anyhow::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for entry in walkdir::WalkDir::new(root) {
+        let entry =
+            entry.with_context(|| format!("failed to read plan directory {}", root.display()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Ok(rel) = entry.path().strip_prefix(root) else {
+            continue;
+        };
+        if let Some(path) = portable_path(rel).strip_suffix(PLAN_DIR_SUFFIX) {
+            paths.push(path.to_string());
+        }
     }
+    paths.sort_unstable();
+    Ok(paths)
+}
 
-    let rename_total: usize = plan.functions.iter().map(|f| f.renames.len()).sum();
+/// Stand-in for a bundle header: a plan directory was planned for the extensions of the
+/// files it holds plans for.
+fn plan_dir_header(paths: &[String]) -> PlanBundleHeader {
+    let extensions: BTreeSet<String> = paths
+        .iter()
+        .filter_map(|path| Path::new(path).extension()?.to_str().map(str::to_string))
+        .collect();
+    PlanBundleHeader {
+        version: PLAN_BUNDLE_VERSION,
+        extensions: if extensions.is_empty() {
+            default_extensions()
+        } else {
+            extensions.into_iter().collect()
+        },
+        errors: Vec::new(),
+        module_roots: Vec::new(),
+        extra: serde_json::Map::new(),
+    }
+}
 
-    let mut status;
-    let mut final_content: Cow<'_, str> = Cow::Borrowed(source);
+/// Read the plan for `path` from a plan directory; nothing is cached, so only the plans
+/// asked for are ever loaded.
+fn read_plan_dir_file<F>(root: &Path, path: &str) -> anyhow::Result<F>
+where
+    F: serde::de::DeserializeOwned,
+{
+    let file_path = root.join(format!("{}{}", path, PLAN_DIR_SUFFIX));
+    let text = fs::read_to_string(&file_path)
+        .with_context(|| format!("failed to read plan {}", file_path.display()))?;
+    let file: PlanDirFile<F> = serde_json::from_str(&text)
+        .with_context(|| format!("failed to parse plan {}", file_path.display()))?;
+    check_plan_bundle_version(file.version)?;
+    Ok(file.entry)
+}
 
-    if rename_total == 0 {
-        status = "skipped (no renames)".to_string();
-    } else {
-        let rewritten = Minifier::rewrite_with_plan(&plan.module, source, plan)?;
-        if rewritten == source {
-            status = "skipped (rewrite aborted)".to_string();
-        } else {
-            status = "minified".to_string();
-            final_content = Cow::Owned(rewritten);
+/// The plans for `paths` in a plan directory, each read when the iterator reaches it.
+fn plan_dir_entries<F>(
+    root: &Path,
+    paths: Vec<String>,
+) -> Box<dyn Iterator<Item = anyhow::Result<F>> + Send>
+where
+    F: serde::de::DeserializeOwned + Send + 'static,
+{
+    let root = root.to_path_buf();
+    Box::new(
+        paths
+            .into_iter()
+            .map(move |path| read_plan_dir_file(&root, &path)),
+    )
+}
+
+/// Where `apply-plan-dir` takes its plans from.
+enum PlanSource {
+    /// A bundle file, read front to back.
+    Bundle(Box<std::iter::Peekable<PlanFileStream>>),
+    /// A plan directory and the paths it has plans for.
+    Dir { root: PathBuf, paths: Vec<String> },
+}
+
+impl PlanSource {
+    /// Open `path` as a plan directory if it is one, and as a bundle file otherwise.
+    fn open(path: &Path) -> anyhow::Result<(PlanBundleHeader, Self)> {
+        if path.is_dir() {
+            let paths = plan_dir_paths(path)?;
+            let header = plan_dir_header(&paths);
+            let root = path.to_path_buf();
+            return Ok((header, PlanSource::Dir { root, paths }));
         }
+        let (header, files) = open_plan_bundle(path)?;
+        Ok((header, PlanSource::Bundle(Box::new(files.peekable()))))
     }
 
-    let display_path = file_path.display().to_string();
-
-    if in_place && !dry_run {
-        if let Some(ext) = backup_ext {
-            let mut backup_os = file_path.as_os_str().to_os_string();
-            backup_os.push(ext);
-            let backup_path = PathBuf::from(backup_os);
-            if backup_path.exists() {
-                status = "skipped (backup exists)".to_string();
-                final_content = Cow::Borrowed(source);
-            } else {
-                fs::copy(file_path, &backup_path).with_context(|| {
-                    format!("failed to create backup {}", backup_path.display())
-                })?;
-            }
+    fn is_empty(&mut self) -> bool {
+        match self {
+            PlanSource::Bundle(files) => files.peek().is_none(),
+            PlanSource::Dir { paths, .. } => paths.is_empty(),
         }
+    }
 
-        if let Cow::Owned(ref content) = final_content {
-            write_python(file_path, content, metadata)?;
+    /// The entries to apply, with the paths of directory plans no candidate takes; those
+    /// are reported without being read, while a bundle reports its own as it streams.
+    fn into_entries(
+        self,
+        candidates: &HashMap<String, Candidate>,
+    ) -> (PlanFileStream, Vec<String>) {
+        match self {
+            PlanSource::Bundle(files) => (Box::new(files), Vec::new()),
+            PlanSource::Dir { root, paths } => {
+                let (wanted, unmatched): (Vec<String>, Vec<String>) = paths
+                    .into_iter()
+                    .partition(|path| candidates.contains_key(path));
+                (plan_dir_entries(&root, wanted), unmatched)
+            }
         }
     }
+}
 
-    let applied_renames = if matches!(status.as_str(), "minified") {
-        rename_total
+/// Open a plan bundle, decompressing it when it is named `*.gz` or starts with the gzip
+/// magic bytes, and return its header with its file plans.
+///
+/// v2 bundles are parsed one entry at a time so memory stays proportional to the plans in
+/// flight; v1 bundles are a single JSON document and are read whole. A plan directory
+/// yields its plans in path order, reading each as it is reached.
+fn open_plan_bundle(path: &Path) -> anyhow::Result<(PlanBundleHeader, PlanFileStream)> {
+    open_plan_bundle_as::<PlanFile>(path)
+}
+
+/// [`open_plan_bundle`] with entries parsed as `F`, e.g. raw JSON for curation commands
+/// that must keep fields they do not understand.
+fn open_plan_bundle_as<F>(path: &Path) -> anyhow::Result<(PlanBundleHeader, PlanEntryStream<F>)>
+where
+    F: serde::de::DeserializeOwned + Send + 'static,
+{
+    if path.is_dir() {
+        let paths = plan_dir_paths(path)?;
+        return Ok((plan_dir_header(&paths), plan_dir_entries(path, paths)));
+    }
+    let file = fs::File::open(path)
+        .with_context(|| format!("failed to open plan bundle {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let gzip = is_gzip_path(path) || reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+    let mut reader: Box<dyn BufRead + Send> = if gzip {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
     } else {
-        0
+        Box::new(reader)
     };
 
-    if !force_stdout {
-        if show_stats {
-            print_file_status(&display_path, &status, applied_renames, true, quiet);
-        } else if in_place {
-            print_file_status(&display_path, &status, applied_renames, false, quiet);
+    let mut first_line = String::new();
+    reader
+        .read_line(&mut first_line)
+        .with_context(|| format!("failed to read plan bundle {}", path.display()))?;
+
+    // A v1 bundle is one JSON document; a compact one parses as a header with version 1.
+    if let Ok(header) = serde_json::from_str::<PlanBundleHeader>(&first_line) {
+        if header.version >= 2 {
+            check_plan_bundle_version(header.version)?;
+            let files = serde_json::Deserializer::from_reader(reader)
+                .into_iter::<F>()
+                .map(|entry| entry.context("failed to parse plan bundle entry"));
+            return Ok((header, Box::new(files)));
         }
     }
 
-    if diff && matches!(status.as_str(), "minified") && !quiet && !force_stdout {
-        let diff_str =
-            make_unified_diff(&display_path, source, final_content.as_ref(), diff_context);
-        println!("{}", diff_str);
-    }
+    let mut contents = first_line;
+    reader
+        .read_to_string(&mut contents)
+        .with_context(|| format!("failed to read plan bundle {}", path.display()))?;
+    let bundle: PlanBundle<F> = serde_json::from_str(&contents)?;
+    check_plan_bundle_version(bundle.version)?;
+    let header = PlanBundleHeader {
+        version: bundle.version,
+        extensions: bundle.extensions,
+        errors: Vec::new(),
+        module_roots: Vec::new(),
+        extra: bundle.extra,
+    };
+    Ok((header, Box::new(bundle.files.into_iter().map(Ok))))
+}
 
-    let mut stdout_bytes = None;
-    if force_stdout {
-        let bytes = encode_python(final_content.as_ref(), metadata, &display_path)?;
-        stdout_bytes = Some(bytes);
-    } else if !in_place && !show_stats && !quiet {
-        println!("{}", final_content);
-    }
-
-    let mut stats = DirStats::default();
-    stats.processed = 1;
-    stats.total_renames = applied_renames;
-    match status.as_str() {
-        "minified" => {
-            stats.rewritten = 1;
-            bump_reason(&mut stats, "minified");
-        }
-        "skipped (no renames)" => {
-            stats.skipped_no_change = 1;
-            bump_reason(&mut stats, "no_renames");
-        }
-        "skipped (rewrite aborted)" => {
-            stats.bailouts = 1;
-            bump_reason(&mut stats, "rewrite_aborted");
-        }
-        "skipped (backup exists)" => {
-            stats.bailouts = 1;
-            bump_reason(&mut stats, "backup_exists");
-        }
-        _ => {
-            stats.bailouts = 1;
+fn check_plan_bundle_version(version: u32) -> anyhow::Result<()> {
+    if version > PLAN_BUNDLE_VERSION {
+        return Err(TsrsError::PlanVersion {
+            found: version,
+            supported: PLAN_BUNDLE_VERSION,
         }
+        .into());
     }
-    stats.files.push(FileStats {
-        path: display_path.clone(),
-        renames: applied_renames,
-        status: status.clone(),
-    });
+    Ok(())
+}
 
-    let summary_needed =
-        show_stats || fail_on_bailout || fail_on_error || fail_on_change || output_json.is_some();
-    if summary_needed && !force_stdout {
-        let output_target = if in_place {
-            display_path.clone()
-        } else {
-            "stdout".to_string()
-        };
-        print_summary(
-            &stats,
-            show_stats,
-            json_output,
-            dry_run,
-            &output_target,
-            output_json,
-        )?;
-    }
+#[derive(Debug, Serialize, Deserialize)]
+struct PlanFile {
+    path: String,
+    /// Copy of `plan.module`, so entries can be matched by module without parsing plans.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    module: Option<String>,
+    plan: MinifyPlan,
+}
 
-    Ok((stats, stdout_bytes))
+/// Just enough of a [`PlanFile`] to match it by module name; bundles written before
+/// entries carried `module` fall back to the plan's own.
+#[derive(Deserialize)]
+struct PlanFileModule {
+    path: String,
+    #[serde(default)]
+    module: Option<String>,
+    #[serde(default)]
+    plan: Option<PlanModule>,
 }
 
-#[allow(dead_code)]
-fn minify_plan_dir(
-    input_dir: &PathBuf,
-    out_path: &PathBuf,
-    includes: &[String],
-    include_file: Option<&PathBuf>,
-    excludes: &[String],
-    exclude_file: Option<&PathBuf>,
-    jobs: Option<usize>,
-    include_hidden: bool,
-    follow_symlinks: bool,
-    glob_case_insensitive: Option<bool>,
-    quiet: bool,
-) -> anyhow::Result<()> {
-    minify_plan_dir_with_depth(
-        input_dir,
-        out_path,
-        includes,
-        include_file,
-        excludes,
-        exclude_file,
-        jobs,
-        include_hidden,
-        follow_symlinks,
-        glob_case_insensitive,
-        None,
-        false,
-        quiet,
-    )
+#[derive(Deserialize)]
+struct PlanModule {
+    module: String,
 }
 
-fn minify_plan_dir_with_depth(
-    input_dir: &PathBuf,
-    out_path: &PathBuf,
-    includes: &[String],
-    include_file: Option<&PathBuf>,
-    excludes: &[String],
-    exclude_file: Option<&PathBuf>,
-    jobs: Option<usize>,
-    include_hidden: bool,
-    follow_symlinks: bool,
-    glob_case_insensitive: Option<bool>,
-    max_depth: Option<usize>,
-    respect_gitignore: bool,
-    quiet: bool,
-) -> anyhow::Result<()> {
-    let input_dir = canonicalize_directory(input_dir.as_path())?;
-    if !input_dir.is_dir() {
-        anyhow::bail!("Input '{}' is not a directory", input_dir.display());
-    }
+/// Totals of a plan bundle, from `apply-plan-dir --summary-only`.
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleSummary {
+    version: u32,
+    #[serde(flatten)]
+    plans: PlanSummary,
+    /// Files `minify-plan-dir` could not read or plan, listed in the bundle header.
+    plan_errors: usize,
+    /// Planned paths that exist under the input directory.
+    present: usize,
+    /// Planned paths missing from the input directory, sorted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    missing: Vec<String>,
+}
 
-    let mut include_patterns = if includes.is_empty() {
-        vec!["**/*.py".to_string()]
-    } else {
-        includes.to_vec()
+/// Total up a plan bundle without walking the input directory or reading any source;
+/// each planned path is only checked for existence.
+fn summarize_plan_bundle(input_dir: &Path, plan_path: &Path) -> anyhow::Result<BundleSummary> {
+    let (header, files) = open_plan_bundle(plan_path)?;
+    let mut summary = BundleSummary {
+        version: header.version,
+        plans: PlanSummary::default(),
+        plan_errors: header.errors.len(),
+        present: 0,
+        missing: Vec::new(),
     };
-    if let Some(path) = include_file {
-        include_patterns.extend(read_pattern_file(path.as_path())?);
-    }
-    let glob_case_insensitive = glob_case_insensitive.unwrap_or(cfg!(windows));
-    let include_glob = build_globset(&include_patterns, glob_case_insensitive)?;
-    let mut exclude_patterns = merged_exclude_patterns(excludes);
-    if let Some(path) = exclude_file {
-        exclude_patterns.extend(read_pattern_file(path.as_path())?);
+    for entry in files {
+        let entry = entry?;
+        summary.plans.add(&entry.plan);
+        if input_dir.join(&entry.path).exists() {
+            summary.present += 1;
+        } else {
+            summary.missing.push(entry.path);
+        }
     }
-    let exclude_glob = build_globset(&exclude_patterns, glob_case_insensitive)?;
-
-    let mut errors = 0usize;
-    let mut candidates: Vec<Candidate> = Vec::new();
+    summary.missing.sort_unstable();
+    Ok(summary)
+}
 
-    let walker = build_walker(
-        &input_dir,
-        include_hidden,
-        follow_symlinks,
-        max_depth,
-        respect_gitignore,
+fn print_bundle_summary(
+    summary: &BundleSummary,
+    json_output: bool,
+    output_json: Option<&Path>,
+) -> anyhow::Result<()> {
+    if let Some(path) = output_json {
+        let mut json = serde_json::to_string_pretty(summary)?;
+        json.push('\n');
+        fs::write(path, json)?;
+    }
+    if json_output {
+        println!("{}", serde_json::to_string_pretty(summary)?);
+        return Ok(());
+    }
+    let plans = &summary.plans;
+    println!(
+        "Plan bundle v{}: {} files ({} skip-file), {} functions, {} renames, {} module renames",
+        summary.version,
+        plans.files,
+        plans.skipped_files,
+        plans.functions,
+        plans.renames,
+        plans.module_renames
     );
+    println!(
+        "Functions flagged: {} match_statement, {} comprehension, {} nested_functions",
+        plans.with_match_statement, plans.with_comprehension, plans.with_nested_functions
+    );
+    if summary.plan_errors > 0 {
+        println!("Files the bundle could not plan: {}", summary.plan_errors);
+    }
+    println!(
+        "Planned paths present under the input: {} of {}",
+        summary.present, plans.files
+    );
+    for path in &summary.missing {
+        println!("  missing: {}", path);
+    }
+    Ok(())
+}
 
-    for entry in walker {
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(err) => {
-                errors += 1;
-                warn!("walk error: {}", err);
-                continue;
-            }
-        };
-
-        let file_type = match entry.file_type() {
-            Some(ft) => ft,
-            None => continue,
-        };
-
-        if file_type.is_dir() {
+/// Print a readable summary of a plan bundle, optionally limited to one file.
+fn plan_show(plan_path: &Path, file: Option<&str>) -> anyhow::Result<()> {
+    let (header, files) = open_plan_bundle(plan_path)?;
+    let wanted = file.map(|file| normalize_rel_path(Path::new(file)));
+    if wanted.is_none() {
+        println!(
+            "Plan bundle v{} (extensions: {})",
+            header.version,
+            header.extensions.join(", ")
+        );
+    }
+    let mut shown = 0;
+    for entry in files {
+        let entry = entry?;
+        if wanted.as_ref().is_some_and(|wanted| *wanted != entry.path) {
             continue;
         }
-
-        if !follow_symlinks && entry.path_is_symlink() {
-            continue;
+        shown += 1;
+        if wanted.is_none() {
+            println!();
         }
-
-        let path = entry.path();
-        let rel_path = match path.strip_prefix(&input_dir) {
-            Ok(rel) => rel,
-            Err(_) => continue,
-        };
-
-        let rel_norm = normalize_rel_path(rel_path);
-
-        if !include_hidden
-            && rel_path.components().any(|comp| {
-                matches!(comp, std::path::Component::Normal(os) if os.to_string_lossy().starts_with('.'))
-            })
-        {
-            debug!("• {} → skipped (hidden path)", rel_norm);
-            continue;
+        let mut out = String::new();
+        render_plan_file(&entry, &mut out);
+        print!("{}", out);
+    }
+    if let Some(wanted) = wanted {
+        if shown == 0 {
+            bail!("plan bundle has no entry for {}", wanted);
         }
+    }
+    Ok(())
+}
 
-        if !include_glob.is_match(rel_norm.as_str()) {
-            debug!("• {} → skipped (not included)", rel_norm);
-            continue;
+fn render_plan_file(entry: &PlanFile, out: &mut String) {
+    let plan = &entry.plan;
+    out.push_str(&format!("{} (module {})\n", entry.path, plan.module));
+    if plan.skip_file {
+        out.push_str("  skipped by # tsrs: skip-file\n");
+    }
+    let options = plan_option_labels(plan);
+    if !options.is_empty() {
+        out.push_str(&format!("  options: {}\n", options.join(", ")));
+    }
+    if !plan.module_renames.is_empty() {
+        out.push_str(&format!(
+            "  module renames: {}\n",
+            format_renames(&plan.module_renames)
+        ));
+    }
+    for function in &plan.functions {
+        out.push_str(&format!("  {}\n", function.qualified_name));
+        if function.renames.is_empty() {
+            out.push_str("    renames: (none)\n");
+        } else {
+            out.push_str(&format!(
+                "    renames: {}\n",
+                format_renames(&function.renames)
+            ));
         }
-        if exclude_glob.is_match(rel_norm.as_str()) {
-            debug!("• {} → skipped (excluded)", rel_norm);
-            continue;
+        if !function.excluded.is_empty() {
+            out.push_str(&format!("    excluded: {}\n", function.excluded.join(", ")));
         }
-
-        if path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| ext.eq_ignore_ascii_case("py"))
-            != Some(true)
-        {
-            debug!("• {} → skipped (non-Python)", rel_norm);
-            continue;
+        let mut flags = Vec::new();
+        if function.has_nested_functions {
+            flags.push("nested functions".to_string());
+        }
+        if function.has_imports {
+            flags.push("imports".to_string());
+        }
+        if function.has_match_statement {
+            flags.push("match statement".to_string());
+        }
+        if function.has_comprehension {
+            flags.push("comprehension".to_string());
+        }
+        if let Some(reason) = function.bailout_reason {
+            flags.push(format!("bailout: {}", reason));
+        }
+        if !flags.is_empty() {
+            out.push_str(&format!("    flags: {}\n", flags.join(", ")));
         }
-
-        candidates.push(Candidate {
-            abs_path: path.to_path_buf(),
-            rel_path: rel_path.to_path_buf(),
-            rel_norm,
-        });
     }
+}
 
-    let jobs = resolve_jobs(jobs)?;
-
-    #[derive(Debug)]
-    enum PlanOutcome {
-        Success { plan: MinifyPlan, renames: usize },
-        ReadError(String),
-        PlanError(String),
+/// Planning options recorded in `plan` that differ from the defaults.
+fn plan_option_labels(plan: &MinifyPlan) -> Vec<String> {
+    let mut labels = Vec::new();
+    if plan.naming == NamingMode::Stable {
+        labels.push("stable names".to_string());
+    }
+    if plan.keep_parameters {
+        labels.push("keep parameters".to_string());
+    }
+    if plan.preserve_annotated_locals {
+        labels.push("preserve annotated locals".to_string());
+    }
+    if plan.min_name_length > 1 {
+        labels.push(format!("min name length {}", plan.min_name_length));
+    }
+    if plan.skip_single_use {
+        labels.push("skip single use".to_string());
+    }
+    if plan.rename_typevars {
+        labels.push("rename typevars".to_string());
     }
+    if !plan.naming_config.is_default() {
+        labels.push(format!(
+            "rename prefix `{}`, alphabet `{}`",
+            plan.naming_config.prefix, plan.naming_config.alphabet
+        ));
+    }
+    if !plan.function_filter.include.is_empty() {
+        labels.push(format!(
+            "include functions {}",
+            plan.function_filter.include.join(", ")
+        ));
+    }
+    if !plan.function_filter.exclude.is_empty() {
+        labels.push(format!(
+            "exclude functions {}",
+            plan.function_filter.exclude.join(", ")
+        ));
+    }
+    labels
+}
 
-    candidates.sort_by(|a, b| a.rel_norm.cmp(&b.rel_norm));
+fn format_renames(renames: &[RenameEntry]) -> String {
+    renames
+        .iter()
+        .map(|entry| format!("{} → {}", entry.original, entry.renamed))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-    let plan_results: Vec<(Candidate, PlanOutcome)> = if candidates.is_empty() {
-        Vec::new()
-    } else if jobs <= 1 {
-        candidates
-            .iter()
-            .map(|candidate| (candidate.clone(), compute_plan(candidate)))
-            .collect()
-    } else {
-        let pool = ThreadPoolBuilder::new().num_threads(jobs).build()?;
-        pool.install(|| {
-            candidates
-                .par_iter()
-                .map(|candidate| (candidate.clone(), compute_plan(candidate)))
-                .collect()
+/// Functions and renames to remove from a plan bundle with `plan-filter`.
+struct PlanDrops {
+    functions: BTreeSet<String>,
+    /// `(qualified function name, original local name)` pairs.
+    renames: BTreeSet<(String, String)>,
+}
+
+impl PlanDrops {
+    fn parse(drop_functions: &[String], drop_renames: &[String]) -> anyhow::Result<Self> {
+        let mut renames = BTreeSet::new();
+        for spec in drop_renames {
+            let Some((function, name)) = spec.rsplit_once(':') else {
+                bail!("--drop-rename expects FUNC:NAME, got '{}'", spec);
+            };
+            if function.is_empty() || name.is_empty() {
+                bail!("--drop-rename expects FUNC:NAME, got '{}'", spec);
+            }
+            renames.insert((function.to_string(), name.to_string()));
+        }
+        Ok(Self {
+            functions: drop_functions.iter().cloned().collect(),
+            renames,
         })
-    };
+    }
+}
 
-    fn compute_plan(candidate: &Candidate) -> PlanOutcome {
-        let source = match read_python(&candidate.abs_path) {
-            Ok((content, _)) => content,
-            Err(err) => return PlanOutcome::ReadError(err.to_string()),
-        };
+/// Copy a plan bundle to `out_path` without the selected functions and renames.
+///
+/// Entries are handled as raw JSON so fields this version does not model survive; each
+/// filtered entry must still parse as a plan. The output is always a v2 bundle. Fails when
+/// a selector matches nothing, which usually means a typo or a stale bundle.
+fn plan_filter(
+    plan_path: &Path,
+    out_path: &Path,
+    drop_functions: &[String],
+    drop_renames: &[String],
+) -> anyhow::Result<()> {
+    let drops = PlanDrops::parse(drop_functions, drop_renames)?;
+    let (mut header, files) = open_plan_bundle_as::<serde_json::Value>(plan_path)?;
+
+    let mut matched_functions = BTreeSet::new();
+    let mut matched_renames = BTreeSet::new();
+    let mut entries = Vec::new();
+    for entry in files {
+        let mut entry = entry?;
+        if let Some(functions) = entry
+            .pointer_mut("/plan/functions")
+            .and_then(serde_json::Value::as_array_mut)
+        {
+            filter_function_plans(
+                functions,
+                &drops,
+                &mut matched_functions,
+                &mut matched_renames,
+            );
+        }
+        serde_json::from_value::<PlanFile>(entry.clone())
+            .context("failed to parse plan bundle entry")?;
+        entries.push(entry);
+    }
 
-        let module_name = derive_module_name(&candidate.rel_path);
-        let plan = match Minifier::plan_from_source(&module_name, &source) {
-            Ok(plan) => plan,
-            Err(err) => return PlanOutcome::PlanError(err.to_string()),
-        };
+    let missing: Vec<String> = drops
+        .functions
+        .difference(&matched_functions)
+        .cloned()
+        .chain(
+            drops
+                .renames
+                .difference(&matched_renames)
+                .map(|(function, name)| format!("{}:{}", function, name)),
+        )
+        .collect();
+    if !missing.is_empty() {
+        bail!("no plan entry matched: {}", missing.join(", "));
+    }
 
-        let renames = plan.functions.iter().map(|f| f.renames.len()).sum();
-        PlanOutcome::Success { plan, renames }
+    header.version = PLAN_BUNDLE_VERSION;
+    if let Some(parent) = out_path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
     }
+    write_plan_bundle(out_path, &header, &entries)?;
+    println!(
+        "Dropped {} functions and {} renames. Output: {}",
+        matched_functions.len(),
+        matched_renames.len(),
+        out_path.display()
+    );
+    Ok(())
+}
 
-    let mut plans: Vec<PlanFile> = Vec::new();
+/// Apply `drops` to a list of raw function plans and, recursively, their `nested` plans.
+///
+/// A dropped rename leaves its original name in `excluded`, as if the planner had kept it.
+fn filter_function_plans(
+    functions: &mut Vec<serde_json::Value>,
+    drops: &PlanDrops,
+    matched_functions: &mut BTreeSet<String>,
+    matched_renames: &mut BTreeSet<(String, String)>,
+) {
+    functions.retain(|function| {
+        let name = function
+            .get("qualified_name")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default();
+        if drops.functions.contains(name) {
+            matched_functions.insert(name.to_string());
+            false
+        } else {
+            true
+        }
+    });
 
-    for (candidate, outcome) in plan_results {
-        match outcome {
-            PlanOutcome::Success { plan, renames } => {
-                print_file_status(&candidate.rel_norm, "planned", renames, true, quiet);
-                plans.push(PlanFile {
-                    path: candidate.rel_norm,
-                    plan,
-                });
+    for function in functions.iter_mut() {
+        let Some(fields) = function.as_object_mut() else {
+            continue;
+        };
+        let name = fields
+            .get("qualified_name")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        for (target, original) in &drops.renames {
+            if *target != name {
+                continue;
             }
-            PlanOutcome::ReadError(message) => {
-                errors += 1;
-                error!(
-                    "failed to read {}: {}",
-                    candidate.abs_path.display(),
-                    message
-                );
+            let Some(renames) = fields
+                .get_mut("renames")
+                .and_then(serde_json::Value::as_array_mut)
+            else {
+                continue;
+            };
+            let before = renames.len();
+            renames.retain(|entry| {
+                entry.get("original").and_then(serde_json::Value::as_str) != Some(original.as_str())
+            });
+            if renames.len() == before {
+                continue;
             }
-            PlanOutcome::PlanError(message) => {
-                errors += 1;
-                error!(
-                    "failed to plan {}: {}",
-                    candidate.abs_path.display(),
-                    message
-                );
+            matched_renames.insert((target.clone(), original.clone()));
+            if let Some(excluded) = fields
+                .entry("excluded")
+                .or_insert_with(|| serde_json::Value::Array(Vec::new()))
+                .as_array_mut()
+            {
+                if !excluded
+                    .iter()
+                    .any(|existing| existing.as_str() == Some(original.as_str()))
+                {
+                    excluded.push(serde_json::Value::from(original.as_str()));
+                }
             }
         }
-    }
-
-    plans.sort_by(|a, b| a.path.cmp(&b.path));
-    let planned_count = plans.len();
-
-    if planned_count == 0 {
-        warn!("no files matched the provided filters; writing empty plan bundle");
-    }
-
-    if let Some(parent) = out_path.parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)?;
+        if let Some(nested) = fields
+            .get_mut("nested")
+            .and_then(serde_json::Value::as_array_mut)
+        {
+            filter_function_plans(nested, drops, matched_functions, matched_renames);
         }
     }
-
-    let bundle = PlanBundle {
-        version: PLAN_BUNDLE_VERSION,
-        files: plans,
-    };
-    fs::write(out_path, serde_json::to_string_pretty(&bundle)?)?;
-
-    println!(
-        "Planned {} files ({} errors). Output: {}",
-        planned_count,
-        errors,
-        out_path.display()
-    );
-
-    Ok(())
 }
 
-#[allow(dead_code)]
-fn apply_plan_dir(
-    input_dir: &PathBuf,
-    plan_path: &PathBuf,
-    out_dir: Option<PathBuf>,
-    includes: &[String],
-    include_file: Option<&PathBuf>,
-    excludes: &[String],
-    exclude_file: Option<&PathBuf>,
-    backup_ext: Option<&str>,
+fn minify_file(
+    file_path: &PathBuf,
     in_place: bool,
     dry_run: bool,
+    backup_ext: Option<&str>,
     show_stats: bool,
     json_output: bool,
-    include_hidden: bool,
-    follow_symlinks: bool,
-    glob_case_insensitive: Option<bool>,
     quiet: bool,
     output_json: Option<&Path>,
-    jobs: Option<usize>,
     fail_on_bailout: bool,
     fail_on_error: bool,
     fail_on_change: bool,
     diff: bool,
     diff_context: usize,
-) -> anyhow::Result<DirStats> {
-    apply_plan_dir_with_depth(
-        input_dir,
-        plan_path,
-        out_dir,
-        includes,
-        include_file,
-        excludes,
-        exclude_file,
-        backup_ext,
+    force_stdout: bool,
+) -> anyhow::Result<(DirStats, Option<Vec<u8>>)> {
+    minify_file_impl(
+        file_path,
         in_place,
         dry_run,
+        backup_ext,
         show_stats,
         json_output,
-        include_hidden,
-        follow_symlinks,
-        glob_case_insensitive,
         quiet,
         output_json,
-        jobs,
         fail_on_bailout,
         fail_on_error,
         fail_on_change,
         diff,
         diff_context,
-        false,
-        None,
+        force_stdout,
+        false, // remove_dead_code defaults to false
+        &CommandContext::default(),
     )
 }
 
-fn apply_plan_dir_with_depth(
-    input_dir: &PathBuf,
-    plan_path: &PathBuf,
-    out_dir: Option<PathBuf>,
-    includes: &[String],
-    include_file: Option<&PathBuf>,
-    excludes: &[String],
-    exclude_file: Option<&PathBuf>,
-    backup_ext: Option<&str>,
+fn minify_file_impl(
+    file_path: &PathBuf,
     in_place: bool,
     dry_run: bool,
+    backup_ext: Option<&str>,
     show_stats: bool,
     json_output: bool,
-    include_hidden: bool,
-    follow_symlinks: bool,
-    glob_case_insensitive: Option<bool>,
     quiet: bool,
     output_json: Option<&Path>,
-    jobs: Option<usize>,
     fail_on_bailout: bool,
     fail_on_error: bool,
     fail_on_change: bool,
     diff: bool,
     diff_context: usize,
-    respect_gitignore: bool,
-    max_depth: Option<usize>,
-) -> anyhow::Result<DirStats> {
+    force_stdout: bool,
+    remove_dead_code: bool,
+    ctx: &CommandContext,
+) -> anyhow::Result<(DirStats, Option<Vec<u8>>)> {
     if json_output && !show_stats {
         anyhow::bail!("--json requires --stats");
     }
 
-    let input_dir = canonicalize_directory(input_dir.as_path())?;
-    if !input_dir.is_dir() {
-        anyhow::bail!("Input '{}' is not a directory", input_dir.display());
-    }
-
-    if backup_ext.is_some() && !in_place {
-        anyhow::bail!("--backup-ext requires --in-place");
-    }
-
-    if in_place && out_dir.is_some() {
-        anyhow::bail!("Cannot use --out-dir with --in-place");
-    }
+    let (source, metadata) = read_python(file_path, ctx.decode)?;
+    let module_name = file_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| file_path.to_string_lossy().to_string());
 
-    let plan_contents = fs::read_to_string(plan_path)?;
-    let bundle: PlanBundle = serde_json::from_str(&plan_contents)?;
-    if bundle.version > PLAN_BUNDLE_VERSION {
-        anyhow::bail!(
-            "unsupported plan bundle version: {} (supported: {})",
-            bundle.version,
-            PLAN_BUNDLE_VERSION
-        );
-    }
-    let mut plan_map: HashMap<String, MinifyPlan> = HashMap::new();
-    for file_plan in bundle.files {
-        plan_map.insert(file_plan.path, file_plan.plan);
-    }
+    let mut plan = Minifier::plan_from_source(&module_name, &source)?;
 
-    if plan_map.is_empty() {
-        anyhow::bail!("Plan bundle contains no files");
+    // Filter plan if --remove-dead-code is requested
+    if remove_dead_code {
+        let dead_code = detect_dead_code(&source, &module_name, quiet, ctx)?;
+        plan = filter_plan_for_dead_code(plan, &dead_code);
     }
 
-    let plan_map = Arc::new(plan_map);
+    apply_plan_to_file(
+        file_path,
+        &source,
+        &metadata,
+        &plan,
+        in_place,
+        dry_run,
+        backup_ext,
+        show_stats,
+        json_output,
+        quiet,
+        output_json,
+        fail_on_bailout,
+        fail_on_error,
+        fail_on_change,
+        diff,
+        diff_context,
+        force_stdout,
+        None,
+        None,
+        false,
+        None,
+        None,
+        false,
+        false,
+        ctx,
+    )
+}
 
-    let resolved_out_dir = if in_place {
-        input_dir.clone()
+/// Run the `--level` source transforms (when `pipeline` has any) and plan the result, as
+/// the single-file `minify` command does.
+fn plan_single_file(
+    module_name: &str,
+    source: &str,
+    pipeline: &TransformPipeline,
+    is_package_init: bool,
+    options: PlanOptions,
+) -> tsrs::error::Result<(Option<PipelineOutput>, MinifyPlan)> {
+    let transformed = if pipeline.is_empty() {
+        None
     } else {
-        out_dir.unwrap_or_else(|| default_output_dir(&input_dir))
+        let context = TransformContext {
+            module_name,
+            is_package_init,
+        };
+        Some(pipeline.run(&context, source)?)
     };
+    let planned = transformed
+        .as_ref()
+        .map_or(source, |output| output.source.as_str());
+    let plan = Minifier::plan_from_source_with_options(module_name, planned, options)?;
+    log_plan_warnings(&plan, module_name);
+    Ok((transformed, plan))
+}
 
-    if !in_place {
-        let out_norm = normalize_output_path_guard(&resolved_out_dir)?;
-
-        if out_norm.starts_with(&input_dir) {
-            anyhow::bail!("--out-dir cannot be inside the input directory");
-        }
-
-        if resolved_out_dir.exists() {
-            if !resolved_out_dir.is_dir() {
-                anyhow::bail!(
-                    "Output '{}' exists and is not a directory",
-                    resolved_out_dir.display()
-                );
-            }
-            if !dry_run && resolved_out_dir.read_dir()?.next().is_some() {
-                anyhow::bail!(
-                    "Output directory '{}' already exists and is not empty",
-                    resolved_out_dir.display()
-                );
-            }
-        } else if !dry_run {
-            fs::create_dir_all(&resolved_out_dir)?;
-        }
+/// Report a single file left unchanged by `--skip-parse-errors`.
+///
+/// The hint goes to the log so stdout still carries the (unchanged) source when it would
+/// have carried the rewritten one.
+fn skip_unparsable_file(
+    file_path: &Path,
+    source: &str,
+    metadata: &TextMetadata,
+    err: &TsrsError,
+    in_place: bool,
+    dry_run: bool,
+    show_stats: bool,
+    json_output: bool,
+    quiet: bool,
+    output_json: Option<&Path>,
+    force_stdout: bool,
+    ctx: &CommandContext,
+) -> anyhow::Result<(DirStats, Option<Vec<u8>>)> {
+    if json_output && !show_stats {
+        anyhow::bail!("--json requires --stats");
     }
 
-    let mut include_patterns = if includes.is_empty() {
-        vec!["**/*.py".to_string()]
-    } else {
-        includes.to_vec()
+    let line = match err {
+        TsrsError::Parse { line, .. } => *line,
+        _ => 0,
     };
-    if let Some(path) = include_file {
-        include_patterns.extend(read_pattern_file(path.as_path())?);
-    }
-    let glob_case_insensitive = glob_case_insensitive.unwrap_or(cfg!(windows));
-    let include_glob = build_globset(&include_patterns, glob_case_insensitive)?;
-    let mut exclude_patterns = merged_exclude_patterns(excludes);
-    if let Some(path) = exclude_file {
-        exclude_patterns.extend(read_pattern_file(path.as_path())?);
+    let issue = SyntaxIssue::classify(source, line);
+    let status = FinalStatusKind::SkippedSyntax(issue).label();
+    let display_path = portable_path(file_path);
+    if !quiet {
+        warn!("{}: {} at line {}", display_path, status, line);
     }
-    let exclude_glob = build_globset(&exclude_patterns, glob_case_insensitive)?;
 
-    let jobs = resolve_jobs(jobs)?;
+    let mut stdout_bytes = None;
+    if force_stdout {
+        stdout_bytes = Some(encode_python(source, metadata, &display_path)?);
+    } else if !in_place && !show_stats && !quiet {
+        println!("{}", source);
+    }
 
     let mut stats = DirStats::default();
-    let mut candidates: Vec<Candidate> = Vec::new();
-
-    let walker = build_walker(
-        &input_dir,
-        include_hidden,
-        follow_symlinks,
-        max_depth,
-        respect_gitignore,
-    );
-
-    for entry in walker {
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(err) => {
-                stats.errors += 1;
-                warn!("walk error: {}", err);
-                continue;
-            }
-        };
+    stats.processed = 1;
+    stats.skipped_no_change = 1;
+    bump_reason(&mut stats, issue.reason());
+    if metadata.lossy {
+        bump_reason(&mut stats, "lossy_decode");
+    }
+    stats.files.push(FileStats {
+        path: display_path,
+        renames: 0,
+        status: status.to_string(),
+        bytes_saved: 0,
+        skipped_functions: Vec::new(),
+        functions: None,
+        warnings: Vec::new(),
+        lossy_decode: metadata.lossy,
+        fuzzy_matched: Vec::new(),
+        elapsed_ms: None,
+        dead_assignment_bytes: 0,
+        annotation_bytes: 0,
+    });
 
-        let file_type = match entry.file_type() {
-            Some(ft) => ft,
-            None => continue,
+    if (show_stats || output_json.is_some()) && !force_stdout {
+        let output_target = if in_place {
+            file_path.display().to_string()
+        } else {
+            "stdout".to_string()
         };
+        print_summary(
+            &stats,
+            show_stats,
+            json_output,
+            ctx.verbose_stats,
+            dry_run,
+            &output_target,
+            output_json,
+        )?;
+    }
 
-        if file_type.is_dir() {
-            continue;
-        }
-
-        if !follow_symlinks && entry.path_is_symlink() {
-            continue;
-        }
-
-        let path = entry.path();
-        let rel_path = match path.strip_prefix(&input_dir) {
-            Ok(rel) => rel,
-            Err(_) => continue,
-        };
+    Ok((stats, stdout_bytes))
+}
 
-        let rel_norm = normalize_rel_path(rel_path);
+/// With `--allow-partial-plan`, a copy of `plan` without the renames that fail
+/// [`MinifyPlan::validate`] against `source`, warning about each one dropped.
+fn partial_plan(
+    plan: &MinifyPlan,
+    source: &str,
+    allow_partial_plan: bool,
+    label: &str,
+) -> Option<MinifyPlan> {
+    if !allow_partial_plan {
+        return None;
+    }
+    let mut pruned = plan.clone();
+    let dropped = pruned.prune_invalid_renames(source);
+    if dropped.is_empty() {
+        return None;
+    }
+    for err in dropped {
+        file_log!(WARN, "{}: {} (rename skipped)", label, err);
+    }
+    Some(pruned)
+}
 
-        if !include_hidden
-            && rel_path.components().any(|comp| {
-                matches!(comp, std::path::Component::Normal(os) if os.to_string_lossy().starts_with('.'))
-            })
-        {
-            debug!("• {} → skipped (hidden path)", rel_norm);
-            continue;
-        }
+fn apply_plan_to_file(
+    file_path: &PathBuf,
+    source: &str,
+    metadata: &TextMetadata,
+    plan: &MinifyPlan,
+    in_place: bool,
+    dry_run: bool,
+    backup_ext: Option<&str>,
+    show_stats: bool,
+    json_output: bool,
+    quiet: bool,
+    output_json: Option<&Path>,
+    fail_on_bailout: bool,
+    fail_on_error: bool,
+    fail_on_change: bool,
+    diff: bool,
+    diff_context: usize,
+    force_stdout: bool,
+    diff_out: Option<&Path>,
+    source_map: Option<&Path>,
+    verify: bool,
+    verify_exec: Option<&Path>,
+    transformed: Option<&PipelineOutput>,
+    allow_partial_plan: bool,
+    warn_collisions: bool,
+    ctx: &CommandContext,
+) -> anyhow::Result<(DirStats, Option<Vec<u8>>)> {
+    if json_output && !show_stats {
+        anyhow::bail!("--json requires --stats");
+    }
 
-        if !include_glob.is_match(rel_norm.as_str()) {
-            debug!("• {} → skipped (not included)", rel_norm);
-            continue;
-        }
-        if exclude_glob.is_match(rel_norm.as_str()) {
-            debug!("• {} → skipped (excluded)", rel_norm);
-            continue;
-        }
+    if backup_ext.is_some() && !in_place {
+        anyhow::bail!("--backup-ext requires --in-place");
+    }
 
-        if path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| ext.eq_ignore_ascii_case("py"))
-            != Some(true)
-        {
-            debug!("• {} → skipped (non-Python)", rel_norm);
-            continue;
-        }
+    // With source transforms the plan was made against the transformed source.
+    let imports_removed = transformed.map_or(0, |output| output.changes(RemoveUnusedImports::NAME));
+    let base = transformed.map_or(source, |output| output.source.as_str());
+    let pruned = partial_plan(
+        plan,
+        base,
+        allow_partial_plan,
+        &file_path.display().to_string(),
+    );
+    let plan = pruned.as_ref().unwrap_or(plan);
+    let rename_total: usize = plan.functions.iter().map(|f| f.renames.len()).sum();
+    let warnings = if warn_collisions {
+        collision_warnings(plan, base, &file_path.display().to_string())
+    } else {
+        Vec::new()
+    };
 
-        if !plan_map.contains_key(&rel_norm) {
-            debug!("• {} → skipped (no plan)", rel_norm);
-            continue;
+    let mut status;
+    let mut final_content: Cow<'_, str> = Cow::Borrowed(source);
+    let mut skipped_functions = Vec::new();
+    let mut rewrite_outcome = None;
+    let mut fuzzy_matched = Vec::new();
+
+    if plan.skip_file {
+        status = "skipped (directive)".to_string();
+    } else if rename_total == 0 && base != source {
+        status = "minified".to_string();
+        final_content = Cow::Owned(base.to_string());
+    } else if rename_total == 0 {
+        status = "skipped (no renames)".to_string();
+    } else {
+        let mut outcome =
+            Minifier::rewrite_with_plan_options(&plan.module, base, plan, ctx.rewrite).map_err(
+                |err| match err {
+                    TsrsError::InvalidPlan { .. } => {
+                        anyhow::anyhow!("{err} (pass --allow-partial-plan to skip invalid renames)")
+                    }
+                    err => err.into(),
+                },
+            )?;
+        skipped_functions = locate_skipped(plan, outcome.skipped.clone());
+        if outcome.source == source {
+            status = "skipped (rewrite aborted)".to_string();
+        } else {
+            status = "minified".to_string();
+            final_content = Cow::Owned(std::mem::take(&mut outcome.source));
+            fuzzy_matched = std::mem::take(&mut outcome.fuzzy_matched);
         }
-
-        candidates.push(Candidate {
-            abs_path: path.to_path_buf(),
-            rel_path: rel_path.to_path_buf(),
-            rel_norm,
-        });
+        rewrite_outcome = Some(outcome);
     }
 
-    candidates.sort_by(|a, b| a.rel_norm.cmp(&b.rel_norm));
-
-    stats.processed = candidates.len();
+    if verify && matches!(status.as_str(), "minified") {
+        if let Err(message) = verify_rewritten(file_path, &final_content, metadata, verify_exec) {
+            error!(
+                "verification failed for {}: {}",
+                file_path.display(),
+                message
+            );
+            status = "failed (verify)".to_string();
+            final_content = Cow::Borrowed(source);
+        }
+    }
 
-    let processor = {
-        let plan_map = Arc::clone(&plan_map);
-        move |candidate: &Candidate| -> FileResult {
-            let candidate_clone = candidate.clone();
-            let (source, metadata) = match read_python(&candidate.abs_path) {
-                Ok(result) => result,
-                Err(err) => {
-                    return FileResult {
-                        candidate: candidate_clone,
-                        outcome: FileOutcome::ReadError {
-                            message: err.to_string(),
-                        },
-                    }
-                }
-            };
+    let display_path = portable_path(file_path);
 
-            let plan = match plan_map.get(&candidate.rel_norm) {
-                Some(plan) => plan,
-                None => {
-                    return FileResult {
-                        candidate: candidate_clone,
-                        outcome: FileOutcome::PlanError {
-                            message: "plan missing".to_string(),
-                        },
-                    }
-                }
-            };
+    if in_place && !dry_run {
+        if let Some(ext) = backup_ext {
+            let mut backup_os = file_path.as_os_str().to_os_string();
+            backup_os.push(ext);
+            let backup_path = PathBuf::from(backup_os);
+            if backup_path.exists() {
+                status = "skipped (backup exists)".to_string();
+                final_content = Cow::Borrowed(source);
+            } else {
+                fs::copy(file_path, &backup_path).with_context(|| {
+                    format!("failed to create backup {}", backup_path.display())
+                })?;
+            }
+        }
 
-            let rename_total: usize = plan.functions.iter().map(|f| f.renames.len()).sum();
-            let has_nested = plan.functions.iter().any(|f| f.has_nested_functions);
+        if let Cow::Owned(ref content) = final_content {
+            write_python(file_path, content, metadata)?;
+        }
+    }
 
-            if has_nested {
-                return FileResult {
-                    candidate: candidate_clone,
-                    outcome: FileOutcome::SkippedNested {
-                        original: source,
-                        metadata,
-                    },
-                };
-            }
+    let applied_renames = if matches!(status.as_str(), "minified") {
+        rename_total
+    } else {
+        0
+    };
 
-            if rename_total == 0 {
-                return FileResult {
-                    candidate: candidate_clone,
-                    outcome: FileOutcome::SkippedNoRenames {
-                        original: source,
-                        metadata,
-                    },
-                };
-            }
+    if let Some(target) = source_map {
+        if status == "minified" && !dry_run {
+            // Only functions whose renames made it into the written output are mapped.
+            let applied = rewrite_outcome
+                .as_ref()
+                .map_or(&[][..], |outcome| outcome.applied.as_slice());
+            let entry = FileSourceMap::from_applied(
+                &source_map_display_path(file_path),
+                plan,
+                applied,
+                &final_content,
+            );
+            write_file_source_map(target, file_path, entry)?;
+        }
+    }
 
-            match Minifier::rewrite_with_plan(&plan.module, &source, plan) {
-                Ok(rewritten) => {
-                    if rewritten == source {
-                        FileResult {
-                            candidate: candidate_clone,
-                            outcome: FileOutcome::SkippedRewriteAborted {
-                                original: source,
-                                metadata,
-                            },
-                        }
-                    } else {
-                        FileResult {
-                            candidate: candidate_clone,
-                            outcome: FileOutcome::Minified {
-                                original: source,
-                                rewritten,
-                                renames: rename_total,
-                                metadata,
-                            },
-                        }
-                    }
-                }
-                Err(err) => FileResult {
-                    candidate: candidate_clone,
-                    outcome: FileOutcome::RewriteError {
-                        message: err.to_string(),
-                    },
-                },
-            }
+    if !force_stdout {
+        if show_stats {
+            print_file_status(&display_path, &status, applied_renames, true, quiet);
+        } else if in_place {
+            print_file_status(&display_path, &status, applied_renames, false, quiet);
         }
-    };
+    }
 
-    let results = execute_parallel_processing(&candidates, jobs, processor)?;
+    let print_diff = diff && !quiet && !force_stdout;
+    let mut written_diff = None;
+    if matches!(status.as_str(), "minified") && (print_diff || diff_out.is_some()) {
+        let diff_str =
+            make_unified_diff(&display_path, source, final_content.as_ref(), diff_context);
+        if print_diff {
+            println!("{}", diff_str);
+        }
+        if let Some(target) = diff_out {
+            written_diff = Some(write_file_diff(target, file_path, &diff_str)?);
+        }
+    }
 
-    finalize_file_results(
-        results,
-        &mut stats,
-        &input_dir,
-        &resolved_out_dir,
-        in_place,
-        dry_run,
-        backup_ext,
-        quiet,
-        show_stats,
-        diff,
-        diff_context,
-    )?;
+    let mut stdout_bytes = None;
+    if force_stdout {
+        let bytes = encode_python(final_content.as_ref(), metadata, &display_path)?;
+        stdout_bytes = Some(bytes);
+    } else if !in_place && !show_stats && !quiet {
+        println!("{}", final_content);
+    }
 
-    let summary_needed =
-        show_stats || fail_on_bailout || fail_on_error || fail_on_change || output_json.is_some();
-    if summary_needed {
-        let output_label = if in_place {
-            input_dir.display().to_string()
+    let mut stats = DirStats::default();
+    stats.processed = 1;
+    stats.total_renames = applied_renames;
+    match status.as_str() {
+        "minified" => {
+            stats.rewritten = 1;
+            stats.imports_removed = imports_removed;
+            if let Some(output) = transformed {
+                stats.stages = output.stages.clone();
+            }
+            bump_reason(&mut stats, "minified");
+        }
+        "skipped (no renames)" => {
+            stats.skipped_no_change = 1;
+            bump_reason(&mut stats, "no_renames");
+        }
+        "skipped (directive)" => {
+            stats.skipped_no_change = 1;
+            bump_reason(&mut stats, "skip_directive");
+        }
+        "skipped (rewrite aborted)" => {
+            stats.bailouts = 1;
+            bump_reason(&mut stats, "rewrite_aborted");
+        }
+        "skipped (backup exists)" => {
+            stats.bailouts = 1;
+            bump_reason(&mut stats, "backup_exists");
+        }
+        "failed (verify)" => {
+            stats.errors = 1;
+            stats.verify_failures = 1;
+            bump_reason(&mut stats, "verify_failed");
+        }
+        _ => {
+            stats.bailouts = 1;
+        }
+    }
+    let skipped_functions = record_skipped_functions(&mut stats, &display_path, skipped_functions);
+    let functions =
+        show_stats.then(|| function_stats(plan, rewrite_outcome.as_ref(), status == "minified"));
+    let annotation_bytes = annotation_bytes(functions.as_deref());
+    if metadata.lossy {
+        bump_reason(&mut stats, "lossy_decode");
+    }
+    if status != "minified" {
+        fuzzy_matched.clear();
+    } else if !fuzzy_matched.is_empty() {
+        bump_reason(&mut stats, "fuzzy_matched");
+    }
+    stats.files.push(FileStats {
+        path: display_path.clone(),
+        renames: applied_renames,
+        status: status.clone(),
+        bytes_saved: if status == "minified" {
+            bytes_saved(source, &final_content)
         } else {
-            resolved_out_dir.display().to_string()
+            0
+        },
+        skipped_functions,
+        functions,
+        warnings,
+        lossy_decode: metadata.lossy,
+        fuzzy_matched,
+        elapsed_ms: None,
+        dead_assignment_bytes: 0,
+        annotation_bytes,
+    });
+    stats.diff_out = written_diff.as_deref().map(portable_path);
+
+    let summary_needed = show_stats
+        || fail_on_bailout
+        || fail_on_error
+        || fail_on_change
+        || output_json.is_some()
+        || stats.diff_out.is_some();
+    if summary_needed && !force_stdout {
+        let output_target = if in_place {
+            display_path.clone()
+        } else {
+            "stdout".to_string()
         };
         print_summary(
             &stats,
             show_stats,
             json_output,
+            ctx.verbose_stats,
             dry_run,
-            &output_label,
+            &output_target,
             output_json,
         )?;
     }
 
-    Ok(stats)
+    Ok((stats, stdout_bytes))
 }
 
 #[allow(dead_code)]
-fn minify_dir(
+fn minify_plan_dir(
     input_dir: &PathBuf,
-    out_dir: Option<PathBuf>,
+    out_path: &PathBuf,
     includes: &[String],
     include_file: Option<&PathBuf>,
     excludes: &[String],
     exclude_file: Option<&PathBuf>,
-    backup_ext: Option<&str>,
-    in_place: bool,
-    dry_run: bool,
-    show_stats: bool,
-    json_output: bool,
+    jobs: Option<usize>,
     include_hidden: bool,
     follow_symlinks: bool,
     glob_case_insensitive: Option<bool>,
     quiet: bool,
-    output_json: Option<&Path>,
-    jobs: Option<usize>,
-    fail_on_bailout: bool,
-    fail_on_error: bool,
-    fail_on_change: bool,
-    diff: bool,
-    diff_context: usize,
-    remove_dead_code: bool,
-) -> anyhow::Result<DirStats> {
-    minify_dir_with_depth(
+) -> anyhow::Result<()> {
+    minify_plan_dir_with_depth(
         input_dir,
-        out_dir,
+        out_path,
         includes,
         include_file,
         excludes,
         exclude_file,
-        backup_ext,
-        in_place,
-        dry_run,
-        show_stats,
-        json_output,
+        jobs,
         include_hidden,
         follow_symlinks,
         glob_case_insensitive,
-        quiet,
-        output_json,
-        jobs,
-        fail_on_bailout,
-        fail_on_error,
-        fail_on_change,
-        diff,
-        diff_context,
+        None,
+        false,
+        false,
+        false,
+        false,
+        &[],
         false,
         None,
-        remove_dead_code,
-    )
+        false,
+        quiet,
+        false,
+        None,
+        &CommandContext::default(),
+    )?;
+    Ok(())
 }
 
-fn minify_dir_with_depth(
+/// Plan every selected file under `input_dir` and return how many could not be read or
+/// planned.
+fn minify_plan_dir_with_depth(
     input_dir: &PathBuf,
-    out_dir: Option<PathBuf>,
+    out_path: &PathBuf,
     includes: &[String],
     include_file: Option<&PathBuf>,
     excludes: &[String],
     exclude_file: Option<&PathBuf>,
-    backup_ext: Option<&str>,
-    in_place: bool,
-    dry_run: bool,
-    show_stats: bool,
-    json_output: bool,
+    jobs: Option<usize>,
     include_hidden: bool,
     follow_symlinks: bool,
     glob_case_insensitive: Option<bool>,
-    quiet: bool,
-    output_json: Option<&Path>,
-    jobs: Option<usize>,
-    fail_on_bailout: bool,
-    fail_on_error: bool,
-    fail_on_change: bool,
-    diff: bool,
-    diff_context: usize,
-    respect_gitignore: bool,
     max_depth: Option<usize>,
-    remove_dead_code: bool,
-) -> anyhow::Result<DirStats> {
+    respect_gitignore: bool,
+    stable_names: bool,
+    rename_module_privates: bool,
+    preserve_annotated_locals: bool,
+    extensions: &[String],
+    no_tsrsignore: bool,
+    changed_since: Option<&str>,
+    changed_since_optional: bool,
+    quiet: bool,
+    plan_dir: bool,
+    errors_out: Option<&Path>,
+    ctx: &CommandContext,
+) -> anyhow::Result<usize> {
     let input_dir = canonicalize_directory(input_dir.as_path())?;
     if !input_dir.is_dir() {
         anyhow::bail!("Input '{}' is not a directory", input_dir.display());
     }
 
-    if json_output && !show_stats {
-        anyhow::bail!("--json requires --stats");
+    let extensions = resolve_extensions(extensions);
+    let walk = candidate_walker(
+        &input_dir,
+        includes,
+        include_file,
+        excludes,
+        exclude_file,
+        &extensions,
+        include_hidden,
+        follow_symlinks,
+        glob_case_insensitive,
+        max_depth,
+        respect_gitignore,
+        no_tsrsignore,
+        ctx.no_default_excludes,
+    )
+    .walk()?;
+    log_walk_patterns(&walk.patterns);
+    let mut errors = walk.errors;
+    let mut candidates = walk.candidates;
+    if let Some(reference) = changed_since {
+        retain_changed(
+            &mut candidates,
+            &input_dir,
+            reference,
+            changed_since_optional,
+        )?;
     }
 
-    if in_place && out_dir.is_some() {
-        anyhow::bail!("Cannot use --out-dir with --in-place");
+    let (jobs, _) = effective_jobs(resolve_jobs(jobs)?, &candidates, ctx.max_memory_mb);
+
+    if ctx.progress.enabled() {
+        ctx.progress.emit(&ProgressEvent::RunStart {
+            command: "minify-plan-dir",
+            input: portable_path(&input_dir),
+            options: serde_json::json!({
+                "out": out_path,
+                "jobs": jobs,
+                "extensions": extensions,
+                "max_depth": max_depth,
+                "stable_names": stable_names,
+                "rename_module_privates": rename_module_privates,
+                "preserve_annotated_locals": preserve_annotated_locals,
+                "changed_since": changed_since,
+                "out_format": if plan_dir { "dir" } else { "bundle" },
+            }),
+        });
     }
+    ctx.progress.emit(&ProgressEvent::FileQueued {
+        total: candidates.len(),
+    });
 
-    if backup_ext.is_some() && !in_place {
-        anyhow::bail!("--backup-ext requires --in-place");
+    #[derive(Debug)]
+    enum PlanOutcome {
+        Success {
+            plan: Box<MinifyPlan>,
+            renames: usize,
+        },
+        ReadError(String),
+        PlanError(String),
+        Unparsable(SyntaxIssue),
+        OverLimit(PlanLimit),
     }
 
-    let resolved_out_dir = if in_place {
-        input_dir.clone()
+    let naming = naming_mode(stable_names);
+
+    let plan_results: Vec<(Candidate, PlanOutcome)> = if candidates.is_empty() {
+        Vec::new()
+    } else if jobs <= 1 {
+        candidates
+            .iter()
+            .map(|candidate| {
+                plan_file(
+                    candidate,
+                    naming,
+                    rename_module_privates,
+                    preserve_annotated_locals,
+                    ctx,
+                )
+            })
+            .collect()
     } else {
-        out_dir.unwrap_or_else(|| default_output_dir(&input_dir))
+        let pool = ThreadPoolBuilder::new().num_threads(jobs).build()?;
+        pool.install(|| {
+            candidates
+                .par_iter()
+                .map(|candidate| {
+                    plan_file(
+                        candidate,
+                        naming,
+                        rename_module_privates,
+                        preserve_annotated_locals,
+                        ctx,
+                    )
+                })
+                .collect()
+        })
     };
 
-    if !in_place {
-        let out_norm = normalize_output_path_guard(&resolved_out_dir)?;
+    fn compute_plan(
+        candidate: &Candidate,
+        naming: NamingMode,
+        rename_module_privates: bool,
+        preserve_annotated_locals: bool,
+        ctx: &CommandContext,
+    ) -> PlanOutcome {
+        let source = match read_python(&candidate.abs_path, ctx.decode) {
+            Ok((content, _)) => content,
+            Err(err) => return PlanOutcome::ReadError(err.to_string()),
+        };
 
-        if out_norm.starts_with(&input_dir) {
-            anyhow::bail!("--out-dir cannot be inside the input directory");
+        let module_name = module_name_for(&candidate.rel_path, &ctx.module_roots);
+        let options = plan_options(
+            &candidate.rel_path,
+            &ctx.plan,
+            naming,
+            rename_module_privates,
+            preserve_annotated_locals,
+        );
+        let plan = match Minifier::plan_from_source_with_options(&module_name, &source, options) {
+            Ok(plan) => plan,
+            Err(TsrsError::PlanLimit { limit, .. }) => return PlanOutcome::OverLimit(limit),
+            Err(err) => {
+                return match err.syntax_issue(&source) {
+                    Some(issue) => PlanOutcome::Unparsable(issue),
+                    None => PlanOutcome::PlanError(err.to_string()),
+                }
+            }
+        };
+
+        log_plan_warnings(&plan, &candidate.rel_norm);
+        let renames = plan.functions.iter().map(|f| f.renames.len()).sum();
+        PlanOutcome::Success {
+            plan: Box::new(plan),
+            renames,
         }
+    }
 
-        if resolved_out_dir.exists() {
-            if !resolved_out_dir.is_dir() {
-                anyhow::bail!(
-                    "Output '{}' exists and is not a directory",
-                    resolved_out_dir.display()
+    /// Plan one file and report it to `--progress-json`.
+    fn plan_file(
+        candidate: &Candidate,
+        naming: NamingMode,
+        rename_module_privates: bool,
+        preserve_annotated_locals: bool,
+        ctx: &CommandContext,
+    ) -> (Candidate, PlanOutcome) {
+        let started = Instant::now();
+        let outcome = compute_plan(
+            candidate,
+            naming,
+            rename_module_privates,
+            preserve_annotated_locals,
+            ctx,
+        );
+        let (status, renames) = match &outcome {
+            PlanOutcome::Success { renames, .. } => ("planned", *renames),
+            PlanOutcome::ReadError(_) => ("read_error", 0),
+            PlanOutcome::PlanError(_) => ("plan_error", 0),
+            PlanOutcome::Unparsable(issue) => (issue.reason(), 0),
+            PlanOutcome::OverLimit(limit) => (limit.reason(), 0),
+        };
+        ctx.progress
+            .file_done(&candidate.rel_norm, status, renames, started.elapsed());
+        (candidate.clone(), outcome)
+    }
+
+    let mut plans: Vec<PlanFile> = Vec::new();
+    let mut plan_errors: Vec<PlanFileError> = Vec::new();
+
+    for (candidate, outcome) in plan_results {
+        match outcome {
+            PlanOutcome::Success { plan, renames } => {
+                print_file_status(&candidate.rel_norm, "planned", renames, true, quiet);
+                plans.push(PlanFile {
+                    path: candidate.rel_norm,
+                    module: Some(plan.module.clone()),
+                    plan: *plan,
+                });
+            }
+            PlanOutcome::ReadError(message) => {
+                errors += 1;
+                error!(
+                    "failed to read {}: {}",
+                    candidate.abs_path.display(),
+                    message
                 );
+                plan_errors.push(PlanFileError {
+                    path: candidate.rel_norm,
+                    kind: "read_error".to_string(),
+                    message,
+                });
             }
-            if !dry_run && resolved_out_dir.read_dir()?.next().is_some() {
-                anyhow::bail!(
-                    "Output directory '{}' already exists and is not empty",
-                    resolved_out_dir.display()
+            PlanOutcome::PlanError(message) => {
+                errors += 1;
+                error!(
+                    "failed to plan {}: {}",
+                    candidate.abs_path.display(),
+                    message
                 );
+                plan_errors.push(PlanFileError {
+                    path: candidate.rel_norm,
+                    kind: "plan_error".to_string(),
+                    message,
+                });
+            }
+            // Left out of the bundle without counting as an error, as minify-dir does by default.
+            PlanOutcome::Unparsable(issue) => {
+                let status = FinalStatusKind::SkippedSyntax(issue).label();
+                print_file_status(&candidate.rel_norm, status, 0, false, quiet);
+            }
+            PlanOutcome::OverLimit(limit) => {
+                let status = FinalStatusKind::SkippedPlanLimit(limit).label();
+                print_file_status(&candidate.rel_norm, status, 0, false, quiet);
             }
-        } else if !dry_run {
-            fs::create_dir_all(&resolved_out_dir)?;
         }
     }
 
-    let jobs = resolve_jobs(jobs)?;
-
-    let mut stats = DirStats::default();
+    plans.sort_by(|a, b| a.path.cmp(&b.path));
+    let planned_count = plans.len();
 
-    let mut include_patterns = if includes.is_empty() {
-        vec!["**/*.py".to_string()]
-    } else {
-        includes.to_vec()
-    };
-    if let Some(path) = include_file {
-        include_patterns.extend(read_pattern_file(path.as_path())?);
-    }
-    let glob_case_insensitive = glob_case_insensitive.unwrap_or(cfg!(windows));
-    let include_glob = build_globset(&include_patterns, glob_case_insensitive)?;
-    let mut exclude_patterns = merged_exclude_patterns(excludes);
-    if let Some(path) = exclude_file {
-        exclude_patterns.extend(read_pattern_file(path.as_path())?);
+    if planned_count == 0 {
+        warn!("no files matched the provided filters; writing empty plan bundle");
     }
-    let exclude_glob = build_globset(&exclude_patterns, glob_case_insensitive)?;
-
-    let mut candidates: Vec<Candidate> = Vec::new();
 
-    let walker = build_walker(
-        &input_dir,
-        include_hidden,
-        follow_symlinks,
-        max_depth,
-        respect_gitignore,
-    );
+    if let Some(path) = errors_out {
+        let mut json = serde_json::to_string_pretty(&plan_errors)?;
+        json.push('\n');
+        fs::write(path, json).with_context(|| format!("failed to write {}", path.display()))?;
+    }
 
-    for entry in walker {
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(err) => {
-                stats.errors += 1;
-                warn!("walk error: {}", err);
-                continue;
+    if plan_dir {
+        write_plan_dir(out_path, &plans)?;
+    } else {
+        if let Some(parent) = out_path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
             }
-        };
-
-        let file_type = match entry.file_type() {
-            Some(ft) => ft,
-            None => continue,
-        };
-
-        if file_type.is_dir() {
-            continue;
-        }
-
-        if !follow_symlinks && entry.path_is_symlink() {
-            continue;
         }
 
-        let path = entry.path();
-        let rel_path = match path.strip_prefix(&input_dir) {
-            Ok(rel) => rel,
-            Err(_) => continue,
+        let header = PlanBundleHeader {
+            version: PLAN_BUNDLE_VERSION,
+            extensions,
+            errors: plan_errors,
+            module_roots: ctx
+                .module_roots
+                .iter()
+                .map(|root| portable_path(root))
+                .collect(),
+            extra: serde_json::Map::new(),
         };
+        write_plan_bundle(out_path, &header, &plans)?;
+    }
 
-        let rel_norm = normalize_rel_path(rel_path);
+    println!(
+        "Planned {} files ({} errors). Output: {}",
+        planned_count,
+        errors,
+        out_path.display()
+    );
+    ctx.progress.run_end(&serde_json::json!({
+        "processed": candidates.len(),
+        "planned": planned_count,
+        "errors": errors,
+    }));
 
-        if !include_hidden
-            && rel_path.components().any(|comp| {
-                matches!(comp, std::path::Component::Normal(os) if os.to_string_lossy().starts_with('.'))
-            })
-        {
-            debug!("• {} → skipped (hidden path)", rel_norm);
-            continue;
-        }
+    Ok(errors)
+}
 
-        if !include_glob.is_match(rel_norm.as_str()) {
-            debug!("• {} → skipped (not included)", rel_norm);
-            continue;
-        }
-        if exclude_glob.is_match(rel_norm.as_str()) {
-            debug!("• {} → skipped (excluded)", rel_norm);
-            continue;
-        }
+/// One file's line in the `size-audit` report.
+#[derive(Debug, Serialize)]
+struct SizeAuditFile {
+    path: String,
+    bytes: usize,
+    /// Bytes the planned renames would save, from each rename's occurrence count.
+    renames_saved: usize,
+    /// Bytes removed by stripping docstrings, which every rewrite does.
+    docstrings_saved: usize,
+    estimated_bytes: usize,
+    /// Why minify-dir would leave the file as it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped: Option<&'static str>,
+}
 
-        if path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| ext.eq_ignore_ascii_case("py"))
-            != Some(true)
-        {
-            debug!("• {} → skipped (non-Python)", rel_norm);
-            continue;
-        }
+#[derive(Debug, Default, Serialize)]
+struct SizeAuditReport {
+    files: Vec<SizeAuditFile>,
+    bytes: usize,
+    renames_saved: usize,
+    docstrings_saved: usize,
+    estimated_bytes: usize,
+    errors: usize,
+}
 
-        candidates.push(Candidate {
-            abs_path: path.to_path_buf(),
-            rel_path: rel_path.to_path_buf(),
-            rel_norm,
-        });
+fn size_audit(
+    input_dir: &Path,
+    includes: &[String],
+    excludes: &[String],
+    jobs: Option<usize>,
+    max_depth: Option<usize>,
+    extensions: &[String],
+    stable_names: bool,
+    rename_module_privates: bool,
+    json: bool,
+    ctx: &CommandContext,
+) -> anyhow::Result<()> {
+    let input_dir = canonicalize_directory(input_dir)?;
+    if !input_dir.is_dir() {
+        anyhow::bail!("Input '{}' is not a directory", input_dir.display());
     }
 
-    candidates.sort_by(|a, b| a.rel_norm.cmp(&b.rel_norm));
+    let extensions = resolve_extensions(extensions);
+    let walk = candidate_walker(
+        &input_dir,
+        includes,
+        None,
+        excludes,
+        None,
+        &extensions,
+        false,
+        false,
+        None,
+        max_depth,
+        false,
+        false,
+        ctx.no_default_excludes,
+    )
+    .walk()?;
+    let naming = naming_mode(stable_names);
+    let jobs = resolve_jobs(jobs)?;
 
-    stats.processed = candidates.len();
+    let audit = |candidate: &Candidate| -> anyhow::Result<SizeAuditFile> {
+        let (source, _) = read_python(&candidate.abs_path, ctx.decode)?;
+        audit_file(candidate, &source, naming, rename_module_privates, ctx)
+    };
+    let results: Vec<(&Candidate, anyhow::Result<SizeAuditFile>)> = if jobs <= 1 {
+        walk.candidates
+            .iter()
+            .map(|candidate| (candidate, audit(candidate)))
+            .collect()
+    } else {
+        let pool = ThreadPoolBuilder::new().num_threads(jobs).build()?;
+        pool.install(|| {
+            walk.candidates
+                .par_iter()
+                .map(|candidate| (candidate, audit(candidate)))
+                .collect()
+        })
+    };
 
-    let processor = |candidate: &Candidate| -> FileResult {
-        let candidate_clone = candidate.clone();
-        let (source, metadata) = match read_python(&candidate.abs_path) {
-            Ok(result) => result,
-            Err(err) => {
-                return FileResult {
-                    candidate: candidate_clone,
-                    outcome: FileOutcome::ReadError {
-                        message: err.to_string(),
-                    },
-                }
+    let mut report = SizeAuditReport {
+        errors: walk.errors,
+        ..SizeAuditReport::default()
+    };
+    for (candidate, result) in results {
+        match result {
+            Ok(file) => {
+                report.bytes += file.bytes;
+                report.renames_saved += file.renames_saved;
+                report.docstrings_saved += file.docstrings_saved;
+                report.estimated_bytes += file.estimated_bytes;
+                report.files.push(file);
             }
-        };
-
-        let module_name = derive_module_name(&candidate.rel_path);
-        let mut plan = match Minifier::plan_from_source(&module_name, &source) {
-            Ok(plan) => plan,
             Err(err) => {
-                return FileResult {
-                    candidate: candidate_clone,
-                    outcome: FileOutcome::PlanError {
-                        message: err.to_string(),
-                    },
-                }
+                report.errors += 1;
+                error!(
+                    "failed to audit {}: {:#}",
+                    candidate.abs_path.display(),
+                    err
+                );
             }
-        };
+        }
+    }
+    report.files.sort_by(|a, b| a.path.cmp(&b.path));
 
-        // Filter plan if --remove-dead-code is requested
-        if remove_dead_code {
-            let dead_code = match detect_dead_code(&source, &module_name, quiet) {
-                Ok(dead_code) => dead_code,
-                Err(_err) => {
-                    // If dead code detection fails, just continue with unfiltered plan
-                    Vec::new()
-                }
-            };
-            plan = filter_plan_for_dead_code(plan, &dead_code);
-        }
-
-        let rename_total: usize = plan.functions.iter().map(|f| f.renames.len()).sum();
-        let has_nested = plan.functions.iter().any(|f| f.has_nested_functions);
-
-        if has_nested {
-            return FileResult {
-                candidate: candidate_clone,
-                outcome: FileOutcome::SkippedNested {
-                    original: source,
-                    metadata,
-                },
-            };
-        }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_size_audit(&report);
+    }
+    Ok(())
+}
 
-        if rename_total == 0 {
-            return FileResult {
-                candidate: candidate_clone,
-                outcome: FileOutcome::SkippedNoRenames {
-                    original: source,
-                    metadata,
-                },
-            };
-        }
+/// Estimate one file the way minify-dir would treat it: files it skips keep their size.
+fn audit_file(
+    candidate: &Candidate,
+    source: &str,
+    naming: NamingMode,
+    rename_module_privates: bool,
+    ctx: &CommandContext,
+) -> anyhow::Result<SizeAuditFile> {
+    let module_name = module_name_for(&candidate.rel_path, &ctx.module_roots);
+    let options = plan_options(
+        &candidate.rel_path,
+        &ctx.plan,
+        naming,
+        rename_module_privates,
+        false,
+    );
+    let mut file = SizeAuditFile {
+        path: candidate.rel_norm.clone(),
+        bytes: source.len(),
+        renames_saved: 0,
+        docstrings_saved: 0,
+        estimated_bytes: source.len(),
+        skipped: None,
+    };
 
-        match Minifier::rewrite_with_plan(&module_name, &source, &plan) {
-            Ok(rewritten) => {
-                if rewritten == source {
-                    FileResult {
-                        candidate: candidate_clone,
-                        outcome: FileOutcome::SkippedRewriteAborted {
-                            original: source,
-                            metadata,
-                        },
-                    }
-                } else {
-                    FileResult {
-                        candidate: candidate_clone,
-                        outcome: FileOutcome::Minified {
-                            original: source,
-                            rewritten,
-                            renames: rename_total,
-                            metadata,
-                        },
-                    }
-                }
+    let plan = match Minifier::plan_from_source_with_options(&module_name, source, options) {
+        Ok(plan) => plan,
+        Err(err) => match err.syntax_issue(source) {
+            Some(issue) => {
+                file.skipped = Some(FinalStatusKind::SkippedSyntax(issue).label());
+                return Ok(file);
             }
-            Err(err) => FileResult {
-                candidate: candidate_clone,
-                outcome: FileOutcome::RewriteError {
-                    message: err.to_string(),
-                },
-            },
-        }
+            None => return Err(err.into()),
+        },
     };
+    let rename_total: usize = plan.functions.iter().map(|f| f.renames.len()).sum();
+    let renames_saved = plan.estimated_rename_savings();
+    file.skipped = if plan.skip_file {
+        Some(FinalStatusKind::SkippedDirective)
+    } else if plan.functions.iter().any(|f| f.has_nested_functions) {
+        Some(FinalStatusKind::SkippedNested)
+    } else if rename_total == 0 {
+        Some(FinalStatusKind::SkippedNoRenames)
+    } else if renames_saved == 0 {
+        Some(FinalStatusKind::SkippedRewriteAborted)
+    } else {
+        None
+    }
+    .map(FinalStatusKind::label);
+
+    if file.skipped.is_none() {
+        file.renames_saved = renames_saved;
+        file.docstrings_saved = Minifier::docstring_bytes(&module_name, source)?;
+        file.estimated_bytes = file
+            .bytes
+            .saturating_sub(file.renames_saved + file.docstrings_saved);
+    }
+    Ok(file)
+}
 
-    let results = execute_parallel_processing(&candidates, jobs, processor)?;
+fn print_size_audit(report: &SizeAuditReport) {
+    let width = report
+        .files
+        .iter()
+        .map(|file| file.path.len())
+        .max()
+        .unwrap_or(0)
+        .max("file".len());
+    println!(
+        "{:<width$}  {:>9}  {:>9}  {:>10}  {:>9}",
+        "file", "bytes", "renames", "docstrings", "estimated"
+    );
+    for file in &report.files {
+        let row = format!(
+            "{:<width$}  {:>9}  {:>9}  {:>10}  {:>9}",
+            file.path, file.bytes, file.renames_saved, file.docstrings_saved, file.estimated_bytes
+        );
+        match file.skipped {
+            Some(reason) => println!("{}  {}", row, reason),
+            None => println!("{}", row),
+        }
+    }
+    println!(
+        "{:<width$}  {:>9}  {:>9}  {:>10}  {:>9}",
+        "total",
+        report.bytes,
+        report.renames_saved,
+        report.docstrings_saved,
+        report.estimated_bytes
+    );
+    println!(
+        "Estimated savings: {} of {} bytes across {} files ({} errors)",
+        report.bytes - report.estimated_bytes,
+        report.bytes,
+        report.files.len(),
+        report.errors
+    );
+}
 
-    finalize_file_results(
-        results,
-        &mut stats,
-        &input_dir,
-        &resolved_out_dir,
+#[allow(dead_code)]
+fn apply_plan_dir(
+    input_dir: &PathBuf,
+    plan_path: &PathBuf,
+    out_dir: Option<PathBuf>,
+    includes: &[String],
+    include_file: Option<&PathBuf>,
+    excludes: &[String],
+    exclude_file: Option<&PathBuf>,
+    backup_ext: Option<&str>,
+    in_place: bool,
+    dry_run: bool,
+    show_stats: bool,
+    json_output: bool,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    glob_case_insensitive: Option<bool>,
+    quiet: bool,
+    output_json: Option<&Path>,
+    jobs: Option<usize>,
+    fail_on_bailout: bool,
+    fail_on_error: bool,
+    fail_on_change: bool,
+    diff: bool,
+    diff_context: usize,
+) -> anyhow::Result<DirStats> {
+    apply_plan_dir_with_depth(
+        input_dir,
+        plan_path,
+        out_dir,
+        includes,
+        include_file,
+        excludes,
+        exclude_file,
+        backup_ext,
         in_place,
         dry_run,
-        backup_ext,
-        quiet,
         show_stats,
+        json_output,
+        include_hidden,
+        follow_symlinks,
+        glob_case_insensitive,
+        quiet,
+        output_json,
+        jobs,
+        fail_on_bailout,
+        fail_on_error,
+        fail_on_change,
         diff,
         diff_context,
-    )?;
+        false,
+        None,
+        None,
+        &[],
+        false,
+        None,
+        None,
+        false,
+        None,
+        false,
+        false,
+        None,
+        None,
+        None,
+        false,
+        false,
+        &CommandContext::default(),
+    )
+}
 
-    let summary_needed =
-        show_stats || fail_on_bailout || fail_on_error || fail_on_change || output_json.is_some();
-    if summary_needed {
-        let output_label = if in_place {
-            input_dir.display().to_string()
-        } else {
-            resolved_out_dir.display().to_string()
-        };
-        print_summary(
-            &stats,
-            show_stats,
-            json_output,
-            dry_run,
-            &output_label,
-            output_json,
-        )?;
+/// Re-key `candidates` for `--match-by module`: a file with no bundle entry at its own
+/// path takes the entry whose plan module equals its derived module name.
+///
+/// Exact path matches win. A module claimed by more than one remaining entry or file is
+/// ambiguous; those files are returned separately and get no plan.
+fn match_plans_by_module(
+    plan_path: &Path,
+    mut candidates: HashMap<String, Candidate>,
+    module_roots: &[PathBuf],
+) -> anyhow::Result<(HashMap<String, Candidate>, Vec<Candidate>)> {
+    let (_, entries) = open_plan_bundle_as::<PlanFileModule>(plan_path)?;
+    let mut plan_paths: HashSet<String> = HashSet::new();
+    let mut plans_by_module: HashMap<String, BTreeSet<String>> = HashMap::new();
+    for entry in entries {
+        let entry = entry?;
+        let module = entry.module.or(entry.plan.map(|plan| plan.module));
+        if let Some(module) = module {
+            plans_by_module
+                .entry(module)
+                .or_default()
+                .insert(entry.path.clone());
+        }
+        plan_paths.insert(entry.path);
     }
 
-    Ok(stats)
+    let mut moved: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for candidate in candidates.values() {
+        if !plan_paths.contains(&candidate.rel_norm) {
+            moved
+                .entry(module_name_for(&candidate.rel_path, module_roots))
+                .or_default()
+                .push(candidate.rel_norm.clone());
+        }
+    }
+
+    let mut ambiguous = Vec::new();
+    for (module, mut files) in moved {
+        let Some(plans) = plans_by_module.get(&module) else {
+            continue;
+        };
+        files.sort_unstable();
+        let plans: Vec<&String> = plans
+            .iter()
+            .filter(|path| !candidates.contains_key(*path))
+            .collect();
+        match (plans.as_slice(), files.as_slice()) {
+            ([], _) => {}
+            ([plan], [file]) => {
+                debug!(
+                    "• {} → using the plan for {} (module {})",
+                    file, plan, module
+                );
+                if let Some(candidate) = candidates.remove(file) {
+                    candidates.insert((*plan).clone(), candidate);
+                }
+            }
+            _ => {
+                for file in &files {
+                    ambiguous.extend(candidates.remove(file));
+                }
+            }
+        }
+    }
+    Ok((candidates, ambiguous))
 }
 
-fn default_output_dir(input_dir: &Path) -> PathBuf {
-    let parent = input_dir
-        .parent()
-        .filter(|p| !p.as_os_str().is_empty())
-        .map(|p| p.to_path_buf())
-        .unwrap_or_else(|| PathBuf::from("."));
+fn apply_plan_dir_with_depth(
+    input_dir: &PathBuf,
+    plan_path: &PathBuf,
+    out_dir: Option<PathBuf>,
+    includes: &[String],
+    include_file: Option<&PathBuf>,
+    excludes: &[String],
+    exclude_file: Option<&PathBuf>,
+    backup_ext: Option<&str>,
+    in_place: bool,
+    dry_run: bool,
+    show_stats: bool,
+    json_output: bool,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    glob_case_insensitive: Option<bool>,
+    quiet: bool,
+    output_json: Option<&Path>,
+    jobs: Option<usize>,
+    fail_on_bailout: bool,
+    fail_on_error: bool,
+    fail_on_change: bool,
+    diff: bool,
+    diff_context: usize,
+    respect_gitignore: bool,
+    max_depth: Option<usize>,
+    max_file_size: Option<u64>,
+    extensions: &[String],
+    no_tsrsignore: bool,
+    diff_out: Option<&Path>,
+    embed_manifest: Option<EmbedManifest>,
+    verify: bool,
+    verify_exec: Option<&Path>,
+    allow_partial_plan: bool,
+    require_all_plans: bool,
+    summary_by_dir: Option<usize>,
+    top: Option<usize>,
+    lock_wait: Option<u64>,
+    force_lock: bool,
+    match_by_module: bool,
+    ctx: &CommandContext,
+) -> anyhow::Result<DirStats> {
+    if json_output && !show_stats {
+        anyhow::bail!("--json requires --stats");
+    }
+    if (summary_by_dir.is_some() || top.is_some()) && !show_stats {
+        anyhow::bail!("--summary-by-dir and --top require --stats");
+    }
 
-    let name = input_dir
-        .file_name()
-        .map(|os| os.to_string_lossy().to_string())
-        .filter(|s| !s.is_empty())
-        .unwrap_or_else(|| "minified".to_string());
+    let input_dir = canonicalize_directory(input_dir.as_path())?;
+    if !input_dir.is_dir() {
+        anyhow::bail!("Input '{}' is not a directory", input_dir.display());
+    }
 
-    parent.join(format!("{}-min", name))
-}
+    if backup_ext.is_some() && !in_place {
+        anyhow::bail!("--backup-ext requires --in-place");
+    }
 
-fn derive_module_name(rel_path: &Path) -> String {
-    let without_ext = rel_path.with_extension("");
-    let mut parts: Vec<String> = without_ext
-        .iter()
-        .map(|component| component.to_string_lossy().replace('-', "_"))
-        .collect();
+    if in_place && out_dir.is_some() {
+        anyhow::bail!("Cannot use --out-dir with --in-place");
+    }
 
-    if parts.last().map(|part| part == "__init__").unwrap_or(false) {
-        parts.pop();
+    let (header, mut plan_source) = PlanSource::open(plan_path)?;
+    if plan_source.is_empty() {
+        anyhow::bail!("Plan bundle contains no files");
     }
+    // Without --extensions, apply to the same file types the bundle was planned for.
+    let extensions = if extensions.is_empty() {
+        resolve_extensions(&header.extensions)
+    } else {
+        resolve_extensions(extensions)
+    };
+    // Likewise without --module-root, name modules from the roots the bundle used.
+    let module_roots: Vec<PathBuf> = if ctx.module_roots.is_empty() {
+        header.module_roots.iter().map(PathBuf::from).collect()
+    } else {
+        ctx.module_roots.clone()
+    };
 
-    if parts.is_empty() {
-        rel_path
-            .file_stem()
-            .and_then(|stem| stem.to_str())
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| "module".to_string())
+    let resolved_out_dir = if in_place {
+        input_dir.clone()
     } else {
-        parts.join(".")
-    }
-}
+        out_dir.unwrap_or_else(|| default_output_dir(&input_dir))
+    };
 
-fn merged_exclude_patterns(extras: &[String]) -> Vec<String> {
-    let mut patterns: Vec<String> = DEFAULT_EXCLUDES
-        .iter()
-        .map(|pattern| pattern.to_string())
-        .collect();
-    patterns.extend(extras.iter().cloned());
-    patterns
-}
+    if !in_place {
+        let out_norm = normalize_output_path_guard(&resolved_out_dir)?;
 
-fn build_globset(patterns: &[String], case_insensitive: bool) -> anyhow::Result<GlobSet> {
-    let mut builder = GlobSetBuilder::new();
-    for pattern in patterns {
-        let mut glob_builder = GlobBuilder::new(pattern);
-        glob_builder.case_insensitive(case_insensitive);
-        builder.add(glob_builder.build()?);
-    }
-    Ok(builder.build()?)
-}
+        if out_norm.starts_with(&input_dir) {
+            anyhow::bail!("--out-dir cannot be inside the input directory");
+        }
 
-fn normalize_rel_path(rel_path: &Path) -> String {
-    let mut parts = Vec::new();
-    for component in rel_path.iter() {
-        parts.push(component.to_string_lossy());
+        if resolved_out_dir.exists() {
+            if !resolved_out_dir.is_dir() {
+                anyhow::bail!(
+                    "Output '{}' exists and is not a directory",
+                    resolved_out_dir.display()
+                );
+            }
+        } else if !dry_run {
+            fs::create_dir_all(&resolved_out_dir)?;
+        }
     }
-    parts.join("/")
-}
 
-#[derive(Clone)]
-struct Candidate {
-    abs_path: PathBuf,
-    rel_path: PathBuf,
-    rel_norm: String,
-}
+    let _lock = if dry_run {
+        None
+    } else {
+        Some(OutputLock::acquire(
+            &resolved_out_dir,
+            lock_wait,
+            force_lock,
+        )?)
+    };
+    if !in_place && !dry_run && has_output_entries(&resolved_out_dir)? {
+        anyhow::bail!(
+            "Output directory '{}' already exists and is not empty",
+            resolved_out_dir.display()
+        );
+    }
 
-struct FileResult {
-    candidate: Candidate,
-    outcome: FileOutcome,
-}
-
-enum FileOutcome {
-    Minified {
-        original: String,
-        rewritten: String,
-        renames: usize,
-        metadata: TextMetadata,
-    },
-    SkippedNoRenames {
-        original: String,
-        metadata: TextMetadata,
-    },
-    SkippedNested {
-        original: String,
-        metadata: TextMetadata,
-    },
-    SkippedRewriteAborted {
-        original: String,
-        metadata: TextMetadata,
-    },
-    ReadError {
-        message: String,
-    },
-    PlanError {
-        message: String,
-    },
-    RewriteError {
-        message: String,
-    },
-}
-
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum FinalStatusKind {
-    Minified,
-    SkippedNoRenames,
-    SkippedNested,
-    SkippedRewriteAborted,
-    SkippedBackupExists,
-}
-
-impl FinalStatusKind {
-    fn label(self) -> &'static str {
-        match self {
-            FinalStatusKind::Minified => "minified",
-            FinalStatusKind::SkippedNoRenames => "skipped (no renames)",
-            FinalStatusKind::SkippedNested => "skipped (nested scopes)",
-            FinalStatusKind::SkippedRewriteAborted => "skipped (rewrite aborted)",
-            FinalStatusKind::SkippedBackupExists => "skipped (backup exists)",
-        }
-    }
+    let run_started = Instant::now();
+    let walk = candidate_walker(
+        &input_dir,
+        includes,
+        include_file,
+        excludes,
+        exclude_file,
+        &extensions,
+        include_hidden,
+        follow_symlinks,
+        glob_case_insensitive,
+        max_depth,
+        respect_gitignore,
+        no_tsrsignore,
+        ctx.no_default_excludes,
+    )
+    .walk()?;
+    let walk_time = run_started.elapsed();
 
-    fn is_bailout(self) -> bool {
-        matches!(
-            self,
-            FinalStatusKind::SkippedNested
-                | FinalStatusKind::SkippedRewriteAborted
-                | FinalStatusKind::SkippedBackupExists
-        )
+    let jobs = resolve_jobs(jobs)?;
+    if ctx.progress.enabled() {
+        ctx.progress.emit(&ProgressEvent::RunStart {
+            command: "apply-plan-dir",
+            input: portable_path(&input_dir),
+            options: serde_json::json!({
+                "plan": plan_path,
+                "out_dir": resolved_out_dir,
+                "in_place": in_place,
+                "dry_run": dry_run,
+                "jobs": jobs,
+                "extensions": extensions,
+                "max_depth": max_depth,
+                "max_file_size": max_file_size,
+                "verify": verify,
+                "allow_partial_plan": allow_partial_plan,
+                "fuzzy_apply": ctx.rewrite.fuzzy_ranges,
+                "match_by": if match_by_module { "module" } else { "path" },
+            }),
+        });
     }
-}
 
-fn resolve_jobs(jobs: Option<usize>) -> anyhow::Result<usize> {
-    match jobs {
-        Some(0) => anyhow::bail!("--jobs must be at least 1"),
-        Some(value) => Ok(value),
-        None => Ok(std::cmp::max(1, num_cpus::get())),
+    log_walk_patterns(&walk.patterns);
+    let mut stats = DirStats::default();
+    stats.errors = walk.errors;
+    stats.options = Some(walk.patterns);
+    if ctx.timings {
+        stats.timings = Some(RunTimings {
+            walk_ms: duration_ms(walk_time),
+            ..RunTimings::default()
+        });
     }
-}
-
-fn execute_parallel_processing<F>(
-    candidates: &[Candidate],
-    jobs: usize,
-    processor: F,
-) -> anyhow::Result<Vec<FileResult>>
-where
-    F: Fn(&Candidate) -> FileResult + Sync,
-{
-    if candidates.is_empty() {
-        return Ok(Vec::new());
+    let mut candidates = walk.candidates;
+    if embed_manifest.is_some() {
+        candidates.retain(|candidate| !is_manifest_file(&candidate.rel_path));
     }
+    let (jobs, jobs_reduced) = effective_jobs(jobs, &candidates, ctx.max_memory_mb);
+    stats.effective_jobs = jobs;
+    stats.jobs_reduced = jobs_reduced;
+    ctx.progress.emit(&ProgressEvent::FileQueued {
+        total: candidates.len() + walk.duplicates.len(),
+    });
+    let output_collisions = if in_place {
+        HashMap::new()
+    } else {
+        case_folded_collisions(&candidates)
+    };
 
-    if jobs <= 1 {
-        Ok(candidates
-            .iter()
-            .map(|candidate| processor(candidate))
-            .collect())
+    let candidates: HashMap<String, Candidate> = candidates
+        .into_iter()
+        .map(|candidate| (candidate.rel_norm.clone(), candidate))
+        .collect();
+    // Keyed by the bundle path whose plan each candidate takes.
+    let (candidates, ambiguous) = if match_by_module {
+        match_plans_by_module(plan_path, candidates, &module_roots)?
     } else {
-        let pool = ThreadPoolBuilder::new().num_threads(jobs).build()?;
-        Ok(pool.install(|| {
-            candidates
-                .par_iter()
-                .map(|candidate| processor(candidate))
-                .collect()
-        }))
-    }
-}
+        (candidates, Vec::new())
+    };
 
-fn finalize_file_results(
-    results: Vec<FileResult>,
-    stats: &mut DirStats,
-    input_dir: &Path,
-    resolved_out_dir: &Path,
-    in_place: bool,
-    dry_run: bool,
-    backup_ext: Option<&str>,
-    quiet: bool,
-    show_stats: bool,
-    diff: bool,
-    diff_context: usize,
-) -> anyhow::Result<()> {
-    for result in results {
-        let candidate = result.candidate;
-        match result.outcome {
-            FileOutcome::ReadError { message } => {
-                stats.errors += 1;
-                error!(
-                    "failed to read {}: {}",
-                    candidate.abs_path.display(),
-                    message
-                );
-                bump_reason(stats, "read_error");
-            }
-            FileOutcome::PlanError { message } => {
-                stats.errors += 1;
-                error!(
-                    "failed to plan {}: {}",
-                    candidate.abs_path.display(),
-                    message
-                );
-                bump_reason(stats, "plan_error");
-            }
-            FileOutcome::RewriteError { message } => {
-                stats.errors += 1;
-                error!(
-                    "failed to rewrite {}: {}",
-                    candidate.abs_path.display(),
-                    message
-                );
-                debug!("• {} → skipped (rewrite error)", candidate.rel_norm);
-                bump_reason(stats, "rewrite_error");
+    let processor = {
+        let input_dir = input_dir.as_path();
+        let resolved_out_dir = resolved_out_dir.as_path();
+        let output_collisions = &output_collisions;
+        move |candidate: &Candidate, plan: &MinifyPlan| -> FileResult {
+            let candidate_clone = candidate.clone();
+            if let Some(outcome) = output_collision(candidate, output_collisions, resolved_out_dir)
+            {
+                return FileResult {
+                    candidate: candidate_clone,
+                    outcome,
+                    logs: Vec::new(),
+                    timing: FileTiming::default(),
+                };
             }
-            FileOutcome::Minified {
-                original,
-                rewritten,
-                renames,
-                metadata,
-            } => {
-                process_ready_file(
-                    candidate,
-                    original,
-                    Some(rewritten),
-                    renames,
-                    FinalStatusKind::Minified,
-                    stats,
-                    input_dir,
-                    resolved_out_dir,
-                    in_place,
-                    dry_run,
-                    backup_ext,
-                    metadata,
-                    quiet,
-                    show_stats,
-                    diff,
-                    diff_context,
-                )?;
+            if let Some(outcome) = skip_oversized_file(
+                candidate,
+                max_file_size,
+                resolved_out_dir,
+                in_place,
+                dry_run,
+            ) {
+                return FileResult {
+                    candidate: candidate_clone,
+                    outcome,
+                    logs: Vec::new(),
+                    timing: FileTiming::default(),
+                };
             }
-            FileOutcome::SkippedNoRenames { original, metadata } => {
-                process_ready_file(
-                    candidate,
-                    original,
+            let read_stamp = in_place
+                .then(|| SourceStamp::of(&candidate.abs_path))
+                .flatten();
+            let (source, metadata) = match read_python(&candidate.abs_path, ctx.decode) {
+                Ok(result) => result,
+                Err(err) => {
+                    return FileResult {
+                        candidate: candidate_clone,
+                        outcome: FileOutcome::ReadError {
+                            message: err.to_string(),
+                        },
+                        logs: Vec::new(),
+                        timing: FileTiming::default(),
+                    }
+                }
+            };
+
+            let pruned = partial_plan(plan, &source, allow_partial_plan, &candidate.rel_norm);
+            let plan = pruned.as_ref().unwrap_or(plan);
+            let rename_total: usize = plan.functions.iter().map(|f| f.renames.len()).sum();
+            let has_nested = plan.functions.iter().any(|f| f.has_nested_functions);
+
+            let mut fuzzy_matched = Vec::new();
+            let mut mapping = None;
+            let (status_kind, rewritten, renames, skipped, functions) = if plan.skip_file {
+                let functions = show_stats.then(|| function_stats(plan, None, false));
+                (
+                    FinalStatusKind::SkippedDirective,
                     None,
                     0,
-                    FinalStatusKind::SkippedNoRenames,
-                    stats,
-                    input_dir,
-                    resolved_out_dir,
-                    in_place,
-                    dry_run,
-                    backup_ext,
-                    metadata,
-                    quiet,
-                    show_stats,
-                    diff,
-                    diff_context,
-                )?;
-            }
-            FileOutcome::SkippedNested { original, metadata } => {
-                process_ready_file(
-                    candidate,
-                    original,
+                    Vec::new(),
+                    functions,
+                )
+            } else if has_nested {
+                let functions = show_stats.then(|| function_stats(plan, None, false));
+                (
+                    FinalStatusKind::SkippedNested,
                     None,
                     0,
-                    FinalStatusKind::SkippedNested,
-                    stats,
-                    input_dir,
-                    resolved_out_dir,
-                    in_place,
-                    dry_run,
-                    backup_ext,
-                    metadata,
-                    quiet,
-                    show_stats,
-                    diff,
-                    diff_context,
-                )?;
-            }
-            FileOutcome::SkippedRewriteAborted { original, metadata } => {
-                process_ready_file(
-                    candidate,
-                    original,
+                    Vec::new(),
+                    functions,
+                )
+            } else if let Some(limit) = ctx.plan.limits.exceeded_by(plan) {
+                let functions = show_stats.then(|| function_stats(plan, None, false));
+                (
+                    FinalStatusKind::SkippedPlanLimit(limit),
                     None,
                     0,
-                    FinalStatusKind::SkippedRewriteAborted,
-                    stats,
-                    input_dir,
-                    resolved_out_dir,
-                    in_place,
-                    dry_run,
-                    backup_ext,
-                    metadata,
-                    quiet,
-                    show_stats,
-                    diff,
-                    diff_context,
-                )?;
+                    Vec::new(),
+                    functions,
+                )
+            } else if rename_total == 0 {
+                let functions = show_stats.then(|| function_stats(plan, None, false));
+                (
+                    FinalStatusKind::SkippedNoRenames,
+                    None,
+                    0,
+                    Vec::new(),
+                    functions,
+                )
+            } else {
+                match Minifier::rewrite_with_plan_options(&plan.module, &source, plan, ctx.rewrite)
+                {
+                    Ok(outcome) if outcome.source == source => (
+                        FinalStatusKind::SkippedRewriteAborted,
+                        None,
+                        0,
+                        locate_skipped(plan, outcome.skipped.clone()),
+                        show_stats.then(|| function_stats(plan, Some(&outcome), false)),
+                    ),
+                    Ok(mut outcome) => {
+                        let functions =
+                            show_stats.then(|| function_stats(plan, Some(&outcome), true));
+                        fuzzy_matched = std::mem::take(&mut outcome.fuzzy_matched);
+                        mapping = embed_manifest.map(|_| {
+                            FileSourceMap::from_applied(
+                                &candidate.rel_norm,
+                                plan,
+                                &outcome.applied,
+                                &outcome.source,
+                            )
+                        });
+                        (
+                            FinalStatusKind::Minified,
+                            Some(std::mem::take(&mut outcome.source)),
+                            rename_total,
+                            locate_skipped(plan, std::mem::take(&mut outcome.skipped)),
+                            functions,
+                        )
+                    }
+                    Err(err) => {
+                        return FileResult {
+                            candidate: candidate_clone,
+                            outcome: FileOutcome::RewriteError {
+                                message: err.to_string(),
+                            },
+                            logs: Vec::new(),
+                            timing: FileTiming::default(),
+                        }
+                    }
+                }
+            };
+
+            let write_started = Instant::now();
+            let mut outcome = write_ready_file(
+                candidate,
+                source,
+                rewritten,
+                renames,
+                skipped,
+                mapping,
+                status_kind,
+                &metadata,
+                input_dir,
+                resolved_out_dir,
+                in_place,
+                dry_run,
+                backup_ext,
+                (diff && !quiet) || diff_out.is_some(),
+                diff_context,
+                verify,
+                verify_exec,
+                read_stamp,
+                ctx.overwrite_changed,
+            );
+            let write = write_started.elapsed();
+            if let FileOutcome::Ready(ready) = &mut outcome {
+                ready.functions = functions;
+                if ready.status_kind == FinalStatusKind::Minified {
+                    ready.fuzzy_matched = fuzzy_matched;
+                }
+            }
+            FileResult {
+                candidate: candidate_clone,
+                outcome,
+                logs: Vec::new(),
+                timing: FileTiming {
+                    write,
+                    ..FileTiming::default()
+                },
             }
         }
-    }
+    };
 
-    Ok(())
-}
+    let (plan_files, unread_plans) = plan_source.into_entries(&candidates);
+    let (mut results, mut unmatched_plans) =
+        execute_streaming_processing(plan_files, &candidates, jobs, &ctx.progress, processor)?;
+    unmatched_plans.extend(unread_plans);
+    unmatched_plans.sort_unstable();
 
-#[allow(clippy::too_many_arguments)]
-fn process_ready_file(
-    candidate: Candidate,
-    original: String,
-    rewritten: Option<String>,
-    renames: usize,
-    mut status_kind: FinalStatusKind,
-    stats: &mut DirStats,
-    input_dir: &Path,
-    resolved_out_dir: &Path,
-    in_place: bool,
-    dry_run: bool,
-    backup_ext: Option<&str>,
-    metadata: TextMetadata,
-    quiet: bool,
-    show_stats: bool,
-    diff: bool,
-    diff_context: usize,
-) -> anyhow::Result<()> {
-    let mut applied_renames = renames;
-    let target_path = if in_place {
-        input_dir.join(&candidate.rel_path)
-    } else {
-        resolved_out_dir.join(&candidate.rel_path)
-    };
+    let applied: HashSet<&str> = results
+        .iter()
+        .map(|result| result.candidate.rel_norm.as_str())
+        .collect();
+    let mut unplanned: Vec<&str> = candidates
+        .values()
+        .map(|candidate| candidate.rel_norm.as_str())
+        .filter(|rel_norm| !applied.contains(rel_norm))
+        .collect();
+    unplanned.sort_unstable();
+    for rel_norm in unplanned {
+        debug!("• {} → skipped (no plan)", rel_norm);
+        bump_reason(&mut stats, "no_plan");
+        ctx.progress
+            .file_done(rel_norm, "no_plan", 0, Duration::ZERO);
+    }
 
-    if !dry_run {
-        if in_place {
-            if status_kind == FinalStatusKind::Minified {
-                if let Some(ext) = backup_ext {
-                    let mut backup_os: OsString = target_path.as_os_str().to_os_string();
-                    backup_os.push(ext);
-                    let backup_path = PathBuf::from(backup_os);
-                    if backup_path.exists() {
-                        status_kind = FinalStatusKind::SkippedBackupExists;
-                        applied_renames = 0;
-                        debug!("• {} → skipped (backup exists)", candidate.rel_norm);
-                    } else if let Err(err) = fs::copy(&target_path, &backup_path) {
-                        stats.errors += 1;
-                        error!("failed to write backup {}: {}", backup_path.display(), err);
-                        debug!("• {} → skipped (backup failed)", candidate.rel_norm);
-                        bump_reason(stats, "backup_failed");
-                        return Ok(());
-                    }
-                }
+    if !unmatched_plans.is_empty() {
+        debug!(
+            "{} plan entries matched no file on disk",
+            unmatched_plans.len()
+        );
+        for path in &unmatched_plans {
+            debug!("• {} → unmatched plan entry", path);
+        }
+    }
+    stats.unmatched_plans = unmatched_plans;
+
+    for candidate in ambiguous {
+        ctx.progress.file_done(
+            &candidate.rel_norm,
+            FinalStatusKind::SkippedAmbiguousPlan.reason(),
+            0,
+            Duration::ZERO,
+        );
+        let outcome = if dry_run || in_place {
+            None
+        } else {
+            copy_unchanged(&candidate, &resolved_out_dir)
+                .err()
+                .map(|outcome| *outcome)
+        };
+        results.push(FileResult {
+            candidate,
+            outcome: outcome.unwrap_or(FileOutcome::Ready(Box::new(ReadyFile {
+                status_kind: FinalStatusKind::SkippedAmbiguousPlan,
+                renames: 0,
+                skipped: Vec::new(),
+                mapping: None,
+                diff: None,
+                imports_removed: 0,
+                stages: Vec::new(),
+                functions: None,
+                warnings: Vec::new(),
+                bytes_saved: 0,
+                lossy_decode: false,
+                fuzzy_matched: Vec::new(),
+            }))),
+            logs: Vec::new(),
+            timing: FileTiming::default(),
+        });
+    }
+    results.extend(
+        walk.duplicates
+            .into_iter()
+            .map(|duplicate| skip_duplicate_path(duplicate, &ctx.progress)),
+    );
 
-                if status_kind == FinalStatusKind::Minified {
-                    if let Some(ref content) = rewritten {
-                        if let Err(err) = write_python(&target_path, content, &metadata) {
-                            stats.errors += 1;
-                            error!("failed to write {}: {}", target_path.display(), err);
-                            debug!("• {} → skipped (write failed)", candidate.rel_norm);
-                            bump_reason(stats, "write_failed");
-                            return Ok(());
-                        }
-                    }
-                }
-            }
+    stats.processed = results.len();
+    let manifest_files = manifest_files(embed_manifest, &results);
+
+    let mut source_maps: Vec<FileSourceMap> = Vec::new();
+    let mut diffs: Vec<(String, String)> = Vec::new();
+    finalize_file_results(
+        results,
+        &mut stats,
+        dry_run,
+        quiet,
+        show_stats,
+        diff,
+        &mut source_maps,
+        diff_out.is_some().then_some(&mut diffs),
+    );
+
+    let write_started = Instant::now();
+    if let Some(embed) = embed_manifest.filter(|_| !dry_run) {
+        write_manifests(&resolved_out_dir, embed, &manifest_files, &source_maps)?;
+    }
+    if let Some(target) = diff_out {
+        write_dir_diffs(target, diffs)?;
+        stats.diff_out = Some(portable_path(target));
+    }
+    if let Some(timings) = stats.timings.as_mut() {
+        timings.write += write_started.elapsed();
+        timings.finish(run_started.elapsed());
+    }
+
+    let summary_needed = show_stats
+        || fail_on_bailout
+        || fail_on_error
+        || fail_on_change
+        || require_all_plans
+        || output_json.is_some()
+        || diff_out.is_some();
+    stats.summarize(summary_by_dir, top);
+    if summary_needed {
+        let output_label = if in_place {
+            input_dir.display().to_string()
         } else {
-            if let Some(parent) = target_path.parent() {
-                if let Err(err) = fs::create_dir_all(parent) {
-                    stats.errors += 1;
-                    error!("failed to create directory {}: {}", parent.display(), err);
-                    debug!("• {} → skipped (mkdir failed)", candidate.rel_norm);
-                    bump_reason(stats, "mkdir_failed");
-                    return Ok(());
-                }
-            }
+            resolved_out_dir.display().to_string()
+        };
+        print_summary(
+            &stats,
+            show_stats,
+            json_output,
+            ctx.verbose_stats,
+            dry_run,
+            &output_label,
+            output_json,
+        )?;
+    }
+    ctx.progress.run_end(&stats);
 
-            let content = if status_kind == FinalStatusKind::Minified {
-                rewritten
-                    .as_ref()
-                    .map(|s| s.as_str())
-                    .unwrap_or_else(|| original.as_str())
-            } else {
-                original.as_str()
-            };
+    Ok(stats)
+}
 
-            if let Err(err) = write_python(&target_path, content, &metadata) {
-                stats.errors += 1;
-                error!("failed to write {}: {}", target_path.display(), err);
-                debug!("• {} → skipped (write failed)", candidate.rel_norm);
-                bump_reason(stats, "write_failed");
-                return Ok(());
+#[allow(dead_code)]
+fn minify_dir(
+    input_dir: &PathBuf,
+    out_dir: Option<PathBuf>,
+    includes: &[String],
+    include_file: Option<&PathBuf>,
+    excludes: &[String],
+    exclude_file: Option<&PathBuf>,
+    backup_ext: Option<&str>,
+    in_place: bool,
+    dry_run: bool,
+    show_stats: bool,
+    json_output: bool,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    glob_case_insensitive: Option<bool>,
+    quiet: bool,
+    output_json: Option<&Path>,
+    jobs: Option<usize>,
+    fail_on_bailout: bool,
+    fail_on_error: bool,
+    fail_on_change: bool,
+    diff: bool,
+    diff_context: usize,
+    remove_dead_code: bool,
+) -> anyhow::Result<DirStats> {
+    minify_dir_with_depth(
+        input_dir,
+        out_dir,
+        includes,
+        include_file,
+        excludes,
+        exclude_file,
+        backup_ext,
+        in_place,
+        dry_run,
+        show_stats,
+        json_output,
+        include_hidden,
+        follow_symlinks,
+        glob_case_insensitive,
+        quiet,
+        output_json,
+        jobs,
+        fail_on_bailout,
+        fail_on_error,
+        fail_on_change,
+        diff,
+        diff_context,
+        false,
+        None,
+        remove_dead_code,
+        false,
+        false,
+        false,
+        false,
+        None,
+        &[],
+        false,
+        None,
+        false,
+        None,
+        None,
+        None,
+        false,
+        None,
+        &TransformPipeline::new(),
+        false,
+        true,
+        None,
+        None,
+        None,
+        false,
+        OutDirMode::Fresh,
+        None,
+        &CommandContext::default(),
+    )
+}
+
+/// Names mentioned by each candidate, for `--remove-dead-assignments`. `None`, with a
+/// warning, when a file cannot be read, since its references would go unseen.
+fn name_usage_index(candidates: &[Candidate], decode: DecodeOptions) -> Option<NameUsageIndex> {
+    let mut usage = NameUsageIndex::new();
+    for candidate in candidates {
+        match read_python(&candidate.abs_path, decode) {
+            Ok((source, _)) => usage.add_module(&source),
+            Err(err) => {
+                warn!(
+                    "--remove-dead-assignments disabled: {}: {err:#}",
+                    candidate.rel_norm
+                );
+                return None;
             }
         }
     }
+    Some(usage)
+}
 
-    match status_kind {
-        FinalStatusKind::Minified => {
-            stats.rewritten += 1;
-            stats.total_renames += applied_renames;
-            bump_reason(stats, "minified");
-        }
-        FinalStatusKind::SkippedNoRenames => {
-            stats.skipped_no_change += 1;
-            bump_reason(stats, "no_renames");
+fn minify_dir_with_depth(
+    input_dir: &PathBuf,
+    out_dir: Option<PathBuf>,
+    includes: &[String],
+    include_file: Option<&PathBuf>,
+    excludes: &[String],
+    exclude_file: Option<&PathBuf>,
+    backup_ext: Option<&str>,
+    in_place: bool,
+    dry_run: bool,
+    show_stats: bool,
+    json_output: bool,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    glob_case_insensitive: Option<bool>,
+    quiet: bool,
+    output_json: Option<&Path>,
+    jobs: Option<usize>,
+    fail_on_bailout: bool,
+    fail_on_error: bool,
+    fail_on_change: bool,
+    diff: bool,
+    diff_context: usize,
+    respect_gitignore: bool,
+    max_depth: Option<usize>,
+    remove_dead_code: bool,
+    stable_names: bool,
+    rename_module_privates: bool,
+    preserve_annotated_locals: bool,
+    warn_collisions: bool,
+    max_file_size: Option<u64>,
+    extensions: &[String],
+    no_tsrsignore: bool,
+    changed_since: Option<&str>,
+    changed_since_optional: bool,
+    source_map: Option<&Path>,
+    embed_manifest: Option<EmbedManifest>,
+    diff_out: Option<&Path>,
+    verify: bool,
+    verify_exec: Option<&Path>,
+    pipeline: &TransformPipeline,
+    remove_dead_assignments: bool,
+    skip_parse_errors: bool,
+    summary_by_dir: Option<usize>,
+    top: Option<usize>,
+    lock_wait: Option<u64>,
+    force_lock: bool,
+    out_dir_mode: OutDirMode,
+    watch_pass: Option<&HashSet<PathBuf>>,
+    ctx: &CommandContext,
+) -> anyhow::Result<DirStats> {
+    let input_dir = canonicalize_directory(input_dir.as_path())?;
+    if !input_dir.is_dir() {
+        anyhow::bail!("Input '{}' is not a directory", input_dir.display());
+    }
+
+    if json_output && !show_stats {
+        anyhow::bail!("--json requires --stats");
+    }
+    if (summary_by_dir.is_some() || top.is_some()) && !show_stats {
+        anyhow::bail!("--summary-by-dir and --top require --stats");
+    }
+
+    if in_place && out_dir.is_some() {
+        anyhow::bail!("Cannot use --out-dir with --in-place");
+    }
+
+    if backup_ext.is_some() && !in_place {
+        anyhow::bail!("--backup-ext requires --in-place");
+    }
+
+    let resolved_out_dir = if in_place {
+        input_dir.clone()
+    } else {
+        out_dir.unwrap_or_else(|| default_output_dir(&input_dir))
+    };
+
+    if !in_place {
+        let out_norm = normalize_output_path_guard(&resolved_out_dir)?;
+
+        if out_norm.starts_with(&input_dir) {
+            anyhow::bail!("--out-dir cannot be inside the input directory");
         }
-        _ => {
-            if status_kind.is_bailout() {
-                stats.bailouts += 1;
-            }
-            let reason = match status_kind {
-                FinalStatusKind::SkippedNested => "nested_scopes",
-                FinalStatusKind::SkippedRewriteAborted => "rewrite_aborted",
-                FinalStatusKind::SkippedBackupExists => "backup_exists",
-                _ => "unknown",
-            };
-            if reason != "unknown" {
-                bump_reason(stats, reason);
+
+        if resolved_out_dir.exists() {
+            if !resolved_out_dir.is_dir() {
+                anyhow::bail!(
+                    "Output '{}' exists and is not a directory",
+                    resolved_out_dir.display()
+                );
             }
+        } else if !dry_run {
+            fs::create_dir_all(&resolved_out_dir)?;
         }
     }
 
-    if show_stats {
-        stats.files.push(FileStats {
-            path: candidate.rel_norm.clone(),
-            renames: applied_renames,
-            status: status_kind.label().to_string(),
-        });
+    // Held until this pass returns so concurrent runs cannot interleave their writes.
+    let _lock = if dry_run {
+        None
+    } else {
+        Some(OutputLock::acquire(
+            &resolved_out_dir,
+            lock_wait,
+            force_lock,
+        )?)
+    };
+    // Watch passes rewrite into the tree produced by the initial run.
+    if !in_place
+        && !dry_run
+        && watch_pass.is_none()
+        && out_dir_mode == OutDirMode::Fresh
+        && has_output_entries(&resolved_out_dir)?
+    {
+        anyhow::bail!(
+            "Output directory '{}' already exists and is not empty (pass --merge-out-dir to write into it)",
+            resolved_out_dir.display()
+        );
     }
+    // Snapshot of the outputs already present, to tell overwritten files from new ones.
+    let existing_outputs = if in_place || out_dir_mode == OutDirMode::Fresh {
+        None
+    } else {
+        Some(list_output_files(&resolved_out_dir)?)
+    };
 
-    if diff && status_kind == FinalStatusKind::Minified && !quiet {
-        if let Some(ref new_content) = rewritten {
-            let diff_str =
-                make_unified_diff(&candidate.rel_norm, &original, new_content, diff_context);
-            println!("{}", diff_str);
-        }
+    let jobs = resolve_jobs(jobs)?;
+    if ctx.progress.enabled() {
+        ctx.progress.emit(&ProgressEvent::RunStart {
+            command: "minify-dir",
+            input: portable_path(&input_dir),
+            options: serde_json::json!({
+                "out_dir": resolved_out_dir,
+                "in_place": in_place,
+                "dry_run": dry_run,
+                "jobs": jobs,
+                "extensions": extensions,
+                "max_depth": max_depth,
+                "max_file_size": max_file_size,
+                "remove_dead_code": remove_dead_code,
+                "remove_unused_imports": pipeline.names().any(|name| name == RemoveUnusedImports::NAME),
+                "pipeline": pipeline.names().collect::<Vec<_>>(),
+                "remove_dead_assignments": remove_dead_assignments,
+                "stable_names": stable_names,
+                "rename_module_privates": rename_module_privates,
+                "preserve_annotated_locals": preserve_annotated_locals,
+                "skip_parse_errors": skip_parse_errors,
+                "verify": verify,
+                "changed_since": changed_since,
+            }),
+        });
     }
 
-    print_file_status(
-        &candidate.rel_norm,
-        status_kind.label(),
-        applied_renames,
-        show_stats,
-        quiet,
-    );
-
-    Ok(())
-}
+    let mut stats = DirStats::default();
+    let run_started = Instant::now();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use anyhow::Result as AnyResult;
-    use assert_cmd::Command;
-    use encoding_rs::Encoding;
-    use serde_json;
-    #[cfg(unix)]
-    use std::os::unix::fs::{symlink, PermissionsExt};
-    use std::path::PathBuf;
-    use std::process::Command as StdCommand;
-    use tempfile::tempdir;
+    let mut walker = candidate_walker(
+        &input_dir,
+        includes,
+        include_file,
+        excludes,
+        exclude_file,
+        extensions,
+        include_hidden,
+        follow_symlinks,
+        glob_case_insensitive,
+        max_depth,
+        respect_gitignore,
+        no_tsrsignore,
+        ctx.no_default_excludes,
+    );
+    if let Some(only) = watch_pass {
+        walker = walker.only(only.clone());
+    }
+    let walk = walker.walk()?;
+    if watch_pass.is_none() {
+        log_walk_patterns(&walk.patterns);
+    }
+    stats.errors += walk.errors;
+    stats.options = Some(walk.patterns);
+    // Every output this input can produce, including files left out by --changed-since,
+    // so pruning only removes outputs whose sources are gone.
+    let selected_outputs: Option<HashSet<String>> =
+        (matches!(out_dir_mode, OutDirMode::Prune { .. }) && watch_pass.is_none()).then(|| {
+            walk.candidates
+                .iter()
+                .chain(walk.duplicates.iter().map(|duplicate| &duplicate.candidate))
+                .map(|candidate| candidate.rel_norm.clone())
+                .collect()
+        });
+    let mut candidates = walk.candidates;
+    if embed_manifest.is_some() {
+        candidates.retain(|candidate| !is_manifest_file(&candidate.rel_path));
+    }
+    // Indexed before --changed-since narrows the run: unchanged files still reference
+    // constants in the files that are rewritten.
+    let dead_assignments = if remove_dead_assignments {
+        name_usage_index(&candidates, ctx.decode)
+            .map(|usage| RemoveDeadAssignments::new(Arc::new(usage)))
+    } else {
+        None
+    };
+    if let Some(reference) = changed_since {
+        stats.unchanged_skipped = retain_changed(
+            &mut candidates,
+            &input_dir,
+            reference,
+            changed_since_optional,
+        )?;
+    }
 
-    #[derive(Clone)]
-    struct MinifyDirTestCfg {
-        in_place: bool,
-        dry_run: bool,
-        show_stats: bool,
-        json_output: bool,
-        include_file: Option<PathBuf>,
-        include_hidden: bool,
-        follow_symlinks: bool,
-        glob_case_insensitive: Option<bool>,
-        quiet: bool,
-        output_json: Option<PathBuf>,
-        jobs: Option<usize>,
-        fail_on_bailout: bool,
-        fail_on_error: bool,
-        fail_on_change: bool,
-        diff: bool,
-        diff_context: usize,
-        max_depth: Option<usize>,
-        exclude_file: Option<PathBuf>,
-        respect_gitignore: bool,
+    if ctx.timings {
+        stats.timings = Some(RunTimings {
+            walk_ms: duration_ms(run_started.elapsed()),
+            ..RunTimings::default()
+        });
     }
 
-    impl Default for MinifyDirTestCfg {
-        fn default() -> Self {
-            Self {
-                in_place: false,
-                dry_run: false,
-                show_stats: false,
-                json_output: false,
-                include_file: None,
-                include_hidden: false,
-                follow_symlinks: false,
-                glob_case_insensitive: None,
-                quiet: false,
-                output_json: None,
-                jobs: None,
-                fail_on_bailout: false,
-                fail_on_error: false,
-                fail_on_change: false,
-                diff: false,
-                diff_context: 3,
-                max_depth: None,
-                exclude_file: None,
-                respect_gitignore: false,
+    let output_collisions = if in_place {
+        HashMap::new()
+    } else {
+        case_folded_collisions(&candidates)
+    };
+
+    stats.processed = candidates.len() + walk.duplicates.len();
+    let (jobs, jobs_reduced) = effective_jobs(jobs, &candidates, ctx.max_memory_mb);
+    stats.effective_jobs = jobs;
+    stats.jobs_reduced = jobs_reduced;
+    ctx.progress.emit(&ProgressEvent::FileQueued {
+        total: stats.processed,
+    });
+    let naming = naming_mode(stable_names);
+
+    let processor = |candidate: &Candidate| -> FileResult {
+        let candidate_clone = candidate.clone();
+        if let Some(outcome) = output_collision(candidate, &output_collisions, &resolved_out_dir) {
+            return FileResult {
+                candidate: candidate_clone,
+                outcome,
+                logs: Vec::new(),
+                timing: FileTiming::default(),
+            };
+        }
+        if let Some(outcome) = skip_oversized_file(
+            candidate,
+            max_file_size,
+            &resolved_out_dir,
+            in_place,
+            dry_run,
+        ) {
+            return FileResult {
+                candidate: candidate_clone,
+                outcome,
+                logs: Vec::new(),
+                timing: FileTiming::default(),
+            };
+        }
+        let read_stamp = in_place
+            .then(|| SourceStamp::of(&candidate.abs_path))
+            .flatten();
+        let (source, metadata) = match read_python(&candidate.abs_path, ctx.decode) {
+            Ok(result) => result,
+            Err(err) => {
+                return FileResult {
+                    candidate: candidate_clone,
+                    outcome: FileOutcome::ReadError {
+                        message: err.to_string(),
+                    },
+                    logs: Vec::new(),
+                    timing: FileTiming::default(),
+                }
+            }
+        };
+
+        let module_name = module_name_for(&candidate.rel_path, &ctx.module_roots);
+        let transformed = if pipeline.is_empty() && dead_assignments.is_none() {
+            None
+        } else {
+            let context = TransformContext {
+                module_name: &module_name,
+                is_package_init: is_package_init(&candidate.rel_path),
+            };
+            let first = dead_assignments
+                .as_ref()
+                .map(|stage| stage as &dyn Transform);
+            match pipeline.run_after(first, &context, &source) {
+                Ok(output) => Some(output).filter(|output| output.source != source),
+                Err(err) => {
+                    return FileResult {
+                        candidate: candidate_clone,
+                        outcome: plan_failure_outcome(
+                            candidate,
+                            &err,
+                            &source,
+                            skip_parse_errors,
+                            &resolved_out_dir,
+                            in_place,
+                            dry_run,
+                        ),
+                        logs: Vec::new(),
+                        timing: FileTiming::default(),
+                    }
+                }
+            }
+        };
+        let imports_removed = transformed
+            .as_ref()
+            .map_or(0, |output| output.changes(RemoveUnusedImports::NAME));
+        let stages = transformed
+            .as_ref()
+            .map_or_else(Vec::new, |output| output.stages.clone());
+        let planned = transformed
+            .as_ref()
+            .map_or(source.as_str(), |output| output.source.as_str());
+
+        let options = plan_options(
+            &candidate.rel_path,
+            &ctx.plan,
+            naming,
+            rename_module_privates,
+            preserve_annotated_locals,
+        );
+        let mut plan = match Minifier::plan_from_source_with_options(&module_name, planned, options)
+        {
+            Ok(plan) => plan,
+            Err(err) => {
+                return FileResult {
+                    candidate: candidate_clone,
+                    outcome: plan_failure_outcome(
+                        candidate,
+                        &err,
+                        planned,
+                        skip_parse_errors,
+                        &resolved_out_dir,
+                        in_place,
+                        dry_run,
+                    ),
+                    logs: Vec::new(),
+                    timing: FileTiming::default(),
+                }
+            }
+        };
+        log_plan_warnings(&plan, &candidate.rel_norm);
+
+        // Filter plan if --remove-dead-code is requested
+        if remove_dead_code {
+            let dead_code = match detect_dead_code(planned, &module_name, quiet, ctx) {
+                Ok(dead_code) => dead_code,
+                Err(_err) => {
+                    // If dead code detection fails, just continue with unfiltered plan
+                    Vec::new()
+                }
+            };
+            plan = filter_plan_for_dead_code(plan, &dead_code);
+        }
+        let warnings = if warn_collisions {
+            collision_warnings(&plan, planned, &candidate.rel_norm)
+        } else {
+            Vec::new()
+        };
+
+        let rename_total: usize = plan.functions.iter().map(|f| f.renames.len()).sum();
+        let has_nested = plan.functions.iter().any(|f| f.has_nested_functions);
+
+        let mut functions = show_stats.then(|| function_stats(&plan, None, false));
+        let (status_kind, rewritten, renames, mut skipped, mapping) = if plan.skip_file {
+            (FinalStatusKind::SkippedDirective, None, 0, Vec::new(), None)
+        } else if (has_nested || rename_total == 0) && transformed.is_some() {
+            // Nothing to rename, but the source transforms alone still change the file.
+            (
+                FinalStatusKind::Minified,
+                Some(planned.to_string()),
+                0,
+                Vec::new(),
+                None,
+            )
+        } else if has_nested {
+            (FinalStatusKind::SkippedNested, None, 0, Vec::new(), None)
+        } else if rename_total == 0 {
+            (FinalStatusKind::SkippedNoRenames, None, 0, Vec::new(), None)
+        } else {
+            match Minifier::rewrite_with_plan_options(&module_name, planned, &plan, ctx.rewrite) {
+                Ok(outcome) if outcome.source == source => {
+                    functions = show_stats.then(|| function_stats(&plan, Some(&outcome), false));
+                    (
+                        FinalStatusKind::SkippedRewriteAborted,
+                        None,
+                        0,
+                        locate_skipped(&plan, outcome.skipped),
+                        None,
+                    )
+                }
+                Ok(mut outcome) => {
+                    functions = show_stats.then(|| function_stats(&plan, Some(&outcome), true));
+                    let rewritten = std::mem::take(&mut outcome.source);
+                    let mapping = (source_map.is_some() || embed_manifest.is_some()).then(|| {
+                        FileSourceMap::from_applied(
+                            &candidate.rel_norm,
+                            &plan,
+                            &outcome.applied,
+                            &rewritten,
+                        )
+                    });
+                    (
+                        FinalStatusKind::Minified,
+                        Some(rewritten),
+                        rename_total,
+                        locate_skipped(&plan, std::mem::take(&mut outcome.skipped)),
+                        mapping,
+                    )
+                }
+                Err(err) => {
+                    return FileResult {
+                        candidate: candidate_clone,
+                        outcome: FileOutcome::RewriteError {
+                            message: err.to_string(),
+                        },
+                        logs: Vec::new(),
+                        timing: FileTiming::default(),
+                    }
+                }
             }
+        };
+
+        if let Some(output) = &transformed {
+            restore_original_lines(&source, &output.source, functions.as_mut(), &mut skipped);
+        }
+
+        let write_started = Instant::now();
+        let mut outcome = write_ready_file(
+            candidate,
+            source,
+            rewritten,
+            renames,
+            skipped,
+            mapping,
+            status_kind,
+            &metadata,
+            &input_dir,
+            &resolved_out_dir,
+            in_place,
+            dry_run,
+            backup_ext,
+            (diff && !quiet) || diff_out.is_some(),
+            diff_context,
+            verify,
+            verify_exec,
+            read_stamp,
+            ctx.overwrite_changed,
+        );
+        let write = write_started.elapsed();
+        if let FileOutcome::Ready(ready) = &mut outcome {
+            ready.imports_removed = imports_removed;
+            ready.stages = stages;
+            ready.functions = functions;
+            ready.warnings = warnings;
+        }
+        FileResult {
+            candidate: candidate_clone,
+            outcome,
+            logs: Vec::new(),
+            timing: FileTiming {
+                write,
+                ..FileTiming::default()
+            },
         }
+    };
+
+    let mut results = execute_parallel_processing(&candidates, jobs, |candidate| {
+        capture_file_result(&ctx.progress, || processor(candidate))
+    })?;
+    if let Some(existing) = &existing_outputs {
+        stats.out_dir = Some(OutDirChanges::from_results(&results, existing));
     }
+    results.extend(
+        walk.duplicates
+            .into_iter()
+            .map(|duplicate| skip_duplicate_path(duplicate, &ctx.progress)),
+    );
+    let manifest_files = manifest_files(embed_manifest, &results);
 
-    #[derive(Clone)]
-    struct ApplyPlanDirTestCfg {
-        in_place: bool,
-        dry_run: bool,
-        show_stats: bool,
-        json_output: bool,
-        include_file: Option<PathBuf>,
-        include_hidden: bool,
-        follow_symlinks: bool,
-        glob_case_insensitive: Option<bool>,
-        quiet: bool,
-        output_json: Option<PathBuf>,
-        jobs: Option<usize>,
-        fail_on_bailout: bool,
-        fail_on_error: bool,
-        fail_on_change: bool,
-        diff: bool,
-        diff_context: usize,
-        max_depth: Option<usize>,
-        exclude_file: Option<PathBuf>,
-        respect_gitignore: bool,
+    let mut source_maps: Vec<FileSourceMap> = Vec::new();
+    let mut diffs: Vec<(String, String)> = Vec::new();
+    finalize_file_results(
+        results,
+        &mut stats,
+        dry_run,
+        quiet,
+        show_stats,
+        diff,
+        &mut source_maps,
+        diff_out.is_some().then_some(&mut diffs),
+    );
+
+    let write_started = Instant::now();
+    if let (OutDirMode::Prune { force }, Some(existing), Some(selected)) =
+        (out_dir_mode, &existing_outputs, &selected_outputs)
+    {
+        prune_out_dir(
+            &resolved_out_dir,
+            existing,
+            selected,
+            &walker.filter()?,
+            force,
+            dry_run,
+            show_stats,
+            quiet,
+            &mut stats,
+        );
+    }
+    if let Some(embed) = embed_manifest.filter(|_| !dry_run) {
+        write_manifests(&resolved_out_dir, embed, &manifest_files, &source_maps)?;
+    }
+    if let Some(target) = source_map {
+        // A single-file map only covers the files of the pass that wrote it, so watch
+        // passes leave it as written by the initial run.
+        let single_file = target.extension().is_some_and(|ext| ext == "json");
+        if !dry_run && (watch_pass.is_none() || !single_file) {
+            write_dir_source_maps(target, source_maps)?;
+        }
+    }
+
+    if let Some(target) = diff_out {
+        // Like single-file source maps, a patch bundle written by a watch pass would only
+        // cover the changed files, so it is left as written by the initial run.
+        if !(watch_pass.is_some() && diff_out_is_file(target)) {
+            write_dir_diffs(target, diffs)?;
+        }
+        stats.diff_out = Some(portable_path(target));
+    }
+    if let Some(timings) = stats.timings.as_mut() {
+        timings.write += write_started.elapsed();
+        timings.finish(run_started.elapsed());
+    }
+
+    let summary_needed = show_stats
+        || fail_on_bailout
+        || fail_on_error
+        || fail_on_change
+        || output_json.is_some()
+        || diff_out.is_some();
+    stats.summarize(summary_by_dir, top);
+    if summary_needed && watch_pass.is_none() {
+        let output_label = if in_place {
+            input_dir.display().to_string()
+        } else {
+            resolved_out_dir.display().to_string()
+        };
+        print_summary(
+            &stats,
+            show_stats,
+            json_output,
+            ctx.verbose_stats,
+            dry_run,
+            &output_label,
+            output_json,
+        )?;
+    }
+    ctx.progress.run_end(&stats);
+
+    Ok(stats)
+}
+
+/// Minify the Python entries of a `.tar.gz`, `.tar`, or `.zip`/`.whl` archive in memory.
+///
+/// Entries are filtered with the same include/exclude globs as `minify-dir`; matching
+/// sources are rewritten and everything else is copied verbatim, keeping entry order and
+/// permissions. Wheel `RECORD` rows are refreshed for every rewritten entry.
+fn minify_archive(
+    input: &Path,
+    output_archive: Option<&Path>,
+    archive_mtime: Option<u64>,
+    includes: &[String],
+    include_file: Option<&PathBuf>,
+    excludes: &[String],
+    exclude_file: Option<&PathBuf>,
+    dry_run: bool,
+    show_stats: bool,
+    json_output: bool,
+    include_hidden: bool,
+    glob_case_insensitive: Option<bool>,
+    quiet: bool,
+    output_json: Option<&Path>,
+    jobs: Option<usize>,
+    fail_on_bailout: bool,
+    fail_on_error: bool,
+    fail_on_change: bool,
+    diff: bool,
+    diff_context: usize,
+    remove_dead_code: bool,
+    stable_names: bool,
+    rename_module_privates: bool,
+    preserve_annotated_locals: bool,
+    warn_collisions: bool,
+    max_file_size: Option<u64>,
+    extensions: &[String],
+    source_map: Option<&Path>,
+    diff_out: Option<&Path>,
+    verify: bool,
+    verify_exec: Option<&Path>,
+    pipeline: &TransformPipeline,
+    skip_parse_errors: bool,
+    summary_by_dir: Option<usize>,
+    top: Option<usize>,
+    ctx: &CommandContext,
+) -> anyhow::Result<DirStats> {
+    if !input.is_file() {
+        anyhow::bail!("Input archive '{}' is not a file", input.display());
+    }
+
+    if json_output && !show_stats {
+        anyhow::bail!("--json requires --stats");
+    }
+    if (summary_by_dir.is_some() || top.is_some()) && !show_stats {
+        anyhow::bail!("--summary-by-dir and --top require --stats");
+    }
+
+    if output_archive.is_none() && !dry_run {
+        anyhow::bail!("--output-archive is required for archive input unless --dry-run");
+    }
+
+    if let Some(output) = output_archive {
+        let same_file = dunce_canonicalize(output)
+            .ok()
+            .zip(dunce_canonicalize(input).ok())
+            .is_some_and(|(output, input)| output == input);
+        if same_file {
+            anyhow::bail!("--output-archive cannot overwrite the input archive");
+        }
+    }
+
+    let input_format = ArchiveFormat::detect(input)?;
+    let output_format = output_archive
+        .and_then(ArchiveFormat::from_path)
+        .unwrap_or(input_format);
+    let mut archive = SourceArchive::read(input, input_format)?;
+
+    let jobs = resolve_jobs(jobs)?;
+
+    let mut stats = DirStats::default();
+
+    // Archives carry no .tsrsignore files to discover, so only the given patterns apply.
+    let filter = candidate_walker(
+        input,
+        includes,
+        include_file,
+        excludes,
+        exclude_file,
+        extensions,
+        include_hidden,
+        false,
+        glob_case_insensitive,
+        None,
+        false,
+        true,
+        ctx.no_default_excludes,
+    )
+    .filter()?;
+
+    // Entry paths stand in for paths relative to the input; `abs_path` only labels messages.
+    let mut candidates: Vec<Candidate> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (index, entry) in archive.entries.iter().enumerate() {
+        if !entry.is_file() {
+            continue;
+        }
+
+        let rel_norm = entry.path.trim_start_matches("./").to_string();
+        if !filter.matches(&rel_norm) {
+            continue;
+        }
+        let rel_path = PathBuf::from(&rel_norm);
+
+        if positions.insert(rel_norm.clone(), index).is_some() {
+            warn!(
+                "{} appears more than once in {}; only the last entry is minified",
+                rel_norm,
+                input.display()
+            );
+            continue;
+        }
+
+        candidates.push(Candidate {
+            abs_path: input.join(&rel_path),
+            rel_path,
+            rel_norm,
+            size: entry.data.len() as u64,
+        });
+    }
+
+    stats.processed = candidates.len();
+    let (jobs, jobs_reduced) = effective_jobs(jobs, &candidates, ctx.max_memory_mb);
+    stats.effective_jobs = jobs;
+    stats.jobs_reduced = jobs_reduced;
+    let naming = naming_mode(stable_names);
+    let entries = &archive.entries;
+
+    let processor = |candidate: &Candidate| -> (FileResult, Option<Vec<u8>>) {
+        let candidate_clone = candidate.clone();
+        let data = &entries[positions[&candidate.rel_norm]].data;
+        if let Some(limit) = max_file_size.filter(|limit| data.len() as u64 > *limit) {
+            file_log!(
+                DEBUG,
+                "• {} → skipped (too large: {} > {} bytes)",
+                candidate.rel_norm,
+                data.len(),
+                limit
+            );
+            let outcome = FileOutcome::Ready(Box::new(ReadyFile {
+                status_kind: FinalStatusKind::SkippedTooLarge,
+                renames: 0,
+                skipped: Vec::new(),
+                mapping: None,
+                diff: None,
+                imports_removed: 0,
+                stages: Vec::new(),
+                functions: None,
+                warnings: Vec::new(),
+                bytes_saved: 0,
+                lossy_decode: false,
+                fuzzy_matched: Vec::new(),
+            }));
+            return (
+                FileResult {
+                    candidate: candidate_clone,
+                    outcome,
+                    logs: Vec::new(),
+                    timing: FileTiming::default(),
+                },
+                None,
+            );
+        }
+        let (source, metadata) = match decode_python_bytes(data, &candidate.rel_norm, ctx.decode) {
+            Ok(result) => result,
+            Err(err) => {
+                let outcome = FileOutcome::ReadError {
+                    message: err.to_string(),
+                };
+                return (
+                    FileResult {
+                        candidate: candidate_clone,
+                        outcome,
+                        logs: Vec::new(),
+                        timing: FileTiming::default(),
+                    },
+                    None,
+                );
+            }
+        };
+
+        let module_name = module_name_for(&candidate.rel_path, &ctx.module_roots);
+        let transformed = if pipeline.is_empty() {
+            None
+        } else {
+            let context = TransformContext {
+                module_name: &module_name,
+                is_package_init: is_package_init(&candidate.rel_path),
+            };
+            match pipeline.run(&context, &source) {
+                Ok(output) => Some(output).filter(|output| output.source != source),
+                Err(err) => {
+                    let outcome =
+                        classify_plan_failure(candidate, &err, &source, skip_parse_errors);
+                    return (
+                        FileResult {
+                            candidate: candidate_clone,
+                            outcome,
+                            logs: Vec::new(),
+                            timing: FileTiming::default(),
+                        },
+                        None,
+                    );
+                }
+            }
+        };
+        let imports_removed = transformed
+            .as_ref()
+            .map_or(0, |output| output.changes(RemoveUnusedImports::NAME));
+        let stages = transformed
+            .as_ref()
+            .map_or_else(Vec::new, |output| output.stages.clone());
+        let planned = transformed
+            .as_ref()
+            .map_or(source.as_str(), |output| output.source.as_str());
+
+        let options = plan_options(
+            &candidate.rel_path,
+            &ctx.plan,
+            naming,
+            rename_module_privates,
+            preserve_annotated_locals,
+        );
+        let mut plan = match Minifier::plan_from_source_with_options(&module_name, planned, options)
+        {
+            Ok(plan) => plan,
+            Err(err) => {
+                let outcome = classify_plan_failure(candidate, &err, planned, skip_parse_errors);
+                return (
+                    FileResult {
+                        candidate: candidate_clone,
+                        outcome,
+                        logs: Vec::new(),
+                        timing: FileTiming::default(),
+                    },
+                    None,
+                );
+            }
+        };
+        log_plan_warnings(&plan, &candidate.rel_norm);
+
+        if remove_dead_code {
+            let dead_code = detect_dead_code(planned, &module_name, quiet, ctx).unwrap_or_default();
+            plan = filter_plan_for_dead_code(plan, &dead_code);
+        }
+        let warnings = if warn_collisions {
+            collision_warnings(&plan, planned, &candidate.rel_norm)
+        } else {
+            Vec::new()
+        };
+
+        let rename_total: usize = plan.functions.iter().map(|f| f.renames.len()).sum();
+        let has_nested = plan.functions.iter().any(|f| f.has_nested_functions);
+
+        let mut functions = show_stats.then(|| function_stats(&plan, None, false));
+        let (status_kind, rewritten, renames, mut skipped, mapping) = if plan.skip_file {
+            (FinalStatusKind::SkippedDirective, None, 0, Vec::new(), None)
+        } else if (has_nested || rename_total == 0) && transformed.is_some() {
+            (
+                FinalStatusKind::Minified,
+                Some(planned.to_string()),
+                0,
+                Vec::new(),
+                None,
+            )
+        } else if has_nested {
+            (FinalStatusKind::SkippedNested, None, 0, Vec::new(), None)
+        } else if rename_total == 0 {
+            (FinalStatusKind::SkippedNoRenames, None, 0, Vec::new(), None)
+        } else {
+            match Minifier::rewrite_with_plan_options(&module_name, planned, &plan, ctx.rewrite) {
+                Ok(outcome) if outcome.source == source => {
+                    functions = show_stats.then(|| function_stats(&plan, Some(&outcome), false));
+                    (
+                        FinalStatusKind::SkippedRewriteAborted,
+                        None,
+                        0,
+                        locate_skipped(&plan, outcome.skipped),
+                        None,
+                    )
+                }
+                Ok(mut outcome) => {
+                    functions = show_stats.then(|| function_stats(&plan, Some(&outcome), true));
+                    let rewritten = std::mem::take(&mut outcome.source);
+                    let mapping = source_map.map(|_| {
+                        FileSourceMap::from_applied(
+                            &candidate.rel_norm,
+                            &plan,
+                            &outcome.applied,
+                            &rewritten,
+                        )
+                    });
+                    (
+                        FinalStatusKind::Minified,
+                        Some(rewritten),
+                        rename_total,
+                        locate_skipped(&plan, std::mem::take(&mut outcome.skipped)),
+                        mapping,
+                    )
+                }
+                Err(err) => {
+                    let outcome = FileOutcome::RewriteError {
+                        message: err.to_string(),
+                    };
+                    return (
+                        FileResult {
+                            candidate: candidate_clone,
+                            outcome,
+                            logs: Vec::new(),
+                            timing: FileTiming::default(),
+                        },
+                        None,
+                    );
+                }
+            }
+        };
+
+        if let Some(output) = &transformed {
+            restore_original_lines(&source, &output.source, functions.as_mut(), &mut skipped);
+        }
+
+        let (mut outcome, bytes) = encode_archive_entry(
+            candidate,
+            &source,
+            rewritten,
+            renames,
+            skipped,
+            mapping,
+            status_kind,
+            &metadata,
+            (diff && !quiet) || diff_out.is_some(),
+            diff_context,
+            verify,
+            verify_exec,
+        );
+        if let FileOutcome::Ready(ready) = &mut outcome {
+            ready.imports_removed = imports_removed;
+            ready.stages = stages;
+            ready.functions = functions;
+            ready.warnings = warnings;
+        }
+        (
+            FileResult {
+                candidate: candidate_clone,
+                outcome,
+                logs: Vec::new(),
+                timing: FileTiming::default(),
+            },
+            bytes,
+        )
+    };
+
+    let processed = execute_parallel_processing(&candidates, jobs, |candidate| {
+        let ((mut result, bytes), logs) = capture_file_logs(|| processor(candidate));
+        result.logs = logs;
+        (result, bytes)
+    })?;
+
+    let mut modified: HashSet<String> = HashSet::new();
+    let mut results = Vec::with_capacity(processed.len());
+    for (result, bytes) in processed {
+        if let Some(bytes) = bytes {
+            let entry = &mut archive.entries[positions[&result.candidate.rel_norm]];
+            modified.insert(entry.path.clone());
+            entry.data = bytes;
+        }
+        results.push(result);
+    }
+
+    let mut source_maps: Vec<FileSourceMap> = Vec::new();
+    let mut diffs: Vec<(String, String)> = Vec::new();
+    finalize_file_results(
+        results,
+        &mut stats,
+        dry_run,
+        quiet,
+        show_stats,
+        diff,
+        &mut source_maps,
+        diff_out.is_some().then_some(&mut diffs),
+    );
+
+    if let Some(target) = source_map {
+        if !dry_run {
+            write_dir_source_maps(target, source_maps)?;
+        }
+    }
+
+    if let Some(target) = diff_out {
+        write_dir_diffs(target, diffs)?;
+        stats.diff_out = Some(portable_path(target));
+    }
+
+    let output_label = match output_archive {
+        Some(output) if !dry_run => {
+            archive.refresh_wheel_record(&modified);
+            archive.write(output, output_format, archive_mtime)?;
+            output.display().to_string()
+        }
+        _ => input.display().to_string(),
+    };
+
+    let summary_needed = show_stats
+        || fail_on_bailout
+        || fail_on_error
+        || fail_on_change
+        || output_json.is_some()
+        || diff_out.is_some();
+    stats.summarize(summary_by_dir, top);
+    if summary_needed {
+        print_summary(
+            &stats,
+            show_stats,
+            json_output,
+            ctx.verbose_stats,
+            dry_run,
+            &output_label,
+            output_json,
+        )?;
+    }
+
+    Ok(stats)
+}
+
+/// How long the watcher waits for editors to stop writing before re-minifying.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Poll interval for filesystem events and the shutdown signal.
+const WATCH_POLL: Duration = Duration::from_millis(50);
+
+/// Advisory lock file created in an output root while a run writes into it.
+const LOCK_FILE_NAME: &str = ".tsrs.lock";
+
+/// Age after which `--force-lock` may take over a lock whose owner still looks alive.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How often `--wait` checks whether a held lock has been released.
+const LOCK_POLL: Duration = Duration::from_millis(250);
+
+/// Lock files this process holds; the Ctrl-C handler removes them before exiting.
+static HELD_LOCKS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Where Ctrl-C is forwarded while `--watch` waits for it, instead of exiting.
+static INTERRUPT_FORWARD: Mutex<Option<mpsc::Sender<()>>> = Mutex::new(None);
+
+static INTERRUPT_HANDLER_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Install the process-wide Ctrl-C handler once. It hands the signal to a registered
+/// `--watch` loop, or otherwise removes held lock files and exits.
+fn install_interrupt_handler() -> anyhow::Result<()> {
+    if INTERRUPT_HANDLER_INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    ctrlc::set_handler(|| {
+        let forward = INTERRUPT_FORWARD
+            .lock()
+            .ok()
+            .and_then(|sender| sender.clone());
+        if forward.is_some_and(|sender| sender.send(()).is_ok()) {
+            return;
+        }
+        if let Ok(held) = HELD_LOCKS.lock() {
+            for path in held.iter() {
+                let _ = fs::remove_file(path);
+            }
+        }
+        process::exit(130);
+    })
+    .context("failed to install Ctrl-C handler")
+}
+
+/// Contents of a `.tsrs.lock` file.
+#[derive(Debug, Serialize, Deserialize)]
+struct LockInfo {
+    pid: u32,
+    hostname: String,
+    /// Seconds since the Unix epoch when the run started.
+    started: u64,
+}
+
+impl LockInfo {
+    fn current() -> Self {
+        Self {
+            pid: process::id(),
+            hostname: hostname(),
+            started: unix_now(),
+        }
+    }
+
+    fn age(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.started))
+    }
+
+    /// A lock is stale once it is older than [`STALE_LOCK_AGE`] or its process on this
+    /// host has exited.
+    fn is_stale(&self) -> bool {
+        self.age() >= STALE_LOCK_AGE
+            || (self.hostname == hostname() && process_alive(self.pid) == Some(false))
+    }
+}
+
+impl std::fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pid {} on {}, started {}s ago",
+            self.pid,
+            self.hostname,
+            self.age().as_secs()
+        )
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn hostname() -> String {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok())
+        .or_else(|| {
+            let output = process::Command::new("hostname").output().ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Whether `pid` is a running process, or `None` when that cannot be checked here.
+fn process_alive(pid: u32) -> Option<bool> {
+    if cfg!(unix) {
+        process::Command::new("kill")
+            .args(["-0", &pid.to_string()])
+            .stderr(process::Stdio::null())
+            .status()
+            .ok()
+            .map(|status| status.success())
+    } else {
+        None
+    }
+}
+
+/// Advisory lock on an output root, released when dropped.
+struct OutputLock {
+    path: PathBuf,
+}
+
+impl OutputLock {
+    /// Create `.tsrs.lock` in `root`, failing if another run holds it. `wait` polls for
+    /// up to that many seconds; `force` takes over a stale lock with a warning.
+    fn acquire(root: &Path, wait: Option<u64>, force: bool) -> anyhow::Result<Self> {
+        install_interrupt_handler()?;
+        let path = root.join(LOCK_FILE_NAME);
+        let deadline = wait.map(|secs| Instant::now() + Duration::from_secs(secs));
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    // Registered first so Ctrl-C cleans up even a half-written lock.
+                    if let Ok(mut held) = HELD_LOCKS.lock() {
+                        held.push(path.clone());
+                    }
+                    let lock = Self { path };
+                    serde_json::to_writer(&mut file, &LockInfo::current())
+                        .with_context(|| format!("failed to write {}", lock.path.display()))?;
+                    return Ok(lock);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to create {}", path.display()));
+                }
+            }
+
+            // An unreadable lock is treated as stale: its writer died mid-write or it is
+            // not ours.
+            let holder = fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| serde_json::from_str::<LockInfo>(&text).ok());
+            let holder_text = holder
+                .as_ref()
+                .map_or_else(|| "unreadable lock".to_string(), ToString::to_string);
+            let stale = match &holder {
+                Some(info) => info.is_stale(),
+                None => true,
+            };
+            if force && stale {
+                warn!("removing stale lock {} ({})", path.display(), holder_text);
+                match fs::remove_file(&path) {
+                    Ok(()) => continue,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(err) => {
+                        return Err(err)
+                            .with_context(|| format!("failed to remove {}", path.display()));
+                    }
+                }
+            }
+            if deadline.is_some_and(|deadline| Instant::now() < deadline) {
+                std::thread::sleep(LOCK_POLL);
+                continue;
+            }
+            bail!(
+                "{} is locked by another tsrs run ({}); pass --wait <SECS> to wait for it, or \
+                 --force-lock to take over a lock left by a run that died",
+                root.display(),
+                holder_text
+            );
+        }
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // Only remove the lock while it is still ours; `--force-lock` elsewhere may have
+        // replaced it.
+        let holder = fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<LockInfo>(&text).ok());
+        let ours = match holder {
+            Some(info) => info.pid == process::id(),
+            None => true,
+        };
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+        if let Ok(mut held) = HELD_LOCKS.lock() {
+            held.retain(|path| path != &self.path);
+        }
+    }
+}
+
+/// Whether `dir` holds anything besides a lock file or an `--embed-manifest` manifest.
+fn has_output_entries(dir: &Path) -> anyhow::Result<bool> {
+    for entry in dir.read_dir()? {
+        let name = entry?.file_name();
+        if name != LOCK_FILE_NAME && !is_manifest_file(Path::new(&name)) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// How `minify-dir` treats an output directory that already has files in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutDirMode {
+    /// Refuse to write into it.
+    Fresh,
+    /// `--merge-out-dir`: overwrite the outputs of processed files, keep everything else.
+    Merge,
+    /// `--prune-out-dir`: merge, then delete outputs whose sources are gone; with `force`
+    /// (`--prune-force`), also files the filters would never select.
+    Prune { force: bool },
+}
+
+/// `/`-separated paths of the files under `dir`, skipping the lock file and manifests.
+/// A directory that does not exist yet has no files.
+fn list_output_files(dir: &Path) -> anyhow::Result<BTreeSet<String>> {
+    let mut files = BTreeSet::new();
+    if !dir.is_dir() {
+        return Ok(files);
+    }
+    for entry in walkdir::WalkDir::new(dir).min_depth(1) {
+        let entry = entry?;
+        if !entry.file_type().is_file() || is_manifest_file(entry.path()) {
+            continue;
+        }
+        let rel_path = entry.path().strip_prefix(dir)?;
+        if rel_path != Path::new(LOCK_FILE_NAME) {
+            files.insert(normalize_rel_path(rel_path));
+        }
+    }
+    Ok(files)
+}
+
+/// Delete the outputs in `out_dir` that no selected source maps to, for `--prune-out-dir`.
+///
+/// Outputs the filters would still select are stale and deleted, along with directories
+/// left empty. Anything else was put there by hand, so it is reported and kept unless
+/// `force` is set. Dry runs only count what would be deleted.
+fn prune_out_dir(
+    out_dir: &Path,
+    existing: &BTreeSet<String>,
+    selected: &HashSet<String>,
+    filter: &CandidateFilter,
+    force: bool,
+    dry_run: bool,
+    show_stats: bool,
+    quiet: bool,
+    stats: &mut DirStats,
+) {
+    let mut changes = stats.out_dir.take().unwrap_or_default();
+    for rel_norm in existing
+        .iter()
+        .filter(|rel_norm| !selected.contains(*rel_norm))
+    {
+        if !force && !filter.matches(rel_norm) {
+            warn!(
+                "{} in the output directory matches no input filter; left in place (pass --prune-force to delete it)",
+                rel_norm
+            );
+            changes.foreign.push(rel_norm.clone());
+            continue;
+        }
+        if !dry_run {
+            let target = out_dir.join(rel_norm);
+            if let Err(err) = fs::remove_file(&target) {
+                error!("failed to remove {}: {}", target.display(), err);
+                stats.errors += 1;
+                continue;
+            }
+            let mut parent = target.parent();
+            while let Some(dir) = parent.filter(|dir| *dir != out_dir) {
+                if fs::remove_dir(dir).is_err() {
+                    break;
+                }
+                parent = dir.parent();
+            }
+        }
+        changes.pruned += 1;
+        print_file_status(rel_norm, "pruned", 0, show_stats, quiet);
+    }
+    stats.out_dir = Some(changes);
+}
+
+/// Watch `input_dir` and re-run `run_pass` for changed sources until `shutdown` fires.
+///
+/// `run_pass` receives the set of changed source paths; filtering, rewriting, and status
+/// output are left to it so watch passes behave exactly like the initial run. Outputs whose
+/// sources were removed are deleted here. Returns the combined stats of every pass.
+#[allow(clippy::too_many_arguments)]
+fn watch_minify_dir<F>(
+    input_dir: &Path,
+    out_dir: Option<&Path>,
+    dry_run: bool,
+    show_stats: bool,
+    quiet: bool,
+    mut stats: DirStats,
+    shutdown: &mpsc::Receiver<()>,
+    mut run_pass: F,
+) -> anyhow::Result<DirStats>
+where
+    F: FnMut(&HashSet<PathBuf>) -> anyhow::Result<DirStats>,
+{
+    let input_dir = canonicalize_directory(input_dir)?;
+    let (event_tx, event_rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let _ = event_tx.send(event);
+    })
+    .context("failed to start file watcher")?;
+    watcher
+        .watch(&input_dir, RecursiveMode::Recursive)
+        .with_context(|| format!("failed to watch {}", input_dir.display()))?;
+
+    if !quiet {
+        println!(
+            "Watching {} for changes (Ctrl-C to stop)",
+            input_dir.display()
+        );
+    }
+
+    let mut pending: HashSet<PathBuf> = HashSet::new();
+    let mut last_event: Option<Instant> = None;
+
+    loop {
+        match shutdown.try_recv() {
+            Ok(()) | Err(mpsc::TryRecvError::Disconnected) => break,
+            Err(mpsc::TryRecvError::Empty) => {}
+        }
+
+        match event_rx.recv_timeout(WATCH_POLL) {
+            Ok(Ok(event)) => {
+                if !matches!(event.kind, notify::EventKind::Access(_)) {
+                    pending.extend(event.paths);
+                    last_event = Some(Instant::now());
+                }
+            }
+            Ok(Err(err)) => warn!("watch error: {}", err),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        let settled = last_event.is_some_and(|at| at.elapsed() >= WATCH_DEBOUNCE);
+        if !settled || pending.is_empty() {
+            continue;
+        }
+        last_event = None;
+
+        let (changed, removed): (HashSet<PathBuf>, Vec<PathBuf>) = {
+            let mut changed = HashSet::new();
+            let mut removed = Vec::new();
+            for path in pending.drain() {
+                if path.is_file() {
+                    changed.insert(path);
+                } else if !path.exists() {
+                    removed.push(path);
+                }
+            }
+            (changed, removed)
+        };
+
+        if let Some(out_dir) = out_dir {
+            if !dry_run {
+                for path in removed {
+                    remove_watched_output(&input_dir, out_dir, &path, show_stats, quiet);
+                }
+            }
+        }
+
+        if !changed.is_empty() {
+            match run_pass(&changed) {
+                Ok(pass) => stats.merge(pass),
+                Err(err) => {
+                    stats.errors += 1;
+                    error!("watch pass failed: {:#}", err);
+                }
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Delete the mirrored output for a source path that no longer exists.
+fn remove_watched_output(
+    input_dir: &Path,
+    out_dir: &Path,
+    source: &Path,
+    show_stats: bool,
+    quiet: bool,
+) {
+    let Ok(rel_path) = source.strip_prefix(input_dir) else {
+        return;
+    };
+    if rel_path.as_os_str().is_empty() {
+        return;
+    }
+    let target = out_dir.join(rel_path);
+    let result = if target.is_dir() {
+        fs::remove_dir_all(&target)
+    } else if target.is_file() {
+        fs::remove_file(&target)
+    } else {
+        return;
+    };
+    let rel_norm = normalize_rel_path(rel_path);
+    match result {
+        Ok(()) => print_file_status(&rel_norm, "removed", 0, show_stats, quiet),
+        Err(err) => error!("failed to remove {}: {}", target.display(), err),
+    }
+}
+
+fn default_output_dir(input_dir: &Path) -> PathBuf {
+    let parent = input_dir
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .map(|p| p.to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    let name = input_dir
+        .file_name()
+        .map(|os| os.to_string_lossy().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "minified".to_string());
+
+    parent.join(format!("{}-min", name))
+}
+
+/// Package `__init__.py` files usually re-export what they import, so
+/// `--remove-unused-imports` leaves them alone.
+fn is_package_init(path: &Path) -> bool {
+    path.file_stem().is_some_and(|stem| stem == "__init__")
+}
+
+/// Module name of a file under the input, re-based on the `--module-root` `roots`; a file
+/// under no root keeps the name derived from the input directory, with a warning.
+fn module_name_for(rel_path: &Path, roots: &[PathBuf]) -> String {
+    if !roots.is_empty() && strip_module_root(rel_path, roots).is_none() {
+        file_log!(
+            WARN,
+            "{} is under no --module-root; naming its module from the input directory",
+            portable_path(rel_path)
+        );
+    }
+    derive_module_name(rel_path, roots)
+}
+
+/// `--module-root` values as paths relative to `input_dir`, in the order given without
+/// repeats. Absolute roots must lie inside the input directory.
+fn resolve_module_roots(input_dir: &Path, roots: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut resolved: Vec<PathBuf> = Vec::new();
+    for root in roots {
+        let relative = if root.is_absolute() {
+            let input = canonicalize_directory(input_dir)?;
+            let root = canonicalize_directory(root)?;
+            root.strip_prefix(&input)
+                .map(Path::to_path_buf)
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "--module-root {} is not inside {}",
+                        root.display(),
+                        input.display()
+                    )
+                })?
+        } else {
+            let mut relative = PathBuf::new();
+            for component in root.components() {
+                match component {
+                    std::path::Component::CurDir => {}
+                    std::path::Component::Normal(part) => relative.push(part),
+                    _ => bail!(
+                        "--module-root {} must be inside the input directory",
+                        root.display()
+                    ),
+                }
+            }
+            relative
+        };
+        if !resolved.contains(&relative) {
+            resolved.push(relative);
+        }
+    }
+    Ok(resolved)
+}
+
+/// Candidate selection shared by every directory command, so new walker options reach
+/// all of them alike.
+/// Keep only the candidates git reports changed since `reference` (see
+/// [`changed_files_since`]), returning how many were dropped.
+fn retain_changed(
+    candidates: &mut Vec<Candidate>,
+    input_dir: &Path,
+    reference: &str,
+    optional: bool,
+) -> anyhow::Result<usize> {
+    let Some(changed) = changed_files_since(input_dir, reference, optional)? else {
+        return Ok(0);
+    };
+    let before = candidates.len();
+    candidates.retain(|candidate| changed.contains(&candidate.rel_norm));
+    info!(
+        "--changed-since {}: {} of {} candidates changed",
+        reference,
+        candidates.len(),
+        before
+    );
+    Ok(before - candidates.len())
+}
+
+/// Paths under `input_dir` (relative to it, `/`-separated) that git reports changed
+/// between `reference` and the working tree, plus untracked files. Deleted files are left
+/// out and renamed files appear under their new path. Returns `None` when `input_dir` is
+/// not in a git work tree and `optional` is set.
+fn changed_files_since(
+    input_dir: &Path,
+    reference: &str,
+    optional: bool,
+) -> anyhow::Result<Option<HashSet<String>>> {
+    let git = |args: &[&str]| {
+        process::Command::new("git")
+            .arg("-C")
+            .arg(input_dir)
+            .args(args)
+            .output()
+    };
+
+    let in_work_tree = git(&["rev-parse", "--is-inside-work-tree"])
+        .is_ok_and(|output| output.status.success() && output.stdout.starts_with(b"true"));
+    if !in_work_tree {
+        if optional {
+            warn!(
+                "{} is not inside a git work tree; ignoring --changed-since",
+                input_dir.display()
+            );
+            return Ok(None);
+        }
+        bail!(
+            "--changed-since needs a git work tree, but {} is not inside one (pass --changed-since-optional to process every file)",
+            input_dir.display()
+        );
+    }
+
+    let diff = [
+        "diff",
+        "--name-only",
+        "-z",
+        "--relative",
+        "-M",
+        "--diff-filter=d",
+        reference,
+        "--",
+    ];
+    let untracked = ["ls-files", "--others", "--exclude-standard", "-z"];
+    let mut changed = HashSet::new();
+    for args in [&diff[..], &untracked[..]] {
+        let output = git(args).context("failed to run git")?;
+        if !output.status.success() {
+            bail!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        changed.extend(
+            output
+                .stdout
+                .split(|byte| *byte == 0)
+                .filter(|path| !path.is_empty())
+                .map(|path| String::from_utf8_lossy(path).into_owned()),
+        );
+    }
+    Ok(Some(changed))
+}
+
+fn candidate_walker(
+    input_dir: &Path,
+    includes: &[String],
+    include_file: Option<&PathBuf>,
+    excludes: &[String],
+    exclude_file: Option<&PathBuf>,
+    extensions: &[String],
+    include_hidden: bool,
+    follow_symlinks: bool,
+    glob_case_insensitive: Option<bool>,
+    max_depth: Option<usize>,
+    respect_gitignore: bool,
+    no_tsrsignore: bool,
+    no_default_excludes: bool,
+) -> CandidateWalker {
+    let mut walker = CandidateWalker::new(input_dir)
+        .includes(includes)
+        .excludes(excludes)
+        .default_excludes(!no_default_excludes)
+        .extensions(extensions)
+        .include_hidden(include_hidden)
+        .follow_symlinks(follow_symlinks)
+        .case_insensitive(glob_case_insensitive.unwrap_or(cfg!(windows)))
+        .max_depth(max_depth)
+        .respect_gitignore(respect_gitignore)
+        .tsrsignore(!no_tsrsignore);
+    if let Some(path) = include_file {
+        walker = walker.include_file(path);
+    }
+    if let Some(path) = exclude_file {
+        walker = walker.exclude_file(path);
+    }
+    walker
+}
+
+/// Log the globs a dir command selects its files with, so a walk that picks the wrong
+/// files can be explained without debug logging.
+fn log_walk_patterns(patterns: &WalkPatterns) {
+    let list = |patterns: &[String]| {
+        if patterns.is_empty() {
+            "(none)".to_string()
+        } else {
+            patterns.join(", ")
+        }
+    };
+    info!("Include patterns: {}", list(&patterns.include));
+    info!("Exclude patterns: {}", list(&patterns.exclude));
+}
+
+/// Settings of the running command that reach the files it processes, built once from the
+/// parsed arguments.
+#[derive(Debug, Clone)]
+struct CommandContext {
+    /// Add per-function lines to the `--stats` summary (`-v`).
+    verbose_stats: bool,
+    decode: DecodeOptions,
+    /// Planning settings shared by every file; [`plan_options`] adds the per-file ones.
+    plan: PlanOptions,
+    /// How plans are applied: `--fuzzy-apply`, `--annotate-renames`, and whether docstrings
+    /// stay.
+    rewrite: RewriteOptions,
+    /// Report per-phase wall times (`--timings` on the dir commands).
+    timings: bool,
+    /// `--asserts-are-roots`, on unless switched off.
+    asserts_are_roots: bool,
+    /// Write in place over files edited since they were read (`--overwrite-changed`).
+    overwrite_changed: bool,
+    /// `--max-memory` in megabytes; 0 leaves the worker count alone.
+    max_memory_mb: u64,
+    /// Walk `.git`, `__pycache__`, and `.venv` too (`--no-default-excludes`).
+    no_default_excludes: bool,
+    /// `--module-root` directories relative to the input directory.
+    module_roots: Vec<PathBuf>,
+    /// `--progress-json` events, off unless turned on.
+    progress: ProgressStream,
+}
+
+impl Default for CommandContext {
+    fn default() -> Self {
+        Self {
+            verbose_stats: false,
+            decode: DecodeOptions::default(),
+            plan: PlanOptions::default(),
+            rewrite: RewriteOptions::default(),
+            timings: false,
+            asserts_are_roots: true,
+            overwrite_changed: false,
+            max_memory_mb: 0,
+            no_default_excludes: false,
+            module_roots: Vec::new(),
+            progress: ProgressStream::default(),
+        }
+    }
+}
+
+/// Components of `--level` (level 1 when absent) with the individual flags layered on top:
+/// the enabling flags add to the level and the `--no-*` flags take components away.
+fn level_settings(
+    level: Option<u8>,
+    remove_unused_imports: bool,
+    rename_module_privates: bool,
+    remove_dead_code: bool,
+    no_strip_docstrings: bool,
+    no_strip_comments: bool,
+    no_compact_blank_lines: bool,
+) -> LevelSettings {
+    let mut settings = level
+        .and_then(MinifyLevel::from_number)
+        .unwrap_or_default()
+        .settings();
+    settings.remove_unused_imports |= remove_unused_imports;
+    settings.rename_module_privates |= rename_module_privates;
+    settings.remove_dead_code |= remove_dead_code;
+    settings.strip_docstrings &= !no_strip_docstrings;
+    settings.keep_docstrings = no_strip_docstrings;
+    settings.strip_comments &= !no_strip_comments;
+    settings.compact_blank_lines &= !no_compact_blank_lines;
+    settings
+}
+
+fn call_graph_analyzer(ctx: &CommandContext) -> CallGraphAnalyzer {
+    let mut analyzer = CallGraphAnalyzer::new();
+    analyzer.set_asserts_are_roots(ctx.asserts_are_roots);
+    analyzer
+}
+
+/// `base` completed with the command's settings for `path`. Stubs describe an interface,
+/// so their parameter names are left alone.
+fn plan_options(
+    path: &Path,
+    base: &PlanOptions,
+    naming: NamingMode,
+    rename_module_privates: bool,
+    preserve_annotated_locals: bool,
+) -> PlanOptions {
+    let is_stub = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pyi"));
+    PlanOptions {
+        naming,
+        keep_parameters: is_stub,
+        rename_module_privates,
+        preserve_annotated_locals,
+        ..base.clone()
+    }
+}
+
+/// Log what the planner left alone but flagged in `label`, such as a `namedtuple` bound to
+/// a variable of another name.
+fn log_plan_warnings(plan: &MinifyPlan, label: &str) {
+    for function in &plan.functions {
+        for warning in &function.warnings {
+            file_log!(WARN, "{}: {}: {}", label, function.qualified_name, warning);
+        }
+    }
+}
+
+struct FileResult {
+    candidate: Candidate,
+    outcome: FileOutcome,
+    /// Messages raised while processing the file, replayed in path order.
+    logs: Vec<LogLine>,
+    timing: FileTiming,
+}
+
+/// A log message held back until its file's results are reported.
+struct LogLine {
+    level: tracing::Level,
+    message: String,
+}
+
+impl LogLine {
+    fn emit(&self) {
+        if self.level == tracing::Level::ERROR {
+            error!("{}", self.message);
+        } else if self.level == tracing::Level::WARN {
+            warn!("{}", self.message);
+        } else if self.level == tracing::Level::INFO {
+            info!("{}", self.message);
+        } else {
+            debug!("{}", self.message);
+        }
+    }
+}
+
+thread_local! {
+    /// Log lines of the file this worker thread is processing, if any.
+    static FILE_LOGS: std::cell::RefCell<Option<Vec<LogLine>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Hold `message` for the file being processed on this thread, or log it right away
+/// outside of [`capture_file_logs`].
+///
+/// Parallel workers would otherwise interleave messages from different files.
+fn record_file_log(level: tracing::Level, message: String) {
+    let line = LogLine { level, message };
+    let uncaptured = FILE_LOGS.with(|logs| match logs.borrow_mut().as_mut() {
+        Some(lines) => {
+            lines.push(line);
+            None
+        }
+        None => Some(line),
+    });
+    if let Some(line) = uncaptured {
+        line.emit();
+    }
+}
+
+/// Run `process`, collecting what it logs through [`file_log!`] instead of emitting it.
+fn capture_file_logs<T>(process: impl FnOnce() -> T) -> (T, Vec<LogLine>) {
+    let outer = FILE_LOGS.with(|logs| logs.replace(Some(Vec::new())));
+    let value = process();
+    let lines = FILE_LOGS.with(|logs| logs.replace(outer));
+    (value, lines.unwrap_or_default())
+}
+
+/// Process one file, attaching the messages it logged to its result and reporting it to
+/// `progress` as soon as it is done.
+fn capture_file_result(
+    progress: &ProgressStream,
+    process: impl FnOnce() -> FileResult,
+) -> FileResult {
+    let started = Instant::now();
+    let (mut result, logs) = capture_file_logs(process);
+    result.logs = logs;
+    result.timing.elapsed = started.elapsed();
+    progress.file_done(
+        &result.candidate.rel_norm,
+        result.outcome.status(),
+        result.outcome.renames(),
+        result.timing.elapsed,
+    );
+    result
+}
+
+/// Where `--progress-json` events go; off unless a dir command turned them on.
+#[derive(Clone, Default)]
+struct ProgressStream(Option<Arc<Mutex<Box<dyn Write + Send>>>>);
+
+impl std::fmt::Debug for ProgressStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ProgressStream")
+            .field(&self.enabled())
+            .finish()
+    }
+}
+
+/// One line of the `--progress-json` stream, described by [`PROGRESS_EVENTS_SCHEMA`].
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ProgressEvent<'a> {
+    RunStart {
+        command: &'a str,
+        input: String,
+        options: serde_json::Value,
+    },
+    FileQueued {
+        total: usize,
+    },
+    FileDone {
+        path: &'a str,
+        status: &'a str,
+        renames: usize,
+        elapsed_ms: u64,
+    },
+    RunEnd {
+        stats: serde_json::Value,
+    },
+}
+
+impl ProgressStream {
+    /// Send `--progress-json` events to `progress_file`, or to stderr without one.
+    fn open(progress_file: Option<&Path>) -> anyhow::Result<Self> {
+        let out: Box<dyn Write + Send> =
+            match progress_file {
+                Some(path) => Box::new(fs::File::create(path).with_context(|| {
+                    format!("failed to create progress file '{}'", path.display())
+                })?),
+                None => Box::new(std::io::stderr()),
+            };
+        Ok(Self(Some(Arc::new(Mutex::new(out)))))
+    }
+
+    /// Whether `--progress-json` is on, so callers can skip building event payloads.
+    fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Write `event` as one line, flushed so a reader sees it as it happens.
+    ///
+    /// A reader that goes away must not fail the run, so write errors are only logged.
+    fn emit(&self, event: &ProgressEvent<'_>) {
+        let Some(out) = &self.0 else {
+            return;
+        };
+        let Ok(mut out) = out.lock() else {
+            return;
+        };
+        let written = serde_json::to_string(event)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(out, "{}", line))
+            .and_then(|()| out.flush());
+        if let Err(err) = written {
+            debug!("failed to write progress event: {}", err);
+        }
+    }
+
+    fn file_done(&self, path: &str, status: &str, renames: usize, elapsed: Duration) {
+        self.emit(&ProgressEvent::FileDone {
+            path,
+            status,
+            renames,
+            elapsed_ms: u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+        });
+    }
+
+    fn run_end(&self, stats: &impl Serialize) {
+        if !self.enabled() {
+            return;
+        }
+        match serde_json::to_value(stats) {
+            Ok(stats) => self.emit(&ProgressEvent::RunEnd { stats }),
+            Err(err) => debug!("failed to serialize run totals: {}", err),
+        }
+    }
+}
+
+/// JSON Schema of the `--progress-json` event stream.
+const PROGRESS_EVENTS_SCHEMA: &str = r##"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "$id": "https://github.com/GeorgePearse/tsrs/schemas/progress-events-v1.json",
+  "title": "tsrs progress event",
+  "description": "One line of the JSON Lines stream written by --progress-json on minify-dir, apply-plan-dir, and minify-plan-dir. A run emits run_start, then file_queued, then one file_done per queued file as it completes (in completion order), then run_end. Each --watch pass is a run of its own.",
+  "type": "object",
+  "required": ["event"],
+  "oneOf": [
+    {
+      "properties": {
+        "event": { "const": "run_start" },
+        "command": {
+          "description": "Subcommand that started the run.",
+          "enum": ["minify-dir", "apply-plan-dir", "minify-plan-dir"]
+        },
+        "input": {
+          "description": "Canonical path of the input directory.",
+          "type": "string"
+        },
+        "options": {
+          "description": "Options of the run after config files and defaults are applied.",
+          "type": "object"
+        }
+      },
+      "required": ["event", "command", "input", "options"]
+    },
+    {
+      "properties": {
+        "event": { "const": "file_queued" },
+        "total": {
+          "description": "Number of files the run will report with file_done.",
+          "type": "integer",
+          "minimum": 0
+        }
+      },
+      "required": ["event", "total"]
+    },
+    {
+      "properties": {
+        "event": { "const": "file_done" },
+        "path": {
+          "description": "Path of the file relative to the input directory, using '/' separators.",
+          "type": "string"
+        },
+        "status": {
+          "description": "What happened to the file: 'minified' or 'planned', a skip reason such as 'no_renames' or 'nested_scopes', or an error such as 'read_error' or 'syntax_error'. Matches the reasons counted in run_end's stats.",
+          "type": "string"
+        },
+        "renames": {
+          "description": "Renames applied to (or planned for) the file.",
+          "type": "integer",
+          "minimum": 0
+        },
+        "elapsed_ms": {
+          "description": "Time spent processing the file, in milliseconds.",
+          "type": "integer",
+          "minimum": 0
+        }
+      },
+      "required": ["event", "path", "status", "renames", "elapsed_ms"]
+    },
+    {
+      "properties": {
+        "event": { "const": "run_end" },
+        "stats": {
+          "description": "Final totals: the --output-json summary for minify-dir and apply-plan-dir, or processed, planned, and errors counts for minify-plan-dir.",
+          "type": "object"
+        }
+      },
+      "required": ["event", "stats"]
+    }
+  ]
+}
+"##;
+
+enum FileOutcome {
+    /// The file went through the pipeline and its output, if any, has been written.
+    Ready(Box<ReadyFile>),
+    ReadError {
+        message: String,
+    },
+    PlanError {
+        message: String,
+    },
+    /// The file does not parse and `--skip-parse-errors` is off.
+    SyntaxError {
+        issue: SyntaxIssue,
+        message: String,
+    },
+    RewriteError {
+        message: String,
+    },
+    /// The rewritten output failed `--verify` and was not written.
+    VerifyError {
+        message: String,
+    },
+    WriteError {
+        reason: &'static str,
+        message: String,
+    },
+}
+
+impl FileOutcome {
+    /// Reason code of the outcome, as counted in the summary's `reasons`.
+    fn status(&self) -> &'static str {
+        match self {
+            FileOutcome::Ready(ready) => ready.status_kind.reason(),
+            FileOutcome::ReadError { .. } => "read_error",
+            FileOutcome::PlanError { .. } => "plan_error",
+            FileOutcome::SyntaxError { issue, .. } => issue.reason(),
+            FileOutcome::RewriteError { .. } => "rewrite_error",
+            FileOutcome::VerifyError { .. } => "verify_failed",
+            FileOutcome::WriteError { reason, .. } => reason,
+        }
+    }
+
+    fn renames(&self) -> usize {
+        match self {
+            FileOutcome::Ready(ready) => ready.renames,
+            _ => 0,
+        }
+    }
+}
+
+/// What the summary needs from a processed file once its contents have been dropped.
+struct ReadyFile {
+    status_kind: FinalStatusKind,
+    renames: usize,
+    skipped: Vec<SkippedFunction>,
+    mapping: Option<FileSourceMap>,
+    diff: Option<String>,
+    /// Unused imports dropped from the written output.
+    imports_removed: usize,
+    /// What each `--level` source transform did before planning.
+    stages: Vec<StageStats>,
+    /// Per-function detail for `--stats`.
+    functions: Option<Vec<FunctionStats>>,
+    /// Near-miss name collisions found under `--warn-collisions`.
+    warnings: Vec<NameCollision>,
+    /// Bytes saved by the written rewrite; zero unless minified.
+    bytes_saved: i64,
+    /// The source was decoded under `--decode lossy` with bytes replaced.
+    lossy_decode: bool,
+    /// Functions renamed at a range re-resolved under `--fuzzy-apply`.
+    fuzzy_matched: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FinalStatusKind {
+    Minified,
+    SkippedNoRenames,
+    SkippedNested,
+    SkippedDirective,
+    SkippedTooLarge,
+    SkippedRewriteAborted,
+    SkippedBackupExists,
+    /// The file changed on disk after it was read, so an in-place write would lose the edit.
+    SkippedChangedDuringRun,
+    /// Several bundle entries or files share the module under `--match-by module`.
+    SkippedAmbiguousPlan,
+    /// The same file was already selected under another path, e.g. through a symlink.
+    SkippedDuplicatePath,
+    /// Left unchanged under `--skip-parse-errors`.
+    SkippedSyntax(SyntaxIssue),
+    /// The plan passed `--max-renames-per-file` or `--max-plan-functions`.
+    SkippedPlanLimit(PlanLimit),
+}
+
+impl FinalStatusKind {
+    fn label(self) -> &'static str {
+        match self {
+            FinalStatusKind::Minified => "minified",
+            FinalStatusKind::SkippedNoRenames => "skipped (no renames)",
+            FinalStatusKind::SkippedNested => "skipped (nested scopes)",
+            FinalStatusKind::SkippedDirective => "skipped (directive)",
+            FinalStatusKind::SkippedTooLarge => "skipped (too large)",
+            FinalStatusKind::SkippedRewriteAborted => "skipped (rewrite aborted)",
+            FinalStatusKind::SkippedBackupExists => "skipped (backup exists)",
+            FinalStatusKind::SkippedChangedDuringRun => "skipped (changed during run)",
+            FinalStatusKind::SkippedAmbiguousPlan => "skipped (ambiguous plan)",
+            FinalStatusKind::SkippedDuplicatePath => "skipped (duplicate path)",
+            FinalStatusKind::SkippedSyntax(SyntaxIssue::Python2) => "skipped (Python 2 syntax)",
+            FinalStatusKind::SkippedSyntax(SyntaxIssue::Unsupported) => {
+                "skipped (unsupported syntax)"
+            }
+            FinalStatusKind::SkippedSyntax(SyntaxIssue::Invalid) => "skipped (syntax error)",
+            FinalStatusKind::SkippedPlanLimit(PlanLimit::Renames) => "skipped (too many renames)",
+            FinalStatusKind::SkippedPlanLimit(PlanLimit::Functions) => {
+                "skipped (too many functions)"
+            }
+        }
+    }
+
+    /// Machine-readable counterpart of [`label`](Self::label), as counted in `reasons`.
+    fn reason(self) -> &'static str {
+        match self {
+            FinalStatusKind::Minified => "minified",
+            FinalStatusKind::SkippedNoRenames => "no_renames",
+            FinalStatusKind::SkippedNested => "nested_scopes",
+            FinalStatusKind::SkippedDirective => "skip_directive",
+            FinalStatusKind::SkippedTooLarge => "too_large",
+            FinalStatusKind::SkippedRewriteAborted => "rewrite_aborted",
+            FinalStatusKind::SkippedBackupExists => "backup_exists",
+            FinalStatusKind::SkippedChangedDuringRun => "changed_during_run",
+            FinalStatusKind::SkippedAmbiguousPlan => "ambiguous_plan",
+            FinalStatusKind::SkippedDuplicatePath => "duplicate_path",
+            FinalStatusKind::SkippedSyntax(issue) => issue.reason(),
+            FinalStatusKind::SkippedPlanLimit(limit) => limit.reason(),
+        }
+    }
+
+    /// Statuses counted under `bailouts`.
+    const BAILOUTS: [FinalStatusKind; 5] = [
+        FinalStatusKind::SkippedNested,
+        FinalStatusKind::SkippedRewriteAborted,
+        FinalStatusKind::SkippedBackupExists,
+        FinalStatusKind::SkippedChangedDuringRun,
+        FinalStatusKind::SkippedAmbiguousPlan,
+    ];
+
+    fn is_bailout(self) -> bool {
+        Self::BAILOUTS.contains(&self)
+    }
+}
+
+/// Environment variable giving the worker count when `--jobs` is not passed.
+const JOBS_ENV: &str = "TSRS_JOBS";
+
+/// Rough peak memory of a file in flight per byte of source: the source, the rewritten
+/// text, and the AST.
+const MEMORY_PER_SOURCE_BYTE: u64 = 4;
+
+fn resolve_jobs(jobs: Option<usize>) -> anyhow::Result<usize> {
+    jobs_from(jobs, std::env::var(JOBS_ENV).ok().as_deref())
+}
+
+/// Worker count from `--jobs`, then the `TSRS_JOBS` value `env`, then the CPU count.
+fn jobs_from(jobs: Option<usize>, env: Option<&str>) -> anyhow::Result<usize> {
+    match jobs {
+        Some(0) => anyhow::bail!("--jobs must be at least 1"),
+        Some(value) => Ok(value),
+        None => match env.map(str::trim).filter(|value| !value.is_empty()) {
+            Some(value) => match value.parse() {
+                Ok(0) | Err(_) => {
+                    anyhow::bail!("{JOBS_ENV} must be a whole number of at least 1, got `{value}`")
+                }
+                Ok(jobs) => Ok(jobs),
+            },
+            None => Ok(std::cmp::max(1, num_cpus::get())),
+        },
+    }
+}
+
+/// Worker count for `candidates` after `--max-memory`, with the reason it is below `jobs`;
+/// both are logged at info level.
+fn effective_jobs(
+    jobs: usize,
+    candidates: &[Candidate],
+    max_memory_mb: u64,
+) -> (usize, Option<String>) {
+    let (effective, reason) = cap_jobs_by_memory(jobs, candidates, max_memory_mb);
+    match &reason {
+        Some(reason) => info!("Using {} of {} jobs: {}", effective, jobs, reason),
+        None => info!("Using {} jobs", effective),
+    }
+    (effective, reason)
+}
+
+/// Largest worker count up to `jobs` at which the largest candidates in flight fit in
+/// `max_memory_mb` megabytes, counting [`MEMORY_PER_SOURCE_BYTE`] per source byte, and
+/// never below 1. A budget of 0 leaves `jobs` alone.
+fn cap_jobs_by_memory(
+    jobs: usize,
+    candidates: &[Candidate],
+    max_memory_mb: u64,
+) -> (usize, Option<String>) {
+    const MB: u64 = 1024 * 1024;
+    if max_memory_mb == 0 {
+        return (jobs, None);
+    }
+    let budget = max_memory_mb.saturating_mul(MB);
+    let mut sizes: Vec<u64> = candidates.iter().map(|candidate| candidate.size).collect();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut in_flight = 0_u64;
+    for (fitting, size) in sizes.iter().take(jobs).enumerate() {
+        in_flight = in_flight.saturating_add(size.saturating_mul(MEMORY_PER_SOURCE_BYTE));
+        if in_flight > budget {
+            let needed = in_flight.div_ceil(MB);
+            let reason = if fitting == 0 {
+                format!(
+                    "the largest file needs about {needed} MB in flight, over --max-memory {max_memory_mb} MB"
+                )
+            } else {
+                format!(
+                    "{} jobs would need about {needed} MB for the largest files, over --max-memory {max_memory_mb} MB",
+                    fitting + 1
+                )
+            };
+            return (fitting.max(1), Some(reason));
+        }
+    }
+    (jobs, None)
+}
+
+fn execute_parallel_processing<T, F>(
+    candidates: &[Candidate],
+    jobs: usize,
+    processor: F,
+) -> anyhow::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(&Candidate) -> T + Sync,
+{
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if jobs <= 1 {
+        Ok(candidates
+            .iter()
+            .map(|candidate| processor(candidate))
+            .collect())
+    } else {
+        let pool = ThreadPoolBuilder::new().num_threads(jobs).build()?;
+        Ok(pool.install(|| {
+            candidates
+                .par_iter()
+                .map(|candidate| processor(candidate))
+                .collect()
+        }))
+    }
+}
+
+/// Apply each bundle entry to the candidate at its path, pulling entries from `plan_files`
+/// only as workers become free so just the plans in flight are held in memory.
+///
+/// Results come back in path order; entries without a matching candidate are ignored, and a
+/// path listed twice is only applied once.
+fn execute_streaming_processing<F>(
+    plan_files: PlanFileStream,
+    candidates: &HashMap<String, Candidate>,
+    jobs: usize,
+    progress: &ProgressStream,
+    processor: F,
+) -> anyhow::Result<(Vec<FileResult>, Vec<String>)>
+where
+    F: Fn(&Candidate, &MinifyPlan) -> FileResult + Sync,
+{
+    let claimed: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    let unmatched: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let process_entry = |entry: anyhow::Result<PlanFile>| -> anyhow::Result<Option<FileResult>> {
+        let file_plan = entry?;
+        let Some(candidate) = candidates.get(&file_plan.path) else {
+            unmatched
+                .lock()
+                .map_err(|_| anyhow::anyhow!("plan bundle worker panicked"))?
+                .push(file_plan.path);
+            return Ok(None);
+        };
+        let first = claimed
+            .lock()
+            .map_err(|_| anyhow::anyhow!("plan bundle worker panicked"))?
+            .insert(file_plan.path.clone());
+        if !first {
+            warn!(
+                "plan bundle lists {} more than once; using the first entry",
+                file_plan.path
+            );
+            return Ok(None);
+        }
+        Ok(Some(capture_file_result(progress, || {
+            processor(candidate, &file_plan.plan)
+        })))
+    };
+
+    let results: Vec<Option<FileResult>> = if jobs <= 1 {
+        plan_files
+            .map(&process_entry)
+            .collect::<anyhow::Result<_>>()?
+    } else {
+        let pool = ThreadPoolBuilder::new().num_threads(jobs).build()?;
+        pool.install(|| {
+            plan_files
+                .par_bridge()
+                .map(&process_entry)
+                .collect::<anyhow::Result<_>>()
+        })?
+    };
+
+    let mut results: Vec<FileResult> = results.into_iter().flatten().collect();
+    results.sort_by(|a, b| a.candidate.rel_norm.cmp(&b.candidate.rel_norm));
+    let mut unmatched = unmatched
+        .into_inner()
+        .map_err(|_| anyhow::anyhow!("plan bundle worker panicked"))?;
+    unmatched.sort_unstable();
+    unmatched.dedup();
+    Ok((results, unmatched))
+}
+
+/// Report a file the walk reached again under another path as skipped. Only the first
+/// path is read and written, so the two never race for the same output.
+fn skip_duplicate_path(duplicate: DuplicateCandidate, progress: &ProgressStream) -> FileResult {
+    capture_file_result(progress, || {
+        file_log!(
+            DEBUG,
+            "• {} → skipped (same file as {})",
+            duplicate.candidate.rel_norm,
+            duplicate.first
+        );
+        FileResult {
+            candidate: duplicate.candidate,
+            outcome: FileOutcome::Ready(Box::new(ReadyFile {
+                status_kind: FinalStatusKind::SkippedDuplicatePath,
+                renames: 0,
+                skipped: Vec::new(),
+                mapping: None,
+                diff: None,
+                imports_removed: 0,
+                stages: Vec::new(),
+                functions: None,
+                warnings: Vec::new(),
+                bytes_saved: 0,
+                lossy_decode: false,
+                fuzzy_matched: Vec::new(),
+            })),
+            logs: Vec::new(),
+            timing: FileTiming::default(),
+        }
+    })
+}
+
+/// Refuse to write a file whose output path differs from an earlier file's only in case,
+/// since one would overwrite the other on a case-insensitive filesystem.
+fn output_collision(
+    candidate: &Candidate,
+    collisions: &HashMap<String, String>,
+    resolved_out_dir: &Path,
+) -> Option<FileOutcome> {
+    let first = collisions.get(&candidate.rel_norm)?;
+    file_log!(
+        DEBUG,
+        "• {} → skipped (output collides with {})",
+        candidate.rel_norm,
+        first
+    );
+    Some(FileOutcome::WriteError {
+        reason: "output_collision",
+        message: format!(
+            "{} and {} differ only in case and would be written to the same file in {}",
+            first,
+            candidate.rel_norm,
+            resolved_out_dir.display()
+        ),
+    })
+}
+
+/// Skip files above `max_file_size` before reading them.
+///
+/// Oversized files are copied unchanged into the output tree so it stays complete; the copy
+/// is streamed by the OS and never held in memory.
+fn skip_oversized_file(
+    candidate: &Candidate,
+    max_file_size: Option<u64>,
+    resolved_out_dir: &Path,
+    in_place: bool,
+    dry_run: bool,
+) -> Option<FileOutcome> {
+    let limit = max_file_size?;
+    let size = fs::metadata(&candidate.abs_path).ok()?.len();
+    if size <= limit {
+        return None;
+    }
+    file_log!(
+        DEBUG,
+        "• {} → skipped (too large: {} > {} bytes)",
+        candidate.rel_norm,
+        size,
+        limit
+    );
+
+    if !dry_run && !in_place {
+        if let Err(outcome) = copy_unchanged(candidate, resolved_out_dir) {
+            return Some(*outcome);
+        }
+    }
+
+    Some(FileOutcome::Ready(Box::new(ReadyFile {
+        status_kind: FinalStatusKind::SkippedTooLarge,
+        renames: 0,
+        skipped: Vec::new(),
+        mapping: None,
+        diff: None,
+        imports_removed: 0,
+        stages: Vec::new(),
+        functions: None,
+        warnings: Vec::new(),
+        bytes_saved: 0,
+        lossy_decode: false,
+        fuzzy_matched: Vec::new(),
+    })))
+}
+
+/// Classify a failed import cleanup or plan, copying skipped files into the output tree.
+///
+/// See [`classify_plan_failure`]; a skipped file is copied through unchanged like an
+/// oversized one.
+fn plan_failure_outcome(
+    candidate: &Candidate,
+    err: &TsrsError,
+    source: &str,
+    skip_parse_errors: bool,
+    resolved_out_dir: &Path,
+    in_place: bool,
+    dry_run: bool,
+) -> FileOutcome {
+    let outcome = classify_plan_failure(candidate, err, source, skip_parse_errors);
+    if matches!(outcome, FileOutcome::Ready(_)) && !dry_run && !in_place {
+        if let Err(outcome) = copy_unchanged(candidate, resolved_out_dir) {
+            return *outcome;
+        }
+    }
+    outcome
+}
+
+/// Classify a failed import cleanup or plan.
+///
+/// Parse failures get a [`SyntaxIssue`] reason; with `skip_parse_errors` the file is skipped
+/// instead of counting as an error. Plans over a `--max-renames-per-file` or
+/// `--max-plan-functions` limit are always skipped.
+fn classify_plan_failure(
+    candidate: &Candidate,
+    err: &TsrsError,
+    source: &str,
+    skip_parse_errors: bool,
+) -> FileOutcome {
+    let status_kind = match err {
+        TsrsError::Parse { line, .. } => {
+            let issue = SyntaxIssue::classify(source, *line);
+            if !skip_parse_errors {
+                return FileOutcome::SyntaxError {
+                    issue,
+                    message: format!("{} at line {}", issue.describe(), line),
+                };
+            }
+            FinalStatusKind::SkippedSyntax(issue)
+        }
+        TsrsError::PlanLimit { limit, .. } => FinalStatusKind::SkippedPlanLimit(*limit),
+        _ => {
+            return FileOutcome::PlanError {
+                message: err.to_string(),
+            }
+        }
+    };
+    file_log!(DEBUG, "• {} → skipped ({})", candidate.rel_norm, err);
+
+    FileOutcome::Ready(Box::new(ReadyFile {
+        status_kind,
+        renames: 0,
+        skipped: Vec::new(),
+        mapping: None,
+        diff: None,
+        imports_removed: 0,
+        stages: Vec::new(),
+        functions: None,
+        warnings: Vec::new(),
+        bytes_saved: 0,
+        lossy_decode: false,
+        fuzzy_matched: Vec::new(),
+    }))
+}
+
+/// Copy a skipped file into the output tree as-is.
+fn copy_unchanged(candidate: &Candidate, resolved_out_dir: &Path) -> Result<(), Box<FileOutcome>> {
+    let target_path = resolved_out_dir.join(&candidate.rel_path);
+    create_parent_dir(&target_path)?;
+    fs::copy(&candidate.abs_path, &target_path).map_err(|err| {
+        Box::new(FileOutcome::WriteError {
+            reason: "write_failed",
+            message: format!("failed to write {}: {}", target_path.display(), err),
+        })
+    })?;
+    Ok(())
+}
+
+fn create_parent_dir(target_path: &Path) -> Result<(), Box<FileOutcome>> {
+    if let Some(parent) = target_path.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(Box::new(FileOutcome::WriteError {
+                reason: "mkdir_failed",
+                message: format!("failed to create directory {}: {}", parent.display(), err),
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Size and modification time of a file when it was read, to spot edits made before an
+/// in-place write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SourceStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl SourceStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// Write a processed file's output from inside the worker.
+///
+/// `original` and `rewritten` are consumed here so neither outlives the worker; only a
+/// rendered diff is kept when `diff` is set. With `verify`, output that fails
+/// [`verify_rewritten`] is never written. Files that are not rewritten are copied into
+/// `resolved_out_dir` byte for byte rather than re-encoded from `original`. An in-place
+/// write is skipped when the file no longer matches `read_stamp`, unless
+/// `overwrite_changed` is set.
+#[allow(clippy::too_many_arguments)]
+fn write_ready_file(
+    candidate: &Candidate,
+    original: String,
+    rewritten: Option<String>,
+    renames: usize,
+    skipped: Vec<SkippedFunction>,
+    mapping: Option<FileSourceMap>,
+    mut status_kind: FinalStatusKind,
+    metadata: &TextMetadata,
+    input_dir: &Path,
+    resolved_out_dir: &Path,
+    in_place: bool,
+    dry_run: bool,
+    backup_ext: Option<&str>,
+    diff: bool,
+    diff_context: usize,
+    verify: bool,
+    verify_exec: Option<&Path>,
+    read_stamp: Option<SourceStamp>,
+    overwrite_changed: bool,
+) -> FileOutcome {
+    if verify && status_kind == FinalStatusKind::Minified {
+        if let Some(ref content) = rewritten {
+            if let Err(message) =
+                verify_rewritten(&candidate.rel_path, content, metadata, verify_exec)
+            {
+                file_log!(DEBUG, "• {} → skipped (verify failed)", candidate.rel_norm);
+                return FileOutcome::VerifyError { message };
+            }
+        }
+    }
+
+    let mut applied_renames = renames;
+    let target_path = if in_place {
+        input_dir.join(&candidate.rel_path)
+    } else {
+        resolved_out_dir.join(&candidate.rel_path)
+    };
+
+    if !dry_run {
+        if in_place {
+            if status_kind == FinalStatusKind::Minified
+                && !overwrite_changed
+                && read_stamp.is_some_and(|stamp| SourceStamp::of(&target_path) != Some(stamp))
+            {
+                status_kind = FinalStatusKind::SkippedChangedDuringRun;
+                applied_renames = 0;
+                file_log!(
+                    ERROR,
+                    "{} changed on disk after it was read; left as is (pass --overwrite-changed to write anyway)",
+                    candidate.rel_norm
+                );
+            }
+            if status_kind == FinalStatusKind::Minified {
+                if let Some(ext) = backup_ext {
+                    let mut backup_os: OsString = target_path.as_os_str().to_os_string();
+                    backup_os.push(ext);
+                    let backup_path = PathBuf::from(backup_os);
+                    if backup_path.exists() {
+                        status_kind = FinalStatusKind::SkippedBackupExists;
+                        applied_renames = 0;
+                        file_log!(DEBUG, "• {} → skipped (backup exists)", candidate.rel_norm);
+                    } else if let Err(err) = fs::copy(&target_path, &backup_path) {
+                        file_log!(DEBUG, "• {} → skipped (backup failed)", candidate.rel_norm);
+                        return FileOutcome::WriteError {
+                            reason: "backup_failed",
+                            message: format!(
+                                "failed to write backup {}: {}",
+                                backup_path.display(),
+                                err
+                            ),
+                        };
+                    }
+                }
+
+                if status_kind == FinalStatusKind::Minified {
+                    if let Some(ref content) = rewritten {
+                        if let Err(err) = write_python(&target_path, content, metadata) {
+                            file_log!(DEBUG, "• {} → skipped (write failed)", candidate.rel_norm);
+                            return FileOutcome::WriteError {
+                                reason: "write_failed",
+                                message: format!(
+                                    "failed to write {}: {}",
+                                    target_path.display(),
+                                    err
+                                ),
+                            };
+                        }
+                    }
+                }
+            }
+        } else if let Some(content) = rewritten
+            .as_ref()
+            .filter(|_| status_kind == FinalStatusKind::Minified)
+        {
+            if let Err(outcome) = create_parent_dir(&target_path) {
+                file_log!(DEBUG, "• {} → skipped (mkdir failed)", candidate.rel_norm);
+                return *outcome;
+            }
+
+            if let Err(err) = write_python(&target_path, content, metadata) {
+                file_log!(DEBUG, "• {} → skipped (write failed)", candidate.rel_norm);
+                return FileOutcome::WriteError {
+                    reason: "write_failed",
+                    message: format!("failed to write {}: {}", target_path.display(), err),
+                };
+            }
+        } else if let Err(outcome) = copy_unchanged(candidate, resolved_out_dir) {
+            // Only rewritten text is re-encoded; anything else keeps its exact bytes.
+            file_log!(DEBUG, "• {} → skipped (copy failed)", candidate.rel_norm);
+            return *outcome;
+        }
+    }
+
+    let minified = rewritten
+        .as_ref()
+        .filter(|_| status_kind == FinalStatusKind::Minified);
+    let diff = if diff {
+        minified.map(|new_content| {
+            make_unified_diff(&candidate.rel_norm, &original, new_content, diff_context)
+        })
+    } else {
+        None
+    };
+    let saved = minified.map_or(0, |new_content| bytes_saved(&original, new_content));
+
+    FileOutcome::Ready(Box::new(ReadyFile {
+        status_kind,
+        renames: applied_renames,
+        skipped,
+        mapping,
+        diff,
+        imports_removed: 0,
+        stages: Vec::new(),
+        functions: None,
+        warnings: Vec::new(),
+        bytes_saved: saved,
+        lossy_decode: metadata.lossy,
+        fuzzy_matched: Vec::new(),
+    }))
+}
+
+/// Encode a processed archive entry from inside the worker.
+///
+/// The archive counterpart of [`write_ready_file`]: instead of writing to disk, the
+/// re-encoded bytes of a minified entry are returned for the caller to swap into the
+/// archive. Unchanged entries return `None` and keep their original bytes.
+#[allow(clippy::too_many_arguments)]
+fn encode_archive_entry(
+    candidate: &Candidate,
+    original: &str,
+    rewritten: Option<String>,
+    renames: usize,
+    skipped: Vec<SkippedFunction>,
+    mapping: Option<FileSourceMap>,
+    status_kind: FinalStatusKind,
+    metadata: &TextMetadata,
+    diff: bool,
+    diff_context: usize,
+    verify: bool,
+    verify_exec: Option<&Path>,
+) -> (FileOutcome, Option<Vec<u8>>) {
+    let rewritten = rewritten.filter(|_| status_kind == FinalStatusKind::Minified);
+    let mut bytes = None;
+    if let Some(content) = &rewritten {
+        if verify {
+            if let Err(message) =
+                verify_rewritten(&candidate.rel_path, content, metadata, verify_exec)
+            {
+                file_log!(DEBUG, "• {} → skipped (verify failed)", candidate.rel_norm);
+                return (FileOutcome::VerifyError { message }, None);
+            }
+        }
+        match encode_python(content, metadata, &candidate.rel_norm) {
+            Ok(encoded) => bytes = Some(encoded),
+            Err(err) => {
+                file_log!(DEBUG, "• {} → skipped (encode failed)", candidate.rel_norm);
+                let outcome = FileOutcome::WriteError {
+                    reason: "write_failed",
+                    message: format!("failed to encode {}: {}", candidate.rel_norm, err),
+                };
+                return (outcome, None);
+            }
+        }
+    }
+
+    let diff = if diff {
+        rewritten.as_ref().map(|new_content| {
+            make_unified_diff(&candidate.rel_norm, original, new_content, diff_context)
+        })
+    } else {
+        None
+    };
+
+    let outcome = FileOutcome::Ready(Box::new(ReadyFile {
+        status_kind,
+        renames,
+        skipped,
+        mapping,
+        diff,
+        imports_removed: 0,
+        stages: Vec::new(),
+        functions: None,
+        warnings: Vec::new(),
+        bytes_saved: rewritten
+            .as_ref()
+            .map_or(0, |new_content| bytes_saved(original, new_content)),
+        lossy_decode: metadata.lossy,
+        fuzzy_matched: Vec::new(),
+    }));
+    (outcome, bytes)
+}
+
+/// Fold worker results into `stats`, printing diffs when `print_diffs` is set and
+/// collecting them (keyed by relative path) into `diffs` for `--diff-out`.
+///
+/// Files are reported in path order, each with the messages its worker logged, so one
+/// file's output is never split up by another's.
+#[allow(clippy::too_many_arguments)]
+fn finalize_file_results(
+    mut results: Vec<FileResult>,
+    stats: &mut DirStats,
+    dry_run: bool,
+    quiet: bool,
+    show_stats: bool,
+    print_diffs: bool,
+    source_maps: &mut Vec<FileSourceMap>,
+    mut diffs: Option<&mut Vec<(String, String)>>,
+) {
+    results.sort_by(|a, b| a.candidate.rel_norm.cmp(&b.candidate.rel_norm));
+    for result in results {
+        let candidate = result.candidate;
+        let mut elapsed_ms = None;
+        if let Some(timings) = stats.timings.as_mut() {
+            timings.record_file(&candidate.rel_norm, result.timing);
+            elapsed_ms = Some(duration_ms(result.timing.elapsed));
+        }
+        for line in &result.logs {
+            line.emit();
+        }
+        match result.outcome {
+            FileOutcome::ReadError { message } => {
+                stats.errors += 1;
+                error!(
+                    "failed to read {}: {}",
+                    candidate.abs_path.display(),
+                    message
+                );
+                bump_reason(stats, "read_error");
+            }
+            FileOutcome::PlanError { message } => {
+                stats.errors += 1;
+                error!(
+                    "failed to plan {}: {}",
+                    candidate.abs_path.display(),
+                    message
+                );
+                bump_reason(stats, "plan_error");
+            }
+            FileOutcome::SyntaxError { issue, message } => {
+                stats.errors += 1;
+                error!(
+                    "failed to parse {}: {}",
+                    candidate.abs_path.display(),
+                    message
+                );
+                bump_reason(stats, issue.reason());
+            }
+            FileOutcome::RewriteError { message } => {
+                stats.errors += 1;
+                error!(
+                    "failed to rewrite {}: {}",
+                    candidate.abs_path.display(),
+                    message
+                );
+                debug!("• {} → skipped (rewrite error)", candidate.rel_norm);
+                bump_reason(stats, "rewrite_error");
+            }
+            FileOutcome::VerifyError { message } => {
+                stats.errors += 1;
+                stats.verify_failures += 1;
+                error!(
+                    "verification failed for {}: {}",
+                    candidate.abs_path.display(),
+                    message
+                );
+                bump_reason(stats, "verify_failed");
+            }
+            FileOutcome::WriteError { reason, message } => {
+                stats.errors += 1;
+                error!("{}", message);
+                bump_reason(stats, reason);
+            }
+            FileOutcome::Ready(ready) => {
+                let status_kind = ready.status_kind;
+                let mapping = ready.mapping;
+                let diff = match (ready.diff, diffs.as_mut()) {
+                    (Some(diff), Some(collected)) => {
+                        let printed = print_diffs.then(|| diff.clone());
+                        collected.push((candidate.rel_norm.clone(), diff));
+                        printed
+                    }
+                    (diff, _) => diff.filter(|_| print_diffs),
+                };
+                let mut dead_assignment_bytes = 0;
+                if status_kind == FinalStatusKind::Minified {
+                    stats.imports_removed += ready.imports_removed;
+                    merge_stage_stats(&mut stats.stages, &ready.stages);
+                    dead_assignment_bytes = ready
+                        .stages
+                        .iter()
+                        .find(|stage| stage.stage == RemoveDeadAssignments::NAME)
+                        .map_or(0, |stage| stage.bytes_saved);
+                }
+                record_ready_file(
+                    &candidate,
+                    status_kind,
+                    ready.renames,
+                    ready.bytes_saved,
+                    ready.skipped,
+                    ready.functions,
+                    ready.warnings,
+                    ready.lossy_decode,
+                    ready.fuzzy_matched,
+                    elapsed_ms,
+                    dead_assignment_bytes,
+                    diff,
+                    stats,
+                    quiet,
+                    show_stats,
+                );
+                if status_kind == FinalStatusKind::Minified && !dry_run {
+                    source_maps.extend(mapping);
+                }
+            }
+        }
+    }
+    stats.files.sort_by(|a, b| a.path.cmp(&b.path));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn record_ready_file(
+    candidate: &Candidate,
+    status_kind: FinalStatusKind,
+    applied_renames: usize,
+    bytes_saved: i64,
+    skipped_functions: Vec<SkippedFunction>,
+    functions: Option<Vec<FunctionStats>>,
+    warnings: Vec<NameCollision>,
+    lossy_decode: bool,
+    fuzzy_matched: Vec<String>,
+    elapsed_ms: Option<u64>,
+    dead_assignment_bytes: usize,
+    diff: Option<String>,
+    stats: &mut DirStats,
+    quiet: bool,
+    show_stats: bool,
+) {
+    match status_kind {
+        FinalStatusKind::Minified => {
+            stats.rewritten += 1;
+            stats.total_renames += applied_renames;
+            bump_reason(stats, "minified");
+        }
+        FinalStatusKind::SkippedNoRenames => {
+            stats.skipped_no_change += 1;
+            bump_reason(stats, "no_renames");
+        }
+        FinalStatusKind::SkippedDirective => {
+            stats.skipped_no_change += 1;
+            bump_reason(stats, "skip_directive");
+        }
+        FinalStatusKind::SkippedTooLarge => {
+            stats.skipped_no_change += 1;
+            bump_reason(stats, "too_large");
+        }
+        FinalStatusKind::SkippedDuplicatePath => {
+            stats.skipped_no_change += 1;
+            bump_reason(stats, "duplicate_path");
+        }
+        FinalStatusKind::SkippedSyntax(issue) => {
+            stats.skipped_no_change += 1;
+            bump_reason(stats, issue.reason());
+        }
+        FinalStatusKind::SkippedPlanLimit(limit) => {
+            stats.skipped_no_change += 1;
+            bump_reason(stats, limit.reason());
+        }
+        _ => {
+            if status_kind.is_bailout() {
+                stats.bailouts += 1;
+            }
+            let reason = match status_kind {
+                FinalStatusKind::SkippedNested => "nested_scopes",
+                FinalStatusKind::SkippedRewriteAborted => "rewrite_aborted",
+                FinalStatusKind::SkippedBackupExists => "backup_exists",
+                FinalStatusKind::SkippedChangedDuringRun => "changed_during_run",
+                FinalStatusKind::SkippedAmbiguousPlan => "ambiguous_plan",
+                _ => "unknown",
+            };
+            if reason != "unknown" {
+                bump_reason(stats, reason);
+            }
+            if status_kind.is_bailout() && reason != "unknown" {
+                stats.findings.push(tsrs::SarifFinding {
+                    rule_id: bailout_rule(reason),
+                    message: format!("`{}` was not minified: {}", candidate.rel_norm, reason),
+                    location: Some(tsrs::FindingLocation {
+                        file: candidate.rel_norm.clone(),
+                        line: 0,
+                        column: 0,
+                    }),
+                    properties: None,
+                });
+            }
+        }
+    }
+
+    if lossy_decode {
+        bump_reason(stats, "lossy_decode");
+    }
+    if !fuzzy_matched.is_empty() {
+        bump_reason(stats, "fuzzy_matched");
+    }
+
+    let skipped_functions = record_skipped_functions(stats, &candidate.rel_norm, skipped_functions);
+    if show_stats {
+        let annotation_bytes = annotation_bytes(functions.as_deref());
+        stats.files.push(FileStats {
+            path: candidate.rel_norm.clone(),
+            renames: applied_renames,
+            status: status_kind.label().to_string(),
+            bytes_saved,
+            skipped_functions,
+            functions,
+            warnings,
+            lossy_decode,
+            fuzzy_matched,
+            elapsed_ms,
+            dead_assignment_bytes,
+            annotation_bytes,
+        });
+    }
+
+    if let Some(diff_str) = diff {
+        if !quiet {
+            println!("{}", diff_str);
+        }
+    }
+
+    print_file_status(
+        &candidate.rel_norm,
+        status_kind.label(),
+        applied_renames,
+        show_stats,
+        quiet,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result as AnyResult;
+    use assert_cmd::Command;
+    use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+    use serde_json;
+    #[cfg(unix)]
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::PathBuf;
+    use std::process::Command as StdCommand;
+    use tempfile::tempdir;
+    use tsrs::CollisionKind;
+
+    #[derive(Clone)]
+    struct MinifyDirTestCfg {
+        in_place: bool,
+        dry_run: bool,
+        show_stats: bool,
+        json_output: bool,
+        include_file: Option<PathBuf>,
+        include_hidden: bool,
+        follow_symlinks: bool,
+        glob_case_insensitive: Option<bool>,
+        quiet: bool,
+        output_json: Option<PathBuf>,
+        jobs: Option<usize>,
+        fail_on_bailout: bool,
+        fail_on_error: bool,
+        fail_on_change: bool,
+        diff: bool,
+        diff_context: usize,
+        max_depth: Option<usize>,
+        exclude_file: Option<PathBuf>,
+        respect_gitignore: bool,
+        max_file_size: Option<u64>,
+        diff_out: Option<PathBuf>,
+        verify: bool,
+        verify_exec: Option<PathBuf>,
+        remove_unused_imports: bool,
+        remove_dead_assignments: bool,
+        skip_parse_errors: bool,
+        out_dir_mode: OutDirMode,
+    }
+
+    impl Default for MinifyDirTestCfg {
+        fn default() -> Self {
+            Self {
+                in_place: false,
+                dry_run: false,
+                show_stats: false,
+                json_output: false,
+                include_file: None,
+                include_hidden: false,
+                follow_symlinks: false,
+                glob_case_insensitive: None,
+                quiet: false,
+                output_json: None,
+                jobs: None,
+                fail_on_bailout: false,
+                fail_on_error: false,
+                fail_on_change: false,
+                diff: false,
+                diff_context: 3,
+                max_depth: None,
+                exclude_file: None,
+                respect_gitignore: false,
+                max_file_size: None,
+                diff_out: None,
+                verify: false,
+                verify_exec: None,
+                remove_unused_imports: false,
+                remove_dead_assignments: false,
+                skip_parse_errors: true,
+                out_dir_mode: OutDirMode::Fresh,
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct ApplyPlanDirTestCfg {
+        in_place: bool,
+        dry_run: bool,
+        show_stats: bool,
+        json_output: bool,
+        include_file: Option<PathBuf>,
+        include_hidden: bool,
+        follow_symlinks: bool,
+        glob_case_insensitive: Option<bool>,
+        quiet: bool,
+        output_json: Option<PathBuf>,
+        jobs: Option<usize>,
+        fail_on_bailout: bool,
+        fail_on_error: bool,
+        fail_on_change: bool,
+        diff: bool,
+        diff_context: usize,
+        max_depth: Option<usize>,
+        exclude_file: Option<PathBuf>,
+        respect_gitignore: bool,
+        max_file_size: Option<u64>,
+        diff_out: Option<PathBuf>,
+        verify: bool,
+        verify_exec: Option<PathBuf>,
+        allow_partial_plan: bool,
+        match_by_module: bool,
+    }
+
+    impl Default for ApplyPlanDirTestCfg {
+        fn default() -> Self {
+            Self {
+                in_place: false,
+                dry_run: false,
+                show_stats: false,
+                json_output: false,
+                include_file: None,
+                include_hidden: false,
+                follow_symlinks: false,
+                glob_case_insensitive: None,
+                quiet: false,
+                output_json: None,
+                jobs: None,
+                fail_on_bailout: false,
+                fail_on_error: false,
+                fail_on_change: false,
+                diff: false,
+                diff_context: 3,
+                max_depth: None,
+                exclude_file: None,
+                respect_gitignore: false,
+                max_file_size: None,
+                diff_out: None,
+                verify: false,
+                verify_exec: None,
+                allow_partial_plan: false,
+                match_by_module: false,
+            }
+        }
+    }
+
+    fn run_minify_dir(
+        input_dir: &Path,
+        out_dir: Option<PathBuf>,
+        includes: &[String],
+        excludes: &[String],
+        backup_ext: Option<&str>,
+        cfg: MinifyDirTestCfg,
+    ) -> AnyResult<DirStats> {
+        minify_dir_with_depth(
+            &input_dir.to_path_buf(),
+            out_dir,
+            includes,
+            cfg.include_file.as_ref(),
+            excludes,
+            cfg.exclude_file.as_ref(),
+            backup_ext,
+            cfg.in_place,
+            cfg.dry_run,
+            cfg.show_stats,
+            cfg.json_output,
+            cfg.include_hidden,
+            cfg.follow_symlinks,
+            cfg.glob_case_insensitive,
+            cfg.quiet,
+            cfg.output_json.as_deref(),
+            cfg.jobs,
+            cfg.fail_on_bailout,
+            cfg.fail_on_error,
+            cfg.fail_on_change,
+            cfg.diff,
+            cfg.diff_context,
+            cfg.respect_gitignore,
+            cfg.max_depth,
+            false,
+            false,
+            false,
+            false,
+            false,
+            cfg.max_file_size,
+            &[],
+            false,
+            None,
+            false,
+            None,
+            None,
+            cfg.diff_out.as_deref(),
+            cfg.verify,
+            cfg.verify_exec.as_deref(),
+            &LevelSettings {
+                remove_unused_imports: cfg.remove_unused_imports,
+                ..LevelSettings::default()
+            }
+            .pipeline(),
+            cfg.remove_dead_assignments,
+            cfg.skip_parse_errors,
+            None,
+            None,
+            None,
+            false,
+            cfg.out_dir_mode,
+            None,
+            &CommandContext::default(),
+        )
+    }
+
+    fn run_watch_pass(
+        input_dir: &Path,
+        out_dir: &Path,
+        changed: &HashSet<PathBuf>,
+    ) -> AnyResult<DirStats> {
+        minify_dir_with_depth(
+            &input_dir.to_path_buf(),
+            Some(out_dir.to_path_buf()),
+            &[],
+            None,
+            &[],
+            None,
+            None,
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+            None,
+            true,
+            None,
+            Some(1),
+            false,
+            false,
+            false,
+            false,
+            3,
+            false,
+            None,
+            false,
+            false,
+            false,
+            false,
+            false,
+            None,
+            &[],
+            false,
+            None,
+            false,
+            None,
+            None,
+            None,
+            false,
+            None,
+            &TransformPipeline::new(),
+            false,
+            true,
+            None,
+            None,
+            None,
+            false,
+            OutDirMode::Fresh,
+            Some(changed),
+            &CommandContext::default(),
+        )
+    }
+
+    fn run_apply_plan_dir(
+        input_dir: &Path,
+        plan_path: &Path,
+        out_dir: Option<PathBuf>,
+        includes: &[String],
+        excludes: &[String],
+        backup_ext: Option<&str>,
+        cfg: ApplyPlanDirTestCfg,
+    ) -> AnyResult<DirStats> {
+        apply_plan_dir_with_depth(
+            &input_dir.to_path_buf(),
+            &plan_path.to_path_buf(),
+            out_dir,
+            includes,
+            cfg.include_file.as_ref(),
+            excludes,
+            cfg.exclude_file.as_ref(),
+            backup_ext,
+            cfg.in_place,
+            cfg.dry_run,
+            cfg.show_stats,
+            cfg.json_output,
+            cfg.include_hidden,
+            cfg.follow_symlinks,
+            cfg.glob_case_insensitive,
+            cfg.quiet,
+            cfg.output_json.as_deref(),
+            cfg.jobs,
+            cfg.fail_on_bailout,
+            cfg.fail_on_error,
+            cfg.fail_on_change,
+            cfg.diff,
+            cfg.diff_context,
+            cfg.respect_gitignore,
+            cfg.max_depth,
+            cfg.max_file_size,
+            &[],
+            false,
+            cfg.diff_out.as_deref(),
+            None,
+            cfg.verify,
+            cfg.verify_exec.as_deref(),
+            cfg.allow_partial_plan,
+            false,
+            None,
+            None,
+            None,
+            false,
+            cfg.match_by_module,
+            &CommandContext::default(),
+        )
+    }
+
+    fn create_nested_fixture(base: &Path) -> AnyResult<()> {
+        fs::create_dir_all(base)?;
+        fs::write(base.join("root.py"), "def root():\n    return 1\n")?;
+        let level1 = base.join("level1");
+        fs::create_dir_all(&level1)?;
+        fs::write(level1.join("inner.py"), "def inner():\n    return 2\n")?;
+        let level2 = level1.join("level2");
+        fs::create_dir_all(&level2)?;
+        fs::write(level2.join("deep.py"), "def deep():\n    return 3\n")?;
+        Ok(())
+    }
+
+    /// Load a plan bundle of any version whole, for assertions.
+    fn read_plan_bundle(path: &Path) -> AnyResult<PlanBundle> {
+        let (header, files) = open_plan_bundle(path)?;
+        Ok(PlanBundle {
+            version: header.version,
+            extensions: header.extensions,
+            extra: header.extra,
+            files: files.collect::<AnyResult<_>>()?,
+        })
+    }
+
+    fn cli_cmd() -> AnyResult<Command> {
+        Ok(Command::from_std(StdCommand::new(cli_binary_path())))
+    }
+
+    fn cli_binary_path() -> PathBuf {
+        if let Some(path) = std::env::var_os("CARGO_BIN_EXE_tsrs-cli") {
+            return PathBuf::from(path);
+        }
+
+        let mut target_dir = std::env::var_os("CARGO_TARGET_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target"));
+
+        let profile = std::env::var("PROFILE").unwrap_or_else(|_| "debug".to_string());
+        target_dir.push(profile);
+        let binary = if cfg!(windows) {
+            "tsrs-cli.exe"
+        } else {
+            "tsrs-cli"
+        };
+        target_dir.push(binary);
+        target_dir
+    }
+
+    #[test]
+    fn unified_diff_smoke() {
+        let diff = make_unified_diff("example.py", "a = 1\n", "a = 2\n", 3);
+        assert!(diff.contains("a/example.py"));
+        assert!(diff.contains("b/example.py"));
+        assert!(diff.contains("-a = 1"));
+        assert!(diff.contains("+a = 2"));
+    }
+
+    #[test]
+    fn unified_diff_context_zero() {
+        let diff = make_unified_diff("example.py", "a = 1\nprint(a)\n", "a = 2\nprint(a)\n", 0);
+        assert!(diff.contains("@@"));
+        let context_lines = diff.lines().filter(|line| line.starts_with(' ')).count();
+        assert_eq!(context_lines, 0, "unexpected context lines: {diff}");
+    }
+
+    #[test]
+    fn minify_dir_diff_context_one_outputs_expected() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\nprint(\"done\")\n",
+        )?;
+
+        let out_dir = tmp.path().join("out");
+
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .arg("--diff")
+            .arg("--diff-context")
+            .arg("1")
+            .arg("--dry-run")
+            .output()?;
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        let context_lines = stdout.lines().filter(|line| line.starts_with(' ')).count();
+        assert_eq!(context_lines, 1, "unexpected context lines: {stdout}");
+        Ok(())
+    }
+
+    /// Minimal `git apply`: apply each file's hunks under `root`, failing when a context or
+    /// removed line does not match the file being patched.
+    fn apply_unified_patch(root: &Path, patch: &str) -> AnyResult<()> {
+        let mut lines = patch.lines().peekable();
+        while let Some(line) = lines.next() {
+            let Some(old) = line.strip_prefix("--- a/") else {
+                anyhow::ensure!(line.is_empty(), "unexpected patch line: {line}");
+                continue;
+            };
+            let new = lines
+                .next()
+                .and_then(|line| line.strip_prefix("+++ b/"))
+                .ok_or_else(|| anyhow::anyhow!("missing +++ header for {old}"))?;
+            anyhow::ensure!(old == new, "unexpected rename {old} -> {new}");
+
+            let path = root.join(old);
+            let original = fs::read_to_string(&path)?;
+            let source: Vec<&str> = original.lines().collect();
+            let mut patched: Vec<&str> = Vec::new();
+            let mut cursor = 0;
+            while let Some(range) = lines.peek().and_then(|line| line.strip_prefix("@@ -")) {
+                let start: usize = range.split([',', ' ']).next().unwrap_or("1").parse()?;
+                lines.next();
+                let start = start.saturating_sub(1);
+                patched.extend(&source[cursor..start]);
+                cursor = start;
+                while let Some(&hunk_line) = lines.peek() {
+                    if hunk_line.starts_with("@@") || hunk_line.starts_with("--- a/") {
+                        break;
+                    }
+                    if let Some(context) = hunk_line.strip_prefix(' ') {
+                        anyhow::ensure!(
+                            source.get(cursor) == Some(&context),
+                            "context mismatch in {old}"
+                        );
+                        patched.push(context);
+                        cursor += 1;
+                    } else if let Some(removed) = hunk_line.strip_prefix('-') {
+                        anyhow::ensure!(
+                            source.get(cursor) == Some(&removed),
+                            "removal mismatch in {old}"
+                        );
+                        cursor += 1;
+                    } else if let Some(added) = hunk_line.strip_prefix('+') {
+                        patched.push(added);
+                    } else if !hunk_line.starts_with('\\') {
+                        break;
+                    }
+                    lines.next();
+                }
+            }
+            patched.extend(&source[cursor..]);
+            fs::write(&path, format!("{}\n", patched.join("\n")))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_diff_out_bundle_applies_to_input_tree() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(
+            input_dir.join("pkg").join("beta.py"),
+            "def scale(value, factor):\n    result = value * factor\n    return result\n",
+        )?;
+        fs::write(
+            input_dir.join("alpha.py"),
+            "import os\n\n\ndef total(items):\n    running = 0\n    for item in items:\n        running += item\n    return running\n",
+        )?;
+        fs::write(input_dir.join("constants.py"), "LIMIT = 10\n")?;
+
+        let patch_path = tmp.path().join("patch.diff");
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(&input_dir)
+            .arg("--dry-run")
+            .arg("--diff-out")
+            .arg(&patch_path)
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(
+            stdout.contains(&format!("Diffs: {}", patch_path.display())),
+            "summary should name the diff output: {stdout}"
+        );
+        assert!(
+            !stdout.contains("+++ b/"),
+            "diffs should not be printed: {stdout}"
+        );
+
+        let patch = fs::read_to_string(&patch_path)?;
+        let headers: Vec<&str> = patch
+            .lines()
+            .filter(|line| line.starts_with("--- a/"))
+            .collect();
+        assert_eq!(headers, vec!["--- a/alpha.py", "--- a/pkg/beta.py"]);
+
+        let out_dir = tmp.path().join("out");
+        cli_cmd()?
+            .arg("minify-dir")
+            .arg(&input_dir)
+            .arg("--out-dir")
+            .arg(&out_dir)
+            .assert()
+            .success();
+
+        apply_unified_patch(&input_dir, &patch)?;
+        for rel in ["alpha.py", "pkg/beta.py", "constants.py"] {
+            assert_eq!(
+                fs::read_to_string(input_dir.join(rel))?,
+                fs::read_to_string(out_dir.join(rel))?,
+                "patched {rel} differs from --out-dir output"
+            );
+        }
+
+        let patch_dir = tmp.path().join("patches");
+        let cfg = MinifyDirTestCfg {
+            dry_run: true,
+            quiet: true,
+            diff_out: Some(patch_dir.clone()),
+            ..Default::default()
+        };
+        fs::write(
+            input_dir.join("alpha.py"),
+            "def total(items):\n    running = sum(items)\n    return running\n",
+        )?;
+        run_minify_dir(&input_dir, None, &[], &[], None, cfg)?;
+        assert!(patch_dir.join("alpha.py.patch").is_file());
+        assert!(!patch_dir.join("constants.py.patch").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_preserves_structure() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("input");
+        let nested = input_dir.join("pkg");
+        fs::create_dir_all(&nested)?;
+
+        let module_source = "\
+def sample(value):
+    temp = value + 1
+    return temp
+";
+        fs::write(input_dir.join("module.py"), module_source)?;
+        fs::write(nested.join("__init__.py"), "")?;
+
+        let output_dir = tmp.path().join("output");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let _stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        let rewritten = fs::read_to_string(output_dir.join("module.py"))?;
+        assert!(rewritten.contains("def sample(a):"));
+        assert!(output_dir.join("pkg/__init__.py").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_merge_out_dir_updates_only_the_edited_output() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("a.py"),
+            "def foo(x):\n    y = x + 1\n    return y\n",
+        )?;
+        fs::write(
+            input_dir.join("b.py"),
+            "def bar(y):\n    z = y - 1\n    return z\n",
+        )?;
+        let output_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &[],
+            &[],
+            None,
+            cfg.clone(),
+        )?;
+        let b_before = fs::read_to_string(output_dir.join("b.py"))?;
+        fs::write(output_dir.join("notes.txt"), "kept\n")?;
+
+        fs::write(
+            input_dir.join("a.py"),
+            "def foo(x):\n    y = x * 2\n    return y\n",
+        )?;
+        let err = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &[],
+            &[],
+            None,
+            cfg.clone(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("already exists and is not empty"));
+
+        let stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &[],
+            &[],
+            None,
+            MinifyDirTestCfg {
+                out_dir_mode: OutDirMode::Merge,
+                ..cfg
+            },
+        )?;
+        assert!(fs::read_to_string(output_dir.join("a.py"))?.contains("a * 2"));
+        assert_eq!(fs::read_to_string(output_dir.join("b.py"))?, b_before);
+        assert_eq!(fs::read_to_string(output_dir.join("notes.txt"))?, "kept\n");
+        let changes = stats.out_dir.expect("merge runs report output changes");
+        assert_eq!(
+            (changes.created, changes.overwritten, changes.pruned),
+            (0, 2, 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_prune_out_dir_removes_stale_outputs_and_reports_foreign_files() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(input_dir.join("a.py"), "def foo(x):\n    return x\n")?;
+        fs::write(input_dir.join("pkg/gone.py"), "def bar(y):\n    return y\n")?;
+        let output_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &[],
+            &[],
+            None,
+            cfg.clone(),
+        )?;
+        assert!(output_dir.join("pkg/gone.py").exists());
+        fs::remove_file(input_dir.join("pkg/gone.py"))?;
+        fs::write(output_dir.join("notes.txt"), "parked\n")?;
+        fs::write(input_dir.join("new.py"), "VALUE = 1\n")?;
+
+        let prune = |force| {
+            run_minify_dir(
+                &input_dir,
+                Some(output_dir.clone()),
+                &[],
+                &[],
+                None,
+                MinifyDirTestCfg {
+                    out_dir_mode: OutDirMode::Prune { force },
+                    ..cfg.clone()
+                },
+            )
+        };
+        let changes = prune(false)?
+            .out_dir
+            .expect("prune runs report output changes");
+        assert!(!output_dir.join("pkg").exists());
+        assert!(output_dir.join("notes.txt").exists());
+        assert!(output_dir.join("new.py").exists());
+        assert_eq!(
+            (changes.created, changes.overwritten, changes.pruned),
+            (1, 1, 1)
+        );
+        assert_eq!(changes.foreign, vec!["notes.txt".to_string()]);
+
+        let changes = prune(true)?
+            .out_dir
+            .expect("prune runs report output changes");
+        assert!(!output_dir.join("notes.txt").exists());
+        assert_eq!(changes.pruned, 1);
+        assert!(changes.foreign.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_respects_include_exclude() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        let pkg_a = input_dir.join("pkg_a");
+        let pkg_b = input_dir.join("pkg_b");
+        fs::create_dir_all(&pkg_a)?;
+        fs::create_dir_all(&pkg_b)?;
+
+        fs::write(
+            pkg_a.join("mod.py"),
+            "def foo(x):\n    y = x + 1\n    return y\n",
+        )?;
+        fs::write(
+            pkg_b.join("mod.py"),
+            "def bar(y):\n    z = y - 1\n    return z\n",
+        )?;
+
+        let output_dir = tmp.path().join("out");
+        let includes = vec!["pkg_a/**".to_string()];
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let _stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        assert!(output_dir.join("pkg_a/mod.py").exists());
+        assert!(!output_dir.join("pkg_b/mod.py").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_dry_run_creates_no_output() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def foo(x):\n    y = x + 1\n    return y\n",
+        )?;
+
+        let output_dir = tmp.path().join("out");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            dry_run: true,
+            show_stats: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let _stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        assert!(!output_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_in_place_updates_files() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let file_path = input_dir.join("example.py");
+        fs::write(
+            &file_path,
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            in_place: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let _stats = run_minify_dir(&input_dir, None, &includes, &excludes, None, cfg)?;
+
+        let rewritten = fs::read_to_string(&file_path)?;
+        assert!(rewritten.contains("def foo(a):"));
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_in_place_writes_backup() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let file_path = input_dir.join("example.py");
+        let original = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(&file_path, original)?;
+
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            in_place: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let _stats = run_minify_dir(&input_dir, None, &includes, &excludes, Some(".bak"), cfg)?;
+
+        let rewritten = fs::read_to_string(&file_path)?;
+        assert!(rewritten.contains("def foo(a):"));
+
+        let backup_path = input_dir.join("example.py.bak");
+        assert!(backup_path.exists());
+        let backup_contents = fs::read_to_string(backup_path)?;
+        assert_eq!(backup_contents, original);
+
+        Ok(())
+    }
+
+    /// Write `rewritten` over `edited.py` in place after the file was saved again since
+    /// it was read, as an editor would mid-run.
+    fn write_after_concurrent_edit(
+        dir: &Path,
+        saved: &str,
+        overwrite_changed: bool,
+    ) -> AnyResult<FileOutcome> {
+        let path = dir.join("edited.py");
+        fs::write(&path, "def foo(value):\n    return value\n")?;
+        let (original, metadata) = read_python(&path, DecodeOptions::default())?;
+        let read_stamp = SourceStamp::of(&path);
+        fs::write(&path, saved)?;
+        let candidate = Candidate {
+            abs_path: path.clone(),
+            rel_path: PathBuf::from("edited.py"),
+            rel_norm: "edited.py".to_string(),
+            size: original.len() as u64,
+        };
+        Ok(write_ready_file(
+            &candidate,
+            original,
+            Some("def foo(a):\n    return a\n".to_string()),
+            1,
+            Vec::new(),
+            None,
+            FinalStatusKind::Minified,
+            &metadata,
+            dir,
+            dir,
+            true,
+            false,
+            None,
+            false,
+            3,
+            false,
+            None,
+            read_stamp,
+            overwrite_changed,
+        ))
+    }
+
+    #[test]
+    fn in_place_write_leaves_files_edited_mid_run() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let saved = "def foo(value):\n    return value + 2  # saved mid-run\n";
+        let outcome = write_after_concurrent_edit(tmp.path(), saved, false)?;
+
+        let FileOutcome::Ready(ready) = outcome else {
+            panic!("expected a ready file");
+        };
+        assert_eq!(ready.status_kind, FinalStatusKind::SkippedChangedDuringRun);
+        assert_eq!(fs::read_to_string(tmp.path().join("edited.py"))?, saved);
+        Ok(())
+    }
+
+    #[test]
+    fn overwrite_changed_writes_files_edited_mid_run() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let saved = "def foo(value):\n    return value + 2  # saved mid-run\n";
+        let outcome = write_after_concurrent_edit(tmp.path(), saved, true)?;
+
+        let FileOutcome::Ready(ready) = outcome else {
+            panic!("expected a ready file");
+        };
+        assert_eq!(ready.status_kind, FinalStatusKind::Minified);
+        assert!(fs::read_to_string(tmp.path().join("edited.py"))?.contains("def foo(a):"));
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_stats_json_runs() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def foo(x):\n    y = x + 1\n    return y\n",
+        )?;
+
+        let output_dir = tmp.path().join("out");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            dry_run: true,
+            show_stats: true,
+            json_output: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let _stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_skips_hidden_by_default() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join(".hidden.py"),
+            "def foo(x):\n    y = x + 1\n    return y\n",
+        )?;
+
+        let output_dir = tmp.path().join("out");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let _stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        assert!(!output_dir.join(".hidden.py").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_includes_hidden_when_requested() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join(".hidden.py"),
+            "def foo(x):\n    y = x + 1\n    return y\n",
+        )?;
+
+        let output_dir = tmp.path().join("out");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            include_hidden: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let _stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        assert!(output_dir.join(".hidden.py").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_respects_max_depth() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        create_nested_fixture(&input_dir)?;
+
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+
+        let cfg_depth1 = MinifyDirTestCfg {
+            quiet: true,
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        let stats_depth1 = run_minify_dir(
+            &input_dir,
+            Some(tmp.path().join("min-out-depth1")),
+            &includes,
+            &excludes,
+            None,
+            cfg_depth1,
+        )?;
+        assert_eq!(stats_depth1.processed, 1);
+
+        let cfg_depth2 = MinifyDirTestCfg {
+            quiet: true,
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let stats_depth2 = run_minify_dir(
+            &input_dir,
+            Some(tmp.path().join("min-out-depth2")),
+            &includes,
+            &excludes,
+            None,
+            cfg_depth2,
+        )?;
+        assert_eq!(stats_depth2.processed, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_respects_gitignore() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(input_dir.join(".gitignore"), "alpha.py\n")?;
+        fs::write(
+            input_dir.join("alpha.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+        fs::write(
+            input_dir.join("beta.py"),
+            "def bar(value):\n    temp = value + 2\n    return temp\n",
+        )?;
+
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+
+        let cfg_all = MinifyDirTestCfg {
+            in_place: true,
+            dry_run: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let stats_all = run_minify_dir(&input_dir, None, &includes, &excludes, None, cfg_all)?;
+        assert_eq!(stats_all.processed, 2);
+
+        let cfg_respect = MinifyDirTestCfg {
+            in_place: true,
+            dry_run: true,
+            quiet: true,
+            respect_gitignore: true,
+            ..Default::default()
+        };
+        let stats_respected =
+            run_minify_dir(&input_dir, None, &includes, &excludes, None, cfg_respect)?;
+        assert_eq!(stats_respected.processed, 1);
+        assert_eq!(stats_respected.rewritten, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_include_exclude_precedence_exclude_wins() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(input_dir.join("alpha.py"), "def foo():\n    return 1\n")?;
+        fs::write(input_dir.join("beta.py"), "def bar():\n    return 2\n")?;
+
+        let plan_path = tmp.path().join("plan.json");
+        minify_plan_dir_with_depth(
+            &input_dir,
+            &plan_path,
+            &["*.py".to_string()],
+            None,
+            &["alpha*.py".to_string()],
+            None,
+            None,
+            false,
+            false,
+            None,
+            None,
+            false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
+            true,
+            false,
+            None,
+            &CommandContext::default(),
+        )?;
+
+        let bundle = read_plan_bundle(&plan_path)?;
+        let paths: Vec<String> = bundle.files.into_iter().map(|f| f.path).collect();
+        assert_eq!(paths, vec!["beta.py".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_pattern_files_respected() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("alpha.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+        fs::write(
+            input_dir.join("beta.py"),
+            "def bar(value):\n    temp = value + 2\n    return temp\n",
+        )?;
+
+        let include_file = tmp.path().join("includes.txt");
+        fs::write(&include_file, "*.py\n")?;
+        let exclude_file = tmp.path().join("excludes.txt");
+        fs::write(&exclude_file, "alpha*.py\n")?;
+
+        let output_dir = tmp.path().join("out");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            include_file: Some(include_file.clone()),
+            exclude_file: Some(exclude_file.clone()),
+            quiet: true,
+            ..Default::default()
+        };
+
+        let stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        assert_eq!(stats.processed, 1);
+        assert!(output_dir.join("beta.py").exists());
+        assert!(!output_dir.join("alpha.py").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_stats_report_skipped_functions() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def scale(values, factor):\n    return [v * factor for v in values]\n\n\ndef shift(amount):\n    total = amount + 1\n    return total\n",
+        )?;
+
+        let output_dir = tmp.path().join("out");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            dry_run: true,
+            show_stats: true,
+            json_output: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.reasons.get("function:comprehension"), Some(&1));
+        let file = &stats.files[0];
+        assert_eq!(file.skipped_functions.len(), 1);
+        assert_eq!(file.skipped_functions[0].name, "scale");
+        assert_eq!(file.skipped_functions[0].reason, "comprehension");
+        assert_eq!(file.skipped_functions[0].line, Some(1));
+
+        let json = serde_json::to_value(file)?;
+        assert_eq!(json["skipped_functions"][0]["reason"], "comprehension");
+        assert_eq!(json["skipped_functions"][0]["line"], 1);
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_sarif_reports_skipped_functions() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(
+            input_dir.join("pkg").join("example.py"),
+            "def shift(amount):\n    total = amount + 1\n    return total\n\n\ndef scale(values, factor):\n    return [v * factor for v in values]\n",
+        )?;
+        let sarif_path = tmp.path().join("bailouts.sarif");
+
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(&input_dir)
+            .arg("--out-dir")
+            .arg(tmp.path().join("out"))
+            .arg("--dry-run")
+            .arg("--sarif")
+            .arg(&sarif_path)
+            .output()?;
+        assert!(output.status.success());
+
+        let sarif: serde_json::Value = serde_json::from_str(&fs::read_to_string(&sarif_path)?)?;
+        assert_eq!(sarif["version"], "2.1.0");
+        assert_eq!(sarif["runs"][0]["tool"]["driver"]["name"], "tsrs");
+        let results = sarif["runs"][0]["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["ruleId"], "tsrs/bailout-comprehension");
+        assert!(results[0]["message"]["text"]
+            .as_str()
+            .unwrap()
+            .contains("scale"));
+        let location = &results[0]["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "pkg/example.py");
+        assert_eq!(location["region"]["startLine"], 6);
+        Ok(())
+    }
+
+    #[test]
+    fn optimize_sarif_points_at_dead_functions() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let code_dir = tmp.path().join("app");
+        fs::create_dir_all(code_dir.join("pkg"))?;
+        fs::write(
+            code_dir.join("pkg").join("jobs.py"),
+            "def main():\n    return 1\n\n\nclass Worker:\n    def stale(self):\n        return 2\n\n\nmain()\n",
+        )?;
+        let venv_dir = tmp.path().join("venv");
+        fs::create_dir_all(&venv_dir)?;
+        let sarif_path = tmp.path().join("dead.sarif");
+
+        let output = cli_cmd()?
+            .arg("--quiet")
+            .arg("optimize")
+            .arg(&code_dir)
+            .arg(&venv_dir)
+            .arg("--output")
+            .arg(tmp.path().join("optimized"))
+            .arg("--sarif")
+            .arg(&sarif_path)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let sarif: serde_json::Value = serde_json::from_str(&fs::read_to_string(&sarif_path)?)?;
+        assert_eq!(sarif["runs"][0]["tool"]["driver"]["name"], "tsrs");
+        let results = sarif["runs"][0]["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["ruleId"], "tsrs/dead-function");
+        assert!(results[0]["message"]["text"]
+            .as_str()
+            .unwrap()
+            .contains("Worker.stale"));
+        let location = &results[0]["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "pkg/jobs.py");
+        assert_eq!(location["region"]["startLine"], 6);
+        assert_eq!(location["region"]["startColumn"], 5);
+        Ok(())
+    }
+
+    #[test]
+    fn optimize_min_confidence_filters_dead_functions() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let code_dir = tmp.path().join("app");
+        fs::create_dir_all(code_dir.join("pkg"))?;
+        fs::write(
+            code_dir.join("pkg").join("jobs.py"),
+            "def main():\n    return 1\n\n\ndef _helper():\n    return 2\n\n\ndef stale():\n    return 3\n\n\nmain()\n",
+        )?;
+        let venv_dir = tmp.path().join("venv");
+        fs::create_dir_all(&venv_dir)?;
+        let sarif_path = tmp.path().join("dead.sarif");
+
+        let output = cli_cmd()?
+            .arg("--quiet")
+            .arg("optimize")
+            .arg(&code_dir)
+            .arg(&venv_dir)
+            .arg("--output")
+            .arg(tmp.path().join("optimized"))
+            .arg("--sarif")
+            .arg(&sarif_path)
+            .arg("--min-confidence")
+            .arg("high")
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let sarif: serde_json::Value = serde_json::from_str(&fs::read_to_string(&sarif_path)?)?;
+        let results = sarif["runs"][0]["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0]["message"]["text"]
+            .as_str()
+            .unwrap()
+            .contains("_helper"));
+        let properties = &results[0]["properties"];
+        assert_eq!(properties["confidence"], "high");
+        assert_eq!(properties["removalSpan"]["startLine"], 5);
+        assert_eq!(properties["removalSpan"]["endLine"], 6);
+        Ok(())
+    }
+
+    #[test]
+    fn optimize_report_keeps_re_exports_and_public_roots_live() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let code_dir = tmp.path().join("app");
+        fs::create_dir_all(code_dir.join("mypkg/api"))?;
+        fs::write(
+            code_dir.join("mypkg/__init__.py"),
+            "from .core import exported\n",
+        )?;
+        fs::write(
+            code_dir.join("mypkg/core.py"),
+            "def exported():\n    return 1\n\n\ndef forgotten():\n    return 2\n",
+        )?;
+        fs::write(
+            code_dir.join("mypkg/api/views.py"),
+            "def endpoint():\n    return 3\n",
+        )?;
+        let venv_dir = tmp.path().join("venv");
+        fs::create_dir_all(&venv_dir)?;
+
+        let output = cli_cmd()?
+            .arg("--quiet")
+            .arg("optimize")
+            .arg(&code_dir)
+            .arg(&venv_dir)
+            .arg("--output")
+            .arg(tmp.path().join("optimized"))
+            .arg("--print-report")
+            .arg("--public-roots")
+            .arg("mypkg/api/*.py")
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        let names = |key: &str| -> Vec<(String, String)> {
+            report[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|entry| {
+                    (
+                        entry["name"].as_str().unwrap_or_default().to_string(),
+                        entry["reason"].as_str().unwrap_or_default().to_string(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            names("dead_functions"),
+            [(
+                "forgotten".to_string(),
+                "Unreachable from entry points".to_string()
+            )]
+        );
+        assert_eq!(
+            names("roots"),
+            [
+                ("endpoint".to_string(), "root: public module".to_string()),
+                (
+                    "exported".to_string(),
+                    "root: __init__ re-export".to_string()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stats_report_per_function_detail() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let source = "def scale(values, factor):\n    return [v * factor for v in values]\n\n\ndef shift(amount):\n    total = amount + 1\n    return total\n";
+        fs::write(input_dir.join("example.py"), source)?;
+
+        let cfg = MinifyDirTestCfg {
+            dry_run: true,
+            show_stats: true,
+            json_output: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_minify_dir(
+            &input_dir,
+            Some(tmp.path().join("out")),
+            &[],
+            &[],
+            None,
+            cfg,
+        )?;
+        let functions = stats.files[0]
+            .functions
+            .as_ref()
+            .expect("--stats fills functions");
+        assert_eq!(functions.len(), 2);
+
+        let scale = &functions[0];
+        assert_eq!(scale.name, "scale");
+        assert!(scale.bailed);
+        assert_eq!(scale.bailout_reason.as_deref(), Some("comprehension"));
+        assert_eq!(scale.line, Some(1));
+        assert_eq!(scale.renames, 0);
+        assert_eq!(scale.bytes_after, scale.bytes_before);
+
+        let shift = &functions[1];
+        assert_eq!(shift.name, "shift");
+        assert_eq!(shift.line, Some(5));
+        assert!(!shift.bailed);
+        assert_eq!((shift.locals, shift.renames), (2, 2));
+        assert_eq!(shift.bytes_before - shift.bytes_after, 2 * 5 + 2 * 4);
+
+        // The single-file path reports the same detail
+        let (single, _) = minify(
+            &input_dir.join("example.py"),
+            false,
+            false,
+            None,
+            true,
+            true,
+            true,
+            None,
+            false,
+            false,
+            false,
+            false,
+            3,
+            false,
+        )?;
+        let json = serde_json::to_value(&single.files[0])?;
+        assert_eq!(json["functions"][1]["name"], "shift");
+        assert_eq!(json["functions"][1]["renames"], 2);
+        assert_eq!(json["functions"][0]["bailout_reason"], "comprehension");
+
+        // Without --stats the field is left out
+        let (plain, _) = minify(
+            &input_dir.join("example.py"),
+            false,
+            false,
+            None,
+            false,
+            false,
+            true,
+            None,
+            false,
+            false,
+            false,
+            false,
+            3,
+            false,
+        )?;
+        assert!(plain.files[0].functions.is_none());
+        assert!(serde_json::to_value(&plain.files[0])?
+            .get("functions")
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_max_file_size_skips_large_files() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let small = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input_dir.join("small.py"), small)?;
+
+        // Stand-in for a ~20 MB generated module.
+        let chunk = "def generated(value):\n    temp = value * 2\n    return temp\n\n\n";
+        let large = chunk.repeat(20 * 1024 * 1024 / chunk.len() + 1);
+        fs::write(input_dir.join("generated.py"), &large)?;
+
+        let output_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            show_stats: true,
+            quiet: true,
+            jobs: Some(2),
+            max_file_size: Some(1024 * 1024),
+            ..Default::default()
+        };
+        let stats = run_minify_dir(&input_dir, Some(output_dir.clone()), &[], &[], None, cfg)?;
+
+        assert_eq!(stats.processed, 2);
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.skipped_no_change, 1);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.reasons.get("too_large"), Some(&1));
+        let generated = stats
+            .files
+            .iter()
+            .find(|file| file.path == "generated.py")
+            .expect("generated.py in stats");
+        assert_eq!(generated.status, "skipped (too large)");
+        assert_eq!(generated.renames, 0);
+
+        // Oversized files are copied through byte-for-byte; the rest are still minified.
+        assert_eq!(
+            fs::metadata(output_dir.join("generated.py"))?.len(),
+            large.len() as u64
+        );
+        assert_ne!(fs::read_to_string(output_dir.join("small.py"))?, small);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn minify_dir_processes_a_symlinked_file_once() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input_dir.join("Utils.py"), source)?;
+        std::os::unix::fs::symlink("Utils.py", input_dir.join("helpers.py"))?;
+
+        let output_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            show_stats: true,
+            quiet: true,
+            jobs: Some(2),
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let stats = run_minify_dir(&input_dir, Some(output_dir.clone()), &[], &[], None, cfg)?;
+
+        assert_eq!(stats.processed, 2);
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.reasons.get("duplicate_path"), Some(&1));
+        let helpers = stats
+            .files
+            .iter()
+            .find(|file| file.path == "helpers.py")
+            .expect("helpers.py in stats");
+        assert_eq!(helpers.status, "skipped (duplicate path)");
+        assert_ne!(fs::read_to_string(output_dir.join("Utils.py"))?, source);
+        assert!(!output_dir.join("helpers.py").exists());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn minify_dir_refuses_outputs_that_differ_only_in_case() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input_dir.join("Mod.py"), source)?;
+        fs::write(input_dir.join("mod.py"), source)?;
+
+        let output_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_minify_dir(&input_dir, Some(output_dir.clone()), &[], &[], None, cfg)?;
+
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.reasons.get("output_collision"), Some(&1));
+        assert!(output_dir.join("Mod.py").is_file());
+        assert!(!output_dir.join("mod.py").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_skip_file_directive_counts_reason() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let skipped = "# tsrs: skip-file\ndef foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input_dir.join("generated.py"), skipped)?;
+
+        let output_dir = tmp.path().join("out");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            show_stats: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir.clone()),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
+
+        assert_eq!(stats.skipped_no_change, 1);
+        assert_eq!(stats.bailouts, 0);
+        assert_eq!(stats.reasons.get("skip_directive"), Some(&1));
+        assert_eq!(stats.files[0].status, "skipped (directive)");
+        assert_eq!(
+            fs::read_to_string(output_dir.join("generated.py"))?,
+            skipped
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_watch_resyncs_changed_and_removed_files() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("alpha.py"),
+            "def foo(value):\n    return value\n",
+        )?;
+        fs::write(
+            input_dir.join("beta.py"),
+            "def bar(item):\n    return item\n",
+        )?;
+
+        let output_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let initial = run_minify_dir(&input_dir, Some(output_dir.clone()), &[], &[], None, cfg)?;
+        assert_eq!(initial.rewritten, 2);
+
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        let watch_input = input_dir.clone();
+        let watch_output = output_dir.clone();
+        let handle = std::thread::spawn(move || {
+            watch_minify_dir(
+                &watch_input,
+                Some(watch_output.as_path()),
+                false,
+                false,
+                true,
+                initial,
+                &shutdown_rx,
+                |changed| run_watch_pass(&watch_input, &watch_output, changed),
+            )
+        });
+
+        // Give the watcher time to register before touching files.
+        std::thread::sleep(Duration::from_millis(500));
+        fs::write(
+            input_dir.join("alpha.py"),
+            "def foo(value):\n    doubled = value * 2\n    return doubled\n",
+        )?;
+        fs::remove_file(input_dir.join("beta.py"))?;
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let synced = || {
+            fs::read_to_string(output_dir.join("alpha.py"))
+                .map(|content| content.contains("b = a * 2"))
+                .unwrap_or(false)
+                && !output_dir.join("beta.py").exists()
+        };
+        while !synced() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        shutdown_tx.send(())?;
+        let stats = handle.join().expect("watcher thread panicked")?;
+
+        assert!(synced(), "out-dir was not updated by the watcher");
+        assert!(stats.rewritten >= 3);
+        assert_eq!(stats.errors, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn minify_file_output_json_writes_file() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        fs::write(
+            &file_path,
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+
+        let json_path = tmp.path().join("file.json");
+        let (stats, _) = minify_file(
+            &file_path,
+            false,
+            false,
+            None,
+            false,
+            false,
+            true,
+            Some(json_path.as_path()),
+            false,
+            false,
+            false,
+            false,
+            3,
+            false,
+        )?;
+
+        let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(written.processed, stats.processed);
+        assert_eq!(written.rewritten, stats.rewritten);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn minify_file_output_json_unwritable_parent_fails() -> AnyResult<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        fs::write(
+            &file_path,
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+
+        let reports_dir = tmp.path().join("reports");
+        fs::create_dir(&reports_dir)?;
+        let mut perms = fs::metadata(&reports_dir)?.permissions();
+        perms.set_mode(0o500);
+        fs::set_permissions(&reports_dir, perms.clone())?;
+
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(file_path.to_str().unwrap())
+            .arg("--stats")
+            .arg("--output-json")
+            .arg(reports_dir.join("minify.json").to_str().unwrap())
+            .output()?;
+
+        perms.set_mode(0o700);
+        fs::set_permissions(&reports_dir, perms)?;
+
+        assert!(!output.status.success());
+        assert!(!reports_dir.join("minify.json").exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn minify_dir_output_json_unwritable_parent_fails() -> AnyResult<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+
+        let reports_dir = tmp.path().join("reports");
+        fs::create_dir(&reports_dir)?;
+        let mut perms = fs::metadata(&reports_dir)?.permissions();
+        perms.set_mode(0o555);
+        fs::set_permissions(&reports_dir, perms.clone())?;
+
+        let out_dir = tmp.path().join("out");
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .arg("--stats")
+            .arg("--output-json")
+            .arg(reports_dir.join("minify-dir.json").to_str().unwrap())
+            .output()?;
+
+        perms.set_mode(0o755);
+        fs::set_permissions(&reports_dir, perms)?;
+
+        assert!(!output.status.success());
+        assert!(!reports_dir.join("minify-dir.json").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn minify_cli_output_json_writes_file() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        fs::write(
+            &file_path,
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+
+        let json_path = tmp.path().join("cli.json");
+        let (stats, _) = minify(
+            &file_path,
+            false,
+            false,
+            None,
+            false,
+            false,
+            true,
+            Some(json_path.as_path()),
+            false,
+            false,
+            false,
+            false,
+            3,
+            false,
+        )?;
+
+        let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(written.processed, stats.processed);
+        assert_eq!(written.rewritten, stats.rewritten);
+        Ok(())
     }
 
-    impl Default for ApplyPlanDirTestCfg {
-        fn default() -> Self {
-            Self {
-                in_place: false,
-                dry_run: false,
-                show_stats: false,
-                json_output: false,
-                include_file: None,
-                include_hidden: false,
-                follow_symlinks: false,
-                glob_case_insensitive: None,
-                quiet: false,
-                output_json: None,
-                jobs: None,
-                fail_on_bailout: false,
-                fail_on_error: false,
-                fail_on_change: false,
-                diff: false,
-                diff_context: 3,
-                max_depth: None,
-                exclude_file: None,
-                respect_gitignore: false,
-            }
-        }
-    }
+    #[test]
+    fn minify_cli_dry_run_no_write() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        let original = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(&file_path, original)?;
 
-    fn run_minify_dir(
-        input_dir: &Path,
-        out_dir: Option<PathBuf>,
-        includes: &[String],
-        excludes: &[String],
-        backup_ext: Option<&str>,
-        cfg: MinifyDirTestCfg,
-    ) -> AnyResult<DirStats> {
-        minify_dir_with_depth(
-            &input_dir.to_path_buf(),
-            out_dir,
-            includes,
-            cfg.include_file.as_ref(),
-            excludes,
-            cfg.exclude_file.as_ref(),
-            backup_ext,
-            cfg.in_place,
-            cfg.dry_run,
-            cfg.show_stats,
-            cfg.json_output,
-            cfg.include_hidden,
-            cfg.follow_symlinks,
-            cfg.glob_case_insensitive,
-            cfg.quiet,
-            cfg.output_json.as_deref(),
-            cfg.jobs,
-            cfg.fail_on_bailout,
-            cfg.fail_on_error,
-            cfg.fail_on_change,
-            cfg.diff,
-            cfg.diff_context,
-            cfg.respect_gitignore,
-            cfg.max_depth,
-            false,
-        )
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(file_path.to_str().unwrap())
+            .arg("--in-place")
+            .arg("--dry-run")
+            .output()?;
+        assert!(output.status.success());
+
+        let after = fs::read_to_string(&file_path)?;
+        assert_eq!(after, original);
+        Ok(())
     }
 
-    fn run_apply_plan_dir(
-        input_dir: &Path,
-        plan_path: &Path,
-        out_dir: Option<PathBuf>,
-        includes: &[String],
-        excludes: &[String],
-        backup_ext: Option<&str>,
-        cfg: ApplyPlanDirTestCfg,
-    ) -> AnyResult<DirStats> {
-        apply_plan_dir_with_depth(
-            &input_dir.to_path_buf(),
-            &plan_path.to_path_buf(),
-            out_dir,
-            includes,
-            cfg.include_file.as_ref(),
-            excludes,
-            cfg.exclude_file.as_ref(),
-            backup_ext,
-            cfg.in_place,
-            cfg.dry_run,
-            cfg.show_stats,
-            cfg.json_output,
-            cfg.include_hidden,
-            cfg.follow_symlinks,
-            cfg.glob_case_insensitive,
-            cfg.quiet,
-            cfg.output_json.as_deref(),
-            cfg.jobs,
-            cfg.fail_on_bailout,
-            cfg.fail_on_error,
-            cfg.fail_on_change,
-            cfg.diff,
-            cfg.diff_context,
-            cfg.respect_gitignore,
-            cfg.max_depth,
-        )
+    #[test]
+    fn minify_cli_renames_module_privates_only_when_requested() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        let original = "__all__ = [\"public\"]\n\n_DEFAULT_OFFSET = 1\n\n\ndef _offset_value(value):\n    return value + _DEFAULT_OFFSET\n\n\ndef public(value):\n    return _offset_value(value)\n";
+        fs::write(&file_path, original)?;
+
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(file_path.to_str().unwrap())
+            .arg("--in-place")
+            .output()?;
+        assert!(output.status.success());
+        let plain = fs::read_to_string(&file_path)?;
+        assert!(plain.contains("_offset_value"));
+
+        fs::write(&file_path, original)?;
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(file_path.to_str().unwrap())
+            .arg("--in-place")
+            .arg("--rename-module-privates")
+            .output()?;
+        assert!(output.status.success());
+        let renamed = fs::read_to_string(&file_path)?;
+        assert!(!renamed.contains("_offset_value"));
+        assert!(!renamed.contains("_DEFAULT_OFFSET"));
+        assert!(renamed.contains("def public("));
+        Ok(())
     }
 
-    fn create_nested_fixture(base: &Path) -> AnyResult<()> {
-        fs::create_dir_all(base)?;
-        fs::write(base.join("root.py"), "def root():\n    return 1\n")?;
-        let level1 = base.join("level1");
-        fs::create_dir_all(&level1)?;
-        fs::write(level1.join("inner.py"), "def inner():\n    return 2\n")?;
-        let level2 = level1.join("level2");
-        fs::create_dir_all(&level2)?;
-        fs::write(level2.join("deep.py"), "def deep():\n    return 3\n")?;
+    #[test]
+    fn minify_plan_cli_preserves_annotated_locals() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        fs::write(
+            &file_path,
+            "def build(value):\n    count: int = value + 1\n    scratch = count * 2\n    return scratch\n",
+        )?;
+
+        let output = cli_cmd()?
+            .arg("minify-plan")
+            .arg(file_path.to_str().unwrap())
+            .arg("--preserve-annotated-locals")
+            .output()?;
+        assert!(output.status.success());
+        let plan: MinifyPlan = serde_json::from_slice(&output.stdout)?;
+        assert!(plan.preserve_annotated_locals);
+        let originals: Vec<&str> = plan.functions[0]
+            .renames
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect();
+        assert!(originals.contains(&"scratch"));
+        assert!(!originals.contains(&"count"));
         Ok(())
     }
 
-    fn cli_cmd() -> AnyResult<Command> {
-        Ok(Command::from_std(StdCommand::new(cli_binary_path())))
+    /// Run git in `dir` with a throwaway identity, failing the test if it exits non-zero.
+    fn run_git(dir: &Path, args: &[&str]) -> AnyResult<()> {
+        let status = StdCommand::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args([
+                "-c",
+                "user.name=tsrs",
+                "-c",
+                "user.email=tsrs@example.com",
+                "-c",
+                "commit.gpgsign=false",
+            ])
+            .args(args)
+            .status()?;
+        anyhow::ensure!(status.success(), "git {} failed", args.join(" "));
+        Ok(())
     }
 
-    fn cli_binary_path() -> PathBuf {
-        if let Some(path) = std::env::var_os("CARGO_BIN_EXE_tsrs-cli") {
-            return PathBuf::from(path);
+    #[test]
+    fn changed_since_limits_minify_dir_and_plan_dir_to_touched_files() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let repo = tmp.path().join("repo");
+        let input_dir = repo.join("src");
+        fs::create_dir_all(&input_dir)?;
+        for name in ["touched.py", "untouched.py", "renamed.py", "deleted.py"] {
+            fs::write(
+                input_dir.join(name),
+                "def foo(value):\n    temp = value + 1\n    return temp\n",
+            )?;
         }
+        fs::write(
+            repo.join("outside.py"),
+            "def foo(value):\n    return value\n",
+        )?;
+        run_git(&repo, &["init", "-q"])?;
+        run_git(&repo, &["add", "."])?;
+        run_git(&repo, &["commit", "-q", "-m", "initial"])?;
 
-        let mut target_dir = std::env::var_os("CARGO_TARGET_DIR")
-            .map(PathBuf::from)
-            .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target"));
+        fs::write(
+            input_dir.join("touched.py"),
+            "def foo(value):\n    temp = value + 2\n    return temp\n",
+        )?;
+        fs::write(
+            repo.join("outside.py"),
+            "def foo(value):\n    return -value\n",
+        )?;
+        run_git(&repo, &["mv", "src/renamed.py", "src/moved.py"])?;
+        run_git(&repo, &["rm", "-q", "src/deleted.py"])?;
+        run_git(&repo, &["commit", "-q", "-am", "second"])?;
 
-        let profile = std::env::var("PROFILE").unwrap_or_else(|_| "debug".to_string());
-        target_dir.push(profile);
-        let binary = if cfg!(windows) {
-            "tsrs-cli.exe"
-        } else {
-            "tsrs-cli"
+        let json_path = tmp.path().join("stats.json");
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(tmp.path().join("out").to_str().unwrap())
+            .arg("--changed-since")
+            .arg("HEAD~1")
+            .arg("--output-json")
+            .arg(json_path.to_str().unwrap())
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(stats.processed, 2);
+        assert_eq!(stats.unchanged_skipped, 1);
+        assert!(tmp.path().join("out").join("touched.py").exists());
+        assert!(tmp.path().join("out").join("moved.py").exists());
+        assert!(!tmp.path().join("out").join("untouched.py").exists());
+
+        let plan_path = tmp.path().join("plan.json");
+        let output = cli_cmd()?
+            .arg("minify-plan-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out")
+            .arg(plan_path.to_str().unwrap())
+            .arg("--changed-since")
+            .arg("HEAD")
+            .output()?;
+        assert!(output.status.success());
+        assert!(read_plan_bundle(&plan_path)?.files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn no_default_excludes_selects_cache_dirs_and_reports_the_patterns() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("__pycache__"))?;
+        fs::write(
+            input_dir.join("__pycache__").join("cached.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+
+        let run = |extra: &[&str]| -> AnyResult<(std::process::Output, DirStats)> {
+            let json_path = tmp.path().join("stats.json");
+            let output = cli_cmd()?
+                .arg("minify-dir")
+                .arg(input_dir.to_str().unwrap())
+                .arg("--out-dir")
+                .arg(tmp.path().join("out").to_str().unwrap())
+                .arg("--output-json")
+                .arg(json_path.to_str().unwrap())
+                .args(extra)
+                .output()?;
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let stats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+            Ok((output, stats))
         };
-        target_dir.push(binary);
-        target_dir
+
+        let (output, stats) = run(&[])?;
+        assert_eq!(stats.processed, 0);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Exclude patterns: **/.git/**, **/__pycache__/**"));
+        assert!(stderr.contains("exclude pattern '**/__pycache__/**' matches them"));
+
+        let (output, stats) = run(&["--no-default-excludes", "--exclude", "gen/**"])?;
+        assert_eq!(stats.processed, 1);
+        let options = stats.options.expect("walk patterns in the stats");
+        assert_eq!(options.include, vec!["**/*.[pP][yY]"]);
+        assert_eq!(options.exclude, vec!["gen/**"]);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Exclude patterns: gen/**"));
+        assert!(tmp.path().join("out/__pycache__/cached.py").exists());
+        Ok(())
     }
 
     #[test]
-    fn unified_diff_smoke() {
-        let diff = make_unified_diff("example.py", "a = 1\n", "a = 2\n", 3);
-        assert!(diff.contains("a/example.py"));
-        assert!(diff.contains("b/example.py"));
-        assert!(diff.contains("-a = 1"));
-        assert!(diff.contains("+a = 2"));
+    fn plan_limits_skip_generated_files_until_raised() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let source: String = (0..100)
+            .map(|index| {
+                format!("def f{index}(value):\n    total = value + 1\n    return total\n\n")
+            })
+            .collect();
+        fs::write(input_dir.join("generated.py"), &source)?;
+
+        let run = |out_dir: &Path, limit_flag: &str, limit: &str| -> AnyResult<DirStats> {
+            let json_path = tmp.path().join("stats.json");
+            let output = cli_cmd()?
+                .arg("minify-dir")
+                .arg(input_dir.to_str().unwrap())
+                .arg("--out-dir")
+                .arg(out_dir.to_str().unwrap())
+                .arg("--output-json")
+                .arg(json_path.to_str().unwrap())
+                .args([limit_flag, limit])
+                .output()?;
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            Ok(serde_json::from_str(&fs::read_to_string(&json_path)?)?)
+        };
+
+        let stats = run(&tmp.path().join("out1"), "--max-renames-per-file", "10")?;
+        assert_eq!(stats.rewritten, 0);
+        assert_eq!(stats.reasons.get("too_many_renames"), Some(&1));
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("out1/generated.py"))?,
+            source
+        );
+
+        let stats = run(&tmp.path().join("out2"), "--max-plan-functions", "50")?;
+        assert_eq!(stats.reasons.get("too_many_functions"), Some(&1));
+
+        let stats = run(&tmp.path().join("out3"), "--max-renames-per-file", "200")?;
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.total_renames, 200);
+        assert_ne!(
+            fs::read_to_string(tmp.path().join("out3/generated.py"))?,
+            source
+        );
+        Ok(())
     }
 
     #[test]
-    fn unified_diff_context_zero() {
-        let diff = make_unified_diff("example.py", "a = 1\nprint(a)\n", "a = 2\nprint(a)\n", 0);
-        assert!(diff.contains("@@"));
-        let context_lines = diff.lines().filter(|line| line.starts_with(' ')).count();
-        assert_eq!(context_lines, 0, "unexpected context lines: {diff}");
+    fn module_roots_rename_plan_modules_and_apply_still_matches_by_path() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("repo");
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        for rel in [
+            "services/a/src/pkg/api.py",
+            "services/b/jobs.py",
+            "libs/common/pkg/util.py",
+            "tools/run.py",
+        ] {
+            let path = input_dir.join(rel);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, source)?;
+        }
+
+        let plan_path = tmp.path().join("plan.json");
+        let output = cli_cmd()?
+            .arg("minify-plan-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out")
+            .arg(plan_path.to_str().unwrap())
+            .args([
+                "--module-root",
+                "services",
+                "--module-root",
+                "./services/a/src",
+            ])
+            .args(["--module-root", "libs/common"])
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("tools/run.py is under no --module-root"));
+
+        let (header, files) = open_plan_bundle(&plan_path)?;
+        assert_eq!(
+            header.module_roots,
+            vec!["services", "services/a/src", "libs/common"]
+        );
+        let modules: Vec<(String, String)> = files
+            .map(|entry| {
+                let entry = entry?;
+                assert_eq!(entry.module.as_ref(), Some(&entry.plan.module));
+                Ok((entry.path, entry.plan.module))
+            })
+            .collect::<AnyResult<_>>()?;
+        assert_eq!(
+            modules,
+            vec![
+                (
+                    "libs/common/pkg/util.py".to_string(),
+                    "pkg.util".to_string()
+                ),
+                (
+                    "services/a/src/pkg/api.py".to_string(),
+                    "pkg.api".to_string()
+                ),
+                ("services/b/jobs.py".to_string(), "b.jobs".to_string()),
+                ("tools/run.py".to_string(), "tools.run".to_string()),
+            ]
+        );
+
+        let out_dir = tmp.path().join("out");
+        let output = cli_cmd()?
+            .arg("apply-plan-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--plan")
+            .arg(plan_path.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let rewritten = fs::read_to_string(out_dir.join("services/a/src/pkg/api.py"))?;
+        assert!(!rewritten.contains("temp"));
+        assert!(!fs::read_to_string(out_dir.join("tools/run.py"))?.contains("temp"));
+        Ok(())
     }
 
     #[test]
-    fn minify_dir_diff_context_one_outputs_expected() -> AnyResult<()> {
+    fn changed_since_outside_a_git_repo_fails_unless_optional() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
         fs::write(
             input_dir.join("example.py"),
-            "def foo(value):\n    temp = value + 1\n    return temp\nprint(\"done\")\n",
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
         )?;
 
-        let out_dir = tmp.path().join("out");
-
         let output = cli_cmd()?
             .arg("minify-dir")
             .arg(input_dir.to_str().unwrap())
-            .arg("--out-dir")
-            .arg(out_dir.to_str().unwrap())
-            .arg("--diff")
-            .arg("--diff-context")
-            .arg("1")
             .arg("--dry-run")
+            .arg("--changed-since")
+            .arg("main")
             .output()?;
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--changed-since-optional"));
 
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--dry-run")
+            .arg("--changed-since")
+            .arg("main")
+            .arg("--changed-since-optional")
+            .arg("--stats")
+            .output()?;
         assert!(output.status.success());
-        let stdout = String::from_utf8(output.stdout)?;
-        let context_lines = stdout.lines().filter(|line| line.starts_with(' ')).count();
-        assert_eq!(context_lines, 1, "unexpected context lines: {stdout}");
-        Ok(())
-    }
-
-    #[test]
-    fn glob_case_insensitive_matches_uppercase() -> AnyResult<()> {
-        let set = build_globset(&["a*.py".to_string()], true)?;
-        assert!(set.is_match("A.py"));
-        Ok(())
-    }
-
-    #[cfg(windows)]
-    #[test]
-    fn default_glob_matching_is_case_insensitive_on_windows() -> AnyResult<()> {
-        let set = build_globset(&["a*.py".to_string()], cfg!(windows))?;
-        assert!(set.is_match("A.py"));
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Dry run complete: 1 files"));
         Ok(())
     }
 
-    #[cfg(not(windows))]
     #[test]
-    fn glob_matching_requires_opt_in_for_case_insensitivity_on_unix() -> AnyResult<()> {
-        let set = build_globset(&["a*.py".to_string()], false)?;
-        assert!(!set.is_match("A.py"));
+    fn minify_dir_warn_collisions_reports_attribute_near_misses() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("clash.py"),
+            "def tally(items):\n    total = 0\n    for item in items:\n        total += item\n    settings.a = total\n    return total\n",
+        )?;
+        fs::write(
+            input_dir.join("clean.py"),
+            "def tally(items):\n    total = 0\n    for item in items:\n        total += item\n    return total\n",
+        )?;
+        let json_path = tmp.path().join("stats.json");
 
-        let insensitive = build_globset(&["a*.py".to_string()], true)?;
-        assert!(insensitive.is_match("A.py"));
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(tmp.path().join("out").to_str().unwrap())
+            .arg("--stats")
+            .arg("--warn-collisions")
+            .arg("--output-json")
+            .arg(json_path.to_str().unwrap())
+            .output()?;
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("clash.py:5: attribute `a` matches the name generated for"));
+        assert!(!stderr.contains("clean.py"));
+
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        let file = |name: &str| stats.files.iter().find(|file| file.path == name).unwrap();
+        let warnings = &file("clash.py").warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].name, "a");
+        assert_eq!(warnings[0].kind, CollisionKind::Attribute);
+        assert!(file("clean.py").warnings.is_empty());
         Ok(())
     }
 
     #[test]
-    fn minify_dir_preserves_structure() -> AnyResult<()> {
+    fn size_audit_estimate_matches_minify_dir_output() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("input");
-        let nested = input_dir.join("pkg");
-        fs::create_dir_all(&nested)?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(
+            input_dir.join("pkg/stats.py"),
+            "def mean(values):\n    \"\"\"Arithmetic mean.\"\"\"\n    total = sum(values)\n    count = len(values)\n    return total / count\n\n\ndef spread(values):\n    lowest = min(values)\n    highest = max(values)\n    return highest - lowest\n",
+        )?;
+        fs::write(
+            input_dir.join("pkg/closure.py"),
+            "def outer(value):\n    def inner(offset):\n        return value + offset\n    return inner\n",
+        )?;
+        fs::write(input_dir.join("constants.py"), "LIMIT = 10\n")?;
 
-        let module_source = "\
-def sample(value):
-    temp = value + 1
-    return temp
-";
-        fs::write(input_dir.join("module.py"), module_source)?;
-        fs::write(nested.join("__init__.py"), "")?;
+        let output = cli_cmd()?
+            .arg("size-audit")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--json")
+            .output()?;
+        assert!(output.status.success());
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        let estimated = report["estimated_bytes"].as_u64().unwrap();
+        let files = report["files"].as_array().unwrap();
+        assert_eq!(files.len(), 3);
+        let closure = files
+            .iter()
+            .find(|f| f["path"] == "pkg/closure.py")
+            .unwrap();
+        assert_eq!(closure["skipped"], "skipped (nested scopes)");
+        let stats = files.iter().find(|f| f["path"] == "pkg/stats.py").unwrap();
+        assert!(stats["renames_saved"].as_u64().unwrap() > 0);
+        assert!(stats["docstrings_saved"].as_u64().unwrap() > 0);
 
-        let output_dir = tmp.path().join("output");
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            quiet: true,
-            ..Default::default()
-        };
-        let _stats = run_minify_dir(
-            &input_dir,
-            Some(output_dir.clone()),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )?;
+        let out_dir = tmp.path().join("out");
+        let status = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .output()?
+            .status;
+        assert!(status.success());
+        let actual: u64 = ["pkg/stats.py", "pkg/closure.py", "constants.py"]
+            .iter()
+            .map(|rel| fs::metadata(out_dir.join(rel)).map(|meta| meta.len()))
+            .sum::<std::io::Result<u64>>()?;
+        assert!(
+            estimated.abs_diff(actual) * 100 <= actual * 3,
+            "estimated {estimated} bytes, minify-dir wrote {actual}"
+        );
 
-        let rewritten = fs::read_to_string(output_dir.join("module.py"))?;
-        assert!(rewritten.contains("def sample(a):"));
-        assert!(output_dir.join("pkg/__init__.py").exists());
+        let table = cli_cmd()?
+            .arg("size-audit")
+            .arg(input_dir.to_str().unwrap())
+            .output()?;
+        let stdout = String::from_utf8_lossy(&table.stdout);
+        assert!(stdout.contains("pkg/closure.py"));
+        assert!(stdout.contains("Estimated savings:"));
         Ok(())
     }
 
     #[test]
-    fn minify_dir_respects_include_exclude() -> AnyResult<()> {
+    fn locked_output_dir_is_refused_by_minify_dir_and_apply_plan_dir() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
-        let pkg_a = input_dir.join("pkg_a");
-        let pkg_b = input_dir.join("pkg_b");
-        fs::create_dir_all(&pkg_a)?;
-        fs::create_dir_all(&pkg_b)?;
-
-        fs::write(
-            pkg_a.join("mod.py"),
-            "def foo(x):\n    y = x + 1\n    return y\n",
-        )?;
+        fs::create_dir_all(&input_dir)?;
         fs::write(
-            pkg_b.join("mod.py"),
-            "def bar(y):\n    z = y - 1\n    return z\n",
+            input_dir.join("example.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
         )?;
+        let out_dir = tmp.path().join("out");
+        fs::create_dir_all(&out_dir)?;
+        let lock_path = out_dir.join(LOCK_FILE_NAME);
+        let lock = serde_json::json!({
+            "pid": std::process::id(),
+            "hostname": "ci-runner",
+            "started": unix_now(),
+        });
+        fs::write(&lock_path, lock.to_string())?;
 
-        let output_dir = tmp.path().join("out");
-        let includes = vec!["pkg_a/**".to_string()];
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            quiet: true,
-            ..Default::default()
-        };
-        let _stats = run_minify_dir(
-            &input_dir,
-            Some(output_dir.clone()),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )?;
+        let started = Instant::now();
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .output()?;
+        assert!(!output.status.success());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("is locked by another tsrs run"), "{stderr}");
+        assert!(stderr.contains("ci-runner"));
 
-        assert!(output_dir.join("pkg_a/mod.py").exists());
-        assert!(!output_dir.join("pkg_b/mod.py").exists());
+        let plan_path = tmp.path().join("plan.json");
+        let status = cli_cmd()?
+            .arg("minify-plan-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out")
+            .arg(plan_path.to_str().unwrap())
+            .output()?
+            .status;
+        assert!(status.success());
+        let output = cli_cmd()?
+            .arg("apply-plan-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--plan")
+            .arg(plan_path.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .output()?;
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("is locked"));
+
+        // The refused runs leave the holder's lock alone and write nothing.
+        assert!(lock_path.exists());
+        assert!(!out_dir.join("example.py").exists());
         Ok(())
     }
 
     #[test]
-    fn minify_dir_dry_run_creates_no_output() -> AnyResult<()> {
+    fn apply_plan_dir_fuzzy_apply_follows_shifted_functions() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-        fs::write(
-            input_dir.join("example.py"),
-            "def foo(x):\n    y = x + 1\n    return y\n",
-        )?;
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input_dir.join("example.py"), source)?;
 
-        let output_dir = tmp.path().join("out");
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            dry_run: true,
-            show_stats: true,
-            quiet: true,
-            ..Default::default()
+        let plan_path = tmp.path().join("plan.json");
+        let status = cli_cmd()?
+            .arg("minify-plan-dir")
+            .arg(&input_dir)
+            .arg("--out")
+            .arg(&plan_path)
+            .output()?
+            .status;
+        assert!(status.success());
+        let shifted = format!("# edited after planning\n{source}");
+        fs::write(input_dir.join("example.py"), &shifted)?;
+
+        let run = |name: &str, extra: &[&str]| -> AnyResult<DirStats> {
+            let json_path = tmp.path().join(format!("{name}.json"));
+            let output = cli_cmd()?
+                .arg("apply-plan-dir")
+                .arg(&input_dir)
+                .arg("--plan")
+                .arg(&plan_path)
+                .arg("--out-dir")
+                .arg(tmp.path().join(name))
+                .arg("--stats")
+                .arg("--output-json")
+                .arg(&json_path)
+                .args(extra)
+                .output()?;
+            assert!(output.status.success());
+            Ok(serde_json::from_str(&fs::read_to_string(json_path)?)?)
         };
-        let _stats = run_minify_dir(
-            &input_dir,
-            Some(output_dir.clone()),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )?;
 
-        assert!(!output_dir.exists());
+        let strict = run("strict", &[])?;
+        assert_eq!(strict.rewritten, 0);
+        assert_eq!(strict.reasons.get("rewrite_aborted"), Some(&1));
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("strict/example.py"))?,
+            shifted
+        );
+
+        let fuzzy = run("fuzzy", &["--fuzzy-apply"])?;
+        assert_eq!(fuzzy.rewritten, 1);
+        assert_eq!(fuzzy.reasons.get("fuzzy_matched"), Some(&1));
+        assert_eq!(fuzzy.files[0].fuzzy_matched, vec!["foo"]);
+        let written = fs::read_to_string(tmp.path().join("fuzzy/example.py"))?;
+        assert!(written.starts_with("# edited after planning\ndef foo(a):"));
         Ok(())
     }
 
     #[test]
-    fn minify_dir_in_place_updates_files() -> AnyResult<()> {
+    fn force_lock_takes_over_stale_lock() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-        let file_path = input_dir.join("example.py");
         fs::write(
-            &file_path,
+            input_dir.join("example.py"),
             "def foo(value):\n    temp = value + 1\n    return temp\n",
         )?;
-
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            in_place: true,
-            quiet: true,
-            ..Default::default()
+        let out_dir = tmp.path().join("out");
+        fs::create_dir_all(&out_dir)?;
+        let lock_path = out_dir.join(LOCK_FILE_NAME);
+        let lock = serde_json::json!({
+            "pid": 999_999_999u32,
+            "hostname": "gone-host",
+            "started": 1_000,
+        });
+        fs::write(&lock_path, lock.to_string())?;
+
+        let minify = || -> AnyResult<Command> {
+            let mut cmd = cli_cmd()?;
+            cmd.arg("minify-dir")
+                .arg(input_dir.to_str().unwrap())
+                .arg("--out-dir")
+                .arg(out_dir.to_str().unwrap());
+            Ok(cmd)
         };
-        let _stats = run_minify_dir(&input_dir, None, &includes, &excludes, None, cfg)?;
+        let output = minify()?.output()?;
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--force-lock"));
 
-        let rewritten = fs::read_to_string(&file_path)?;
-        assert!(rewritten.contains("def foo(a):"));
+        let output = minify()?.arg("--force-lock").output()?;
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("removing stale lock"), "{stderr}");
+        assert!(stderr.contains("pid 999999999 on gone-host"));
+        assert!(out_dir.join("example.py").exists());
+        assert!(!lock_path.exists());
         Ok(())
     }
 
     #[test]
-    fn minify_dir_in_place_writes_backup() -> AnyResult<()> {
+    fn minify_dir_min_name_length_keeps_short_locals() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-        let file_path = input_dir.join("example.py");
-        let original = "def foo(value):\n    temp = value + 1\n    return temp\n";
-        fs::write(&file_path, original)?;
+        fs::write(
+            input_dir.join("store.py"),
+            "def load(db, value):\n    ok = db.ping()\n    result = []\n    for i in range(value):\n        result.append(db.get(i))\n    return result if ok else None\n",
+        )?;
 
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            in_place: true,
-            quiet: true,
-            ..Default::default()
+        let run = |name: &str, extra: &[&str]| -> AnyResult<serde_json::Value> {
+            let json_path = tmp.path().join(format!("{name}.json"));
+            let output = cli_cmd()?
+                .arg("minify-dir")
+                .arg(&input_dir)
+                .arg("--out-dir")
+                .arg(tmp.path().join(name))
+                .arg("--stats")
+                .arg("--output-json")
+                .arg(&json_path)
+                .args(extra)
+                .output()?;
+            assert!(output.status.success());
+            Ok(serde_json::from_str(&fs::read_to_string(json_path)?)?)
         };
-        let _stats = run_minify_dir(&input_dir, None, &includes, &excludes, Some(".bak"), cfg)?;
-
-        let rewritten = fs::read_to_string(&file_path)?;
-        assert!(rewritten.contains("def foo(a):"));
-
-        let backup_path = input_dir.join("example.py.bak");
-        assert!(backup_path.exists());
-        let backup_contents = fs::read_to_string(backup_path)?;
-        assert_eq!(backup_contents, original);
-
+        let default_stats = run("default", &[])?;
+        let thresholded = run("thresholded", &["--min-name-length", "3"])?;
+
+        assert_eq!(default_stats["total_renames"], 5);
+        assert_eq!(thresholded["total_renames"], 2);
+        let minified = fs::read_to_string(tmp.path().join("thresholded").join("store.py"))?;
+        assert!(minified.starts_with("def load(db, "));
+        assert!(minified.contains("ok = db.ping()"));
+        assert!(minified.contains("for i in range("));
+        assert!(!minified.contains("value"));
+        assert!(!minified.contains("result"));
         Ok(())
     }
 
     #[test]
-    fn minify_dir_stats_json_runs() -> AnyResult<()> {
+    fn rename_prefix_and_alphabet_shape_planned_names() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
+        let file_path = input_dir.join("example.py");
         fs::write(
-            input_dir.join("example.py"),
-            "def foo(x):\n    y = x + 1\n    return y\n",
+            &file_path,
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
         )?;
 
-        let output_dir = tmp.path().join("out");
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            dry_run: true,
-            show_stats: true,
-            json_output: true,
-            quiet: true,
-            ..Default::default()
-        };
-        let _stats = run_minify_dir(
-            &input_dir,
-            Some(output_dir),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )?;
+        let output = cli_cmd()?
+            .arg("minify-plan")
+            .arg(&file_path)
+            .args(["--rename-prefix", "_t", "--rename-alphabet", "xyz"])
+            .output()?;
+        assert!(output.status.success());
+        let plan: MinifyPlan = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(plan.naming_config.prefix, "_t");
+        assert_eq!(plan.naming_config.alphabet, "xyz");
+        let renamed: Vec<&str> = plan.functions[0]
+            .renames
+            .iter()
+            .map(|entry| entry.renamed.as_str())
+            .collect();
+        assert_eq!(renamed, vec!["_tx", "_ty"]);
 
+        let out_dir = tmp.path().join("out");
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(&input_dir)
+            .arg("--out-dir")
+            .arg(&out_dir)
+            .args(["--rename-prefix", "t-"])
+            .output()?;
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("rename prefix `t-` contains `-`"),
+            "{stderr}"
+        );
+        assert!(!out_dir.exists());
         Ok(())
     }
 
     #[test]
-    fn minify_dir_skips_hidden_by_default() -> AnyResult<()> {
+    fn call_graph_prints_calls_with_their_lines() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        fs::create_dir_all(&input_dir)?;
+        let file_path = tmp.path().join("app.py");
         fs::write(
-            input_dir.join(".hidden.py"),
-            "def foo(x):\n    y = x + 1\n    return y\n",
+            &file_path,
+            "def main():\n    return helper(1)\n\n\ndef helper(value):\n    return value\n",
         )?;
 
-        let output_dir = tmp.path().join("out");
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            quiet: true,
-            ..Default::default()
+        let output = cli_cmd()?
+            .arg("call-graph")
+            .arg(&file_path)
+            .arg("--json")
+            .output()?;
+        assert!(output.status.success());
+        let export: tsrs::CallGraphExport = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(export.package, "app");
+        let id = |name: &str| {
+            export
+                .nodes
+                .iter()
+                .find(|node| node.function.name == name)
+                .map(|node| node.id)
         };
-        let _stats = run_minify_dir(
-            &input_dir,
-            Some(output_dir.clone()),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )?;
+        assert_eq!(export.edges.len(), 1);
+        assert_eq!(Some(export.edges[0].caller), id("main"));
+        assert_eq!(Some(export.edges[0].callee), id("helper"));
+        assert_eq!(export.edges[0].site.location.line, 2);
 
-        assert!(!output_dir.join(".hidden.py").exists());
+        let output = cli_cmd()?.arg("call-graph").arg(&file_path).output()?;
+        assert!(output.status.success());
+        let text = String::from_utf8(output.stdout)?;
+        assert!(text.starts_with("2 functions, 1 calls between them\n"));
+        assert!(text.contains("  main -> helper (line 2)\n"));
         Ok(())
     }
 
     #[test]
-    fn minify_dir_includes_hidden_when_requested() -> AnyResult<()> {
+    fn function_globs_narrow_plan_bundles() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
+        let source = "class Model:\n    def save(self, store):\n        payload = dict(owner=self)\n        store.put(payload)\n\n\ndef helper(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input_dir.join("example.py"), source)?;
+
+        let plan = |name: &str, extra: &[&str]| -> AnyResult<PathBuf> {
+            let plan_path = tmp.path().join(name);
+            let status = cli_cmd()?
+                .arg("minify-plan-dir")
+                .arg(&input_dir)
+                .arg("--out")
+                .arg(&plan_path)
+                .args(extra)
+                .output()?
+                .status;
+            assert!(status.success());
+            Ok(plan_path)
+        };
+        let full = plan("full.json", &[])?;
+        let narrowed = plan("narrowed.json", &["--include-function", "*.Model.*"])?;
+        assert!(fs::metadata(&narrowed)?.len() < fs::metadata(&full)?.len());
+        let bundle = fs::read_to_string(&narrowed)?;
+        assert!(bundle.contains("\"function_filter\":{\"include\":[\"*.Model.*\"]}"));
+        assert!(!bundle.contains("\"qualified_name\":\"helper\""));
+
+        let out_dir = tmp.path().join("out");
+        let status = cli_cmd()?
+            .arg("apply-plan-dir")
+            .arg(&input_dir)
+            .arg("--plan")
+            .arg(&narrowed)
+            .arg("--out-dir")
+            .arg(&out_dir)
+            .output()?
+            .status;
+        assert!(status.success());
+        let written = fs::read_to_string(out_dir.join("example.py"))?;
+        assert!(!written.contains("payload"));
+        assert!(written.contains("def helper(value):\n    temp = value + 1"));
+        Ok(())
+    }
+
+    #[test]
+    fn annotate_renames_comments_appear_in_stdout_and_stats() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("tool.py");
         fs::write(
-            input_dir.join(".hidden.py"),
-            "def foo(x):\n    y = x + 1\n    return y\n",
+            &file_path,
+            "def main(argv):\n    \"\"\"Entry point.\"\"\"\n    count = len(argv)\n    return count\n",
         )?;
 
-        let output_dir = tmp.path().join("out");
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            include_hidden: true,
-            quiet: true,
-            ..Default::default()
-        };
-        let _stats = run_minify_dir(
-            &input_dir,
-            Some(output_dir.clone()),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )?;
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--annotate-renames")
+            .arg("--stdout")
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("def main(a):\n    # tsrs: a=argv, b=count\n    b = len(a)\n"));
+        Minifier::check_syntax("tool", &stdout)?;
 
-        assert!(output_dir.join(".hidden.py").exists());
+        let stats_path = tmp.path().join("stats.json");
+        let status = cli_cmd()?
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--annotate-renames")
+            .arg("--annotate-width")
+            .arg("10")
+            .arg("--in-place")
+            .arg("--stats")
+            .arg("--output-json")
+            .arg(&stats_path)
+            .output()?
+            .status;
+        assert!(status.success());
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&stats_path)?)?;
+        let annotation = "    # tsrs: a=argv,\n    # tsrs: b=count\n";
+        assert_eq!(stats.files[0].annotation_bytes, annotation.len());
+        let written = fs::read_to_string(&file_path)?;
+        assert!(written.contains(annotation));
         Ok(())
     }
 
     #[test]
-    fn minify_dir_respects_max_depth() -> AnyResult<()> {
+    fn minify_file_fail_on_change_exits_nonzero() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        create_nested_fixture(&input_dir)?;
+        let file_path = tmp.path().join("example.py");
+        fs::write(
+            &file_path,
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
 
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(file_path.to_str().unwrap())
+            .arg("--fail-on-change")
+            .output()?;
+        assert!(!output.status.success());
+        assert_eq!(output.status.code(), Some(4));
+        Ok(())
+    }
 
-        let cfg_depth1 = MinifyDirTestCfg {
-            quiet: true,
-            max_depth: Some(1),
-            ..Default::default()
-        };
-        let stats_depth1 = run_minify_dir(
-            &input_dir,
-            Some(tmp.path().join("min-out-depth1")),
-            &includes,
-            &excludes,
-            None,
-            cfg_depth1,
+    #[test]
+    fn minify_file_fail_on_bailout_exits_nonzero() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        fs::write(
+            &file_path,
+            "def foo(values):\n    squared = [v * v for v in values]\n    return squared\n",
         )?;
-        assert_eq!(stats_depth1.processed, 1);
 
-        let cfg_depth2 = MinifyDirTestCfg {
-            quiet: true,
-            max_depth: Some(2),
-            ..Default::default()
-        };
-        let stats_depth2 = run_minify_dir(
-            &input_dir,
-            Some(tmp.path().join("min-out-depth2")),
-            &includes,
-            &excludes,
-            None,
-            cfg_depth2,
-        )?;
-        assert_eq!(stats_depth2.processed, 2);
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(file_path.to_str().unwrap())
+            .arg("--fail-on-bailout")
+            .output()?;
 
+        assert!(!output.status.success());
+        assert_eq!(output.status.code(), Some(2));
         Ok(())
     }
 
     #[test]
-    fn minify_dir_respects_gitignore() -> AnyResult<()> {
+    #[cfg(unix)]
+    fn minify_file_fail_on_error_exits_nonzero() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        fs::create_dir_all(&input_dir)?;
-        fs::write(input_dir.join(".gitignore"), "alpha.py\n")?;
+        let file_path = tmp.path().join("example.py");
         fs::write(
-            input_dir.join("alpha.py"),
+            &file_path,
             "def foo(value):\n    temp = value + 1\n    return temp\n",
         )?;
-        fs::write(
-            input_dir.join("beta.py"),
-            "def bar(value):\n    temp = value + 2\n    return temp\n",
-        )?;
+        let mut perms = fs::metadata(&file_path)?.permissions();
+        let mut readonly = perms.clone();
+        readonly.set_mode(0o444);
+        fs::set_permissions(&file_path, readonly)?;
 
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(file_path.to_str().unwrap())
+            .arg("--in-place")
+            .arg("--fail-on-error")
+            .output()?;
 
-        let cfg_all = MinifyDirTestCfg {
-            in_place: true,
-            dry_run: true,
-            quiet: true,
-            ..Default::default()
-        };
-        let stats_all = run_minify_dir(&input_dir, None, &includes, &excludes, None, cfg_all)?;
-        assert_eq!(stats_all.processed, 2);
+        perms.set_mode(0o644);
+        fs::set_permissions(&file_path, perms)?;
 
-        let cfg_respect = MinifyDirTestCfg {
-            in_place: true,
-            dry_run: true,
-            quiet: true,
-            respect_gitignore: true,
-            ..Default::default()
-        };
-        let stats_respected =
-            run_minify_dir(&input_dir, None, &includes, &excludes, None, cfg_respect)?;
-        assert_eq!(stats_respected.processed, 1);
-        assert_eq!(stats_respected.rewritten, 1);
+        assert!(!output.status.success());
+        assert_eq!(output.status.code(), Some(1));
         Ok(())
     }
 
     #[test]
-    fn minify_dir_include_exclude_precedence_exclude_wins() -> AnyResult<()> {
+    fn minify_keeps_script_header_when_docstring_and_dead_code_edits_fire() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        fs::create_dir_all(&input_dir)?;
-        fs::write(input_dir.join("alpha.py"), "def foo():\n    return 1\n")?;
-        fs::write(input_dir.join("beta.py"), "def bar():\n    return 2\n")?;
-
-        let plan_path = tmp.path().join("plan.json");
-        minify_plan_dir_with_depth(
-            &input_dir,
-            &plan_path,
-            &["*.py".to_string()],
-            None,
-            &["alpha*.py".to_string()],
-            None,
-            None,
-            false,
-            false,
-            None,
-            None,
-            false,
-            true,
+        let file_path = tmp.path().join("tool.py");
+        fs::write(
+            &file_path,
+            "#!/usr/bin/env python3\n# -*- coding: utf-8 -*-\n\"\"\"Command-line tool.\"\"\"\nfrom __future__ import annotations\n\nimport os\nimport sys\n\n\ndef unused_helper(value):\n    doubled = value * 2\n    return doubled\n\n\ndef main(argv):\n    count = len(argv)\n    return count\n\n\nif __name__ == \"__main__\":\n    sys.exit(main(sys.argv))\n",
         )?;
 
-        let bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
-        let paths: Vec<String> = bundle.files.into_iter().map(|f| f.path).collect();
-        assert_eq!(paths, vec!["beta.py".to_string()]);
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--remove-dead-code")
+            .arg("--remove-unused-imports")
+            .arg("--stdout")
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.starts_with(
+            "#!/usr/bin/env python3\n# -*- coding: utf-8 -*-\nfrom __future__ import annotations\n"
+        ));
+        assert!(!stdout.contains("Command-line tool"));
+        assert!(!stdout.contains("import os"));
+        assert!(stdout.contains("doubled = value * 2"));
+        assert!(stdout.contains(" = len(a)\n"));
+        Minifier::check_syntax("tool", &stdout)?;
         Ok(())
     }
 
     #[test]
-    fn minify_dir_pattern_files_respected() -> AnyResult<()> {
+    fn minify_only_function_leaves_other_functions_unchanged() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        fs::create_dir_all(&input_dir)?;
-        fs::write(
-            input_dir.join("alpha.py"),
-            "def foo(value):\n    temp = value + 1\n    return temp\n",
-        )?;
+        let file_path = tmp.path().join("pkg_mod.py");
+        let untouched =
+            "def first(value):\n    \"\"\"Kept.\"\"\"\n    temp = value + 1\n    return temp\n";
         fs::write(
-            input_dir.join("beta.py"),
-            "def bar(value):\n    temp = value + 2\n    return temp\n",
+            &file_path,
+            format!("{untouched}\n\nclass Box:\n    def second(self, amount):\n        total = amount * 2\n        return total\n"),
         )?;
 
-        let include_file = tmp.path().join("includes.txt");
-        fs::write(&include_file, "*.py\n")?;
-        let exclude_file = tmp.path().join("excludes.txt");
-        fs::write(&exclude_file, "alpha*.py\n")?;
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--only-function")
+            .arg("pkg_mod.Box.second")
+            .arg("--stdout")
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.starts_with(untouched));
+        assert!(!stdout.contains("amount"));
+        assert!(!stdout.contains("total"));
 
-        let output_dir = tmp.path().join("out");
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            include_file: Some(include_file.clone()),
-            exclude_file: Some(exclude_file.clone()),
-            quiet: true,
-            ..Default::default()
-        };
+        let json_path = tmp.path().join("stats.json");
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--only-function")
+            .arg("first")
+            .arg("--stats")
+            .arg("--dry-run")
+            .arg("--output-json")
+            .arg(&json_path)
+            .output()?;
+        assert!(output.status.success());
+        let stats: serde_json::Value = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(stats["total_renames"], 2);
+
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--only-function")
+            .arg("Box.missing")
+            .output()?;
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(stderr.contains("available: first, Box.second"));
+        Ok(())
+    }
 
-        let stats = run_minify_dir(
-            &input_dir,
-            Some(output_dir.clone()),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )?;
+    #[test]
+    fn minify_stdin_stdout_rewrites() -> AnyResult<()> {
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
 
-        assert_eq!(stats.processed, 1);
-        assert!(output_dir.join("beta.py").exists());
-        assert!(!output_dir.join("alpha.py").exists());
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg("stdin.py")
+            .arg("--stdin")
+            .arg("--stdout")
+            .write_stdin(source)
+            .output()?;
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("def foo(a):"));
+        assert!(!stdout.contains("value"));
+        assert!(!stdout.contains("Processed"));
         Ok(())
     }
 
     #[test]
-    fn minify_file_output_json_writes_file() -> AnyResult<()> {
+    fn minify_file_reasons_noop() -> AnyResult<()> {
         let tmp = tempdir()?;
         let file_path = tmp.path().join("example.py");
-        fs::write(
-            &file_path,
-            "def foo(value):\n    temp = value + 1\n    return temp\n",
-        )?;
+        fs::write(&file_path, "def foo():\n    return 42\n")?;
 
-        let json_path = tmp.path().join("file.json");
+        let json_path = tmp.path().join("reasons.json");
         let (stats, _) = minify_file(
             &file_path,
             false,
@@ -4286,446 +14027,1225 @@ def sample(value):
             false,
         )?;
 
+        assert_eq!(stats.reasons.get("no_renames"), Some(&1));
+
         let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
-        assert_eq!(written.processed, stats.processed);
-        assert_eq!(written.rewritten, stats.rewritten);
+        assert_eq!(written.reasons.get("no_renames"), Some(&1));
+
         Ok(())
     }
 
-    #[cfg(unix)]
     #[test]
-    fn minify_file_output_json_unwritable_parent_fails() -> AnyResult<()> {
-        use std::os::unix::fs::PermissionsExt;
-
+    fn minify_file_preserves_encoding_cookie() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
-        fs::write(
-            &file_path,
-            "def foo(value):\n    temp = value + 1\n    return temp\n",
-        )?;
-
-        let reports_dir = tmp.path().join("reports");
-        fs::create_dir(&reports_dir)?;
-        let mut perms = fs::metadata(&reports_dir)?.permissions();
-        perms.set_mode(0o500);
-        fs::set_permissions(&reports_dir, perms.clone())?;
+        let file_path = tmp.path().join("latin.py");
+        let source = "# -*- coding: latin-1 -*-\n\nmsg = \"café\"\n\ndef foo(value):\n    temp = value + 1\n    return msg\n";
+        let encoding = Encoding::for_label(b"iso-8859-1").expect("latin-1 encoding");
+        let (encoded, output_enc, had_errors) = encoding.encode(source);
+        assert!(!had_errors);
+        assert!(std::ptr::eq(output_enc, encoding));
+        match encoded {
+            Cow::Borrowed(bytes) => fs::write(&file_path, bytes)?,
+            Cow::Owned(buffer) => fs::write(&file_path, buffer)?,
+        }
 
-        let output = cli_cmd()?
-            .arg("minify")
-            .arg(file_path.to_str().unwrap())
-            .arg("--stats")
-            .arg("--output-json")
-            .arg(reports_dir.join("minify.json").to_str().unwrap())
-            .output()?;
+        let (stats, _) = minify_file(
+            &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
+            false,
+        )?;
+        assert_eq!(stats.rewritten, 1);
 
-        perms.set_mode(0o700);
-        fs::set_permissions(&reports_dir, perms)?;
+        let bytes_after = fs::read(&file_path)?;
+        let (decoded, had_decode_errors) = encoding.decode_without_bom_handling(&bytes_after);
+        assert!(!had_decode_errors);
+        let text = decoded.into_owned();
+        assert!(text.lines().next().unwrap().contains("coding: latin-1"));
+        assert!(text.contains("café"));
 
-        assert!(!output.status.success());
-        assert!(!reports_dir.join("minify.json").exists());
         Ok(())
     }
 
-    #[cfg(unix)]
     #[test]
-    fn minify_dir_output_json_unwritable_parent_fails() -> AnyResult<()> {
-        use std::os::unix::fs::PermissionsExt;
+    fn lossy_decode_replaces_invalid_bytes_and_updates_the_cookie() -> AnyResult<()> {
+        // Saved as latin-1 but decoded as UTF-8, where a lone 0xE9 is invalid.
+        let corrupt = b"# -*- coding: latin-1 -*-\nmsg = \"caf\xe9\"\n";
+        let utf8 = Encoding::for_label(b"utf-8");
+        let forced = |forced, lossy| DecodeOptions { forced, lossy };
+
+        let err = decode_python_bytes(corrupt, "legacy.py", forced(utf8, false))
+            .expect_err("strict decoding must fail");
+        assert!(err.to_string().contains("--decode lossy"), "{err}");
+
+        let (text, metadata) = decode_python_bytes(corrupt, "legacy.py", forced(utf8, true))?;
+        assert_eq!(text, "# -*- coding: utf-8 -*-\nmsg = \"caf\u{fffd}\"\n");
+        assert!(metadata.lossy);
+        assert!(std::ptr::eq(metadata.encoding.unwrap(), UTF_8));
+
+        // Without an override the cookie is honoured and nothing is lost.
+        let (text, metadata) = decode_python_bytes(corrupt, "legacy.py", DecodeOptions::default())?;
+        assert!(text.contains("café"));
+        assert!(!metadata.lossy);
+        Ok(())
+    }
 
+    #[test]
+    fn minify_dir_lossy_decode_marks_files_in_stats() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
         fs::write(
-            input_dir.join("example.py"),
+            input_dir.join("legacy.py"),
+            b"def greet(name):\n    message = \"caf\xe9 \" + name\n    return message\n",
+        )?;
+        fs::write(
+            input_dir.join("clean.py"),
             "def foo(value):\n    temp = value + 1\n    return temp\n",
         )?;
+        let run = |out: &str, lossy: bool| -> AnyResult<(std::process::Output, PathBuf)> {
+            let json_path = tmp.path().join(format!("{out}.json"));
+            let mut cmd = cli_cmd()?;
+            cmd.arg("minify-dir")
+                .arg(input_dir.to_str().unwrap())
+                .arg("--out-dir")
+                .arg(tmp.path().join(out).to_str().unwrap())
+                .arg("--stats")
+                .arg("--output-json")
+                .arg(json_path.to_str().unwrap());
+            if lossy {
+                cmd.arg("--decode").arg("lossy");
+            }
+            Ok((cmd.output()?, json_path))
+        };
 
-        let reports_dir = tmp.path().join("reports");
-        fs::create_dir(&reports_dir)?;
-        let mut perms = fs::metadata(&reports_dir)?.permissions();
-        perms.set_mode(0o555);
-        fs::set_permissions(&reports_dir, perms.clone())?;
+        let (output, json_path) = run("strict", false)?;
+        assert!(output.status.success());
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.reasons.get("read_error"), Some(&1));
 
-        let out_dir = tmp.path().join("out");
-        let output = cli_cmd()?
+        let (output, json_path) = run("lossy", true)?;
+        assert!(output.status.success());
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.reasons.get("lossy_decode"), Some(&1));
+        let file = |name: &str| stats.files.iter().find(|file| file.path == name).unwrap();
+        assert!(file("legacy.py").lossy_decode);
+        assert_eq!(file("legacy.py").status, "minified");
+        assert!(!file("clean.py").lossy_decode);
+
+        let written = fs::read_to_string(tmp.path().join("lossy/legacy.py"))?;
+        assert!(written.contains("caf\u{fffd} "));
+        assert!(!written.contains("message"));
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_parallel_output_keeps_each_file_together() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        for index in 0..12 {
+            let name = format!("mod_{index:02}.py");
+            let content: &[u8] = match index {
+                5 => b"def broken(:\n    pass\n",
+                8 => b"def greet(name):\n    message = \"caf\xe9 \" + name\n    return message\n",
+                _ => b"def foo(value):\n    temp = value + 1\n    return temp\n",
+            };
+            fs::write(input_dir.join(name), content)?;
+        }
+
+        let log_path = tmp.path().join("output.log");
+        let log = fs::File::create(&log_path)?;
+        let json_path = tmp.path().join("stats.json");
+        StdCommand::new(cli_binary_path())
             .arg("minify-dir")
-            .arg(input_dir.to_str().unwrap())
+            .arg(&input_dir)
             .arg("--out-dir")
-            .arg(out_dir.to_str().unwrap())
+            .arg(tmp.path().join("out"))
+            .arg("--jobs")
+            .arg("8")
+            .arg("--decode")
+            .arg("lossy")
             .arg("--stats")
             .arg("--output-json")
-            .arg(reports_dir.join("minify-dir.json").to_str().unwrap())
-            .output()?;
-
-        perms.set_mode(0o755);
-        fs::set_permissions(&reports_dir, perms)?;
-
-        assert!(!output.status.success());
-        assert!(!reports_dir.join("minify-dir.json").exists());
+            .arg(&json_path)
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .status()?;
+
+        let output = fs::read_to_string(&log_path)?;
+        let lines: Vec<&str> = output.lines().collect();
+        let position = |needle: &str| {
+            lines
+                .iter()
+                .position(|line| line.contains(needle))
+                .unwrap_or_else(|| panic!("missing {needle:?} in:\n{output}"))
+        };
+        let status = |index: usize| position(&format!("• mod_{index:02}.py →"));
+
+        // Statuses come out in path order whatever order the workers finished in, the
+        // parse failure included
+        let statuses: Vec<usize> = (0..12).map(status).collect();
+        assert!(statuses.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(lines[status(5)].contains("syntax error"));
+        // A worker's warning is replayed right before its file's status
+        let lossy_warning = position("replaced bytes");
+        assert!(lines[lossy_warning].contains("mod_08.py"));
+        assert_eq!(lossy_warning + 1, status(8));
+
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        let paths: Vec<&str> = stats.files.iter().map(|file| file.path.as_str()).collect();
+        let mut sorted = paths.clone();
+        sorted.sort_unstable();
+        assert_eq!(paths, sorted);
+        assert_eq!(paths.len(), 12);
         Ok(())
     }
 
     #[test]
-    fn minify_cli_output_json_writes_file() -> AnyResult<()> {
+    fn minify_dir_takes_jobs_from_the_environment_unless_the_flag_is_given() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
         fs::write(
-            &file_path,
+            input_dir.join("example.py"),
             "def foo(value):\n    temp = value + 1\n    return temp\n",
         )?;
 
-        let json_path = tmp.path().join("cli.json");
-        let (stats, _) = minify(
-            &file_path,
-            false,
-            false,
-            None,
-            false,
-            false,
-            true,
-            Some(json_path.as_path()),
-            false,
-            false,
+        let effective_jobs = |name: &str, flag: Option<&str>| -> AnyResult<usize> {
+            let json_path = tmp.path().join(format!("{name}.json"));
+            let mut command = cli_cmd()?;
+            command
+                .env(JOBS_ENV, "3")
+                .arg("minify-dir")
+                .arg(&input_dir)
+                .arg("--out-dir")
+                .arg(tmp.path().join(name))
+                .arg("--stats")
+                .arg("--output-json")
+                .arg(&json_path);
+            if let Some(jobs) = flag {
+                command.arg("--jobs").arg(jobs);
+            }
+            command.assert().success();
+            let stats: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+            Ok(stats.effective_jobs)
+        };
+        assert_eq!(effective_jobs("from-env", None)?, 3);
+        assert_eq!(effective_jobs("from-flag", Some("2"))?, 2);
+
+        assert_eq!(jobs_from(Some(5), Some("3"))?, 5);
+        assert_eq!(jobs_from(None, Some(" 3 "))?, 3);
+        assert!(jobs_from(None, Some("0")).is_err());
+        assert!(jobs_from(None, Some("many")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn max_memory_caps_jobs_by_the_largest_files_in_flight() {
+        const MB: u64 = 1024 * 1024;
+        let candidates = |sizes: &[u64]| -> Vec<Candidate> {
+            sizes
+                .iter()
+                .enumerate()
+                .map(|(index, size)| Candidate {
+                    abs_path: PathBuf::from(format!("mod_{index}.py")),
+                    rel_path: PathBuf::from(format!("mod_{index}.py")),
+                    rel_norm: format!("mod_{index}.py"),
+                    size: size * MB,
+                })
+                .collect()
+        };
+        let large = candidates(&[10, 60, 60, 60, 60, 60, 60, 60]);
+
+        // 4x 60 MB per file in flight: two fit in 512 MB, a third does not.
+        let (jobs, reason) = cap_jobs_by_memory(8, &large, 512);
+        assert_eq!(jobs, 2);
+        assert_eq!(
+            reason.as_deref(),
+            Some("3 jobs would need about 720 MB for the largest files, over --max-memory 512 MB")
+        );
+        assert_eq!(cap_jobs_by_memory(8, &large, 0), (8, None));
+        assert_eq!(cap_jobs_by_memory(8, &large, 4096), (8, None));
+        assert_eq!(cap_jobs_by_memory(2, &large, 512), (2, None));
+
+        let (jobs, reason) = cap_jobs_by_memory(4, &candidates(&[200, 1]), 512);
+        assert_eq!(jobs, 1);
+        assert!(reason
+            .unwrap()
+            .starts_with("the largest file needs about 800 MB"));
+    }
+
+    #[test]
+    fn minify_file_preserves_utf8_bom() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("bom.py");
+        let source = b"\xEF\xBB\xBFdef foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(&file_path, source)?;
+
+        let (_stats, _) = minify_file(
+            &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
             false,
+        )?;
+
+        let bytes_after = fs::read(&file_path)?;
+        assert!(bytes_after.starts_with(b"\xEF\xBB\xBF"));
+
+        let text = String::from_utf8(bytes_after[3..].to_vec())?;
+        assert!(text.contains("def foo(a):"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn minify_file_preserves_utf16le_bom() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("bom_le.py");
+        let utf16: Vec<u8> = {
+            let mut bytes = vec![0xFF, 0xFE];
+            let content = "def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n";
+            for unit in content.encode_utf16() {
+                bytes.extend_from_slice(&unit.to_le_bytes());
+            }
+            bytes
+        };
+        fs::write(&file_path, &utf16)?;
+
+        let (_stats, _) = minify_file(
+            &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
             false,
-            3,
+        )?;
+
+        let bytes_after = fs::read(&file_path)?;
+        assert!(bytes_after.starts_with(&[0xFF, 0xFE]));
+        let decoded = UTF_16LE
+            .decode_without_bom_handling(&bytes_after)
+            .0
+            .into_owned();
+        assert!(decoded.contains("def foo(a):"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn minify_file_preserves_utf16be_bom() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("bom_be.py");
+        let utf16: Vec<u8> = {
+            let mut bytes = vec![0xFE, 0xFF];
+            let content = "def foo(value):\n    temp = value + 1\n    return temp\n";
+            for unit in content.encode_utf16() {
+                let be = unit.to_be_bytes();
+                bytes.extend_from_slice(&be);
+            }
+            bytes
+        };
+        fs::write(&file_path, &utf16)?;
+
+        let (_stats, _) = minify_file(
+            &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
             false,
         )?;
 
-        let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
-        assert_eq!(written.processed, stats.processed);
-        assert_eq!(written.rewritten, stats.rewritten);
+        let bytes_after = fs::read(&file_path)?;
+        assert!(bytes_after.starts_with(&[0xFE, 0xFF]));
+        let decoded = UTF_16BE
+            .decode_without_bom_handling(&bytes_after)
+            .0
+            .into_owned();
+        assert!(decoded.contains("def foo(a):"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_preserves_utf8_bom_and_crlf() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+
+        let mut bytes = b"\xEF\xBB\xBF".to_vec();
+        bytes.extend_from_slice(b"def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n");
+        fs::write(input_dir.join("example.py"), bytes)?;
+
+        let out_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_minify_dir(&input_dir, Some(out_dir.clone()), &[], &[], None, cfg)?;
+        assert_eq!(stats.rewritten, 1);
+
+        let output_bytes = fs::read(out_dir.join("example.py"))?;
+        assert!(output_bytes.starts_with(b"\xEF\xBB\xBF"));
+        assert!(output_bytes.windows(2).any(|w| w == b"\r\n"));
+        let decoded = String::from_utf8(output_bytes[3..].to_vec())?;
+        assert!(decoded.contains("def foo(a):"));
         Ok(())
     }
 
     #[test]
-    fn minify_cli_dry_run_no_write() -> AnyResult<()> {
+    fn repeated_dir_runs_write_byte_identical_artifacts() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
-        let original = "def foo(value):\n    temp = value + 1\n    return temp\n";
-        fs::write(&file_path, original)?;
+        let input_dir = tmp.path().join("src");
+        for (rel, body) in [
+            (
+                "top.py",
+                "def top(alpha, beta):\n    total = alpha + beta\n    return total\n",
+            ),
+            (
+                "pkg/a.py",
+                "def a(value):\n    doubled = value * 2\n    return doubled\n",
+            ),
+            (
+                "pkg/sub/b.py",
+                "def b(items):\n    count = len(items)\n    return count\n",
+            ),
+            ("pkg/sub/c.py", "X = 1\n"),
+        ] {
+            let path = input_dir.join(rel);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, body)?;
+        }
 
-        let output = cli_cmd()?
-            .arg("minify")
-            .arg(file_path.to_str().unwrap())
-            .arg("--in-place")
-            .arg("--dry-run")
-            .output()?;
-        assert!(output.status.success());
+        // Both runs write to the same places, since the summary records where diffs went.
+        let run = || -> AnyResult<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+            let stats_path = tmp.path().join("stats.json");
+            let diff_path = tmp.path().join("changes.patch");
+            let plan_path = tmp.path().join("plans.jsonl");
+            let out_dir = tmp.path().join("out");
+            if out_dir.exists() {
+                fs::remove_dir_all(&out_dir)?;
+            }
+            let cfg = MinifyDirTestCfg {
+                quiet: true,
+                show_stats: true,
+                jobs: Some(4),
+                output_json: Some(stats_path.clone()),
+                diff_out: Some(diff_path.clone()),
+                ..Default::default()
+            };
+            run_minify_dir(&input_dir, Some(out_dir), &[], &[], None, cfg)?;
+            minify_plan_dir(
+                &input_dir,
+                &plan_path,
+                &[],
+                None,
+                &[],
+                None,
+                Some(4),
+                false,
+                false,
+                None,
+                true,
+            )?;
+            Ok((
+                fs::read(stats_path)?,
+                fs::read(diff_path)?,
+                fs::read(plan_path)?,
+            ))
+        };
 
-        let after = fs::read_to_string(&file_path)?;
-        assert_eq!(after, original);
+        let first = run()?;
+        let second = run()?;
+        assert_eq!(first, second);
+
+        let (stats, _, plans) = &first;
+        let stats = String::from_utf8(stats.clone())?;
+        assert!(stats.ends_with("}\n"));
+        assert!(!stats.contains('\r'));
+        let summary: serde_json::Value = serde_json::from_str(&stats)?;
+        let paths: Vec<&str> = summary["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            paths,
+            ["pkg/a.py", "pkg/sub/b.py", "pkg/sub/c.py", "top.py"]
+        );
+        assert!(!String::from_utf8(plans.clone())?.contains("\\\\"));
         Ok(())
     }
 
     #[test]
-    fn minify_file_fail_on_change_exits_nonzero() -> AnyResult<()> {
+    fn timings_are_reported_only_when_requested() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
         fs::write(
-            &file_path,
-            "def foo(value):\n    temp = value + 1\n    return temp\n",
+            input_dir.join("pkg/a.py"),
+            "def a(value):\n    doubled = value * 2\n    return doubled\n",
         )?;
+        fs::write(input_dir.join("b.py"), "X = 1\n")?;
+
+        let run = |name: &str, extra: &[&str]| -> AnyResult<(serde_json::Value, String)> {
+            let json_path = tmp.path().join(format!("{name}.json"));
+            let output = cli_cmd()?
+                .arg("minify-dir")
+                .arg(&input_dir)
+                .arg("--out-dir")
+                .arg(tmp.path().join(name))
+                .arg("--stats")
+                .arg("--output-json")
+                .arg(&json_path)
+                .args(extra)
+                .output()?;
+            assert!(output.status.success());
+            Ok((
+                serde_json::from_str(&fs::read_to_string(json_path)?)?,
+                String::from_utf8(output.stdout)?,
+            ))
+        };
 
-        let output = cli_cmd()?
-            .arg("minify")
-            .arg(file_path.to_str().unwrap())
-            .arg("--fail-on-change")
-            .output()?;
-        assert!(!output.status.success());
-        assert_eq!(output.status.code(), Some(4));
+        let (plain, stdout) = run("plain", &[])?;
+        assert!(plain.get("timings").is_none());
+        assert!(plain["files"][0].get("elapsed_ms").is_none());
+        assert!(!stdout.contains("Timings:"));
+
+        let (timed, stdout) = run("timed", &["--timings"])?;
+        let timings = &timed["timings"];
+        for field in ["total_ms", "walk_ms", "plan_rewrite_ms", "write_ms"] {
+            assert!(timings[field].as_u64().is_some(), "{field}");
+        }
+        let slowest = timings["slowest"].as_array().unwrap();
+        assert_eq!(slowest.len(), 2);
+        assert!(slowest[0]["elapsed_ms"].as_u64() >= slowest[1]["elapsed_ms"].as_u64());
+        for file in timed["files"].as_array().unwrap() {
+            assert!(file["elapsed_ms"].as_u64().is_some());
+        }
+        assert!(stdout.contains("Timings: walk "));
         Ok(())
     }
 
     #[test]
-    fn minify_file_fail_on_bailout_exits_nonzero() -> AnyResult<()> {
+    fn minify_dir_copies_skipped_files_byte_for_byte() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
-        fs::write(
-            &file_path,
-            "def foo(values):\n    squared = [v * v for v in values]\n    return squared\n",
-        )?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
 
-        let output = cli_cmd()?
-            .arg("minify")
-            .arg(file_path.to_str().unwrap())
-            .arg("--fail-on-bailout")
-            .output()?;
+        let mixed: &[u8] = b"X = 1\r\nY = 2\nZ = 3\r\n";
+        let latin: &[u8] = b"# -*- coding: latin-1 -*-\nmsg = \"caf\xe9\"\n\ndef outer(value):\n    def inner():\n        return value\n    return inner\n";
+        fs::write(input_dir.join("mixed.py"), mixed)?;
+        fs::write(input_dir.join("latin.py"), latin)?;
 
-        assert!(!output.status.success());
-        assert_eq!(output.status.code(), Some(2));
+        let out_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_minify_dir(&input_dir, Some(out_dir.clone()), &[], &[], None, cfg)?;
+        assert_eq!(stats.rewritten, 0);
+        assert_eq!(stats.reasons.get("no_renames"), Some(&1));
+        assert_eq!(stats.reasons.get("nested_scopes"), Some(&1));
+
+        assert_eq!(fs::read(out_dir.join("mixed.py"))?, mixed);
+        assert_eq!(fs::read(out_dir.join("latin.py"))?, latin);
         Ok(())
     }
 
     #[test]
-    #[cfg(unix)]
-    fn minify_file_fail_on_error_exits_nonzero() -> AnyResult<()> {
+    fn minify_dir_preserves_utf16le_bom_and_crlf() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
-        fs::write(
-            &file_path,
-            "def foo(value):\n    temp = value + 1\n    return temp\n",
-        )?;
-        let mut perms = fs::metadata(&file_path)?.permissions();
-        let mut readonly = perms.clone();
-        readonly.set_mode(0o444);
-        fs::set_permissions(&file_path, readonly)?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
 
-        let output = cli_cmd()?
-            .arg("minify")
-            .arg(file_path.to_str().unwrap())
-            .arg("--in-place")
-            .arg("--fail-on-error")
-            .output()?;
+        let mut bytes = vec![0xFF, 0xFE];
+        let content = "def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n";
+        for unit in content.encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        fs::write(input_dir.join("example.py"), &bytes)?;
 
-        perms.set_mode(0o644);
-        fs::set_permissions(&file_path, perms)?;
+        let out_dir = tmp.path().join("out");
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_minify_dir(&input_dir, Some(out_dir.clone()), &[], &[], None, cfg)?;
+        assert_eq!(stats.rewritten, 1);
 
-        assert!(!output.status.success());
-        assert_eq!(output.status.code(), Some(1));
+        let output_bytes = fs::read(out_dir.join("example.py"))?;
+        assert!(output_bytes.starts_with(&[0xFF, 0xFE]));
+        assert!(output_bytes
+            .windows(4)
+            .any(|w| w == [0x0D, 0x00, 0x0A, 0x00]));
+        let decoded = UTF_16LE
+            .decode_without_bom_handling(&output_bytes)
+            .0
+            .into_owned();
+        assert!(decoded.contains("def foo(a):"));
         Ok(())
     }
 
     #[test]
-    fn minify_stdin_stdout_rewrites() -> AnyResult<()> {
-        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+    fn apply_plan_dir_preserves_bom_and_crlf() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
 
-        let output = cli_cmd()?
-            .arg("minify")
-            .arg("stdin.py")
-            .arg("--stdin")
-            .arg("--stdout")
-            .write_stdin(source)
-            .output()?;
+        let mut utf8_bom = b"\xEF\xBB\xBF".to_vec();
+        utf8_bom
+            .extend_from_slice(b"def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n");
+        fs::write(input_dir.join("utf8.py"), utf8_bom)?;
 
-        assert!(output.status.success());
-        let stdout = String::from_utf8(output.stdout)?;
-        assert!(stdout.contains("def foo(a):"));
-        assert!(!stdout.contains("value"));
-        assert!(!stdout.contains("Processed"));
+        let mut utf16le_bom = vec![0xFF, 0xFE];
+        let utf16_content = "def bar(value):\r\n    temp = value + 2\r\n    return temp\r\n";
+        for unit in utf16_content.encode_utf16() {
+            utf16le_bom.extend_from_slice(&unit.to_le_bytes());
+        }
+        fs::write(input_dir.join("utf16.py"), &utf16le_bom)?;
+
+        let plan_path = tmp.path().join("plan.json");
+        minify_plan_dir_with_depth(
+            &input_dir,
+            &plan_path,
+            &[],
+            None,
+            &[],
+            None,
+            None,
+            false,
+            false,
+            None,
+            None,
+            false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
+            true,
+            false,
+            None,
+            &CommandContext::default(),
+        )?;
+
+        let out_dir = tmp.path().join("out");
+        let cfg = ApplyPlanDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_apply_plan_dir(
+            &input_dir,
+            &plan_path,
+            Some(out_dir.clone()),
+            &[],
+            &[],
+            None,
+            cfg,
+        )?;
+        assert_eq!(stats.rewritten, 2);
+
+        let utf8_bytes = fs::read(out_dir.join("utf8.py"))?;
+        assert!(utf8_bytes.starts_with(b"\xEF\xBB\xBF"));
+        assert!(utf8_bytes.windows(2).any(|w| w == b"\r\n"));
+        let utf8_decoded = String::from_utf8(utf8_bytes[3..].to_vec())?;
+        assert!(utf8_decoded.contains("def foo(a):"));
+
+        let utf16_bytes = fs::read(out_dir.join("utf16.py"))?;
+        assert!(utf16_bytes.starts_with(&[0xFF, 0xFE]));
+        assert!(utf16_bytes
+            .windows(4)
+            .any(|w| w == [0x0D, 0x00, 0x0A, 0x00]));
+        let utf16_decoded = UTF_16LE
+            .decode_without_bom_handling(&utf16_bytes)
+            .0
+            .into_owned();
+        assert!(utf16_decoded.contains("def bar(a):"));
         Ok(())
     }
 
     #[test]
-    fn minify_file_reasons_noop() -> AnyResult<()> {
+    fn apply_plan_dir_match_by_module_follows_moved_files() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
-        fs::write(&file_path, "def foo():\n    return 42\n")?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        for name in ["helpers.py", "keep.py", "a-b.py", "a_b.py"] {
+            fs::write(input_dir.join(name), source)?;
+        }
 
-        let json_path = tmp.path().join("reasons.json");
-        let (stats, _) = minify_file(
-            &file_path,
+        let plan_path = tmp.path().join("plan.json");
+        minify_plan_dir_with_depth(
+            &input_dir,
+            &plan_path,
+            &[],
+            None,
+            &[],
+            None,
+            None,
             false,
             false,
             None,
+            None,
             false,
             false,
-            true,
-            Some(json_path.as_path()),
             false,
             false,
+            &[],
             false,
+            None,
             false,
-            3,
+            true,
             false,
+            None,
+            &CommandContext::default(),
         )?;
+        let bundle = fs::read_to_string(&plan_path)?;
+        assert!(bundle.contains("\"module\":\"helpers\""));
+
+        // `helpers` becomes a package; both `a_b` plans now compete for one file
+        fs::create_dir_all(input_dir.join("helpers"))?;
+        fs::rename(
+            input_dir.join("helpers.py"),
+            input_dir.join("helpers/__init__.py"),
+        )?;
+        fs::remove_file(input_dir.join("a-b.py"))?;
+        fs::remove_file(input_dir.join("a_b.py"))?;
+        fs::create_dir_all(input_dir.join("a_b"))?;
+        fs::write(input_dir.join("a_b/__init__.py"), source)?;
 
-        assert_eq!(stats.reasons.get("no_renames"), Some(&1));
-
-        let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
-        assert_eq!(written.reasons.get("no_renames"), Some(&1));
+        let by_path = run_apply_plan_dir(
+            &input_dir,
+            &plan_path,
+            Some(tmp.path().join("by_path")),
+            &[],
+            &[],
+            None,
+            ApplyPlanDirTestCfg {
+                quiet: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(by_path.rewritten, 1);
+        assert_eq!(by_path.unmatched_plans.len(), 3);
 
+        let out_dir = tmp.path().join("by_module");
+        let stats = run_apply_plan_dir(
+            &input_dir,
+            &plan_path,
+            Some(out_dir.clone()),
+            &[],
+            &[],
+            None,
+            ApplyPlanDirTestCfg {
+                quiet: true,
+                match_by_module: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(stats.rewritten, 2);
+        assert_eq!(stats.bailouts, 1);
+        assert_eq!(stats.reasons.get("ambiguous_plan"), Some(&1));
+        assert_eq!(stats.unmatched_plans, vec!["a-b.py", "a_b.py"]);
+        let moved = fs::read_to_string(out_dir.join("helpers/__init__.py"))?;
+        assert!(moved.contains("def foo(a):"));
+        assert_eq!(fs::read_to_string(out_dir.join("a_b/__init__.py"))?, source);
         Ok(())
     }
 
     #[test]
-    fn minify_file_preserves_encoding_cookie() -> AnyResult<()> {
+    fn preserves_crlf_after_rewrite() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("latin.py");
-        let source = "# -*- coding: latin-1 -*-\n\nmsg = \"café\"\n\ndef foo(value):\n    temp = value + 1\n    return msg\n";
-        let encoding = Encoding::for_label(b"iso-8859-1").expect("latin-1 encoding");
-        let (encoded, output_enc, had_errors) = encoding.encode(source);
-        assert!(!had_errors);
-        assert!(std::ptr::eq(output_enc, encoding));
-        match encoded {
-            Cow::Borrowed(bytes) => fs::write(&file_path, bytes)?,
-            Cow::Owned(buffer) => fs::write(&file_path, buffer)?,
-        }
+        let file_path = tmp.path().join("crlf.py");
+        let source = "def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n";
+        fs::write(&file_path, source)?;
 
-        let (stats, _) = minify_file(
+        let _ = minify_file(
             &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
             false,
         )?;
-        assert_eq!(stats.rewritten, 1);
 
         let bytes_after = fs::read(&file_path)?;
-        let (decoded, had_decode_errors) = encoding.decode_without_bom_handling(&bytes_after);
-        assert!(!had_decode_errors);
-        let text = decoded.into_owned();
-        assert!(text.lines().next().unwrap().contains("coding: latin-1"));
-        assert!(text.contains("café"));
+        for (idx, byte) in bytes_after.iter().enumerate() {
+            if *byte == b'\n' {
+                assert!(idx > 0 && bytes_after[idx - 1] == b'\r');
+            }
+        }
 
         Ok(())
     }
 
     #[test]
-    fn minify_file_preserves_utf8_bom() -> AnyResult<()> {
+    fn preserves_missing_final_newline() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("bom.py");
-        let source = b"\xEF\xBB\xBFdef foo(value):\n    temp = value + 1\n    return temp\n";
+        let file_path = tmp.path().join("no_newline.py");
+        let source = "def foo(value):\n    temp = value + 1\n    return temp";
         fs::write(&file_path, source)?;
 
-        let (_stats, _) = minify_file(
+        let _ = minify_file(
             &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
             false,
         )?;
 
         let bytes_after = fs::read(&file_path)?;
-        assert!(bytes_after.starts_with(b"\xEF\xBB\xBF"));
-
-        let text = String::from_utf8(bytes_after[3..].to_vec())?;
-        assert!(text.contains("def foo(a):"));
+        if let Some(last) = bytes_after.last() {
+            assert!(*last != b'\n' && *last != b'\r');
+        }
 
         Ok(())
     }
 
     #[test]
-    fn minify_file_preserves_utf16le_bom() -> AnyResult<()> {
+    fn minify_dir_output_json_writes_file() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("bom_le.py");
-        let utf16: Vec<u8> = {
-            let mut bytes = vec![0xFF, 0xFE];
-            let content = "def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n";
-            for unit in content.encode_utf16() {
-                bytes.extend_from_slice(&unit.to_le_bytes());
-            }
-            bytes
-        };
-        fs::write(&file_path, &utf16)?;
-
-        let (_stats, _) = minify_file(
-            &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
-            false,
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def foo(x):\n    y = x + 1\n    return y\n",
         )?;
 
-        let bytes_after = fs::read(&file_path)?;
-        assert!(bytes_after.starts_with(&[0xFF, 0xFE]));
-        let decoded = UTF_16LE
-            .decode_without_bom_handling(&bytes_after)
-            .0
-            .into_owned();
-        assert!(decoded.contains("def foo(a):"));
+        let output_dir = tmp.path().join("out");
+        let json_path = tmp.path().join("dir.json");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = MinifyDirTestCfg {
+            quiet: true,
+            output_json: Some(json_path.clone()),
+            ..Default::default()
+        };
+        let stats = run_minify_dir(
+            &input_dir,
+            Some(output_dir),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )?;
 
+        let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(written.processed, stats.processed);
+        assert_eq!(written.rewritten, stats.rewritten);
         Ok(())
     }
 
     #[test]
-    fn minify_file_preserves_utf16be_bom() -> AnyResult<()> {
-        let tmp = tempdir()?;
-        let file_path = tmp.path().join("bom_be.py");
-        let utf16: Vec<u8> = {
-            let mut bytes = vec![0xFE, 0xFF];
-            let content = "def foo(value):\n    temp = value + 1\n    return temp\n";
-            for unit in content.encode_utf16() {
-                let be = unit.to_be_bytes();
-                bytes.extend_from_slice(&be);
-            }
-            bytes
+    fn summarize_groups_by_leading_directories_and_ranks_savings() {
+        let file = |path: &str, status: &str, renames: usize, bytes_saved: i64| FileStats {
+            path: path.to_string(),
+            renames,
+            status: status.to_string(),
+            bytes_saved,
+            skipped_functions: Vec::new(),
+            functions: None,
+            warnings: Vec::new(),
+            lossy_decode: false,
+            fuzzy_matched: Vec::new(),
+            elapsed_ms: None,
+            dead_assignment_bytes: 0,
+            annotation_bytes: 0,
         };
-        fs::write(&file_path, &utf16)?;
+        let mut stats = DirStats::default();
+        stats.files = vec![
+            file("setup.py", "minified", 1, 4),
+            file("pkg/a.py", "minified", 3, 30),
+            file("pkg/sub/b.py", "minified", 2, 12),
+            file("pkg/sub/c.py", "skipped (no renames)", 0, 0),
+            file("tools/d.py", "minified", 2, 30),
+        ];
+
+        stats.summarize(Some(1), Some(2));
+        let rows: Vec<(&str, usize, usize, usize, i64)> = stats
+            .by_dir
+            .iter()
+            .map(|row| {
+                (
+                    row.dir.as_str(),
+                    row.processed,
+                    row.rewritten,
+                    row.renames,
+                    row.bytes_saved,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (".", 1, 1, 1, 4),
+                ("pkg", 3, 2, 5, 42),
+                ("tools", 1, 1, 2, 30)
+            ]
+        );
+        let top: Vec<&str> = stats.top_files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(top, vec!["pkg/a.py", "tools/d.py"]);
 
-        let (_stats, _) = minify_file(
-            &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
-            false,
+        stats.summarize(Some(2), None);
+        let dirs: Vec<&str> = stats.by_dir.iter().map(|row| row.dir.as_str()).collect();
+        assert_eq!(dirs, vec![".", "pkg", "pkg/sub", "tools"]);
+        assert!(stats.top_files.is_empty());
+    }
+
+    #[test]
+    fn stats_diff_flags_new_bailouts_and_minified_files_that_now_bail_out() {
+        let file = |path: &str, status: &str| FileStats {
+            path: path.to_string(),
+            renames: 0,
+            status: status.to_string(),
+            bytes_saved: 0,
+            skipped_functions: Vec::new(),
+            functions: None,
+            warnings: Vec::new(),
+            lossy_decode: false,
+            fuzzy_matched: Vec::new(),
+            elapsed_ms: None,
+            dead_assignment_bytes: 0,
+            annotation_bytes: 0,
+        };
+        let mut old = DirStats::default();
+        old.processed = 3;
+        old.rewritten = 2;
+        old.total_renames = 5;
+        old.files = vec![
+            file("a.py", "minified"),
+            file("b.py", "minified"),
+            file("c.py", "skipped (no renames)"),
+        ];
+        let mut new = DirStats::default();
+        new.processed = 3;
+        new.rewritten = 1;
+        new.bailouts = 1;
+        new.total_renames = 2;
+        new.files = vec![
+            file("a.py", "minified"),
+            file("b.py", "skipped (rewrite aborted)"),
+            file("d.py", "minified"),
+        ];
+
+        let diff = diff_stats(&old, &new);
+        let deltas: Vec<(&str, i64)> = diff.totals.iter().map(|t| (t.name, t.delta)).collect();
+        assert_eq!(
+            deltas,
+            vec![
+                ("processed", 0),
+                ("rewritten", -1),
+                ("bailouts", 1),
+                ("errors", 0),
+                ("total_renames", -3)
+            ]
+        );
+        assert!(diff.files_compared);
+        let changes: Vec<(&str, Option<&str>, Option<&str>, bool)> = diff
+            .changed_files
+            .iter()
+            .map(|c| {
+                (
+                    c.path.as_str(),
+                    c.old.as_deref(),
+                    c.new.as_deref(),
+                    c.regressed,
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    "b.py",
+                    Some("minified"),
+                    Some("skipped (rewrite aborted)"),
+                    true
+                ),
+                ("c.py", Some("skipped (no renames)"), None, false),
+                ("d.py", None, Some("minified"), false),
+            ]
+        );
+        assert_eq!(
+            diff.regressions,
+            vec![
+                "bailouts increased from 0 to 1".to_string(),
+                "b.py: minified → skipped (rewrite aborted)".to_string()
+            ]
+        );
+        assert!(diff
+            .render()
+            .contains("b.py: minified → skipped (rewrite aborted) [regression]"));
+    }
+
+    #[test]
+    fn stats_diff_without_file_lists_compares_totals_only() {
+        let mut old = DirStats::default();
+        old.processed = 4;
+        old.errors = 1;
+        let mut new = DirStats::default();
+        new.processed = 4;
+
+        let diff = diff_stats(&old, &new);
+        assert!(!diff.files_compared);
+        assert!(diff.changed_files.is_empty());
+        assert!(diff.regressions.is_empty());
+        assert!(diff.render().contains("Per-file statuses not compared"));
+    }
+
+    #[test]
+    fn self_test_bundled_corpus_passes() {
+        let results = run_self_test(&bundled_self_test_cases());
+        assert_eq!(results.len(), BUNDLED_SELF_TEST.len());
+        for result in &results {
+            assert!(result.failure.is_none(), "{}", render_self_test(&results));
+        }
+        let rendered = render_self_test(&results);
+        assert!(rendered.contains("match_statement"), "{rendered}");
+        assert!(rendered.contains("bail match_statement  ok"), "{rendered}");
+    }
+
+    #[test]
+    fn self_test_corpus_reports_mismatches_and_exits_non_zero() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let corpus = tmp.path().join("corpus");
+        fs::create_dir_all(&corpus)?;
+        fs::write(
+            corpus.join("renamed.py"),
+            "def foo(value):\n    result = value + 1\n    return result\n",
+        )?;
+        fs::write(
+            corpus.join("renamed.expected.py"),
+            "def foo(value):\n    result = value + 1\n    return result\n",
+        )?;
+        fs::write(
+            corpus.join("no_bail.py"),
+            "# self-test: bail comprehension\ndef foo(value):\n    return value\n",
         )?;
 
-        let bytes_after = fs::read(&file_path)?;
-        assert!(bytes_after.starts_with(&[0xFE, 0xFF]));
-        let decoded = UTF_16BE
-            .decode_without_bom_handling(&bytes_after)
-            .0
-            .into_owned();
-        assert!(decoded.contains("def foo(a):"));
+        let results = run_self_test(&load_self_test_corpus(&corpus, DecodeOptions::default())?);
+        let failures: Vec<(&str, &str)> = results
+            .iter()
+            .filter_map(|result| {
+                let name = Path::new(&result.name).file_name()?.to_str()?;
+                Some((name, result.failure.as_deref()?))
+            })
+            .collect();
+        assert_eq!(
+            failures,
+            vec![
+                ("no_bail.py", "rewrote without bailing out"),
+                ("renamed.py", "output differs from golden at line 1"),
+            ]
+        );
 
+        let output = cli_cmd()?
+            .arg("self-test")
+            .arg("--corpus")
+            .arg(&corpus)
+            .output()?;
+        assert!(!output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("13 cases, 2 failed"), "{stdout}");
         Ok(())
     }
 
     #[test]
-    fn minify_dir_preserves_utf8_bom_and_crlf() -> AnyResult<()> {
+    fn import_graph_reports_cycles_and_fails_on_them() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
+        fs::write(input_dir.join("a.py"), "import b\n")?;
+        fs::write(input_dir.join("b.py"), "import os\nfrom a import run\n")?;
+        fs::write(input_dir.join("c.py"), "import a\n")?;
 
-        let mut bytes = b"\xEF\xBB\xBF".to_vec();
-        bytes.extend_from_slice(b"def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n");
-        fs::write(input_dir.join("example.py"), bytes)?;
+        let output = cli_cmd()?
+            .arg("import-graph")
+            .arg(&input_dir)
+            .arg("--fail-on-cycles")
+            .output()?;
+        assert_eq!(output.status.code(), Some(1));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            stdout.contains("3 modules, 3 imports between them"),
+            "{stdout}"
+        );
+        assert!(stdout.contains("Import cycle 1: a, b\n"), "{stdout}");
+        assert!(stdout.contains("  b -> a (b.py:2)\n"), "{stdout}");
 
-        let out_dir = tmp.path().join("out");
-        let cfg = MinifyDirTestCfg {
-            quiet: true,
-            ..Default::default()
-        };
-        let stats = run_minify_dir(&input_dir, Some(out_dir.clone()), &[], &[], None, cfg)?;
-        assert_eq!(stats.rewritten, 1);
+        let output = cli_cmd()?
+            .arg("import-graph")
+            .arg(&input_dir)
+            .arg("--format")
+            .arg("json")
+            .arg("--max-depth-from")
+            .arg("b")
+            .output()?;
+        assert!(output.status.success());
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(json["modules"], serde_json::json!(["a", "b"]));
+        assert_eq!(json["cycles"][0]["modules"], serde_json::json!(["a", "b"]));
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_summary_by_dir_and_top_reach_output_json() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(
+            input_dir.join("pkg/big.py"),
+            "def total(values):\n    running_total = 0\n    for current_value in values:\n        running_total += current_value\n    return running_total\n",
+        )?;
+        fs::write(
+            input_dir.join("small.py"),
+            "def foo(value):\n    result = value + 1\n    return result\n",
+        )?;
+
+        let json_path = tmp.path().join("stats.json");
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(&input_dir)
+            .arg("--dry-run")
+            .arg("--stats")
+            .arg("--summary-by-dir")
+            .arg("--top")
+            .arg("1")
+            .arg("--output-json")
+            .arg(&json_path)
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("bytes saved"), "{stdout}");
+        assert!(stdout.contains("1. pkg/big.py"), "{stdout}");
+
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        let dirs: Vec<&str> = written["by_dir"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["dir"].as_str().unwrap())
+            .collect();
+        assert_eq!(dirs, vec![".", "pkg"]);
+        assert!(written["by_dir"][1]["bytes_saved"].as_i64().unwrap() > 0);
+        let top = written["top_files"].as_array().unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0]["path"], "pkg/big.py");
+
+        // A depth is only ever written `--summary-by-dir=N`, so the input directory right
+        // after the bare flag stays the positional argument.
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg("--dry-run")
+            .arg("--stats")
+            .arg("--summary-by-dir")
+            .arg(&input_dir)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg("--dry-run")
+            .arg("--stats")
+            .arg("--summary-by-dir=2")
+            .arg(&input_dir)
+            .output()?;
+        assert!(output.status.success());
+
+        cli_cmd()?
+            .arg("minify-dir")
+            .arg(&input_dir)
+            .arg("--dry-run")
+            .arg("--top")
+            .arg("1")
+            .assert()
+            .failure();
+        Ok(())
+    }
+
+    #[test]
+    fn apply_plan_file_output_json_writes_file() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(&file_path, source)?;
+
+        let module_name = "example";
+        let plan = Minifier::plan_from_source(module_name, source)?;
+        let plan_path = tmp.path().join("plan.json");
+        fs::write(&plan_path, serde_json::to_string(&plan)?)?;
+
+        let json_path = tmp.path().join("apply.json");
+        let (stats, _) = apply_plan(
+            &file_path,
+            &plan_path,
+            false,
+            false,
+            None,
+            false,
+            false,
+            true,
+            Some(json_path.as_path()),
+            false,
+            false,
+            false,
+            false,
+            3,
+            false,
+            None,
+            false,
+            None,
+            false,
+            &CommandContext::default(),
+        )?;
 
-        let output_bytes = fs::read(out_dir.join("example.py"))?;
-        assert!(output_bytes.starts_with(b"\xEF\xBB\xBF"));
-        assert!(output_bytes.windows(2).any(|w| w == b"\r\n"));
-        let decoded = String::from_utf8(output_bytes[3..].to_vec())?;
-        assert!(decoded.contains("def foo(a):"));
+        let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(written.processed, stats.processed);
+        assert_eq!(written.rewritten, stats.rewritten);
         Ok(())
     }
 
+    #[cfg(unix)]
     #[test]
-    fn minify_dir_preserves_utf16le_bom_and_crlf() -> AnyResult<()> {
+    fn apply_plan_file_output_json_unwritable_parent_fails() -> AnyResult<()> {
+        use std::os::unix::fs::PermissionsExt;
+
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        fs::create_dir_all(&input_dir)?;
+        let file_path = tmp.path().join("example.py");
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(&file_path, source)?;
 
-        let mut bytes = vec![0xFF, 0xFE];
-        let content = "def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n";
-        for unit in content.encode_utf16() {
-            bytes.extend_from_slice(&unit.to_le_bytes());
-        }
-        fs::write(input_dir.join("example.py"), &bytes)?;
+        let plan = Minifier::plan_from_source("example", source)?;
+        let plan_path = tmp.path().join("plan.json");
+        fs::write(&plan_path, serde_json::to_string(&plan)?)?;
 
-        let out_dir = tmp.path().join("out");
-        let cfg = MinifyDirTestCfg {
-            quiet: true,
-            ..Default::default()
-        };
-        let stats = run_minify_dir(&input_dir, Some(out_dir.clone()), &[], &[], None, cfg)?;
-        assert_eq!(stats.rewritten, 1);
+        let reports_dir = tmp.path().join("reports");
+        fs::create_dir(&reports_dir)?;
+        let mut perms = fs::metadata(&reports_dir)?.permissions();
+        perms.set_mode(0o500);
+        fs::set_permissions(&reports_dir, perms.clone())?;
 
-        let output_bytes = fs::read(out_dir.join("example.py"))?;
-        assert!(output_bytes.starts_with(&[0xFF, 0xFE]));
-        assert!(output_bytes
-            .windows(4)
-            .any(|w| w == [0x0D, 0x00, 0x0A, 0x00]));
-        let decoded = UTF_16LE
-            .decode_without_bom_handling(&output_bytes)
-            .0
-            .into_owned();
-        assert!(decoded.contains("def foo(a):"));
+        let output = cli_cmd()?
+            .arg("apply-plan")
+            .arg(file_path.to_str().unwrap())
+            .arg("--plan")
+            .arg(plan_path.to_str().unwrap())
+            .arg("--stats")
+            .arg("--output-json")
+            .arg(reports_dir.join("apply.json").to_str().unwrap())
+            .output()?;
+
+        perms.set_mode(0o700);
+        fs::set_permissions(&reports_dir, perms)?;
+
+        assert!(!output.status.success());
+        assert!(!reports_dir.join("apply.json").exists());
         Ok(())
     }
 
+    #[cfg(unix)]
     #[test]
-    fn apply_plan_dir_preserves_bom_and_crlf() -> AnyResult<()> {
+    fn apply_plan_dir_output_json_unwritable_parent_fails() -> AnyResult<()> {
+        use std::os::unix::fs::PermissionsExt;
+
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-
-        let mut utf8_bom = b"\xEF\xBB\xBF".to_vec();
-        utf8_bom
-            .extend_from_slice(b"def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n");
-        fs::write(input_dir.join("utf8.py"), utf8_bom)?;
-
-        let mut utf16le_bom = vec![0xFF, 0xFE];
-        let utf16_content = "def bar(value):\r\n    temp = value + 2\r\n    return temp\r\n";
-        for unit in utf16_content.encode_utf16() {
-            utf16le_bom.extend_from_slice(&unit.to_le_bytes());
-        }
-        fs::write(input_dir.join("utf16.py"), &utf16le_bom)?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
 
         let plan_path = tmp.path().join("plan.json");
         minify_plan_dir_with_depth(
@@ -4741,370 +15261,879 @@ def sample(value):
             None,
             None,
             false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
             true,
+            false,
+            None,
+            &CommandContext::default(),
         )?;
 
+        let reports_dir = tmp.path().join("reports");
+        fs::create_dir(&reports_dir)?;
+        let mut perms = fs::metadata(&reports_dir)?.permissions();
+        perms.set_mode(0o555);
+        fs::set_permissions(&reports_dir, perms.clone())?;
+
         let out_dir = tmp.path().join("out");
-        let cfg = ApplyPlanDirTestCfg {
-            quiet: true,
-            ..Default::default()
-        };
-        let stats = run_apply_plan_dir(
-            &input_dir,
-            &plan_path,
-            Some(out_dir.clone()),
-            &[],
-            &[],
-            None,
-            cfg,
-        )?;
-        assert_eq!(stats.rewritten, 2);
+        let output = cli_cmd()?
+            .arg("apply-plan-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--plan")
+            .arg(plan_path.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .arg("--stats")
+            .arg("--output-json")
+            .arg(reports_dir.join("apply-dir.json").to_str().unwrap())
+            .output()?;
 
-        let utf8_bytes = fs::read(out_dir.join("utf8.py"))?;
-        assert!(utf8_bytes.starts_with(b"\xEF\xBB\xBF"));
-        assert!(utf8_bytes.windows(2).any(|w| w == b"\r\n"));
-        let utf8_decoded = String::from_utf8(utf8_bytes[3..].to_vec())?;
-        assert!(utf8_decoded.contains("def foo(a):"));
+        perms.set_mode(0o755);
+        fs::set_permissions(&reports_dir, perms)?;
 
-        let utf16_bytes = fs::read(out_dir.join("utf16.py"))?;
-        assert!(utf16_bytes.starts_with(&[0xFF, 0xFE]));
-        assert!(utf16_bytes
-            .windows(4)
-            .any(|w| w == [0x0D, 0x00, 0x0A, 0x00]));
-        let utf16_decoded = UTF_16LE
-            .decode_without_bom_handling(&utf16_bytes)
-            .0
-            .into_owned();
-        assert!(utf16_decoded.contains("def bar(a):"));
+        assert!(!output.status.success());
+        assert!(!reports_dir.join("apply-dir.json").exists());
         Ok(())
     }
 
     #[test]
-    fn preserves_crlf_after_rewrite() -> AnyResult<()> {
+    fn apply_plan_stdin_and_plan_stdin_pipe() -> AnyResult<()> {
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        let plan = Minifier::plan_from_source("stdin", source)?;
+        let plan_json = serde_json::to_string(&plan)?;
+        let combined = format!("{source}\n{plan_json}");
+
+        let output = cli_cmd()?
+            .arg("apply-plan")
+            .arg("stdin.py")
+            .arg("--stdin")
+            .arg("--plan-stdin")
+            .write_stdin(combined)
+            .output()?;
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("def foo(a):"));
+        Ok(())
+    }
+
+    #[test]
+    fn minify_plan_stdin_combined_round_trips_through_apply_plan() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("crlf.py");
-        let source = "def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n";
+        let file_path = tmp.path().join("example.py");
+        let source = "CONFIG = {\"depth\": 2}\n\n\ndef foo(value):\n    temp = {\"key\": value + 1}\n    return temp\n";
         fs::write(&file_path, source)?;
 
-        let _ = minify_file(
-            &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
-            false,
-        )?;
+        let planned = cli_cmd()?
+            .arg("--quiet")
+            .arg("minify-plan")
+            .arg("--stdin")
+            .arg("--combined")
+            .write_stdin(source)
+            .output()?;
+        assert!(planned.status.success());
+        assert!(planned.stdout.starts_with(source.as_bytes()));
 
-        let bytes_after = fs::read(&file_path)?;
-        for (idx, byte) in bytes_after.iter().enumerate() {
-            if *byte == b'\n' {
-                assert!(idx > 0 && bytes_after[idx - 1] == b'\r');
-            }
-        }
+        let applied = cli_cmd()?
+            .arg("--quiet")
+            .arg("apply-plan")
+            .arg("stdin.py")
+            .arg("--stdin")
+            .arg("--plan-stdin")
+            .arg("--stdout")
+            .write_stdin(planned.stdout)
+            .output()?;
+        assert!(applied.status.success());
+
+        let minified = cli_cmd()?
+            .arg("--quiet")
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--stdout")
+            .output()?;
+        assert!(minified.status.success());
+        assert_eq!(
+            String::from_utf8(applied.stdout)?,
+            String::from_utf8(minified.stdout)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn minify_plan_stdin_uses_module_name_override() -> AnyResult<()> {
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+
+        let output = cli_cmd()?
+            .arg("minify-plan")
+            .arg("--stdin")
+            .write_stdin(source)
+            .output()?;
+        assert!(output.status.success());
+        let plan: MinifyPlan = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(plan.module, "stdin");
+
+        let output = cli_cmd()?
+            .arg("minify-plan")
+            .arg("--stdin")
+            .arg("--module-name")
+            .arg("pkg.example")
+            .write_stdin(source)
+            .output()?;
+        assert!(output.status.success());
+        let plan: MinifyPlan = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(plan.module, "pkg.example");
+        Ok(())
+    }
+
+    #[test]
+    fn apply_plan_file_reads_plan_from_dash() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(&file_path, source)?;
+
+        let plan = Minifier::plan_from_source("example", source)?;
+        let plan_json = serde_json::to_string(&plan)?;
+
+        let output = cli_cmd()?
+            .arg("apply-plan")
+            .arg(file_path.to_str().unwrap())
+            .arg("--plan")
+            .arg("-")
+            .write_stdin(plan_json)
+            .output()?;
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("def foo(a):"));
+        Ok(())
+    }
+
+    #[test]
+    fn apply_plan_file_fail_on_change_exits_nonzero() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("example.py");
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(&file_path, source)?;
+
+        let plan = Minifier::plan_from_source("module", source)?;
+        let plan_path = tmp.path().join("plan.json");
+        fs::write(&plan_path, serde_json::to_string(&plan)?)?;
+
+        let output = cli_cmd()?
+            .arg("apply-plan")
+            .arg(file_path.to_str().unwrap())
+            .arg("--plan")
+            .arg(plan_path.to_str().unwrap())
+            .arg("--fail-on-change")
+            .output()?;
 
+        assert!(!output.status.success());
+        assert_eq!(output.status.code(), Some(4));
         Ok(())
     }
 
     #[test]
-    fn preserves_missing_final_newline() -> AnyResult<()> {
+    fn apply_plan_cli_dry_run_no_write() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("no_newline.py");
-        let source = "def foo(value):\n    temp = value + 1\n    return temp";
+        let file_path = tmp.path().join("example.py");
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
         fs::write(&file_path, source)?;
 
-        let _ = minify_file(
-            &file_path, true, false, None, false, false, true, None, false, false, false, false, 3,
-            false,
-        )?;
+        let plan = Minifier::plan_from_source("module", source)?;
+        let plan_path = tmp.path().join("plan.json");
+        fs::write(&plan_path, serde_json::to_string(&plan)?)?;
 
-        let bytes_after = fs::read(&file_path)?;
-        if let Some(last) = bytes_after.last() {
-            assert!(*last != b'\n' && *last != b'\r');
-        }
+        let output = cli_cmd()?
+            .arg("apply-plan")
+            .arg(file_path.to_str().unwrap())
+            .arg("--plan")
+            .arg(plan_path.to_str().unwrap())
+            .arg("--in-place")
+            .arg("--dry-run")
+            .output()?;
+        assert!(output.status.success());
 
+        let after = fs::read_to_string(&file_path)?;
+        assert_eq!(after, source);
         Ok(())
     }
 
     #[test]
-    fn minify_dir_output_json_writes_file() -> AnyResult<()> {
+    fn apply_plan_stdin_stdout_rewrites() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        fs::create_dir_all(&input_dir)?;
-        fs::write(
-            input_dir.join("example.py"),
-            "def foo(x):\n    y = x + 1\n    return y\n",
-        )?;
+        let plan_path = tmp.path().join("plan.json");
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        let plan = Minifier::plan_from_source("module", source)?;
+        fs::write(&plan_path, serde_json::to_string(&plan)?)?;
 
-        let output_dir = tmp.path().join("out");
-        let json_path = tmp.path().join("dir.json");
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = MinifyDirTestCfg {
-            quiet: true,
-            output_json: Some(json_path.clone()),
-            ..Default::default()
-        };
-        let stats = run_minify_dir(
-            &input_dir,
-            Some(output_dir),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )?;
+        let output = cli_cmd()?
+            .arg("apply-plan")
+            .arg("stdin.py")
+            .arg("--plan")
+            .arg(plan_path.to_str().unwrap())
+            .arg("--stdin")
+            .arg("--stdout")
+            .write_stdin(source)
+            .output()?;
 
-        let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
-        assert_eq!(written.processed, stats.processed);
-        assert_eq!(written.rewritten, stats.rewritten);
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("def foo(a):"));
+        assert!(!stdout.contains("value"));
+        assert!(!stdout.contains("Processed"));
         Ok(())
     }
 
     #[test]
-    fn apply_plan_file_output_json_writes_file() -> AnyResult<()> {
+    fn apply_plan_rejects_invalid_plan_unless_partial_allowed() -> AnyResult<()> {
         let tmp = tempdir()?;
         let file_path = tmp.path().join("example.py");
         let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
         fs::write(&file_path, source)?;
 
-        let module_name = "example";
-        let plan = Minifier::plan_from_source(module_name, source)?;
+        let mut plan = Minifier::plan_from_source("example", source)?;
+        for entry in &mut plan.functions[0].renames {
+            if entry.original == "temp" {
+                entry.original = "tmep".to_string();
+            }
+        }
         let plan_path = tmp.path().join("plan.json");
         fs::write(&plan_path, serde_json::to_string(&plan)?)?;
 
-        let json_path = tmp.path().join("apply.json");
-        let (stats, _) = apply_plan(
-            &file_path,
-            &plan_path,
-            false,
-            false,
-            None,
-            false,
-            false,
-            true,
-            Some(json_path.as_path()),
-            false,
-            false,
-            false,
-            false,
-            3,
-            false,
-        )?;
+        let output = cli_cmd()?
+            .arg("apply-plan")
+            .arg(&file_path)
+            .arg("--plan")
+            .arg(&plan_path)
+            .output()?;
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(
+            stderr.contains("invalid plan for foo: `tmep` is not a local"),
+            "{stderr}"
+        );
+        assert!(stderr.contains("--allow-partial-plan"), "{stderr}");
 
-        let written: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
-        assert_eq!(written.processed, stats.processed);
-        assert_eq!(written.rewritten, stats.rewritten);
+        let output = cli_cmd()?
+            .arg("apply-plan")
+            .arg(&file_path)
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--stdout")
+            .arg("--allow-partial-plan")
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(
+            stdout.contains("def foo(a):\n    temp = a + 1\n"),
+            "{stdout}"
+        );
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(stderr.contains("rename skipped"), "{stderr}");
         Ok(())
     }
 
-    #[cfg(unix)]
     #[test]
-    fn apply_plan_file_output_json_unwritable_parent_fails() -> AnyResult<()> {
-        use std::os::unix::fs::PermissionsExt;
+    fn verify_rewritten_rejects_unparsable_output() -> AnyResult<()> {
+        let (_, metadata) = decode_python_bytes(b"x = 1\n", "test", DecodeOptions::default())?;
+        let path = Path::new("pkg/mod.py");
+        assert!(verify_rewritten(path, "def ok(a):\n    return a\n", &metadata, None).is_ok());
+
+        let message = verify_rewritten(path, "def broken(1x):\n    return 1x\n", &metadata, None)
+            .expect_err("invalid output must fail verification");
+        assert!(message.contains("pkg/mod.py"), "{message}");
+        assert!(message.contains("line 1"), "{message}");
+        Ok(())
+    }
+
+    #[test]
+    fn apply_plan_verify_exec_keeps_original_when_output_does_not_compile() -> AnyResult<()> {
+        if StdCommand::new("python3")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            eprintln!("skipping: python3 not available");
+            return Ok(());
+        }
 
         let tmp = tempdir()?;
         let file_path = tmp.path().join("example.py");
         let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
         fs::write(&file_path, source)?;
 
-        let plan = Minifier::plan_from_source("example", source)?;
+        // `__debug__ = ...` parses but CPython refuses to compile it.
+        let mut plan = Minifier::plan_from_source("example", source)?;
+        for entry in &mut plan.functions[0].renames {
+            if entry.original == "temp" {
+                entry.renamed = "__debug__".to_string();
+            }
+        }
         let plan_path = tmp.path().join("plan.json");
         fs::write(&plan_path, serde_json::to_string(&plan)?)?;
-
-        let reports_dir = tmp.path().join("reports");
-        fs::create_dir(&reports_dir)?;
-        let mut perms = fs::metadata(&reports_dir)?.permissions();
-        perms.set_mode(0o500);
-        fs::set_permissions(&reports_dir, perms.clone())?;
+        let stats_path = tmp.path().join("stats.json");
 
         let output = cli_cmd()?
             .arg("apply-plan")
-            .arg(file_path.to_str().unwrap())
+            .arg(&file_path)
             .arg("--plan")
-            .arg(plan_path.to_str().unwrap())
-            .arg("--stats")
+            .arg(&plan_path)
+            .arg("--in-place")
+            .arg("--verify-exec")
+            .arg("python3")
+            .arg("--fail-on-error")
             .arg("--output-json")
-            .arg(reports_dir.join("apply.json").to_str().unwrap())
+            .arg(&stats_path)
             .output()?;
 
-        perms.set_mode(0o700);
-        fs::set_permissions(&reports_dir, perms)?;
-
-        assert!(!output.status.success());
-        assert!(!reports_dir.join("apply.json").exists());
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(fs::read_to_string(&file_path)?, source);
+
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&stats_path)?)?;
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.verify_failures, 1);
+        assert_eq!(stats.rewritten, 0);
+        assert_eq!(stats.reasons.get("verify_failed"), Some(&1));
+        assert!(!stats.reasons.contains_key("rewrite_error"));
         Ok(())
     }
 
-    #[cfg(unix)]
     #[test]
-    fn apply_plan_dir_output_json_unwritable_parent_fails() -> AnyResult<()> {
-        use std::os::unix::fs::PermissionsExt;
+    fn minify_dir_verify_exec_writes_output_that_compiles() -> AnyResult<()> {
+        if StdCommand::new("python3")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            eprintln!("skipping: python3 not available");
+            return Ok(());
+        }
 
         let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        fs::create_dir_all(&input_dir)?;
+        let input = tmp.path().join("src");
+        fs::create_dir_all(&input)?;
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input.join("good.py"), source)?;
+        let out_dir = tmp.path().join("out");
+
+        let stats = run_minify_dir(
+            &input,
+            Some(out_dir.clone()),
+            &[],
+            &[],
+            None,
+            MinifyDirTestCfg {
+                quiet: true,
+                verify_exec: Some(PathBuf::from("python3")),
+                verify: true,
+                ..MinifyDirTestCfg::default()
+            },
+        )?;
+
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.verify_failures, 0);
+        assert!(fs::read_to_string(out_dir.join("good.py"))?.contains("def foo(a):"));
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_remove_unused_imports_counts_removals_and_skips_package_init() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input = tmp.path().join("src");
+        fs::create_dir_all(input.join("pkg"))?;
+        let init = "from .mod import helper\n";
+        fs::write(input.join("pkg/__init__.py"), init)?;
         fs::write(
-            input_dir.join("example.py"),
-            "def foo(value):\n    temp = value + 1\n    return temp\n",
+            input.join("pkg/mod.py"),
+            "import os\nimport sys\n\ndef helper(value):\n    temp = value + 1\n    return temp\n\nprint(sys.argv)\n",
         )?;
+        fs::write(input.join("pkg/consts.py"), "import json\nLIMIT = 3\n")?;
+        let out_dir = tmp.path().join("out");
 
-        let plan_path = tmp.path().join("plan.json");
-        minify_plan_dir_with_depth(
-            &input_dir,
-            &plan_path,
+        let stats = run_minify_dir(
+            &input,
+            Some(out_dir.clone()),
             &[],
-            None,
             &[],
             None,
+            MinifyDirTestCfg {
+                quiet: true,
+                remove_unused_imports: true,
+                ..MinifyDirTestCfg::default()
+            },
+        )?;
+
+        assert_eq!(stats.imports_removed, 2);
+        assert_eq!(stats.rewritten, 2);
+        assert_eq!(fs::read_to_string(out_dir.join("pkg/__init__.py"))?, init);
+        let module = fs::read_to_string(out_dir.join("pkg/mod.py"))?;
+        assert!(module.starts_with("import sys\n"), "{module}");
+        assert!(module.contains("def helper(a):"), "{module}");
+        assert_eq!(
+            fs::read_to_string(out_dir.join("pkg/consts.py"))?,
+            "LIMIT = 3\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_remove_dead_assignments_keeps_constants_other_modules_import() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input = tmp.path().join("src");
+        fs::create_dir_all(input.join("pkg"))?;
+        let table: String = (0..20).map(|code| format!("    {code},\n")).collect();
+        let dead = format!("_CODES = [\n{table}]\n");
+        fs::write(
+            input.join("pkg/tables.py"),
+            format!("{dead}LIMIT = 3\nHANDLE = open(__file__)\n"),
+        )?;
+        fs::write(
+            input.join("pkg/app.py"),
+            "from pkg.tables import LIMIT\n\ndef run(value):\n    total = value + LIMIT\n    return total\n",
+        )?;
+        let out_dir = tmp.path().join("out");
+
+        let stats = run_minify_dir(
+            &input,
+            Some(out_dir.clone()),
+            &[],
+            &[],
             None,
-            false,
-            false,
-            None,
-            None,
-            false,
-            true,
+            MinifyDirTestCfg {
+                quiet: true,
+                show_stats: true,
+                remove_dead_assignments: true,
+                ..MinifyDirTestCfg::default()
+            },
         )?;
 
-        let reports_dir = tmp.path().join("reports");
-        fs::create_dir(&reports_dir)?;
-        let mut perms = fs::metadata(&reports_dir)?.permissions();
-        perms.set_mode(0o555);
-        fs::set_permissions(&reports_dir, perms.clone())?;
+        assert_eq!(
+            fs::read_to_string(out_dir.join("pkg/tables.py"))?,
+            "LIMIT = 3\nHANDLE = open(__file__)\n"
+        );
+        let tables = stats
+            .files
+            .iter()
+            .find(|file| file.path == "pkg/tables.py")
+            .expect("tables stats");
+        assert_eq!(tables.dead_assignment_bytes, dead.len());
+        let app = stats
+            .files
+            .iter()
+            .find(|file| file.path == "pkg/app.py")
+            .expect("app stats");
+        assert_eq!(app.dead_assignment_bytes, 0);
+        Ok(())
+    }
 
+    #[test]
+    fn minify_dir_stats_report_function_lines_of_input_with_dead_assignments() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input = tmp.path().join("src");
+        fs::create_dir_all(&input)?;
+        fs::write(
+            input.join("app.py"),
+            "_UNUSED = [\n    1,\n    2,\n]\n\ndef run(value):\n    total = value + 1\n    return total\n",
+        )?;
         let out_dir = tmp.path().join("out");
-        let output = cli_cmd()?
-            .arg("apply-plan-dir")
-            .arg(input_dir.to_str().unwrap())
-            .arg("--plan")
-            .arg(plan_path.to_str().unwrap())
-            .arg("--out-dir")
-            .arg(out_dir.to_str().unwrap())
-            .arg("--stats")
-            .arg("--output-json")
-            .arg(reports_dir.join("apply-dir.json").to_str().unwrap())
-            .output()?;
 
-        perms.set_mode(0o755);
-        fs::set_permissions(&reports_dir, perms)?;
+        let stats = run_minify_dir(
+            &input,
+            Some(out_dir.clone()),
+            &[],
+            &[],
+            None,
+            MinifyDirTestCfg {
+                quiet: true,
+                show_stats: true,
+                remove_dead_assignments: true,
+                ..MinifyDirTestCfg::default()
+            },
+        )?;
+
+        assert!(fs::read_to_string(out_dir.join("app.py"))?.starts_with("\ndef run("));
+        let app = stats
+            .files
+            .iter()
+            .find(|file| file.path == "app.py")
+            .expect("app stats");
+        let functions = app.functions.as_ref().expect("function stats");
+        assert_eq!(functions.len(), 1);
+        assert_eq!(functions[0].name, "run");
+        assert_eq!(functions[0].line, Some(6));
+        Ok(())
+    }
 
-        assert!(!output.status.success());
-        assert!(!reports_dir.join("apply-dir.json").exists());
+    /// A vendored tree with one file the parser rejects in each category.
+    fn write_unparsable_fixtures(input: &Path) -> AnyResult<()> {
+        fs::create_dir_all(input.join("vendor"))?;
+        fs::write(
+            input.join("app.py"),
+            "def main(argv):\n    count = len(argv)\n    return count\n",
+        )?;
+        fs::write(
+            input.join("vendor/legacy.py"),
+            "def greet(name):\n    print \"hello\", name\n",
+        )?;
+        fs::write(
+            input.join("vendor/compat.py"),
+            "try:\n    import json\nexcept ImportError, exc:\n    json = None\n",
+        )?;
+        fs::write(
+            input.join("vendor/modern.py"),
+            "def label(user):\n    return f\"{user[\"name\"]}\"\n",
+        )?;
+        fs::write(input.join("vendor/broken.py"), "def oops(:\n    pass\n")?;
         Ok(())
     }
 
     #[test]
-    fn apply_plan_stdin_and_plan_stdin_pipe() -> AnyResult<()> {
-        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
-        let plan = Minifier::plan_from_source("stdin", source)?;
-        let plan_json = serde_json::to_string(&plan)?;
-        let combined = format!("{source}\n{plan_json}");
+    fn minify_dir_skips_unparsable_files_by_reason() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input = tmp.path().join("src");
+        write_unparsable_fixtures(&input)?;
+        let out_dir = tmp.path().join("out");
 
-        let output = cli_cmd()?
-            .arg("apply-plan")
-            .arg("stdin.py")
-            .arg("--stdin")
-            .arg("--plan-stdin")
-            .write_stdin(combined)
-            .output()?;
+        let stats = run_minify_dir(
+            &input,
+            Some(out_dir.clone()),
+            &[],
+            &[],
+            None,
+            MinifyDirTestCfg {
+                quiet: true,
+                fail_on_error: true,
+                ..MinifyDirTestCfg::default()
+            },
+        )?;
 
-        assert!(output.status.success());
-        let stdout = String::from_utf8(output.stdout)?;
-        assert!(stdout.contains("def foo(a):"));
+        assert_eq!(stats.processed, 5);
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.skipped_no_change, 4);
+        assert_eq!(stats.reasons.get("python2_syntax"), Some(&2));
+        assert_eq!(stats.reasons.get("unsupported_syntax"), Some(&1));
+        assert_eq!(stats.reasons.get("syntax_error"), Some(&1));
+        assert_eq!(stats.reasons.get("plan_error"), None);
+        assert_eq!(compute_exit_code(&stats, false, true, false, false), 0);
+
+        // Skipped files are carried into the output tree untouched.
+        assert_eq!(
+            fs::read_to_string(out_dir.join("vendor/legacy.py"))?,
+            fs::read_to_string(input.join("vendor/legacy.py"))?
+        );
+        assert!(out_dir.join("vendor/modern.py").exists());
         Ok(())
     }
 
     #[test]
-    fn apply_plan_file_reads_plan_from_dash() -> AnyResult<()> {
+    fn minify_dir_parse_errors_fail_when_not_skipped() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
-        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
-        fs::write(&file_path, source)?;
-
-        let plan = Minifier::plan_from_source("example", source)?;
-        let plan_json = serde_json::to_string(&plan)?;
+        let input = tmp.path().join("src");
+        write_unparsable_fixtures(&input)?;
 
-        let output = cli_cmd()?
-            .arg("apply-plan")
-            .arg(file_path.to_str().unwrap())
-            .arg("--plan")
-            .arg("-")
-            .write_stdin(plan_json)
-            .output()?;
+        let stats = run_minify_dir(
+            &input,
+            Some(tmp.path().join("out")),
+            &[],
+            &[],
+            None,
+            MinifyDirTestCfg {
+                quiet: true,
+                fail_on_error: true,
+                skip_parse_errors: false,
+                ..MinifyDirTestCfg::default()
+            },
+        )?;
 
-        assert!(output.status.success());
-        let stdout = String::from_utf8(output.stdout)?;
-        assert!(stdout.contains("def foo(a):"));
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.errors, 4);
+        assert_eq!(stats.reasons.get("python2_syntax"), Some(&2));
+        assert_eq!(stats.reasons.get("unsupported_syntax"), Some(&1));
+        assert_eq!(stats.reasons.get("syntax_error"), Some(&1));
+        assert_eq!(compute_exit_code(&stats, false, true, false, false), 1);
         Ok(())
     }
 
     #[test]
-    fn apply_plan_file_fail_on_change_exits_nonzero() -> AnyResult<()> {
+    fn minify_skip_parse_errors_passes_single_file_through() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let file_path = tmp.path().join("example.py");
-        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        let file_path = tmp.path().join("legacy.py");
+        let source = "print \"hello\"\n";
         fs::write(&file_path, source)?;
 
-        let plan = Minifier::plan_from_source("module", source)?;
-        let plan_path = tmp.path().join("plan.json");
-        fs::write(&plan_path, serde_json::to_string(&plan)?)?;
+        let output = cli_cmd()?.arg("minify").arg(&file_path).output()?;
+        assert!(!output.status.success(), "parse errors fail by default");
 
+        let json_path = tmp.path().join("stats.json");
         let output = cli_cmd()?
-            .arg("apply-plan")
-            .arg(file_path.to_str().unwrap())
-            .arg("--plan")
-            .arg(plan_path.to_str().unwrap())
-            .arg("--fail-on-change")
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--skip-parse-errors")
+            .arg("--stats")
+            .arg("--output-json")
+            .arg(&json_path)
             .output()?;
+        assert!(output.status.success());
+        let summary: serde_json::Value = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(summary["skipped_no_change"], 1);
+        assert_eq!(summary["reasons"]["python2_syntax"], 1);
 
-        assert!(!output.status.success());
-        assert_eq!(output.status.code(), Some(4));
+        // The bare switch never takes the path after it as its value.
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg("--skip-parse-errors")
+            .arg(&file_path)
+            .arg("--stdout")
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(String::from_utf8(output.stdout)?, source);
+        assert_eq!(fs::read_to_string(&file_path)?, source);
+
+        let out_tmp = tempdir()?;
+        let out_dir = out_tmp.path().join("out");
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg("--skip-parse-errors")
+            .arg(tmp.path())
+            .arg("--out-dir")
+            .arg(&out_dir)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(fs::read_to_string(out_dir.join("legacy.py"))?, source);
         Ok(())
     }
 
     #[test]
-    fn apply_plan_cli_dry_run_no_write() -> AnyResult<()> {
+    fn minify_remove_unused_imports_plans_against_cleaned_source() -> AnyResult<()> {
         let tmp = tempdir()?;
         let file_path = tmp.path().join("example.py");
-        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
-        fs::write(&file_path, source)?;
-
-        let plan = Minifier::plan_from_source("module", source)?;
-        let plan_path = tmp.path().join("plan.json");
-        fs::write(&plan_path, serde_json::to_string(&plan)?)?;
+        fs::write(
+            &file_path,
+            "import re, typing as t\n\ndef foo(value: t.Any):\n    temp = value + 1\n    return temp\n",
+        )?;
+        let stats_path = tmp.path().join("stats.json");
 
         let output = cli_cmd()?
-            .arg("apply-plan")
-            .arg(file_path.to_str().unwrap())
-            .arg("--plan")
-            .arg(plan_path.to_str().unwrap())
+            .arg("minify")
+            .arg(&file_path)
             .arg("--in-place")
-            .arg("--dry-run")
+            .arg("--remove-unused-imports")
+            .arg("--output-json")
+            .arg(&stats_path)
             .output()?;
         assert!(output.status.success());
 
-        let after = fs::read_to_string(&file_path)?;
-        assert_eq!(after, source);
+        assert_eq!(
+            fs::read_to_string(&file_path)?,
+            "import typing as t\n\ndef foo(a: t.Any):\n    b = a + 1\n    return b\n"
+        );
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&stats_path)?)?;
+        assert_eq!(stats.imports_removed, 1);
+        assert_eq!(stats.rewritten, 1);
         Ok(())
     }
 
     #[test]
-    fn apply_plan_stdin_stdout_rewrites() -> AnyResult<()> {
+    fn minify_dir_level_two_reports_each_stage_and_flags_override_it() -> AnyResult<()> {
         let tmp = tempdir()?;
-        let plan_path = tmp.path().join("plan.json");
-        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
-        let plan = Minifier::plan_from_source("module", source)?;
-        fs::write(&plan_path, serde_json::to_string(&plan)?)?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir(&input_dir)?;
+        fs::write(
+            input_dir.join("app.py"),
+            "\"\"\"App.\"\"\"\nimport os\nimport sys\n\n\ndef main(argv):  # entry\n    \"\"\"Run.\"\"\"\n    count = len(argv)\n    return sys.exit(count)\n",
+        )?;
 
-        let output = cli_cmd()?
-            .arg("apply-plan")
-            .arg("stdin.py")
-            .arg("--plan")
-            .arg(plan_path.to_str().unwrap())
-            .arg("--stdin")
-            .arg("--stdout")
-            .write_stdin(source)
-            .output()?;
+        let run = |out: &str, extra: &[&str]| -> AnyResult<DirStats> {
+            let stats_path = tmp.path().join(format!("{out}.json"));
+            let output = cli_cmd()?
+                .arg("minify-dir")
+                .arg(&input_dir)
+                .arg("--out-dir")
+                .arg(tmp.path().join(out))
+                .args(["--level", "2"])
+                .args(extra)
+                .arg("--output-json")
+                .arg(&stats_path)
+                .output()?;
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            Ok(serde_json::from_str(&fs::read_to_string(&stats_path)?)?)
+        };
 
-        assert!(output.status.success());
-        let stdout = String::from_utf8(output.stdout)?;
-        assert!(stdout.contains("def foo(a):"));
-        assert!(!stdout.contains("value"));
-        assert!(!stdout.contains("Processed"));
+        let stats = run("out", &[])?;
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("out/app.py"))?,
+            "import sys\ndef main(a):\n    b = len(a)\n    return sys.exit(b)\n"
+        );
+        let changes: Vec<(&str, usize)> = stats
+            .stages
+            .iter()
+            .map(|stage| (stage.stage.as_str(), stage.changes))
+            .take(3)
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("remove-unused-imports", 1),
+                ("strip-docstrings", 2),
+                ("strip-comments", 1),
+            ]
+        );
+        assert_eq!(stats.stages[3].stage, "compact-blank-lines");
+        assert_eq!(stats.imports_removed, 1);
+
+        let stats = run("kept", &["--no-strip-docstrings"])?;
+        let kept = fs::read_to_string(tmp.path().join("kept/app.py"))?;
+        assert!(kept.starts_with("\"\"\"App.\"\"\"\nimport sys\n"));
+        assert!(kept.contains("    \"\"\"Run.\"\"\"\n    b = len(a)\n"));
+        assert!(stats
+            .stages
+            .iter()
+            .all(|stage| stage.stage != "strip-docstrings"));
+        Ok(())
+    }
+
+    fn scan_imports(
+        input_dir: &Path,
+        third_party_only: bool,
+        venv: Option<&Path>,
+    ) -> AnyResult<ImportsReport> {
+        collect_imports_report(
+            input_dir,
+            &[],
+            None,
+            &[],
+            None,
+            false,
+            false,
+            None,
+            None,
+            false,
+            &[],
+            false,
+            third_party_only,
+            venv,
+            None,
+            &CommandContext::default(),
+        )
+    }
+
+    #[test]
+    fn imports_report_lists_packages_and_per_file_detail() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input = tmp.path().join("src");
+        fs::create_dir_all(input.join("pkg"))?;
+        fs::write(
+            input.join("pkg/app.py"),
+            "import os\nimport numpy as np\nfrom .models import User\n\ndef load():\n    import yaml\n    return yaml\n",
+        )?;
+        fs::write(
+            input.join("pkg/types.py"),
+            "from typing import TYPE_CHECKING\n\nif TYPE_CHECKING:\n    import pandas\n",
+        )?;
+        fs::write(input.join("pkg/broken.py"), "def broken(:\n")?;
+        let venv = tmp.path().join("venv");
+        fs::create_dir_all(venv.join("lib/python3.11/site-packages/numpy"))?;
+        fs::write(
+            venv.join("lib/python3.11/site-packages/numpy/__init__.py"),
+            "",
+        )?;
+
+        let report = scan_imports(&input, false, None)?;
+        let names: Vec<&str> = report.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["numpy", "os", "pandas", "typing", "yaml"]);
+        assert!(report.packages.iter().all(|p| p.in_venv.is_none()));
+        assert_eq!(report.errors, 1);
+
+        let app = &report.files[0];
+        assert_eq!(app.path, "pkg/app.py");
+        let summary: Vec<(&str, u32, Option<&str>, bool)> = app
+            .imports
+            .iter()
+            .map(|import| {
+                (
+                    import.module.as_str(),
+                    import.level,
+                    import.alias.as_deref(),
+                    import.deferred,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("os", 0, None, false),
+                ("numpy", 0, Some("np"), false),
+                ("models", 1, None, false),
+                ("yaml", 0, None, true),
+            ]
+        );
+        assert!(report.files[1].imports[1].type_checking);
+
+        let report = scan_imports(&input, true, Some(&venv))?;
+        let packages: Vec<(&str, Option<bool>)> = report
+            .packages
+            .iter()
+            .map(|p| (p.name.as_str(), p.in_venv))
+            .collect();
+        assert_eq!(
+            packages,
+            vec![
+                ("numpy", Some(true)),
+                ("pandas", Some(false)),
+                ("yaml", Some(false)),
+            ]
+        );
+        assert!(report.files[0].imports.iter().all(|i| i.module != "os"));
+        Ok(())
+    }
+
+    #[test]
+    fn imports_python_version_decides_what_is_stdlib() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input = tmp.path().join("src");
+        fs::create_dir_all(&input)?;
+        fs::write(input.join("app.py"), "import json\nimport tomllib\n")?;
+
+        let third_party = |version: &str| -> AnyResult<String> {
+            let output = cli_cmd()?
+                .arg("imports")
+                .arg(&input)
+                .arg("--third-party-only")
+                .arg("--python-version")
+                .arg(version)
+                .output()?;
+            assert!(output.status.success());
+            Ok(String::from_utf8(output.stdout)?)
+        };
+        assert_eq!(third_party("3.10")?, "tomllib\n");
+        assert_eq!(third_party("3.11")?, "");
+
+        cli_cmd()?
+            .arg("imports")
+            .arg(&input)
+            .arg("--python-version")
+            .arg("latest")
+            .assert()
+            .failure();
         Ok(())
     }
 
@@ -5214,7 +16243,17 @@ def sample(value):
             None,
             None,
             false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
             true,
+            false,
+            None,
+            &CommandContext::default(),
         )?;
         assert!(plan_path.exists());
 
@@ -5244,58 +16283,57 @@ def sample(value):
     }
 
     #[test]
-    fn minify_plan_dir_respects_max_depth() -> AnyResult<()> {
+    fn apply_plan_dir_reports_unmatched_and_unplanned_files() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
-        create_nested_fixture(&input_dir)?;
+        fs::create_dir_all(&input_dir)?;
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input_dir.join("kept.py"), source)?;
+        fs::write(input_dir.join("gone.py"), source)?;
 
-        let plan_depth1 = tmp.path().join("plan-depth1.json");
-        minify_plan_dir_with_depth(
-            &input_dir,
-            &plan_depth1,
-            &[],
-            None,
-            &[],
-            None,
-            None,
-            false,
-            false,
-            None,
-            Some(1),
-            false,
-            true,
-        )?;
-        let bundle1: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_depth1)?)?;
-        let paths1: Vec<String> = bundle1.files.iter().map(|f| f.path.clone()).collect();
-        assert_eq!(paths1, vec!["root.py".to_string()]);
+        let plan_path = tmp.path().join("plan.json");
+        cli_cmd()?
+            .arg("minify-plan-dir")
+            .arg(&input_dir)
+            .arg("--out")
+            .arg(&plan_path)
+            .assert()
+            .success();
 
-        let plan_depth2 = tmp.path().join("plan-depth2.json");
-        minify_plan_dir_with_depth(
-            &input_dir,
-            &plan_depth2,
-            &[],
-            None,
-            &[],
-            None,
-            None,
-            false,
-            false,
-            None,
-            Some(2),
-            false,
-            true,
-        )?;
-        let mut paths2: Vec<String> =
-            serde_json::from_str::<PlanBundle>(&fs::read_to_string(&plan_depth2)?)?
-                .files
-                .into_iter()
-                .map(|f| f.path)
-                .collect();
-        paths2.sort();
-        assert_eq!(
-            paths2,
-            vec!["level1/inner.py".to_string(), "root.py".to_string()]
-        );
+        fs::remove_file(input_dir.join("gone.py"))?;
+        fs::write(input_dir.join("fresh.py"), source)?;
+
+        let json_path = tmp.path().join("apply-dir.json");
+        let output = cli_cmd()?
+            .arg("apply-plan-dir")
+            .arg(&input_dir)
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--out-dir")
+            .arg(tmp.path().join("out"))
+            .arg("--output-json")
+            .arg(&json_path)
+            .arg("--require-all-plans")
+            .output()?;
+        assert_eq!(output.status.code(), Some(8));
+
+        let stats: DirStats = serde_json::from_str(&fs::read_to_string(&json_path)?)?;
+        assert_eq!(stats.processed, 1);
+        assert_eq!(stats.unmatched_plans, vec!["gone.py".to_string()]);
+        assert_eq!(stats.reasons.get("no_plan"), Some(&1));
+
+        fs::remove_file(input_dir.join("fresh.py"))?;
+        fs::write(input_dir.join("gone.py"), source)?;
+        cli_cmd()?
+            .arg("apply-plan-dir")
+            .arg(&input_dir)
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--out-dir")
+            .arg(tmp.path().join("out2"))
+            .arg("--require-all-plans")
+            .assert()
+            .success();
         Ok(())
     }
 
@@ -5478,7 +16516,17 @@ def sample(value):
             None,
             None,
             false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
             true,
+            false,
+            None,
+            &CommandContext::default(),
         )?;
 
         let include_file = tmp.path().join("includes.txt");
@@ -5532,7 +16580,17 @@ def sample(value):
             None,
             None,
             false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
             true,
+            false,
+            None,
+            &CommandContext::default(),
         )?;
 
         let includes: Vec<String> = Vec::new();
@@ -5602,7 +16660,17 @@ def sample(value):
             None,
             None,
             false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
             true,
+            false,
+            None,
+            &CommandContext::default(),
         )?;
 
         let includes: Vec<String> = Vec::new();
@@ -5803,6 +16871,11 @@ def sample(value):
             false,
             3,
             false,
+            None,
+            false,
+            None,
+            false,
+            &CommandContext::default(),
         )?;
 
         let rewritten = fs::read_to_string(&file_path)?;
@@ -5828,33 +16901,108 @@ def sample(value):
         fs::write(&plan_path, serde_json::to_string(&plan)?)?;
 
         let (_stats, _) = apply_plan(
-            &file_path, &plan_path, false, false, None, true, true, true, None, false, false,
-            false, false, 3, false,
+            &file_path,
+            &plan_path,
+            false,
+            false,
+            None,
+            true,
+            true,
+            true,
+            None,
+            false,
+            false,
+            false,
+            false,
+            3,
+            false,
+            None,
+            false,
+            None,
+            false,
+            &CommandContext::default(),
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_plan_for_dead_code_spares_keep_marked_functions() -> AnyResult<()> {
+        let source = "def main():\n    return 1\n\n\ndef template_hook(value):  # tsrs: keep\n    result = value + 1\n    return result\n\n\ndef unused_sibling(value):\n    result = value * 2\n    return result\n\n\nif __name__ == \"__main__\":\n    main()\n";
+        let dead_code = detect_dead_code(source, "hooks", true, &CommandContext::default())?;
+        let dead_names: Vec<&str> = dead_code.iter().map(|(_, name)| name.as_str()).collect();
+        assert!(dead_names.contains(&"unused_sibling"));
+        assert!(!dead_names.contains(&"template_hook"));
+
+        let plan = Minifier::plan_from_source("hooks", source)?;
+        let plan = filter_plan_for_dead_code(plan, &dead_code);
+        let names: Vec<&str> = plan
+            .functions
+            .iter()
+            .map(|func| func.qualified_name.as_str())
+            .collect();
+        assert!(names.contains(&"template_hook"));
+        assert!(!names.contains(&"unused_sibling"));
+        Ok(())
+    }
+
+    #[test]
+    fn remove_dead_code_keeps_assert_only_helpers_unless_disabled() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let path = tmp.path().join("merge.py");
+        fs::write(
+            &path,
+            "def is_sorted(items):\n    ordered = sorted(items)\n    return items == ordered\n\n\ndef merge(left, right):\n    result = left + right\n    assert is_sorted(result)\n    return result\n\n\nmerge([1], [2])\n",
         )?;
 
+        let run = |extra: &[&str]| -> AnyResult<String> {
+            let output = cli_cmd()?
+                .arg("minify")
+                .arg(&path)
+                .arg("--remove-dead-code")
+                .arg("--stdout")
+                .args(extra)
+                .output()?;
+            assert!(output.status.success());
+            Ok(String::from_utf8(output.stdout)?)
+        };
+
+        let kept = run(&[])?;
+        assert!(!kept.contains("ordered"), "{kept}");
+        assert!(!kept.contains("result"), "{kept}");
+
+        assert_eq!(run(&["--asserts-are-roots"])?, kept);
+
+        let removed = run(&["--asserts-are-roots=false"])?;
+        assert!(removed.contains("ordered = sorted(items)"), "{removed}");
+        assert!(!removed.contains("result"), "{removed}");
         Ok(())
     }
 
     #[test]
     fn compute_exit_code_flags() {
         let mut stats = DirStats::default();
-        assert_eq!(compute_exit_code(&stats, false, false, false), 0);
+        assert_eq!(compute_exit_code(&stats, false, false, false, false), 0);
 
         stats.errors = 1;
-        assert_eq!(compute_exit_code(&stats, false, true, false), 1);
+        assert_eq!(compute_exit_code(&stats, false, true, false, false), 1);
 
         stats.errors = 0;
         stats.bailouts = 2;
-        assert_eq!(compute_exit_code(&stats, true, false, false), 2);
+        assert_eq!(compute_exit_code(&stats, true, false, false, false), 2);
 
         stats.bailouts = 0;
         stats.rewritten = 3;
-        assert_eq!(compute_exit_code(&stats, false, false, true), 4);
+        assert_eq!(compute_exit_code(&stats, false, false, true, false), 4);
 
         stats.errors = 1;
         stats.bailouts = 1;
         stats.rewritten = 1;
-        assert_eq!(compute_exit_code(&stats, true, true, true), 7);
+        assert_eq!(compute_exit_code(&stats, true, true, true, false), 7);
+
+        stats.unmatched_plans = vec!["gone.py".to_string()];
+        assert_eq!(compute_exit_code(&stats, false, false, false, false), 0);
+        assert_eq!(compute_exit_code(&stats, true, true, true, true), 15);
     }
 
     #[test]
@@ -5889,7 +17037,7 @@ def sample(value):
         )?;
         assert!(plan_path.exists());
 
-        let plan_bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
+        let plan_bundle = read_plan_bundle(&plan_path)?;
         assert_eq!(plan_bundle.files.len(), 2);
 
         let output_dir = tmp.path().join("out");
@@ -5919,15 +17067,317 @@ def sample(value):
         Ok(())
     }
 
+    /// Plan `input_dir` into a plan directory at `plans_dir`.
+    fn write_plan_dir_for(input_dir: &PathBuf, plans_dir: &PathBuf) -> AnyResult<()> {
+        minify_plan_dir_with_depth(
+            input_dir,
+            plans_dir,
+            &[],
+            None,
+            &[],
+            None,
+            None,
+            false,
+            false,
+            None,
+            None,
+            false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
+            true,
+            true,
+            None,
+            &CommandContext::default(),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn minify_plan_dir_out_format_dir_round_trip() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        let nested = input_dir.join("pkg");
+        fs::create_dir_all(&nested)?;
+
+        fs::write(
+            input_dir.join("module.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+        fs::write(
+            nested.join("helpers.py"),
+            "def helper(value):\n    result = value * 2\n    return result\n",
+        )?;
+
+        let plans_dir = tmp.path().join("plans");
+        write_plan_dir_for(&input_dir, &plans_dir)?;
+
+        let entry: serde_json::Value = serde_json::from_str(&fs::read_to_string(
+            plans_dir.join("pkg/helpers.py.plan.json"),
+        )?)?;
+        assert_eq!(entry["version"], PLAN_BUNDLE_VERSION);
+        assert_eq!(entry["path"], "pkg/helpers.py");
+        assert_eq!(entry["plan"]["module"], "pkg.helpers");
+        assert!(plans_dir.join("module.py.plan.json").is_file());
+
+        let plan_bundle = read_plan_bundle(&plans_dir)?;
+        assert_eq!(plan_bundle.extensions, vec!["py".to_string()]);
+        let paths: Vec<&str> = plan_bundle
+            .files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(paths, vec!["module.py", "pkg/helpers.py"]);
+
+        let output_dir = tmp.path().join("out");
+        let cfg = ApplyPlanDirTestCfg {
+            quiet: true,
+            ..Default::default()
+        };
+        run_apply_plan_dir(
+            &input_dir,
+            &plans_dir,
+            Some(output_dir.clone()),
+            &[],
+            &[],
+            None,
+            cfg,
+        )?;
+
+        let rewritten_module = fs::read_to_string(output_dir.join("module.py"))?;
+        assert!(rewritten_module.contains("def foo(a):"));
+        let rewritten_helper = fs::read_to_string(output_dir.join("pkg/helpers.py"))?;
+        assert!(rewritten_helper.contains("def helper(a):"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_plan_dir_reads_only_the_plans_of_included_files() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        for name in ["a.py", "b.py", "pkg/c.py"] {
+            fs::write(
+                input_dir.join(name),
+                "def foo(value):\n    temp = value + 1\n    return temp\n",
+            )?;
+        }
+        let plans_dir = tmp.path().join("plans");
+        write_plan_dir_for(&input_dir, &plans_dir)?;
+        // Reading either of these would fail the run.
+        for name in ["b.py.plan.json", "pkg/c.py.plan.json"] {
+            fs::write(plans_dir.join(name), "not a plan")?;
+        }
+
+        let output_dir = tmp.path().join("out");
+        let cfg = ApplyPlanDirTestCfg {
+            show_stats: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let stats = run_apply_plan_dir(
+            &input_dir,
+            &plans_dir,
+            Some(output_dir.clone()),
+            &["a.py".to_string()],
+            &[],
+            None,
+            cfg,
+        )?;
+
+        assert_eq!(stats.processed, 1);
+        assert_eq!(stats.unmatched_plans, vec!["b.py", "pkg/c.py"]);
+        assert!(fs::read_to_string(output_dir.join("a.py"))?.contains("def foo(a):"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn minify_plan_dir_includes_version() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(input_dir.join("example.py"), "def foo(x):\n    return x\n")?;
+
+        let plan_path = tmp.path().join("plan.json");
+        minify_plan_dir(
+            &input_dir,
+            &plan_path,
+            &[],
+            None,
+            &[],
+            None,
+            None,
+            false,
+            false,
+            None,
+            true,
+        )?;
+
+        let plan_bundle = read_plan_bundle(&plan_path)?;
+        assert_eq!(plan_bundle.version, PLAN_BUNDLE_VERSION);
+
+        Ok(())
+    }
+
+    #[test]
+    fn minify_plan_dir_fail_on_error_lists_unplanned_files() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("good.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+        fs::write(input_dir.join("bad.py"), b"x = '\xff'\n")?;
+        let bundle_path = tmp.path().join("plans.jsonl");
+        let errors_path = tmp.path().join("errors.json");
+
+        let output = cli_cmd()?
+            .arg("minify-plan-dir")
+            .arg(&input_dir)
+            .arg("--out")
+            .arg(&bundle_path)
+            .arg("--fail-on-error")
+            .arg("--errors-out")
+            .arg(&errors_path)
+            .output()?;
+        assert_eq!(output.status.code(), Some(1));
+
+        let bundle = fs::read_to_string(&bundle_path)?;
+        assert_eq!(bundle.lines().count(), 2);
+        let header: serde_json::Value = serde_json::from_str(bundle.lines().next().unwrap())?;
+        assert_eq!(header["errors"][0]["path"], "bad.py");
+        assert_eq!(header["errors"][0]["kind"], "read_error");
+        assert!(header["errors"][0]["message"].is_string());
+        let errors: serde_json::Value = serde_json::from_str(&fs::read_to_string(&errors_path)?)?;
+        assert_eq!(errors, header["errors"]);
+
+        let output = cli_cmd()?
+            .arg("minify-plan-dir")
+            .arg(&input_dir)
+            .arg("--out")
+            .arg(&bundle_path)
+            .output()?;
+        assert!(output.status.success());
+        Ok(())
+    }
+
+    /// A bundle entry that fails to serialize when asked to, like a run cut short.
+    struct InterruptedEntry(bool);
+
+    impl Serialize for InterruptedEntry {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if self.0 {
+                Err(serde::ser::Error::custom("interrupted"))
+            } else {
+                serializer.serialize_str("entry")
+            }
+        }
+    }
+
+    #[test]
+    fn plan_bundle_is_never_partially_written_under_its_name() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let bundle_path = tmp.path().join("plans.jsonl");
+        let header = PlanBundleHeader {
+            version: PLAN_BUNDLE_VERSION,
+            extensions: default_extensions(),
+            errors: Vec::new(),
+            module_roots: Vec::new(),
+            extra: serde_json::Map::new(),
+        };
+        let interrupted = [InterruptedEntry(false), InterruptedEntry(true)];
+
+        assert!(write_plan_bundle(&bundle_path, &header, &interrupted).is_err());
+        assert!(!bundle_path.exists());
+
+        write_plan_bundle(&bundle_path, &header, &[InterruptedEntry(false)])?;
+        let complete = fs::read_to_string(&bundle_path)?;
+        assert!(write_plan_bundle(&bundle_path, &header, &interrupted).is_err());
+        assert_eq!(fs::read_to_string(&bundle_path)?, complete);
+        assert_eq!(fs::read_dir(tmp.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn tsrsignore_files_are_merged_and_scoped_to_their_subtree() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        let pkg = input_dir.join("pkg");
+        fs::create_dir_all(&pkg)?;
+        fs::write(
+            input_dir.join(".tsrsignore"),
+            "# shared exclusions\n**/legacy_*.py\n",
+        )?;
+        fs::write(pkg.join(".tsrsignore"), "generated_*.py\n")?;
+        for path in [
+            input_dir.join("generated_root.py"),
+            input_dir.join("legacy_root.py"),
+            pkg.join("generated_api.py"),
+            pkg.join("legacy_api.py"),
+            pkg.join("core.py"),
+        ] {
+            fs::write(path, "def foo(x):\n    return x\n")?;
+        }
+
+        let plan_paths = |no_tsrsignore: bool| -> AnyResult<Vec<String>> {
+            let plan_path = tmp.path().join("plan.json");
+            minify_plan_dir_with_depth(
+                &input_dir,
+                &plan_path,
+                &[],
+                None,
+                &[],
+                None,
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                false,
+                false,
+                false,
+                &[],
+                no_tsrsignore,
+                None,
+                false,
+                true,
+                false,
+                None,
+                &CommandContext::default(),
+            )?;
+            let bundle = read_plan_bundle(&plan_path)?;
+            Ok(bundle.files.into_iter().map(|file| file.path).collect())
+        };
+
+        // The root file applies everywhere; the nested file only inside `pkg/` and cannot
+        // lift the root's exclusions.
+        assert_eq!(
+            plan_paths(false)?,
+            vec!["generated_root.py".to_string(), "pkg/core.py".to_string()]
+        );
+        assert_eq!(plan_paths(true)?.len(), 5);
+        Ok(())
+    }
+
     #[test]
-    fn minify_plan_dir_includes_version() -> AnyResult<()> {
+    fn minify_plan_dir_plans_stubs_without_parameter_renames() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-        fs::write(input_dir.join("example.py"), "def foo(x):\n    return x\n")?;
+        let source = "def area(width, height):\n    total = width * height\n    return total\n";
+        fs::write(input_dir.join("shapes.py"), source)?;
+        fs::write(input_dir.join("shapes.pyi"), source)?;
 
         let plan_path = tmp.path().join("plan.json");
-        minify_plan_dir(
+        minify_plan_dir_with_depth(
             &input_dir,
             &plan_path,
             &[],
@@ -5938,26 +17388,49 @@ def sample(value):
             false,
             false,
             None,
+            None,
+            false,
+            false,
+            false,
+            false,
+            &["py".to_string(), ".PYI".to_string()],
+            false,
+            None,
+            false,
             true,
+            false,
+            None,
+            &CommandContext::default(),
         )?;
 
-        let plan_bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
-        assert_eq!(plan_bundle.version, PLAN_BUNDLE_VERSION);
-
-        Ok(())
-    }
-
-    #[test]
-    fn minify_plan_dir_skips_hidden_by_default() -> AnyResult<()> {
-        let tmp = tempdir()?;
-        let input_dir = tmp.path().join("src");
-        fs::create_dir_all(&input_dir)?;
-        fs::write(input_dir.join(".hidden.py"), "def foo(x):\n    return x\n")?;
+        let bundle = read_plan_bundle(&plan_path)?;
+        assert_eq!(bundle.extensions, vec!["py".to_string(), "pyi".to_string()]);
+        let renamed = |path: &str| -> Vec<String> {
+            let file = bundle
+                .files
+                .iter()
+                .find(|file| file.path == path)
+                .expect("file planned");
+            file.plan.functions[0]
+                .renames
+                .iter()
+                .map(|entry| entry.original.clone())
+                .collect()
+        };
+        assert_eq!(renamed("shapes.py"), vec!["width", "height", "total"]);
+        assert_eq!(renamed("shapes.pyi"), vec!["total"]);
+        let stub = bundle
+            .files
+            .iter()
+            .find(|file| file.path == "shapes.pyi")
+            .unwrap();
+        assert!(stub.plan.keep_parameters);
 
-        let plan_path = tmp.path().join("plan.json");
+        // The default extension set leaves stubs alone.
+        let default_plan = tmp.path().join("default.json");
         minify_plan_dir(
             &input_dir,
-            &plan_path,
+            &default_plan,
             &[],
             None,
             &[],
@@ -5968,39 +17441,82 @@ def sample(value):
             None,
             true,
         )?;
-
-        let plan_bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
-        assert!(plan_bundle.files.is_empty());
+        let bundle = read_plan_bundle(&default_plan)?;
+        assert_eq!(bundle.extensions, vec!["py".to_string()]);
+        let paths: Vec<&str> = bundle.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, vec!["shapes.py"]);
 
         Ok(())
     }
 
     #[test]
-    fn minify_plan_dir_includes_hidden_when_requested() -> AnyResult<()> {
+    fn minify_dir_treats_pyw_like_py() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-        fs::write(input_dir.join(".hidden.py"), "def foo(x):\n    return x\n")?;
+        let source = "def main(argv):\n    count = len(argv)\n    return count\n";
+        fs::write(input_dir.join("cli.py"), source)?;
+        fs::write(input_dir.join("gui.pyw"), source)?;
 
-        let plan_path = tmp.path().join("plan.json");
-        minify_plan_dir(
+        let output_dir = tmp.path().join("out");
+        let stats = minify_dir_with_depth(
             &input_dir,
-            &plan_path,
+            Some(output_dir.clone()),
             &[],
             None,
             &[],
             None,
             None,
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+            None,
             true,
+            None,
+            Some(1),
+            false,
+            false,
+            false,
+            false,
+            3,
+            false,
+            None,
+            false,
+            false,
+            false,
+            false,
+            false,
+            None,
+            &["py".to_string(), "pyw".to_string()],
             false,
             None,
+            false,
+            None,
+            None,
+            None,
+            false,
+            None,
+            &TransformPipeline::new(),
+            false,
             true,
+            None,
+            None,
+            None,
+            false,
+            OutDirMode::Fresh,
+            None,
+            &CommandContext::default(),
         )?;
 
-        let plan_bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
-        assert_eq!(plan_bundle.files.len(), 1);
-        assert_eq!(plan_bundle.files[0].path, ".hidden.py");
-
+        assert_eq!(stats.processed, 2);
+        assert_eq!(stats.rewritten, 2);
+        let py = fs::read_to_string(output_dir.join("cli.py"))?;
+        let pyw = fs::read_to_string(output_dir.join("gui.pyw"))?;
+        assert_ne!(py, source);
+        assert_eq!(py, pyw);
         Ok(())
     }
 
@@ -6031,10 +17547,20 @@ def sample(value):
             None,
             None,
             false,
+            false,
+            false,
+            false,
+            &[],
+            false,
+            None,
+            false,
             true,
+            false,
+            None,
+            &CommandContext::default(),
         )?;
 
-        let bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
+        let bundle = read_plan_bundle(&plan_path)?;
         let paths: Vec<String> = bundle.files.into_iter().map(|f| f.path).collect();
         assert_eq!(paths, vec!["beta.py".to_string()]);
         Ok(())
@@ -6067,7 +17593,7 @@ def sample(value):
             true,
         )?;
 
-        let plan_bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
+        let plan_bundle = read_plan_bundle(&plan_path)?;
         assert_eq!(plan_bundle.files.len(), 1);
         assert_eq!(plan_bundle.files[0].path, "real/a.py");
 
@@ -6101,34 +17627,30 @@ def sample(value):
             true,
         )?;
 
-        let plan_bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
-        assert_eq!(plan_bundle.files.len(), 2);
+        // The file behind the link is planned once, under the first path
+        let plan_bundle = read_plan_bundle(&plan_path)?;
         let paths: Vec<_> = plan_bundle
             .files
             .into_iter()
             .map(|entry| entry.path)
             .collect();
-        assert_eq!(
-            paths,
-            vec!["link/a.py".to_string(), "real/a.py".to_string()]
-        );
+        assert_eq!(paths, vec!["link/a.py".to_string()]);
 
         Ok(())
     }
 
-    #[cfg(windows)]
     #[test]
-    fn minify_plan_dir_default_case_insensitive_on_windows() -> AnyResult<()> {
+    fn apply_plan_dir_rejects_future_version() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-        fs::write(input_dir.join("A.py"), "def foo(x):\n    return x\n")?;
+        fs::write(input_dir.join("example.py"), "def foo(x):\n    return x\n")?;
 
         let plan_path = tmp.path().join("plan.json");
         minify_plan_dir(
             &input_dir,
             &plan_path,
-            &["a*.py".to_string()],
+            &[],
             None,
             &[],
             None,
@@ -6139,84 +17661,229 @@ def sample(value):
             true,
         )?;
 
-        let bundle: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
-        let paths: Vec<_> = bundle.files.into_iter().map(|f| f.path).collect();
-        assert_eq!(paths, vec!["A.py".to_string()]);
+        let mut bundle = read_plan_bundle(&plan_path)?;
+        bundle.version = PLAN_BUNDLE_VERSION + 1;
+        fs::write(&plan_path, serde_json::to_string_pretty(&bundle)?)?;
+
+        let output_dir = tmp.path().join("out");
+        let includes: Vec<String> = Vec::new();
+        let excludes: Vec<String> = Vec::new();
+        let cfg = ApplyPlanDirTestCfg {
+            quiet: true,
+            fail_on_bailout: false,
+            ..Default::default()
+        };
+        let err = run_apply_plan_dir(
+            &input_dir,
+            &plan_path,
+            Some(output_dir),
+            &includes,
+            &excludes,
+            None,
+            cfg,
+        )
+        .expect_err("future plan version should be rejected");
+
+        assert!(
+            matches!(
+                err.downcast_ref::<TsrsError>(),
+                Some(TsrsError::PlanVersion {
+                    supported: PLAN_BUNDLE_VERSION,
+                    ..
+                })
+            ),
+            "unexpected error: {err}"
+        );
+
+        Ok(())
+    }
 
+    /// Plan `input_dir` through the CLI and tag the bundle with fields no version knows.
+    fn plan_bundle_with_unknown_fields(input_dir: &Path, plan_path: &Path) -> AnyResult<()> {
+        cli_cmd()?
+            .arg("minify-plan-dir")
+            .arg(input_dir)
+            .arg("--out")
+            .arg(plan_path)
+            .assert()
+            .success();
+        let mut lines = Vec::new();
+        for line in fs::read_to_string(plan_path)?.lines() {
+            let mut value: serde_json::Value = serde_json::from_str(line)?;
+            value["future_hash"] = serde_json::Value::from("abc123");
+            if let Some(plan) = value.get_mut("plan") {
+                plan["future_flag"] = serde_json::Value::from(true);
+            }
+            lines.push(serde_json::to_string(&value)?);
+        }
+        fs::write(plan_path, lines.join("\n") + "\n")?;
         Ok(())
     }
 
-    #[cfg(not(windows))]
     #[test]
-    fn minify_plan_dir_case_insensitive_flag_controls_matching() -> AnyResult<()> {
+    fn plan_filter_drops_selected_plans_and_still_applies() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-        fs::write(input_dir.join("A.py"), "def foo(x):\n    return x\n")?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def keep(value):\n    temp = value + 1\n    return temp\n\n\ndef drop(value):\n    other = value * 2\n    return other\n",
+        )?;
+        let plan_path = tmp.path().join("plan.jsonl");
+        plan_bundle_with_unknown_fields(&input_dir, &plan_path)?;
 
-        let plan_default = tmp.path().join("plan_default.json");
-        minify_plan_dir(
+        let filtered_path = tmp.path().join("filtered.jsonl");
+        cli_cmd()?
+            .arg("plan-filter")
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--out")
+            .arg(&filtered_path)
+            .arg("--drop-function")
+            .arg("drop")
+            .arg("--drop-rename")
+            .arg("keep:temp")
+            .assert()
+            .success();
+
+        let contents = fs::read_to_string(&filtered_path)?;
+        let raw: Vec<serde_json::Value> = contents
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(raw[0]["future_hash"], "abc123");
+        assert_eq!(raw[1]["future_hash"], "abc123");
+        assert_eq!(raw[1]["plan"]["future_flag"], true);
+
+        let bundle = read_plan_bundle(&filtered_path)?;
+        let plan = &bundle.files[0].plan;
+        assert!(plan.functions.iter().all(|f| f.qualified_name != "drop"));
+        let keep = &plan.functions[0];
+        assert_eq!(keep.qualified_name, "keep");
+        assert!(keep.renames.iter().all(|entry| entry.original != "temp"));
+        assert!(keep.renames.iter().any(|entry| entry.original == "value"));
+        assert!(keep.excluded.contains(&"temp".to_string()));
+
+        let out_dir = tmp.path().join("out");
+        let stats = run_apply_plan_dir(
             &input_dir,
-            &plan_default,
-            &["a*.py".to_string()],
-            None,
+            &filtered_path,
+            Some(out_dir.clone()),
+            &[],
             &[],
             None,
-            None,
-            false,
-            false,
-            None,
-            true,
+            ApplyPlanDirTestCfg {
+                quiet: true,
+                ..Default::default()
+            },
         )?;
-        let bundle_default: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_default)?)?;
-        assert!(bundle_default.files.is_empty());
+        assert_eq!(stats.errors, 0);
+        let rewritten = fs::read_to_string(out_dir.join("example.py"))?;
+        assert!(!rewritten.contains("def keep(value)"));
+        assert!(rewritten.contains("temp = "));
+        assert!(rewritten.contains("def drop(value):\n    other = value * 2"));
+        Ok(())
+    }
 
-        let plan_ci = tmp.path().join("plan_ci.json");
-        minify_plan_dir(
-            &input_dir,
-            &plan_ci,
-            &["a*.py".to_string()],
-            None,
-            &[],
-            None,
-            None,
-            false,
-            false,
-            Some(true),
-            true,
+    #[test]
+    fn plan_filter_rejects_selectors_that_match_nothing() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def keep(value):\n    temp = value + 1\n    return temp\n",
         )?;
-        let bundle_ci: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_ci)?)?;
-        let ci_paths: Vec<_> = bundle_ci.files.into_iter().map(|f| f.path).collect();
-        assert_eq!(ci_paths, vec!["A.py".to_string()]);
+        let plan_path = tmp.path().join("plan.jsonl");
+        plan_bundle_with_unknown_fields(&input_dir, &plan_path)?;
+        let filtered_path = tmp.path().join("filtered.jsonl");
 
-        let plan_cs = tmp.path().join("plan_cs.json");
-        minify_plan_dir(
-            &input_dir,
-            &plan_cs,
-            &["a*.py".to_string()],
-            None,
-            &[],
-            None,
-            None,
-            false,
-            false,
-            Some(false),
-            true,
+        let output = cli_cmd()?
+            .arg("plan-filter")
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--out")
+            .arg(&filtered_path)
+            .arg("--drop-rename")
+            .arg("keep:missing")
+            .output()?;
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)?.contains("no plan entry matched: keep:missing"));
+        assert!(!filtered_path.exists());
+
+        let output = cli_cmd()?
+            .arg("plan-filter")
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--out")
+            .arg(&filtered_path)
+            .arg("--drop-rename")
+            .arg("temp")
+            .output()?;
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)?.contains("expects FUNC:NAME"));
+        Ok(())
+    }
+
+    #[test]
+    fn plan_show_renders_renames_and_filters_by_file() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(
+            input_dir.join("pkg/example.py"),
+            "def keep(value):\n    temp = value + 1\n    return temp\n",
         )?;
-        let bundle_cs: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_cs)?)?;
-        assert!(bundle_cs.files.is_empty());
+        fs::write(input_dir.join("other.py"), "def other():\n    return 1\n")?;
+        let plan_path = tmp.path().join("plan.jsonl");
+        plan_bundle_with_unknown_fields(&input_dir, &plan_path)?;
+
+        let output = cli_cmd()?
+            .arg("plan-show")
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--file")
+            .arg("pkg/example.py")
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.starts_with("pkg/example.py (module "));
+        assert!(stdout.contains("  keep\n"));
+        assert!(stdout.contains("temp → "));
+        assert!(!stdout.contains("other.py"));
+
+        let output = cli_cmd()?
+            .arg("plan-show")
+            .arg("--plan")
+            .arg(&plan_path)
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.starts_with("Plan bundle v2"));
+        assert!(stdout.contains("other.py (module "));
 
+        let output = cli_cmd()?
+            .arg("plan-show")
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--file")
+            .arg("missing.py")
+            .output()?;
+        assert!(!output.status.success());
         Ok(())
     }
 
     #[test]
-    fn apply_plan_dir_rejects_future_version() -> AnyResult<()> {
+    fn minify_plan_dir_gzip_bundle_applies_by_extension_or_magic() -> AnyResult<()> {
         let tmp = tempdir()?;
         let input_dir = tmp.path().join("src");
         fs::create_dir_all(&input_dir)?;
-        fs::write(input_dir.join("example.py"), "def foo(x):\n    return x\n")?;
+        fs::write(
+            input_dir.join("example.py"),
+            "def foo(value):\n    temp = value + 1\n    return temp\n",
+        )?;
 
-        let plan_path = tmp.path().join("plan.json");
+        let plan_path = tmp.path().join("plan.json.gz");
         minify_plan_dir(
             &input_dir,
             &plan_path,
@@ -6230,45 +17897,167 @@ def sample(value):
             None,
             true,
         )?;
+        assert!(fs::read(&plan_path)?.starts_with(&GZIP_MAGIC));
+        assert_eq!(read_plan_bundle(&plan_path)?.files.len(), 1);
+
+        // Without the extension the bundle is still recognised by its magic bytes.
+        let renamed = tmp.path().join("plan.bundle");
+        fs::copy(&plan_path, &renamed)?;
+        for (plan, out_name) in [(&plan_path, "out-gz"), (&renamed, "out-magic")] {
+            let out_dir = tmp.path().join(out_name);
+            let stats = run_apply_plan_dir(
+                &input_dir,
+                plan,
+                Some(out_dir.clone()),
+                &[],
+                &[],
+                None,
+                ApplyPlanDirTestCfg {
+                    quiet: true,
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(stats.rewritten, 1);
+            assert!(fs::read_to_string(out_dir.join("example.py"))?.contains("def foo(a):"));
+        }
+        Ok(())
+    }
 
-        let mut bundle_value: serde_json::Value =
-            serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
-        if let serde_json::Value::Object(ref mut obj) = bundle_value {
-            obj.insert(
-                "version".to_string(),
-                serde_json::Value::Number(serde_json::Number::from(
-                    (PLAN_BUNDLE_VERSION + 1) as u64,
-                )),
-            );
+    #[test]
+    fn apply_plan_dir_streams_large_v2_bundles_and_still_reads_v1() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(&input_dir)?;
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        let plan = Minifier::plan_from_source("module", source)?;
+
+        // A synthetic 10k-file bundle; every 100th entry has a file on disk to apply to.
+        let files: Vec<PlanFile> = (0..10_000)
+            .map(|index| PlanFile {
+                path: format!("pkg{}/mod{index}.py", index % 10),
+                module: None,
+                plan: plan.clone(),
+            })
+            .collect();
+        for file in files.iter().step_by(100) {
+            let path = input_dir.join(&file.path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, source)?;
         }
-        fs::write(&plan_path, serde_json::to_string_pretty(&bundle_value)?)?;
 
-        let output_dir = tmp.path().join("out");
-        let includes: Vec<String> = Vec::new();
-        let excludes: Vec<String> = Vec::new();
-        let cfg = ApplyPlanDirTestCfg {
-            quiet: true,
-            fail_on_bailout: false,
-            ..Default::default()
+        let v2_path = tmp.path().join("plan.jsonl.gz");
+        let header = PlanBundleHeader {
+            version: PLAN_BUNDLE_VERSION,
+            extensions: default_extensions(),
+            errors: Vec::new(),
+            module_roots: Vec::new(),
+            extra: serde_json::Map::new(),
         };
-        let err = run_apply_plan_dir(
-            &input_dir,
-            &plan_path,
-            Some(output_dir),
-            &includes,
-            &excludes,
-            None,
-            cfg,
-        )
-        .expect_err("future plan version should be rejected");
+        write_plan_bundle(&v2_path, &header, &files)?;
+        assert_eq!(read_plan_bundle(&v2_path)?.files.len(), 10_000);
+
+        let v1_path = tmp.path().join("plan-v1.json");
+        let v1 = PlanBundle {
+            version: 1,
+            extensions: default_extensions(),
+            extra: serde_json::Map::new(),
+            files,
+        };
+        fs::write(&v1_path, serde_json::to_string_pretty(&v1)?)?;
 
-        let message = err.to_string();
-        assert!(
-            message.contains("unsupported plan bundle version"),
-            "unexpected error: {}",
-            message
-        );
+        let mut outputs = Vec::new();
+        for (plan_path, out_name) in [(&v2_path, "out-v2"), (&v1_path, "out-v1")] {
+            let out_dir = tmp.path().join(out_name);
+            let stats = run_apply_plan_dir(
+                &input_dir,
+                plan_path,
+                Some(out_dir.clone()),
+                &[],
+                &[],
+                None,
+                ApplyPlanDirTestCfg {
+                    quiet: true,
+                    jobs: Some(4),
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(stats.processed, 100);
+            assert_eq!(stats.rewritten, 100);
+            assert_eq!(stats.errors, 0);
+            outputs.push(fs::read_to_string(out_dir.join("pkg0/mod9900.py"))?);
+        }
+        assert!(outputs[0].contains("def foo(a):"));
+        assert_eq!(outputs[0], outputs[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn apply_plan_dir_summary_only_totals_a_bundle_without_reading_sources() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        // Present but not Python at all: a summary never reads it.
+        fs::write(input_dir.join("pkg/plain.py"), b"\xff\xfe not python")?;
+
+        let plain = Minifier::plan_from_source(
+            "pkg.plain",
+            "def plain(value):\n    temp = value + 1\n    return temp\n",
+        )?;
+        let mixed = Minifier::plan_from_source(
+            "pkg.mixed",
+            "def pick(cmd):\n    match cmd:\n        case 1:\n            return 1\n\n\ndef squares(items):\n    return [i * i for i in items]\n\n\ndef outer(x):\n    def inner(y):\n        return y\n\n    return inner(x)\n",
+        )?;
+        let files = vec![
+            PlanFile {
+                path: "pkg/mixed.py".to_string(),
+                module: None,
+                plan: mixed,
+            },
+            PlanFile {
+                path: "pkg/plain.py".to_string(),
+                module: None,
+                plan: plain,
+            },
+        ];
+        let header = PlanBundleHeader {
+            version: PLAN_BUNDLE_VERSION,
+            extensions: default_extensions(),
+            errors: vec![PlanFileError {
+                path: "pkg/broken.py".to_string(),
+                kind: "plan_error".to_string(),
+                message: "invalid syntax".to_string(),
+            }],
+            module_roots: Vec::new(),
+            extra: serde_json::Map::new(),
+        };
+        let plan_path = tmp.path().join("plan.jsonl");
+        write_plan_bundle(&plan_path, &header, &files)?;
+
+        let summary = summarize_plan_bundle(&input_dir, &plan_path)?;
+        assert_eq!(summary.plans.files, 2);
+        assert_eq!(summary.plans.functions, 5);
+        assert_eq!(summary.plans.with_match_statement, 1);
+        assert_eq!(summary.plans.with_comprehension, 1);
+        assert_eq!(summary.plans.with_nested_functions, 1);
+        assert!(summary.plans.renames >= 2);
+        assert_eq!(summary.plan_errors, 1);
+        assert_eq!(summary.present, 1);
+        assert_eq!(summary.missing, vec!["pkg/mixed.py".to_string()]);
 
+        let output = cli_cmd()?
+            .arg("apply-plan-dir")
+            .arg(&input_dir)
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--summary-only")
+            .arg("--json")
+            .output()?;
+        assert!(output.status.success());
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(json["files"], 2);
+        assert_eq!(json["with_nested_functions"], 1);
+        assert_eq!(json["missing"][0], "pkg/mixed.py");
+        assert!(!tmp.path().join("src-min").exists());
         Ok(())
     }
 
@@ -6296,7 +18085,7 @@ def sample(value):
             None,
             true,
         )?;
-        let bundle_one: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
+        let bundle_one = read_plan_bundle(&plan_path)?;
 
         minify_plan_dir(
             &input_dir,
@@ -6311,7 +18100,7 @@ def sample(value):
             None,
             true,
         )?;
-        let bundle_two: PlanBundle = serde_json::from_str(&fs::read_to_string(&plan_path)?)?;
+        let bundle_two = read_plan_bundle(&plan_path)?;
 
         let expected = vec!["a.py", "b.py"];
         let paths_one: Vec<_> = bundle_one.files.iter().map(|f| f.path.as_str()).collect();
@@ -6322,4 +18111,640 @@ def sample(value):
 
         Ok(())
     }
+
+    #[test]
+    fn minify_dir_uses_pyproject_config_from_parent() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        fs::write(
+            tmp.path().join("pyproject.toml"),
+            "[tool.tsrs]\nexclude = [\"**/skip_*.py\"]\n",
+        )?;
+        let input_dir = tmp.path().join("pkg").join("src");
+        fs::create_dir_all(&input_dir)?;
+        fs::write(
+            input_dir.join("keep.py"),
+            "def foo(value):\n    return value\n",
+        )?;
+        fs::write(
+            input_dir.join("skip_me.py"),
+            "def bar(value):\n    return value\n",
+        )?;
+        let out_dir = tmp.path().join("out");
+
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .output()?;
+
+        assert!(output.status.success());
+        assert!(out_dir.join("keep.py").exists());
+        assert!(!out_dir.join("skip_me.py").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn explicit_cli_switch_overrides_configured_true() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join(".hidden"))?;
+        fs::write(input_dir.join("tsrs.toml"), "include-hidden = true\n")?;
+        fs::write(
+            input_dir.join("keep.py"),
+            "def foo(value):\n    return value\n",
+        )?;
+        fs::write(
+            input_dir.join(".hidden").join("secret.py"),
+            "def bar(value):\n    return value\n",
+        )?;
+
+        let run = |out: &str, extra: &[&str]| -> AnyResult<PathBuf> {
+            let out_dir = tmp.path().join(out);
+            let output = cli_cmd()?
+                .arg("minify-dir")
+                .arg(&input_dir)
+                .arg("--out-dir")
+                .arg(&out_dir)
+                .args(extra)
+                .output()?;
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            Ok(out_dir)
+        };
+
+        let configured = run("configured", &[])?;
+        assert!(configured.join(".hidden/secret.py").exists());
+        let overridden = run("overridden", &["--include-hidden=false"])?;
+        assert!(overridden.join("keep.py").exists());
+        assert!(!overridden.join(".hidden/secret.py").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn config_show_prints_effective_settings() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        fs::write(tmp.path().join("tsrs.toml"), "jobs = 2\nstats = true\n")?;
+        let nested = tmp.path().join("nested");
+        fs::create_dir_all(&nested)?;
+
+        let output = cli_cmd()?
+            .arg("config")
+            .arg("--show")
+            .arg(nested.to_str().unwrap())
+            .output()?;
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("tsrs.toml"), "unexpected output: {stdout}");
+        assert!(stdout.contains("jobs = 2"), "unexpected output: {stdout}");
+        assert!(
+            stdout.contains("stats = true"),
+            "unexpected output: {stdout}"
+        );
+        assert!(
+            stdout.contains("diff-context = 3"),
+            "unexpected output: {stdout}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn minify_source_map_matches_the_written_file() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let file_path = tmp.path().join("calc.py");
+        fs::write(
+            &file_path,
+            "def keep(value):\n    doubled = value * 2\n    return doubled\n\n\ndef comp(values):\n    total = 0\n    return [total + v for v in values]\n",
+        )?;
+        let map_path = tmp.path().join("calc.map.json");
+
+        let output = cli_cmd()?
+            .arg("minify")
+            .arg(&file_path)
+            .arg("--in-place")
+            .arg("--source-map")
+            .arg(&map_path)
+            .output()?;
+        assert!(output.status.success());
+
+        let map = SourceMap::from_json(&fs::read_to_string(&map_path)?)?;
+        let written = fs::read_to_string(&file_path)?;
+        assert!(
+            written.starts_with("def keep(a):"),
+            "unexpected output: {written}"
+        );
+        assert_eq!(
+            map.files[0].content_hash,
+            tsrs::sourcemap::content_hash(&written)
+        );
+        let mapped: Vec<_> = map.files[0].functions.keys().collect();
+        assert_eq!(mapped, ["keep"]);
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_source_map_round_trips_through_unmangle() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(
+            input_dir.join("pkg").join("calc.py"),
+            "def scale(amount, factor):\n    product = amount * factor\n    return product / missing\n",
+        )?;
+        let out_dir = tmp.path().join("out");
+        let maps_dir = tmp.path().join("maps");
+
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .arg("--source-map")
+            .arg(maps_dir.to_str().unwrap())
+            .output()?;
+        assert!(output.status.success());
+
+        let map_path = maps_dir.join("pkg").join("calc.py.map.json");
+        let map = SourceMap::from_json(&fs::read_to_string(&map_path)?)?;
+        assert_eq!(map.files.len(), 1);
+        assert_eq!(map.files[0].path, "pkg/calc.py");
+        let minified = fs::read_to_string(out_dir.join("pkg").join("calc.py"))?;
+        assert_eq!(
+            map.files[0].content_hash,
+            tsrs::sourcemap::content_hash(&minified)
+        );
+
+        let return_line = minified
+            .lines()
+            .find(|line| line.contains("return"))
+            .unwrap()
+            .to_string();
+        let traceback = format!(
+            "Traceback (most recent call last):\n  File \"/deploy/pkg/calc.py\", line 3, in scale\n{}\nNameError: name 'missing' is not defined\n",
+            return_line
+        );
+
+        let output = cli_cmd()?
+            .arg("unmangle")
+            .arg("--source-map")
+            .arg(maps_dir.to_str().unwrap())
+            .write_stdin(traceback)
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(
+            stdout.contains("    return product / missing\n"),
+            "unexpected output: {stdout}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn embedded_manifest_lists_the_renames_between_input_and_output() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(
+            input_dir.join("app.py"),
+            "def main(argv):\n    count = len(argv)\n    return count\n",
+        )?;
+        fs::write(
+            input_dir.join("pkg").join("calc.py"),
+            "def scale(amount, factor):\n    product = amount * factor\n    return product\n",
+        )?;
+        // Nested functions make the whole file bail out, so its plan never reaches the output.
+        fs::write(
+            input_dir.join("pkg").join("nested.py"),
+            "def outer(value):\n    def inner(item):\n        return item\n    result = inner(value)\n    return result\n",
+        )?;
+        let out_dir = tmp.path().join("out");
+
+        // The bare flag means `root` and leaves the input directory after it alone.
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg("--embed-manifest")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .output()?;
+        assert!(output.status.success(), "{:?}", output);
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(out_dir.join("_tsrs_manifest.json"))?)?;
+        assert_eq!(manifest["tsrs_version"], env!("CARGO_PKG_VERSION"));
+        let files = manifest["files"].as_object().unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["app.py", "pkg/calc.py"]);
+
+        let words = |text: &str| -> Vec<String> {
+            text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .filter(|word| !word.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        for (path, functions) in files {
+            let before = words(&fs::read_to_string(input_dir.join(path))?);
+            let after = words(&fs::read_to_string(out_dir.join(path))?);
+            assert_eq!(before.len(), after.len());
+            let diffed: BTreeSet<(String, String)> = after
+                .into_iter()
+                .zip(before)
+                .filter(|(renamed, original)| renamed != original)
+                .collect();
+            let listed: BTreeSet<(String, String)> = functions
+                .as_object()
+                .unwrap()
+                .values()
+                .flat_map(|renames| renames.as_object().unwrap().clone())
+                .map(|(renamed, original)| (renamed, original.as_str().unwrap().to_string()))
+                .collect();
+            assert!(!listed.is_empty());
+            assert_eq!(listed, diffed, "{path}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn per_package_python_manifests_are_not_processed_or_counted_as_output() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        fs::write(
+            input_dir.join("app.py"),
+            "def main(argv):\n    count = len(argv)\n    return count\n",
+        )?;
+        fs::write(
+            input_dir.join("pkg").join("calc.py"),
+            "def scale(amount, factor):\n    product = amount * factor\n    return product\n",
+        )?;
+        let out_dir = tmp.path().join("out");
+        // A manifest left behind by an earlier run does not make the output non-empty.
+        fs::create_dir_all(&out_dir)?;
+        fs::write(out_dir.join("_tsrs_manifest.py"), "MANIFEST = {}\n")?;
+
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(input_dir.to_str().unwrap())
+            .arg("--out-dir")
+            .arg(out_dir.to_str().unwrap())
+            .arg("--embed-manifest=per-package")
+            .arg("--manifest-format")
+            .arg("py")
+            .output()?;
+        assert!(output.status.success(), "{:?}", output);
+
+        let package =
+            RenameManifest::read(&out_dir.join("pkg").join("_tsrs_manifest.py"), true)?.unwrap();
+        assert_eq!(package.files.keys().collect::<Vec<_>>(), ["calc.py"]);
+        assert!(package.files["calc.py"]["scale"]
+            .values()
+            .any(|original| original == "product"));
+        let root = RenameManifest::read(&out_dir.join("_tsrs_manifest.py"), true)?.unwrap();
+        assert_eq!(root.files.keys().collect::<Vec<_>>(), ["app.py"]);
+        assert!(
+            fs::read_to_string(out_dir.join("pkg").join("_tsrs_manifest.py"))?
+                .starts_with("# Generated by tsrs")
+        );
+
+        let stats_path = tmp.path().join("stats.json");
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(out_dir.to_str().unwrap())
+            .arg("--in-place")
+            .arg("--embed-manifest=per-package")
+            .arg("--manifest-format")
+            .arg("py")
+            .arg("--output-json")
+            .arg(stats_path.to_str().unwrap())
+            .output()?;
+        assert!(output.status.success(), "{:?}", output);
+        let summary: serde_json::Value = serde_json::from_str(&fs::read_to_string(&stats_path)?)?;
+        assert_eq!(summary["processed"], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn schema_source_map_is_valid_json() -> AnyResult<()> {
+        let output = cli_cmd()?.arg("schema").arg("source-map").output()?;
+        assert!(output.status.success());
+        let schema: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(schema["title"], "tsrs source map");
+        Ok(())
+    }
+
+    fn progress_events(text: &str) -> AnyResult<Vec<serde_json::Value>> {
+        text.lines()
+            .map(|line| -> AnyResult<serde_json::Value> { Ok(serde_json::from_str(line)?) })
+            .collect()
+    }
+
+    #[test]
+    fn progress_json_streams_one_event_per_line() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let input_dir = tmp.path().join("src");
+        fs::create_dir_all(input_dir.join("pkg"))?;
+        let source = "def foo(value):\n    temp = value + 1\n    return temp\n";
+        fs::write(input_dir.join("pkg/mod.py"), source)?;
+        fs::write(input_dir.join("consts.py"), "LIMIT = 3\n")?;
+        fs::write(input_dir.join("broken.py"), "def broken(:\n")?;
+
+        // minify-plan-dir writes to stderr, which --quiet keeps free of other output.
+        let plan_path = tmp.path().join("plan.json");
+        let output = cli_cmd()?
+            .arg("--quiet")
+            .arg("minify-plan-dir")
+            .arg(&input_dir)
+            .arg("--out")
+            .arg(&plan_path)
+            .arg("--progress-json")
+            .output()?;
+        assert!(output.status.success());
+        let events = progress_events(&String::from_utf8(output.stderr)?)?;
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[0]["event"], "run_start");
+        assert_eq!(events[0]["command"], "minify-plan-dir");
+        assert_eq!(
+            events[1],
+            serde_json::json!({"event": "file_queued", "total": 3})
+        );
+        let planned = events
+            .iter()
+            .find(|event| event["path"] == "pkg/mod.py")
+            .expect("file_done for pkg/mod.py");
+        assert_eq!(planned["status"], "planned");
+        assert_eq!(planned["renames"], 2);
+        assert_eq!(events[5]["event"], "run_end");
+        assert_eq!(events[5]["stats"]["planned"], 2);
+
+        let events_path = tmp.path().join("events.jsonl");
+        cli_cmd()?
+            .arg("minify-dir")
+            .arg(&input_dir)
+            .arg("--out-dir")
+            .arg(tmp.path().join("out"))
+            .arg("--progress-json")
+            .arg("--progress-file")
+            .arg(&events_path)
+            .assert()
+            .success();
+        let events = progress_events(&fs::read_to_string(&events_path)?)?;
+        let kinds: Vec<&str> = events
+            .iter()
+            .filter_map(|event| event["event"].as_str())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "run_start",
+                "file_queued",
+                "file_done",
+                "file_done",
+                "file_done",
+                "run_end"
+            ]
+        );
+        assert_eq!(events[0]["options"]["in_place"], false);
+        let mut statuses: Vec<(String, String, u64)> = events[2..5]
+            .iter()
+            .map(|event| {
+                assert!(event["elapsed_ms"].is_u64());
+                (
+                    event["path"].as_str().unwrap_or_default().to_string(),
+                    event["status"].as_str().unwrap_or_default().to_string(),
+                    event["renames"].as_u64().unwrap_or_default(),
+                )
+            })
+            .collect();
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            [
+                ("broken.py".to_string(), "syntax_error".to_string(), 0),
+                ("consts.py".to_string(), "no_renames".to_string(), 0),
+                ("pkg/mod.py".to_string(), "minified".to_string(), 2),
+            ]
+        );
+        let stats: DirStats = serde_json::from_value(events[5]["stats"].clone())?;
+        assert_eq!(stats.rewritten, 1);
+        assert_eq!(stats.total_renames, 2);
+
+        fs::write(input_dir.join("fresh.py"), source)?;
+        cli_cmd()?
+            .arg("apply-plan-dir")
+            .arg(&input_dir)
+            .arg("--plan")
+            .arg(&plan_path)
+            .arg("--out-dir")
+            .arg(tmp.path().join("applied"))
+            .arg("--progress-json")
+            .arg("--progress-file")
+            .arg(&events_path)
+            .assert()
+            .success();
+        let events = progress_events(&fs::read_to_string(&events_path)?)?;
+        assert_eq!(events[1]["total"], 4);
+        let fresh = events
+            .iter()
+            .find(|event| event["path"] == "fresh.py")
+            .expect("file_done for fresh.py");
+        assert_eq!(fresh["status"], "no_plan");
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| event["event"] == "file_done")
+                .count(),
+            4
+        );
+        assert_eq!(events[events.len() - 1]["event"], "run_end");
+        Ok(())
+    }
+
+    #[test]
+    fn schema_progress_events_is_valid_json() -> AnyResult<()> {
+        let output = cli_cmd()?.arg("schema").arg("progress-events").output()?;
+        assert!(output.status.success());
+        let schema: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(schema["title"], "tsrs progress event");
+        assert_eq!(schema["oneOf"].as_array().map(Vec::len), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_rewrites_wheel_and_refreshes_record() -> AnyResult<()> {
+        use std::io::Cursor;
+        use zip::write::SimpleFileOptions;
+
+        let tmp = tempdir()?;
+        let wheel_path = tmp.path().join("demo-1.0-py3-none-any.whl");
+        let module =
+            "def scale(value, factor):\n    product = value * factor\n    return product\n";
+        let record = format!(
+            "demo/__init__.py,{},0\ndemo/core.py,{},{}\ndemo/data.txt,{},5\ndemo-1.0.dist-info/RECORD,,\n",
+            tsrs::archive::record_hash(b""),
+            tsrs::archive::record_hash(module.as_bytes()),
+            module.len(),
+            tsrs::archive::record_hash(b"data\n"),
+        );
+        let mut writer = zip::ZipWriter::new(fs::File::create(&wheel_path)?);
+        let entries: [(&str, &str, u32); 4] = [
+            ("demo/__init__.py", "", 0o644),
+            ("demo/core.py", module, 0o600),
+            ("demo/data.txt", "data\n", 0o755),
+            ("demo-1.0.dist-info/RECORD", &record, 0o644),
+        ];
+        for (name, body, mode) in entries {
+            writer.start_file(name, SimpleFileOptions::default().unix_permissions(mode))?;
+            writer.write_all(body.as_bytes())?;
+        }
+        writer.finish()?;
+
+        let out_path = tmp.path().join("out").join("demo-1.0-py3-none-any.whl");
+        fs::create_dir_all(out_path.parent().unwrap())?;
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(wheel_path.to_str().unwrap())
+            .arg("--output-archive")
+            .arg(out_path.to_str().unwrap())
+            .arg("--archive-mtime")
+            .arg("1700000000")
+            .output()?;
+        assert!(output.status.success(), "{:?}", output);
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(fs::read(&out_path)?))?;
+        let names: Vec<String> = archive.file_names().map(String::from).collect();
+        let mut ordered = Vec::new();
+        for index in 0..archive.len() {
+            let file = archive.by_index(index)?;
+            ordered.push((file.name().to_string(), file.unix_mode().map(|m| m & 0o777)));
+        }
+        assert_eq!(names.len(), 4);
+        assert_eq!(
+            ordered,
+            vec![
+                ("demo/__init__.py".to_string(), Some(0o644)),
+                ("demo/core.py".to_string(), Some(0o600)),
+                ("demo/data.txt".to_string(), Some(0o755)),
+                ("demo-1.0.dist-info/RECORD".to_string(), Some(0o644)),
+            ]
+        );
+
+        let mut minified = String::new();
+        archive
+            .by_name("demo/core.py")?
+            .read_to_string(&mut minified)?;
+        assert_ne!(minified, module);
+        assert!(!minified.contains("product"));
+
+        let mut refreshed = String::new();
+        archive
+            .by_name("demo-1.0.dist-info/RECORD")?
+            .read_to_string(&mut refreshed)?;
+        let expected_row = format!(
+            "demo/core.py,{},{}\n",
+            tsrs::archive::record_hash(minified.as_bytes()),
+            minified.len()
+        );
+        assert!(refreshed.contains(&expected_row), "{refreshed}");
+        assert!(refreshed.starts_with(&format!(
+            "demo/__init__.py,{},0\n",
+            tsrs::archive::record_hash(b"")
+        )));
+        assert!(refreshed.contains("demo/data.txt,"));
+        Ok(())
+    }
+
+    #[test]
+    fn minify_dir_filters_sdist_entries_and_copies_the_rest() -> AnyResult<()> {
+        let tmp = tempdir()?;
+        let sdist_path = tmp.path().join("demo-1.0.tar.gz");
+        let module =
+            "def scale(value, factor):\n    product = value * factor\n    return product\n";
+        let encoder = GzEncoder::new(fs::File::create(&sdist_path)?, Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        let entries: [(&str, &str, u32); 3] = [
+            ("demo-1.0/setup.py", module, 0o755),
+            ("demo-1.0/tests/test_core.py", module, 0o644),
+            ("demo-1.0/demo/core.py", module, 0o640),
+        ];
+        for (name, body, mode) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(body.len() as u64);
+            header.set_mode(mode);
+            header.set_mtime(1);
+            builder.append_data(&mut header, name, body.as_bytes())?;
+        }
+        builder.into_inner()?.finish()?;
+
+        let dry_run = cli_cmd()?
+            .arg("minify-dir")
+            .arg(sdist_path.to_str().unwrap())
+            .arg("--dry-run")
+            .arg("--exclude")
+            .arg("**/tests/**")
+            .arg("--stats")
+            .arg("--output-json")
+            .arg(tmp.path().join("dry.json").to_str().unwrap())
+            .output()?;
+        assert!(dry_run.status.success(), "{:?}", dry_run);
+        let summary: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(tmp.path().join("dry.json"))?)?;
+        assert_eq!(summary["processed"], 2);
+        assert_eq!(summary["rewritten"], 2);
+
+        let missing_output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(sdist_path.to_str().unwrap())
+            .output()?;
+        assert!(!missing_output.status.success());
+
+        let out_path = tmp.path().join("demo-1.0-min.tar.gz");
+        let output = cli_cmd()?
+            .arg("minify-dir")
+            .arg(sdist_path.to_str().unwrap())
+            .arg("--output-archive")
+            .arg(out_path.to_str().unwrap())
+            .arg("--exclude")
+            .arg("**/tests/**")
+            .arg("--archive-mtime")
+            .arg("1700000000")
+            .output()?;
+        assert!(output.status.success(), "{:?}", output);
+
+        let mut archive = tar::Archive::new(MultiGzDecoder::new(fs::File::open(&out_path)?));
+        let mut seen = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let header = entry.header().clone();
+            let mut body = String::new();
+            entry.read_to_string(&mut body)?;
+            seen.push((
+                entry.path()?.to_string_lossy().into_owned(),
+                header.mode()?,
+                header.mtime()?,
+                body == module,
+            ));
+        }
+        assert_eq!(
+            seen,
+            vec![
+                ("demo-1.0/setup.py".to_string(), 0o755, 1_700_000_000, false),
+                (
+                    "demo-1.0/tests/test_core.py".to_string(),
+                    0o644,
+                    1_700_000_000,
+                    true
+                ),
+                (
+                    "demo-1.0/demo/core.py".to_string(),
+                    0o640,
+                    1_700_000_000,
+                    false
+                ),
+            ]
+        );
+        Ok(())
+    }
 }
@@ -8,17 +8,25 @@
 //! - Cross-package analysis
 //! - Reachability from entry points
 
-use crate::error::{Result, TsrsError};
-use rustpython_parser::{ast, Parse};
+use crate::error::{read_source, Result, TsrsError};
+use crate::fswalk::build_globset;
+use crate::reporting::{Confidence, RemovalSpan};
+use globset::GlobSet;
+use rustpython_parser::ast::Ranged;
+use rustpython_parser::{ast, lexer, Mode, Parse, Tok};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::path::Path;
+use std::sync::OnceLock;
 
 /// Unique identifier for a function node
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct FunctionId(pub usize);
 
 /// Source location information
+///
+/// For functions this is the 1-based line and column of the `def` keyword; zero when
+/// unknown.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SourceLocation {
     pub line: usize,
@@ -57,6 +65,49 @@ pub enum EntryPointKind {
     Regular,
 }
 
+/// Why a function counts as live even though nothing analyzed calls it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RootReason {
+    /// Imported into, or listed in `__all__` of, an analyzed `__init__.py`
+    InitReExport,
+    /// Defined at the top level of a module matched by the public-roots globs
+    PublicModule,
+}
+
+impl RootReason {
+    /// Short description for reports
+    #[must_use]
+    pub fn describe(self) -> &'static str {
+        match self {
+            RootReason::InitReExport => "root: __init__ re-export",
+            RootReason::PublicModule => "root: public module",
+        }
+    }
+}
+
+/// A name made live by a declaration rather than a call
+#[derive(Debug)]
+struct DeclaredRoot {
+    /// Package whose source declared the root
+    package: String,
+    /// Function or class name
+    name: String,
+    /// Whether definitions in packages nested below `package` match too
+    nested: bool,
+    reason: RootReason,
+}
+
+impl DeclaredRoot {
+    fn covers(&self, package: &str) -> bool {
+        package == self.package
+            || (self.nested
+                && package
+                    .strip_prefix(self.package.as_str())
+                    .is_some_and(|rest| rest.starts_with(['/', '\\', '.'])))
+    }
+}
+
 /// A function in the call graph
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CallGraphNode {
@@ -70,6 +121,16 @@ pub struct CallGraphNode {
     pub decorators: Vec<String>,
     /// Whether this function is marked with @property or similar
     pub is_special: bool,
+    /// Qualified name of the enclosing class, for methods
+    #[serde(default)]
+    pub class: Option<String>,
+    /// File the function was defined in, when analyzed through
+    /// [`CallGraphAnalyzer::analyze_file`] or [`CallGraphAnalyzer::analyze_source_in_file`]
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Lines from the first decorator through the end of the body
+    #[serde(default)]
+    pub removal_span: Option<RemovalSpan>,
 }
 
 /// A call edge from caller to callee
@@ -77,23 +138,208 @@ pub struct CallGraphNode {
 pub struct CallEdge {
     pub caller: FunctionId,
     pub callee: FunctionId,
+    pub site: CallSite,
+}
+
+/// Where a call sits in the caller's source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CallSite {
+    /// 1-based line and column of the start of the call expression
     pub location: SourceLocation,
+    /// Byte offset of the start of the call expression
+    pub start_byte: usize,
+    /// Byte offset just past the end of the call expression
+    pub end_byte: usize,
+    /// Made inside an `assert` or an `if __debug__:` block, which `python -O` skips
+    #[serde(default)]
+    pub debug_only: bool,
+}
+
+/// Base classes that contribute no methods a subclass could override.
+const METHODLESS_BASES: &[&str] = &["object", "ABC", "Generic", "Protocol"];
+
+/// A class defined in an analyzed package
+#[derive(Debug, Default)]
+struct ClassInfo {
+    /// Base class names as written; dotted bases keep only their last component
+    bases: Vec<String>,
+    /// Whether some base could not be reduced to a plain name (e.g. `mod.Base`)
+    opaque_base: bool,
+    /// Methods defined directly in the class body
+    methods: HashMap<String, FunctionId>,
+    /// Methods carrying any decorator
+    decorated: HashSet<FunctionId>,
+}
+
+/// Last component of a possibly class-qualified function name.
+fn simple_name(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+/// Prefix `name` with its enclosing class, if any.
+fn qualify(class: Option<&str>, name: &str) -> String {
+    match class {
+        Some(class) => format!("{class}.{name}"),
+        None => name.to_string(),
+    }
+}
+
+/// Whether an `if` test is `__debug__`, which is false under `python -O`
+fn is_debug_test(test: &ast::Expr) -> bool {
+    matches!(test, ast::Expr::Name(name) if name.id.as_str() == "__debug__")
+}
+
+/// Lines of a source holding `def` keywords and `# tsrs: keep` comments.
+#[derive(Debug, Default)]
+struct KeepMarkers {
+    /// Byte offset at which each line starts.
+    line_starts: Vec<usize>,
+    /// Length of the source in bytes.
+    len: usize,
+    /// Byte offsets of `def` keywords, in ascending order.
+    defs: Vec<usize>,
+    /// Lines (0-based) carrying the keep directive.
+    keep_lines: HashSet<usize>,
+    /// Identifier-like words inside string literals.
+    string_words: HashSet<String>,
+}
+
+impl KeepMarkers {
+    fn scan(source: &str) -> Self {
+        let mut markers = KeepMarkers {
+            line_starts: std::iter::once(0)
+                .chain(source.match_indices('\n').map(|(index, _)| index + 1))
+                .collect(),
+            len: source.len(),
+            ..KeepMarkers::default()
+        };
+
+        for (token, range) in lexer::lex(source, Mode::Module).map_while(|result| result.ok()) {
+            let line = markers.line_of(usize::from(range.start()));
+            match token {
+                Tok::Def => markers.defs.push(usize::from(range.start())),
+                Tok::Comment(text) if is_keep_directive(&text) => {
+                    markers.keep_lines.insert(line);
+                }
+                Tok::String { value, .. } => {
+                    markers.string_words.extend(
+                        value
+                            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                            .filter(|word| !word.is_empty())
+                            .map(str::to_string),
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        markers
+    }
+
+    fn line_of(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next.saturating_sub(1),
+        }
+    }
+
+    /// Whether a function whose header (first decorator or `def`) starts at `start` is
+    /// marked between that line and its `def` line.
+    fn keeps(&self, start: usize) -> bool {
+        if self.keep_lines.is_empty() {
+            return false;
+        }
+        let start_line = self.line_of(start);
+        let def_line = self.line_of(self.def_offset(start));
+        (start_line..=def_line).any(|line| self.keep_lines.contains(&line))
+    }
+
+    /// Offset of the first `def` keyword at or after `start`.
+    fn def_offset(&self, start: usize) -> usize {
+        let index = self.defs.partition_point(|offset| *offset < start);
+        self.defs.get(index).copied().unwrap_or(start)
+    }
+
+    /// Location of the `def` keyword of a function whose header starts at `start`.
+    fn def_location(&self, start: usize) -> SourceLocation {
+        let offset = self.def_offset(start);
+        let line = self.line_of(offset);
+        SourceLocation {
+            line: line + 1,
+            col: offset - self.line_starts[line] + 1,
+        }
+    }
+
+    /// Whole lines from the one holding `start` through the one holding the last byte
+    /// before `end`.
+    fn removal_span(&self, start: usize, end: usize) -> RemovalSpan {
+        let start_line = self.line_of(start);
+        let end_line = self.line_of(end.saturating_sub(1).max(start));
+        RemovalSpan {
+            start_byte: self.line_starts[start_line],
+            end_byte: self
+                .line_starts
+                .get(end_line + 1)
+                .copied()
+                .unwrap_or(self.len),
+            start_line: start_line + 1,
+            end_line: end_line + 1,
+        }
+    }
+}
+
+/// Whether a comment is exactly the keep directive (`# tsrs: keep`, spacing aside).
+fn is_keep_directive(comment: &str) -> bool {
+    comment
+        .trim_start_matches('#')
+        .trim()
+        .strip_prefix("tsrs:")
+        .is_some_and(|directive| directive.trim() == "keep")
+}
+
+/// Offset of a function's first decorator, or of the function itself when undecorated.
+fn header_start(range_start: usize, decorators: &[ast::Expr]) -> usize {
+    decorators
+        .iter()
+        .map(|decorator| usize::from(decorator.range().start()))
+        .chain(std::iter::once(range_start))
+        .min()
+        .unwrap_or(range_start)
 }
 
-/// Represents a function or class reference (legacy, for compatibility)
+/// Represents a function or class reference
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FunctionRef {
     /// Package name (top-level module)
     pub package: String,
-    /// Function or class name
+    /// Function or class name; methods are qualified by their class (`Class.method`)
     pub name: String,
+    /// Lines from the first decorator through the end of the body, for functions of the
+    /// analyzed sources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<RemovalSpan>,
 }
 
 impl FunctionRef {
     /// Create a new function reference
     #[must_use]
     pub fn new(package: String, name: String) -> Self {
-        FunctionRef { package, name }
+        FunctionRef {
+            package,
+            name,
+            span: None,
+        }
+    }
+
+    /// Whether this is `name`, written as `Class.method` or prefixed with the package
+    /// (`pkg.Class.method`)
+    #[must_use]
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name == name
+            || name
+                .strip_prefix(self.package.as_str())
+                .and_then(|rest| rest.strip_prefix('.'))
+                == Some(self.name.as_str())
     }
 
     /// Check if this is a standard library or builtin
@@ -117,6 +363,12 @@ pub struct PackageCallGraph {
     pub external_calls: HashSet<FunctionRef>,
     /// Functions/classes used locally (from same package)
     pub internal_calls: HashSet<String>,
+    /// Functions by id, filled in the graph from [`CallGraphAnalyzer::graph`]
+    #[serde(skip)]
+    functions: BTreeMap<FunctionId, FunctionRef>,
+    /// Calls between `functions` as (caller, callee, site), by caller and position
+    #[serde(skip)]
+    calls: Vec<(FunctionId, FunctionId, CallSite)>,
 }
 
 impl PackageCallGraph {
@@ -128,6 +380,71 @@ impl PackageCallGraph {
             definitions: HashSet::new(),
             external_calls: HashSet::new(),
             internal_calls: HashSet::new(),
+            functions: BTreeMap::new(),
+            calls: Vec::new(),
+        }
+    }
+
+    /// Functions of the graph, in id order
+    pub fn nodes(&self) -> impl Iterator<Item = &FunctionRef> {
+        self.functions.values()
+    }
+
+    /// The function with id `id`
+    #[must_use]
+    pub fn node(&self, id: FunctionId) -> Option<&FunctionRef> {
+        self.functions.get(&id)
+    }
+
+    /// Calls between the functions of the graph as (caller, callee, site), ordered by
+    /// caller and then by where the call sits
+    pub fn edges(&self) -> impl Iterator<Item = (FunctionId, FunctionId, CallSite)> + '_ {
+        self.calls.iter().copied()
+    }
+
+    /// Functions calling the one named `name` (see [`FunctionRef::is_named`]), once per
+    /// call site
+    #[must_use]
+    pub fn callers_of(&self, name: &str) -> Vec<(&FunctionRef, CallSite)> {
+        self.calls
+            .iter()
+            .filter(|(_, callee, _)| self.node(*callee).is_some_and(|f| f.is_named(name)))
+            .filter_map(|(caller, _, site)| Some((self.node(*caller)?, *site)))
+            .collect()
+    }
+
+    /// Functions the one named `name` (see [`FunctionRef::is_named`]) calls, once per
+    /// call site
+    #[must_use]
+    pub fn callees_of(&self, name: &str) -> Vec<(&FunctionRef, CallSite)> {
+        self.calls
+            .iter()
+            .filter(|(caller, _, _)| self.node(*caller).is_some_and(|f| f.is_named(name)))
+            .filter_map(|(_, callee, site)| Some((self.node(*callee)?, *site)))
+            .collect()
+    }
+
+    /// Serializable form of the nodes and edges, with functions identified by id
+    #[must_use]
+    pub fn export(&self) -> CallGraphExport {
+        CallGraphExport {
+            package: self.package.clone(),
+            nodes: self
+                .functions
+                .iter()
+                .map(|(id, function)| ExportedFunction {
+                    id: *id,
+                    function: function.clone(),
+                })
+                .collect(),
+            edges: self
+                .edges()
+                .map(|(caller, callee, site)| CallEdge {
+                    caller,
+                    callee,
+                    site,
+                })
+                .collect(),
         }
     }
 
@@ -147,6 +464,25 @@ impl PackageCallGraph {
     }
 }
 
+/// Nodes and edges of a [`PackageCallGraph`], as written by `tsrs-cli call-graph --json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallGraphExport {
+    /// The analyzed package, empty when the graph spans several
+    pub package: String,
+    /// Functions in id order
+    pub nodes: Vec<ExportedFunction>,
+    /// Calls by caller and position
+    pub edges: Vec<CallEdge>,
+}
+
+/// A node of a [`CallGraphExport`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedFunction {
+    pub id: FunctionId,
+    #[serde(flatten)]
+    pub function: FunctionRef,
+}
+
 /// Analyzes function calls per package using AST traversal
 pub struct CallGraphAnalyzer {
     /// Legacy per-package graphs (for backward compatibility)
@@ -159,6 +495,9 @@ pub struct CallGraphAnalyzer {
     next_id: usize,
     /// Map from (package, function_name) to FunctionId
     function_index: HashMap<(String, String), FunctionId>,
+    /// Other definitions of a function's name in its package, such as the one in the
+    /// other branch of an `if`; a call may reach any of them, so they are live together
+    redefinitions: HashMap<FunctionId, Vec<FunctionId>>,
     /// Entry points (functions reachable from script/module init)
     entry_points: HashSet<FunctionId>,
     /// Public API exports from each package
@@ -166,6 +505,34 @@ pub struct CallGraphAnalyzer {
     /// Import tracking: (package, local_name) → (source_package, source_function)
     /// Maps how functions are imported from other packages
     imports: HashMap<(String, String), (String, String)>,
+    /// Functions marked with `# tsrs: keep`, never reported as dead code
+    suppressed: HashSet<FunctionId>,
+    /// Functions carrying any decorator
+    decorated: HashSet<FunctionId>,
+    /// Identifier-like words inside the string literals of every analyzed source
+    string_words: HashSet<String>,
+    /// Classes keyed by (package, qualified class name)
+    classes: HashMap<(String, String), ClassInfo>,
+    /// File of the source being analyzed, recorded on the functions it defines
+    current_file: Option<String>,
+    /// `obj.method` references: (caller, attribute name, site); `None` is module level
+    method_refs: Vec<(Option<FunctionId>, String, CallSite)>,
+    /// Possible instantiations `Cls()`: (caller, (package, class name), site)
+    instantiations: Vec<(Option<FunctionId>, (String, String), CallSite)>,
+    /// Names made live by `__init__.py` re-exports and public-root modules
+    declared_roots: Vec<DeclaredRoot>,
+    /// Files whose top-level definitions are all roots, from [`Self::set_public_roots`]
+    public_roots: Option<GlobSet>,
+    /// Functions called at module level only inside `assert` or `if __debug__:`
+    debug_entry_points: HashSet<FunctionId>,
+    /// Whether the calls being walked sit inside an `assert` or `if __debug__:` block
+    debug_context: bool,
+    /// Whether debug-only references keep functions live
+    asserts_are_roots: bool,
+    /// Byte offset at which each line of the source being analyzed starts
+    line_starts: Vec<usize>,
+    /// Combined graph of everything analyzed, built on first use after each source
+    graph: OnceLock<PackageCallGraph>,
 }
 
 impl CallGraphAnalyzer {
@@ -178,13 +545,56 @@ impl CallGraphAnalyzer {
             edges: Vec::new(),
             next_id: 0,
             function_index: HashMap::new(),
+            redefinitions: HashMap::new(),
             entry_points: HashSet::new(),
             public_exports: HashMap::new(),
             imports: HashMap::new(),
+            suppressed: HashSet::new(),
+            decorated: HashSet::new(),
+            string_words: HashSet::new(),
+            classes: HashMap::new(),
+            current_file: None,
+            method_refs: Vec::new(),
+            instantiations: Vec::new(),
+            declared_roots: Vec::new(),
+            public_roots: None,
+            debug_entry_points: HashSet::new(),
+            debug_context: false,
+            asserts_are_roots: true,
+            line_starts: Vec::new(),
+            graph: OnceLock::new(),
         }
     }
 
+    /// Count functions referenced only inside `assert` statements or `if __debug__:`
+    /// blocks as live (the default)
+    ///
+    /// `python -O` skips those references, so with this off such functions are reported as
+    /// dead code.
+    pub fn set_asserts_are_roots(&mut self, asserts_are_roots: bool) {
+        self.asserts_are_roots = asserts_are_roots;
+    }
+
+    /// Treat every top-level function and class of the files matching `patterns` as live
+    ///
+    /// Patterns are globs such as `mypkg/api/*.py`, matched against the file given to
+    /// [`analyze_file`](Self::analyze_file) or
+    /// [`analyze_source_in_file`](Self::analyze_source_in_file); set them before analyzing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a pattern is not a valid glob.
+    pub fn set_public_roots(&mut self, patterns: &[String]) -> Result<()> {
+        self.public_roots = if patterns.is_empty() {
+            None
+        } else {
+            Some(build_globset(patterns, false)?)
+        };
+        Ok(())
+    }
+
     /// Register a function in the call graph
+    #[allow(clippy::too_many_arguments)]
     fn register_function(
         &mut self,
         package: String,
@@ -193,6 +603,7 @@ impl CallGraphAnalyzer {
         kind: FunctionKind,
         entry_point: EntryPointKind,
         decorators: Vec<String>,
+        class: Option<&str>,
     ) -> FunctionId {
         let id = FunctionId(self.next_id);
         self.next_id += 1;
@@ -210,10 +621,29 @@ impl CallGraphAnalyzer {
             entry_point,
             decorators,
             is_special,
+            class: class.map(str::to_string),
+            file: self.current_file.clone(),
+            removal_span: None,
         };
 
         self.nodes.insert(id, node);
-        self.function_index.insert((package, name), id);
+        if let Some(class) = class {
+            if let Some(info) = self.classes.get_mut(&(package.clone(), class.to_string())) {
+                info.methods.insert(simple_name(&name).to_string(), id);
+            }
+        }
+        if let Some(previous) = self.function_index.insert((package, name), id) {
+            let mut others = self
+                .redefinitions
+                .get(&previous)
+                .cloned()
+                .unwrap_or_default();
+            others.push(previous);
+            for other in &others {
+                self.redefinitions.entry(*other).or_default().push(id);
+            }
+            self.redefinitions.insert(id, others);
+        }
 
         if matches!(
             entry_point,
@@ -231,8 +661,27 @@ impl CallGraphAnalyzer {
     ///
     /// Returns an error if the file cannot be read or parsed.
     pub fn analyze_file<P: AsRef<Path>>(&mut self, path: P, package: &str) -> Result<()> {
-        let source = std::fs::read_to_string(path).map_err(TsrsError::Io)?;
-        self.analyze_source(package, &source)
+        let path = path.as_ref();
+        let source = read_source(path)?;
+        self.analyze_source_in_file(package, &source, &path.display().to_string())
+            .map_err(|err| err.with_file(path))
+    }
+
+    /// Analyze Python source read from `file`, recording the file on its functions
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source code cannot be parsed.
+    pub fn analyze_source_in_file(
+        &mut self,
+        package: &str,
+        source: &str,
+        file: &str,
+    ) -> Result<()> {
+        self.current_file = Some(file.to_string());
+        let result = self.analyze_source(package, source);
+        self.current_file = None;
+        result
     }
 
     /// Analyze Python source code using AST traversal
@@ -241,16 +690,22 @@ impl CallGraphAnalyzer {
     ///
     /// Returns an error if the source code cannot be parsed.
     pub fn analyze_source(&mut self, package: &str, source: &str) -> Result<()> {
-        let suite = ast::Suite::parse(source, "<source>")
-            .map_err(|e| TsrsError::ParseError(format!("Failed to parse Python: {e}")))?;
+        let suite =
+            ast::Suite::parse(source, "<source>").map_err(|err| TsrsError::parse(source, &err))?;
+        self.graph = OnceLock::new();
 
         // First pass: detect exports, entry points, and imports from module level
         self.detect_module_exports(package, &suite)?;
+        self.detect_declared_roots(package, &suite)?;
         self.detect_main_block(&suite)?;
         self.extract_imports(package, &suite)?;
 
         // Second pass: register all functions
-        self.register_module_functions_suite(package, &suite)?;
+        let mut markers = KeepMarkers::scan(source);
+        self.string_words
+            .extend(std::mem::take(&mut markers.string_words));
+        self.line_starts = markers.line_starts.clone();
+        self.register_module_functions_suite(package, &suite, &markers, None)?;
 
         // Third pass: build call edges
         self.extract_calls_suite(package, &suite)?;
@@ -300,6 +755,76 @@ impl CallGraphAnalyzer {
         Ok(())
     }
 
+    /// Record the names an `__init__.py` re-exports, and the top-level definitions of files
+    /// matched by [`set_public_roots`](Self::set_public_roots)
+    ///
+    /// Re-exports cover definitions in the package and the packages nested below it, since
+    /// `from .core import Engine` may name any of its modules.
+    fn detect_declared_roots(&mut self, package: &str, suite: &[ast::Stmt]) -> Result<()> {
+        let Some(file) = self.current_file.as_deref() else {
+            return Ok(());
+        };
+        let is_init = Path::new(file)
+            .file_name()
+            .is_some_and(|name| name == "__init__.py");
+        let public = self
+            .public_roots
+            .as_ref()
+            .is_some_and(|globs| globs.is_match(file));
+
+        let mut re_exports = HashSet::new();
+        let mut public_defs = Vec::new();
+        for stmt in suite {
+            match stmt {
+                ast::Stmt::ImportFrom(import_from) if is_init => {
+                    for alias in &import_from.names {
+                        if alias.name.as_str() != "*" {
+                            re_exports.insert(alias.name.as_str().to_string());
+                        }
+                    }
+                }
+                ast::Stmt::Assign(assign) if is_init => {
+                    let is_all = assign.targets.iter().any(|target| {
+                        matches!(target, ast::Expr::Name(name) if name.id.as_str() == "__all__")
+                    });
+                    if is_all {
+                        self.extract_all_exports(&assign.value, &mut re_exports)?;
+                    }
+                }
+                ast::Stmt::FunctionDef(func_def) if public => {
+                    public_defs.push(func_def.name.as_str().to_string());
+                }
+                ast::Stmt::AsyncFunctionDef(func_def) if public => {
+                    public_defs.push(func_def.name.as_str().to_string());
+                }
+                ast::Stmt::ClassDef(class_def) if public => {
+                    public_defs.push(class_def.name.as_str().to_string());
+                }
+                _ => {}
+            }
+        }
+
+        let mut re_exports: Vec<String> = re_exports.into_iter().collect();
+        re_exports.sort();
+        for name in re_exports {
+            self.declared_roots.push(DeclaredRoot {
+                package: package.to_string(),
+                name,
+                nested: true,
+                reason: RootReason::InitReExport,
+            });
+        }
+        for name in public_defs {
+            self.declared_roots.push(DeclaredRoot {
+                package: package.to_string(),
+                name,
+                nested: false,
+                reason: RootReason::PublicModule,
+            });
+        }
+        Ok(())
+    }
+
     /// Extract list of names from __all__ = [...] assignment
     fn extract_all_exports(&self, expr: &ast::Expr, exports: &mut HashSet<String>) -> Result<()> {
         match expr {
@@ -445,15 +970,26 @@ impl CallGraphAnalyzer {
         &mut self,
         package: &str,
         suite: &[ast::Stmt],
+        markers: &KeepMarkers,
+        class: Option<&str>,
     ) -> Result<()> {
         for stmt in suite {
-            self.register_module_functions(package, stmt)?;
+            self.register_module_functions(package, stmt, markers, class)?;
         }
         Ok(())
     }
 
     /// Register functions at module level (handles nested classes/functions too)
-    fn register_module_functions(&mut self, package: &str, stmt: &ast::Stmt) -> Result<()> {
+    ///
+    /// `class` is the qualified name of the class whose body holds `stmt`; methods are
+    /// registered as `Class.method`.
+    fn register_module_functions(
+        &mut self,
+        package: &str,
+        stmt: &ast::Stmt,
+        markers: &KeepMarkers,
+        class: Option<&str>,
+    ) -> Result<()> {
         match stmt {
             ast::Stmt::FunctionDef(func_def) => {
                 let decorators = func_def
@@ -466,6 +1002,8 @@ impl CallGraphAnalyzer {
                 let is_dunder = func_name.starts_with("__") && func_name.ends_with("__");
                 let kind = if is_dunder {
                     FunctionKind::DunderMethod
+                } else if class.is_some() {
+                    FunctionKind::Method
                 } else {
                     FunctionKind::Function
                 };
@@ -477,19 +1015,35 @@ impl CallGraphAnalyzer {
                     EntryPointKind::Regular
                 };
 
-                let location = SourceLocation { line: 0, col: 0 };
+                let header = header_start(
+                    usize::from(func_def.range().start()),
+                    &func_def.decorator_list,
+                );
+                let location = markers.def_location(header);
 
-                self.register_function(
+                let id = self.register_function(
                     package.to_string(),
-                    func_name.to_string(),
+                    qualify(class, func_name),
                     location,
                     kind,
                     entry_point,
                     decorators,
+                    class,
+                );
+                if markers.keeps(header) {
+                    self.suppressed.insert(id);
+                }
+                if !func_def.decorator_list.is_empty() {
+                    self.decorated.insert(id);
+                    self.mark_decorated(package, class, id);
+                }
+                self.record_removal_span(
+                    id,
+                    markers.removal_span(header, usize::from(func_def.range().end())),
                 );
 
                 // Also register nested functions/classes
-                self.register_module_functions_suite(package, &func_def.body)?;
+                self.register_module_functions_suite(package, &func_def.body, markers, None)?;
             }
             ast::Stmt::AsyncFunctionDef(func_def) => {
                 let decorators = func_def
@@ -508,23 +1062,83 @@ impl CallGraphAnalyzer {
                     EntryPointKind::Regular
                 };
 
-                let location = SourceLocation { line: 0, col: 0 };
+                let header = header_start(
+                    usize::from(func_def.range().start()),
+                    &func_def.decorator_list,
+                );
+                let location = markers.def_location(header);
 
-                self.register_function(
+                let kind = if class.is_some() {
+                    FunctionKind::Method
+                } else {
+                    FunctionKind::AsyncFunction
+                };
+                let id = self.register_function(
                     package.to_string(),
-                    func_name.to_string(),
+                    qualify(class, func_name),
                     location,
-                    FunctionKind::AsyncFunction,
+                    kind,
                     entry_point,
                     decorators,
+                    class,
+                );
+                if markers.keeps(header) {
+                    self.suppressed.insert(id);
+                }
+                if !func_def.decorator_list.is_empty() {
+                    self.decorated.insert(id);
+                    self.mark_decorated(package, class, id);
+                }
+                self.record_removal_span(
+                    id,
+                    markers.removal_span(header, usize::from(func_def.range().end())),
                 );
 
                 // Also register nested functions/classes
-                self.register_module_functions_suite(package, &func_def.body)?;
+                self.register_module_functions_suite(package, &func_def.body, markers, None)?;
             }
             ast::Stmt::ClassDef(class_def) => {
+                let qualified = qualify(class, class_def.name.as_str());
+                let mut info = ClassInfo::default();
+                for base in &class_def.bases {
+                    // `Generic[T]` contributes its subscripted name
+                    let base = match base {
+                        ast::Expr::Subscript(subscript) => subscript.value.as_ref(),
+                        other => other,
+                    };
+                    match base {
+                        ast::Expr::Name(name) => info.bases.push(name.id.as_str().to_string()),
+                        ast::Expr::Attribute(attr) => {
+                            info.bases.push(attr.attr.as_str().to_string());
+                            info.opaque_base = true;
+                        }
+                        _ => info.opaque_base = true,
+                    }
+                }
+                self.classes
+                    .insert((package.to_string(), qualified.clone()), info);
+
                 // Register methods inside classes
-                self.register_module_functions_suite(package, &class_def.body)?;
+                self.register_module_functions_suite(
+                    package,
+                    &class_def.body,
+                    markers,
+                    Some(&qualified),
+                )?;
+            }
+            // Functions defined conditionally, e.g. per platform or as an import fallback
+            ast::Stmt::If(if_stmt) => {
+                self.register_module_functions_suite(package, &if_stmt.body, markers, class)?;
+                self.register_module_functions_suite(package, &if_stmt.orelse, markers, class)?;
+            }
+            ast::Stmt::Try(try_stmt) => {
+                self.register_module_functions_suite(package, &try_stmt.body, markers, class)?;
+                for handler in &try_stmt.handlers {
+                    let ast::ExceptHandler::ExceptHandler(h) = handler;
+                    self.register_module_functions_suite(package, &h.body, markers, class)?;
+                }
+                self.register_module_functions_suite(package, &try_stmt.orelse, markers, class)?;
+                self.register_module_functions_suite(package, &try_stmt.finalbody, markers, class)?;
             }
             _ => {}
         }
@@ -532,6 +1146,26 @@ impl CallGraphAnalyzer {
         Ok(())
     }
 
+    /// Record the lines deleting function `id` would remove
+    fn record_removal_span(&mut self, id: FunctionId, span: RemovalSpan) {
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.removal_span = Some(span);
+        }
+    }
+
+    /// Record a decorated method of `class`
+    fn mark_decorated(&mut self, package: &str, class: Option<&str>, id: FunctionId) {
+        let Some(class) = class else {
+            return;
+        };
+        if let Some(info) = self
+            .classes
+            .get_mut(&(package.to_string(), class.to_string()))
+        {
+            info.decorated.insert(id);
+        }
+    }
+
     /// Extract function calls from all statements in a suite (module level)
     fn extract_calls_suite(&mut self, package: &str, suite: &[ast::Stmt]) -> Result<()> {
         for stmt in suite {
@@ -577,14 +1211,20 @@ impl CallGraphAnalyzer {
                 }
             }
             ast::Stmt::ClassDef(class_def) => {
-                // Walk class methods
-                for body_stmt in &class_def.body {
-                    self.extract_calls_from_stmt(package, body_stmt, current_func)?;
-                }
+                self.extract_calls_from_class(package, None, class_def, current_func)?;
             }
             ast::Stmt::If(if_stmt) => {
-                for body_stmt in &if_stmt.body {
-                    self.extract_calls_from_stmt(package, body_stmt, current_func)?;
+                if is_debug_test(&if_stmt.test) {
+                    let outer = std::mem::replace(&mut self.debug_context, true);
+                    let walked = if_stmt.body.iter().try_for_each(|body_stmt| {
+                        self.extract_calls_from_stmt(package, body_stmt, current_func)
+                    });
+                    self.debug_context = outer;
+                    walked?;
+                } else {
+                    for body_stmt in &if_stmt.body {
+                        self.extract_calls_from_stmt(package, body_stmt, current_func)?;
+                    }
                 }
                 for else_stmt in &if_stmt.orelse {
                     self.extract_calls_from_stmt(package, else_stmt, current_func)?;
@@ -654,12 +1294,60 @@ impl CallGraphAnalyzer {
                     self.extract_calls_from_expr(package, value, current_func)?;
                 }
             }
+            ast::Stmt::Assert(assert_stmt) => {
+                let outer = std::mem::replace(&mut self.debug_context, true);
+                let walked = self
+                    .extract_calls_from_expr(package, &assert_stmt.test, current_func)
+                    .and_then(|()| match &assert_stmt.msg {
+                        Some(msg) => self.extract_calls_from_expr(package, msg, current_func),
+                        None => Ok(()),
+                    });
+                self.debug_context = outer;
+                walked?;
+            }
             _ => {}
         }
 
         Ok(())
     }
 
+    /// Walk a class body, using each method's class-qualified node as call context
+    ///
+    /// Statements directly in the class body run with the enclosing context.
+    fn extract_calls_from_class(
+        &mut self,
+        package: &str,
+        outer: Option<&str>,
+        class_def: &ast::StmtClassDef,
+        current_func: Option<FunctionId>,
+    ) -> Result<()> {
+        let qualified = qualify(outer, class_def.name.as_str());
+        for body_stmt in &class_def.body {
+            let (name, body) = match body_stmt {
+                ast::Stmt::FunctionDef(func_def) => (func_def.name.as_str(), &func_def.body),
+                ast::Stmt::AsyncFunctionDef(func_def) => (func_def.name.as_str(), &func_def.body),
+                ast::Stmt::ClassDef(inner) => {
+                    self.extract_calls_from_class(package, Some(&qualified), inner, current_func)?;
+                    continue;
+                }
+                other => {
+                    self.extract_calls_from_stmt(package, other, current_func)?;
+                    continue;
+                }
+            };
+            let method_id = self
+                .function_index
+                .get(&(package.to_string(), format!("{qualified}.{name}")))
+                .copied();
+            if let Some(method_id) = method_id {
+                for stmt in body {
+                    self.extract_calls_from_stmt(package, stmt, Some(method_id))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Extract calls from an expression tree
     fn extract_calls_from_expr(
         &mut self,
@@ -679,19 +1367,47 @@ impl CallGraphAnalyzer {
                         // Look up the callee using resolved package and function name
                         if let Some(callee_id) = self
                             .function_index
-                            .get(&(resolved_pkg, resolved_func))
+                            .get(&(resolved_pkg.clone(), resolved_func.clone()))
                             .copied()
                         {
                             if let Some(caller_id) = current_func {
-                                let location = SourceLocation { line: 0, col: 0 };
+                                let site = self.call_site(call);
                                 self.edges.push(CallEdge {
                                     caller: caller_id,
                                     callee: callee_id,
-                                    location,
+                                    site,
                                 });
+                            } else if self.debug_context {
+                                self.debug_entry_points.insert(callee_id);
+                            } else {
+                                // Called while the module is imported
+                                self.entry_points.insert(callee_id);
                             }
+                        } else {
+                            let site = self.call_site(call);
+                            self.instantiations.push((
+                                current_func,
+                                (resolved_pkg, resolved_func),
+                                site,
+                            ));
                         }
+                    } else {
+                        // The class may live in a source analyzed later
+                        let site = self.call_site(call);
+                        self.instantiations.push((
+                            current_func,
+                            (package.to_string(), func_name.to_string()),
+                            site,
+                        ));
                     }
+                } else if let ast::Expr::Attribute(attr) = call.func.as_ref() {
+                    // A called method is referenced at the call
+                    let site = self.call_site(call);
+                    self.method_refs
+                        .push((current_func, attr.attr.as_str().to_string(), site));
+                    self.extract_calls_from_expr(package, &attr.value, current_func)?;
+                } else {
+                    self.extract_calls_from_expr(package, &call.func, current_func)?;
                 }
                 // Recursively process arguments
                 for arg in &call.args {
@@ -701,6 +1417,14 @@ impl CallGraphAnalyzer {
                     self.extract_calls_from_expr(package, &keyword.value, current_func)?;
                 }
             }
+            // `obj.method` (called or passed around): the receiver's type is unknown,
+            // so any analyzed class defining the method may be the target
+            ast::Expr::Attribute(attr) => {
+                let site = self.call_site(attr);
+                self.method_refs
+                    .push((current_func, attr.attr.as_str().to_string(), site));
+                self.extract_calls_from_expr(package, &attr.value, current_func)?;
+            }
             // Recursively process compound expressions
             ast::Expr::List(list) => {
                 for elt in &list.elts {
@@ -746,6 +1470,25 @@ impl CallGraphAnalyzer {
         Ok(())
     }
 
+    /// Site of a call or attribute reference in the source being analyzed
+    fn call_site<T: Ranged>(&self, node: &T) -> CallSite {
+        let start = usize::from(node.range().start());
+        let line = match self.line_starts.binary_search(&start) {
+            Ok(line) => line,
+            Err(next) => next.saturating_sub(1),
+        };
+        let line_start = self.line_starts.get(line).copied().unwrap_or(0);
+        CallSite {
+            location: SourceLocation {
+                line: line + 1,
+                col: start - line_start + 1,
+            },
+            start_byte: start,
+            end_byte: usize::from(node.range().end()),
+            debug_only: self.debug_context,
+        }
+    }
+
     /// Extract decorator name from an expression
     fn extract_decorator_name(&self, expr: &ast::Expr) -> Option<String> {
         match expr {
@@ -768,6 +1511,13 @@ impl CallGraphAnalyzer {
                 graph.add_definition(node.name.clone());
             }
         }
+
+        // A re-export or public-root declaration counts as a use
+        for root in &self.declared_roots {
+            if root.package == package {
+                graph.add_internal_call(root.name.clone());
+            }
+        }
     }
 
     /// Get all call graphs (legacy)
@@ -828,29 +1578,326 @@ impl CallGraphAnalyzer {
         &self.entry_points
     }
 
-    /// Compute reachable functions from entry points
-    #[must_use]
-    pub fn compute_reachable(&self) -> HashSet<FunctionId> {
-        let mut reachable = HashSet::new();
-        let mut queue = VecDeque::from_iter(self.entry_points.iter().copied());
+    /// Combined call graph of every analyzed source
+    ///
+    /// Nodes are the analyzed functions and methods. Edges are direct calls plus `Cls()`
+    /// and `obj.method` references resolved to analyzed methods; receivers are not typed,
+    /// so `obj.method` reaches every analyzed method of that name. Calls made at module
+    /// level start no edge. [`find_dead_code`](Self::find_dead_code) walks these edges.
+    #[must_use]
+    pub fn graph(&self) -> &PackageCallGraph {
+        self.graph.get_or_init(|| self.build_graph())
+    }
+
+    fn build_graph(&self) -> PackageCallGraph {
+        let mut packages = self.nodes.values().map(|node| node.package.as_str());
+        let first = packages.next().unwrap_or_default();
+        let package = if packages.all(|package| package == first) {
+            first
+        } else {
+            ""
+        };
+        let mut graph = PackageCallGraph::new(package.to_string());
+        for node in self.nodes.values() {
+            graph.add_definition(node.name.clone());
+            graph.functions.insert(
+                node.id,
+                FunctionRef {
+                    package: node.package.clone(),
+                    name: node.name.clone(),
+                    span: node.removal_span,
+                },
+            );
+        }
+
+        let resolved = self
+            .resolve_method_calls()
+            .into_iter()
+            .filter_map(|(caller, callee, site)| Some((caller?, callee, site)));
+        graph.calls = self
+            .edges
+            .iter()
+            .map(|edge| (edge.caller, edge.callee, edge.site))
+            .chain(resolved)
+            .collect();
+        graph
+            .calls
+            .sort_by_key(|(caller, callee, site)| (*caller, site.start_byte, *callee));
+        graph.calls.dedup();
+        for (_, callee, _) in &graph.calls {
+            if let Some(function) = graph.functions.get(callee) {
+                graph.internal_calls.insert(function.name.clone());
+            }
+        }
+        graph
+    }
+
+    /// Whether a call at `site` keeps its callee live; debug-only calls count only while
+    /// [`set_asserts_are_roots`](Self::set_asserts_are_roots) is on
+    fn follows(&self, site: &CallSite) -> bool {
+        self.asserts_are_roots || !site.debug_only
+    }
+
+    /// Compute reachable functions from entry points, following the edges of
+    /// [`graph`](Self::graph)
+    #[must_use]
+    pub fn compute_reachable(&self) -> HashSet<FunctionId> {
+        let mut reachable = HashSet::new();
+        let mut queue = VecDeque::from_iter(self.entry_points.iter().copied());
+        if self.asserts_are_roots {
+            queue.extend(self.debug_entry_points.iter().copied());
+        }
+        queue.extend(self.live_methods());
+        queue.extend(self.declared_root_functions().into_keys());
+        // Methods referenced at module level run on import
+        for (caller, callee, site) in self.resolve_method_calls() {
+            if caller.is_none() && self.follows(&site) {
+                queue.push_back(callee);
+            }
+        }
+
+        let mut callees: HashMap<FunctionId, Vec<FunctionId>> = HashMap::new();
+        for (caller, callee, site) in self.graph().edges() {
+            if self.follows(&site) {
+                callees.entry(caller).or_default().push(callee);
+            }
+        }
+
+        while let Some(current) = queue.pop_front() {
+            if reachable.insert(current) {
+                // Find all functions called by current
+                for callee in callees.get(&current).into_iter().flatten() {
+                    if !reachable.contains(callee) {
+                        queue.push_back(*callee);
+                    }
+                }
+                for other in self.redefinitions.get(&current).into_iter().flatten() {
+                    if !reachable.contains(other) {
+                        queue.push_back(*other);
+                    }
+                }
+            }
+        }
+
+        reachable
+    }
+
+    /// Resolve a base class name written in `package` to an analyzed class
+    fn resolve_class(&self, package: &str, name: &str) -> Option<(String, String)> {
+        let local = (package.to_string(), name.to_string());
+        if self.classes.contains_key(&local) {
+            return Some(local);
+        }
+        self.imports
+            .get(&local)
+            .filter(|imported| self.classes.contains_key(*imported))
+            .cloned()
+    }
+
+    /// Find `method` on a class or, failing that, on its analyzed base classes
+    fn lookup_method(
+        &self,
+        class: &(String, String),
+        method: &str,
+        seen: &mut HashSet<(String, String)>,
+    ) -> Option<FunctionId> {
+        if !seen.insert(class.clone()) {
+            return None;
+        }
+        let info = self.classes.get(class)?;
+        if let Some(id) = info.methods.get(method) {
+            return Some(*id);
+        }
+        info.bases
+            .iter()
+            .filter_map(|base| self.resolve_class(&class.0, base))
+            .find_map(|base| self.lookup_method(&base, method, seen))
+    }
+
+    /// Whether a class inherits from something outside the analysis set
+    fn has_external_base(&self, class: &(String, String)) -> bool {
+        let Some(info) = self.classes.get(class) else {
+            return false;
+        };
+        info.opaque_base
+            || info.bases.iter().any(|base| {
+                !METHODLESS_BASES.contains(&base.as_str())
+                    && self.resolve_class(&class.0, base).is_none()
+            })
+    }
+
+    /// Methods that are live regardless of calls seen in the analyzed code
+    ///
+    /// These are dunders other than constructors (invoked implicitly by Python), decorated
+    /// methods (properties, framework hooks), methods of classes with an external base
+    /// (they may override something it calls), and methods of classes that are exported
+    /// or imported elsewhere.
+    fn live_methods(&self) -> Vec<FunctionId> {
+        let imported: HashSet<&(String, String)> = self.imports.values().collect();
+        let mut live = Vec::new();
+        for (key, info) in &self.classes {
+            let exported = self
+                .public_exports
+                .get(&key.0)
+                .is_some_and(|exports| exports.contains(&key.1));
+            let whole_class = exported || imported.contains(key) || self.has_external_base(key);
+            for (method, id) in &info.methods {
+                let is_dunder = method.starts_with("__") && method.ends_with("__");
+                let constructor = method == "__init__" || method == "__new__";
+                if whole_class || (is_dunder && !constructor) || info.decorated.contains(id) {
+                    live.push(*id);
+                }
+            }
+        }
+        live
+    }
+
+    /// Functions made live by declared roots, each with the reason of the first root naming
+    /// it
+    ///
+    /// A root naming a class makes all of its methods live.
+    fn declared_root_functions(&self) -> HashMap<FunctionId, RootReason> {
+        let mut roots = HashMap::new();
+        for root in &self.declared_roots {
+            for node in self.nodes.values() {
+                if node.class.is_none() && node.name == root.name && root.covers(&node.package) {
+                    roots.entry(node.id).or_insert(root.reason);
+                }
+            }
+            for ((package, class), info) in &self.classes {
+                if *class == root.name && root.covers(package) {
+                    for id in info.methods.values() {
+                        roots.entry(*id).or_insert(root.reason);
+                    }
+                }
+            }
+        }
+        roots
+    }
+
+    /// Find functions that are live only because of a declared root
+    ///
+    /// These are re-exported by an analyzed `__init__.py` or defined at the top level of a
+    /// file matched by [`set_public_roots`](Self::set_public_roots), and nothing analyzed
+    /// calls them. Sorted by name.
+    #[must_use]
+    pub fn find_declared_roots(&self) -> Vec<(FunctionId, String, RootReason)> {
+        let called: HashSet<FunctionId> = self
+            .graph()
+            .edges()
+            .filter(|(_, _, site)| self.follows(site))
+            .map(|(_, callee, _)| callee)
+            .chain(
+                self.resolve_method_calls()
+                    .into_iter()
+                    .filter(|(caller, _, site)| caller.is_none() && self.follows(site))
+                    .map(|(_, callee, _)| callee),
+            )
+            .collect();
+        let mut roots: Vec<(FunctionId, String, RootReason)> = self
+            .declared_root_functions()
+            .into_iter()
+            .filter(|(id, _)| !called.contains(id))
+            .filter_map(|(id, reason)| {
+                self.nodes
+                    .get(&id)
+                    .map(|node| (id, node.name.clone(), reason))
+            })
+            .collect();
+        roots.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        roots
+    }
+
+    /// Resolve recorded `Cls()` calls and `obj.method` references to method nodes, with
+    /// the site of each, debug-only ones included
+    ///
+    /// Attribute references go to every analyzed class defining the method, since the
+    /// receiver's type is not tracked.
+    fn resolve_method_calls(&self) -> Vec<(Option<FunctionId>, FunctionId, CallSite)> {
+        let mut by_name: HashMap<&str, Vec<FunctionId>> = HashMap::new();
+        for info in self.classes.values() {
+            for (method, id) in &info.methods {
+                by_name.entry(method.as_str()).or_default().push(*id);
+            }
+        }
 
-        while let Some(current) = queue.pop_front() {
-            if reachable.insert(current) {
-                // Find all functions called by current
-                for edge in &self.edges {
-                    if edge.caller == current && !reachable.contains(&edge.callee) {
-                        queue.push_back(edge.callee);
-                    }
+        let mut resolved = Vec::new();
+        for (caller, method, site) in &self.method_refs {
+            for callee in by_name.get(method.as_str()).into_iter().flatten() {
+                resolved.push((*caller, *callee, *site));
+            }
+        }
+        for (caller, (package, name), site) in &self.instantiations {
+            let Some(class) = self.resolve_class(package, name) else {
+                continue;
+            };
+            for constructor in ["__init__", "__new__"] {
+                if let Some(callee) = self.lookup_method(&class, constructor, &mut HashSet::new()) {
+                    resolved.push((*caller, callee, *site));
                 }
             }
         }
-
-        reachable
+        resolved
     }
 
     /// Find dead code (unreachable from entry points)
+    ///
+    /// Methods are reported with class-qualified names (`Class.method`); use
+    /// [`CallGraphNode::class`] to tell them apart from functions. Functions marked with `# tsrs: keep` are left out; see
+    /// [`find_suppressed_dead_code`](Self::find_suppressed_dead_code).
     #[must_use]
     pub fn find_dead_code(&self) -> Vec<(FunctionId, String)> {
+        self.find_unreachable()
+            .into_iter()
+            .filter(|(id, _)| !self.suppressed.contains(id))
+            .collect()
+    }
+
+    /// How sure a dead code finding for `id` is
+    ///
+    /// Private (`_name`) functions start at [`Confidence::High`] and public ones at
+    /// [`Confidence::Medium`]; a decorator and an `__init__.py` module each lower that by a
+    /// level. A name found in any analyzed string literal, where `getattr` or a registry
+    /// could look it up, is always [`Confidence::Low`].
+    #[must_use]
+    pub fn dead_code_confidence(&self, id: FunctionId) -> Confidence {
+        let Some(node) = self.nodes.get(&id) else {
+            return Confidence::Low;
+        };
+        let name = simple_name(&node.name);
+        if self.string_words.contains(name) {
+            return Confidence::Low;
+        }
+
+        let mut confidence = if name.starts_with('_') {
+            Confidence::High
+        } else {
+            Confidence::Medium
+        };
+        if self.decorated.contains(&id) {
+            confidence = confidence.lowered();
+        }
+        let in_init = node
+            .file
+            .as_deref()
+            .and_then(|file| Path::new(file).file_name())
+            .is_some_and(|name| name == "__init__.py");
+        if in_init {
+            confidence = confidence.lowered();
+        }
+        confidence
+    }
+
+    /// Find unreachable functions spared from dead code by a `# tsrs: keep` comment
+    #[must_use]
+    pub fn find_suppressed_dead_code(&self) -> Vec<(FunctionId, String)> {
+        self.find_unreachable()
+            .into_iter()
+            .filter(|(id, _)| self.suppressed.contains(id))
+            .collect()
+    }
+
+    fn find_unreachable(&self) -> Vec<(FunctionId, String)> {
         let reachable = self.compute_reachable();
 
         self.nodes
@@ -862,7 +1909,8 @@ impl CallGraphAnalyzer {
                 }
 
                 // Keep dunder methods
-                if node.name.starts_with("__") && node.name.ends_with("__") {
+                let name = simple_name(&node.name);
+                if name.starts_with("__") && name.ends_with("__") {
                     return None;
                 }
 
@@ -917,6 +1965,7 @@ impl CallGraphAnalyzer {
     ) {
         self.imports
             .insert((package, local_name), (source_package, source_function));
+        self.graph = OnceLock::new();
     }
 
     /// Resolve a call name to its actual function (local or imported)
@@ -1002,6 +2051,23 @@ impl Default for CallGraphAnalyzer {
 mod tests {
     use super::*;
 
+    #[test]
+    fn analyze_file_parse_errors_name_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("broken.py");
+        std::fs::write(&path, "def broken(:\n    pass\n").unwrap();
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        match analyzer.analyze_file(&path, "pkg") {
+            Err(TsrsError::Parse {
+                file: Some(file),
+                line: 1,
+                ..
+            }) => assert_eq!(file, path),
+            other => panic!("expected parse error, got {other:?}"),
+        }
+    }
+
     #[test]
     fn test_entry_point_detection_main_block() {
         let source = r#"
@@ -1432,6 +2498,48 @@ def func_b():
         );
     }
 
+    #[test]
+    fn test_keep_directive_suppresses_dead_code() {
+        let source = r#"
+def decorator(func):
+    return func
+
+def rendered_by_name():  # tsrs: keep
+    pass
+
+@decorator  # tsrs: keep
+def decorated_template_hook():
+    pass
+
+def unused_sibling():
+    pass
+
+def keep_names_is_not_keep():  # tsrs: keep-names value
+    pass
+"#;
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+
+        let dead_code = analyzer.find_dead_code();
+        let dead_names: Vec<_> = dead_code.iter().map(|(_, name)| name.as_str()).collect();
+        assert!(dead_names.contains(&"unused_sibling"));
+        assert!(dead_names.contains(&"keep_names_is_not_keep"));
+        assert!(!dead_names.contains(&"rendered_by_name"));
+        assert!(!dead_names.contains(&"decorated_template_hook"));
+
+        let mut suppressed: Vec<_> = analyzer
+            .find_suppressed_dead_code()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        suppressed.sort();
+        assert_eq!(
+            suppressed,
+            vec!["decorated_template_hook", "rendered_by_name"]
+        );
+    }
+
     #[test]
     fn test_decorator_preservation() {
         let source = r#"
@@ -1648,4 +2756,515 @@ def helper():
             "Imported helper should be reachable (marked as entry point)"
         );
     }
+
+    fn dead_names(analyzer: &CallGraphAnalyzer) -> Vec<String> {
+        analyzer
+            .find_dead_code()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    #[test]
+    fn test_instantiation_reaches_constructor_and_methods() {
+        let source = r#"
+class Service:
+    def __init__(self):
+        self.setup()
+
+    def setup(self):
+        configure()
+
+    def run(self):
+        pass
+
+    def unused(self):
+        pass
+
+def configure():
+    pass
+
+def test_service():
+    service = Service()
+    service.run()
+"#;
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+        let dead = dead_names(&analyzer);
+
+        assert_eq!(dead, vec!["Service.unused".to_string()]);
+        let unused = analyzer
+            .get_nodes()
+            .values()
+            .find(|n| n.name == "Service.unused")
+            .unwrap();
+        assert_eq!(unused.kind, FunctionKind::Method);
+        assert_eq!(unused.class.as_deref(), Some("Service"));
+    }
+
+    #[test]
+    fn test_inherited_constructor_resolves_through_base() {
+        let source = r#"
+class Base:
+    def __init__(self):
+        prepare()
+
+class Child(Base):
+    pass
+
+def prepare():
+    pass
+
+def test_child():
+    Child()
+"#;
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+
+        assert!(dead_names(&analyzer).is_empty());
+    }
+
+    #[test]
+    fn test_uninstantiated_constructor_callees_are_dead() {
+        let source = r#"
+class Lonely:
+    def __init__(self):
+        prepare()
+
+def prepare():
+    pass
+"#;
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+
+        assert_eq!(dead_names(&analyzer), vec!["prepare".to_string()]);
+    }
+
+    #[test]
+    fn test_methods_of_classes_with_external_bases_are_live() {
+        let source = r#"
+from http.server import BaseHTTPRequestHandler
+
+class Handler(BaseHTTPRequestHandler):
+    def do_GET(self):
+        pass
+
+class Plain(object):
+    def helper(self):
+        pass
+
+    @property
+    def size(self):
+        return 0
+"#;
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+
+        assert_eq!(dead_names(&analyzer), vec!["Plain.helper".to_string()]);
+    }
+
+    #[test]
+    fn test_method_called_on_unknown_receiver_is_not_dead() {
+        // `handler` could be any object, so every analyzed class's `process` stays
+        let source = r#"
+def test_dispatch(handler):
+    handler.process()
+
+class Worker:
+    def process(self):
+        pass
+
+    def idle(self):
+        pass
+
+class Printer:
+    def process(self):
+        pass
+"#;
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+
+        let mut dead = dead_names(&analyzer);
+        dead.sort();
+        assert_eq!(dead, vec!["Worker.idle".to_string()]);
+    }
+
+    #[test]
+    fn test_functions_record_def_location_and_file() {
+        let source = "import functools\n\n@functools.cache\ndef cached():\n    pass\n\nclass Box:\n    async def load(self):\n        pass\n";
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer
+            .analyze_source_in_file("pkg", source, "pkg/box.py")
+            .unwrap();
+        analyzer
+            .analyze_source("other", "def loose():\n    pass\n")
+            .unwrap();
+
+        let node = |name: &str| {
+            analyzer
+                .get_nodes()
+                .values()
+                .find(|n| n.name == name)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(node("cached").location, SourceLocation { line: 4, col: 1 });
+        assert_eq!(
+            node("Box.load").location,
+            SourceLocation { line: 8, col: 11 }
+        );
+        assert_eq!(node("Box.load").file.as_deref(), Some("pkg/box.py"));
+        assert_eq!(node("loose").file, None);
+    }
+
+    #[test]
+    fn test_class_defined_in_later_source_is_resolved() {
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer
+            .analyze_source("app", "def test_app():\n    Model().save()\n")
+            .unwrap();
+        analyzer
+            .analyze_source(
+                "app",
+                "class Model:\n    def save(self):\n        pass\n\n    def drop(self):\n        pass\n",
+            )
+            .unwrap();
+
+        assert_eq!(dead_names(&analyzer), vec!["Model.drop".to_string()]);
+    }
+
+    #[test]
+    fn test_init_re_export_is_a_root() {
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer
+            .analyze_source_in_file("mypkg", "from .core import exported\n", "mypkg/__init__.py")
+            .unwrap();
+        analyzer
+            .analyze_source_in_file(
+                "mypkg",
+                "def exported():\n    helper()\n\ndef helper():\n    pass\n\ndef forgotten():\n    pass\n",
+                "mypkg/core.py",
+            )
+            .unwrap();
+
+        assert_eq!(dead_names(&analyzer), vec!["forgotten".to_string()]);
+        let roots: Vec<_> = analyzer
+            .find_declared_roots()
+            .into_iter()
+            .map(|(_, name, reason)| (name, reason.describe()))
+            .collect();
+        assert_eq!(
+            roots,
+            vec![("exported".to_string(), "root: __init__ re-export")]
+        );
+        let unused = analyzer.find_unused_functions("mypkg");
+        assert!(unused.contains("forgotten"));
+        assert!(!unused.contains("exported"));
+    }
+
+    #[test]
+    fn test_public_roots_and_nested_re_exports() {
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer
+            .set_public_roots(&["mypkg/api/*.py".to_string()])
+            .unwrap();
+        analyzer
+            .analyze_source_in_file(
+                "mypkg",
+                "from .engine.core import Engine\n\n__all__ = ['Engine']\n",
+                "mypkg/__init__.py",
+            )
+            .unwrap();
+        analyzer
+            .analyze_source_in_file(
+                "mypkg/engine",
+                "class Engine:\n    def run(self):\n        pass\n\ndef unused():\n    pass\n",
+                "mypkg/engine/core.py",
+            )
+            .unwrap();
+        analyzer
+            .analyze_source_in_file(
+                "mypkg/api",
+                "def endpoint():\n    pass\n\nclass Resource:\n    def get(self):\n        pass\n",
+                "mypkg/api/views.py",
+            )
+            .unwrap();
+        analyzer
+            .analyze_source_in_file("other", "def Engine():\n    pass\n", "other/engine.py")
+            .unwrap();
+
+        let mut dead = dead_names(&analyzer);
+        dead.sort();
+        assert_eq!(dead, vec!["Engine".to_string(), "unused".to_string()]);
+        let roots: Vec<_> = analyzer
+            .find_declared_roots()
+            .into_iter()
+            .map(|(_, name, reason)| (name, reason))
+            .collect();
+        assert_eq!(
+            roots,
+            vec![
+                ("Engine.run".to_string(), RootReason::InitReExport),
+                ("Resource.get".to_string(), RootReason::PublicModule),
+                ("endpoint".to_string(), RootReason::PublicModule),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assert_only_references_are_roots_unless_disabled() {
+        let source = r#"
+def is_sorted(items):
+    return items == sorted(items)
+
+def describe(items):
+    return repr(items)
+
+def check_invariants():
+    return True
+
+class Checker:
+    def verify(self):
+        return True
+
+def merge(left, right):
+    result = left + right
+    assert is_sorted(result), describe(result)
+    return result
+
+if __debug__:
+    check_invariants()
+    Checker().verify()
+
+merge([1], [2])
+"#;
+
+        let dead_names = |asserts_are_roots: bool| {
+            let mut analyzer = CallGraphAnalyzer::new();
+            analyzer.set_asserts_are_roots(asserts_are_roots);
+            analyzer.analyze_source("test", source).unwrap();
+            let mut names: Vec<String> = analyzer
+                .find_dead_code()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert!(dead_names(true).is_empty());
+        assert_eq!(
+            dead_names(false),
+            ["Checker.verify", "check_invariants", "describe", "is_sorted"]
+        );
+
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+        assert_eq!(analyzer.get_edges().len(), 2);
+        assert!(analyzer.get_edges().iter().all(|edge| edge.site.debug_only));
+    }
+
+    #[test]
+    fn test_dead_code_confidence_signals() {
+        let source = r#"import functools
+
+def _private():
+    return 1
+
+def public():
+    return 2
+
+@functools.cache
+def _cached():
+    return 3
+
+@functools.cache
+def cached():
+    return 4
+
+def _dispatched():
+    return 5
+
+HANDLERS = {"run": "_dispatched"}
+"#;
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer
+            .analyze_source_in_file("mypkg", source, "mypkg/jobs.py")
+            .unwrap();
+        analyzer
+            .analyze_source_in_file(
+                "mypkg",
+                "def _init_helper():\n    pass\n",
+                "mypkg/__init__.py",
+            )
+            .unwrap();
+
+        let mut confidences: Vec<_> = analyzer
+            .find_dead_code()
+            .into_iter()
+            .map(|(id, name)| (name, analyzer.dead_code_confidence(id)))
+            .collect();
+        confidences.sort();
+        assert_eq!(
+            confidences,
+            vec![
+                ("_cached".to_string(), Confidence::Medium),
+                ("_dispatched".to_string(), Confidence::Low),
+                ("_init_helper".to_string(), Confidence::Medium),
+                ("_private".to_string(), Confidence::High),
+                ("cached".to_string(), Confidence::Low),
+                ("public".to_string(), Confidence::Medium),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_removal_span_covers_decorators_through_body() {
+        let source =
+            "x = 1\n\n@functools.cache\ndef cached():\n    return x\n\ndef last():\n    pass";
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+
+        let span_of = |name: &str| {
+            let node = analyzer.get_nodes().values().find(|node| node.name == name);
+            node.and_then(|node| node.removal_span).unwrap()
+        };
+        let cached = span_of("cached");
+        assert_eq!((cached.start_line, cached.end_line), (3, 5));
+        assert_eq!(
+            &source[cached.start_byte..cached.end_byte],
+            "@functools.cache\ndef cached():\n    return x\n"
+        );
+        let last = span_of("last");
+        assert_eq!((last.start_line, last.end_line), (7, 8));
+        assert_eq!(
+            &source[last.start_byte..last.end_byte],
+            "def last():\n    pass"
+        );
+    }
+
+    #[test]
+    fn test_conditional_redefinitions_are_live_together() {
+        let source = r#"
+import sys
+
+if sys.platform == "win32":
+    def _get_path():
+        return _win_root()
+else:
+    def _get_path():
+        return _posix_root()
+
+def _win_root():
+    return "C:"
+
+def _posix_root():
+    return "/"
+
+def _unused():
+    pass
+
+_get_path()
+"#;
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("test", source).unwrap();
+
+        let definitions = analyzer
+            .get_nodes()
+            .values()
+            .filter(|node| node.name == "_get_path")
+            .count();
+        assert_eq!(definitions, 2);
+        let dead: Vec<String> = analyzer
+            .find_dead_code()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        assert_eq!(dead, vec!["_unused".to_string()]);
+    }
+
+    #[test]
+    fn test_graph_lists_callers_callees_and_call_sites() {
+        let source = r#"class Store:
+    def __init__(self):
+        self.items = []
+
+    def put(self, item):
+        self.items.append(item)
+        return self.count()
+
+    def count(self):
+        return len(self.items)
+
+
+class Service:
+    def __init__(self):
+        self.store = Store()
+
+    def save(self, item):
+        return self.store.put(normalize(item))
+
+
+def normalize(item):
+    return item.strip()
+
+
+Service().save(" x ")
+"#;
+        let mut analyzer = CallGraphAnalyzer::new();
+        analyzer.analyze_source("app", source).unwrap();
+        let graph = analyzer.graph();
+        let sites = |calls: Vec<(&FunctionRef, CallSite)>| -> Vec<(String, usize, usize)> {
+            calls
+                .into_iter()
+                .map(|(function, site)| {
+                    (function.name.clone(), site.location.line, site.location.col)
+                })
+                .collect()
+        };
+
+        assert_eq!(graph.nodes().count(), 6);
+        assert_eq!(
+            sites(graph.callees_of("Service.save")),
+            [
+                ("Store.put".to_string(), 18, 16),
+                ("normalize".to_string(), 18, 31)
+            ]
+        );
+        assert_eq!(
+            sites(graph.callers_of("Store.__init__")),
+            [("Service.__init__".to_string(), 15, 22)]
+        );
+        assert_eq!(
+            sites(graph.callers_of("app.Store.count")),
+            [("Store.put".to_string(), 7, 16)]
+        );
+        assert!(graph.callees_of("normalize").is_empty());
+
+        let (_, put) = graph.callees_of("Service.save")[0];
+        assert_eq!(
+            &source[put.start_byte..put.end_byte],
+            "self.store.put(normalize(item))"
+        );
+        let normalize = graph.nodes().find(|f| f.is_named("normalize")).unwrap();
+        let span = normalize.span.unwrap();
+        assert_eq!((span.start_line, span.end_line), (21, 22));
+        assert!(analyzer.find_dead_code().is_empty());
+
+        let export = graph.export();
+        assert_eq!(export.package, "app");
+        assert_eq!(export.edges.len(), graph.edges().count());
+        let json = serde_json::to_string(&export).unwrap();
+        assert!(json.contains(r#""name":"Service.save""#));
+        assert_eq!(
+            serde_json::from_str::<CallGraphExport>(&json).unwrap(),
+            export
+        );
+    }
 }
@@ -0,0 +1,345 @@
+//! Project configuration loaded from `tsrs.toml` or `pyproject.toml`
+
+use crate::error::{IoResultExt, Result, TsrsError};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Name of the standalone configuration file
+pub const CONFIG_FILE_NAME: &str = "tsrs.toml";
+
+/// Name of the pyproject file searched for a `[tool.tsrs]` table
+pub const PYPROJECT_FILE_NAME: &str = "pyproject.toml";
+
+/// Keys understood under `[tool.tsrs]` / `tsrs.toml`.
+///
+/// `local-dependencies` is consumed by `tsrs-minify-tree` and is accepted here so it
+/// does not trigger unknown-key warnings.
+const KNOWN_KEYS: &[&str] = &[
+    "include",
+    "include-file",
+    "exclude",
+    "exclude-file",
+    "extensions",
+    "include-hidden",
+    "follow-symlinks",
+    "glob-case-insensitive",
+    "max-depth",
+    "max-file-size",
+    "respect-gitignore",
+    "stats",
+    "json",
+    "remove-dead-code",
+    "stable-names",
+    "jobs",
+    "diff-context",
+    "local-dependencies",
+];
+
+/// Defaults for CLI options that can be set from a configuration file.
+///
+/// Every field is optional; values given on the command line take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    /// Glob patterns to include
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<String>>,
+    /// File containing newline-delimited include globs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_file: Option<PathBuf>,
+    /// Glob patterns to exclude
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,
+    /// File containing newline-delimited exclude globs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_file: Option<PathBuf>,
+    /// Source file extensions to process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Vec<String>>,
+    /// Include hidden files and directories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_hidden: Option<bool>,
+    /// Follow symlinks when traversing directories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow_symlinks: Option<bool>,
+    /// Force case-insensitive glob matching
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glob_case_insensitive: Option<bool>,
+    /// Maximum directory depth to traverse
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    /// Skip files larger than this many bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+    /// Respect .gitignore files when scanning
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub respect_gitignore: Option<bool>,
+    /// Print per-file stats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<bool>,
+    /// Emit stats as JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<bool>,
+    /// Remove dead code in addition to minification
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remove_dead_code: Option<bool>,
+    /// Assign hash-derived short names that stay put across unrelated edits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stable_names: Option<bool>,
+    /// Number of parallel workers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<usize>,
+    /// Number of context lines in diffs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff_context: Option<usize>,
+}
+
+/// A configuration together with the file it was loaded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedConfig {
+    /// Path of the `tsrs.toml` or `pyproject.toml` that provided the settings
+    pub path: PathBuf,
+    /// Parsed settings
+    pub config: Config,
+}
+
+impl Config {
+    /// Search `start` and its ancestors for a configuration file.
+    ///
+    /// In each directory `tsrs.toml` wins over a `pyproject.toml` with a `[tool.tsrs]`
+    /// table; the first directory providing either stops the search. Relative file
+    /// paths inside the configuration are resolved against the file's directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a discovered file cannot be read or parsed.
+    pub fn discover(start: &Path) -> Result<Option<LoadedConfig>> {
+        let start = dunce::canonicalize(start).unwrap_or_else(|_| start.to_path_buf());
+        let start = if start.is_file() {
+            start.parent().map(Path::to_path_buf).unwrap_or_default()
+        } else {
+            start
+        };
+
+        for dir in start.ancestors() {
+            let tsrs_toml = dir.join(CONFIG_FILE_NAME);
+            if tsrs_toml.is_file() {
+                let config = Self::load_tsrs_toml(&tsrs_toml)?;
+                return Ok(Some(LoadedConfig {
+                    path: tsrs_toml,
+                    config: config.resolve_paths(dir),
+                }));
+            }
+
+            let pyproject = dir.join(PYPROJECT_FILE_NAME);
+            if pyproject.is_file() {
+                if let Some(config) = Self::load_pyproject(&pyproject)? {
+                    return Ok(Some(LoadedConfig {
+                        path: pyproject,
+                        config: config.resolve_paths(dir),
+                    }));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Load settings from a standalone `tsrs.toml`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not valid configuration.
+    pub fn load_tsrs_toml(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).with_path(path)?;
+        let label = path.display().to_string();
+        let value: Value = toml::from_str(&contents)
+            .map_err(|e| TsrsError::ConfigError(format!("{label}: {e}")))?;
+        Self::from_value(value, &label)
+    }
+
+    /// Load settings from the `[tool.tsrs]` table of a `pyproject.toml`.
+    ///
+    /// Returns `Ok(None)` when the file has no such table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not valid configuration.
+    pub fn load_pyproject(path: &Path) -> Result<Option<Self>> {
+        let contents = std::fs::read_to_string(path).with_path(path)?;
+        let label = path.display().to_string();
+        let document: Value = toml::from_str(&contents)
+            .map_err(|e| TsrsError::ConfigError(format!("{label}: {e}")))?;
+
+        match document.get("tool").and_then(|tool| tool.get("tsrs")) {
+            Some(table) => {
+                Self::from_value(table.clone(), &format!("{label} [tool.tsrs]")).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Parse settings from a TOML table, warning about unknown keys
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `value` is not a table or a known key has the wrong type.
+    pub fn from_value(value: Value, label: &str) -> Result<Self> {
+        let Some(table) = value.as_table() else {
+            return Err(TsrsError::ConfigError(format!(
+                "{label}: expected a table of settings"
+            )));
+        };
+
+        for key in unknown_keys(table) {
+            tracing::warn!("{label}: unknown configuration key `{key}`");
+        }
+
+        value
+            .try_into()
+            .map_err(|e| TsrsError::ConfigError(format!("{label}: {e}")))
+    }
+
+    /// Return a copy with every unset option replaced by the CLI default
+    #[must_use]
+    pub fn with_defaults(&self) -> Self {
+        Config {
+            include: Some(self.include.clone().unwrap_or_default()),
+            include_file: self.include_file.clone(),
+            exclude: Some(self.exclude.clone().unwrap_or_default()),
+            exclude_file: self.exclude_file.clone(),
+            extensions: Some(
+                self.extensions
+                    .clone()
+                    .unwrap_or_else(|| vec!["py".to_string()]),
+            ),
+            include_hidden: Some(self.include_hidden.unwrap_or(false)),
+            follow_symlinks: Some(self.follow_symlinks.unwrap_or(false)),
+            glob_case_insensitive: Some(self.glob_case_insensitive.unwrap_or(cfg!(windows))),
+            max_depth: self.max_depth,
+            max_file_size: self.max_file_size,
+            respect_gitignore: Some(self.respect_gitignore.unwrap_or(false)),
+            stats: Some(self.stats.unwrap_or(false)),
+            json: Some(self.json.unwrap_or(false)),
+            remove_dead_code: Some(self.remove_dead_code.unwrap_or(false)),
+            stable_names: Some(self.stable_names.unwrap_or(false)),
+            jobs: self.jobs,
+            diff_context: Some(self.diff_context.unwrap_or(3)),
+        }
+    }
+
+    /// Serialize the settings as TOML
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if serialization fails.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).map_err(|e| TsrsError::ConfigError(e.to_string()))
+    }
+
+    fn resolve_paths(mut self, base: &Path) -> Self {
+        if let Some(path) = self.include_file.take() {
+            self.include_file = Some(base.join(path));
+        }
+        if let Some(path) = self.exclude_file.take() {
+            self.exclude_file = Some(base.join(path));
+        }
+        self
+    }
+}
+
+fn unknown_keys(table: &toml::value::Table) -> Vec<String> {
+    table
+        .keys()
+        .filter(|key| !KNOWN_KEYS.contains(&key.as_str()))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn discovers_pyproject_from_nested_subdirectory() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("pyproject.toml"),
+            "[project]\nname = \"demo\"\n\n[tool.tsrs]\nexclude = [\"**/tests/**\"]\njobs = 2\nrespect-gitignore = true\nexclude-file = \"ignore.txt\"\n",
+        )
+        .unwrap();
+        let nested = dir.path().join("src").join("pkg");
+        fs::create_dir_all(&nested).unwrap();
+
+        let loaded = Config::discover(&nested).unwrap().expect("config found");
+        assert!(loaded.path.ends_with("pyproject.toml"));
+        assert_eq!(loaded.config.exclude, Some(vec!["**/tests/**".to_string()]));
+        assert_eq!(loaded.config.jobs, Some(2));
+        assert_eq!(loaded.config.respect_gitignore, Some(true));
+        assert_eq!(loaded.config.include_hidden, None);
+        let exclude_file = loaded.config.exclude_file.unwrap();
+        assert!(exclude_file.ends_with("ignore.txt"));
+        assert!(exclude_file.is_absolute());
+    }
+
+    #[test]
+    fn pyproject_without_tool_table_is_skipped() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("tsrs.toml"), "stats = true\n").unwrap();
+        let inner = dir.path().join("inner");
+        fs::create_dir_all(&inner).unwrap();
+        fs::write(
+            inner.join("pyproject.toml"),
+            "[project]\nname = \"inner\"\n",
+        )
+        .unwrap();
+
+        let loaded = Config::discover(&inner).unwrap().expect("config found");
+        assert!(loaded.path.ends_with("tsrs.toml"));
+        assert_eq!(loaded.config.stats, Some(true));
+    }
+
+    #[test]
+    fn tsrs_toml_takes_precedence_in_same_directory() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("tsrs.toml"), "jobs = 4\n").unwrap();
+        fs::write(dir.path().join("pyproject.toml"), "[tool.tsrs]\njobs = 8\n").unwrap();
+
+        let loaded = Config::discover(dir.path()).unwrap().expect("config found");
+        assert_eq!(loaded.config.jobs, Some(4));
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let value: Value =
+            toml::from_str("stats = true\nlocal-dependencies = {}\nfancy-mode = 1\n").unwrap();
+        let table = value.as_table().unwrap();
+        assert_eq!(unknown_keys(table), vec!["fancy-mode".to_string()]);
+
+        let config = Config::from_value(value, "test").unwrap();
+        assert_eq!(config.stats, Some(true));
+    }
+
+    #[test]
+    fn wrong_type_is_an_error() {
+        let value: Value = toml::from_str("jobs = \"many\"\n").unwrap();
+        assert!(Config::from_value(value, "test").is_err());
+    }
+
+    #[test]
+    fn with_defaults_round_trips_through_toml() {
+        let config = Config {
+            jobs: Some(3),
+            ..Config::default()
+        }
+        .with_defaults();
+        let rendered = config.to_toml().unwrap();
+        assert!(rendered.contains("jobs = 3"));
+        assert!(rendered.contains("diff-context = 3"));
+        let parsed: Config = toml::from_str(&rendered).unwrap();
+        assert_eq!(parsed, config);
+    }
+}
@@ -1,5 +1,8 @@
 //! Error types for tsrs
 
+use regex::RegexSet;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use thiserror::Error;
 
 /// Result type for tsrs operations
@@ -8,14 +11,28 @@ pub type Result<T> = std::result::Result<T, TsrsError>;
 /// Errors that can occur during tree-shaking operations
 #[derive(Error, Debug)]
 pub enum TsrsError {
-    #[error("IO error: {0}")]
-    Io(#[from] std::io::Error),
+    /// Reading or writing `path` failed
+    #[error("IO error: {}: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
 
     #[error("Invalid venv path: {0}")]
     InvalidVenvPath(String),
 
-    #[error("Failed to parse Python file: {0}")]
-    ParseError(String),
+    /// Python source could not be parsed; `line` and `column` are 1-based
+    #[error(
+        "Failed to parse Python file: {}{message} at line {line}, column {column}",
+        file_prefix(.file.as_ref())
+    )]
+    Parse {
+        file: Option<PathBuf>,
+        line: usize,
+        column: usize,
+        message: String,
+    },
 
     #[error("Failed to analyze venv: {0}")]
     AnalysisError(String),
@@ -25,4 +42,359 @@ pub enum TsrsError {
 
     #[error("Invalid metadata: {0}")]
     InvalidMetadata(String),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
+    /// A rename in `function`'s plan cannot be applied, e.g. after a bad hand edit
+    #[error("invalid plan for {function}: {detail}")]
+    InvalidPlan { function: String, detail: String },
+
+    /// No function in the plan has the requested qualified name
+    #[error("no function named {name} in the plan (available: {})", .available.join(", "))]
+    UnknownFunction {
+        name: String,
+        available: Vec<String>,
+    },
+
+    /// A plan was written by a newer tsrs than this one
+    #[error("unsupported plan bundle version: {found} (supported: {supported})")]
+    PlanVersion { found: u32, supported: u32 },
+
+    /// The contents of `path` are not valid `encoding`
+    #[error("Failed to decode {} as {encoding}", .path.display())]
+    Encoding {
+        path: PathBuf,
+        encoding: &'static str,
+    },
+
+    /// Kept distributions have a license that `SlimOptions::fail_on_license` rejects; each
+    /// entry names the distribution, its license, and what kept it
+    #[error("kept packages have a rejected license:\n  {}", .packages.join("\n  "))]
+    LicenseDenied { packages: Vec<String> },
+
+    /// Rewritten text for `path` has characters `encoding` cannot represent
+    #[error("Failed to encode {} as {encoding}", .path.display())]
+    Encode {
+        path: PathBuf,
+        encoding: &'static str,
+    },
+
+    /// Planning stopped because the plan grew past a `PlanOptions::limits` cap of `max`
+    #[error("plan has more than {max} {}", .limit.describe())]
+    PlanLimit { limit: PlanLimit, max: usize },
+}
+
+impl TsrsError {
+    /// Build a [`TsrsError::Parse`] from a parser error, resolving its byte offset in `source`
+    pub(crate) fn parse(source: &str, err: &rustpython_parser::ParseError) -> Self {
+        let offset = usize::try_from(u32::from(err.offset)).unwrap_or(usize::MAX);
+        let (line, column) = line_column(source, offset);
+        TsrsError::Parse {
+            file: None,
+            line,
+            column,
+            message: err.error.to_string(),
+        }
+    }
+
+    /// Record the file a parse error came from, unless it already names one
+    #[must_use]
+    pub fn with_file<P: AsRef<Path>>(self, path: P) -> Self {
+        match self {
+            TsrsError::Parse {
+                file: None,
+                line,
+                column,
+                message,
+            } => TsrsError::Parse {
+                file: Some(path.as_ref().to_path_buf()),
+                line,
+                column,
+                message,
+            },
+            other => other,
+        }
+    }
+
+    /// Classify a parse error by the `source` it came from; `None` for every other error
+    #[must_use]
+    pub fn syntax_issue(&self, source: &str) -> Option<SyntaxIssue> {
+        match self {
+            TsrsError::Parse { line, .. } => Some(SyntaxIssue::classify(source, *line)),
+            _ => None,
+        }
+    }
+}
+
+/// Likely cause of a [`TsrsError::Parse`], guessed from the lines around the error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxIssue {
+    /// Python 2 only constructs such as `print x` or `except E, e:`
+    Python2,
+    /// Syntax newer than the parser supports, such as PEP 701 f-strings or PEP 696 defaults
+    Unsupported,
+    /// A plain syntax error
+    Invalid,
+}
+
+impl SyntaxIssue {
+    /// Look at the 1-based error `line` and the one before it, since the parser
+    /// often reports a statement's error at the start of the next line
+    #[must_use]
+    pub fn classify(source: &str, line: usize) -> Self {
+        let lines: Vec<&str> = source.lines().collect();
+        let nearby = [line, line.saturating_sub(1)]
+            .into_iter()
+            .filter(|&number| number >= 1)
+            .filter_map(|number| lines.get(number - 1))
+            .map(|text| text.trim());
+
+        let mut issue = SyntaxIssue::Invalid;
+        for text in nearby {
+            if python2_patterns().is_match(text) {
+                return SyntaxIssue::Python2;
+            }
+            if issue == SyntaxIssue::Invalid && unsupported_patterns().is_match(text) {
+                issue = SyntaxIssue::Unsupported;
+            }
+        }
+        issue
+    }
+
+    /// Key used for this issue in per-reason counts
+    #[must_use]
+    pub fn reason(self) -> &'static str {
+        match self {
+            SyntaxIssue::Python2 => "python2_syntax",
+            SyntaxIssue::Unsupported => "unsupported_syntax",
+            SyntaxIssue::Invalid => "syntax_error",
+        }
+    }
+
+    /// Short human-readable description
+    #[must_use]
+    pub fn describe(self) -> &'static str {
+        match self {
+            SyntaxIssue::Python2 => "Python 2 syntax",
+            SyntaxIssue::Unsupported => "unsupported syntax",
+            SyntaxIssue::Invalid => "syntax error",
+        }
+    }
+}
+
+/// Which cap of a [`TsrsError::PlanLimit`] a plan exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanLimit {
+    /// More renames than `PlanLimits::max_renames`
+    Renames,
+    /// More functions than `PlanLimits::max_functions`
+    Functions,
+}
+
+impl PlanLimit {
+    /// Key used for this limit in per-reason counts
+    #[must_use]
+    pub fn reason(self) -> &'static str {
+        match self {
+            PlanLimit::Renames => "too_many_renames",
+            PlanLimit::Functions => "too_many_functions",
+        }
+    }
+
+    /// What the limit counts, in the plural
+    #[must_use]
+    pub fn describe(self) -> &'static str {
+        match self {
+            PlanLimit::Renames => "renames",
+            PlanLimit::Functions => "functions",
+        }
+    }
+}
+
+fn python2_patterns() -> &'static RegexSet {
+    static PATTERNS: OnceLock<RegexSet> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        RegexSet::new([
+            // print statement, including `print >>stream, value`
+            r"^print\b\s*(>>|[^\s(=.,:)\]}])",
+            r"^exec\s+[^\s(=.]",
+            r"^except\s+[\w.]+\s*,\s*\w+\s*:",
+            r"^raise\s+[\w.]+\s*,",
+            r"\w\s*<>\s*\w",
+            r"(^|[=(\[{,:\s])\d+[lL]\b",
+            r"(^|[=(\[{,:\s])0[0-7]+\b",
+        ])
+        .expect("valid Python 2 regexes")
+    })
+}
+
+fn unsupported_patterns() -> &'static RegexSet {
+    static PATTERNS: OnceLock<RegexSet> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        RegexSet::new([
+            // PEP 695 / 696 type parameters
+            r"^type\s+\w+\s*[\[=]",
+            r"^(async\s+)?def\s+\w+\s*\[",
+            r"^class\s+\w+\s*\[",
+            // PEP 701 f-strings reusing their own quote inside a replacement field
+            r#"(?i)(^|[=(\[{,:\s])[rb]?f"[^"]*\{[^}"]*""#,
+            r"(?i)(^|[=(\[{,:\s])[rb]?f'[^']*\{[^}']*'",
+            // PEP 750 template strings
+            r#"(?i)(^|[=(\[{,:\s])r?t["']"#,
+            r"^except\s*\*",
+        ])
+        .expect("valid unsupported-syntax regexes")
+    })
+}
+
+/// Attach the path an IO operation was working on to its error
+pub(crate) trait IoResultExt<T> {
+    fn with_path<P: AsRef<Path>>(self, path: P) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for std::result::Result<T, std::io::Error> {
+    fn with_path<P: AsRef<Path>>(self, path: P) -> Result<T> {
+        self.map_err(|source| TsrsError::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// Read a UTF-8 source file, reporting undecodable contents as [`TsrsError::Encoding`]
+pub(crate) fn read_source(path: &Path) -> Result<String> {
+    let bytes = std::fs::read(path).with_path(path)?;
+    String::from_utf8(bytes).map_err(|_| TsrsError::Encoding {
+        path: path.to_path_buf(),
+        encoding: "utf-8",
+    })
+}
+
+fn file_prefix(file: Option<&PathBuf>) -> String {
+    file.map(|path| format!("{}: ", path.display()))
+        .unwrap_or_default()
+}
+
+/// 1-based line and column (in characters) of a byte offset, clamped to the end of `source`
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let mut end = offset.min(source.len());
+    while !source.is_char_boundary(end) {
+        end -= 1;
+    }
+    let before = &source[..end];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_column_is_one_based_and_counts_characters() {
+        let source = "a = 1\nb = 'é' +\n";
+        assert_eq!(line_column(source, 0), (1, 1));
+        assert_eq!(line_column(source, 6), (2, 1));
+        assert_eq!(line_column(source, 15), (2, 9));
+        assert_eq!(line_column(source, 999), (3, 1));
+    }
+
+    #[test]
+    fn read_source_reports_path_and_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.py");
+        match read_source(&missing) {
+            Err(TsrsError::Io { path, source }) => {
+                assert_eq!(path, missing);
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("expected Io error, got {other:?}"),
+        }
+
+        let latin1 = dir.path().join("latin1.py");
+        std::fs::write(&latin1, b"name = '\xe9'\n").unwrap();
+        match read_source(&latin1) {
+            Err(TsrsError::Encoding { path, encoding }) => {
+                assert_eq!(path, latin1);
+                assert_eq!(encoding, "utf-8");
+            }
+            other => panic!("expected Encoding error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn with_file_only_fills_missing_parse_file() {
+        let err = TsrsError::Parse {
+            file: None,
+            line: 2,
+            column: 3,
+            message: "invalid syntax".to_string(),
+        }
+        .with_file("pkg/mod.py");
+        assert!(matches!(
+            &err,
+            TsrsError::Parse { file: Some(file), line: 2, column: 3, .. }
+                if file == Path::new("pkg/mod.py")
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Failed to parse Python file: pkg/mod.py: invalid syntax at line 2, column 3"
+        );
+
+        let err = err.with_file("other.py");
+        assert!(matches!(
+            err,
+            TsrsError::Parse { file: Some(file), .. } if file == Path::new("pkg/mod.py")
+        ));
+    }
+
+    #[test]
+    fn syntax_issue_spots_python2_and_newer_syntax() {
+        let python2 = [
+            "print \"hello\"\n",
+            "import sys\nprint >>sys.stderr, 'oops'\n",
+            "try:\n    pass\nexcept ValueError, exc:\n    pass\n",
+            "raise ValueError, 'bad'\n",
+            "mode = 0755\n",
+        ];
+        for source in python2 {
+            let line = source.lines().count();
+            assert_eq!(
+                SyntaxIssue::classify(source, line),
+                SyntaxIssue::Python2,
+                "{source}"
+            );
+        }
+
+        let unsupported = [
+            "type Pair[T = int] = tuple[T, T]\n",
+            "label = f\"{user[\"name\"]}\"\n",
+            "greeting = t'hello {name}'\n",
+        ];
+        for source in unsupported {
+            assert_eq!(
+                SyntaxIssue::classify(source, 1),
+                SyntaxIssue::Unsupported,
+                "{source}"
+            );
+        }
+
+        assert_eq!(SyntaxIssue::classify("def f(:\n", 1), SyntaxIssue::Invalid);
+        assert_eq!(SyntaxIssue::classify("print(x)\n", 1), SyntaxIssue::Invalid);
+        assert_eq!(SyntaxIssue::classify("", 1), SyntaxIssue::Invalid);
+    }
+
+    #[test]
+    fn syntax_issue_is_only_reported_for_parse_errors() {
+        let source = "print 'hi'\n";
+        let err = crate::Minifier::plan_from_source("mod", source).unwrap_err();
+        assert_eq!(err.syntax_issue(source), Some(SyntaxIssue::Python2));
+        assert_eq!(SyntaxIssue::Python2.reason(), "python2_syntax");
+
+        let err = TsrsError::ConfigError("nope".to_string());
+        assert_eq!(err.syntax_issue(source), None);
+    }
 }
@@ -0,0 +1,1006 @@
+//! Selection of the source files a directory command operates on
+//!
+//! [`CandidateWalker`] walks a tree and keeps the files that pass the same checks in the
+//! same order for every command: hidden paths are skipped unless requested, include and
+//! exclude globs match slash-separated paths relative to the root, and only files with a
+//! known source extension are kept. A file reached under several paths (through a
+//! followed symlink or a hard link) is kept once. [`CandidateFilter`] applies those checks
+//! to paths that do not come from a directory walk, such as archive entries.
+
+use crate::error::{Result, TsrsError};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Exclude globs that apply in addition to any given ones unless turned off with
+/// [`CandidateWalker::default_excludes`]
+pub const DEFAULT_EXCLUDES: &[&str] = &["**/.git/**", "**/__pycache__/**", "**/.venv/**"];
+
+/// Per-directory ignore file whose patterns are merged into the exclude globs
+pub const TSRSIGNORE_FILE_NAME: &str = ".tsrsignore";
+
+/// Source file extensions selected when none are given
+pub const DEFAULT_EXTENSIONS: &[&str] = &["py"];
+
+/// Rejected source files whose exclude globs are checked when a walk selects nothing
+const EMPTY_WALK_SAMPLE: usize = 32;
+
+/// A file selected for processing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// Path as found under the walked root
+    pub abs_path: PathBuf,
+    /// Path relative to the root
+    pub rel_path: PathBuf,
+    /// `rel_path` joined with `/`, the form globs are matched against
+    pub rel_norm: String,
+    /// Size in bytes when the walk found it; 0 if its metadata could not be read
+    pub size: u64,
+}
+
+/// Result of [`CandidateWalker::walk`]
+#[derive(Debug, Clone, Default)]
+pub struct WalkOutcome {
+    /// Selected files, sorted by `rel_norm`
+    pub candidates: Vec<Candidate>,
+    /// Directory entries that could not be read; each one is logged as a warning
+    pub errors: usize,
+    /// Files already selected under another path, left out of `candidates`
+    pub duplicates: Vec<DuplicateCandidate>,
+    /// Include and exclude globs the walk applied
+    pub patterns: WalkPatterns,
+    /// Set when nothing was selected although files with a selected extension were
+    /// found; it is also logged as a warning
+    pub empty_hint: Option<EmptyWalkHint>,
+}
+
+/// The effective globs of a walk: given or default includes, and excludes from every source
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalkPatterns {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// Why a walk that selected nothing passed over source files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmptyWalkHint {
+    /// Files with a selected extension that the filter rejected
+    pub skipped_sources: usize,
+    /// Exclude glob matching the most of a sample of those files, if any matched
+    pub exclude_pattern: Option<String>,
+}
+
+/// A selected file reached again under another path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateCandidate {
+    /// The later path, which is not processed
+    pub candidate: Candidate,
+    /// `rel_norm` of the candidate kept for the same file
+    pub first: String,
+}
+
+/// Builder for walking a directory tree and selecting candidate source files
+#[derive(Debug, Clone)]
+pub struct CandidateWalker {
+    root: PathBuf,
+    includes: Vec<String>,
+    include_files: Vec<PathBuf>,
+    excludes: Vec<String>,
+    exclude_files: Vec<PathBuf>,
+    default_excludes: bool,
+    extensions: Vec<String>,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    case_insensitive: bool,
+    max_depth: Option<usize>,
+    respect_gitignore: bool,
+    tsrsignore: bool,
+    only: Option<HashSet<PathBuf>>,
+}
+
+impl CandidateWalker {
+    /// Walker over `root` with the CLI defaults: `.py` files, hidden paths and symlinks
+    /// skipped, `.tsrsignore` files honored, and case-insensitive globs only on Windows
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            includes: Vec::new(),
+            include_files: Vec::new(),
+            excludes: Vec::new(),
+            exclude_files: Vec::new(),
+            default_excludes: true,
+            extensions: default_extensions(),
+            include_hidden: false,
+            follow_symlinks: false,
+            case_insensitive: cfg!(windows),
+            max_depth: None,
+            respect_gitignore: false,
+            tsrsignore: true,
+            only: None,
+        }
+    }
+
+    /// Add include globs; without any, every file with a selected extension is included
+    #[must_use]
+    pub fn includes(mut self, patterns: &[String]) -> Self {
+        self.includes.extend(patterns.iter().cloned());
+        self
+    }
+
+    /// Add include globs from a pattern file (one per line, `#` comments)
+    #[must_use]
+    pub fn include_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.include_files.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Add exclude globs; these win over include globs
+    #[must_use]
+    pub fn excludes(mut self, patterns: &[String]) -> Self {
+        self.excludes.extend(patterns.iter().cloned());
+        self
+    }
+
+    /// Add exclude globs from a pattern file (one per line, `#` comments)
+    #[must_use]
+    pub fn exclude_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.exclude_files.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Apply [`DEFAULT_EXCLUDES`] (on by default)
+    #[must_use]
+    pub fn default_excludes(mut self, default_excludes: bool) -> Self {
+        self.default_excludes = default_excludes;
+        self
+    }
+
+    /// Source extensions to select, normalized as by [`resolve_extensions`]
+    #[must_use]
+    pub fn extensions(mut self, extensions: &[String]) -> Self {
+        self.extensions = resolve_extensions(extensions);
+        self
+    }
+
+    /// Select dot-prefixed files and descend into dot-prefixed directories
+    #[must_use]
+    pub fn include_hidden(mut self, include_hidden: bool) -> Self {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    /// Follow symlinked directories and select symlinked files
+    #[must_use]
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Match globs case-insensitively
+    #[must_use]
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Descend at most `max_depth` levels below the root (`Some(1)`: only its own files)
+    #[must_use]
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Skip files ignored by `.gitignore`, `.ignore`, and git's exclude files
+    #[must_use]
+    pub fn respect_gitignore(mut self, respect_gitignore: bool) -> Self {
+        self.respect_gitignore = respect_gitignore;
+        self
+    }
+
+    /// Load exclude globs from `.tsrsignore` files under the root (on by default)
+    #[must_use]
+    pub fn tsrsignore(mut self, tsrsignore: bool) -> Self {
+        self.tsrsignore = tsrsignore;
+        self
+    }
+
+    /// Only select files among `paths`, which are compared with the walked paths as-is
+    #[must_use]
+    pub fn only(mut self, paths: HashSet<PathBuf>) -> Self {
+        self.only = Some(paths);
+        self
+    }
+
+    /// Collect the globs of the walk: include globs (one `**/*.<ext>` per extension when
+    /// none were given), the default excludes unless turned off, pattern files, and
+    /// `.tsrsignore` files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a pattern file or `.tsrsignore` cannot be read.
+    pub fn patterns(&self) -> Result<WalkPatterns> {
+        let mut include_patterns = if self.includes.is_empty() {
+            default_include_patterns(&self.extensions)
+        } else {
+            self.includes.clone()
+        };
+        for path in &self.include_files {
+            include_patterns.extend(read_pattern_file(path)?);
+        }
+
+        let mut exclude_patterns = merged_exclude_patterns(&self.excludes, self.default_excludes);
+        for path in &self.exclude_files {
+            exclude_patterns.extend(read_pattern_file(path)?);
+        }
+        if self.tsrsignore {
+            exclude_patterns.extend(self.tsrsignore_patterns()?);
+        }
+
+        Ok(WalkPatterns {
+            include: include_patterns,
+            exclude: exclude_patterns,
+        })
+    }
+
+    /// Build the path filter from [`CandidateWalker::patterns`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a pattern file or `.tsrsignore` cannot be read, or a glob is
+    /// invalid.
+    pub fn filter(&self) -> Result<CandidateFilter> {
+        self.filter_for(&self.patterns()?)
+    }
+
+    fn filter_for(&self, patterns: &WalkPatterns) -> Result<CandidateFilter> {
+        Ok(CandidateFilter {
+            include: build_globset(&patterns.include, self.case_insensitive)?,
+            exclude: build_globset(&patterns.exclude, self.case_insensitive)?,
+            exclude_patterns: patterns.exclude.clone(),
+            extensions: self.extensions.clone(),
+            include_hidden: self.include_hidden,
+        })
+    }
+
+    /// Walk the root and return the selected files sorted by `rel_norm`.
+    ///
+    /// Unreadable entries are logged and counted rather than failing the walk. When two
+    /// paths lead to the same physical file, the first in `rel_norm` order is kept and the
+    /// others are returned as duplicates, so the file is never processed twice at once.
+    /// When nothing is selected but files with a selected extension were rejected, a
+    /// sample of them is matched against each exclude glob to name the likely culprit.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the filter cannot be built; see [`CandidateWalker::filter`].
+    pub fn walk(&self) -> Result<WalkOutcome> {
+        let patterns = self.patterns()?;
+        let filter = self.filter_for(&patterns)?;
+        let mut outcome = WalkOutcome {
+            patterns,
+            ..WalkOutcome::default()
+        };
+        let mut skipped_sources = 0;
+        let mut skipped_sample = Vec::new();
+
+        for entry in self.walk_builder().build() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    outcome.errors += 1;
+                    warn!("walk error: {}", err);
+                    continue;
+                }
+            };
+
+            let Some(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                continue;
+            }
+            if !self.follow_symlinks && entry.path_is_symlink() {
+                continue;
+            }
+
+            let path = entry.path();
+            if self.only.as_ref().is_some_and(|only| !only.contains(path)) {
+                continue;
+            }
+            let Ok(rel_path) = path.strip_prefix(&self.root) else {
+                continue;
+            };
+
+            let rel_norm = normalize_rel_path(rel_path);
+            if !filter.matches(&rel_norm) {
+                if has_source_extension(rel_path, &self.extensions) {
+                    skipped_sources += 1;
+                    if skipped_sample.len() < EMPTY_WALK_SAMPLE {
+                        skipped_sample.push(rel_norm);
+                    }
+                }
+                continue;
+            }
+
+            outcome.candidates.push(Candidate {
+                abs_path: path.to_path_buf(),
+                rel_path: rel_path.to_path_buf(),
+                rel_norm,
+                size: entry.metadata().map_or(0, |metadata| metadata.len()),
+            });
+        }
+
+        outcome
+            .candidates
+            .sort_by(|a, b| a.rel_norm.cmp(&b.rel_norm));
+        outcome.duplicates = remove_duplicates(&mut outcome.candidates);
+        if outcome.candidates.is_empty() && skipped_sources > 0 {
+            let hint = EmptyWalkHint {
+                skipped_sources,
+                exclude_pattern: filter.likely_exclude(&skipped_sample),
+            };
+            match &hint.exclude_pattern {
+                Some(pattern) => warn!(
+                    "no files selected under {}, but {} source files were filtered out; \
+                     exclude pattern '{}' matches them",
+                    self.root.display(),
+                    skipped_sources,
+                    pattern
+                ),
+                None => warn!(
+                    "no files selected under {}, but {} source files were filtered out by \
+                     the include patterns",
+                    self.root.display(),
+                    skipped_sources
+                ),
+            }
+            outcome.empty_hint = Some(hint);
+        }
+        Ok(outcome)
+    }
+
+    /// Collect exclude globs from every `.tsrsignore` under the root.
+    ///
+    /// Patterns match paths relative to the root. A file in a subdirectory only applies to
+    /// that subtree, so its patterns are prefixed with the subdirectory path; nested files
+    /// can add exclusions but never lift a parent's.
+    fn tsrsignore_patterns(&self) -> Result<Vec<String>> {
+        let mut builder = self.walk_builder();
+        builder.filter_entry(|entry| entry.file_type().is_some_and(|ft| ft.is_dir()));
+
+        let mut patterns = Vec::new();
+        for entry in builder.build() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    debug!(
+                        "walk error while looking for {}: {}",
+                        TSRSIGNORE_FILE_NAME, err
+                    );
+                    continue;
+                }
+            };
+            let ignore_path = entry.path().join(TSRSIGNORE_FILE_NAME);
+            if !ignore_path.is_file() {
+                continue;
+            }
+
+            let loaded = read_pattern_file(&ignore_path)?;
+            debug!(
+                "loaded {} ({} patterns)",
+                ignore_path.display(),
+                loaded.len()
+            );
+            let prefix = entry
+                .path()
+                .strip_prefix(&self.root)
+                .map(normalize_rel_path)
+                .unwrap_or_default();
+            for pattern in loaded {
+                if prefix.is_empty() {
+                    patterns.push(pattern);
+                } else {
+                    patterns.push(format!("{}/{}", prefix, pattern.trim_start_matches('/')));
+                }
+            }
+        }
+        Ok(patterns)
+    }
+
+    fn walk_builder(&self) -> WalkBuilder {
+        let mut builder = WalkBuilder::new(&self.root);
+        builder.follow_links(self.follow_symlinks);
+        builder.standard_filters(false);
+        builder.hidden(!self.include_hidden);
+        builder.max_depth(self.max_depth);
+        builder.require_git(false);
+
+        let gitignore = self.respect_gitignore;
+        builder
+            .git_ignore(gitignore)
+            .git_global(gitignore)
+            .git_exclude(gitignore)
+            .parents(gitignore)
+            .ignore(gitignore);
+
+        builder
+    }
+}
+
+/// Physical identity of a file: its device and inode on Unix, its canonical path elsewhere
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FileIdentity {
+    #[cfg(unix)]
+    Inode { dev: u64, ino: u64 },
+    #[cfg(not(unix))]
+    Path(PathBuf),
+}
+
+impl FileIdentity {
+    #[cfg(unix)]
+    fn of(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = fs::metadata(path).ok()?;
+        Some(FileIdentity::Inode {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn of(path: &Path) -> Option<Self> {
+        dunce::canonicalize(path).ok().map(FileIdentity::Path)
+    }
+}
+
+/// Remove candidates that are the same file as an earlier one and return them
+fn remove_duplicates(candidates: &mut Vec<Candidate>) -> Vec<DuplicateCandidate> {
+    let mut first_paths: HashMap<FileIdentity, String> = HashMap::new();
+    let mut duplicates = Vec::new();
+    candidates.retain(|candidate| {
+        let Some(identity) = FileIdentity::of(&candidate.abs_path) else {
+            return true;
+        };
+        match first_paths.entry(identity) {
+            Entry::Vacant(entry) => {
+                entry.insert(candidate.rel_norm.clone());
+                true
+            }
+            Entry::Occupied(entry) => {
+                warn!(
+                    "{} is the same file as {}; processing it once",
+                    candidate.rel_norm,
+                    entry.get()
+                );
+                duplicates.push(DuplicateCandidate {
+                    candidate: candidate.clone(),
+                    first: entry.get().clone(),
+                });
+                false
+            }
+        }
+    });
+    duplicates
+}
+
+/// Candidates whose `rel_norm` differs from an earlier candidate's only in case, mapped to
+/// that earlier `rel_norm`; on a case-insensitive filesystem both would be written to the
+/// same output file
+#[must_use]
+pub fn case_folded_collisions(candidates: &[Candidate]) -> HashMap<String, String> {
+    let mut written: HashMap<String, &str> = HashMap::new();
+    let mut collisions = HashMap::new();
+    for candidate in candidates {
+        match written.entry(candidate.rel_norm.to_lowercase()) {
+            Entry::Vacant(entry) => {
+                entry.insert(&candidate.rel_norm);
+            }
+            Entry::Occupied(entry) => {
+                collisions.insert(candidate.rel_norm.clone(), (*entry.get()).to_string());
+            }
+        }
+    }
+    collisions
+}
+
+/// Path checks shared by directory walks and archive entries
+#[derive(Debug, Clone)]
+pub struct CandidateFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+    exclude_patterns: Vec<String>,
+    extensions: Vec<String>,
+    include_hidden: bool,
+}
+
+impl CandidateFilter {
+    /// Whether a `/`-separated path relative to the root is a candidate. Rejections are
+    /// logged at debug level with the reason.
+    #[must_use]
+    pub fn matches(&self, rel_norm: &str) -> bool {
+        if !self.include_hidden && rel_norm.split('/').any(|part| part.starts_with('.')) {
+            debug!("• {} → skipped (hidden path)", rel_norm);
+            return false;
+        }
+        if !self.include.is_match(rel_norm) {
+            debug!("• {} → skipped (not included)", rel_norm);
+            return false;
+        }
+        if self.exclude.is_match(rel_norm) {
+            debug!("• {} → skipped (excluded)", rel_norm);
+            return false;
+        }
+        if !has_source_extension(Path::new(rel_norm), &self.extensions) {
+            debug!("• {} → skipped (non-Python)", rel_norm);
+            return false;
+        }
+        true
+    }
+
+    /// The exclude glob matching the most of `paths`, the first given on ties
+    fn likely_exclude(&self, paths: &[String]) -> Option<String> {
+        let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+        for path in paths {
+            for index in self.exclude.matches(path) {
+                *counts.entry(index).or_insert(0) += 1;
+            }
+        }
+        let (index, _) = counts
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))?;
+        self.exclude_patterns.get(index).cloned()
+    }
+}
+
+/// [`DEFAULT_EXTENSIONS`] as owned strings
+#[must_use]
+pub fn default_extensions() -> Vec<String> {
+    DEFAULT_EXTENSIONS
+        .iter()
+        .map(|ext| (*ext).to_string())
+        .collect()
+}
+
+/// Normalize extension values (`.PYI` becomes `pyi`), falling back to the defaults.
+#[must_use]
+pub fn resolve_extensions(extensions: &[String]) -> Vec<String> {
+    let mut resolved: Vec<String> = Vec::new();
+    for ext in extensions {
+        let ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+        if !ext.is_empty() && !resolved.contains(&ext) {
+            resolved.push(ext);
+        }
+    }
+    if resolved.is_empty() {
+        resolved = default_extensions();
+    }
+    resolved
+}
+
+/// Whether `path` ends in one of `extensions`, ignoring case
+#[must_use]
+pub fn has_source_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            extensions
+                .iter()
+                .any(|known| ext.eq_ignore_ascii_case(known))
+        })
+}
+
+/// Join the components of a relative path with `/`
+#[must_use]
+pub fn normalize_rel_path(rel_path: &Path) -> String {
+    let parts: Vec<_> = rel_path
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect();
+    parts.join("/")
+}
+
+/// Dotted module name of the source at `rel_path`: `pkg/sub/mod.py` is `pkg.sub.mod`, a
+/// package's `__init__` is named after its directory, and `-` becomes `_`.
+///
+/// The path is first re-based against the deepest of `module_roots` containing it (see
+/// [`strip_module_root`]), so a tree with several source roots gets the names its modules
+/// are imported by; a path under no root is named from the walked root.
+#[must_use]
+pub fn derive_module_name(rel_path: &Path, module_roots: &[PathBuf]) -> String {
+    let rel_path = strip_module_root(rel_path, module_roots).unwrap_or(rel_path);
+    let without_ext = rel_path.with_extension("");
+    let mut parts: Vec<String> = without_ext
+        .iter()
+        .map(|component| component.to_string_lossy().replace('-', "_"))
+        .collect();
+
+    if parts.last().map(|part| part == "__init__").unwrap_or(false) {
+        parts.pop();
+    }
+
+    if parts.is_empty() {
+        rel_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "module".to_string())
+    } else {
+        parts.join(".")
+    }
+}
+
+/// `rel_path` below the deepest of `module_roots` containing it. Roots are relative to the
+/// walked root and compared by whole components, so `libs` does not contain `libs2/mod.py`.
+#[must_use]
+pub fn strip_module_root<'a>(rel_path: &'a Path, module_roots: &[PathBuf]) -> Option<&'a Path> {
+    module_roots
+        .iter()
+        .filter_map(|root| Some((root.components().count(), rel_path.strip_prefix(root).ok()?)))
+        .max_by_key(|(depth, _)| *depth)
+        .map(|(_, below)| below)
+}
+
+/// `path` with `/` separators on every platform, for paths written into plans, stats,
+/// and diffs so they read the same wherever the run happened
+#[must_use]
+pub fn portable_path(path: &Path) -> String {
+    let text = path.to_string_lossy();
+    if std::path::MAIN_SEPARATOR == '\\' {
+        text.replace('\\', "/")
+    } else {
+        text.into_owned()
+    }
+}
+
+/// Read globs from a pattern file: one per line, skipping blanks and `#` comments.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read.
+pub fn read_pattern_file(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path).map_err(|source| TsrsError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Compile `patterns` into one glob set.
+///
+/// # Errors
+///
+/// Returns an error naming the first invalid pattern.
+pub fn build_globset(patterns: &[String], case_insensitive: bool) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map_err(|err| TsrsError::ConfigError(err.to_string()))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|err| TsrsError::ConfigError(err.to_string()))
+}
+
+/// Include globs used when none are given: one `**/*.<ext>` per extension, matching the
+/// extension in any case like [`has_source_extension`]
+fn default_include_patterns(extensions: &[String]) -> Vec<String> {
+    extensions
+        .iter()
+        .map(|ext| {
+            let ext: String = ext
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphabetic() {
+                        format!("[{}{}]", c.to_ascii_lowercase(), c.to_ascii_uppercase())
+                    } else {
+                        c.to_string()
+                    }
+                })
+                .collect();
+            format!("**/*.{}", ext)
+        })
+        .collect()
+}
+
+/// `extras` after [`DEFAULT_EXCLUDES`] when `defaults` is set
+fn merged_exclude_patterns(extras: &[String], defaults: bool) -> Vec<String> {
+    let defaults = if defaults { DEFAULT_EXCLUDES } else { &[] };
+    defaults
+        .iter()
+        .map(|pattern| (*pattern).to_string())
+        .chain(extras.iter().cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn rel_paths(walker: &CandidateWalker) -> Vec<String> {
+        walker
+            .walk()
+            .unwrap()
+            .candidates
+            .into_iter()
+            .map(|candidate| candidate.rel_norm)
+            .collect()
+    }
+
+    #[test]
+    fn glob_case_insensitive_matches_uppercase() {
+        let set = build_globset(&["a*.py".to_string()], true).unwrap();
+        assert!(set.is_match("A.py"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn glob_matching_requires_opt_in_for_case_insensitivity_on_unix() {
+        let set = build_globset(&["a*.py".to_string()], false).unwrap();
+        assert!(!set.is_match("A.py"));
+    }
+
+    #[test]
+    fn portable_paths_use_forward_slashes() {
+        let path = Path::new("pkg").join("sub").join("mod.py");
+        assert_eq!(portable_path(&path), "pkg/sub/mod.py");
+        assert_eq!(portable_path(Path::new("/abs/mod.py")), "/abs/mod.py");
+    }
+
+    #[test]
+    fn invalid_globs_are_config_errors() {
+        let err = build_globset(&["src/[".to_string()], false).unwrap_err();
+        assert!(matches!(err, TsrsError::ConfigError(_)));
+    }
+
+    #[test]
+    fn hidden_paths_are_skipped_unless_requested() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join(".hidden.py"), "x = 1\n").unwrap();
+        fs::create_dir_all(dir.path().join(".cache")).unwrap();
+        fs::write(dir.path().join(".cache/mod.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("visible.py"), "x = 1\n").unwrap();
+
+        let walker = CandidateWalker::new(dir.path());
+        assert_eq!(rel_paths(&walker), vec!["visible.py"]);
+        assert_eq!(
+            rel_paths(&walker.include_hidden(true)),
+            vec![".cache/mod.py", ".hidden.py", "visible.py"]
+        );
+    }
+
+    #[test]
+    fn case_insensitive_flag_controls_matching() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("A.py"), "x = 1\n").unwrap();
+        let walker = CandidateWalker::new(dir.path()).includes(&["a*.py".to_string()]);
+
+        assert_eq!(
+            rel_paths(&walker.clone().case_insensitive(true)),
+            vec!["A.py"]
+        );
+        assert!(rel_paths(&walker.case_insensitive(false)).is_empty());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn default_matching_is_case_insensitive_on_windows() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("A.py"), "x = 1\n").unwrap();
+        let walker = CandidateWalker::new(dir.path()).includes(&["a*.py".to_string()]);
+        assert_eq!(rel_paths(&walker), vec!["A.py"]);
+    }
+
+    #[test]
+    fn max_depth_limits_descent() {
+        let dir = tempdir().unwrap();
+        let deep = dir.path().join("level1/level2");
+        fs::create_dir_all(&deep).unwrap();
+        fs::write(dir.path().join("root.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("level1/inner.py"), "x = 1\n").unwrap();
+        fs::write(deep.join("deep.py"), "x = 1\n").unwrap();
+
+        let walker = CandidateWalker::new(dir.path());
+        assert_eq!(
+            rel_paths(&walker.clone().max_depth(Some(1))),
+            vec!["root.py"]
+        );
+        assert_eq!(
+            rel_paths(&walker.clone().max_depth(Some(2))),
+            vec!["level1/inner.py", "root.py"]
+        );
+        assert_eq!(rel_paths(&walker).len(), 3);
+    }
+
+    #[test]
+    fn excludes_tsrsignore_and_extensions_filter_candidates() {
+        let dir = tempdir().unwrap();
+        let pkg = dir.path().join("pkg");
+        fs::create_dir_all(&pkg).unwrap();
+        fs::create_dir_all(dir.path().join("__pycache__")).unwrap();
+        fs::write(dir.path().join("__pycache__/cached.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("keep.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("skip.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("stub.PYI"), "x: int\n").unwrap();
+        fs::write(dir.path().join("notes.txt"), "text\n").unwrap();
+        fs::write(pkg.join(TSRSIGNORE_FILE_NAME), "# generated\ngen_*.py\n").unwrap();
+        fs::write(pkg.join("gen_api.py"), "x = 1\n").unwrap();
+        fs::write(pkg.join("api.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("gen_top.py"), "x = 1\n").unwrap();
+
+        let walker = CandidateWalker::new(dir.path())
+            .excludes(&["skip.py".to_string()])
+            .extensions(&[".py".to_string(), "pyi".to_string()]);
+        assert_eq!(
+            rel_paths(&walker),
+            vec!["gen_top.py", "keep.py", "pkg/api.py", "stub.PYI"]
+        );
+        assert_eq!(rel_paths(&walker.tsrsignore(false)).len(), 5);
+    }
+
+    #[test]
+    fn filter_applies_to_paths_outside_a_walk() {
+        let dir = tempdir().unwrap();
+        let filter = CandidateWalker::new(dir.path())
+            .tsrsignore(false)
+            .excludes(&["tests/**".to_string()])
+            .filter()
+            .unwrap();
+
+        assert!(filter.matches("pkg/mod.py"));
+        assert!(!filter.matches("pkg/.hidden/mod.py"));
+        assert!(!filter.matches("tests/test_mod.py"));
+        assert!(!filter.matches("pkg/data.json"));
+    }
+
+    #[test]
+    fn module_names_are_rebased_on_the_deepest_root() {
+        let roots = vec![
+            PathBuf::from("services"),
+            PathBuf::from("services/a/src"),
+            PathBuf::from("libs/common"),
+        ];
+        let name = |path: &str| derive_module_name(Path::new(path), &roots);
+
+        assert_eq!(name("services/a/src/pkg/api.py"), "pkg.api");
+        assert_eq!(name("services/b/jobs.py"), "b.jobs");
+        assert_eq!(name("libs/common/pkg/__init__.py"), "pkg");
+        assert_eq!(name("libs/common-extra/mod.py"), "libs.common_extra.mod");
+        assert_eq!(strip_module_root(Path::new("tools/run.py"), &roots), None);
+        assert_eq!(name("tools/run.py"), "tools.run");
+        assert_eq!(
+            derive_module_name(Path::new("tools/run.py"), &[]),
+            "tools.run"
+        );
+    }
+
+    #[test]
+    fn default_excludes_can_be_turned_off() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("__pycache__")).unwrap();
+        fs::write(dir.path().join("__pycache__/cached.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("mod.py"), "x = 1\n").unwrap();
+
+        let walker = CandidateWalker::new(dir.path()).excludes(&["gen/**".to_string()]);
+        assert_eq!(rel_paths(&walker), vec!["mod.py"]);
+
+        let walker = walker.default_excludes(false);
+        assert_eq!(rel_paths(&walker), vec!["__pycache__/cached.py", "mod.py"]);
+        assert_eq!(
+            walker.patterns().unwrap(),
+            WalkPatterns {
+                include: vec!["**/*.[pP][yY]".to_string()],
+                exclude: vec!["gen/**".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn empty_walks_name_the_exclude_that_filtered_sources() {
+        let dir = tempdir().unwrap();
+        let snapshot = dir.path().join("venv-snapshot/lib");
+        fs::create_dir_all(&snapshot).unwrap();
+        fs::write(snapshot.join("a.py"), "x = 1\n").unwrap();
+        fs::write(snapshot.join("b.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("notes.txt"), "text\n").unwrap();
+
+        let outcome = CandidateWalker::new(dir.path())
+            .excludes(&["**/b.py".to_string(), "venv-snapshot/**".to_string()])
+            .walk()
+            .unwrap();
+        assert!(outcome.candidates.is_empty());
+        assert_eq!(
+            outcome.empty_hint,
+            Some(EmptyWalkHint {
+                skipped_sources: 2,
+                exclude_pattern: Some("venv-snapshot/**".to_string()),
+            })
+        );
+
+        let outcome = CandidateWalker::new(dir.path())
+            .includes(&["src/**".to_string()])
+            .walk()
+            .unwrap();
+        let hint = outcome.empty_hint.unwrap();
+        assert_eq!(hint.skipped_sources, 2);
+        assert_eq!(hint.exclude_pattern, None);
+
+        fs::write(dir.path().join("main.py"), "x = 1\n").unwrap();
+        let outcome = CandidateWalker::new(dir.path())
+            .excludes(&["venv-snapshot/**".to_string()])
+            .walk()
+            .unwrap();
+        assert_eq!(outcome.empty_hint, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_files_are_kept_once() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("Utils.py"), "x = 1\n").unwrap();
+        std::os::unix::fs::symlink("Utils.py", dir.path().join("link.py")).unwrap();
+        fs::hard_link(dir.path().join("Utils.py"), dir.path().join("zz_copy.py")).unwrap();
+
+        let walker = CandidateWalker::new(dir.path());
+        let outcome = walker.clone().follow_symlinks(true).walk().unwrap();
+        let kept: Vec<&str> = outcome
+            .candidates
+            .iter()
+            .map(|candidate| candidate.rel_norm.as_str())
+            .collect();
+        assert_eq!(kept, vec!["Utils.py"]);
+        let duplicates: Vec<(&str, &str)> = outcome
+            .duplicates
+            .iter()
+            .map(|duplicate| {
+                (
+                    duplicate.candidate.rel_norm.as_str(),
+                    duplicate.first.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            duplicates,
+            vec![("link.py", "Utils.py"), ("zz_copy.py", "Utils.py")]
+        );
+
+        // Without following symlinks only the hard link is a duplicate
+        let outcome = walker.walk().unwrap();
+        assert_eq!(outcome.candidates.len(), 1);
+        assert_eq!(outcome.duplicates.len(), 1);
+    }
+
+    #[test]
+    fn case_folded_collisions_name_the_earlier_path() {
+        let candidate = |rel_norm: &str| Candidate {
+            abs_path: PathBuf::from(rel_norm),
+            rel_path: PathBuf::from(rel_norm),
+            rel_norm: rel_norm.to_string(),
+            size: 0,
+        };
+        let candidates = [
+            candidate("pkg/Utils.py"),
+            candidate("pkg/other.py"),
+            candidate("pkg/utils.py"),
+            candidate("PKG/utils.py"),
+        ];
+        let collisions = case_folded_collisions(&candidates);
+        assert_eq!(collisions.len(), 2);
+        assert_eq!(collisions["pkg/utils.py"], "pkg/Utils.py");
+        assert_eq!(collisions["PKG/utils.py"], "pkg/Utils.py");
+    }
+}
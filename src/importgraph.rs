@@ -0,0 +1,488 @@
+//! Module-level import graph of first-party code and the import cycles in it
+
+use crate::error::Result;
+use crate::imports::{DetailedImport, ImportCollector};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+
+/// Imports made by one first-party module
+#[derive(Debug, Clone)]
+pub struct ModuleImports {
+    /// Dotted module name, e.g. `pkg.sub` for `pkg/sub.py` or `pkg/sub/__init__.py`
+    pub module: String,
+    /// File the module was read from, as reported for each import
+    pub path: String,
+    /// Whether the file is a package's `__init__.py`, so relative imports resolve
+    /// against the module itself rather than its parent
+    pub is_package: bool,
+    /// Absolute and relative imports in line order
+    pub imports: Vec<DetailedImport>,
+}
+
+impl ModuleImports {
+    /// Collect the imports of `source`, the contents of `path`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed.
+    pub fn from_source(module: &str, path: &str, is_package: bool, source: &str) -> Result<Self> {
+        let mut collector = ImportCollector::new();
+        collector
+            .collect_from_source(source)
+            .map_err(|err| err.with_file(path))?;
+        let mut imports: Vec<DetailedImport> = collector
+            .get_detailed_imports()
+            .into_iter()
+            .chain(collector.get_relative_imports())
+            .collect();
+        imports.sort_by_key(|import| import.lineno);
+        Ok(Self {
+            module: module.to_string(),
+            path: path.to_string(),
+            is_package,
+            imports,
+        })
+    }
+
+    /// Module name of the file at `rel_path` (`/`-separated, relative to the scanned
+    /// directory) and whether it is a package, with `root_package` prefixed when the
+    /// directory is itself a package. `None` when a component is not an importable name.
+    #[must_use]
+    pub fn module_name(rel_path: &str, root_package: Option<&str>) -> Option<(String, bool)> {
+        let (dir, file) = rel_path.rsplit_once('/').unwrap_or(("", rel_path));
+        let stem = file.rsplit_once('.').map_or(file, |(stem, _)| stem);
+        let mut parts: Vec<&str> = root_package.into_iter().collect();
+        parts.extend(dir.split('/').filter(|part| !part.is_empty()));
+        let is_package = stem == "__init__";
+        if !is_package {
+            parts.push(stem);
+        }
+        if parts.is_empty() || !parts.iter().all(|part| is_identifier(part)) {
+            return None;
+        }
+        Some((parts.join("."), is_package))
+    }
+
+    /// Absolute module named by `import`, resolving a relative import against this
+    /// module's package; `None` when it climbs above the top-level package
+    #[must_use]
+    pub fn resolve(&self, import: &DetailedImport) -> Option<String> {
+        if import.level == 0 {
+            return Some(import.module.clone());
+        }
+        let mut package: Vec<&str> = self.module.split('.').collect();
+        if !self.is_package {
+            package.pop();
+        }
+        let up = usize::try_from(import.level - 1).ok()?;
+        let kept = package.len().checked_sub(up)?;
+        package.truncate(kept);
+        if !import.module.is_empty() {
+            package.push(&import.module);
+        }
+        Some(package.join("."))
+    }
+}
+
+/// Where an import statement behind an edge appears
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportLocation {
+    pub path: String,
+    /// 1-indexed line of the import statement
+    pub line: usize,
+}
+
+impl fmt::Display for ImportLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path, self.line)
+    }
+}
+
+/// `from` imports `to`, at each of `sites`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportEdge {
+    pub from: String,
+    pub to: String,
+    pub sites: Vec<ImportLocation>,
+}
+
+/// Modules that import each other, directly or through one another
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportCycle {
+    /// Participating modules, sorted
+    pub modules: Vec<String>,
+    /// Edges between the participating modules
+    pub edges: Vec<ImportEdge>,
+}
+
+/// Directed graph of imports between first-party modules
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ImportGraph {
+    /// Modules, sorted
+    pub modules: Vec<String>,
+    /// Edges sorted by importing and then imported module
+    pub edges: Vec<ImportEdge>,
+}
+
+impl ImportGraph {
+    /// Graph of the imports between `modules`, leaving out imports of anything else.
+    ///
+    /// Imports inside functions and under `if TYPE_CHECKING:` do not run when the module
+    /// is imported, so they only count with `include_deferred`. `from pkg import name`
+    /// points at `pkg.name` when that is one of the modules, and other imports at the
+    /// longest module prefix of what they name.
+    #[must_use]
+    pub fn build(modules: &[ModuleImports], include_deferred: bool) -> Self {
+        let known: BTreeSet<&str> = modules
+            .iter()
+            .map(|module| module.module.as_str())
+            .collect();
+        let mut edges: BTreeMap<(&str, &str), Vec<ImportLocation>> = BTreeMap::new();
+        for module in modules {
+            for import in &module.imports {
+                if !include_deferred && (import.deferred || import.type_checking) {
+                    continue;
+                }
+                let Some(target) = module.resolve(import) else {
+                    continue;
+                };
+                let Some(to) = first_party_target(&known, &target, &import.symbols) else {
+                    continue;
+                };
+                if to == module.module {
+                    continue;
+                }
+                let site = ImportLocation {
+                    path: module.path.clone(),
+                    line: import.lineno,
+                };
+                let sites = edges.entry((module.module.as_str(), to)).or_default();
+                if !sites.contains(&site) {
+                    sites.push(site);
+                }
+            }
+        }
+
+        Self {
+            modules: known.iter().map(ToString::to_string).collect(),
+            edges: edges
+                .into_iter()
+                .map(|((from, to), sites)| ImportEdge {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    sites,
+                })
+                .collect(),
+        }
+    }
+
+    /// The part of the graph reachable from `root`, or `None` if it is not a module
+    #[must_use]
+    pub fn cone(&self, root: &str) -> Option<Self> {
+        if self
+            .modules
+            .binary_search_by(|module| module.as_str().cmp(root))
+            .is_err()
+        {
+            return None;
+        }
+        let mut reached: BTreeSet<&str> = BTreeSet::from([root]);
+        let mut queue = VecDeque::from([root]);
+        while let Some(module) = queue.pop_front() {
+            for edge in self.edges.iter().filter(|edge| edge.from == module) {
+                if reached.insert(&edge.to) {
+                    queue.push_back(&edge.to);
+                }
+            }
+        }
+        Some(Self {
+            modules: reached.iter().map(ToString::to_string).collect(),
+            edges: self
+                .edges
+                .iter()
+                .filter(|edge| reached.contains(edge.from.as_str()))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Strongly connected components of more than one module, by first module
+    #[must_use]
+    pub fn cycles(&self) -> Vec<ImportCycle> {
+        let index_of: BTreeMap<&str, usize> = self
+            .modules
+            .iter()
+            .enumerate()
+            .map(|(index, module)| (module.as_str(), index))
+            .collect();
+        let mut adjacency = vec![Vec::new(); self.modules.len()];
+        for edge in &self.edges {
+            if let (Some(&from), Some(&to)) = (
+                index_of.get(edge.from.as_str()),
+                index_of.get(edge.to.as_str()),
+            ) {
+                adjacency[from].push(to);
+            }
+        }
+
+        let mut tarjan = Tarjan::new(&adjacency);
+        for node in 0..self.modules.len() {
+            if tarjan.index[node].is_none() {
+                tarjan.visit(node);
+            }
+        }
+
+        let mut cycles: Vec<ImportCycle> = tarjan
+            .components
+            .into_iter()
+            .filter(|component| component.len() > 1)
+            .map(|mut component| {
+                component.sort_unstable();
+                let members: BTreeSet<&str> = component
+                    .iter()
+                    .map(|&node| self.modules[node].as_str())
+                    .collect();
+                ImportCycle {
+                    modules: members.iter().map(ToString::to_string).collect(),
+                    edges: self
+                        .edges
+                        .iter()
+                        .filter(|edge| {
+                            members.contains(edge.from.as_str())
+                                && members.contains(edge.to.as_str())
+                        })
+                        .cloned()
+                        .collect(),
+                }
+            })
+            .collect();
+        cycles.sort_by(|a, b| a.modules.cmp(&b.modules));
+        cycles
+    }
+
+    /// Export as Graphviz DOT, drawing the edges of `cycles` in red
+    #[must_use]
+    pub fn to_dot(&self, cycles: &[ImportCycle]) -> String {
+        let in_cycle: BTreeSet<(&str, &str)> = cycles
+            .iter()
+            .flat_map(|cycle| &cycle.edges)
+            .map(|edge| (edge.from.as_str(), edge.to.as_str()))
+            .collect();
+
+        let mut dot = String::from("digraph ImportGraph {\n");
+        dot.push_str("  rankdir=LR;\n");
+        dot.push_str("  node [shape=box];\n\n");
+        for module in &self.modules {
+            dot.push_str(&format!("  \"{}\";\n", escape_dot(module)));
+        }
+        if !self.edges.is_empty() {
+            dot.push('\n');
+        }
+        for edge in &self.edges {
+            let color = if in_cycle.contains(&(edge.from.as_str(), edge.to.as_str())) {
+                " [color=red]"
+            } else {
+                ""
+            };
+            dot.push_str(&format!(
+                "  \"{}\" -> \"{}\"{};\n",
+                escape_dot(&edge.from),
+                escape_dot(&edge.to),
+                color
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// The module `target` refers to: `target.symbol` for a symbol that is itself a module,
+/// otherwise the longest prefix of `target` that is one
+fn first_party_target<'a>(
+    known: &BTreeSet<&'a str>,
+    target: &str,
+    symbols: &[String],
+) -> Option<&'a str> {
+    for symbol in symbols.iter().filter(|symbol| *symbol != "*") {
+        let submodule = if target.is_empty() {
+            symbol.clone()
+        } else {
+            format!("{target}.{symbol}")
+        };
+        if let Some(found) = known.get(submodule.as_str()) {
+            return Some(*found);
+        }
+    }
+    let mut candidate = target;
+    loop {
+        if let Some(found) = known.get(candidate) {
+            return Some(*found);
+        }
+        candidate = &candidate[..candidate.rfind('.')?];
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first == '_' || first.is_alphabetic())
+        && chars.all(|c| c == '_' || c.is_alphanumeric())
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('"', "\\\"")
+}
+
+/// Tarjan's strongly connected components over node indices
+struct Tarjan<'a> {
+    adjacency: &'a [Vec<usize>],
+    index: Vec<Option<usize>>,
+    lowlink: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    next_index: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn new(adjacency: &'a [Vec<usize>]) -> Self {
+        Self {
+            adjacency,
+            index: vec![None; adjacency.len()],
+            lowlink: vec![0; adjacency.len()],
+            on_stack: vec![false; adjacency.len()],
+            stack: Vec::new(),
+            next_index: 0,
+            components: Vec::new(),
+        }
+    }
+
+    fn visit(&mut self, node: usize) {
+        self.index[node] = Some(self.next_index);
+        self.lowlink[node] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+
+        let adjacency = self.adjacency;
+        for &next in &adjacency[node] {
+            match self.index[next] {
+                None => {
+                    self.visit(next);
+                    self.lowlink[node] = self.lowlink[node].min(self.lowlink[next]);
+                }
+                Some(index) if self.on_stack[next] => {
+                    self.lowlink[node] = self.lowlink[node].min(index);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if self.index[node] == Some(self.lowlink[node]) {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(rel_path: &str, source: &str) -> ModuleImports {
+        let (name, is_package) = ModuleImports::module_name(rel_path, None).unwrap();
+        ModuleImports::from_source(&name, rel_path, is_package, source).unwrap()
+    }
+
+    #[test]
+    fn three_module_cycle_is_reported_deterministically() {
+        let mut modules = vec![
+            module("pkg/__init__.py", ""),
+            module("pkg/a.py", "import os\nfrom pkg import b\n"),
+            module("pkg/b.py", "from .c import helper\n"),
+            module("pkg/c.py", "import pkg.a\n\n\ndef helper():\n    pass\n"),
+        ];
+        let graph = ImportGraph::build(&modules, false);
+        let cycles = graph.cycles();
+
+        let edge = |from: &str, to: &str, path: &str, line| ImportEdge {
+            from: from.to_string(),
+            to: to.to_string(),
+            sites: vec![ImportLocation {
+                path: path.to_string(),
+                line,
+            }],
+        };
+        assert_eq!(
+            cycles,
+            vec![ImportCycle {
+                modules: vec!["pkg.a".into(), "pkg.b".into(), "pkg.c".into()],
+                edges: vec![
+                    edge("pkg.a", "pkg.b", "pkg/a.py", 2),
+                    edge("pkg.b", "pkg.c", "pkg/b.py", 1),
+                    edge("pkg.c", "pkg.a", "pkg/c.py", 1),
+                ],
+            }]
+        );
+
+        modules.reverse();
+        let reversed = ImportGraph::build(&modules, false);
+        assert_eq!(reversed, graph);
+        assert_eq!(reversed.cycles(), cycles);
+    }
+
+    #[test]
+    fn dag_has_no_cycles_and_a_stable_dot() {
+        let modules = vec![
+            module("app.py", "import lib\nimport os\n"),
+            module("lib.py", "from util import tools\n"),
+            module("util.py", "def tools():\n    import app\n"),
+        ];
+        let graph = ImportGraph::build(&modules, false);
+        assert!(graph.cycles().is_empty());
+        assert_eq!(
+            graph.to_dot(&[]),
+            "digraph ImportGraph {\n  rankdir=LR;\n  node [shape=box];\n\n  \"app\";\n  \"lib\";\n  \"util\";\n\n  \"app\" -> \"lib\";\n  \"lib\" -> \"util\";\n}\n"
+        );
+
+        let with_deferred = ImportGraph::build(&modules, true);
+        assert_eq!(with_deferred.cycles().len(), 1);
+        let cone = graph.cone("lib").unwrap();
+        assert_eq!(cone.modules, vec!["lib", "util"]);
+        assert!(graph.cone("missing").is_none());
+    }
+
+    #[test]
+    fn relative_imports_resolve_against_the_package() {
+        assert_eq!(
+            ModuleImports::module_name("sub/__init__.py", Some("pkg")),
+            Some(("pkg.sub".to_string(), true))
+        );
+        assert_eq!(ModuleImports::module_name("my-script.py", None), None);
+
+        let init = module(
+            "pkg/sub/__init__.py",
+            "from . import x\nfrom ..y import z\n",
+        );
+        let resolved: Vec<Option<String>> = init
+            .imports
+            .iter()
+            .map(|import| init.resolve(import))
+            .collect();
+        assert_eq!(
+            resolved,
+            vec![Some("pkg.sub".to_string()), Some("pkg.y".to_string())]
+        );
+        let leaf = module("pkg/mod.py", "from ... import too_far\n");
+        assert_eq!(leaf.resolve(&leaf.imports[0]), None);
+    }
+}
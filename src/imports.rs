@@ -1,11 +1,19 @@
 //! Import tracking and collection
 
-use crate::error::{Result, TsrsError};
+use crate::error::{read_source, Result, TsrsError};
+use crate::stdlib::{self, PythonVersion};
 use rustpython_parser::{ast, Parse};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
 
+/// Whether the top-level package of `module` ships with the standard library of any
+/// Python version from 3.8 through 3.13, including ones removed in later releases.
+#[must_use]
+pub fn is_stdlib_module(module: &str) -> bool {
+    stdlib::is_stdlib_in_any_version(module)
+}
+
 /// Set of unique imports
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ImportSet {
@@ -29,6 +37,18 @@ pub struct DetailedImport {
     pub binding_name: String,
     /// Line number where the import statement appears (1-indexed)
     pub lineno: usize,
+    /// Name given with `as`, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// Number of leading dots of a relative import (0 for absolute imports)
+    #[serde(default)]
+    pub level: u32,
+    /// Whether the import only runs when its enclosing function is called
+    #[serde(default)]
+    pub deferred: bool,
+    /// Whether the import sits under `if TYPE_CHECKING:`
+    #[serde(default)]
+    pub type_checking: bool,
 }
 
 /// Information about symbol usage in the code
@@ -63,6 +83,22 @@ impl ImportSet {
         imports.sort();
         imports
     }
+
+    /// Remove the imports that `python_version` ships in its standard library and
+    /// return them sorted
+    pub fn remove_stdlib(&mut self, python_version: PythonVersion) -> Vec<String> {
+        let mut removed: Vec<String> = self
+            .imports
+            .iter()
+            .filter(|import| stdlib::is_stdlib(import, python_version))
+            .cloned()
+            .collect();
+        for import in &removed {
+            self.imports.remove(import);
+        }
+        removed.sort();
+        removed
+    }
 }
 
 /// Collects imports from Python code via AST traversal
@@ -70,10 +106,18 @@ pub struct ImportCollector {
     imports: ImportSet,
     /// Detailed imports with symbol-level information
     detailed_imports: Vec<DetailedImport>,
+    /// Relative imports, kept apart since they never name a dependency
+    relative_imports: Vec<DetailedImport>,
     /// Mapping from binding names to their detailed import information
     binding_to_import: HashMap<String, DetailedImport>,
     /// Source code for symbol usage analysis
     source: Option<String>,
+    /// Byte offset at which each line of the current source starts
+    line_starts: Vec<usize>,
+    /// Number of function bodies enclosing the statement being visited
+    function_depth: usize,
+    /// Number of `if TYPE_CHECKING:` blocks enclosing the statement being visited
+    type_checking_depth: usize,
 }
 
 impl ImportCollector {
@@ -83,8 +127,12 @@ impl ImportCollector {
         ImportCollector {
             imports: ImportSet::new(),
             detailed_imports: Vec::new(),
+            relative_imports: Vec::new(),
             binding_to_import: HashMap::new(),
             source: None,
+            line_starts: vec![0],
+            function_depth: 0,
+            type_checking_depth: 0,
         }
     }
 
@@ -95,10 +143,10 @@ impl ImportCollector {
     /// Returns an error if the file cannot be read or parsed.
     pub fn collect_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         let path_ref = path.as_ref();
-        let source = std::fs::read_to_string(path_ref).map_err(TsrsError::Io)?;
+        let source = read_source(path_ref)?;
         let filename = path_ref.display().to_string();
-        self.collect_from_source_with_name(&source, &filename)?;
-        Ok(())
+        self.collect_from_source_with_name(&source, &filename)
+            .map_err(|err| err.with_file(path_ref))
     }
 
     /// Parse Python source code and extract imports
@@ -113,8 +161,11 @@ impl ImportCollector {
 
     fn collect_from_source_with_name(&mut self, source: &str, filename: &str) -> Result<()> {
         self.source = Some(source.to_string());
-        let suite = ast::Suite::parse(source, filename)
-            .map_err(|err| TsrsError::ParseError(err.to_string()))?;
+        self.line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        let suite =
+            ast::Suite::parse(source, filename).map_err(|err| TsrsError::parse(source, &err))?;
         self.visit_suite(&suite);
         Ok(())
     }
@@ -129,8 +180,10 @@ impl ImportCollector {
         match stmt {
             ast::Stmt::Import(import) => self.handle_import(import),
             ast::Stmt::ImportFrom(import_from) => self.handle_import_from(import_from),
-            ast::Stmt::FunctionDef(function_def) => self.visit_suite(&function_def.body),
-            ast::Stmt::AsyncFunctionDef(function_def) => self.visit_suite(&function_def.body),
+            ast::Stmt::FunctionDef(function_def) => self.visit_function_body(&function_def.body),
+            ast::Stmt::AsyncFunctionDef(function_def) => {
+                self.visit_function_body(&function_def.body);
+            }
             ast::Stmt::ClassDef(class_def) => self.visit_suite(&class_def.body),
             ast::Stmt::For(for_stmt) => {
                 self.visit_suite(&for_stmt.body);
@@ -145,7 +198,13 @@ impl ImportCollector {
                 self.visit_suite(&while_stmt.orelse);
             }
             ast::Stmt::If(if_stmt) => {
-                self.visit_suite(&if_stmt.body);
+                if is_type_checking_guard(&if_stmt.test) {
+                    self.type_checking_depth += 1;
+                    self.visit_suite(&if_stmt.body);
+                    self.type_checking_depth -= 1;
+                } else {
+                    self.visit_suite(&if_stmt.body);
+                }
                 self.visit_suite(&if_stmt.orelse);
             }
             ast::Stmt::With(with_stmt) => self.visit_suite(&with_stmt.body),
@@ -177,7 +236,37 @@ impl ImportCollector {
         }
     }
 
+    fn visit_function_body(&mut self, body: &[ast::Stmt]) {
+        self.function_depth += 1;
+        self.visit_suite(body);
+        self.function_depth -= 1;
+    }
+
+    /// 1-indexed line containing `offset` in the current source
+    fn line_of(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line + 1,
+            Err(next) => next,
+        }
+    }
+
+    /// An import of `module` at `lineno`, tagged with the enclosing context
+    fn new_import(&self, module: String, lineno: usize) -> DetailedImport {
+        DetailedImport {
+            binding_name: module.clone(),
+            module,
+            symbols: Vec::new(),
+            is_wildcard: false,
+            lineno,
+            alias: None,
+            level: 0,
+            deferred: self.function_depth > 0,
+            type_checking: self.type_checking_depth > 0,
+        }
+    }
+
     fn handle_import(&mut self, import: &ast::StmtImport) {
+        let lineno = self.line_of(usize::from(import.range.start()));
         for alias in &import.names {
             let module_name = alias.name.as_str().to_string();
             self.add_identifier_name(&alias.name);
@@ -192,11 +281,9 @@ impl ImportCollector {
 
             if !binding_name.is_empty() {
                 let detailed = DetailedImport {
-                    module: module_name,
-                    symbols: vec![],
-                    is_wildcard: false,
                     binding_name: binding_name.clone(),
-                    lineno: 0,
+                    alias: alias.asname.as_ref().map(ToString::to_string),
+                    ..self.new_import(module_name, lineno)
                 };
                 self.detailed_imports.push(detailed.clone());
                 self.binding_to_import.insert(binding_name, detailed);
@@ -206,10 +293,30 @@ impl ImportCollector {
 
     fn handle_import_from(&mut self, import_from: &ast::StmtImportFrom) {
         let level = import_from.level.as_ref().map_or(0, ast::Int::to_u32);
+        let lineno = self.line_of(usize::from(import_from.range.start()));
 
         if level > 0 {
-            // Relative imports refer to the current package; skip to avoid
-            // incorrectly attributing them to external dependencies.
+            // Relative imports refer to the current package; keep them out of the
+            // dependency set so they are never attributed to external packages.
+            let module = import_from
+                .module
+                .as_ref()
+                .map_or_else(String::new, ToString::to_string);
+            for alias in &import_from.names {
+                let symbol_name = alias.name.as_str().to_string();
+                let detailed = DetailedImport {
+                    is_wildcard: symbol_name == "*",
+                    binding_name: alias
+                        .asname
+                        .as_ref()
+                        .map_or_else(|| symbol_name.clone(), ToString::to_string),
+                    symbols: vec![symbol_name],
+                    alias: alias.asname.as_ref().map(ToString::to_string),
+                    level,
+                    ..self.new_import(module.clone(), lineno)
+                };
+                self.relative_imports.push(detailed);
+            }
             return;
         }
 
@@ -224,11 +331,8 @@ impl ImportCollector {
             if is_wildcard {
                 // `from module import *` - binding is the module itself
                 let detailed = DetailedImport {
-                    module: module_str.clone(),
-                    symbols: vec![],
                     is_wildcard: true,
-                    binding_name: module_str.clone(),
-                    lineno: 0,
+                    ..self.new_import(module_str.clone(), lineno)
                 };
                 self.detailed_imports.push(detailed.clone());
                 self.binding_to_import.insert(module_str, detailed);
@@ -243,11 +347,10 @@ impl ImportCollector {
                     };
 
                     let detailed = DetailedImport {
-                        module: module_str.clone(),
                         symbols: vec![symbol_name],
-                        is_wildcard: false,
                         binding_name: binding_name.clone(),
-                        lineno: 0,
+                        alias: alias.asname.as_ref().map(ToString::to_string),
+                        ..self.new_import(module_str.clone(), lineno)
                     };
                     self.detailed_imports.push(detailed.clone());
                     self.binding_to_import.insert(binding_name, detailed);
@@ -288,6 +391,15 @@ impl ImportCollector {
         self.detailed_imports.clone()
     }
 
+    /// Get relative imports (`from . import x`, `from ..pkg import y`)
+    ///
+    /// These are never part of [`ImportCollector::get_imports`] or
+    /// [`ImportCollector::get_detailed_imports`] since they refer to the importing package.
+    #[must_use]
+    pub fn get_relative_imports(&self) -> Vec<DetailedImport> {
+        self.relative_imports.clone()
+    }
+
     /// Get detailed import information by binding name
     ///
     /// This is useful for looking up an imported symbol by its name in the current scope.
@@ -319,8 +431,8 @@ impl ImportCollector {
         let mut usage: HashMap<String, Vec<usize>> = HashMap::new();
 
         // Parse the source to get the AST
-        let suite = ast::Suite::parse(source, "<analyze>")
-            .map_err(|err| TsrsError::ParseError(err.to_string()))?;
+        let suite =
+            ast::Suite::parse(source, "<analyze>").map_err(|err| TsrsError::parse(source, &err))?;
 
         // Visit all statements to find Name references
         let mut visitor = NameVisitor::new();
@@ -372,6 +484,15 @@ impl ImportCollector {
     }
 }
 
+/// Whether an `if` test is `TYPE_CHECKING` or `typing.TYPE_CHECKING`
+fn is_type_checking_guard(test: &ast::Expr) -> bool {
+    match test {
+        ast::Expr::Name(name) => name.id.as_str() == "TYPE_CHECKING",
+        ast::Expr::Attribute(attr) => attr.attr.as_str() == "TYPE_CHECKING",
+        _ => false,
+    }
+}
+
 /// Helper visitor for finding Name references in the AST
 struct NameVisitor {
     /// Map from name to list of line numbers where it's used
@@ -681,6 +802,17 @@ mod tests {
         collector.get_imports().get_imports()
     }
 
+    #[test]
+    fn collect_from_missing_file_reports_its_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.py");
+        let mut collector = ImportCollector::new();
+        match collector.collect_from_file(&path) {
+            Err(TsrsError::Io { path: reported, .. }) => assert_eq!(reported, path),
+            other => panic!("expected IO error, got {other:?}"),
+        }
+    }
+
     #[test]
     fn collects_top_level_modules() {
         let imports = imports_from(
@@ -732,6 +864,23 @@ from os import path
 
     // ============= New symbol-level tracking tests =============
 
+    #[test]
+    fn detailed_imports_record_line_numbers() {
+        let mut collector = ImportCollector::new();
+        collector
+            .collect_from_source("import os\n\nfrom collections import (\n    deque,\n)\n")
+            .unwrap();
+        let lines: Vec<(String, usize)> = collector
+            .get_detailed_imports()
+            .into_iter()
+            .map(|import| (import.module, import.lineno))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![("os".to_string(), 1), ("collections".to_string(), 3)]
+        );
+    }
+
     #[test]
     fn collects_detailed_imports_from_import() {
         let mut collector = ImportCollector::new();
@@ -1032,4 +1181,97 @@ def func():
         assert!(usage.contains_key("lru_cache"));
         assert!(usage.contains_key("chain"));
     }
+
+    #[test]
+    fn detailed_imports_record_alias_deferral_and_type_checking() {
+        let mut collector = ImportCollector::new();
+        let source = r#"
+from typing import TYPE_CHECKING
+import numpy as np
+
+if TYPE_CHECKING:
+    from pandas import DataFrame as Frame
+else:
+    import json
+
+def load():
+    import yaml
+    return yaml
+"#;
+        collector
+            .collect_from_source(source)
+            .expect("parse should succeed");
+
+        let flags: Vec<(String, Option<String>, bool, bool)> = collector
+            .get_detailed_imports()
+            .into_iter()
+            .map(|import| {
+                (
+                    import.binding_name,
+                    import.alias,
+                    import.deferred,
+                    import.type_checking,
+                )
+            })
+            .collect();
+        assert_eq!(
+            flags,
+            vec![
+                ("TYPE_CHECKING".to_string(), None, false, false),
+                ("np".to_string(), Some("np".to_string()), false, false),
+                ("Frame".to_string(), Some("Frame".to_string()), false, true),
+                ("json".to_string(), None, false, false),
+                ("yaml".to_string(), None, true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_imports_are_reported_separately() {
+        let mut collector = ImportCollector::new();
+        collector
+            .collect_from_source("from . import local as loc\nfrom ..package import feature\n")
+            .expect("parse should succeed");
+
+        assert!(collector.get_imports().imports.is_empty());
+        assert!(collector.get_detailed_imports().is_empty());
+        let relative: Vec<(String, u32, String)> = collector
+            .get_relative_imports()
+            .into_iter()
+            .map(|import| (import.module, import.level, import.binding_name))
+            .collect();
+        assert_eq!(
+            relative,
+            vec![
+                (String::new(), 1, "loc".to_string()),
+                ("package".to_string(), 2, "feature".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn stdlib_modules_are_recognised_by_top_level_name() {
+        assert!(is_stdlib_module("os.path"));
+        assert!(is_stdlib_module("__future__"));
+        assert!(is_stdlib_module("distutils"));
+        assert!(!is_stdlib_module("numpy"));
+        assert!(!is_stdlib_module("requests.adapters"));
+    }
+
+    #[test]
+    fn remove_stdlib_follows_the_python_version() {
+        let mut imports = ImportSet::new();
+        for name in ["tomllib", "json", "numpy"] {
+            imports.add(name.to_string());
+        }
+        let mut older = imports.clone();
+
+        assert_eq!(
+            imports.remove_stdlib(PythonVersion::new(3, 11)),
+            vec!["json", "tomllib"]
+        );
+        assert_eq!(imports.get_imports(), vec!["numpy"]);
+        assert_eq!(older.remove_stdlib(PythonVersion::new(3, 10)), vec!["json"]);
+        assert_eq!(older.get_imports(), vec!["numpy", "tomllib"]);
+    }
 }
@@ -1,17 +1,66 @@
+#[cfg(feature = "batch")]
+pub mod archive;
+#[cfg(feature = "batch")]
 pub mod callgraph;
+#[cfg(feature = "batch")]
+pub mod config;
 pub mod error;
+#[cfg(feature = "batch")]
+pub mod fswalk;
+pub mod importgraph;
 pub mod imports;
 pub mod minify;
+pub mod pipeline;
 pub mod reporting;
+#[cfg(feature = "venv")]
 pub mod slim;
+pub mod sourcemap;
+pub mod stdlib;
+pub mod textio;
+#[cfg(feature = "venv")]
 pub mod venv;
 
-pub use callgraph::{CallGraphAnalyzer, FunctionRef, PackageCallGraph};
+#[cfg(feature = "batch")]
+pub use archive::{ArchiveEntry, ArchiveFormat, EntryKind, SourceArchive};
+#[cfg(feature = "batch")]
+pub use callgraph::{
+    CallGraphAnalyzer, CallGraphExport, CallSite, ExportedFunction, FunctionRef, PackageCallGraph,
+    RootReason,
+};
+#[cfg(feature = "batch")]
+pub use config::Config;
+pub use error::{PlanLimit, SyntaxIssue, TsrsError};
+#[cfg(feature = "batch")]
+pub use fswalk::{
+    derive_module_name, Candidate, CandidateFilter, CandidateWalker, DuplicateCandidate,
+    EmptyWalkHint, WalkOutcome, WalkPatterns,
+};
+pub use importgraph::{ImportCycle, ImportEdge, ImportGraph, ImportLocation, ModuleImports};
 pub use imports::{ImportCollector, ImportSet};
-pub use minify::{FunctionPlan as MinifyFunctionPlan, Minifier, MinifyPlan, RenameEntry};
-pub use reporting::{CallGraphDot, DeadCodeReport, DeadFunction};
-pub use slim::VenvSlimmer;
-pub use venv::{VenvAnalyzer, VenvInfo};
+pub use minify::{
+    AssignmentCleanup, BailoutReason, CollisionKind, FunctionFilter,
+    FunctionPlan as MinifyFunctionPlan, FunctionRewrite, ImportCleanup, Minifier, MinifyPlan,
+    NameCollision, NamingConfig, NamingMode, PlanLimits, PlanOptions, PlanSummary, RenameEntry,
+    RewriteOptions, RewriteOutcome, RewriteSummary, StreamOptions, DEFAULT_RENAME_ALPHABET,
+};
+pub use pipeline::{
+    LevelSettings, MinifyLevel, PipelineOutput, StageStats, Transform, TransformContext,
+    TransformOutput, TransformPipeline,
+};
+pub use reporting::{
+    sarif_log, CallGraphDot, Confidence, DeadCodeReport, DeadFunction, FindingLocation,
+    RemovalSpan, SarifFinding, DEAD_FUNCTION_RULE,
+};
+#[cfg(feature = "venv")]
+pub use slim::{
+    FailedImport, ImportSite, ImportVerification, LicenseSummary, PackageDecision, PackagePolicy,
+    SlimDecision, SlimFormat, SlimOptions, SlimReport, SlimStep, VenvSlimmer,
+};
+pub use sourcemap::{FileSourceMap, SourceMap};
+pub use stdlib::PythonVersion;
+pub use textio::{LineEnding, TextMetadata};
+#[cfg(feature = "venv")]
+pub use venv::{EnvironmentKind, PackageInfo, PackageMetadata, VenvAnalyzer, VenvInfo};
 
 #[cfg(feature = "python-extension")]
 use pyo3::prelude::*;
@@ -30,6 +79,16 @@ fn tsrs(py: Python, m: &PyModule) -> PyResult<()> {
     Ok(())
 }
 
+/// Raise parse failures as `SyntaxError` and IO failures as `OSError`
+#[cfg(feature = "python-extension")]
+fn to_py_err(err: TsrsError) -> PyErr {
+    match err {
+        TsrsError::Parse { .. } => pyo3::exceptions::PySyntaxError::new_err(err.to_string()),
+        TsrsError::Io { .. } => pyo3::exceptions::PyOSError::new_err(err.to_string()),
+        _ => PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(err.to_string()),
+    }
+}
+
 #[cfg(feature = "python-extension")]
 #[pyclass]
 pub struct PyVenvAnalyzer {
@@ -41,19 +100,22 @@ pub struct PyVenvAnalyzer {
 impl PyVenvAnalyzer {
     #[new]
     fn new(venv_path: String) -> PyResult<Self> {
-        let analyzer = VenvAnalyzer::new(venv_path)
-            .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
+        let analyzer = VenvAnalyzer::new(venv_path).map_err(to_py_err)?;
         Ok(PyVenvAnalyzer { analyzer })
     }
 
     fn analyze(&self) -> PyResult<String> {
-        let info = self
-            .analyzer
-            .analyze()
-            .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
+        let info = self.analyzer.analyze().map_err(to_py_err)?;
         Ok(serde_json::to_string(&info)
             .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?)
     }
+
+    /// Distribution that installs the module `name` imports, if exactly one does
+    fn distribution_for_import(&self, name: &str) -> PyResult<Option<String>> {
+        self.analyzer
+            .distribution_for_import(name)
+            .map_err(to_py_err)
+    }
 }
 
 #[cfg(feature = "python-extension")]
@@ -66,16 +128,33 @@ pub struct PyVenvSlimmer {
 #[pymethods]
 impl PyVenvSlimmer {
     #[new]
-    fn new(venv_path: String, output_path: String) -> PyResult<Self> {
-        let slimmer = VenvSlimmer::new(venv_path, output_path)
-            .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
+    #[pyo3(signature = (code_paths, venv_path, output_path=None))]
+    fn new(
+        code_paths: Vec<String>,
+        venv_path: String,
+        output_path: Option<String>,
+    ) -> PyResult<Self> {
+        let roots: Vec<std::path::PathBuf> = code_paths.into_iter().map(Into::into).collect();
+        let venv = std::path::PathBuf::from(venv_path);
+        let output = output_path.map_or_else(
+            || {
+                venv.parent()
+                    .map_or_else(
+                        || std::path::PathBuf::from("."),
+                        std::path::Path::to_path_buf,
+                    )
+                    .join(".venv-slim")
+            },
+            Into::into,
+        );
+        let slimmer = VenvSlimmer::new_with_roots(&roots, &venv, &output).map_err(to_py_err)?;
         Ok(PyVenvSlimmer { slimmer })
     }
 
+    /// Create the slim venv and return the report as JSON
     fn slim(&self) -> PyResult<String> {
-        self.slimmer
-            .slim()
-            .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
-        Ok("Slim venv created successfully".to_string())
+        let report = self.slimmer.slim().map_err(to_py_err)?;
+        serde_json::to_string(&report)
+            .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
     }
 }
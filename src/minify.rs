@@ -1,10 +1,14 @@
 //! Scope-aware rename planning inspired by pyminifier.
 
-use crate::error::{Result, TsrsError};
+use crate::error::{IoResultExt, PlanLimit, Result, TsrsError};
+use crate::textio::{decode_python_bytes, encode_python, TextMetadata};
 use rustpython_parser::ast::Ranged;
-use rustpython_parser::{ast, Parse};
+use rustpython_parser::{ast, lexer, Mode, Parse, Tok};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Write};
+use unicode_normalization::{is_nfkc, UnicodeNormalization};
 
 const PYTHON_KEYWORDS: &[&str] = &[
     "False", "None", "True", "and", "as", "assert", "async", "await", "break", "case", "class",
@@ -15,6 +19,167 @@ const PYTHON_KEYWORDS: &[&str] = &[
 
 const RESERVED_IDENTIFIERS: &[&str] = &["self", "cls", "_"];
 
+/// Soft keywords are valid identifiers but read badly (and parse ambiguously) as short names.
+const PYTHON_SOFT_KEYWORDS: &[&str] = &["match", "case", "type", "_"];
+
+/// Public names in the `builtins` module; generated names must never shadow these, since code
+/// elsewhere in the function (or an `eval`) may still expect the builtin.
+const PYTHON_BUILTINS: &[&str] = &[
+    "ArithmeticError",
+    "AssertionError",
+    "AttributeError",
+    "BaseException",
+    "BaseExceptionGroup",
+    "BlockingIOError",
+    "BrokenPipeError",
+    "BufferError",
+    "BytesWarning",
+    "ChildProcessError",
+    "ConnectionAbortedError",
+    "ConnectionError",
+    "ConnectionRefusedError",
+    "ConnectionResetError",
+    "DeprecationWarning",
+    "EOFError",
+    "Ellipsis",
+    "EncodingWarning",
+    "EnvironmentError",
+    "Exception",
+    "ExceptionGroup",
+    "False",
+    "FileExistsError",
+    "FileNotFoundError",
+    "FloatingPointError",
+    "FutureWarning",
+    "GeneratorExit",
+    "IOError",
+    "ImportError",
+    "ImportWarning",
+    "IndentationError",
+    "IndexError",
+    "InterruptedError",
+    "IsADirectoryError",
+    "KeyError",
+    "KeyboardInterrupt",
+    "LookupError",
+    "MemoryError",
+    "ModuleNotFoundError",
+    "NameError",
+    "None",
+    "NotADirectoryError",
+    "NotImplemented",
+    "NotImplementedError",
+    "OSError",
+    "OverflowError",
+    "PendingDeprecationWarning",
+    "PermissionError",
+    "ProcessLookupError",
+    "PythonFinalizationError",
+    "RecursionError",
+    "ReferenceError",
+    "ResourceWarning",
+    "RuntimeError",
+    "RuntimeWarning",
+    "StopAsyncIteration",
+    "StopIteration",
+    "SyntaxError",
+    "SyntaxWarning",
+    "SystemError",
+    "SystemExit",
+    "TabError",
+    "TimeoutError",
+    "True",
+    "TypeError",
+    "UnboundLocalError",
+    "UnicodeDecodeError",
+    "UnicodeEncodeError",
+    "UnicodeError",
+    "UnicodeTranslateError",
+    "UnicodeWarning",
+    "UserWarning",
+    "ValueError",
+    "Warning",
+    "ZeroDivisionError",
+    "abs",
+    "aiter",
+    "all",
+    "anext",
+    "any",
+    "ascii",
+    "bin",
+    "bool",
+    "breakpoint",
+    "bytearray",
+    "bytes",
+    "callable",
+    "chr",
+    "classmethod",
+    "compile",
+    "complex",
+    "copyright",
+    "credits",
+    "delattr",
+    "dict",
+    "dir",
+    "divmod",
+    "enumerate",
+    "eval",
+    "exec",
+    "exit",
+    "filter",
+    "float",
+    "format",
+    "frozenset",
+    "getattr",
+    "globals",
+    "hasattr",
+    "hash",
+    "help",
+    "hex",
+    "id",
+    "input",
+    "int",
+    "isinstance",
+    "issubclass",
+    "iter",
+    "len",
+    "license",
+    "list",
+    "locals",
+    "map",
+    "max",
+    "memoryview",
+    "min",
+    "next",
+    "object",
+    "oct",
+    "open",
+    "ord",
+    "pow",
+    "print",
+    "property",
+    "quit",
+    "range",
+    "repr",
+    "reversed",
+    "round",
+    "set",
+    "setattr",
+    "slice",
+    "sorted",
+    "staticmethod",
+    "str",
+    "sum",
+    "super",
+    "tuple",
+    "type",
+    "vars",
+    "zip",
+];
+
+/// Number of leading lines searched for a `# tsrs: skip-file` directive.
+const SKIP_FILE_DIRECTIVE_LINES: usize = 10;
+
 /// High-level API for computing rename plans.
 pub struct Minifier;
 
@@ -25,13 +190,100 @@ impl Minifier {
     ///
     /// Returns an error if the source cannot be parsed.
     pub fn plan_from_source(module_name: &str, source: &str) -> Result<MinifyPlan> {
-        let suite = ast::Suite::parse(source, module_name)
-            .map_err(|err| TsrsError::ParseError(err.to_string()))?;
+        Self::plan_from_source_with_naming(module_name, source, NamingMode::Sequential)
+    }
+
+    /// Build a plan using the given short-name assignment strategy.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed.
+    pub fn plan_from_source_with_naming(
+        module_name: &str,
+        source: &str,
+        naming: NamingMode,
+    ) -> Result<MinifyPlan> {
+        Self::plan_from_source_with_options(
+            module_name,
+            source,
+            PlanOptions {
+                naming,
+                ..PlanOptions::default()
+            },
+        )
+    }
+
+    /// Build a plan with explicit per-file planning options.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed, or [`TsrsError::PlanLimit`] once the
+    /// plan passes one of `options.limits`.
+    pub fn plan_from_source_with_options(
+        module_name: &str,
+        source: &str,
+        options: PlanOptions,
+    ) -> Result<MinifyPlan> {
+        let suite =
+            ast::Suite::parse(source, module_name).map_err(|err| TsrsError::parse(source, &err))?;
+
+        let directives = Directives::parse(source);
+        if directives.skip_file {
+            let mut plan =
+                Planner::new(module_name.to_string(), HashSet::new(), directives, options)
+                    .finish(source);
+            plan.skip_file = true;
+            return Ok(plan);
+        }
 
-        let mut planner = Planner::new(module_name.to_string());
+        let module_renames = if options.rename_module_privates {
+            plan_module_renames(&suite, source, options.naming, &options.naming_config)
+        } else {
+            Vec::new()
+        };
+        // Function plans must not hand out the new module-level names either.
+        let mut module_bindings = collect_module_bindings(&suite);
+        module_bindings.extend(module_renames.iter().map(|entry| entry.renamed.clone()));
+
+        let mut planner = Planner::new(
+            module_name.to_string(),
+            module_bindings.clone(),
+            directives,
+            options.clone(),
+        );
+        planner.count_planned(0, module_renames.len());
         planner.visit_suite(&suite, &mut Vec::new());
+        if let Some(limit) = planner.exceeded {
+            return Err(options.limits.error(limit));
+        }
+
+        let mut plan = planner.finish(source);
+        plan.module_renames = module_renames;
+        count_occurrences(&mut plan, source, &suite);
 
-        Ok(planner.finish())
+        // Single-use locals are only known once occurrences are counted; plan again with
+        // them kept so the remaining locals get the short names they would have freed.
+        let single_use = if options.skip_single_use {
+            single_use_locals(&plan)
+        } else {
+            HashSet::new()
+        };
+        if !single_use.is_empty() {
+            let mut planner = Planner::new(
+                module_name.to_string(),
+                module_bindings,
+                Directives::parse(source),
+                options,
+            );
+            planner.single_use = single_use;
+            planner.visit_suite(&suite, &mut Vec::new());
+
+            let module_renames = std::mem::take(&mut plan.module_renames);
+            plan = planner.finish(source);
+            plan.module_renames = module_renames;
+            count_occurrences(&mut plan, source, &suite);
+        }
+        Ok(plan)
     }
 
     /// Rewrite source code by applying planned renames when no nested functions are present.
@@ -42,48 +294,635 @@ impl Minifier {
     pub fn rewrite_source(module_name: &str, source: &str) -> Result<String> {
         let plan = Self::plan_from_source(module_name, source)?;
 
-        Self::rewrite_with_plan_internal(module_name, source, &plan)
+        Self::rewrite_with_plan_internal(module_name, source, &plan, RewriteOptions::default())
+            .map(|outcome| outcome.source)
+    }
+
+    /// Rename locals only inside `qualified_name` (and functions nested in it), leaving the
+    /// rest of the module byte-identical, docstrings included.
+    ///
+    /// The whole module is planned, so the names chosen still avoid every other binding.
+    /// `qualified_name` may be given with or without the `module_name.` prefix.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed, or [`TsrsError::UnknownFunction`]
+    /// listing the plan's functions if none is named `qualified_name`.
+    pub fn rewrite_function(
+        module_name: &str,
+        source: &str,
+        qualified_name: &str,
+    ) -> Result<String> {
+        let plan =
+            Self::plan_from_source(module_name, source)?.only_functions(&[qualified_name])?;
+        Self::rewrite_with_plan(module_name, source, &plan)
     }
 
     /// Rewrite using a precomputed plan, enabling plan curation before application.
     ///
     /// # Errors
     ///
-    /// Returns an error if the source cannot be parsed.
+    /// Returns an error if the source cannot be parsed, or [`TsrsError::InvalidPlan`] if a
+    /// rename fails [`MinifyPlan::validate`].
     pub fn rewrite_with_plan(module_name: &str, source: &str, plan: &MinifyPlan) -> Result<String> {
-        Self::rewrite_with_plan_internal(module_name, source, plan)
+        Self::rewrite_with_plan_internal(module_name, source, plan, RewriteOptions::default())
+            .map(|outcome| outcome.source)
+    }
+
+    /// Rewrite using a precomputed plan and report which functions were renamed or skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed, or [`TsrsError::InvalidPlan`] if a
+    /// rename fails [`MinifyPlan::validate`].
+    pub fn rewrite_with_plan_detailed(
+        module_name: &str,
+        source: &str,
+        plan: &MinifyPlan,
+    ) -> Result<RewriteOutcome> {
+        Self::rewrite_with_plan_internal(module_name, source, plan, RewriteOptions::default())
+    }
+
+    /// Rewrite using a precomputed plan with explicit application options.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed, or [`TsrsError::InvalidPlan`] if a
+    /// rename fails [`MinifyPlan::validate`].
+    pub fn rewrite_with_plan_options(
+        module_name: &str,
+        source: &str,
+        plan: &MinifyPlan,
+        options: RewriteOptions,
+    ) -> Result<RewriteOutcome> {
+        Self::rewrite_with_plan_internal(module_name, source, plan, options)
+    }
+
+    /// Minify the Python source read from `reader` and write it to `writer` in its original
+    /// encoding, line endings, and trailing newline.
+    ///
+    /// The whole input is buffered, since parsing needs the complete text. The encoding
+    /// comes from a BOM or PEP 263 coding cookie as in [`decode_python_bytes`], unless
+    /// [`StreamOptions::encoding`] overrides it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TsrsError::Io`] if reading or writing fails, [`TsrsError::Encoding`] or
+    /// [`TsrsError::Encode`] if the text cannot be converted, or an error if the source
+    /// cannot be parsed or planned.
+    pub fn rewrite_stream<R: Read, W: Write>(
+        module_name: &str,
+        mut reader: R,
+        mut writer: W,
+        options: &StreamOptions,
+    ) -> Result<RewriteSummary> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).with_path(module_name)?;
+        let (source, metadata) =
+            decode_python_bytes(&bytes, module_name, options.encoding, options.lossy)?;
+
+        let plan = Self::plan_from_source_with_options(module_name, &source, options.plan.clone())?;
+        let outcome =
+            Self::rewrite_with_plan_internal(module_name, &source, &plan, options.rewrite)?;
+        let output = encode_python(&outcome.source, &metadata, module_name)?;
+        writer.write_all(&output).with_path(module_name)?;
+
+        Ok(RewriteSummary {
+            outcome,
+            metadata,
+            bytes_read: bytes.len(),
+            bytes_written: output.len(),
+        })
+    }
+
+    /// Check that `source` is valid Python, e.g. to verify rewritten output before writing it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TsrsError::Parse`] describing the first syntax error.
+    pub fn check_syntax(module_name: &str, source: &str) -> Result<()> {
+        ast::Suite::parse(source, module_name)
+            .map(|_| ())
+            .map_err(|err| TsrsError::parse(source, &err))
+    }
+
+    /// Bytes a rewrite removes from `source` by stripping docstrings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed.
+    pub fn docstring_bytes(module_name: &str, source: &str) -> Result<usize> {
+        let suite =
+            ast::Suite::parse(source, module_name).map_err(|err| TsrsError::parse(source, &err))?;
+        let mut ranges = Vec::new();
+        collect_docstrings_in_suite(source, &suite, false, &mut ranges);
+        Ok(ranges.iter().map(|(start, end)| end - start).sum())
+    }
+
+    /// Remove import bindings that nothing else in the module references.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed.
+    pub fn remove_unused_imports(module_name: &str, source: &str) -> Result<String> {
+        Self::remove_unused_imports_detailed(module_name, source).map(|cleanup| cleanup.source)
+    }
+
+    /// Remove unused imports and report which bindings were dropped.
+    ///
+    /// A binding counts as used when its name appears anywhere outside import statements,
+    /// including inside strings, so names listed in `__all__` are kept. Star imports,
+    /// `__future__` imports, imports inside a `try` that catches `ImportError`, and
+    /// statements on a `# noqa` line are left untouched. Only the unused aliases of a
+    /// multi-name import are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed.
+    pub fn remove_unused_imports_detailed(
+        module_name: &str,
+        source: &str,
+    ) -> Result<ImportCleanup> {
+        let suite =
+            ast::Suite::parse(source, module_name).map_err(|err| TsrsError::parse(source, &err))?;
+
+        let directives = Directives::parse(source);
+        if directives.skip_file {
+            return Ok(ImportCleanup {
+                source: source.to_string(),
+                removed: Vec::new(),
+            });
+        }
+
+        let mut scan = ImportScan::default();
+        scan.visit_suite(&suite, false, false);
+
+        let mut import_ranges: Vec<(usize, usize)> = scan
+            .imports
+            .iter()
+            .map(|import| {
+                let range = import.stmt.range();
+                (usize::from(range.start()), usize::from(range.end()))
+            })
+            .collect();
+        import_ranges.sort_unstable();
+        let in_import = |offset: usize| {
+            let next = import_ranges.partition_point(|(start, _)| *start <= offset);
+            next > 0 && offset < import_ranges[next - 1].1
+        };
+
+        let mut used: HashSet<String> = HashSet::new();
+        let mut noqa_lines: HashSet<usize> = HashSet::new();
+        for (token, range) in lexer::lex(source, Mode::Module).map_while(|result| result.ok()) {
+            let start = usize::from(range.start());
+            match token {
+                Tok::Comment(text) if text.to_ascii_lowercase().contains("noqa") => {
+                    noqa_lines.insert(directives.line_of(start));
+                }
+                _ if in_import(start) => {}
+                Tok::Name { name } => {
+                    used.insert(name);
+                }
+                Tok::String { value, .. } => {
+                    used.extend(identifiers_in_text(&value).map(str::to_string));
+                }
+                _ => {}
+            }
+        }
+
+        let mut removed = Vec::new();
+        let mut replacements = Vec::new();
+        // Fully removed statements per nested suite, so a suite is never left empty.
+        let mut emptied: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+
+        for import in &scan.imports {
+            if import.guarded {
+                continue;
+            }
+            let range = import.stmt.range();
+            let (start, end) = (usize::from(range.start()), usize::from(range.end()));
+            if (directives.line_of(start)..=directives.line_of(end))
+                .any(|line| noqa_lines.contains(&line))
+            {
+                continue;
+            }
+            let Some(bindings) = import_bindings(import.stmt) else {
+                continue;
+            };
+
+            let (kept, unused): (Vec<_>, Vec<_>) = bindings
+                .into_iter()
+                .partition(|(_, binding)| used.contains(binding));
+            if unused.is_empty() {
+                continue;
+            }
+            removed.extend(unused.into_iter().map(|(_, binding)| binding));
+
+            if kept.is_empty() {
+                match import.suite {
+                    Some(suite_id) => emptied.entry(suite_id).or_default().push((start, end)),
+                    None => replacements.push(removal_replacement(source, start, end)),
+                }
+            } else {
+                let aliases: Vec<&ast::Alias> = kept.into_iter().map(|(alias, _)| alias).collect();
+                replacements.push(Replacement {
+                    start,
+                    end,
+                    text: render_import(import.stmt, &aliases),
+                });
+            }
+        }
+
+        for (suite_id, statements) in emptied {
+            let keep_pass = statements.len() == scan.suite_lens[suite_id];
+            for (index, &(start, end)) in statements.iter().enumerate() {
+                if keep_pass && index + 1 == statements.len() {
+                    replacements.push(Replacement {
+                        start,
+                        end,
+                        text: "pass".to_string(),
+                    });
+                } else {
+                    replacements.push(removal_replacement(source, start, end));
+                }
+            }
+        }
+
+        replacements.sort_by_key(|replacement| replacement.start);
+
+        let cleaned = splice_replacements(source, &replacements);
+        Ok(
+            match Self::preserve_module_header(module_name, source, &cleaned) {
+                Some(cleaned) => ImportCleanup {
+                    source: cleaned,
+                    removed,
+                },
+                None => ImportCleanup {
+                    source: source.to_string(),
+                    removed: Vec::new(),
+                },
+            },
+        )
+    }
+
+    /// Remove top-level `NAME = value` assignments whose name nothing references.
+    ///
+    /// A name counts as referenced when it appears anywhere else in the module, including
+    /// inside strings (so `__all__` entries and literal `__all__` mutations keep it), or
+    /// when `used_elsewhere` reports it for another module. Dunder names, assignments
+    /// nested in `if` or `try` blocks (such as `if TYPE_CHECKING:`), targets other than a
+    /// single name, and values that could run code when evaluated (calls, awaits,
+    /// attribute and subscript access, comprehensions, f-strings, or operators applied to
+    /// names) are kept, as is every assignment in a module that uses `globals()`, `vars()`,
+    /// `eval`, `exec`, or a module `__getattr__`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed.
+    pub fn remove_dead_assignments(
+        module_name: &str,
+        source: &str,
+        used_elsewhere: &dyn Fn(&str) -> bool,
+    ) -> Result<AssignmentCleanup> {
+        let suite =
+            ast::Suite::parse(source, module_name).map_err(|err| TsrsError::parse(source, &err))?;
+        let unchanged = || AssignmentCleanup {
+            source: source.to_string(),
+            removed: Vec::new(),
+        };
+
+        if Directives::parse(source).skip_file {
+            return Ok(unchanged());
+        }
+
+        let mut mentions: HashMap<String, usize> = HashMap::new();
+        let mut in_strings: HashSet<String> = HashSet::new();
+        for (token, _) in lexer::lex(source, Mode::Module).map_while(|result| result.ok()) {
+            match token {
+                Tok::Name { name } => *mentions.entry(name).or_default() += 1,
+                Tok::String { value, .. } => {
+                    in_strings.extend(identifiers_in_text(&value).map(str::to_string));
+                }
+                _ => {}
+            }
+        }
+        if DYNAMIC_SCOPE_NAMES
+            .iter()
+            .any(|name| mentions.contains_key(*name))
+        {
+            return Ok(unchanged());
+        }
+
+        let mut removed = Vec::new();
+        let mut replacements = Vec::new();
+        for stmt in &suite {
+            let ast::Stmt::Assign(assign) = stmt else {
+                continue;
+            };
+            let [ast::Expr::Name(target)] = assign.targets.as_slice() else {
+                continue;
+            };
+            let name = target.id.as_str();
+            let dunder = name.starts_with("__") && name.ends_with("__");
+            let dead = !dunder
+                && mentions.get(name) == Some(&1)
+                && !in_strings.contains(name)
+                && !used_elsewhere(name)
+                && is_pure_value(&assign.value);
+            if !dead {
+                continue;
+            }
+            let range = stmt.range();
+            replacements.push(removal_replacement(
+                source,
+                usize::from(range.start()),
+                usize::from(range.end()),
+            ));
+            removed.push(name.to_string());
+        }
+
+        let cleaned = splice_replacements(source, &replacements);
+        Ok(
+            match Self::preserve_module_header(module_name, source, &cleaned) {
+                Some(cleaned) => AssignmentCleanup {
+                    source: cleaned,
+                    removed,
+                },
+                None => unchanged(),
+            },
+        )
+    }
+
+    /// Check that `rewritten` keeps the header Python requires of `original`: its `#!` line
+    /// first, its coding cookie on one of the first two lines, and its `__future__` imports
+    /// before any statement other than the docstring. A lost shebang or cookie is put back;
+    /// `None` means the header cannot be repaired and `original` should be kept instead.
+    /// Every rewrite runs its output through this before returning it.
+    #[must_use]
+    pub fn preserve_module_header(
+        module_name: &str,
+        original: &str,
+        rewritten: &str,
+    ) -> Option<String> {
+        if rewritten == original {
+            return Some(rewritten.to_string());
+        }
+        let header = ModuleHeader::of(module_name, original)?;
+        let mut repaired = rewritten.to_string();
+        for line in [header.shebang, header.cookie].into_iter().flatten() {
+            let kept = leading_lines(&repaired, 2).any(|kept| kept.trim_end() == line.trim_end());
+            if kept {
+                continue;
+            }
+            // A header line that moved further down cannot be put back safely
+            if repaired
+                .split_inclusive('\n')
+                .any(|kept| kept.trim_end() == line.trim_end())
+            {
+                return None;
+            }
+            let at = if header.shebang.is_some_and(|shebang| shebang != line) {
+                repaired.find('\n').map_or(repaired.len(), |end| end + 1)
+            } else {
+                0
+            };
+            repaired.insert_str(at, line);
+            if !line.ends_with('\n') {
+                repaired.insert(at + line.len(), '\n');
+            }
+        }
+        if let Some(shebang) = header.shebang {
+            if !repaired.starts_with(shebang.trim_end()) {
+                return None;
+            }
+        }
+
+        let rewritten_header = ModuleHeader::of(module_name, &repaired)?;
+        let futures_kept = header
+            .future_features
+            .iter()
+            .all(|feature| rewritten_header.future_features.contains(feature));
+        let futures_first = rewritten_header.futures_first || !header.futures_first;
+        (futures_kept && futures_first).then_some(repaired)
     }
 
     fn rewrite_with_plan_internal(
         module_name: &str,
         source: &str,
         plan: &MinifyPlan,
-    ) -> Result<String> {
-        let mut plan_map: HashMap<String, FunctionPlan> = HashMap::new();
-        let mut invalid_plan = false;
+        options: RewriteOptions,
+    ) -> Result<RewriteOutcome> {
+        if plan.skip_file {
+            return Ok(RewriteOutcome {
+                source: source.to_string(),
+                ..RewriteOutcome::default()
+            });
+        }
+        plan.validate(source)?;
+
+        let mut with_renames = Vec::new();
+        let mut missing_ranges = Vec::new();
 
         for function_plan in &plan.functions {
             if function_plan.range.is_none() {
-                invalid_plan = true;
+                missing_ranges.push((
+                    function_plan.qualified_name.clone(),
+                    BailoutReason::MissingRange.to_string(),
+                ));
                 continue;
             }
             if function_plan.renames.is_empty() {
                 continue;
             }
-            plan_map.insert(function_plan.qualified_name.clone(), function_plan.clone());
+            with_renames.push(function_plan);
+        }
+        let plan_map = plans_by_name(with_renames);
+        if !plan.targets.is_empty() {
+            missing_ranges.retain(|(name, _)| plan.targets.contains(name));
         }
 
-        let mut rewritten = source.to_string();
+        let mut outcome = RewriteOutcome {
+            source: source.to_string(),
+            ..RewriteOutcome::default()
+        };
 
-        if !invalid_plan && !plan_map.is_empty() {
+        if !missing_ranges.is_empty() {
+            outcome.skipped = missing_ranges;
+        } else if !plan_map.is_empty() || !plan.module_renames.is_empty() {
             let suite = ast::Suite::parse(source, module_name)
-                .map_err(|err| TsrsError::ParseError(err.to_string()))?;
+                .map_err(|err| TsrsError::parse(source, &err))?;
+
+            let mut rewriter = FunctionRewriter::new(source, &plan_map, options);
+            rewriter.rename_module_privates(&suite, &plan.module_renames);
+            outcome = rewriter.rewrite(&suite)?;
+        }
+
+        if plan.targets.is_empty() && !options.keep_docstrings {
+            outcome.source = strip_docstrings(module_name, &outcome.source)?.0;
+        }
+        match Self::preserve_module_header(module_name, source, &outcome.source) {
+            Some(checked) => outcome.source = checked,
+            None => {
+                outcome = RewriteOutcome {
+                    source: source.to_string(),
+                    skipped: vec![(
+                        "<module>".to_string(),
+                        BailoutReason::ModuleHeaderViolation.to_string(),
+                    )],
+                    ..RewriteOutcome::default()
+                };
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+/// Result of applying a plan, listing which functions were renamed and which were skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RewriteOutcome {
+    /// Rewritten source (the original source when the rewrite was aborted).
+    pub source: String,
+    /// Qualified names of functions whose renames were applied.
+    pub applied: Vec<String>,
+    /// Qualified names of skipped functions paired with the reason they were skipped.
+    pub skipped: Vec<(String, String)>,
+    /// Size effect of the renames in each function listed in `applied`.
+    pub functions: Vec<FunctionRewrite>,
+    /// Qualified names of applied functions whose recorded range had drifted and was
+    /// re-resolved under [`RewriteOptions::fuzzy_ranges`].
+    pub fuzzy_matched: Vec<String>,
+    /// Bytes of the comments written under [`RewriteOptions::annotate_renames`]; the
+    /// sizes in `functions` leave them out.
+    pub annotation_bytes: usize,
+}
+
+/// Settings that change how a plan is applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RewriteOptions {
+    /// When a function's recorded range no longer matches its definition, e.g. because a
+    /// line was added above it after planning, apply its renames to the range of the one
+    /// definition with the same qualified name instead of bailing out. Functions defined
+    /// more than once under that name still bail out.
+    pub fuzzy_ranges: bool,
+    /// Leave docstrings in place; by default a whole-module rewrite strips them.
+    pub keep_docstrings: bool,
+    /// Add a `# tsrs: a=original, ...` comment listing each renamed function's renames
+    /// after its `def` line, or after its docstring, wrapped at this many columns. An
+    /// annotation already there is replaced rather than repeated.
+    pub annotate_renames: Option<usize>,
+}
+
+/// Settings for [`Minifier::rewrite_stream`].
+#[derive(Debug, Clone, Default)]
+pub struct StreamOptions {
+    /// How the plan is built.
+    pub plan: PlanOptions,
+    /// How the plan is applied.
+    pub rewrite: RewriteOptions,
+    /// Decode the input as this encoding instead of detecting it.
+    pub encoding: Option<&'static encoding_rs::Encoding>,
+    /// Replace undecodable bytes with U+FFFD and write UTF-8 instead of failing.
+    pub lossy: bool,
+}
+
+/// Result of [`Minifier::rewrite_stream`].
+#[derive(Debug, Clone)]
+pub struct RewriteSummary {
+    /// Functions renamed and skipped; `source` is the rewritten text before encoding.
+    pub outcome: RewriteOutcome,
+    /// How the input was stored, and so how the output was written.
+    pub metadata: TextMetadata,
+    /// Bytes read from the input.
+    pub bytes_read: usize,
+    /// Bytes written to the output.
+    pub bytes_written: usize,
+}
+
+/// Renames applied to one function by a rewrite.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionRewrite {
+    /// Fully-qualified function name.
+    pub qualified_name: String,
+    /// Identifier occurrences replaced in the function's own scope.
+    pub replacements: usize,
+    /// Length of the function's source range before the rewrite.
+    pub bytes_before: usize,
+    /// Length of the same range after renames there and in nested functions; docstring
+    /// removal is not counted.
+    pub bytes_after: usize,
+    /// Bytes of the rename comment added under [`RewriteOptions::annotate_renames`].
+    pub annotation_bytes: usize,
+}
+
+/// Result of removing unused imports from a module.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportCleanup {
+    /// Source without the unused imports (the original source when nothing was removed).
+    pub source: String,
+    /// Bound names whose imports were removed, in source order.
+    pub removed: Vec<String>,
+}
+
+/// Result of [`Minifier::remove_dead_assignments`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssignmentCleanup {
+    /// Source without the dead assignments (the original source when nothing was removed).
+    pub source: String,
+    /// Names whose assignments were removed, in source order.
+    pub removed: Vec<String>,
+}
 
-            let rewriter = FunctionRewriter::new(source, &plan_map);
-            rewritten = rewriter.rewrite(&suite)?;
+/// Reason a function could not be rewritten.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum BailoutReason {
+    /// The body contains a `match` statement; the whole file is left untouched.
+    MatchStatement,
+    /// The body contains a comprehension; the function is left untouched.
+    Comprehension,
+    /// The plan has no source range for the function.
+    MissingRange,
+    /// The body contains a lambda.
+    Lambda,
+    /// A definition or parameter name could not be located in the source text.
+    IdentifierNotFound,
+    /// An occurrence fell outside the function's source range.
+    OutOfRange,
+    /// The source text at an occurrence did not match the expected name.
+    SourceMismatch,
+    /// Replacements overlapped each other or another function's; the function is left
+    /// untouched.
+    OverlappingReplacements,
+    /// The rewrite would move the shebang, coding cookie, or `__future__` imports out of
+    /// their required place at the top of the module; the module is left untouched.
+    ModuleHeaderViolation,
+    /// The body calls `locals()` or `vars()` without arguments, which hands out every
+    /// local by name; the function is left untouched.
+    LocalsCall,
+}
+
+impl BailoutReason {
+    /// Stable snake_case label used in JSON output and stats.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BailoutReason::MatchStatement => "match_statement",
+            BailoutReason::Comprehension => "comprehension",
+            BailoutReason::MissingRange => "missing_range",
+            BailoutReason::Lambda => "lambda",
+            BailoutReason::IdentifierNotFound => "identifier_not_found",
+            BailoutReason::OutOfRange => "out_of_range",
+            BailoutReason::SourceMismatch => "source_mismatch",
+            BailoutReason::OverlappingReplacements => "overlapping_replacements",
+            BailoutReason::ModuleHeaderViolation => "module_header_violation",
+            BailoutReason::LocalsCall => "locals_call",
         }
+    }
+}
 
-        strip_docstrings(module_name, &rewritten)
+impl std::fmt::Display for BailoutReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
@@ -93,2570 +932,7761 @@ pub struct MinifyPlan {
     pub module: String,
     pub keywords: Vec<String>,
     pub functions: Vec<FunctionPlan>,
-}
-
-/// Rename mapping for a single function scope.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct FunctionPlan {
-    /// Fully-qualified function name (e.g. `module.Class.method`).
-    pub qualified_name: String,
-    /// Ordered list of original local names considered for renaming.
-    pub locals: Vec<String>,
-    /// Planned replacements paired with their original names.
-    pub renames: Vec<RenameEntry>,
-    /// Names encountered but excluded from renaming (globals, keywords, etc.).
-    #[serde(default)]
-    pub excluded: Vec<String>,
-    /// Optional function source range (byte offsets) to support rewriting.
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub range: Option<FunctionRange>,
-    /// Indicates whether nested function definitions exist inside this function.
-    pub has_nested_functions: bool,
-    /// Indicates if the function body contains import statements.
-    pub has_imports: bool,
+    /// Set when the module opts out of minification with `# tsrs: skip-file`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub skip_file: bool,
+    /// Strategy used to assign short names; plans without it were made sequentially.
     #[serde(default)]
-    pub has_match_statement: bool,
-    #[serde(default)]
-    pub has_comprehension: bool,
-    /// Nested function plans collected recursively.
-    #[serde(default)]
-    pub nested: Vec<FunctionPlan>,
+    pub naming: NamingMode,
+    /// Prefix and alphabet generated names are spelled with; plans without it use the
+    /// plain `a`..`z` scheme, and only a declared scheme is enforced by validation.
+    #[serde(default, skip_serializing_if = "NamingConfig::is_default")]
+    pub naming_config: NamingConfig,
+    /// Set when parameters were left untouched, as for `.pyi` stubs.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub keep_parameters: bool,
+    /// Module-wide renames of private module-level names, applied at every reference.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub module_renames: Vec<RenameEntry>,
+    /// Set when locals with an annotated assignment were left untouched.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub preserve_annotated_locals: bool,
+    /// Locals with fewer characters than this were left untouched; 0 and 1 mean none were.
+    #[serde(default, skip_serializing_if = "is_trivial_min_length")]
+    pub min_name_length: usize,
+    /// Set when locals bound and read only once were left untouched.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub skip_single_use: bool,
+    /// Set when locals such as `T = TypeVar("T")` were renamed together with the string
+    /// naming them instead of left untouched.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub rename_typevars: bool,
+    /// Globs that chose the planned functions; functions they filtered out have no plan.
+    #[serde(default, skip_serializing_if = "FunctionFilter::is_empty")]
+    pub function_filter: FunctionFilter,
+    /// Qualified names the plan was narrowed to with [`MinifyPlan::only_functions`]; when
+    /// set, everything outside them is left byte-identical, docstrings included.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
 }
 
-/// Mapping from an original identifier to a generated replacement.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct RenameEntry {
-    pub original: String,
-    pub renamed: String,
+/// Per-file settings that change how a plan is built.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlanOptions {
+    /// How replacement names are assigned.
+    pub naming: NamingMode,
+    /// How replacement names are spelled.
+    pub naming_config: NamingConfig,
+    /// Never rename parameters; stubs define an interface whose parameter names callers
+    /// may rely on as keywords.
+    pub keep_parameters: bool,
+    /// Also shorten `_`-prefixed module-level functions, classes, and constants.
+    pub rename_module_privates: bool,
+    /// Never rename locals assigned with an annotation (`name: T = ...`), whose names are
+    /// visible at runtime through `__annotations__` and `typing.get_type_hints()`.
+    pub preserve_annotated_locals: bool,
+    /// Never rename locals shorter than this many characters, where a rename saves
+    /// nothing; 0 and 1 rename every local.
+    pub min_name_length: usize,
+    /// Never rename locals that occur only twice (bound once, read once), whose rename
+    /// saves little and mostly adds diff noise.
+    pub skip_single_use: bool,
+    /// Rename locals bound to a call that spells their name, such as `T = TypeVar("T")`
+    /// or `Point = namedtuple("Point", ...)`, and the string with them. By default they
+    /// are left untouched, since typing introspection and frameworks read the string.
+    pub rename_typevars: bool,
+    /// Functions to plan; the others, and functions nested in them, get no plan.
+    pub function_filter: FunctionFilter,
+    /// Caps on the size of the plan; planning fails with [`TsrsError::PlanLimit`] as soon
+    /// as one is exceeded.
+    pub limits: PlanLimits,
 }
 
-/// Location of a function in the original source using byte offsets.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
-pub struct FunctionRange {
-    pub start: usize,
-    pub end: usize,
+/// Most renames and functions a module's plan may hold; `None` leaves either unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlanLimits {
+    /// Renames across every function of the module, module-level renames included.
+    pub max_renames: Option<usize>,
+    /// Planned functions, nested ones included.
+    pub max_functions: Option<usize>,
 }
 
-fn flatten_plan(mut plan: FunctionPlan, out: &mut Vec<FunctionPlan>) {
-    out.push(plan.clone());
-    for child in plan.nested.drain(..) {
-        flatten_plan(child, out);
+impl PlanLimits {
+    /// The limit `functions` planned functions with `renames` renames exceed, if any,
+    /// functions checked first.
+    #[must_use]
+    pub fn exceeded(&self, functions: usize, renames: usize) -> Option<PlanLimit> {
+        if self.max_functions.is_some_and(|max| functions > max) {
+            Some(PlanLimit::Functions)
+        } else if self.max_renames.is_some_and(|max| renames > max) {
+            Some(PlanLimit::Renames)
+        } else {
+            None
+        }
+    }
+
+    /// The limit an already built `plan` exceeds, if any.
+    #[must_use]
+    pub fn exceeded_by(&self, plan: &MinifyPlan) -> Option<PlanLimit> {
+        let renames = plan.module_renames.len()
+            + plan
+                .functions
+                .iter()
+                .map(|function| function.renames.len())
+                .sum::<usize>();
+        self.exceeded(plan.functions.len(), renames)
+    }
+
+    fn error(&self, limit: PlanLimit) -> TsrsError {
+        let max = match limit {
+            PlanLimit::Renames => self.max_renames,
+            PlanLimit::Functions => self.max_functions,
+        };
+        TsrsError::PlanLimit {
+            limit,
+            max: max.unwrap_or_default(),
+        }
     }
 }
 
-struct Planner {
-    module: String,
-    roots: Vec<FunctionPlan>,
+/// Shell-style globs choosing which functions are planned, matched against the
+/// module-qualified name such as `pkg.models.User.save`
+///
+/// `*` matches any run of characters, dots included, `?` any one character, and `[...]`
+/// one character of a set (`[!...]` negates it).
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct FunctionFilter {
+    /// Only functions matching one of these are planned; empty plans every function.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Functions matching one of these are never planned, even when included.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
 }
 
-impl Planner {
-    fn new(module: String) -> Self {
-        Self {
-            module,
-            roots: Vec::new(),
+impl FunctionFilter {
+    /// A filter over checked globs.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TsrsError::ConfigError`] for a glob with an unclosed `[`.
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Result<Self> {
+        if let Some(pattern) = include
+            .iter()
+            .chain(&exclude)
+            .find(|pattern| !is_valid_glob(pattern))
+        {
+            return Err(TsrsError::ConfigError(format!(
+                "function glob `{pattern}` has an unclosed `[`"
+            )));
         }
+        Ok(Self { include, exclude })
     }
 
-    fn finish(self) -> MinifyPlan {
-        let mut functions = Vec::new();
-        for plan in self.roots {
-            flatten_plan(plan, &mut functions);
-        }
+    /// Whether every function is planned.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
 
-        MinifyPlan {
-            module: self.module,
-            keywords: PYTHON_KEYWORDS
-                .iter()
-                .map(std::string::ToString::to_string)
-                .collect(),
-            functions,
+    /// Whether the function with module-qualified name `name` is planned.
+    #[must_use]
+    pub fn selects(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob_matches(glob, name)))
+            && !self.exclude.iter().any(|glob| glob_matches(glob, name))
+    }
+}
+
+/// Whether every `[` in `pattern` opens a closed character class.
+fn is_valid_glob(pattern: &str) -> bool {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '[' {
+            match class_end(&chars[i..]) {
+                Some(end) => i += end + 1,
+                None => return false,
+            }
+        } else {
+            i += 1;
         }
     }
+    true
+}
 
-    fn visit_suite(&mut self, suite: &[ast::Stmt], path: &mut Vec<String>) {
-        for stmt in suite {
-            match stmt {
-                ast::Stmt::FunctionDef(func) => {
-                    let range = Some(range_from_node(func));
-                    let plan =
-                        self.plan_function(&func.name, &func.args, &func.body, path, None, range);
-                    self.roots.push(plan);
-                }
-                ast::Stmt::AsyncFunctionDef(func) => {
-                    let range = Some(range_from_node(func));
-                    let plan =
-                        self.plan_function(&func.name, &func.args, &func.body, path, None, range);
-                    self.roots.push(plan);
-                }
-                ast::Stmt::ClassDef(class_def) => {
-                    let class_plans = self.visit_class_collect(class_def, path);
-                    self.roots.extend(class_plans);
+/// Whether `text` matches the shell-style glob `pattern`; see [`FunctionFilter`].
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and of the text it currently stops at, to retry from.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(_) => {
+                if let Some(len) = match_one(&pattern[p..], text[t]) {
+                    p += len;
+                    t += 1;
+                    continue;
                 }
-                _ => {}
             }
+            None => {}
         }
+        let Some((star, start)) = backtrack else {
+            return false;
+        };
+        backtrack = Some((star, start + 1));
+        p = star + 1;
+        t = start + 1;
     }
+    pattern[p..].iter().all(|c| *c == '*')
+}
 
-    fn visit_class_collect(
-        &mut self,
-        class_def: &ast::StmtClassDef,
-        path: &mut Vec<String>,
-    ) -> Vec<FunctionPlan> {
-        path.push(class_def.name.to_string());
-        let mut plans = Vec::new();
-        for stmt in &class_def.body {
-            match stmt {
-                ast::Stmt::FunctionDef(func) => {
-                    let range = Some(range_from_node(func));
-                    let plan =
-                        self.plan_function(&func.name, &func.args, &func.body, path, None, range);
-                    plans.push(plan);
-                }
-                ast::Stmt::AsyncFunctionDef(func) => {
-                    let range = Some(range_from_node(func));
-                    let plan =
-                        self.plan_function(&func.name, &func.args, &func.body, path, None, range);
-                    plans.push(plan);
-                }
-                ast::Stmt::ClassDef(inner) => {
-                    let inner_plans = self.visit_class_collect(inner, path);
-                    plans.extend(inner_plans);
+/// Length of the glob token opening `pattern` when it matches `c`.
+fn match_one(pattern: &[char], c: char) -> Option<usize> {
+    match pattern[0] {
+        '?' => Some(1),
+        '[' => {
+            let end = class_end(pattern)?;
+            let mut set = &pattern[1..end];
+            let negated = matches!(set.first(), Some('!' | '^'));
+            if negated {
+                set = &set[1..];
+            }
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    found |= (set[i]..=set[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
                 }
-                _ => {}
             }
+            (found != negated).then_some(end + 1)
         }
-        path.pop();
-        plans
+        literal => (literal == c).then_some(1),
     }
+}
 
-    fn plan_function(
-        &mut self,
-        name: &ast::Identifier,
-        args: &ast::Arguments,
-        body: &[ast::Stmt],
-        path: &mut Vec<String>,
-        parent_collector: Option<&mut FunctionCollector>,
-        range: Option<FunctionRange>,
-    ) -> FunctionPlan {
-        let name_str = name.to_string();
-        if let Some(collector) = parent_collector {
-            collector.reserve_name(&name_str);
-            collector.mark_nested_function();
+/// Index of the `]` closing the class that opens `pattern`; a `]` first in the class is
+/// a member, as in `[]a]`.
+fn class_end(pattern: &[char]) -> Option<usize> {
+    let mut i = 1;
+    if matches!(pattern.get(i), Some('!' | '^')) {
+        i += 1;
+    }
+    if pattern.get(i) == Some(&']') {
+        i += 1;
+    }
+    pattern
+        .get(i..)?
+        .iter()
+        .position(|c| *c == ']')
+        .map(|offset| i + offset)
+}
+
+/// How replacement names are chosen for a function's locals.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum NamingMode {
+    /// `a`, `b`, `c`, ... in discovery order.
+    #[default]
+    Sequential,
+    /// Derived from a hash of the qualified function name and the original name, so
+    /// adding or removing one local leaves the other replacements unchanged.
+    Stable,
+}
+
+/// Characters generated names are spelled with unless another alphabet is configured.
+pub const DEFAULT_RENAME_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz";
+
+fn default_rename_alphabet() -> String {
+    DEFAULT_RENAME_ALPHABET.to_string()
+}
+
+/// How generated names are spelled: `prefix` followed by a word over `alphabet`, counted
+/// like `a`, `b`, ..., `z`, `aa`, `ab`, ... for the default alphabet.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct NamingConfig {
+    /// Put in front of every generated name, e.g. `_t` for `_ta`, `_tb`, ...
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    /// Characters of the word after the prefix, in counting order.
+    #[serde(default = "default_rename_alphabet")]
+    pub alphabet: String,
+}
+
+impl Default for NamingConfig {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            alphabet: default_rename_alphabet(),
         }
+    }
+}
 
-        path.push(name_str);
-        let qualified_name = path.join(".");
+impl NamingConfig {
+    /// A checked naming scheme; `None` keeps the default prefix or alphabet.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TsrsError::ConfigError`] if the scheme fails [`NamingConfig::validate`].
+    pub fn new(prefix: Option<&str>, alphabet: Option<&str>) -> Result<Self> {
+        let config = Self {
+            prefix: prefix.unwrap_or_default().to_string(),
+            alphabet: alphabet.map_or_else(default_rename_alphabet, str::to_string),
+        };
+        config.validate()?;
+        Ok(config)
+    }
 
-        let mut reserved = default_reserved();
-        let (globals, nonlocals) = collect_declared_names(body);
-        for name in globals.iter().chain(nonlocals.iter()) {
-            reserved.insert(name.clone());
+    /// Check that every name of the scheme is a plain ASCII identifier.
+    ///
+    /// The prefix may hold letters, digits, and underscores but must not start with a
+    /// digit or with `__`, which Python mangles inside class bodies. The alphabet holds
+    /// distinct letters and digits, and digits only behind a prefix.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TsrsError::ConfigError`] describing the first rule the scheme breaks.
+    pub fn validate(&self) -> Result<()> {
+        let (prefix, alphabet) = (self.prefix.as_str(), self.alphabet.as_str());
+        let detail = if let Some(c) = prefix
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || *c == '_'))
+        {
+            format!("rename prefix `{prefix}` contains `{c}`, which cannot appear in a Python identifier")
+        } else if prefix.starts_with(|c: char| c.is_ascii_digit()) {
+            format!("rename prefix `{prefix}` starts with a digit")
+        } else if prefix.starts_with("__") {
+            format!(
+                "rename prefix `{prefix}` starts with `__`, which Python mangles inside classes"
+            )
+        } else if alphabet.is_empty() {
+            "rename alphabet is empty".to_string()
+        } else if let Some(c) = alphabet.chars().find(|c| !c.is_ascii_alphanumeric()) {
+            format!("rename alphabet `{alphabet}` contains `{c}`; use ASCII letters and digits")
+        } else if let Some(c) = alphabet
+            .char_indices()
+            .find(|&(index, c)| alphabet[..index].contains(c))
+            .map(|(_, c)| c)
+        {
+            format!("rename alphabet `{alphabet}` repeats `{c}`")
+        } else if prefix.is_empty() && alphabet.chars().any(|c| c.is_ascii_digit()) {
+            format!("rename alphabet `{alphabet}` contains digits, which need a rename prefix")
+        } else {
+            return Ok(());
+        };
+        Err(TsrsError::ConfigError(detail))
+    }
+
+    /// Whether this is the plain `a`..`z` scheme without a prefix.
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether `name` is spelled the way this scheme spells generated names.
+    #[must_use]
+    pub fn matches(&self, name: &str) -> bool {
+        name.strip_prefix(self.prefix.as_str())
+            .is_some_and(|word| !word.is_empty() && word.chars().all(|c| self.alphabet.contains(c)))
+    }
+
+    /// Why a name does not match the scheme.
+    fn mismatch(&self) -> String {
+        if self.prefix.is_empty() {
+            format!("not spelled with the plan's alphabet `{}`", self.alphabet)
+        } else {
+            format!(
+                "not the plan's prefix `{}` followed by characters of `{}`",
+                self.prefix, self.alphabet
+            )
         }
+    }
 
-        let mut collector = FunctionCollector::new(reserved);
-        collector.collect_parameters(args);
-        collector.record_exclusions(globals.into_iter());
-        collector.record_exclusions(nonlocals.into_iter());
+    /// The `value`th name of the scheme.
+    fn name(&self, value: usize) -> String {
+        format!(
+            "{}{}",
+            self.prefix,
+            encode_identifier(value, self.alphabet.as_bytes())
+        )
+    }
 
-        let nested = self.collect_in_function(&mut collector, body, path);
+    /// Number of names with one- or two-character words, used as the stable-name space.
+    fn stable_space(&self) -> usize {
+        let base = self.alphabet.len();
+        base + base * base
+    }
 
-        let mut plan = collector.into_plan(qualified_name, range);
-        plan.nested = nested;
+    /// The scheme of module-level renames, which must keep a single leading `_`.
+    fn module_private(&self) -> Self {
+        if self.prefix.starts_with('_') {
+            self.clone()
+        } else {
+            Self {
+                prefix: format!("_{}", self.prefix),
+                alphabet: self.alphabet.clone(),
+            }
+        }
+    }
+}
 
-        path.pop();
-        plan
+impl MinifyPlan {
+    /// Bytes the renames would save: each rename's length difference times its
+    /// `occurrences`, over every function and module-level rename.
+    #[must_use]
+    pub fn estimated_rename_savings(&self) -> usize {
+        self.functions
+            .iter()
+            .flat_map(|function| &function.renames)
+            .chain(&self.module_renames)
+            .map(|entry| {
+                entry.original.len().saturating_sub(entry.renamed.len()) * entry.occurrences
+            })
+            .sum()
     }
 
-    #[allow(clippy::too_many_lines)]
-    fn collect_in_function(
-        &mut self,
-        collector: &mut FunctionCollector,
-        body: &[ast::Stmt],
-        path: &mut Vec<String>,
-    ) -> Vec<FunctionPlan> {
-        let mut nested_plans = Vec::new();
-        for stmt in body {
-            match stmt {
-                ast::Stmt::FunctionDef(func) => {
-                    let captured = collect_used_names_in_function(func, 0);
-                    for name in captured {
-                        collector.reserve_name(&name);
-                    }
-                    let range = Some(range_from_node(func));
-                    let plan = self.plan_function(
-                        &func.name,
-                        &func.args,
-                        &func.body,
-                        path,
-                        Some(collector),
-                        range,
-                    );
-                    nested_plans.push(plan);
-                }
-                ast::Stmt::AsyncFunctionDef(func) => {
-                    let captured = collect_used_names_in_async_function(func, 0);
-                    for name in captured {
-                        collector.reserve_name(&name);
-                    }
-                    let range = Some(range_from_node(func));
-                    let plan = self.plan_function(
-                        &func.name,
-                        &func.args,
-                        &func.body,
-                        path,
-                        Some(collector),
-                        range,
-                    );
-                    nested_plans.push(plan);
-                }
-                ast::Stmt::ClassDef(class_def) => {
-                    let captured = collect_used_names_in_class(class_def, 1);
-                    for name in captured {
-                        collector.reserve_name(&name);
-                    }
-                    let class_name = class_def.name.to_string();
-                    collector.reserve_name(&class_name);
-                    collector.mark_nested_function();
-                    let class_plans = self.visit_class_collect(class_def, path);
-                    nested_plans.extend(class_plans);
-                }
-                ast::Stmt::Assign(assign) => {
-                    for target in &assign.targets {
-                        collector.add_names_from_expr(target);
-                    }
-                    collector.collect_from_expression(&assign.value);
-                }
-                ast::Stmt::AnnAssign(assign) => {
-                    collector.add_names_from_expr(&assign.target);
-                    if let Some(value) = &assign.value {
-                        collector.collect_from_expression(value);
-                    }
+    /// Keep only the renames of the functions in `names` and of functions nested in them.
+    ///
+    /// Other functions stay in the plan without renames, module-level renames are dropped,
+    /// and docstrings are no longer stripped, so a rewrite changes nothing outside the
+    /// targets. Names may carry the `module.` prefix.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TsrsError::UnknownFunction`] for the first name matching no function.
+    pub fn only_functions<S: AsRef<str>>(mut self, names: &[S]) -> Result<Self> {
+        let prefix = format!("{}.", self.module);
+        let mut targets = Vec::with_capacity(names.len());
+        for name in names {
+            let name = name.as_ref();
+            let known = |candidate: &str| {
+                self.functions
+                    .iter()
+                    .any(|function| function.qualified_name == candidate)
+            };
+            let resolved = match name.strip_prefix(&prefix) {
+                _ if known(name) => name,
+                Some(stripped) if known(stripped) => stripped,
+                _ => {
+                    return Err(TsrsError::UnknownFunction {
+                        name: name.to_string(),
+                        available: self
+                            .functions
+                            .iter()
+                            .map(|function| function.qualified_name.clone())
+                            .collect(),
+                    })
                 }
-                ast::Stmt::AugAssign(assign) => {
-                    collector.add_names_from_expr(&assign.target);
-                    collector.collect_from_expression(&assign.value);
+            };
+            targets.push(resolved.to_string());
+        }
+
+        // A target without `#N` covers every definition of its name
+        let targeted = |qualified_name: &str| {
+            targets.iter().any(|target| {
+                [qualified_name, base_qualified_name(qualified_name)]
+                    .into_iter()
+                    .any(|name| {
+                        name == target.as_str()
+                            || name
+                                .strip_prefix(target.as_str())
+                                .is_some_and(|rest| rest.starts_with('.'))
+                    })
+            })
+        };
+        let mut pending: Vec<&mut FunctionPlan> = self.functions.iter_mut().collect();
+        while let Some(function) = pending.pop() {
+            if !targeted(&function.qualified_name) {
+                function.renames.clear();
+            }
+            pending.extend(function.nested.iter_mut());
+        }
+        self.module_renames.clear();
+        self.targets = targets;
+        Ok(self)
+    }
+
+    /// Check every rename against the function it belongs to in `source`.
+    ///
+    /// An `original` must be one of the function's `locals` or occur within its range, and a
+    /// `renamed` value must be an identifier that is not a keyword, an excluded name, a name
+    /// the function already uses and keeps, or another rename's target. When the plan declares
+    /// a `naming_config`, every `renamed` value must also be spelled the way it describes.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TsrsError::InvalidPlan`] describing the first rename that breaks a rule.
+    pub fn validate(&self, source: &str) -> Result<()> {
+        if let Some((_, detail)) = self.invalid_module_renames(source).into_iter().next() {
+            return Err(TsrsError::InvalidPlan {
+                function: self.module.clone(),
+                detail,
+            });
+        }
+        let names = spelled_names(source);
+        for function in &self.functions {
+            if let Some((_, detail)) =
+                invalid_renames(function, source, &names, &self.naming_config)
+                    .into_iter()
+                    .next()
+            {
+                return Err(TsrsError::InvalidPlan {
+                    function: function.qualified_name.clone(),
+                    detail,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the renames [`MinifyPlan::validate`] rejects, returning an error for each.
+    pub fn prune_invalid_renames(&mut self, source: &str) -> Vec<TsrsError> {
+        let names = spelled_names(source);
+        let mut dropped = Vec::new();
+        let invalid = self.invalid_module_renames(source);
+        if !invalid.is_empty() {
+            let mut index = 0;
+            self.module_renames.retain(|_| {
+                let keep = !invalid.iter().any(|(invalid, _)| *invalid == index);
+                index += 1;
+                keep
+            });
+            dropped.extend(
+                invalid
+                    .into_iter()
+                    .map(|(_, detail)| TsrsError::InvalidPlan {
+                        function: self.module.clone(),
+                        detail,
+                    }),
+            );
+        }
+        for function in &mut self.functions {
+            let invalid = invalid_renames(function, source, &names, &self.naming_config);
+            if invalid.is_empty() {
+                continue;
+            }
+            let mut index = 0;
+            function.renames.retain(|_| {
+                let keep = !invalid.iter().any(|(invalid, _)| *invalid == index);
+                index += 1;
+                keep
+            });
+            dropped.extend(
+                invalid
+                    .into_iter()
+                    .map(|(_, detail)| TsrsError::InvalidPlan {
+                        function: function.qualified_name.clone(),
+                        detail,
+                    }),
+            );
+        }
+        dropped
+    }
+
+    /// Module renames that cannot be applied, by index, with the reason for each: the
+    /// target must be a fresh identifier, not a keyword or a name already in `source`.
+    fn invalid_module_renames(&self, source: &str) -> Vec<(usize, String)> {
+        let whole = FunctionRange {
+            start: 0,
+            end: source.len(),
+            ..FunctionRange::default()
+        };
+        let scheme = self.naming_config.module_private();
+        let mut targets: HashMap<&str, &str> = HashMap::new();
+        let mut invalid = Vec::new();
+
+        for (index, entry) in self.module_renames.iter().enumerate() {
+            let (original, renamed) = (entry.original.as_str(), entry.renamed.as_str());
+            let detail = if !is_valid_identifier(renamed) {
+                format!("`{original}` → `{renamed}`: not a valid identifier")
+            } else if PYTHON_KEYWORDS.contains(&renamed) {
+                format!("`{original}` → `{renamed}`: `{renamed}` is a keyword")
+            } else if !self.naming_config.is_default() && !scheme.matches(renamed) {
+                format!("`{original}` → `{renamed}`: {}", scheme.mismatch())
+            } else if find_identifier_in_range(source, &whole, renamed).is_some() {
+                format!("`{original}` → `{renamed}`: `{renamed}` already appears in the module")
+            } else if let Some(other) = targets.get(renamed) {
+                format!(
+                    "`{original}` → `{renamed}`: `{renamed}` is already the target for `{other}`"
+                )
+            } else {
+                targets.insert(renamed, original);
+                continue;
+            };
+            invalid.push((index, detail));
+        }
+
+        invalid
+    }
+
+    /// Places where a generated name also appears inside its function as an attribute, a
+    /// keyword argument, or a word in a string literal.
+    ///
+    /// The rewrite leaves those occurrences alone, so the output is still correct, but a
+    /// reader (or a `getattr` built from a string) can mistake them for the renamed local.
+    pub fn name_collisions(&self, source: &str) -> Vec<NameCollision> {
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        let sites = collision_sites(source);
+        let mut seen = HashSet::new();
+        let mut collisions = Vec::new();
+        let mut pending: Vec<&FunctionPlan> = self.functions.iter().rev().collect();
+
+        while let Some(function) = pending.pop() {
+            pending.extend(function.nested.iter().rev());
+            let Some(range) = function.range else {
+                continue;
+            };
+            let first = sites.partition_point(|(offset, _, _)| *offset < range.start);
+            for (offset, kind, word) in sites[first..]
+                .iter()
+                .take_while(|(offset, _, _)| *offset < range.end)
+            {
+                let Some(entry) = function.renames.iter().find(|entry| entry.renamed == *word)
+                else {
+                    continue;
+                };
+                let (line, _) = line_column(source, &line_starts, *offset);
+                if seen.insert((function.qualified_name.as_str(), word.as_str(), *kind, line)) {
+                    collisions.push(NameCollision {
+                        function: function.qualified_name.clone(),
+                        name: entry.renamed.clone(),
+                        original: entry.original.clone(),
+                        kind: *kind,
+                        line,
+                    });
                 }
-                ast::Stmt::For(for_stmt) => {
-                    collector.add_names_from_expr(&for_stmt.target);
-                    self.collect_in_function(collector, &for_stmt.body, path);
-                    self.collect_in_function(collector, &for_stmt.orelse, path);
+            }
+        }
+
+        collisions
+    }
+}
+
+/// Where a generated name was found alongside the local it replaces.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionKind {
+    /// `obj.name`
+    Attribute,
+    /// `call(name=...)`
+    KeywordArgument,
+    /// A word inside a string literal, e.g. `getattr(obj, "name")`.
+    StringLiteral,
+}
+
+impl CollisionKind {
+    /// Label used in warnings.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CollisionKind::Attribute => "attribute",
+            CollisionKind::KeywordArgument => "keyword argument",
+            CollisionKind::StringLiteral => "string literal",
+        }
+    }
+}
+
+impl std::fmt::Display for CollisionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A generated short name that also appears, unrenamed, in the function it was made for.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NameCollision {
+    /// Qualified name of the function.
+    pub function: String,
+    /// The generated name.
+    pub name: String,
+    /// The local it replaces.
+    pub original: String,
+    pub kind: CollisionKind,
+    /// 1-based line of the occurrence.
+    pub line: usize,
+}
+
+/// Attribute names, keyword-argument names, and string-literal words in `source`, by byte
+/// offset in ascending order.
+fn collision_sites(source: &str) -> Vec<(usize, CollisionKind, String)> {
+    let tokens: Vec<_> = lexer::lex(source, Mode::Module)
+        .map_while(|result| result.ok())
+        .collect();
+    let mut brackets = Vec::new();
+    let mut sites = Vec::new();
+
+    for (index, (token, range)) in tokens.iter().enumerate() {
+        let start = usize::from(range.start());
+        let next = tokens.get(index + 1).map(|(token, _)| token);
+        match token {
+            Tok::Lpar | Tok::Lsqb | Tok::Lbrace => brackets.push(token),
+            Tok::Rpar | Tok::Rsqb | Tok::Rbrace => {
+                brackets.pop();
+            }
+            Tok::Dot => {
+                if let Some((Tok::Name { name }, range)) = tokens.get(index + 1) {
+                    let offset = usize::from(range.start());
+                    sites.push((offset, CollisionKind::Attribute, name.clone()));
                 }
-                ast::Stmt::AsyncFor(for_stmt) => {
-                    collector.add_names_from_expr(&for_stmt.target);
-                    self.collect_in_function(collector, &for_stmt.body, path);
-                    self.collect_in_function(collector, &for_stmt.orelse, path);
-                }
-                ast::Stmt::While(while_stmt) => {
-                    self.collect_in_function(collector, &while_stmt.body, path);
-                    self.collect_in_function(collector, &while_stmt.orelse, path);
-                }
-                ast::Stmt::If(if_stmt) => {
-                    self.collect_in_function(collector, &if_stmt.body, path);
-                    self.collect_in_function(collector, &if_stmt.orelse, path);
-                }
-                ast::Stmt::With(with_stmt) => {
-                    for item in &with_stmt.items {
-                        if let Some(optional) = &item.optional_vars {
-                            collector.add_names_from_expr(optional);
-                        }
-                    }
-                    self.collect_in_function(collector, &with_stmt.body, path);
-                }
-                ast::Stmt::AsyncWith(with_stmt) => {
-                    for item in &with_stmt.items {
-                        if let Some(optional) = &item.optional_vars {
-                            collector.add_names_from_expr(optional);
-                        }
-                    }
-                    self.collect_in_function(collector, &with_stmt.body, path);
-                }
-                ast::Stmt::Try(try_stmt) => {
-                    self.collect_in_function(collector, &try_stmt.body, path);
-                    self.collect_in_function(collector, &try_stmt.orelse, path);
-                    self.collect_in_function(collector, &try_stmt.finalbody, path);
-                    for handler in &try_stmt.handlers {
-                        let ast::ExceptHandler::ExceptHandler(handler) = handler;
-                        if let Some(name) = &handler.name {
-                            collector.add_name(name.as_ref());
-                        }
-                        self.collect_in_function(collector, &handler.body, path);
-                    }
-                }
-                ast::Stmt::TryStar(try_stmt) => {
-                    self.collect_in_function(collector, &try_stmt.body, path);
-                    self.collect_in_function(collector, &try_stmt.orelse, path);
-                    self.collect_in_function(collector, &try_stmt.finalbody, path);
-                    for handler in &try_stmt.handlers {
-                        let ast::ExceptHandler::ExceptHandler(handler) = handler;
-                        if let Some(name) = &handler.name {
-                            collector.add_name(name.as_ref());
-                        }
-                        self.collect_in_function(collector, &handler.body, path);
-                    }
-                }
-                ast::Stmt::Return(ret) => {
-                    if let Some(value) = &ret.value {
-                        collector.collect_from_expression(value);
-                    }
-                }
-                ast::Stmt::Match(match_stmt) => {
-                    collector.has_match_statement = true;
-                    for case in &match_stmt.cases {
-                        collector.add_names_from_pattern(&case.pattern);
-                        if let Some(guard) = &case.guard {
-                            collector.collect_from_expression(guard);
-                        }
-                        self.collect_in_function(collector, &case.body, path);
-                    }
-                }
-                ast::Stmt::Import(import_stmt) => {
-                    collector.mark_import();
-                    for alias in &import_stmt.names {
-                        if let Some(asname) = &alias.asname {
-                            collector.reserve_name(asname.as_ref());
-                        } else {
-                            let module = alias.name.to_string();
-                            let base = module.split('.').next().unwrap_or(&module);
-                            if module.contains('.') {
-                                collector.reserve_name(base);
-                            } else {
-                                collector.add_name(base);
-                            }
-                        }
-                    }
-                }
-                ast::Stmt::ImportFrom(import_from) => {
-                    collector.mark_import();
-                    for alias in &import_from.names {
-                        let name = alias.name.to_string();
-                        if name == "*" {
-                            continue;
-                        }
-                        if let Some(asname) = &alias.asname {
-                            collector.reserve_name(asname.as_ref());
-                        } else {
-                            let base = name.split('.').next().unwrap_or(&name);
-                            collector.add_name(base);
-                        }
-                    }
-                }
-                ast::Stmt::Expr(expr_stmt) => {
-                    collector.collect_from_expression(&expr_stmt.value);
-                }
-                _ => {}
             }
+            Tok::Name { name }
+                if matches!(next, Some(Tok::Equal))
+                    && matches!(brackets.last(), Some(Tok::Lpar)) =>
+            {
+                sites.push((start, CollisionKind::KeywordArgument, name.clone()));
+            }
+            Tok::String { value, .. } => {
+                sites.extend(
+                    identifiers_in_text(value)
+                        .map(|word| (start, CollisionKind::StringLiteral, word.to_string())),
+                );
+            }
+            _ => {}
         }
-        nested_plans
     }
+
+    sites
 }
 
-fn collect_declared_names(body: &[ast::Stmt]) -> (HashSet<String>, HashSet<String>) {
-    let mut globals = HashSet::new();
-    let mut nonlocals = HashSet::new();
+/// Renames in `function` that cannot be applied, by index, with the reason for each.
+///
+/// `names` holds the [`spelled_names`] of `source`.
+fn invalid_renames(
+    function: &FunctionPlan,
+    source: &str,
+    names: &[(usize, String)],
+    naming_config: &NamingConfig,
+) -> Vec<(usize, String)> {
+    let excluded: HashMap<Cow<str>, &str> = function
+        .excluded
+        .iter()
+        .map(|name| (normalize_identifier(name), name.as_str()))
+        .collect();
+    let originals: HashSet<&str> = function
+        .renames
+        .iter()
+        .map(|entry| entry.original.as_str())
+        .collect();
+    let kept = |name: &str| {
+        let folded = normalize_identifier(name);
+        let same = |other: &str| normalize_identifier(other) == folded;
+        !originals.contains(name)
+            && (function.locals.iter().any(|local| same(local))
+                || function.range.is_some_and(|range| {
+                    names.iter().any(|(offset, spelled)| {
+                        (range.start..range.end).contains(offset) && same(spelled)
+                    })
+                }))
+    };
+    let mut targets: HashMap<&str, &str> = HashMap::new();
+    let mut invalid = Vec::new();
+
+    for (index, entry) in function.renames.iter().enumerate() {
+        let (original, renamed) = (entry.original.as_str(), entry.renamed.as_str());
+        let in_function = function.locals.iter().any(|local| local == original)
+            || function
+                .range
+                .is_some_and(|range| find_identifier_in_range(source, &range, original).is_some());
+        let detail = if !in_function {
+            format!("`{original}` is not a local of this function")
+        } else if !is_valid_identifier(renamed) {
+            format!("`{original}` → `{renamed}`: not a valid identifier")
+        } else if PYTHON_KEYWORDS.contains(&renamed) {
+            format!("`{original}` → `{renamed}`: `{renamed}` is a keyword")
+        } else if !naming_config.is_default() && !naming_config.matches(renamed) {
+            format!("`{original}` → `{renamed}`: {}", naming_config.mismatch())
+        } else if let Some(name) = excluded.get(&*normalize_identifier(renamed)) {
+            format!("`{original}` → `{renamed}`: collides with excluded name `{name}`")
+        } else if kept(renamed) {
+            format!("`{original}` → `{renamed}`: `{renamed}` is already used in this function")
+        } else if let Some(other) = targets.get(renamed) {
+            format!("`{original}` → `{renamed}`: `{renamed}` is already the target for `{other}`")
+        } else {
+            targets.insert(renamed, original);
+            continue;
+        };
+        invalid.push((index, detail));
+    }
 
-    for stmt in body {
-        match stmt {
-            ast::Stmt::Global(glob) => {
-                for name in &glob.names {
-                    globals.insert(name.to_string());
-                }
-            }
-            ast::Stmt::Nonlocal(non) => {
-                for name in &non.names {
-                    nonlocals.insert(name.to_string());
-                }
-            }
-            _ => {}
+    invalid
+}
+
+/// Offsets of the names spelled in `source`, leaving out attribute names and keyword
+/// argument names, which a rename cannot shadow.
+fn spelled_names(source: &str) -> Vec<(usize, String)> {
+    let tokens: Vec<_> = lexer::lex(source, Mode::Module)
+        .map_while(|result| result.ok())
+        .collect();
+    tokens
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (token, range))| {
+            let Tok::Name { name } = token else {
+                return None;
+            };
+            let attribute = index > 0 && matches!(tokens[index - 1].0, Tok::Dot);
+            let keyword = matches!(tokens.get(index + 1), Some((Tok::Equal, _)));
+            (!attribute && !keyword).then(|| (usize::from(range.start()), name.clone()))
+        })
+        .collect()
+}
+
+fn is_valid_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first == '_' || first.is_alphabetic())
+        && chars.all(|c| c == '_' || c.is_alphanumeric())
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_trivial_min_length(value: &usize) -> bool {
+    *value <= 1
+}
+
+/// Totals over a set of plans, such as the entries of a plan bundle
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanSummary {
+    pub files: usize,
+    /// Files opted out with `# tsrs: skip-file`.
+    pub skipped_files: usize,
+    pub functions: usize,
+    /// Planned function-local renames.
+    pub renames: usize,
+    /// Planned renames of private module-level names.
+    pub module_renames: usize,
+    /// Functions flagged `has_match_statement`, which the rewrite skips.
+    pub with_match_statement: usize,
+    /// Functions flagged `has_comprehension`, which the rewrite skips.
+    pub with_comprehension: usize,
+    /// Functions flagged `has_nested_functions`, which the rewrite skips.
+    pub with_nested_functions: usize,
+}
+
+impl PlanSummary {
+    /// Add one file's plan to the totals.
+    pub fn add(&mut self, plan: &MinifyPlan) {
+        self.files += 1;
+        self.skipped_files += usize::from(plan.skip_file);
+        self.module_renames += plan.module_renames.len();
+        for function in &plan.functions {
+            self.functions += 1;
+            self.renames += function.renames.len();
+            self.with_match_statement += usize::from(function.has_match_statement);
+            self.with_comprehension += usize::from(function.has_comprehension);
+            self.with_nested_functions += usize::from(function.has_nested_functions);
         }
     }
+}
 
-    (globals, nonlocals)
+/// Rename mapping for a single function scope.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FunctionPlan {
+    /// Fully-qualified function name (e.g. `module.Class.method`). A name defined more than
+    /// once in the module, such as in both branches of an `if`, gets `#2`, `#3`, ... on
+    /// its later definitions.
+    pub qualified_name: String,
+    /// Ordered list of original local names considered for renaming.
+    pub locals: Vec<String>,
+    /// Planned replacements paired with their original names.
+    pub renames: Vec<RenameEntry>,
+    /// Names encountered but excluded from renaming (globals, keywords, etc.).
+    #[serde(default)]
+    pub excluded: Vec<String>,
+    /// Optional function source range (byte offsets) to support rewriting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<FunctionRange>,
+    /// Indicates whether nested function definitions exist inside this function.
+    pub has_nested_functions: bool,
+    /// Indicates if the function body contains import statements.
+    pub has_imports: bool,
+    #[serde(default)]
+    pub has_match_statement: bool,
+    #[serde(default)]
+    pub has_comprehension: bool,
+    /// Why the rewriter will skip this function, if known at planning time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bailout_reason: Option<BailoutReason>,
+    /// Patterns the planner left alone that may still need a look, e.g. a `namedtuple`
+    /// bound to a variable of another name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Nested function plans collected recursively.
+    #[serde(default)]
+    pub nested: Vec<FunctionPlan>,
 }
 
-fn default_reserved() -> HashSet<String> {
-    let mut reserved: HashSet<String> = PYTHON_KEYWORDS
+/// Mapping from an original identifier to a generated replacement.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RenameEntry {
+    pub original: String,
+    pub renamed: String,
+    /// Identifier occurrences the rewrite replaces, counted while planning. Zero when the
+    /// rewriter would skip the function, or for plans written before the count existed.
+    #[serde(default)]
+    pub occurrences: usize,
+}
+
+/// Location of a function in the original source.
+///
+/// `start`/`end` are byte offsets and drive the rewrite; the 1-based line and column
+/// (counted in characters) are for reporting. `end_line`/`end_col` point just past the
+/// last character. Positions are zero when a range was not resolved against its source.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FunctionRange {
+    pub start: usize,
+    pub end: usize,
+    #[serde(default)]
+    pub start_line: usize,
+    #[serde(default)]
+    pub start_col: usize,
+    #[serde(default)]
+    pub end_line: usize,
+    #[serde(default)]
+    pub end_col: usize,
+}
+
+impl FunctionRange {
+    /// Fill in line and column positions using the line start offsets of `source`.
+    fn located(self, source: &str, line_starts: &[usize]) -> Self {
+        let (start_line, start_col) = line_column(source, line_starts, self.start);
+        let (end_line, end_col) = line_column(source, line_starts, self.end);
+        FunctionRange {
+            start_line,
+            start_col,
+            end_line,
+            end_col,
+            ..self
+        }
+    }
+}
+
+/// 1-based line and character column of a byte offset.
+fn line_column(source: &str, line_starts: &[usize], offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let line = match line_starts.binary_search(&offset) {
+        Ok(line) => line,
+        Err(next) => next.saturating_sub(1),
+    };
+    let line_start = line_starts.get(line).copied().unwrap_or(0);
+    let column = source
+        .get(line_start..offset)
+        .map_or(offset - line_start, |text| text.chars().count());
+    (line + 1, column + 1)
+}
+
+/// Resolve line and column positions for every planned range, including nested plans.
+fn locate_ranges(plans: &mut [FunctionPlan], source: &str, line_starts: &[usize]) {
+    for plan in plans {
+        plan.range = plan.range.map(|range| range.located(source, line_starts));
+        locate_ranges(&mut plan.nested, source, line_starts);
+    }
+}
+
+/// Fill in each rename's `occurrences` by running the rewriter's collection pass over the
+/// fresh plan, so the counts match what a rewrite would replace.
+fn count_occurrences(plan: &mut MinifyPlan, source: &str, suite: &[ast::Stmt]) {
+    if plan
+        .functions
         .iter()
-        .map(std::string::ToString::to_string)
-        .collect();
-    for ident in RESERVED_IDENTIFIERS {
-        reserved.insert((*ident).to_string());
+        .any(|function| function.range.is_none())
+    {
+        return;
+    }
+    let plans = plans_by_name(
+        plan.functions
+            .iter()
+            .filter(|function| !function.renames.is_empty()),
+    );
+    let mut rewriter = FunctionRewriter::new(source, &plans, RewriteOptions::default());
+    rewriter.rename_module_privates(suite, &plan.module_renames);
+    if rewriter.visit_suite(suite, &mut Vec::new()).is_err() || rewriter.abort {
+        return;
+    }
+    let counts = std::mem::take(&mut rewriter.occurrences);
+
+    for entry in &mut plan.module_renames {
+        entry.occurrences = counts
+            .get(&(String::new(), entry.original.clone()))
+            .copied()
+            .unwrap_or(0);
+    }
+    let mut pending: Vec<&mut FunctionPlan> = plan.functions.iter_mut().collect();
+    while let Some(function) = pending.pop() {
+        for entry in &mut function.renames {
+            entry.occurrences = counts
+                .get(&(function.qualified_name.clone(), entry.original.clone()))
+                .copied()
+                .unwrap_or(0);
+        }
+        pending.extend(function.nested.iter_mut());
     }
-    reserved
 }
 
-#[derive(Default)]
-struct UsedNameCollector {
-    names: HashSet<String>,
+/// `(function, local)` pairs whose rename replaces at most two occurrences: the
+/// binding and a single read.
+fn single_use_locals(plan: &MinifyPlan) -> HashSet<(String, String)> {
+    plan.functions
+        .iter()
+        .flat_map(|function| {
+            function
+                .renames
+                .iter()
+                .filter(|entry| (1..=2).contains(&entry.occurrences))
+                .map(|entry| (function.qualified_name.clone(), entry.original.clone()))
+        })
+        .collect()
 }
 
-impl UsedNameCollector {
-    fn into_names(self) -> HashSet<String> {
-        self.names
+/// Plans grouped under their qualified name without the `#N` of a repeated definition; the
+/// rewriter tells the plans of one group apart by their ranges.
+fn plans_by_name<'p>(
+    functions: impl IntoIterator<Item = &'p FunctionPlan>,
+) -> HashMap<String, Vec<FunctionPlan>> {
+    let mut plans: HashMap<String, Vec<FunctionPlan>> = HashMap::new();
+    for function in functions {
+        plans
+            .entry(base_qualified_name(&function.qualified_name).to_string())
+            .or_default()
+            .push(function.clone());
+    }
+    plans
+}
+
+/// `qualified_name` without the `#N` that numbers a repeated definition.
+fn base_qualified_name(qualified_name: &str) -> &str {
+    qualified_name
+        .split_once('#')
+        .map_or(qualified_name, |(base, _)| base)
+}
+
+fn flatten_plan(mut plan: FunctionPlan, out: &mut Vec<FunctionPlan>) {
+    out.push(plan.clone());
+    for child in plan.nested.drain(..) {
+        flatten_plan(child, out);
     }
+}
 
-    fn record_name(&mut self, expr_name: &ast::ExprName) {
-        if matches!(expr_name.ctx, ast::ExprContext::Load) {
-            self.names.insert(expr_name.id.to_string());
+struct Planner {
+    module: String,
+    roots: Vec<FunctionPlan>,
+    /// Every name bound at module level; generated names must never shadow these.
+    module_bindings: HashSet<String>,
+    directives: Directives,
+    options: PlanOptions,
+    /// `(function, local)` pairs left unrenamed under `skip_single_use`.
+    single_use: HashSet<(String, String)>,
+    /// Functions planned so far under each qualified name.
+    definitions: HashMap<String, usize>,
+    /// Functions and renames planned so far, checked against `options.limits`.
+    planned_functions: usize,
+    planned_renames: usize,
+    /// The first limit the plan exceeded; nothing more is planned once it is set.
+    exceeded: Option<PlanLimit>,
+}
+
+impl Planner {
+    fn new(
+        module: String,
+        module_bindings: HashSet<String>,
+        directives: Directives,
+        options: PlanOptions,
+    ) -> Self {
+        Self {
+            module,
+            roots: Vec::new(),
+            module_bindings,
+            directives,
+            options,
+            single_use: HashSet::new(),
+            definitions: HashMap::new(),
+            planned_functions: 0,
+            planned_renames: 0,
+            exceeded: None,
         }
     }
 
-    fn visit_suite(&mut self, suite: &[ast::Stmt], depth: usize) {
-        for stmt in suite {
-            self.visit_stmt(stmt, depth);
+    /// Count more planned functions and renames, recording the first limit they exceed.
+    fn count_planned(&mut self, functions: usize, renames: usize) {
+        self.planned_functions += functions;
+        self.planned_renames += renames;
+        if self.exceeded.is_none() {
+            self.exceeded = self
+                .options
+                .limits
+                .exceeded(self.planned_functions, self.planned_renames);
         }
     }
 
-    #[allow(clippy::too_many_lines)]
-    fn visit_stmt(&mut self, stmt: &ast::Stmt, depth: usize) {
-        match stmt {
-            ast::Stmt::FunctionDef(func) => self.visit_function_def(func, depth),
-            ast::Stmt::AsyncFunctionDef(func) => self.visit_async_function_def(func, depth),
-            ast::Stmt::ClassDef(class_def) => self.visit_class_def(class_def, depth),
-            ast::Stmt::Return(ret) => {
-                if let Some(value) = &ret.value {
-                    self.visit_expr(value, depth);
-                }
-            }
-            ast::Stmt::Expr(expr_stmt) => self.visit_expr(&expr_stmt.value, depth),
-            ast::Stmt::Assign(assign) => {
-                for target in &assign.targets {
-                    self.visit_expr(target, depth);
-                }
-                self.visit_expr(&assign.value, depth);
-            }
-            ast::Stmt::AnnAssign(assign) => {
-                self.visit_expr(&assign.target, depth);
-                self.visit_expr(&assign.annotation, depth);
-                if let Some(value) = &assign.value {
-                    self.visit_expr(value, depth);
-                }
-            }
-            ast::Stmt::AugAssign(assign) => {
-                self.visit_expr(&assign.target, depth);
-                self.visit_expr(&assign.value, depth);
-            }
-            ast::Stmt::For(for_stmt) => {
-                self.visit_expr(&for_stmt.target, depth);
-                self.visit_expr(&for_stmt.iter, depth);
-                self.visit_suite(&for_stmt.body, depth);
-                self.visit_suite(&for_stmt.orelse, depth);
-            }
-            ast::Stmt::AsyncFor(for_stmt) => {
-                self.visit_expr(&for_stmt.target, depth);
-                self.visit_expr(&for_stmt.iter, depth);
-                self.visit_suite(&for_stmt.body, depth);
-                self.visit_suite(&for_stmt.orelse, depth);
-            }
-            ast::Stmt::While(while_stmt) => {
-                self.visit_expr(&while_stmt.test, depth);
-                self.visit_suite(&while_stmt.body, depth);
-                self.visit_suite(&while_stmt.orelse, depth);
-            }
-            ast::Stmt::If(if_stmt) => {
-                self.visit_expr(&if_stmt.test, depth);
-                self.visit_suite(&if_stmt.body, depth);
-                self.visit_suite(&if_stmt.orelse, depth);
+    /// `qualified_name`, numbered `#N` from its second definition on, so a function defined
+    /// in both branches of an `if` gets a plan of its own for each.
+    fn unique_name(&mut self, qualified_name: String) -> String {
+        let count = self.definitions.entry(qualified_name.clone()).or_default();
+        *count += 1;
+        if *count == 1 {
+            qualified_name
+        } else {
+            format!("{qualified_name}#{count}")
+        }
+    }
+
+    fn finish(mut self, source: &str) -> MinifyPlan {
+        locate_ranges(&mut self.roots, source, &self.directives.line_starts);
+        let mut functions = Vec::new();
+        for plan in self.roots {
+            flatten_plan(plan, &mut functions);
+        }
+
+        MinifyPlan {
+            module: self.module,
+            keywords: PYTHON_KEYWORDS
+                .iter()
+                .map(std::string::ToString::to_string)
+                .collect(),
+            functions,
+            skip_file: false,
+            naming: self.options.naming,
+            naming_config: self.options.naming_config,
+            keep_parameters: self.options.keep_parameters,
+            module_renames: Vec::new(),
+            preserve_annotated_locals: self.options.preserve_annotated_locals,
+            min_name_length: self.options.min_name_length,
+            skip_single_use: self.options.skip_single_use,
+            rename_typevars: self.options.rename_typevars,
+            function_filter: self.options.function_filter,
+            targets: Vec::new(),
+        }
+    }
+
+    fn visit_suite(&mut self, suite: &[ast::Stmt], path: &mut Vec<String>) {
+        for stmt in suite {
+            if self.exceeded.is_some() {
+                return;
             }
-            ast::Stmt::With(with_stmt) => {
-                for item in &with_stmt.items {
-                    self.visit_expr(&item.context_expr, depth);
-                    if let Some(optional) = &item.optional_vars {
-                        self.visit_expr(optional, depth);
+            match stmt {
+                ast::Stmt::FunctionDef(func) => {
+                    let range = Some(range_from_node(func));
+                    if let Some(plan) = self.plan_function(
+                        &func.name,
+                        &func.args,
+                        &func.decorator_list,
+                        func.returns.as_deref(),
+                        &func.body,
+                        path,
+                        None,
+                        range,
+                    ) {
+                        self.roots.push(plan);
                     }
                 }
-                self.visit_suite(&with_stmt.body, depth);
-            }
-            ast::Stmt::AsyncWith(with_stmt) => {
-                for item in &with_stmt.items {
-                    self.visit_expr(&item.context_expr, depth);
-                    if let Some(optional) = &item.optional_vars {
-                        self.visit_expr(optional, depth);
+                ast::Stmt::AsyncFunctionDef(func) => {
+                    let range = Some(range_from_node(func));
+                    if let Some(plan) = self.plan_function(
+                        &func.name,
+                        &func.args,
+                        &func.decorator_list,
+                        func.returns.as_deref(),
+                        &func.body,
+                        path,
+                        None,
+                        range,
+                    ) {
+                        self.roots.push(plan);
                     }
                 }
-                self.visit_suite(&with_stmt.body, depth);
-            }
-            ast::Stmt::Try(try_stmt) => {
-                self.visit_suite(&try_stmt.body, depth);
-                self.visit_suite(&try_stmt.orelse, depth);
-                self.visit_suite(&try_stmt.finalbody, depth);
-                for handler in &try_stmt.handlers {
-                    let ast::ExceptHandler::ExceptHandler(handler) = handler;
-                    if let Some(typ) = &handler.type_ {
-                        self.visit_expr(typ, depth);
-                    }
-                    self.visit_suite(&handler.body, depth);
+                ast::Stmt::ClassDef(class_def) => {
+                    let class_plans = self.visit_class_collect(class_def, path);
+                    self.roots.extend(class_plans);
                 }
-            }
-            ast::Stmt::TryStar(try_stmt) => {
-                self.visit_suite(&try_stmt.body, depth);
-                self.visit_suite(&try_stmt.orelse, depth);
-                self.visit_suite(&try_stmt.finalbody, depth);
-                for handler in &try_stmt.handlers {
-                    let ast::ExceptHandler::ExceptHandler(handler) = handler;
-                    if let Some(typ) = &handler.type_ {
-                        self.visit_expr(typ, depth);
+                other => {
+                    for suite in conditional_suites(other) {
+                        self.visit_suite(suite, path);
                     }
-                    self.visit_suite(&handler.body, depth);
                 }
             }
-            ast::Stmt::Raise(raise) => {
-                if let Some(exc) = &raise.exc {
-                    self.visit_expr(exc, depth);
-                }
-                if let Some(cause) = &raise.cause {
-                    self.visit_expr(cause, depth);
-                }
+        }
+    }
+
+    fn visit_class_collect(
+        &mut self,
+        class_def: &ast::StmtClassDef,
+        path: &mut Vec<String>,
+    ) -> Vec<FunctionPlan> {
+        path.push(class_def.name.to_string());
+        let mut plans = Vec::new();
+        self.collect_class_suite(&class_def.body, path, &mut plans);
+        path.pop();
+        plans
+    }
+
+    fn collect_class_suite(
+        &mut self,
+        suite: &[ast::Stmt],
+        path: &mut Vec<String>,
+        plans: &mut Vec<FunctionPlan>,
+    ) {
+        for stmt in suite {
+            if self.exceeded.is_some() {
+                return;
             }
-            ast::Stmt::Assert(assert_stmt) => {
-                self.visit_expr(&assert_stmt.test, depth);
-                if let Some(msg) = &assert_stmt.msg {
-                    self.visit_expr(msg, depth);
+            match stmt {
+                ast::Stmt::FunctionDef(func) => {
+                    let range = Some(range_from_node(func));
+                    if let Some(plan) = self.plan_function(
+                        &func.name,
+                        &func.args,
+                        &func.decorator_list,
+                        func.returns.as_deref(),
+                        &func.body,
+                        path,
+                        None,
+                        range,
+                    ) {
+                        plans.push(plan);
+                    }
                 }
-            }
-            ast::Stmt::Match(match_stmt) => {
-                self.visit_expr(&match_stmt.subject, depth);
-                for case in &match_stmt.cases {
-                    if let Some(guard) = &case.guard {
-                        self.visit_expr(guard, depth);
+                ast::Stmt::AsyncFunctionDef(func) => {
+                    let range = Some(range_from_node(func));
+                    if let Some(plan) = self.plan_function(
+                        &func.name,
+                        &func.args,
+                        &func.decorator_list,
+                        func.returns.as_deref(),
+                        &func.body,
+                        path,
+                        None,
+                        range,
+                    ) {
+                        plans.push(plan);
                     }
-                    self.visit_suite(&case.body, depth);
                 }
-            }
-            ast::Stmt::Delete(delete) => {
-                for target in &delete.targets {
-                    self.visit_expr(target, depth);
+                ast::Stmt::ClassDef(inner) => {
+                    let inner_plans = self.visit_class_collect(inner, path);
+                    plans.extend(inner_plans);
+                }
+                other => {
+                    for suite in conditional_suites(other) {
+                        self.collect_class_suite(suite, path, plans);
+                    }
                 }
-            }
-            ast::Stmt::Pass(_)
-            | ast::Stmt::Break(_)
-            | ast::Stmt::Continue(_)
-            | ast::Stmt::Global(_)
-            | ast::Stmt::Nonlocal(_)
-            | ast::Stmt::Import(_)
-            | ast::Stmt::ImportFrom(_) => {}
-            ast::Stmt::TypeAlias(type_alias) => {
-                self.visit_expr(&type_alias.value, depth);
             }
         }
     }
 
-    fn visit_function_def(&mut self, func: &ast::StmtFunctionDef, depth: usize) {
-        for decorator in &func.decorator_list {
-            self.visit_expr(decorator, depth);
+    #[allow(clippy::too_many_arguments)]
+    fn plan_function(
+        &mut self,
+        name: &ast::Identifier,
+        args: &ast::Arguments,
+        decorators: &[ast::Expr],
+        returns: Option<&ast::Expr>,
+        body: &[ast::Stmt],
+        path: &mut Vec<String>,
+        parent_collector: Option<&mut FunctionCollector>,
+        range: Option<FunctionRange>,
+    ) -> Option<FunctionPlan> {
+        let name_str = name.to_string();
+        if let Some(collector) = parent_collector {
+            collector.reserve_name(&name_str);
+            collector.mark_nested_function();
         }
-        self.visit_arguments(&func.args, depth);
-        if let Some(returns) = &func.returns {
-            self.visit_expr(returns, depth);
+
+        if range.is_some_and(|range| self.directives.is_off(&range)) {
+            return None;
         }
-        if depth == 0 {
-            return;
+        // Like `tsrs: off`, a filtered-out function takes its nested functions with it;
+        // its name was already reserved in the enclosing scope above.
+        if !self.options.function_filter.is_empty() {
+            let mut qualified = self.module.clone();
+            for part in path.iter().chain([&name_str]) {
+                qualified.push('.');
+                qualified.push_str(part);
+            }
+            if !self.options.function_filter.selects(&qualified) {
+                return None;
+            }
         }
-        let body_depth = depth - 1;
-        self.visit_suite(&func.body, body_depth);
-    }
 
-    fn visit_async_function_def(&mut self, func: &ast::StmtAsyncFunctionDef, depth: usize) {
-        for decorator in &func.decorator_list {
-            self.visit_expr(decorator, depth);
+        self.count_planned(1, 0);
+        if self.exceeded.is_some() {
+            return None;
         }
-        self.visit_arguments(&func.args, depth);
-        if let Some(returns) = &func.returns {
-            self.visit_expr(returns, depth);
-        }
-        if depth == 0 {
-            return;
+
+        path.push(name_str);
+        let qualified_name = self.unique_name(path.join("."));
+
+        let mut reserved = default_reserved();
+        let (globals, nonlocals) = collect_declared_names(body);
+        for name in globals.iter().chain(nonlocals.iter()) {
+            reserved.insert(name.clone());
         }
-        let body_depth = depth - 1;
-        self.visit_suite(&func.body, body_depth);
-    }
 
-    fn visit_class_def(&mut self, class_def: &ast::StmtClassDef, depth: usize) {
-        for decorator in &class_def.decorator_list {
-            self.visit_expr(decorator, depth);
+        let mut collector = FunctionCollector::new(reserved);
+        collector.rename_typevars = self.options.rename_typevars;
+        collector.guard_names(self.module_bindings.iter().cloned());
+        let used = collect_used_names_in_body(args, returns, body);
+        collector.guard_names(used.names.into_iter());
+        collector.guard_attributes(used.attributes);
+        if let Some(range) = &range {
+            for name in self.directives.keep_names(range) {
+                collector.reserve_name(&name);
+            }
         }
-        for base in &class_def.bases {
-            self.visit_expr(base, depth);
+        for name in collect_signature_names(args, decorators) {
+            collector.reserve_name(&name);
         }
-        for keyword in &class_def.keywords {
-            self.visit_expr(&keyword.value, depth);
+        collector.collect_parameters(args);
+        if self.options.keep_parameters {
+            collector.reserve_parameters(args);
         }
-        if depth == 0 {
-            return;
+        if let Some(returns) = returns {
+            collector.reserve_names_in_string_annotations(returns);
         }
-        let body_depth = depth - 1;
-        self.visit_suite(&class_def.body, body_depth);
-    }
-
-    fn visit_arguments(&mut self, args: &ast::Arguments, depth: usize) {
-        for param in &args.posonlyargs {
-            self.visit_arg_with_default(param, depth);
-        }
-        for param in &args.args {
-            self.visit_arg_with_default(param, depth);
-        }
-        if let Some(vararg) = &args.vararg {
-            self.visit_arg(vararg, depth);
-        }
-        for param in &args.kwonlyargs {
-            self.visit_arg_with_default(param, depth);
-        }
-        if let Some(kwarg) = &args.kwarg {
-            self.visit_arg(kwarg, depth);
-        }
-    }
+        collector.record_exclusions(globals.into_iter());
+        collector.record_exclusions(nonlocals.into_iter());
 
-    fn visit_arg_with_default(&mut self, arg: &ast::ArgWithDefault, depth: usize) {
-        self.visit_arg(&arg.def, depth);
-        if let Some(default) = &arg.default {
-            self.visit_expr(default, depth);
+        let nested = self.collect_in_function(&mut collector, body, path);
+        collector.mark_capturing_lambdas();
+        if used.exposes_locals {
+            collector.exposes_locals = true;
+            collector.keep_locals(|_| true);
+        } else {
+            collector.keep_looked_up_locals(&used.looked_up);
         }
-    }
+        collector.keep_locals(|local| {
+            local.chars().count() < self.options.min_name_length
+                || self
+                    .single_use
+                    .contains(&(qualified_name.clone(), local.to_string()))
+        });
 
-    fn visit_arg(&mut self, arg: &ast::Arg, depth: usize) {
-        if let Some(annotation) = &arg.annotation {
-            self.visit_expr(annotation, depth);
-        }
+        let mut plan = collector.into_plan(
+            qualified_name,
+            range,
+            self.options.naming,
+            &self.options.naming_config,
+        );
+        plan.nested = nested;
+        self.count_planned(0, plan.renames.len());
+
+        path.pop();
+        Some(plan)
     }
 
     #[allow(clippy::too_many_lines)]
-    fn visit_expr(&mut self, expr: &ast::Expr, depth: usize) {
-        match expr {
-            ast::Expr::Name(expr_name) => self.record_name(expr_name),
-            ast::Expr::BoolOp(expr_bool) => {
-                for value in &expr_bool.values {
-                    self.visit_expr(value, depth);
-                }
-            }
-            ast::Expr::BinOp(expr_bin) => {
-                self.visit_expr(&expr_bin.left, depth);
-                self.visit_expr(&expr_bin.right, depth);
-            }
-            ast::Expr::UnaryOp(expr_unary) => {
-                self.visit_expr(&expr_unary.operand, depth);
-            }
-            ast::Expr::Lambda(lambda) => {
-                self.visit_arguments(&lambda.args, depth);
-                let inner_depth = depth.saturating_sub(1);
-                self.visit_expr(&lambda.body, inner_depth);
-            }
-            ast::Expr::IfExp(expr_if) => {
-                self.visit_expr(&expr_if.test, depth);
-                self.visit_expr(&expr_if.body, depth);
-                self.visit_expr(&expr_if.orelse, depth);
+    fn collect_in_function(
+        &mut self,
+        collector: &mut FunctionCollector,
+        body: &[ast::Stmt],
+        path: &mut Vec<String>,
+    ) -> Vec<FunctionPlan> {
+        let mut nested_plans = Vec::new();
+        for stmt in body {
+            if self.exceeded.is_some() {
+                break;
             }
-            ast::Expr::List(expr_list) => {
-                for elt in &expr_list.elts {
-                    self.visit_expr(elt, depth);
+            match stmt {
+                ast::Stmt::FunctionDef(func) => {
+                    let captured = collect_used_names_in_function(func, 0);
+                    for name in captured {
+                        collector.reserve_name(&name);
+                    }
+                    let range = Some(range_from_node(func));
+                    if let Some(plan) = self.plan_function(
+                        &func.name,
+                        &func.args,
+                        &func.decorator_list,
+                        func.returns.as_deref(),
+                        &func.body,
+                        path,
+                        Some(collector),
+                        range,
+                    ) {
+                        nested_plans.push(plan);
+                    }
                 }
-            }
-            ast::Expr::Tuple(expr_tuple) => {
-                for elt in &expr_tuple.elts {
-                    self.visit_expr(elt, depth);
+                ast::Stmt::AsyncFunctionDef(func) => {
+                    let captured = collect_used_names_in_async_function(func, 0);
+                    for name in captured {
+                        collector.reserve_name(&name);
+                    }
+                    let range = Some(range_from_node(func));
+                    if let Some(plan) = self.plan_function(
+                        &func.name,
+                        &func.args,
+                        &func.decorator_list,
+                        func.returns.as_deref(),
+                        &func.body,
+                        path,
+                        Some(collector),
+                        range,
+                    ) {
+                        nested_plans.push(plan);
+                    }
                 }
-            }
-            ast::Expr::Set(expr_set) => {
-                for elt in &expr_set.elts {
-                    self.visit_expr(elt, depth);
+                ast::Stmt::ClassDef(class_def) => {
+                    let captured = collect_used_names_in_class(class_def, 1);
+                    for name in captured {
+                        collector.reserve_name(&name);
+                    }
+                    let class_name = class_def.name.to_string();
+                    collector.reserve_name(&class_name);
+                    collector.mark_nested_function();
+                    let class_plans = self.visit_class_collect(class_def, path);
+                    nested_plans.extend(class_plans);
                 }
-            }
-            ast::Expr::Dict(expr_dict) => {
-                for key in expr_dict.keys.iter().flatten() {
-                    self.visit_expr(key, depth);
+                ast::Stmt::Assign(assign) => {
+                    for target in &assign.targets {
+                        collector.add_names_from_expr(target);
+                    }
+                    if let [ast::Expr::Name(target)] = assign.targets.as_slice() {
+                        collector.assignment_target = Some(target.id.to_string());
+                    }
+                    collector.collect_from_expression(&assign.value);
                 }
-                for value in &expr_dict.values {
-                    self.visit_expr(value, depth);
+                ast::Stmt::AnnAssign(assign) => {
+                    if self.options.preserve_annotated_locals {
+                        collector.reserve_names_from_expr(&assign.target);
+                    } else {
+                        collector.add_names_from_expr(&assign.target);
+                    }
+                    if let Some(value) = &assign.value {
+                        collector.collect_from_expression(value);
+                    }
+                    collector.reserve_names_in_string_annotations(&assign.annotation);
                 }
-            }
-            ast::Expr::ListComp(expr) => {
-                self.visit_expr(&expr.elt, depth);
-                self.visit_comprehension_generators(&expr.generators, depth);
-            }
-            ast::Expr::SetComp(expr) => {
-                self.visit_expr(&expr.elt, depth);
-                self.visit_comprehension_generators(&expr.generators, depth);
-            }
-            ast::Expr::DictComp(expr) => {
-                self.visit_expr(&expr.key, depth);
-                self.visit_expr(&expr.value, depth);
-                self.visit_comprehension_generators(&expr.generators, depth);
-            }
-            ast::Expr::GeneratorExp(expr) => {
-                self.visit_expr(&expr.elt, depth);
-                self.visit_comprehension_generators(&expr.generators, depth);
-            }
-            ast::Expr::Compare(expr_compare) => {
-                self.visit_expr(&expr_compare.left, depth);
-                for comp in &expr_compare.comparators {
-                    self.visit_expr(comp, depth);
+                ast::Stmt::AugAssign(assign) => {
+                    collector.add_names_from_expr(&assign.target);
+                    collector.collect_from_expression(&assign.value);
                 }
-            }
-            ast::Expr::Call(expr_call) => {
-                self.visit_expr(&expr_call.func, depth);
-                for arg in &expr_call.args {
-                    self.visit_expr(arg, depth);
+                ast::Stmt::For(for_stmt) => {
+                    collector.add_names_from_expr(&for_stmt.target);
+                    collector.collect_from_expression(&for_stmt.iter);
+                    self.collect_in_function(collector, &for_stmt.body, path);
+                    self.collect_in_function(collector, &for_stmt.orelse, path);
                 }
-                for keyword in &expr_call.keywords {
-                    self.visit_expr(&keyword.value, depth);
+                ast::Stmt::AsyncFor(for_stmt) => {
+                    collector.add_names_from_expr(&for_stmt.target);
+                    collector.collect_from_expression(&for_stmt.iter);
+                    self.collect_in_function(collector, &for_stmt.body, path);
+                    self.collect_in_function(collector, &for_stmt.orelse, path);
                 }
-            }
-            ast::Expr::Attribute(expr_attr) => {
-                self.visit_expr(&expr_attr.value, depth);
-            }
-            ast::Expr::Subscript(expr_sub) => {
-                self.visit_expr(&expr_sub.value, depth);
-                self.visit_expr(&expr_sub.slice, depth);
-            }
-            ast::Expr::Starred(expr_star) => {
-                self.visit_expr(&expr_star.value, depth);
-            }
-            ast::Expr::Await(expr_await) => self.visit_expr(&expr_await.value, depth),
-            ast::Expr::Yield(expr_yield) => {
-                if let Some(value) = &expr_yield.value {
-                    self.visit_expr(value, depth);
+                // Conditions can bind locals with `:=`, as in `if (n := compute()) > 10:`.
+                ast::Stmt::While(while_stmt) => {
+                    collector.collect_from_expression(&while_stmt.test);
+                    self.collect_in_function(collector, &while_stmt.body, path);
+                    self.collect_in_function(collector, &while_stmt.orelse, path);
                 }
-            }
-            ast::Expr::YieldFrom(expr_yield) => self.visit_expr(&expr_yield.value, depth),
-            ast::Expr::Constant(_) => {}
-            ast::Expr::Slice(slice) => self.visit_slice(slice, depth),
-            ast::Expr::JoinedStr(joined) => {
-                for value in &joined.values {
-                    self.visit_expr(value, depth);
+                ast::Stmt::If(if_stmt) => {
+                    collector.collect_from_expression(&if_stmt.test);
+                    self.collect_in_function(collector, &if_stmt.body, path);
+                    self.collect_in_function(collector, &if_stmt.orelse, path);
                 }
-            }
-            ast::Expr::FormattedValue(formatted) => {
-                self.visit_expr(&formatted.value, depth);
-                if let Some(format_spec) = &formatted.format_spec {
-                    self.visit_expr(format_spec, depth);
+                ast::Stmt::With(with_stmt) => {
+                    for item in &with_stmt.items {
+                        collector.collect_from_expression(&item.context_expr);
+                        if let Some(optional) = &item.optional_vars {
+                            collector.add_names_from_expr(optional);
+                        }
+                    }
+                    self.collect_in_function(collector, &with_stmt.body, path);
                 }
-            }
-            ast::Expr::NamedExpr(named) => {
-                self.visit_expr(&named.target, depth);
-                self.visit_expr(&named.value, depth);
-            }
-        }
-    }
-
-    fn visit_comprehension_generators(&mut self, generators: &[ast::Comprehension], depth: usize) {
-        for generator in generators {
-            self.visit_expr(&generator.iter, depth);
-            self.visit_expr(&generator.target, depth);
-            for condition in &generator.ifs {
-                self.visit_expr(condition, depth);
-            }
-        }
-    }
-
-    fn visit_slice(&mut self, slice: &ast::ExprSlice, depth: usize) {
-        if let Some(lower) = &slice.lower {
-            self.visit_expr(lower, depth);
-        }
-        if let Some(upper) = &slice.upper {
-            self.visit_expr(upper, depth);
-        }
-        if let Some(step) = &slice.step {
-            self.visit_expr(step, depth);
-        }
-    }
-}
-
-fn collect_used_names_in_function(func: &ast::StmtFunctionDef, depth: usize) -> HashSet<String> {
-    let mut collector = UsedNameCollector::default();
-    for decorator in &func.decorator_list {
-        collector.visit_expr(decorator, depth);
-    }
-    collector.visit_arguments(&func.args, depth);
-    if let Some(returns) = &func.returns {
-        collector.visit_expr(returns, depth);
-    }
-    collector.visit_suite(&func.body, depth);
-    collector.into_names()
+                ast::Stmt::AsyncWith(with_stmt) => {
+                    for item in &with_stmt.items {
+                        collector.collect_from_expression(&item.context_expr);
+                        if let Some(optional) = &item.optional_vars {
+                            collector.add_names_from_expr(optional);
+                        }
+                    }
+                    self.collect_in_function(collector, &with_stmt.body, path);
+                }
+                ast::Stmt::Try(try_stmt) => {
+                    self.collect_in_function(collector, &try_stmt.body, path);
+                    self.collect_in_function(collector, &try_stmt.orelse, path);
+                    self.collect_in_function(collector, &try_stmt.finalbody, path);
+                    for handler in &try_stmt.handlers {
+                        let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                        if let Some(name) = &handler.name {
+                            collector.add_name(name.as_ref());
+                        }
+                        self.collect_in_function(collector, &handler.body, path);
+                    }
+                }
+                ast::Stmt::TryStar(try_stmt) => {
+                    self.collect_in_function(collector, &try_stmt.body, path);
+                    self.collect_in_function(collector, &try_stmt.orelse, path);
+                    self.collect_in_function(collector, &try_stmt.finalbody, path);
+                    for handler in &try_stmt.handlers {
+                        let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                        if let Some(name) = &handler.name {
+                            collector.add_name(name.as_ref());
+                        }
+                        self.collect_in_function(collector, &handler.body, path);
+                    }
+                }
+                ast::Stmt::Return(ret) => {
+                    if let Some(value) = &ret.value {
+                        collector.collect_from_expression(value);
+                    }
+                }
+                ast::Stmt::Raise(raise) => {
+                    for expr in raise.exc.iter().chain(&raise.cause) {
+                        collector.collect_from_expression(expr);
+                    }
+                }
+                ast::Stmt::Assert(assert) => {
+                    collector.collect_from_expression(&assert.test);
+                    if let Some(msg) = &assert.msg {
+                        collector.collect_from_expression(msg);
+                    }
+                }
+                ast::Stmt::Match(match_stmt) => {
+                    collector.has_match_statement = true;
+                    collector.collect_from_expression(&match_stmt.subject);
+                    for case in &match_stmt.cases {
+                        collector.add_names_from_pattern(&case.pattern);
+                        if let Some(guard) = &case.guard {
+                            collector.collect_from_expression(guard);
+                        }
+                        self.collect_in_function(collector, &case.body, path);
+                    }
+                }
+                ast::Stmt::Import(import_stmt) => {
+                    collector.mark_import();
+                    for alias in &import_stmt.names {
+                        if let Some(asname) = &alias.asname {
+                            collector.reserve_name(asname.as_ref());
+                        } else {
+                            let module = alias.name.to_string();
+                            let base = module.split('.').next().unwrap_or(&module);
+                            if module.contains('.') {
+                                collector.reserve_name(base);
+                            } else {
+                                collector.add_name(base);
+                            }
+                        }
+                    }
+                }
+                ast::Stmt::ImportFrom(import_from) => {
+                    collector.mark_import();
+                    for alias in &import_from.names {
+                        let name = alias.name.to_string();
+                        if name == "*" {
+                            continue;
+                        }
+                        if let Some(asname) = &alias.asname {
+                            collector.reserve_name(asname.as_ref());
+                        } else {
+                            let base = name.split('.').next().unwrap_or(&name);
+                            collector.add_name(base);
+                        }
+                    }
+                }
+                ast::Stmt::Expr(expr_stmt) => {
+                    collector.collect_from_expression(&expr_stmt.value);
+                }
+                _ => {}
+            }
+        }
+        nested_plans
+    }
 }
 
-fn collect_used_names_in_async_function(
-    func: &ast::StmtAsyncFunctionDef,
-    depth: usize,
-) -> HashSet<String> {
-    let mut collector = UsedNameCollector::default();
-    for decorator in &func.decorator_list {
-        collector.visit_expr(decorator, depth);
-    }
-    collector.visit_arguments(&func.args, depth);
-    if let Some(returns) = &func.returns {
-        collector.visit_expr(returns, depth);
+fn collect_declared_names(body: &[ast::Stmt]) -> (HashSet<String>, HashSet<String>) {
+    let mut globals = HashSet::new();
+    let mut nonlocals = HashSet::new();
+
+    for stmt in body {
+        match stmt {
+            ast::Stmt::Global(glob) => {
+                for name in &glob.names {
+                    globals.insert(name.to_string());
+                }
+            }
+            ast::Stmt::Nonlocal(non) => {
+                for name in &non.names {
+                    nonlocals.insert(name.to_string());
+                }
+            }
+            _ => {}
+        }
     }
-    collector.visit_suite(&func.body, depth);
-    collector.into_names()
+
+    (globals, nonlocals)
 }
 
-fn collect_used_names_in_class(class_def: &ast::StmtClassDef, depth: usize) -> HashSet<String> {
-    let mut collector = UsedNameCollector::default();
-    for decorator in &class_def.decorator_list {
-        collector.visit_expr(decorator, depth);
-    }
-    for base in &class_def.bases {
-        collector.visit_expr(base, depth);
-    }
-    for keyword in &class_def.keywords {
-        collector.visit_expr(&keyword.value, depth);
-    }
-    collector.visit_suite(&class_def.body, depth);
-    collector.into_names()
+/// Identifier-like words in a string annotation, e.g. `"Optional[Node]"`.
+pub(crate) fn identifiers_in_text(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c == '_' || c.is_alphanumeric()))
+        .filter(|word| word.chars().next().is_some_and(|c| !c.is_ascii_digit()))
 }
 
-struct FunctionCollector {
-    locals: Vec<String>,
-    seen: HashSet<String>,
-    excluded: HashSet<String>,
-    reserved: HashSet<String>,
-    declared_exclusions: HashSet<String>,
-    has_nested_functions: bool,
-    has_imports: bool,
-    has_match_statement: bool,
-    has_comprehension: bool,
+/// `# tsrs: ...` comment directives found while tokenizing a module.
+#[derive(Debug, Default)]
+struct Directives {
+    /// `# tsrs: skip-file` appeared within the first few lines.
+    skip_file: bool,
+    /// Lines (0-based) carrying `# tsrs: off`.
+    off_lines: HashSet<usize>,
+    /// Names listed by `# tsrs: keep-names`, keyed by 0-based line.
+    keep_names: HashMap<usize, Vec<String>>,
+    /// Lines (0-based) holding a `def` keyword, in ascending order.
+    def_lines: Vec<usize>,
+    /// Lines (0-based) covered by a decorator, from its `@` to the end of its expression.
+    decorator_lines: HashSet<usize>,
+    /// Byte offset at which each line starts.
+    line_starts: Vec<usize>,
 }
 
-impl FunctionCollector {
-    fn new(reserved: HashSet<String>) -> Self {
-        Self {
-            locals: Vec::new(),
-            seen: HashSet::new(),
-            excluded: HashSet::new(),
-            reserved,
-            declared_exclusions: HashSet::new(),
-            has_nested_functions: false,
-            has_imports: false,
-            has_match_statement: false,
-            has_comprehension: false,
-        }
-    }
+impl Directives {
+    fn parse(source: &str) -> Self {
+        let mut directives = Directives {
+            line_starts: std::iter::once(0)
+                .chain(source.match_indices('\n').map(|(index, _)| index + 1))
+                .collect(),
+            ..Directives::default()
+        };
 
-    fn collect_parameters(&mut self, args: &ast::Arguments) {
-        for param in &args.posonlyargs {
-            self.add_name(param.def.arg.as_ref());
-            if let Some(default) = &param.default {
-                self.collect_from_expression(default);
-            }
-        }
-        for param in &args.args {
-            self.add_name(param.def.arg.as_ref());
-            if let Some(default) = &param.default {
-                self.collect_from_expression(default);
-            }
-        }
-        if let Some(vararg) = &args.vararg {
-            self.add_name(vararg.arg.as_ref());
-        }
-        for param in &args.kwonlyargs {
-            self.add_name(param.def.arg.as_ref());
-            if let Some(default) = &param.default {
-                self.collect_from_expression(default);
+        let mut line_start = true;
+        let mut decorator_from = None;
+        for (token, range) in lexer::lex(source, Mode::Module).map_while(|result| result.ok()) {
+            let line = directives.line_of(usize::from(range.start()));
+            let ends_line = matches!(
+                token,
+                Tok::Newline | Tok::NonLogicalNewline | Tok::Indent | Tok::Dedent
+            );
+            match token {
+                Tok::Def => directives.def_lines.push(line),
+                Tok::Comment(text) => directives.record_comment(&text, line),
+                Tok::At if line_start => decorator_from = Some(line),
+                Tok::Newline => {
+                    if let Some(from) = decorator_from.take() {
+                        directives.decorator_lines.extend(from..=line);
+                    }
+                }
+                _ => {}
             }
-        }
-        if let Some(kwarg) = &args.kwarg {
-            self.add_name(kwarg.arg.as_ref());
+            line_start = ends_line;
         }
 
-        for default in args.defaults() {
-            self.collect_from_expression(default);
-        }
+        directives
     }
 
-    fn record_exclusions<I>(&mut self, iter: I)
-    where
-        I: Iterator<Item = String>,
-    {
-        for name in iter {
-            self.declared_exclusions.insert(name.clone());
-            self.reserve_name(&name);
+    fn record_comment(&mut self, text: &str, line: usize) {
+        let body = text.trim_start_matches('#').trim();
+        let Some(directive) = body.strip_prefix("tsrs:") else {
+            return;
+        };
+        let directive = directive.trim();
+
+        if directive == "off" {
+            self.off_lines.insert(line);
+        } else if directive == "skip-file" {
+            if line < SKIP_FILE_DIRECTIVE_LINES {
+                self.skip_file = true;
+            }
+        } else if let Some(names) = directive.strip_prefix("keep-names") {
+            self.keep_names.entry(line).or_default().extend(
+                names
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string),
+            );
         }
     }
 
-    fn add_name(&mut self, name: &str) {
-        if name.is_empty() {
-            return;
-        }
-        if self.should_skip(name) {
-            self.excluded.insert(name.to_string());
-            self.reserved.insert(name.to_string());
-            self.locals.retain(|existing| existing != name);
-            self.seen.remove(name);
-            return;
+    fn line_of(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next.saturating_sub(1),
         }
+    }
 
-        if self.seen.insert(name.to_string()) {
-            self.locals.push(name.to_string());
+    /// Lines on which a directive applies to the function at `range`: the line above the
+    /// definition (or its first decorator), any decorator lines, and the `def` line.
+    fn header_lines(&self, range: &FunctionRange) -> std::ops::RangeInclusive<usize> {
+        // Function ranges start at `def`, after any decorators.
+        let mut start = self.line_of(range.start);
+        while start > 0 && self.decorator_lines.contains(&(start - 1)) {
+            start -= 1;
         }
+        let def_line = self
+            .def_lines
+            .iter()
+            .copied()
+            .find(|line| *line >= start)
+            .unwrap_or(start);
+        start.saturating_sub(1)..=def_line
     }
 
-    fn reserve_name(&mut self, name: &str) {
-        self.excluded.insert(name.to_string());
-        self.reserved.insert(name.to_string());
-        self.locals.retain(|existing| existing != name);
-        self.seen.remove(name);
+    fn is_off(&self, range: &FunctionRange) -> bool {
+        self.header_lines(range)
+            .any(|line| self.off_lines.contains(&line))
     }
 
-    fn should_skip(&self, name: &str) -> bool {
-        self.reserved.contains(name)
-            || name == "_"
-            || (name.starts_with("__") && name.ends_with("__"))
+    fn keep_names(&self, range: &FunctionRange) -> Vec<String> {
+        self.header_lines(range)
+            .filter_map(|line| self.keep_names.get(&line))
+            .flatten()
+            .cloned()
+            .collect()
     }
+}
 
-    fn mark_nested_function(&mut self) {
-        self.has_nested_functions = true;
+fn default_reserved() -> HashSet<String> {
+    let mut reserved: HashSet<String> = PYTHON_KEYWORDS
+        .iter()
+        .map(std::string::ToString::to_string)
+        .collect();
+    for ident in RESERVED_IDENTIFIERS {
+        reserved.insert((*ident).to_string());
     }
+    reserved
+}
 
-    fn mark_import(&mut self) {
-        self.has_imports = true;
+#[derive(Default)]
+struct UsedNameCollector {
+    names: HashSet<String>,
+    /// Attribute names accessed directly on a bare name, keyed by that name.
+    attributes: HashMap<String, HashSet<String>>,
+    /// Names whose every occurrence is recorded in `sites`.
+    tracked: HashSet<String>,
+    /// Name, byte range, and whether it is stored or deleted rather than read.
+    sites: Vec<(String, usize, usize, bool)>,
+    /// String literals that are valid identifiers.
+    strings: HashSet<String>,
+    /// Set by a `getattr`-style call or a subscript of `vars()`, `locals()`, or
+    /// `__dict__`, any of which can reach a name spelled in a string.
+    looks_up_names: bool,
+    /// Set by a bare `locals()` or `vars()` call.
+    exposes_locals: bool,
+}
+
+/// What [`collect_used_names_in_body`] found in a function.
+struct UsedNames {
+    names: HashSet<String>,
+    /// Attribute names accessed directly on a bare name, keyed by that name.
+    attributes: HashMap<String, HashSet<String>>,
+    /// Identifiers spelled in string literals of a function that looks names up
+    /// dynamically; empty otherwise.
+    looked_up: HashSet<String>,
+    /// The function calls `locals()` or `vars()` without arguments.
+    exposes_locals: bool,
+}
+
+impl UsedNameCollector {
+    fn into_names(self) -> HashSet<String> {
+        self.names
     }
 
-    fn add_names_from_expr(&mut self, expr: &ast::Expr) {
-        match expr {
-            ast::Expr::Name(ast::ExprName { id, ctx, .. }) => {
-                if matches!(ctx, ast::ExprContext::Store | ast::ExprContext::Del) {
-                    self.add_name(id.to_string().as_str());
-                }
-            }
-            ast::Expr::Tuple(ast::ExprTuple { elts, .. })
-            | ast::Expr::List(ast::ExprList { elts, .. }) => {
-                for elt in elts {
-                    self.add_names_from_expr(elt);
-                }
-            }
-            ast::Expr::Starred(ast::ExprStarred { value, .. }) => {
-                self.add_names_from_expr(value);
-            }
-            _ => {}
+    fn record_name(&mut self, expr_name: &ast::ExprName) {
+        let id: &str = expr_name.id.as_ref();
+        if self.tracked.contains(id) {
+            let range = range_from_node(expr_name);
+            let stored = !matches!(expr_name.ctx, ast::ExprContext::Load);
+            self.sites
+                .push((id.to_string(), range.start, range.end, stored));
+        }
+        if matches!(expr_name.ctx, ast::ExprContext::Load) {
+            self.names.insert(expr_name.id.to_string());
         }
     }
 
-    fn reserve_names_from_expr(&mut self, expr: &ast::Expr) {
-        match expr {
-            ast::Expr::Name(ast::ExprName { id, ctx, .. }) => {
-                if matches!(ctx, ast::ExprContext::Store | ast::ExprContext::Del) {
-                    self.reserve_name(id.as_ref());
-                }
-            }
-            ast::Expr::Tuple(ast::ExprTuple { elts, .. })
-            | ast::Expr::List(ast::ExprList { elts, .. }) => {
-                for elt in elts {
-                    self.reserve_names_from_expr(elt);
-                }
-            }
-            ast::Expr::Starred(ast::ExprStarred { value, .. }) => {
-                self.reserve_names_from_expr(value);
-            }
-            _ => {}
+    fn visit_suite(&mut self, suite: &[ast::Stmt], depth: usize) {
+        for stmt in suite {
+            self.visit_stmt(stmt, depth);
         }
     }
 
-    fn add_names_from_pattern(&mut self, pattern: &ast::Pattern) {
-        match pattern {
-            ast::Pattern::MatchAs(pat) => {
-                if let Some(sub) = &pat.pattern {
-                    self.add_names_from_pattern(sub);
-                }
-                if let Some(name) = &pat.name {
-                    self.add_name(name.to_string().as_str());
+    #[allow(clippy::too_many_lines)]
+    fn visit_stmt(&mut self, stmt: &ast::Stmt, depth: usize) {
+        match stmt {
+            ast::Stmt::FunctionDef(func) => self.visit_function_def(func, depth),
+            ast::Stmt::AsyncFunctionDef(func) => self.visit_async_function_def(func, depth),
+            ast::Stmt::ClassDef(class_def) => self.visit_class_def(class_def, depth),
+            ast::Stmt::Return(ret) => {
+                if let Some(value) = &ret.value {
+                    self.visit_expr(value, depth);
                 }
             }
-            ast::Pattern::MatchStar(pat) => {
-                if let Some(name) = &pat.name {
-                    self.add_name(name.to_string().as_str());
+            ast::Stmt::Expr(expr_stmt) => self.visit_expr(&expr_stmt.value, depth),
+            ast::Stmt::Assign(assign) => {
+                for target in &assign.targets {
+                    self.visit_expr(target, depth);
                 }
+                self.visit_expr(&assign.value, depth);
             }
-            ast::Pattern::MatchSequence(seq) => {
-                for sub in &seq.patterns {
-                    self.add_names_from_pattern(sub);
+            ast::Stmt::AnnAssign(assign) => {
+                self.visit_expr(&assign.target, depth);
+                self.visit_expr(&assign.annotation, depth);
+                if let Some(value) = &assign.value {
+                    self.visit_expr(value, depth);
                 }
             }
-            ast::Pattern::MatchMapping(map) => {
-                for sub in &map.patterns {
-                    self.add_names_from_pattern(sub);
-                }
-                if let Some(rest) = &map.rest {
-                    self.add_name(rest.as_ref());
-                }
+            ast::Stmt::AugAssign(assign) => {
+                self.visit_expr(&assign.target, depth);
+                self.visit_expr(&assign.value, depth);
             }
-            ast::Pattern::MatchClass(class) => {
-                for sub in &class.patterns {
-                    self.add_names_from_pattern(sub);
-                }
-                for sub in &class.kwd_patterns {
-                    self.add_names_from_pattern(sub);
-                }
+            ast::Stmt::For(for_stmt) => {
+                self.visit_expr(&for_stmt.target, depth);
+                self.visit_expr(&for_stmt.iter, depth);
+                self.visit_suite(&for_stmt.body, depth);
+                self.visit_suite(&for_stmt.orelse, depth);
             }
-            ast::Pattern::MatchOr(pat) => {
-                for sub in &pat.patterns {
-                    self.add_names_from_pattern(sub);
-                }
+            ast::Stmt::AsyncFor(for_stmt) => {
+                self.visit_expr(&for_stmt.target, depth);
+                self.visit_expr(&for_stmt.iter, depth);
+                self.visit_suite(&for_stmt.body, depth);
+                self.visit_suite(&for_stmt.orelse, depth);
             }
-            _ => {}
-        }
-    }
-
-    fn collect_from_expression(&mut self, expr: &ast::Expr) {
-        match expr {
-            ast::Expr::NamedExpr(named) => {
-                self.add_names_from_expr(&named.target);
-                self.collect_from_expression(&named.value);
+            ast::Stmt::While(while_stmt) => {
+                self.visit_expr(&while_stmt.test, depth);
+                self.visit_suite(&while_stmt.body, depth);
+                self.visit_suite(&while_stmt.orelse, depth);
             }
-            ast::Expr::BoolOp(ast::ExprBoolOp { values, .. })
-            | ast::Expr::Tuple(ast::ExprTuple { elts: values, .. })
-            | ast::Expr::List(ast::ExprList { elts: values, .. }) => {
-                for value in values {
-                    self.collect_from_expression(value);
-                }
+            ast::Stmt::If(if_stmt) => {
+                self.visit_expr(&if_stmt.test, depth);
+                self.visit_suite(&if_stmt.body, depth);
+                self.visit_suite(&if_stmt.orelse, depth);
             }
-            ast::Expr::IfExp(ast::ExprIfExp {
-                test, body, orelse, ..
-            }) => {
-                self.collect_from_expression(test);
-                self.collect_from_expression(body);
-                self.collect_from_expression(orelse);
+            ast::Stmt::With(with_stmt) => {
+                for item in &with_stmt.items {
+                    self.visit_expr(&item.context_expr, depth);
+                    if let Some(optional) = &item.optional_vars {
+                        self.visit_expr(optional, depth);
+                    }
+                }
+                self.visit_suite(&with_stmt.body, depth);
             }
-            ast::Expr::Compare(ast::ExprCompare {
-                left, comparators, ..
-            }) => {
-                self.collect_from_expression(left);
-                for comp in comparators {
-                    self.collect_from_expression(comp);
+            ast::Stmt::AsyncWith(with_stmt) => {
+                for item in &with_stmt.items {
+                    self.visit_expr(&item.context_expr, depth);
+                    if let Some(optional) = &item.optional_vars {
+                        self.visit_expr(optional, depth);
+                    }
                 }
+                self.visit_suite(&with_stmt.body, depth);
             }
-            ast::Expr::BinOp(ast::ExprBinOp { left, right, .. }) => {
-                self.collect_from_expression(left);
-                self.collect_from_expression(right);
+            ast::Stmt::Try(try_stmt) => {
+                self.visit_suite(&try_stmt.body, depth);
+                self.visit_suite(&try_stmt.orelse, depth);
+                self.visit_suite(&try_stmt.finalbody, depth);
+                for handler in &try_stmt.handlers {
+                    let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                    if let Some(typ) = &handler.type_ {
+                        self.visit_expr(typ, depth);
+                    }
+                    self.visit_suite(&handler.body, depth);
+                }
             }
-            ast::Expr::UnaryOp(ast::ExprUnaryOp { operand, .. }) => {
-                self.collect_from_expression(operand);
+            ast::Stmt::TryStar(try_stmt) => {
+                self.visit_suite(&try_stmt.body, depth);
+                self.visit_suite(&try_stmt.orelse, depth);
+                self.visit_suite(&try_stmt.finalbody, depth);
+                for handler in &try_stmt.handlers {
+                    let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                    if let Some(typ) = &handler.type_ {
+                        self.visit_expr(typ, depth);
+                    }
+                    self.visit_suite(&handler.body, depth);
+                }
             }
-            ast::Expr::Call(ast::ExprCall {
-                func,
-                args,
-                keywords,
-                ..
-            }) => {
-                self.collect_from_expression(func);
-                for arg in args {
-                    self.collect_from_expression(arg);
+            ast::Stmt::Raise(raise) => {
+                if let Some(exc) = &raise.exc {
+                    self.visit_expr(exc, depth);
                 }
-                for keyword in keywords {
-                    self.collect_from_expression(&keyword.value);
+                if let Some(cause) = &raise.cause {
+                    self.visit_expr(cause, depth);
                 }
             }
-            ast::Expr::Lambda(_) => {
-                // Lambdas introduce their own scope; avoid rewriting in these cases.
-                self.mark_nested_function();
-            }
-            ast::Expr::ListComp(expr) => {
-                self.has_comprehension = true;
-                self.collect_from_expression(&expr.elt);
-                self.collect_from_comprehension_generators(&expr.generators);
+            ast::Stmt::Assert(assert_stmt) => {
+                self.visit_expr(&assert_stmt.test, depth);
+                if let Some(msg) = &assert_stmt.msg {
+                    self.visit_expr(msg, depth);
+                }
             }
-            ast::Expr::SetComp(expr) => {
-                self.has_comprehension = true;
-                self.collect_from_expression(&expr.elt);
-                self.collect_from_comprehension_generators(&expr.generators);
+            ast::Stmt::Match(match_stmt) => {
+                self.visit_expr(&match_stmt.subject, depth);
+                for case in &match_stmt.cases {
+                    if let Some(guard) = &case.guard {
+                        self.visit_expr(guard, depth);
+                    }
+                    self.visit_suite(&case.body, depth);
+                }
             }
-            ast::Expr::DictComp(expr) => {
-                self.has_comprehension = true;
-                self.collect_from_expression(&expr.key);
-                self.collect_from_expression(&expr.value);
-                self.collect_from_comprehension_generators(&expr.generators);
+            ast::Stmt::Delete(delete) => {
+                for target in &delete.targets {
+                    self.visit_expr(target, depth);
+                }
             }
-            ast::Expr::GeneratorExp(expr) => {
-                self.has_comprehension = true;
-                self.collect_from_expression(&expr.elt);
-                self.collect_from_comprehension_generators(&expr.generators);
+            ast::Stmt::Pass(_)
+            | ast::Stmt::Break(_)
+            | ast::Stmt::Continue(_)
+            | ast::Stmt::Global(_)
+            | ast::Stmt::Nonlocal(_)
+            | ast::Stmt::Import(_)
+            | ast::Stmt::ImportFrom(_) => {}
+            ast::Stmt::TypeAlias(type_alias) => {
+                self.visit_expr(&type_alias.value, depth);
             }
-            _ => {}
         }
     }
 
-    fn collect_from_comprehension_generators(&mut self, generators: &[ast::Comprehension]) {
-        for generator in generators {
-            self.has_comprehension = true;
-            self.reserve_names_from_expr(&generator.target);
-            self.collect_from_expression(&generator.iter);
-            for condition in &generator.ifs {
-                self.collect_from_expression(condition);
-            }
+    fn visit_function_def(&mut self, func: &ast::StmtFunctionDef, depth: usize) {
+        for decorator in &func.decorator_list {
+            self.visit_expr(decorator, depth);
         }
-    }
-
-    fn into_plan(self, qualified_name: String, range: Option<FunctionRange>) -> FunctionPlan {
-        let mut generator = ShortNameGenerator::new(self.reserved);
-        let mut renames = Vec::with_capacity(self.locals.len());
-
-        for name in &self.locals {
-            let replacement = generator.next();
-            renames.push(RenameEntry {
-                original: name.clone(),
-                renamed: replacement,
-            });
+        self.visit_arguments(&func.args, depth);
+        if let Some(returns) = &func.returns {
+            self.visit_expr(returns, depth);
         }
-
-        let mut excluded: Vec<String> = self
-            .excluded
-            .union(&self.declared_exclusions)
-            .cloned()
-            .collect();
-        excluded.sort();
-        excluded.dedup();
-
-        FunctionPlan {
-            qualified_name,
-            locals: self.locals,
-            renames,
-            excluded,
-            range,
-            has_nested_functions: self.has_nested_functions,
-            has_imports: self.has_imports,
-            has_match_statement: self.has_match_statement,
-            has_comprehension: self.has_comprehension,
-            nested: Vec::new(),
+        if depth == 0 {
+            return;
         }
+        let body_depth = depth - 1;
+        self.visit_suite(&func.body, body_depth);
     }
-}
-
-struct ShortNameGenerator {
-    counter: usize,
-    reserved: HashSet<String>,
-    issued: HashSet<String>,
-}
 
-impl ShortNameGenerator {
-    fn new(reserved: HashSet<String>) -> Self {
-        Self {
-            counter: 0,
-            reserved,
-            issued: HashSet::new(),
+    fn visit_async_function_def(&mut self, func: &ast::StmtAsyncFunctionDef, depth: usize) {
+        for decorator in &func.decorator_list {
+            self.visit_expr(decorator, depth);
         }
-    }
-
-    fn next(&mut self) -> String {
-        loop {
-            let candidate = encode_identifier(self.counter);
-            self.counter += 1;
-
-            if self.reserved.contains(&candidate) || self.issued.contains(&candidate) {
-                continue;
-            }
-
-            self.issued.insert(candidate.clone());
-            return candidate;
+        self.visit_arguments(&func.args, depth);
+        if let Some(returns) = &func.returns {
+            self.visit_expr(returns, depth);
         }
-    }
-}
-
-fn encode_identifier(mut value: usize) -> String {
-    let mut chars = Vec::new();
-    loop {
-        let rem = value % 26;
-        #[allow(clippy::cast_possible_truncation)]
-        chars.push((b'a' + rem as u8) as char);
-        value /= 26;
-        if value == 0 {
-            break;
+        if depth == 0 {
+            return;
         }
-        value -= 1;
-    }
-    chars.iter().rev().collect()
-}
-
-fn range_from_node<T: Ranged>(node: &T) -> FunctionRange {
-    let text_range = node.range();
-    FunctionRange {
-        start: usize::from(text_range.start()),
-        end: usize::from(text_range.end()),
+        let body_depth = depth - 1;
+        self.visit_suite(&func.body, body_depth);
     }
-}
-
-struct Replacement {
-    start: usize,
-    end: usize,
-    text: String,
-}
-
-struct FunctionRewriter<'a> {
-    source: &'a str,
-    plans: &'a HashMap<String, FunctionPlan>,
-    replacements: Vec<Replacement>,
-    abort: bool,
-}
 
-impl<'a> FunctionRewriter<'a> {
-    fn new(source: &'a str, plans: &'a HashMap<String, FunctionPlan>) -> Self {
-        Self {
-            source,
-            plans,
-            replacements: Vec::new(),
-            abort: false,
+    fn visit_class_def(&mut self, class_def: &ast::StmtClassDef, depth: usize) {
+        for decorator in &class_def.decorator_list {
+            self.visit_expr(decorator, depth);
         }
-    }
-
-    fn rewrite(mut self, suite: &[ast::Stmt]) -> Result<String> {
-        self.visit_suite(suite, &mut Vec::new())?;
-        if self.abort {
-            Ok(self.source.to_string())
-        } else {
-            Ok(self.apply())
+        for base in &class_def.bases {
+            self.visit_expr(base, depth);
         }
-    }
-
-    fn visit_suite(&mut self, suite: &[ast::Stmt], path: &mut Vec<String>) -> Result<()> {
-        for stmt in suite {
-            match stmt {
-                ast::Stmt::FunctionDef(func) => {
-                    self.process_function(
-                        &func.name,
-                        &func.args,
-                        func.returns.as_deref(),
-                        &func.body,
-                        path,
-                    )?;
-                }
-                ast::Stmt::AsyncFunctionDef(func) => {
-                    self.process_async_function(func, path)?;
-                }
-                ast::Stmt::ClassDef(class_def) => {
-                    self.visit_class(class_def, path)?;
-                }
-                _ => {}
-            }
+        for keyword in &class_def.keywords {
+            self.visit_expr(&keyword.value, depth);
         }
-        Ok(())
-    }
-
-    fn visit_class(&mut self, class_def: &ast::StmtClassDef, path: &mut Vec<String>) -> Result<()> {
-        path.push(class_def.name.to_string());
-        self.visit_suite(&class_def.body, path)?;
-        path.pop();
-        Ok(())
-    }
-
-    fn process_async_function(
-        &mut self,
-        func: &ast::StmtAsyncFunctionDef,
-        path: &mut Vec<String>,
-    ) -> Result<()> {
-        self.process_function(
-            &func.name,
-            &func.args,
-            func.returns.as_deref(),
-            &func.body,
-            path,
-        )
-    }
-
-    fn process_function(
-        &mut self,
-        name: &ast::Identifier,
-        args: &ast::Arguments,
-        returns: Option<&ast::Expr>,
-        body: &[ast::Stmt],
-        path: &mut Vec<String>,
-    ) -> Result<()> {
-        path.push(name.to_string());
-        let qualified_name = path.join(".");
-
-        if let Some(plan) = self.plans.get(&qualified_name) {
-            if plan.has_match_statement {
-                self.abort = true;
-            } else if !plan.has_comprehension {
-                self.rewrite_with_plan(plan, args, returns, body);
-            }
+        if depth == 0 {
+            return;
         }
-
-        // Visit nested scopes to apply their plans.
-        self.visit_suite(body, path)?;
-
-        path.pop();
-        Ok(())
+        let body_depth = depth - 1;
+        self.visit_suite(&class_def.body, body_depth);
     }
 
-    fn rewrite_with_plan(
-        &mut self,
-        plan: &FunctionPlan,
-        args: &ast::Arguments,
-        returns: Option<&ast::Expr>,
-        body: &[ast::Stmt],
-    ) {
-        let Some(range) = &plan.range else {
-            self.abort = true;
-            return;
-        };
-
-        let renames: HashMap<&str, &str> = plan
-            .renames
-            .iter()
-            .map(|entry| (entry.original.as_str(), entry.renamed.as_str()))
-            .collect();
-
-        if renames.is_empty() {
-            return;
+    fn visit_arguments(&mut self, args: &ast::Arguments, depth: usize) {
+        for param in &args.posonlyargs {
+            self.visit_arg_with_default(param, depth);
         }
-
-        let excluded: HashSet<&str> = plan.excluded.iter().map(|name| name.as_str()).collect();
-        let mut collector = OccurrenceCollector::new(self.source, range, renames, excluded);
-        collector.visit_arguments(args);
-        if let Some(annotation) = returns {
-            collector.with_annotation(|visitor| visitor.visit_expr(annotation));
+        for param in &args.args {
+            self.visit_arg_with_default(param, depth);
         }
-        collector.visit_statements(body);
-
-        if collector.abort {
-            self.abort = true;
-            return;
+        if let Some(vararg) = &args.vararg {
+            self.visit_arg(vararg, depth);
         }
-
-        self.replacements.extend(collector.replacements);
-    }
-
-    fn apply(mut self) -> String {
-        if self.replacements.is_empty() {
-            return self.source.to_string();
+        for param in &args.kwonlyargs {
+            self.visit_arg_with_default(param, depth);
         }
-        self.replacements
-            .sort_by(|a, b| b.start.cmp(&a.start).then(b.end.cmp(&a.end)));
-        let mut result = self.source.to_string();
-        for replacement in self.replacements {
-            result.replace_range(replacement.start..replacement.end, &replacement.text);
+        if let Some(kwarg) = &args.kwarg {
+            self.visit_arg(kwarg, depth);
         }
-        result
     }
-}
 
-fn strip_docstrings(module_name: &str, source: &str) -> Result<String> {
-    let suite = ast::Suite::parse(source, module_name)
-        .map_err(|err| TsrsError::ParseError(err.to_string()))?;
-
-    let mut ranges = Vec::new();
-    collect_docstrings_in_suite(source, &suite, &mut ranges);
-
-    if ranges.is_empty() {
-        return Ok(source.to_string());
+    fn visit_arg_with_default(&mut self, arg: &ast::ArgWithDefault, depth: usize) {
+        self.visit_arg(&arg.def, depth);
+        if let Some(default) = &arg.default {
+            self.visit_expr(default, depth);
+        }
     }
 
-    ranges.sort_by(|a, b| b.0.cmp(&a.0));
-
-    let mut stripped = source.to_string();
-    for (start, end) in ranges {
-        stripped.replace_range(start..end, "");
+    fn visit_arg(&mut self, arg: &ast::Arg, depth: usize) {
+        if let Some(annotation) = &arg.annotation {
+            self.visit_expr(annotation, depth);
+        }
     }
 
-    Ok(stripped)
-}
-
-fn collect_docstrings_in_suite(
-    source: &str,
-    suite: &[ast::Stmt],
-    ranges: &mut Vec<(usize, usize)>,
-) {
-    if let Some(first) = suite.first() {
-        if is_docstring_stmt(first) {
-            ranges.push(docstring_range(source, first));
+    #[allow(clippy::too_many_lines)]
+    fn visit_expr(&mut self, expr: &ast::Expr, depth: usize) {
+        match expr {
+            ast::Expr::Name(expr_name) => self.record_name(expr_name),
+            ast::Expr::BoolOp(expr_bool) => {
+                for value in &expr_bool.values {
+                    self.visit_expr(value, depth);
+                }
+            }
+            ast::Expr::BinOp(expr_bin) => {
+                self.visit_expr(&expr_bin.left, depth);
+                self.visit_expr(&expr_bin.right, depth);
+            }
+            ast::Expr::UnaryOp(expr_unary) => {
+                self.visit_expr(&expr_unary.operand, depth);
+            }
+            ast::Expr::Lambda(lambda) => {
+                self.visit_arguments(&lambda.args, depth);
+                let inner_depth = depth.saturating_sub(1);
+                self.visit_expr(&lambda.body, inner_depth);
+            }
+            ast::Expr::IfExp(expr_if) => {
+                self.visit_expr(&expr_if.test, depth);
+                self.visit_expr(&expr_if.body, depth);
+                self.visit_expr(&expr_if.orelse, depth);
+            }
+            ast::Expr::List(expr_list) => {
+                for elt in &expr_list.elts {
+                    self.visit_expr(elt, depth);
+                }
+            }
+            ast::Expr::Tuple(expr_tuple) => {
+                for elt in &expr_tuple.elts {
+                    self.visit_expr(elt, depth);
+                }
+            }
+            ast::Expr::Set(expr_set) => {
+                for elt in &expr_set.elts {
+                    self.visit_expr(elt, depth);
+                }
+            }
+            ast::Expr::Dict(expr_dict) => {
+                for key in expr_dict.keys.iter().flatten() {
+                    self.visit_expr(key, depth);
+                }
+                for value in &expr_dict.values {
+                    self.visit_expr(value, depth);
+                }
+            }
+            ast::Expr::ListComp(expr) => {
+                self.visit_expr(&expr.elt, depth);
+                self.visit_comprehension_generators(&expr.generators, depth);
+            }
+            ast::Expr::SetComp(expr) => {
+                self.visit_expr(&expr.elt, depth);
+                self.visit_comprehension_generators(&expr.generators, depth);
+            }
+            ast::Expr::DictComp(expr) => {
+                self.visit_expr(&expr.key, depth);
+                self.visit_expr(&expr.value, depth);
+                self.visit_comprehension_generators(&expr.generators, depth);
+            }
+            ast::Expr::GeneratorExp(expr) => {
+                self.visit_expr(&expr.elt, depth);
+                self.visit_comprehension_generators(&expr.generators, depth);
+            }
+            ast::Expr::Compare(expr_compare) => {
+                self.visit_expr(&expr_compare.left, depth);
+                for comp in &expr_compare.comparators {
+                    self.visit_expr(comp, depth);
+                }
+            }
+            ast::Expr::Call(expr_call) => {
+                self.looks_up_names |= takes_name_strings(&expr_call.func);
+                self.exposes_locals |= is_bare_locals_call(expr_call);
+                self.visit_expr(&expr_call.func, depth);
+                for arg in &expr_call.args {
+                    self.visit_expr(arg, depth);
+                }
+                for keyword in &expr_call.keywords {
+                    self.visit_expr(&keyword.value, depth);
+                }
+            }
+            ast::Expr::Attribute(expr_attr) => {
+                if let ast::Expr::Name(base) = expr_attr.value.as_ref() {
+                    self.attributes
+                        .entry(base.id.to_string())
+                        .or_default()
+                        .insert(expr_attr.attr.to_string());
+                }
+                self.visit_expr(&expr_attr.value, depth);
+            }
+            ast::Expr::Subscript(expr_sub) => {
+                self.looks_up_names |= is_namespace_dict(&expr_sub.value);
+                self.visit_expr(&expr_sub.value, depth);
+                self.visit_expr(&expr_sub.slice, depth);
+            }
+            ast::Expr::Starred(expr_star) => {
+                self.visit_expr(&expr_star.value, depth);
+            }
+            ast::Expr::Await(expr_await) => self.visit_expr(&expr_await.value, depth),
+            ast::Expr::Yield(expr_yield) => {
+                if let Some(value) = &expr_yield.value {
+                    self.visit_expr(value, depth);
+                }
+            }
+            ast::Expr::YieldFrom(expr_yield) => self.visit_expr(&expr_yield.value, depth),
+            ast::Expr::Constant(ast::ExprConstant {
+                value: ast::Constant::Str(value),
+                ..
+            }) => {
+                if is_valid_identifier(value) {
+                    self.strings.insert(value.clone());
+                }
+            }
+            ast::Expr::Constant(_) => {}
+            ast::Expr::Slice(slice) => self.visit_slice(slice, depth),
+            ast::Expr::JoinedStr(joined) => {
+                for value in &joined.values {
+                    self.visit_expr(value, depth);
+                }
+            }
+            ast::Expr::FormattedValue(formatted) => {
+                self.visit_expr(&formatted.value, depth);
+                if let Some(format_spec) = &formatted.format_spec {
+                    self.visit_expr(format_spec, depth);
+                }
+            }
+            ast::Expr::NamedExpr(named) => {
+                self.visit_expr(&named.target, depth);
+                self.visit_expr(&named.value, depth);
+            }
         }
     }
 
-    for stmt in suite {
-        match stmt {
-            ast::Stmt::FunctionDef(func) => collect_docstrings_in_suite(source, &func.body, ranges),
-            ast::Stmt::AsyncFunctionDef(func) => {
-                collect_docstrings_in_suite(source, &func.body, ranges)
-            }
-            ast::Stmt::ClassDef(class_def) => {
-                collect_docstrings_in_suite(source, &class_def.body, ranges)
+    fn visit_comprehension_generators(&mut self, generators: &[ast::Comprehension], depth: usize) {
+        for generator in generators {
+            self.visit_expr(&generator.iter, depth);
+            self.visit_expr(&generator.target, depth);
+            for condition in &generator.ifs {
+                self.visit_expr(condition, depth);
             }
-            _ => {}
         }
     }
-}
 
-fn is_docstring_stmt(stmt: &ast::Stmt) -> bool {
-    match stmt {
-        ast::Stmt::Expr(ast::StmtExpr { value, .. }) => {
-            matches!(
-                value.as_ref(),
-                ast::Expr::Constant(ast::ExprConstant {
-                    value: ast::Constant::Str { .. },
-                    ..
-                })
-            )
+    fn visit_slice(&mut self, slice: &ast::ExprSlice, depth: usize) {
+        if let Some(lower) = &slice.lower {
+            self.visit_expr(lower, depth);
+        }
+        if let Some(upper) = &slice.upper {
+            self.visit_expr(upper, depth);
+        }
+        if let Some(step) = &slice.step {
+            self.visit_expr(step, depth);
         }
-        _ => false,
     }
 }
 
-fn docstring_range(source: &str, stmt: &ast::Stmt) -> (usize, usize) {
-    let text_range = stmt.range();
-    let mut start = usize::from(text_range.start());
-    let mut end = usize::from(text_range.end());
-
-    let bytes = source.as_bytes();
+fn collect_used_names_in_function(func: &ast::StmtFunctionDef, depth: usize) -> HashSet<String> {
+    let mut collector = UsedNameCollector::default();
+    for decorator in &func.decorator_list {
+        collector.visit_expr(decorator, depth);
+    }
+    collector.visit_arguments(&func.args, depth);
+    if let Some(returns) = &func.returns {
+        collector.visit_expr(returns, depth);
+    }
+    collector.visit_suite(&func.body, depth);
+    collector.into_names()
+}
 
-    while start > 0 {
-        let previous = bytes[start - 1];
-        if previous == b' ' || previous == b'\t' {
-            start -= 1;
-            continue;
-        }
-        if previous == b'\n' || previous == b'\r' {
-            break;
-        }
-        break;
+fn collect_used_names_in_async_function(
+    func: &ast::StmtAsyncFunctionDef,
+    depth: usize,
+) -> HashSet<String> {
+    let mut collector = UsedNameCollector::default();
+    for decorator in &func.decorator_list {
+        collector.visit_expr(decorator, depth);
+    }
+    collector.visit_arguments(&func.args, depth);
+    if let Some(returns) = &func.returns {
+        collector.visit_expr(returns, depth);
     }
+    collector.visit_suite(&func.body, depth);
+    collector.into_names()
+}
 
-    while end < bytes.len() {
-        let current = bytes[end];
-        if current == b' ' || current == b'\t' {
-            end += 1;
-            continue;
-        }
-        if current == b'\r' {
-            end += 1;
-            if end < bytes.len() && bytes[end] == b'\n' {
-                end += 1;
-            }
-            break;
-        }
-        if current == b'\n' {
-            end += 1;
-            break;
-        }
-        break;
+/// Names read in a function's decorators and parameter defaults. Both are evaluated in the
+/// enclosing scope when the `def` runs, so they must keep their meaning even when the body
+/// binds a local of the same name.
+fn collect_signature_names(args: &ast::Arguments, decorators: &[ast::Expr]) -> HashSet<String> {
+    let mut collector = UsedNameCollector::default();
+    for decorator in decorators {
+        collector.visit_expr(decorator, usize::MAX);
+    }
+    let defaults = args
+        .posonlyargs
+        .iter()
+        .chain(&args.args)
+        .chain(&args.kwonlyargs)
+        .filter_map(|param| param.default.as_deref());
+    for default in defaults {
+        collector.visit_expr(default, usize::MAX);
     }
+    collector.into_names()
+}
 
-    (start, end)
+/// Default values of a signature's parameters, which are evaluated in the enclosing scope.
+fn parameter_defaults(args: &ast::Arguments) -> impl Iterator<Item = &ast::Expr> {
+    args.posonlyargs
+        .iter()
+        .chain(&args.args)
+        .chain(&args.kwonlyargs)
+        .filter_map(|param| param.default.as_deref())
 }
 
-struct OccurrenceCollector<'a> {
-    source: &'a str,
-    function_range: &'a FunctionRange,
-    renames: HashMap<&'a str, &'a str>,
-    excluded: HashSet<&'a str>,
-    replacements: Vec<Replacement>,
-    in_annotation: bool,
-    abort: bool,
+/// Names a lambda's body reads other than its own parameters. Any that are locals of the
+/// enclosing function are captured from it.
+fn lambda_free_names(lambda: &ast::ExprLambda) -> HashSet<String> {
+    let mut collector = UsedNameCollector::default();
+    collector.visit_expr(&lambda.body, usize::MAX);
+    let mut names = collector.into_names();
+    let args = &lambda.args;
+    let params = args
+        .posonlyargs
+        .iter()
+        .chain(&args.args)
+        .chain(&args.kwonlyargs)
+        .map(|param| &param.def)
+        .chain(args.vararg.as_deref())
+        .chain(args.kwarg.as_deref());
+    for param in params {
+        names.remove(param.arg.as_str());
+    }
+    names
 }
 
-impl<'a> OccurrenceCollector<'a> {
-    fn new(
-        source: &'a str,
-        function_range: &'a FunctionRange,
-        renames: HashMap<&'a str, &'a str>,
-        excluded: HashSet<&'a str>,
-    ) -> Self {
-        Self {
-            source,
-            function_range,
-            renames,
-            excluded,
-            replacements: Vec::new(),
-            in_annotation: false,
-            abort: false,
-        }
+/// Collect every name read in a function signature and body, including nested scopes,
+/// along with the attribute names accessed on each bare name and the names spelled in
+/// string literals that the function may look up dynamically.
+fn collect_used_names_in_body(
+    args: &ast::Arguments,
+    returns: Option<&ast::Expr>,
+    body: &[ast::Stmt],
+) -> UsedNames {
+    let mut collector = UsedNameCollector::default();
+    collector.visit_arguments(args, usize::MAX);
+    if let Some(returns) = returns {
+        collector.visit_expr(returns, usize::MAX);
+    }
+    collector.visit_suite(body, usize::MAX);
+    let looked_up = if collector.looks_up_names {
+        collector.strings
+    } else {
+        HashSet::new()
+    };
+    UsedNames {
+        names: collector.names,
+        attributes: collector.attributes,
+        looked_up,
+        exposes_locals: collector.exposes_locals,
     }
+}
 
-    fn with_annotation<F>(&mut self, visitor: F)
-    where
-        F: FnOnce(&mut Self),
-    {
-        let previous = self.in_annotation;
-        self.in_annotation = true;
-        visitor(self);
-        self.in_annotation = previous;
+/// `locals()` or `vars()` with no arguments, whose result maps every local's name to it.
+fn is_bare_locals_call(call: &ast::ExprCall) -> bool {
+    call.args.is_empty()
+        && call.keywords.is_empty()
+        && matches!(
+            call.func.as_ref(),
+            ast::Expr::Name(ast::ExprName { id, .. }) if matches!(id.as_str(), "vars" | "locals")
+        )
+}
+
+/// Callables that take an attribute name as a string.
+const NAME_STRING_FUNCTIONS: &[&str] = &["getattr", "setattr", "delattr", "hasattr", "attrgetter"];
+
+/// `getattr(...)`, `operator.attrgetter(...)`, and the like.
+fn takes_name_strings(func: &ast::Expr) -> bool {
+    let name = match func {
+        ast::Expr::Name(ast::ExprName { id, .. }) => id.as_str(),
+        ast::Expr::Attribute(ast::ExprAttribute { attr, .. }) => attr.as_str(),
+        _ => return false,
+    };
+    NAME_STRING_FUNCTIONS.contains(&name)
+}
+
+/// `vars()`, `locals()`, or `obj.__dict__`, whose keys are names.
+fn is_namespace_dict(expr: &ast::Expr) -> bool {
+    match expr {
+        ast::Expr::Call(ast::ExprCall { func, .. }) => matches!(
+            func.as_ref(),
+            ast::Expr::Name(ast::ExprName { id, .. }) if matches!(id.as_str(), "vars" | "locals")
+        ),
+        ast::Expr::Attribute(ast::ExprAttribute { attr, .. }) => attr.as_str() == "__dict__",
+        _ => false,
     }
+}
 
-    fn visit_arguments(&mut self, args: &ast::Arguments) {
-        for param in &args.posonlyargs {
-            self.record_arg(&param.def);
-            if let Some(default) = &param.default {
-                self.visit_expr(default);
-            }
+/// Names that reach module globals without spelling them out; a module using any of them
+/// keeps its private names.
+const DYNAMIC_SCOPE_NAMES: &[&str] = &["globals", "locals", "vars", "eval", "exec", "__getattr__"];
+
+/// Private by convention: a single leading underscore. Double-underscore names are left
+/// alone because class bodies mangle them.
+fn is_module_private(name: &str) -> bool {
+    name.len() > 1 && name.starts_with('_') && !name.starts_with("__")
+}
+
+/// Short `_`-prefixed replacements for the module's private names that can be renamed at
+/// every reference, in source order.
+fn plan_module_renames(
+    suite: &[ast::Stmt],
+    source: &str,
+    naming: NamingMode,
+    naming_config: &NamingConfig,
+) -> Vec<RenameEntry> {
+    let candidates = module_private_candidates(suite);
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+    let names: HashSet<&str> = candidates.iter().map(String::as_str).collect();
+    let sites = module_private_sites(suite, source, &names);
+
+    // No target may collide with any word in the module, even in strings or comments, and
+    // names whose word would be reserved as a local stay reserved behind the prefix too.
+    let naming_config = naming_config.module_private();
+    let mut reserved: HashSet<String> = default_reserved()
+        .into_iter()
+        .chain(
+            PYTHON_BUILTINS
+                .iter()
+                .chain(PYTHON_SOFT_KEYWORDS)
+                .map(std::string::ToString::to_string),
+        )
+        .map(|name| format!("{}{name}", naming_config.prefix))
+        .collect();
+    reserved.extend(identifiers_in_text(source).map(str::to_string));
+    let mut generator = ShortNameGenerator::new(reserved, &naming_config);
+
+    candidates
+        .into_iter()
+        .filter(|original| sites.contains_key(original))
+        .filter_map(|original| {
+            let renamed = match naming {
+                NamingMode::Sequential => generator.next(),
+                NamingMode::Stable => generator.stable("", &original),
+            };
+            (renamed.len() < original.len()).then_some(RenameEntry {
+                original,
+                renamed,
+                occurrences: 0,
+            })
+        })
+        .collect()
+}
+
+/// Private functions, classes, and plainly assigned names bound by top-level statements.
+fn module_private_candidates(suite: &[ast::Stmt]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut push = |name: &str| {
+        if is_module_private(name) && !names.iter().any(|known| known == name) {
+            names.push(name.to_string());
         }
-        for param in &args.args {
-            self.record_arg(&param.def);
-            if let Some(default) = &param.default {
-                self.visit_expr(default);
+    };
+    for stmt in suite {
+        match stmt {
+            ast::Stmt::FunctionDef(func) => push(func.name.as_ref()),
+            ast::Stmt::AsyncFunctionDef(func) => push(func.name.as_ref()),
+            ast::Stmt::ClassDef(class_def) => push(class_def.name.as_ref()),
+            ast::Stmt::Assign(assign) => {
+                for target in &assign.targets {
+                    if let ast::Expr::Name(name) = target {
+                        push(name.id.as_ref());
+                    }
+                }
             }
-        }
-        if let Some(vararg) = &args.vararg {
-            self.record_arg(vararg);
-        }
-        for param in &args.kwonlyargs {
-            self.record_arg(&param.def);
-            if let Some(default) = &param.default {
-                self.visit_expr(default);
+            ast::Stmt::AnnAssign(assign) => {
+                if let ast::Expr::Name(name) = assign.target.as_ref() {
+                    push(name.id.as_ref());
+                }
             }
+            _ => {}
         }
-        if let Some(kwarg) = &args.kwarg {
-            self.record_arg(kwarg);
+    }
+    names
+}
+
+/// Byte ranges of every occurrence of each name in `names` that is safe to rename across
+/// the whole module; unsafe names are left out.
+///
+/// A name is safe when each token spelling it is a name expression or the name of a
+/// top-level `def`/`class`, it is only assigned by top-level statements, and no string in
+/// the module mentions it (which covers `__all__`, `getattr`, and f-strings). Parameters,
+/// imports, `global` declarations, attributes, and keyword arguments spelled the same way
+/// all make it unsafe.
+fn module_private_sites(
+    suite: &[ast::Stmt],
+    source: &str,
+    names: &HashSet<&str>,
+) -> HashMap<String, Vec<(usize, usize)>> {
+    let mut token_counts: HashMap<String, usize> = HashMap::new();
+    let mut mentioned: HashSet<String> = HashSet::new();
+    for (token, _) in lexer::lex(source, Mode::Module).map_while(|result| result.ok()) {
+        match token {
+            Tok::Name { name } => {
+                if DYNAMIC_SCOPE_NAMES.contains(&name.as_str()) {
+                    return HashMap::new();
+                }
+                if names.contains(name.as_str()) {
+                    *token_counts.entry(name).or_default() += 1;
+                }
+            }
+            Tok::String { value, .. } => {
+                mentioned.extend(
+                    identifiers_in_text(&value)
+                        .filter(|word| names.contains(word))
+                        .map(str::to_string),
+                );
+            }
+            _ => {}
         }
     }
 
-    fn visit_statements(&mut self, stmts: &[ast::Stmt]) {
-        for stmt in stmts {
-            self.visit_stmt(stmt);
-            if self.abort {
-                return;
+    let mut collector = UsedNameCollector {
+        tracked: names.iter().map(|name| (*name).to_string()).collect(),
+        ..UsedNameCollector::default()
+    };
+    collector.visit_suite(suite, usize::MAX);
+
+    let mut sites: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    let mut unsafe_names: HashSet<String> = HashSet::new();
+    let mut allowed_stores: HashSet<usize> = HashSet::new();
+    for stmt in suite {
+        let (name, decorators, range) = match stmt {
+            ast::Stmt::FunctionDef(func) => {
+                (&func.name, &func.decorator_list, range_from_node(func))
+            }
+            ast::Stmt::AsyncFunctionDef(func) => {
+                (&func.name, &func.decorator_list, range_from_node(func))
+            }
+            ast::Stmt::ClassDef(class_def) => (
+                &class_def.name,
+                &class_def.decorator_list,
+                range_from_node(class_def),
+            ),
+            ast::Stmt::Assign(assign) => {
+                allowed_stores.extend(
+                    assign
+                        .targets
+                        .iter()
+                        .map(|target| range_from_node(target).start),
+                );
+                continue;
+            }
+            ast::Stmt::AnnAssign(assign) => {
+                allowed_stores.insert(range_from_node(assign.target.as_ref()).start);
+                continue;
+            }
+            ast::Stmt::AugAssign(assign) => {
+                allowed_stores.insert(range_from_node(assign.target.as_ref()).start);
+                continue;
+            }
+            _ => continue,
+        };
+        let name: &str = name.as_ref();
+        if !names.contains(name) {
+            continue;
+        }
+        match find_identifier_in_range(source, &header_range(range, decorators), name) {
+            Some(site) => sites.entry(name.to_string()).or_default().push(site),
+            None => {
+                unsafe_names.insert(name.to_string());
             }
         }
     }
 
-    #[allow(clippy::too_many_lines)]
-    fn visit_stmt(&mut self, stmt: &ast::Stmt) {
-        if self.abort {
-            return;
+    for (name, start, end, stored) in collector.sites {
+        if stored && !allowed_stores.contains(&start) {
+            unsafe_names.insert(name.clone());
         }
+        sites.entry(name).or_default().push((start, end));
+    }
+
+    sites.retain(|name, ranges| {
+        !unsafe_names.contains(name)
+            && !mentioned.contains(name)
+            && token_counts.get(name) == Some(&ranges.len())
+            && ranges
+                .iter()
+                .all(|&(start, end)| source.get(start..end) == Some(name.as_str()))
+    });
+    sites
+}
+
+/// Collect every name bound at module level, descending into compound statements but
+/// not into function or class bodies.
+///
+/// Conditional bindings count too: imports under `if TYPE_CHECKING:` or inside
+/// `try`/`except ImportError`, `match` captures, walrus targets, and names any function
+/// declares `global`. Functions may read any of them, so every plan reserves them.
+fn collect_module_bindings(suite: &[ast::Stmt]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    collect_module_bindings_into(suite, &mut names);
+    names
+}
 
+fn collect_module_bindings_into(suite: &[ast::Stmt], names: &mut HashSet<String>) {
+    for stmt in suite {
         match stmt {
             ast::Stmt::FunctionDef(func) => {
-                let range = range_from_node(func);
-                if let Some((start, end)) =
-                    find_identifier_in_range(self.source, &range, func.name.as_ref())
-                {
-                    let name_range = FunctionRange { start, end };
-                    self.record_identifier(func.name.as_ref(), name_range);
-                } else {
-                    self.abort = true;
-                }
-                // Skip body; handled in its own plan.
+                names.insert(func.name.to_string());
+                collect_global_declarations(&func.body, names);
             }
             ast::Stmt::AsyncFunctionDef(func) => {
-                let range = range_from_node(func);
-                if let Some((start, end)) =
-                    find_identifier_in_range(self.source, &range, func.name.as_ref())
-                {
-                    let name_range = FunctionRange { start, end };
-                    self.record_identifier(func.name.as_ref(), name_range);
-                } else {
-                    self.abort = true;
-                }
+                names.insert(func.name.to_string());
+                collect_global_declarations(&func.body, names);
             }
             ast::Stmt::ClassDef(class_def) => {
-                let range = range_from_node(class_def);
-                if let Some((start, end)) =
-                    find_identifier_in_range(self.source, &range, class_def.name.as_ref())
-                {
-                    let name_range = FunctionRange { start, end };
-                    self.record_identifier(class_def.name.as_ref(), name_range);
-                } else {
-                    self.abort = true;
-                }
-            }
-            ast::Stmt::Return(ret) => {
-                if let Some(value) = &ret.value {
-                    self.visit_expr(value);
-                }
+                names.insert(class_def.name.to_string());
+                collect_global_declarations(&class_def.body, names);
             }
+            ast::Stmt::Expr(expr_stmt) => collect_walrus_targets(&expr_stmt.value, names),
             ast::Stmt::Assign(assign) => {
                 for target in &assign.targets {
-                    self.visit_expr(target);
-                }
-                self.visit_expr(&assign.value);
-            }
-            ast::Stmt::AnnAssign(assign) => {
-                self.visit_expr(&assign.target);
-                if let Some(value) = &assign.value {
-                    self.visit_expr(value);
+                    collect_target_names(target, names);
                 }
-                self.with_annotation(|collector| collector.visit_expr(&assign.annotation));
+                collect_walrus_targets(&assign.value, names);
             }
-            ast::Stmt::AugAssign(assign) => {
-                self.visit_expr(&assign.target);
-                self.visit_expr(&assign.value);
+            ast::Stmt::AnnAssign(assign) => collect_target_names(&assign.target, names),
+            ast::Stmt::AugAssign(assign) => collect_target_names(&assign.target, names),
+            ast::Stmt::For(for_stmt) => {
+                collect_target_names(&for_stmt.target, names);
+                collect_module_bindings_into(&for_stmt.body, names);
+                collect_module_bindings_into(&for_stmt.orelse, names);
             }
-            ast::Stmt::For(stmt_for) => {
-                self.visit_expr(&stmt_for.target);
-                self.visit_expr(&stmt_for.iter);
-                self.visit_statements(&stmt_for.body);
-                self.visit_statements(&stmt_for.orelse);
+            ast::Stmt::AsyncFor(for_stmt) => {
+                collect_target_names(&for_stmt.target, names);
+                collect_module_bindings_into(&for_stmt.body, names);
+                collect_module_bindings_into(&for_stmt.orelse, names);
             }
-            ast::Stmt::AsyncFor(stmt_for) => {
-                self.visit_expr(&stmt_for.target);
-                self.visit_expr(&stmt_for.iter);
-                self.visit_statements(&stmt_for.body);
-                self.visit_statements(&stmt_for.orelse);
+            ast::Stmt::While(while_stmt) => {
+                collect_walrus_targets(&while_stmt.test, names);
+                collect_module_bindings_into(&while_stmt.body, names);
+                collect_module_bindings_into(&while_stmt.orelse, names);
             }
-            ast::Stmt::While(stmt_while) => {
-                self.visit_expr(&stmt_while.test);
-                self.visit_statements(&stmt_while.body);
-                self.visit_statements(&stmt_while.orelse);
+            ast::Stmt::If(if_stmt) => {
+                collect_walrus_targets(&if_stmt.test, names);
+                collect_module_bindings_into(&if_stmt.body, names);
+                collect_module_bindings_into(&if_stmt.orelse, names);
             }
-            ast::Stmt::If(stmt_if) => {
-                self.visit_expr(&stmt_if.test);
-                self.visit_statements(&stmt_if.body);
-                self.visit_statements(&stmt_if.orelse);
+            ast::Stmt::Match(match_stmt) => {
+                collect_walrus_targets(&match_stmt.subject, names);
+                for case in &match_stmt.cases {
+                    collect_pattern_names(&case.pattern, names);
+                    collect_module_bindings_into(&case.body, names);
+                }
             }
-            ast::Stmt::With(stmt_with) => {
-                for item in &stmt_with.items {
-                    self.visit_expr(&item.context_expr);
+            ast::Stmt::With(with_stmt) => {
+                for item in &with_stmt.items {
                     if let Some(optional) = &item.optional_vars {
-                        self.visit_expr(optional);
+                        collect_target_names(optional, names);
                     }
                 }
-                self.visit_statements(&stmt_with.body);
+                collect_module_bindings_into(&with_stmt.body, names);
             }
-            ast::Stmt::AsyncWith(stmt_with) => {
-                for item in &stmt_with.items {
-                    self.visit_expr(&item.context_expr);
+            ast::Stmt::AsyncWith(with_stmt) => {
+                for item in &with_stmt.items {
                     if let Some(optional) = &item.optional_vars {
-                        self.visit_expr(optional);
+                        collect_target_names(optional, names);
                     }
                 }
-                self.visit_statements(&stmt_with.body);
-            }
-            ast::Stmt::Expr(expr_stmt) => {
-                self.visit_expr(&expr_stmt.value);
+                collect_module_bindings_into(&with_stmt.body, names);
             }
-            ast::Stmt::Try(stmt_try) => {
-                self.visit_statements(&stmt_try.body);
-                self.visit_statements(&stmt_try.orelse);
-                self.visit_statements(&stmt_try.finalbody);
-                for handler in &stmt_try.handlers {
-                    self.visit_except_handler(handler);
+            ast::Stmt::Try(try_stmt) => {
+                collect_module_bindings_into(&try_stmt.body, names);
+                collect_module_bindings_into(&try_stmt.orelse, names);
+                collect_module_bindings_into(&try_stmt.finalbody, names);
+                for handler in &try_stmt.handlers {
+                    let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                    if let Some(name) = &handler.name {
+                        names.insert(name.to_string());
+                    }
+                    collect_module_bindings_into(&handler.body, names);
                 }
             }
-            ast::Stmt::TryStar(stmt_try) => {
-                self.visit_statements(&stmt_try.body);
-                self.visit_statements(&stmt_try.orelse);
-                self.visit_statements(&stmt_try.finalbody);
-                for handler in &stmt_try.handlers {
-                    self.visit_except_handler(handler);
+            ast::Stmt::TryStar(try_stmt) => {
+                collect_module_bindings_into(&try_stmt.body, names);
+                collect_module_bindings_into(&try_stmt.orelse, names);
+                collect_module_bindings_into(&try_stmt.finalbody, names);
+                for handler in &try_stmt.handlers {
+                    let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                    if let Some(name) = &handler.name {
+                        names.insert(name.to_string());
+                    }
+                    collect_module_bindings_into(&handler.body, names);
                 }
             }
-            ast::Stmt::Raise(stmt_raise) => {
-                if let Some(exc) = &stmt_raise.exc {
-                    self.visit_expr(exc);
-                }
-                if let Some(cause) = &stmt_raise.cause {
-                    self.visit_expr(cause);
+            ast::Stmt::Import(import_stmt) => {
+                for alias in &import_stmt.names {
+                    if let Some(asname) = &alias.asname {
+                        names.insert(asname.to_string());
+                    } else {
+                        let module = alias.name.to_string();
+                        let base = module.split('.').next().unwrap_or(&module);
+                        names.insert(base.to_string());
+                    }
                 }
             }
-            ast::Stmt::Assert(stmt_assert) => {
-                self.visit_expr(&stmt_assert.test);
-                if let Some(msg) = &stmt_assert.msg {
-                    self.visit_expr(msg);
+            ast::Stmt::ImportFrom(import_from) => {
+                for alias in &import_from.names {
+                    let name = alias.asname.as_ref().unwrap_or(&alias.name).to_string();
+                    if name != "*" {
+                        names.insert(name);
+                    }
                 }
             }
-            ast::Stmt::Delete(stmt_delete) => {
-                for target in &stmt_delete.targets {
-                    self.visit_expr(target);
+            _ => {}
+        }
+    }
+}
+
+/// Names declared `global` anywhere in a function or class body, nested scopes included.
+fn collect_global_declarations(suite: &[ast::Stmt], names: &mut HashSet<String>) {
+    for stmt in suite {
+        match stmt {
+            ast::Stmt::Global(glob) => {
+                names.extend(glob.names.iter().map(ToString::to_string));
+            }
+            ast::Stmt::FunctionDef(func) => collect_global_declarations(&func.body, names),
+            ast::Stmt::AsyncFunctionDef(func) => collect_global_declarations(&func.body, names),
+            ast::Stmt::ClassDef(class_def) => collect_global_declarations(&class_def.body, names),
+            ast::Stmt::For(for_stmt) => {
+                collect_global_declarations(&for_stmt.body, names);
+                collect_global_declarations(&for_stmt.orelse, names);
+            }
+            ast::Stmt::AsyncFor(for_stmt) => {
+                collect_global_declarations(&for_stmt.body, names);
+                collect_global_declarations(&for_stmt.orelse, names);
+            }
+            ast::Stmt::While(while_stmt) => {
+                collect_global_declarations(&while_stmt.body, names);
+                collect_global_declarations(&while_stmt.orelse, names);
+            }
+            ast::Stmt::If(if_stmt) => {
+                collect_global_declarations(&if_stmt.body, names);
+                collect_global_declarations(&if_stmt.orelse, names);
+            }
+            ast::Stmt::With(with_stmt) => collect_global_declarations(&with_stmt.body, names),
+            ast::Stmt::AsyncWith(with_stmt) => collect_global_declarations(&with_stmt.body, names),
+            ast::Stmt::Try(try_stmt) => {
+                collect_global_declarations(&try_stmt.body, names);
+                collect_global_declarations(&try_stmt.orelse, names);
+                collect_global_declarations(&try_stmt.finalbody, names);
+                for handler in &try_stmt.handlers {
+                    let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                    collect_global_declarations(&handler.body, names);
                 }
             }
-            ast::Stmt::TypeAlias(type_alias) => {
-                self.with_annotation(|collector| collector.visit_expr(&type_alias.value));
+            ast::Stmt::TryStar(try_stmt) => {
+                collect_global_declarations(&try_stmt.body, names);
+                collect_global_declarations(&try_stmt.orelse, names);
+                collect_global_declarations(&try_stmt.finalbody, names);
+                for handler in &try_stmt.handlers {
+                    let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                    collect_global_declarations(&handler.body, names);
+                }
             }
-            ast::Stmt::Match(_) | ast::Stmt::Import(_) | ast::Stmt::ImportFrom(_) => {
-                // Imports introduce bindings; record alias targets conservatively.
-                self.visit_import(stmt);
+            ast::Stmt::Match(match_stmt) => {
+                for case in &match_stmt.cases {
+                    collect_global_declarations(&case.body, names);
+                }
             }
             _ => {}
         }
     }
+}
 
-    fn visit_import(&mut self, stmt: &ast::Stmt) {
-        if self.abort {
-            return;
+/// Walrus targets in a module-level expression; outside a function they bind module names,
+/// even from inside a comprehension.
+fn collect_walrus_targets(expr: &ast::Expr, names: &mut HashSet<String>) {
+    match expr {
+        ast::Expr::NamedExpr(named) => {
+            collect_target_names(&named.target, names);
+            collect_walrus_targets(&named.value, names);
         }
-
-        match stmt {
-            ast::Stmt::Import(import_stmt) => {
-                for alias in &import_stmt.names {
-                    let full_name = alias.name.to_string();
-                    let binding = alias
-                        .asname
-                        .as_ref()
-                        .map(std::string::ToString::to_string)
-                        .unwrap_or_else(|| {
-                            full_name
-                                .split('.')
-                                .next()
-                                .unwrap_or(&full_name)
-                                .to_string()
-                        });
-
-                    if alias.asname.is_some() {
-                        continue;
-                    }
-
-                    if let Some(new_name) = self.renames.get(binding.as_str()) {
-                        if binding != *new_name {
-                            let range = range_from_node(alias);
-                            if !full_name.contains('.') {
-                                let replacement = format!("{full_name} as {new_name}");
-                                self.replacements.push(Replacement {
-                                    start: range.start,
-                                    end: range.end,
-                                    text: replacement,
-                                });
-                            }
-                        }
-                    }
-                }
-            }
-            ast::Stmt::ImportFrom(import_from) => {
-                for alias in &import_from.names {
-                    if alias.name.to_string().as_str() == "*" {
-                        continue;
-                    }
-                    let binding = alias.asname.as_ref().map_or_else(
-                        || {
-                            let full = alias.name.to_string();
-                            full.split('.')
-                                .next()
-                                .map(std::string::ToString::to_string)
-                                .unwrap_or(full)
-                        },
-                        std::string::ToString::to_string,
-                    );
-
-                    if alias.asname.is_some() {
-                        continue;
-                    }
-
-                    if let Some(new_name) = self.renames.get(binding.as_str()) {
-                        if binding != *new_name {
-                            let range = range_from_node(alias);
-                            let module_text = alias.name.to_string();
-                            let replacement = format!("{module_text} as {new_name}");
-                            self.replacements.push(Replacement {
-                                start: range.start,
-                                end: range.end,
-                                text: replacement,
-                            });
-                        }
-                    }
-                }
+        ast::Expr::BoolOp(ast::ExprBoolOp { values, .. })
+        | ast::Expr::Tuple(ast::ExprTuple { elts: values, .. })
+        | ast::Expr::List(ast::ExprList { elts: values, .. })
+        | ast::Expr::Set(ast::ExprSet { elts: values, .. }) => {
+            for value in values {
+                collect_walrus_targets(value, names);
             }
-            _ => {}
         }
-    }
-
-    fn visit_except_handler(&mut self, handler: &ast::ExceptHandler) {
-        if self.abort {
-            return;
+        ast::Expr::IfExp(ast::ExprIfExp {
+            test, body, orelse, ..
+        }) => {
+            collect_walrus_targets(test, names);
+            collect_walrus_targets(body, names);
+            collect_walrus_targets(orelse, names);
         }
-
-        match handler {
-            ast::ExceptHandler::ExceptHandler(ex_handler) => {
-                if let Some(type_) = &ex_handler.type_ {
-                    self.visit_expr(type_);
-                }
-                if let Some(name) = &ex_handler.name {
-                    self.record_except_name(ex_handler, name.as_ref());
-                }
-                self.visit_statements(&ex_handler.body);
+        ast::Expr::Compare(ast::ExprCompare {
+            left, comparators, ..
+        }) => {
+            collect_walrus_targets(left, names);
+            for comparator in comparators {
+                collect_walrus_targets(comparator, names);
             }
         }
-    }
-
-    #[allow(clippy::too_many_lines)]
-    fn visit_expr(&mut self, expr: &ast::Expr) {
-        if self.abort {
-            return;
+        ast::Expr::BinOp(ast::ExprBinOp { left, right, .. }) => {
+            collect_walrus_targets(left, names);
+            collect_walrus_targets(right, names);
         }
-
-        match expr {
-            ast::Expr::Name(expr_name) => {
-                let range = range_from_node(expr_name);
-                self.record_identifier(expr_name.id.as_ref(), range);
+        ast::Expr::UnaryOp(ast::ExprUnaryOp { operand, .. }) => {
+            collect_walrus_targets(operand, names);
+        }
+        ast::Expr::Call(ast::ExprCall {
+            func,
+            args,
+            keywords,
+            ..
+        }) => {
+            collect_walrus_targets(func, names);
+            for arg in args {
+                collect_walrus_targets(arg, names);
+            }
+            for keyword in keywords {
+                collect_walrus_targets(&keyword.value, names);
             }
-            ast::Expr::BoolOp(expr_bool) => {
-                for value in &expr_bool.values {
-                    self.visit_expr(value);
+        }
+        ast::Expr::ListComp(ast::ExprListComp {
+            elt, generators, ..
+        })
+        | ast::Expr::SetComp(ast::ExprSetComp {
+            elt, generators, ..
+        })
+        | ast::Expr::GeneratorExp(ast::ExprGeneratorExp {
+            elt, generators, ..
+        }) => {
+            collect_walrus_targets(elt, names);
+            for generator in generators {
+                for condition in &generator.ifs {
+                    collect_walrus_targets(condition, names);
                 }
             }
-            ast::Expr::BinOp(expr_bin) => {
-                self.visit_expr(&expr_bin.left);
-                self.visit_expr(&expr_bin.right);
+        }
+        _ => {}
+    }
+}
+
+/// Capture names bound by a `match` pattern.
+fn collect_pattern_names(pattern: &ast::Pattern, names: &mut HashSet<String>) {
+    match pattern {
+        ast::Pattern::MatchAs(pat) => {
+            if let Some(sub) = &pat.pattern {
+                collect_pattern_names(sub, names);
             }
-            ast::Expr::UnaryOp(expr_unary) => {
-                self.visit_expr(&expr_unary.operand);
+            if let Some(name) = &pat.name {
+                names.insert(name.to_string());
             }
-            ast::Expr::Lambda(_) => {
-                self.abort = true;
+        }
+        ast::Pattern::MatchStar(pat) => {
+            if let Some(name) = &pat.name {
+                names.insert(name.to_string());
             }
-            ast::Expr::IfExp(expr_if) => {
-                self.visit_expr(&expr_if.test);
-                self.visit_expr(&expr_if.body);
-                self.visit_expr(&expr_if.orelse);
+        }
+        ast::Pattern::MatchSequence(seq) => {
+            for sub in &seq.patterns {
+                collect_pattern_names(sub, names);
             }
-            ast::Expr::List(expr_list) => {
-                for elt in &expr_list.elts {
-                    self.visit_expr(elt);
-                }
+        }
+        ast::Pattern::MatchMapping(map) => {
+            for sub in &map.patterns {
+                collect_pattern_names(sub, names);
             }
-            ast::Expr::Tuple(expr_tuple) => {
-                for elt in &expr_tuple.elts {
-                    self.visit_expr(elt);
-                }
+            if let Some(rest) = &map.rest {
+                names.insert(rest.to_string());
             }
-            ast::Expr::Set(expr_set) => {
-                for elt in &expr_set.elts {
-                    self.visit_expr(elt);
-                }
+        }
+        ast::Pattern::MatchClass(class) => {
+            for sub in class.patterns.iter().chain(&class.kwd_patterns) {
+                collect_pattern_names(sub, names);
             }
-            ast::Expr::Dict(expr_dict) => {
-                for key in expr_dict.keys.iter().flatten() {
-                    self.visit_expr(key);
-                }
-                for value in &expr_dict.values {
-                    self.visit_expr(value);
-                }
+        }
+        ast::Pattern::MatchOr(pat) => {
+            for sub in &pat.patterns {
+                collect_pattern_names(sub, names);
             }
-            ast::Expr::ListComp(expr) => {
-                self.visit_expr(&expr.elt);
-                self.visit_comprehension_generators(&expr.generators);
+        }
+        _ => {}
+    }
+}
+
+fn collect_target_names(expr: &ast::Expr, names: &mut HashSet<String>) {
+    match expr {
+        ast::Expr::Name(ast::ExprName { id, .. }) => {
+            names.insert(id.to_string());
+        }
+        ast::Expr::Tuple(ast::ExprTuple { elts, .. })
+        | ast::Expr::List(ast::ExprList { elts, .. }) => {
+            for elt in elts {
+                collect_target_names(elt, names);
             }
-            ast::Expr::SetComp(expr) => {
-                self.visit_expr(&expr.elt);
-                self.visit_comprehension_generators(&expr.generators);
+        }
+        ast::Expr::Starred(ast::ExprStarred { value, .. }) => collect_target_names(value, names),
+        _ => {}
+    }
+}
+
+fn collect_used_names_in_class(class_def: &ast::StmtClassDef, depth: usize) -> HashSet<String> {
+    let mut collector = UsedNameCollector::default();
+    for decorator in &class_def.decorator_list {
+        collector.visit_expr(decorator, depth);
+    }
+    for base in &class_def.bases {
+        collector.visit_expr(base, depth);
+    }
+    for keyword in &class_def.keywords {
+        collector.visit_expr(&keyword.value, depth);
+    }
+    collector.visit_suite(&class_def.body, depth);
+    collector.into_names()
+}
+
+struct FunctionCollector {
+    locals: Vec<String>,
+    seen: HashSet<String>,
+    excluded: HashSet<String>,
+    reserved: HashSet<String>,
+    declared_exclusions: HashSet<String>,
+    /// Names the generator must not issue (module bindings and every name read in the
+    /// function) without excluding them from renaming.
+    guarded: HashSet<String>,
+    /// Attribute names accessed on each name; those of renamed locals are never issued.
+    attributes: HashMap<String, HashSet<String>>,
+    has_nested_functions: bool,
+    has_imports: bool,
+    has_match_statement: bool,
+    has_comprehension: bool,
+    /// The body calls `locals()` or `vars()` without arguments; every local is kept.
+    exposes_locals: bool,
+    /// Names read in lambda bodies other than the lambdas' own parameters.
+    lambda_free_names: HashSet<String>,
+    /// Rename `T = TypeVar("T")` and the like instead of keeping them.
+    rename_typevars: bool,
+    /// The single name assigned the expression about to be collected.
+    assignment_target: Option<String>,
+    warnings: Vec<String>,
+}
+
+impl FunctionCollector {
+    fn new(reserved: HashSet<String>) -> Self {
+        Self {
+            locals: Vec::new(),
+            seen: HashSet::new(),
+            excluded: HashSet::new(),
+            reserved,
+            declared_exclusions: HashSet::new(),
+            guarded: HashSet::new(),
+            attributes: HashMap::new(),
+            has_nested_functions: false,
+            has_imports: false,
+            has_match_statement: false,
+            has_comprehension: false,
+            exposes_locals: false,
+            lambda_free_names: HashSet::new(),
+            rename_typevars: false,
+            assignment_target: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    fn collect_parameters(&mut self, args: &ast::Arguments) {
+        for param in &args.posonlyargs {
+            self.add_name(param.def.arg.as_ref());
+            if let Some(default) = &param.default {
+                self.collect_from_expression(default);
             }
-            ast::Expr::DictComp(expr) => {
-                self.visit_expr(&expr.key);
-                self.visit_expr(&expr.value);
-                self.visit_comprehension_generators(&expr.generators);
+        }
+        for param in &args.args {
+            self.add_name(param.def.arg.as_ref());
+            if let Some(default) = &param.default {
+                self.collect_from_expression(default);
             }
-            ast::Expr::GeneratorExp(expr) => {
-                self.visit_expr(&expr.elt);
-                self.visit_comprehension_generators(&expr.generators);
+        }
+        if let Some(vararg) = &args.vararg {
+            self.add_name(vararg.arg.as_ref());
+        }
+        for param in &args.kwonlyargs {
+            self.add_name(param.def.arg.as_ref());
+            if let Some(default) = &param.default {
+                self.collect_from_expression(default);
             }
-            ast::Expr::Await(expr_await) => {
-                self.visit_expr(&expr_await.value);
+        }
+        if let Some(kwarg) = &args.kwarg {
+            self.add_name(kwarg.arg.as_ref());
+        }
+
+        for default in args.defaults() {
+            self.collect_from_expression(default);
+        }
+
+        let params = args
+            .posonlyargs
+            .iter()
+            .chain(&args.args)
+            .chain(&args.kwonlyargs)
+            .map(|param| &param.def)
+            .chain(args.vararg.as_deref())
+            .chain(args.kwarg.as_deref());
+        for param in params {
+            if let Some(annotation) = &param.annotation {
+                self.reserve_names_in_string_annotations(annotation);
             }
-            ast::Expr::Yield(expr_yield) => {
-                if let Some(value) = &expr_yield.value {
-                    self.visit_expr(value);
+        }
+    }
+
+    fn reserve_parameters(&mut self, args: &ast::Arguments) {
+        let params = args
+            .posonlyargs
+            .iter()
+            .chain(&args.args)
+            .chain(&args.kwonlyargs)
+            .map(|param| &param.def)
+            .chain(args.vararg.as_deref())
+            .chain(args.kwarg.as_deref());
+        for param in params {
+            self.reserve_name(param.arg.as_ref());
+        }
+    }
+
+    /// Keep names mentioned in string (forward-reference) annotations, since those
+    /// strings may be evaluated later against the function's namespace.
+    fn reserve_names_in_string_annotations(&mut self, annotation: &ast::Expr) {
+        match annotation {
+            ast::Expr::Constant(ast::ExprConstant {
+                value: ast::Constant::Str(text),
+                ..
+            }) => {
+                for name in identifiers_in_text(text) {
+                    self.reserve_name(name);
                 }
             }
-            ast::Expr::YieldFrom(expr_yield) => {
-                self.visit_expr(&expr_yield.value);
-            }
-            ast::Expr::Compare(expr_compare) => {
-                self.visit_expr(&expr_compare.left);
-                for comp in &expr_compare.comparators {
-                    self.visit_expr(comp);
-                }
+            ast::Expr::Subscript(ast::ExprSubscript { value, slice, .. }) => {
+                self.reserve_names_in_string_annotations(value);
+                self.reserve_names_in_string_annotations(slice);
             }
-            ast::Expr::Call(expr_call) => {
-                self.visit_expr(&expr_call.func);
-                for arg in &expr_call.args {
-                    self.visit_expr(arg);
-                }
-                for keyword in &expr_call.keywords {
-                    self.visit_expr(&keyword.value);
+            ast::Expr::Tuple(ast::ExprTuple { elts, .. })
+            | ast::Expr::List(ast::ExprList { elts, .. }) => {
+                for elt in elts {
+                    self.reserve_names_in_string_annotations(elt);
                 }
             }
-            ast::Expr::Attribute(expr_attr) => {
-                self.visit_expr(&expr_attr.value);
-            }
-            ast::Expr::Subscript(expr_sub) => {
-                self.visit_expr(&expr_sub.value);
-                self.visit_expr(&expr_sub.slice);
-            }
-            ast::Expr::Starred(expr_star) => {
-                self.visit_expr(&expr_star.value);
-            }
-            ast::Expr::NamedExpr(expr_named) => {
-                self.visit_expr(&expr_named.target);
-                self.visit_expr(&expr_named.value);
+            ast::Expr::BinOp(ast::ExprBinOp { left, right, .. }) => {
+                self.reserve_names_in_string_annotations(left);
+                self.reserve_names_in_string_annotations(right);
             }
-            ast::Expr::Slice(expr_slice) => {
-                if let Some(lower) = &expr_slice.lower {
-                    self.visit_expr(lower);
+            _ => {}
+        }
+    }
+
+    fn record_exclusions<I>(&mut self, iter: I)
+    where
+        I: Iterator<Item = String>,
+    {
+        for name in iter {
+            self.declared_exclusions.insert(name.clone());
+            self.reserve_name(&name);
+        }
+    }
+
+    fn guard_names<I>(&mut self, iter: I)
+    where
+        I: Iterator<Item = String>,
+    {
+        self.guarded.extend(iter);
+    }
+
+    fn guard_attributes(&mut self, attributes: HashMap<String, HashSet<String>>) {
+        for (name, attrs) in attributes {
+            self.attributes.entry(name).or_default().extend(attrs);
+        }
+    }
+
+    fn add_name(&mut self, name: &str) {
+        if name.is_empty() {
+            return;
+        }
+        if self.should_skip(name) {
+            self.excluded.insert(name.to_string());
+            self.reserved.insert(name.to_string());
+            self.locals.retain(|existing| existing != name);
+            self.seen.remove(name);
+            return;
+        }
+
+        if self.seen.insert(name.to_string()) {
+            self.locals.push(name.to_string());
+        }
+    }
+
+    fn reserve_name(&mut self, name: &str) {
+        self.excluded.insert(name.to_string());
+        self.reserved.insert(name.to_string());
+        self.locals.retain(|existing| existing != name);
+        self.seen.remove(name);
+    }
+
+    /// Keep locals spelled in `strings`, which the function may reach by name through
+    /// `getattr`, `setattr`, `vars()`, and the like, with a warning for each.
+    fn keep_looked_up_locals(&mut self, strings: &HashSet<String>) {
+        let looked_up: Vec<String> = self
+            .locals
+            .iter()
+            .filter(|local| strings.contains(*local))
+            .cloned()
+            .collect();
+        for name in looked_up {
+            self.warnings.push(format!(
+                "`{name}` is spelled in a string the function may look up by name; left as written"
+            ));
+            self.reserve_name(&name);
+        }
+    }
+
+    /// Leave the locals matching `keep` under their original names.
+    fn keep_locals(&mut self, keep: impl Fn(&str) -> bool) {
+        let kept: Vec<String> = self
+            .locals
+            .iter()
+            .filter(|local| keep(local))
+            .cloned()
+            .collect();
+        for name in kept {
+            self.reserve_name(&name);
+        }
+    }
+
+    fn should_skip(&self, name: &str) -> bool {
+        self.reserved.contains(name)
+            || name == "_"
+            || (name.starts_with("__") && name.ends_with("__"))
+    }
+
+    fn mark_nested_function(&mut self) {
+        self.has_nested_functions = true;
+    }
+
+    /// Treat a lambda that reads one of the function's renamable locals like a nested
+    /// function; lambda bodies are left as written, so they must not capture a renamed name.
+    fn mark_capturing_lambdas(&mut self) {
+        if self
+            .lambda_free_names
+            .iter()
+            .any(|name| self.seen.contains(name))
+        {
+            self.mark_nested_function();
+        }
+    }
+
+    fn mark_import(&mut self) {
+        self.has_imports = true;
+    }
+
+    fn add_names_from_expr(&mut self, expr: &ast::Expr) {
+        match expr {
+            ast::Expr::Name(ast::ExprName { id, ctx, .. }) => {
+                if matches!(ctx, ast::ExprContext::Store | ast::ExprContext::Del) {
+                    self.add_name(id.to_string().as_str());
                 }
-                if let Some(upper) = &expr_slice.upper {
-                    self.visit_expr(upper);
+            }
+            ast::Expr::Tuple(ast::ExprTuple { elts, .. })
+            | ast::Expr::List(ast::ExprList { elts, .. }) => {
+                for elt in elts {
+                    self.add_names_from_expr(elt);
                 }
-                if let Some(step) = &expr_slice.step {
-                    self.visit_expr(step);
+            }
+            ast::Expr::Starred(ast::ExprStarred { value, .. }) => {
+                self.add_names_from_expr(value);
+            }
+            _ => {}
+        }
+    }
+
+    fn reserve_names_from_expr(&mut self, expr: &ast::Expr) {
+        match expr {
+            ast::Expr::Name(ast::ExprName { id, ctx, .. }) => {
+                if matches!(ctx, ast::ExprContext::Store | ast::ExprContext::Del) {
+                    self.reserve_name(id.as_ref());
                 }
             }
-            ast::Expr::FormattedValue(expr_format) => {
-                self.visit_expr(&expr_format.value);
+            ast::Expr::Tuple(ast::ExprTuple { elts, .. })
+            | ast::Expr::List(ast::ExprList { elts, .. }) => {
+                for elt in elts {
+                    self.reserve_names_from_expr(elt);
+                }
             }
-            ast::Expr::JoinedStr(expr_joined) => {
-                for value in &expr_joined.values {
-                    self.visit_expr(value);
+            ast::Expr::Starred(ast::ExprStarred { value, .. }) => {
+                self.reserve_names_from_expr(value);
+            }
+            _ => {}
+        }
+    }
+
+    fn add_names_from_pattern(&mut self, pattern: &ast::Pattern) {
+        match pattern {
+            ast::Pattern::MatchAs(pat) => {
+                if let Some(sub) = &pat.pattern {
+                    self.add_names_from_pattern(sub);
+                }
+                if let Some(name) = &pat.name {
+                    self.add_name(name.to_string().as_str());
                 }
             }
-            ast::Expr::Constant(_) => {}
+            ast::Pattern::MatchStar(pat) => {
+                if let Some(name) = &pat.name {
+                    self.add_name(name.to_string().as_str());
+                }
+            }
+            ast::Pattern::MatchSequence(seq) => {
+                for sub in &seq.patterns {
+                    self.add_names_from_pattern(sub);
+                }
+            }
+            ast::Pattern::MatchMapping(map) => {
+                for sub in &map.patterns {
+                    self.add_names_from_pattern(sub);
+                }
+                if let Some(rest) = &map.rest {
+                    self.add_name(rest.as_ref());
+                }
+            }
+            ast::Pattern::MatchClass(class) => {
+                for sub in &class.patterns {
+                    self.add_names_from_pattern(sub);
+                }
+                for sub in &class.kwd_patterns {
+                    self.add_names_from_pattern(sub);
+                }
+            }
+            ast::Pattern::MatchOr(pat) => {
+                for sub in &pat.patterns {
+                    self.add_names_from_pattern(sub);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn collect_from_expression(&mut self, expr: &ast::Expr) {
+        // Only the assigned value itself is bound to the target, not its subexpressions.
+        let assignment_target = self.assignment_target.take();
+        match expr {
+            ast::Expr::NamedExpr(named) => {
+                self.add_names_from_expr(&named.target);
+                self.collect_from_expression(&named.value);
+            }
+            ast::Expr::BoolOp(ast::ExprBoolOp { values, .. })
+            | ast::Expr::Tuple(ast::ExprTuple { elts: values, .. })
+            | ast::Expr::List(ast::ExprList { elts: values, .. }) => {
+                for value in values {
+                    self.collect_from_expression(value);
+                }
+            }
+            ast::Expr::IfExp(ast::ExprIfExp {
+                test, body, orelse, ..
+            }) => {
+                self.collect_from_expression(test);
+                self.collect_from_expression(body);
+                self.collect_from_expression(orelse);
+            }
+            ast::Expr::Compare(ast::ExprCompare {
+                left, comparators, ..
+            }) => {
+                self.collect_from_expression(left);
+                for comp in comparators {
+                    self.collect_from_expression(comp);
+                }
+            }
+            ast::Expr::BinOp(ast::ExprBinOp { left, right, .. }) => {
+                self.collect_from_expression(left);
+                self.collect_from_expression(right);
+            }
+            ast::Expr::UnaryOp(ast::ExprUnaryOp { operand, .. }) => {
+                self.collect_from_expression(operand);
+            }
+            ast::Expr::Call(ast::ExprCall {
+                func,
+                args,
+                keywords,
+                ..
+            }) => {
+                if let Some(target) = assignment_target {
+                    self.check_named_type(&target, expr);
+                }
+                self.collect_from_expression(func);
+                for arg in args {
+                    self.collect_from_expression(arg);
+                }
+                for keyword in keywords {
+                    self.collect_from_expression(&keyword.value);
+                }
+            }
+            ast::Expr::Lambda(lambda) => {
+                // The defaults are evaluated here; the parameters and body form the
+                // lambda's own scope, which is left as written.
+                for default in parameter_defaults(&lambda.args) {
+                    self.collect_from_expression(default);
+                }
+                self.reserve_parameters(&lambda.args);
+                self.lambda_free_names.extend(lambda_free_names(lambda));
+            }
+            ast::Expr::JoinedStr(ast::ExprJoinedStr { values, .. }) => {
+                for value in values {
+                    self.collect_from_expression(value);
+                }
+            }
+            ast::Expr::FormattedValue(ast::ExprFormattedValue {
+                value, format_spec, ..
+            }) => {
+                self.collect_from_expression(value);
+                if let Some(format_spec) = format_spec {
+                    self.collect_from_expression(format_spec);
+                }
+            }
+            ast::Expr::ListComp(expr) => {
+                self.has_comprehension = true;
+                self.collect_from_expression(&expr.elt);
+                self.collect_from_comprehension_generators(&expr.generators);
+            }
+            ast::Expr::SetComp(expr) => {
+                self.has_comprehension = true;
+                self.collect_from_expression(&expr.elt);
+                self.collect_from_comprehension_generators(&expr.generators);
+            }
+            ast::Expr::DictComp(expr) => {
+                self.has_comprehension = true;
+                self.collect_from_expression(&expr.key);
+                self.collect_from_expression(&expr.value);
+                self.collect_from_comprehension_generators(&expr.generators);
+            }
+            ast::Expr::GeneratorExp(expr) => {
+                self.has_comprehension = true;
+                self.collect_from_expression(&expr.elt);
+                self.collect_from_comprehension_generators(&expr.generators);
+            }
+            _ => {}
+        }
+    }
+
+    /// Keep `target` under its name when `value` creates a type spelling that name, as in
+    /// `T = TypeVar("T")`, unless `rename_typevars` renames the string along with it.
+    fn check_named_type(&mut self, target: &str, value: &ast::Expr) {
+        let Some((_, name)) = named_type_literal(value) else {
+            return;
+        };
+        if name != target {
+            self.warnings.push(format!(
+                "`{target}` holds a type named \"{name}\"; the string is left as written"
+            ));
+        } else if !self.rename_typevars {
+            self.reserve_name(target);
         }
     }
 
-    fn visit_comprehension_generators(&mut self, generators: &[ast::Comprehension]) {
-        for generator in generators {
-            self.visit_expr(&generator.iter);
-            for condition in &generator.ifs {
-                self.visit_expr(condition);
-            }
-        }
-    }
+    fn collect_from_comprehension_generators(&mut self, generators: &[ast::Comprehension]) {
+        for generator in generators {
+            self.has_comprehension = true;
+            self.reserve_names_from_expr(&generator.target);
+            self.collect_from_expression(&generator.iter);
+            for condition in &generator.ifs {
+                self.collect_from_expression(condition);
+            }
+        }
+    }
+
+    fn into_plan(
+        mut self,
+        qualified_name: String,
+        range: Option<FunctionRange>,
+        naming: NamingMode,
+        naming_config: &NamingConfig,
+    ) -> FunctionPlan {
+        // Python reads identifiers in NFKC form, so `ſ` and `s` are one variable. The
+        // rewrite only replaces the spelling it planned, so keep locals spelled both ways.
+        let aliased: Vec<String> = if self
+            .guarded
+            .iter()
+            .chain(&self.locals)
+            .all(|name| name.is_ascii())
+        {
+            Vec::new()
+        } else {
+            let mut spellings: HashMap<Cow<str>, HashSet<&str>> = HashMap::new();
+            for name in self.guarded.iter().chain(&self.locals) {
+                spellings
+                    .entry(normalize_identifier(name))
+                    .or_default()
+                    .insert(name.as_str());
+            }
+            self.locals
+                .iter()
+                .filter(|local| spellings[&normalize_identifier(local)].len() > 1)
+                .cloned()
+                .collect()
+        };
+        for local in &aliased {
+            self.reserve_name(local);
+        }
+
+        // Renamed locals free up their original spelling, so only guard names that
+        // still refer to something outside the plan's own locals.
+        let mut reserved = self.reserved;
+        for name in self.guarded {
+            if !self.seen.contains(&name) {
+                reserved.insert(name);
+            }
+        }
+        for name in &self.locals {
+            if let Some(attrs) = self.attributes.get(name) {
+                reserved.extend(attrs.iter().cloned());
+            }
+        }
+        let mut generator = ShortNameGenerator::new(reserved, naming_config);
+        let mut renames = Vec::with_capacity(self.locals.len());
+
+        for name in &self.locals {
+            let replacement = match naming {
+                NamingMode::Sequential => generator.next(),
+                NamingMode::Stable => generator.stable(&qualified_name, name),
+            };
+            renames.push(RenameEntry {
+                original: name.clone(),
+                renamed: replacement,
+                occurrences: 0,
+            });
+        }
+
+        let mut excluded: Vec<String> = self
+            .excluded
+            .union(&self.declared_exclusions)
+            .cloned()
+            .collect();
+        excluded.sort();
+        excluded.dedup();
+        // Every occurrence of a name shares one decision, e.g. `err = None` and a later
+        // `except ... as err`.
+        debug_assert!(
+            renames
+                .iter()
+                .all(|entry| excluded.binary_search(&entry.original).is_err()),
+            "{qualified_name}: a local is both renamed and excluded"
+        );
+
+        let bailout_reason = if self.has_match_statement {
+            Some(BailoutReason::MatchStatement)
+        } else if self.has_comprehension {
+            Some(BailoutReason::Comprehension)
+        } else if self.exposes_locals {
+            Some(BailoutReason::LocalsCall)
+        } else {
+            None
+        };
+
+        FunctionPlan {
+            qualified_name,
+            locals: self.locals,
+            renames,
+            excluded,
+            range,
+            has_nested_functions: self.has_nested_functions,
+            has_imports: self.has_imports,
+            has_match_statement: self.has_match_statement,
+            has_comprehension: self.has_comprehension,
+            bailout_reason,
+            warnings: self.warnings,
+            nested: Vec::new(),
+        }
+    }
+}
+
+/// Callables whose first argument is the name of the type they create.
+const NAMED_TYPE_FACTORIES: &[&str] = &[
+    "Enum",
+    "NamedTuple",
+    "NewType",
+    "ParamSpec",
+    "TypeVar",
+    "TypeVarTuple",
+    "TypedDict",
+    "namedtuple",
+];
+
+/// The string literal passed as the type name in a call such as `TypeVar("T")` or
+/// `collections.namedtuple("Point", ...)`, with its text.
+fn named_type_literal(expr: &ast::Expr) -> Option<(&ast::Expr, &str)> {
+    let ast::Expr::Call(ast::ExprCall { func, args, .. }) = expr else {
+        return None;
+    };
+    let factory = match func.as_ref() {
+        ast::Expr::Name(ast::ExprName { id, .. }) => id.as_str(),
+        ast::Expr::Attribute(ast::ExprAttribute { attr, .. }) => attr.as_str(),
+        _ => return None,
+    };
+    if !NAMED_TYPE_FACTORIES.contains(&factory) {
+        return None;
+    }
+    let literal = args.first()?;
+    match literal {
+        ast::Expr::Constant(ast::ExprConstant {
+            value: ast::Constant::Str(name),
+            ..
+        }) => Some((literal, name.as_str())),
+        _ => None,
+    }
+}
+
+struct ShortNameGenerator {
+    counter: usize,
+    reserved: HashSet<String>,
+    issued: HashSet<String>,
+    naming: NamingConfig,
+}
+
+impl ShortNameGenerator {
+    fn new(mut reserved: HashSet<String>, naming: &NamingConfig) -> Self {
+        reserved.extend(
+            PYTHON_BUILTINS
+                .iter()
+                .chain(PYTHON_SOFT_KEYWORDS)
+                .map(std::string::ToString::to_string),
+        );
+        // A kept `ａ` is the same name as a generated `a` once Python normalizes it.
+        let folded: Vec<String> = reserved
+            .iter()
+            .filter_map(|name| match normalize_identifier(name) {
+                Cow::Owned(folded) => Some(folded),
+                Cow::Borrowed(_) => None,
+            })
+            .collect();
+        reserved.extend(folded);
+        Self {
+            counter: 0,
+            reserved,
+            issued: HashSet::new(),
+            naming: naming.clone(),
+        }
+    }
+
+    fn next(&mut self) -> String {
+        loop {
+            let candidate = self.naming.name(self.counter);
+            self.counter += 1;
+
+            if self.reserved.contains(&candidate) || self.issued.contains(&candidate) {
+                continue;
+            }
+
+            self.issued.insert(candidate.clone());
+            return candidate;
+        }
+    }
+}
+
+impl ShortNameGenerator {
+    /// Name derived from `(qualified_name, original)`, probing forward on collisions.
+    fn stable(&mut self, qualified_name: &str, original: &str) -> String {
+        let mut key = Vec::with_capacity(qualified_name.len() + original.len() + 1);
+        key.extend_from_slice(qualified_name.as_bytes());
+        key.push(0);
+        key.extend_from_slice(original.as_bytes());
+        let space = self.naming.stable_space();
+        #[allow(clippy::cast_possible_truncation)]
+        let start = (fnv1a64(&key) % space as u64) as usize;
+
+        for offset in 0..space {
+            let candidate = self.naming.name((start + offset) % space);
+            if !self.reserved.contains(&candidate) && !self.issued.contains(&candidate) {
+                self.issued.insert(candidate.clone());
+                return candidate;
+            }
+        }
+
+        self.counter = self.counter.max(space);
+        self.next()
+    }
+}
+
+/// FNV-1a 64-bit hash; stable across platforms and releases.
+pub(crate) fn fnv1a64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// The `value`th word over `alphabet`, counting `a`..`z`, `aa`, `ab`, ... for `a`..`z`.
+fn encode_identifier(mut value: usize, alphabet: &[u8]) -> String {
+    let base = alphabet.len();
+    let mut chars = Vec::new();
+    loop {
+        chars.push(char::from(alphabet[value % base]));
+        value /= base;
+        if value == 0 {
+            break;
+        }
+        value -= 1;
+    }
+    chars.iter().rev().collect()
+}
+
+fn range_from_node<T: Ranged>(node: &T) -> FunctionRange {
+    let text_range = node.range();
+    FunctionRange {
+        start: usize::from(text_range.start()),
+        end: usize::from(text_range.end()),
+        ..FunctionRange::default()
+    }
+}
+
+/// `range` of a definition starting after its last decorator, so that searching it for
+/// the defined name cannot land on a decorator that mentions the same name.
+fn header_range(range: FunctionRange, decorators: &[ast::Expr]) -> FunctionRange {
+    FunctionRange {
+        start: decorators.last().map_or(range.start, |decorator| {
+            range_from_node(decorator).end.max(range.start)
+        }),
+        ..range
+    }
+}
+
+struct Replacement {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+/// Build the rewritten source in one pass, copying the text between replacements.
+///
+/// `replacements` must be sorted by start and must not overlap.
+fn splice_replacements<'r, I>(source: &str, replacements: I) -> String
+where
+    I: IntoIterator<Item = &'r Replacement>,
+{
+    let mut output = String::with_capacity(source.len());
+    let mut cursor = 0;
+    for replacement in replacements {
+        debug_assert!(cursor <= replacement.start && replacement.start <= replacement.end);
+        output.push_str(&source[cursor..replacement.start]);
+        output.push_str(&replacement.text);
+        cursor = replacement.end;
+    }
+    output.push_str(&source[cursor..]);
+    output
+}
+
+/// Bodies of the branches of an `if` and the clauses of a `try`, where modules and classes
+/// define functions conditionally.
+fn conditional_suites(stmt: &ast::Stmt) -> Vec<&[ast::Stmt]> {
+    match stmt {
+        ast::Stmt::If(if_stmt) => vec![if_stmt.body.as_slice(), if_stmt.orelse.as_slice()],
+        ast::Stmt::Try(try_stmt) => {
+            let mut suites = vec![try_stmt.body.as_slice()];
+            suites.extend(try_stmt.handlers.iter().map(|handler| {
+                let ast::ExceptHandler::ExceptHandler(handler) = handler;
+                handler.body.as_slice()
+            }));
+            suites.push(&try_stmt.orelse);
+            suites.push(&try_stmt.finalbody);
+            suites
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Count the definitions of each function's qualified name in `suite`, visiting the same
+/// statements as [`FunctionRewriter`].
+fn count_definitions(
+    suite: &[ast::Stmt],
+    path: &mut Vec<String>,
+    counts: &mut HashMap<String, usize>,
+) {
+    for stmt in suite {
+        let (name, body, is_function) = match stmt {
+            ast::Stmt::FunctionDef(func) => (&func.name, &func.body, true),
+            ast::Stmt::AsyncFunctionDef(func) => (&func.name, &func.body, true),
+            ast::Stmt::ClassDef(class_def) => (&class_def.name, &class_def.body, false),
+            other => {
+                for suite in conditional_suites(other) {
+                    count_definitions(suite, path, counts);
+                }
+                continue;
+            }
+        };
+        path.push(name.to_string());
+        if is_function {
+            *counts.entry(path.join(".")).or_default() += 1;
+        }
+        count_definitions(body, path, counts);
+        path.pop();
+    }
+}
+
+struct FunctionRewriter<'a> {
+    source: &'a str,
+    /// Plans grouped by [`plans_by_name`].
+    plans: &'a HashMap<String, Vec<FunctionPlan>>,
+    /// Accepted replacements keyed by start offset; never overlapping.
+    replacements: BTreeMap<usize, Replacement>,
+    applied: Vec<String>,
+    /// Range and replacement count of each function in `applied`.
+    applied_ranges: Vec<(FunctionRange, usize)>,
+    skipped: Vec<(String, String)>,
+    /// Accepted replacements per (qualified function name, original name); module-level
+    /// renames use an empty function name.
+    occurrences: HashMap<(String, String), usize>,
+    abort: bool,
+    options: RewriteOptions,
+    /// Number of definitions of each qualified name.
+    definitions: HashMap<String, usize>,
+    fuzzy_matched: Vec<String>,
+    /// Rename comments from [`RewriteOptions::annotate_renames`], each with the index in
+    /// `applied` of its function.
+    annotations: Vec<(usize, Replacement)>,
+}
+
+impl<'a> FunctionRewriter<'a> {
+    fn new(
+        source: &'a str,
+        plans: &'a HashMap<String, Vec<FunctionPlan>>,
+        options: RewriteOptions,
+    ) -> Self {
+        Self {
+            source,
+            plans,
+            replacements: BTreeMap::new(),
+            applied: Vec::new(),
+            applied_ranges: Vec::new(),
+            skipped: Vec::new(),
+            occurrences: HashMap::new(),
+            abort: false,
+            options,
+            definitions: HashMap::new(),
+            fuzzy_matched: Vec::new(),
+            annotations: Vec::new(),
+        }
+    }
+
+    fn rewrite(mut self, suite: &[ast::Stmt]) -> Result<RewriteOutcome> {
+        count_definitions(suite, &mut Vec::new(), &mut self.definitions);
+        self.visit_suite(suite, &mut Vec::new())?;
+        if !self.abort && !self.replacements_disjoint() {
+            self.skip("<module>", BailoutReason::OverlappingReplacements);
+            self.abort = true;
+        }
+        if self.abort {
+            Ok(RewriteOutcome {
+                source: self.source.to_string(),
+                skipped: self.skipped,
+                ..RewriteOutcome::default()
+            })
+        } else {
+            // A comment may only go where no rename reaches; otherwise it is left out.
+            let replacements = &self.replacements;
+            self.annotations.retain(|(_, annotation)| {
+                replacements
+                    .range(..annotation.end)
+                    .next_back()
+                    .is_none_or(|(_, replacement)| replacement.end <= annotation.start)
+            });
+            let functions = self.function_rewrites();
+            let annotation_bytes = functions.iter().map(|f| f.annotation_bytes).sum();
+            let applied = std::mem::take(&mut self.applied);
+            let skipped = std::mem::take(&mut self.skipped);
+            let fuzzy_matched = std::mem::take(&mut self.fuzzy_matched);
+            Ok(RewriteOutcome {
+                source: self.apply(),
+                applied,
+                skipped,
+                functions,
+                fuzzy_matched,
+                annotation_bytes,
+            })
+        }
+    }
+
+    /// Size of each applied function's range before and after every replacement inside it.
+    fn function_rewrites(&self) -> Vec<FunctionRewrite> {
+        self.applied
+            .iter()
+            .zip(&self.applied_ranges)
+            .enumerate()
+            .map(|(index, (qualified_name, (range, replacements)))| {
+                let bytes_before = range.end - range.start;
+                let bytes_after = self
+                    .replacements
+                    .range(range.start..range.end)
+                    .map(|(_, r)| r)
+                    .filter(|r| r.end <= range.end)
+                    .fold(bytes_before, |size, r| {
+                        size + r.text.len() - (r.end - r.start)
+                    });
+                let annotation_bytes = self
+                    .annotations
+                    .iter()
+                    .filter(|(function, _)| *function == index)
+                    .map(|(_, annotation)| annotation.text.len())
+                    .sum();
+                FunctionRewrite {
+                    qualified_name: qualified_name.clone(),
+                    replacements: *replacements,
+                    bytes_before,
+                    bytes_after,
+                    annotation_bytes,
+                }
+            })
+            .collect()
+    }
+
+    fn skip(&mut self, qualified_name: &str, reason: BailoutReason) {
+        self.skipped
+            .push((qualified_name.to_string(), reason.to_string()));
+    }
+
+    fn visit_suite(&mut self, suite: &[ast::Stmt], path: &mut Vec<String>) -> Result<()> {
+        for stmt in suite {
+            match stmt {
+                ast::Stmt::FunctionDef(func) => {
+                    self.process_function(
+                        &func.name,
+                        &func.args,
+                        func.returns.as_deref(),
+                        &func.body,
+                        range_from_node(func),
+                        path,
+                    )?;
+                }
+                ast::Stmt::AsyncFunctionDef(func) => {
+                    self.process_async_function(func, path)?;
+                }
+                ast::Stmt::ClassDef(class_def) => {
+                    self.visit_class(class_def, path)?;
+                }
+                other => {
+                    for suite in conditional_suites(other) {
+                        self.visit_suite(suite, path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_class(&mut self, class_def: &ast::StmtClassDef, path: &mut Vec<String>) -> Result<()> {
+        path.push(class_def.name.to_string());
+        self.visit_suite(&class_def.body, path)?;
+        path.pop();
+        Ok(())
+    }
+
+    fn process_async_function(
+        &mut self,
+        func: &ast::StmtAsyncFunctionDef,
+        path: &mut Vec<String>,
+    ) -> Result<()> {
+        self.process_function(
+            &func.name,
+            &func.args,
+            func.returns.as_deref(),
+            &func.body,
+            range_from_node(func),
+            path,
+        )
+    }
+
+    fn process_function(
+        &mut self,
+        name: &ast::Identifier,
+        args: &ast::Arguments,
+        returns: Option<&ast::Expr>,
+        body: &[ast::Stmt],
+        definition: FunctionRange,
+        path: &mut Vec<String>,
+    ) -> Result<()> {
+        path.push(name.to_string());
+        let qualified_name = path.join(".");
+
+        if let Some(plan) = self.plan_for(&qualified_name, &definition) {
+            if plan.has_match_statement {
+                self.skip(&plan.qualified_name, BailoutReason::MatchStatement);
+                self.abort = true;
+            } else if plan.has_comprehension {
+                self.skip(&plan.qualified_name, BailoutReason::Comprehension);
+            } else {
+                self.rewrite_with_plan(&qualified_name, plan, args, returns, body, definition);
+            }
+        }
+
+        // Visit nested scopes to apply their plans.
+        self.visit_suite(body, path)?;
+
+        path.pop();
+        Ok(())
+    }
+
+    /// The plan recorded for the definition of `qualified_name` at `definition`; the only
+    /// definition of a name takes its plan even when the recorded range has drifted.
+    fn plan_for(
+        &self,
+        qualified_name: &str,
+        definition: &FunctionRange,
+    ) -> Option<&'a FunctionPlan> {
+        let plans: &'a HashMap<String, Vec<FunctionPlan>> = self.plans;
+        let plans = plans.get(qualified_name)?;
+        plans
+            .iter()
+            .find(|plan| {
+                plan.range.is_some_and(|range| {
+                    (range.start, range.end) == (definition.start, definition.end)
+                })
+            })
+            .or_else(|| {
+                (self.definitions.get(qualified_name) == Some(&1))
+                    .then(|| plans.first())
+                    .flatten()
+            })
+    }
+
+    /// Apply `plan` to the definition of `qualified_name` at `definition`; skips and
+    /// applied functions are reported under the plan's own (possibly `#N`) name.
+    fn rewrite_with_plan(
+        &mut self,
+        qualified_name: &str,
+        plan: &FunctionPlan,
+        args: &ast::Arguments,
+        returns: Option<&ast::Expr>,
+        body: &[ast::Stmt],
+        definition: FunctionRange,
+    ) {
+        let name = plan.qualified_name.as_str();
+        let Some(recorded) = &plan.range else {
+            self.skip(name, BailoutReason::MissingRange);
+            self.abort = true;
+            return;
+        };
+        let fuzzy = self.options.fuzzy_ranges
+            && (recorded.start, recorded.end) != (definition.start, definition.end)
+            && self.definitions.get(qualified_name) == Some(&1);
+        let range = if fuzzy { &definition } else { recorded };
+
+        let renames: HashMap<&str, &str> = plan
+            .renames
+            .iter()
+            .map(|entry| (entry.original.as_str(), entry.renamed.as_str()))
+            .collect();
+
+        if renames.is_empty() {
+            return;
+        }
+
+        let excluded: HashSet<&str> = plan.excluded.iter().map(|name| name.as_str()).collect();
+        let mut collector = OccurrenceCollector::new(self.source, range, renames, excluded);
+        collector.visit_arguments(args);
+        if let Some(annotation) = returns {
+            collector.with_annotation(|visitor| visitor.visit_expr(annotation));
+        }
+        collector.visit_statements(body);
+
+        if collector.abort {
+            let reason = collector
+                .abort_reason
+                .unwrap_or(BailoutReason::SourceMismatch);
+            self.skip(name, reason);
+            self.abort = true;
+            return;
+        }
+
+        let mut replacements = collector.replacements;
+        replacements.sort_by_key(|replacement| replacement.start);
+        if !self.accepts(&replacements) {
+            self.skip(name, BailoutReason::OverlappingReplacements);
+            return;
+        }
+
+        if !replacements.is_empty() {
+            self.applied.push(name.to_string());
+            self.applied_ranges.push((*range, replacements.len()));
+            if fuzzy {
+                self.fuzzy_matched.push(name.to_string());
+            }
+            if let Some(width) = self.options.annotate_renames {
+                if let Some(annotation) =
+                    rename_annotation(self.source, plan, &replacements, body, width)
+                {
+                    self.annotations.push((self.applied.len() - 1, annotation));
+                }
+            }
+        }
+        for replacement in &replacements {
+            let original = &self.source[replacement.start..replacement.end];
+            *self
+                .occurrences
+                .entry((name.to_string(), original.to_string()))
+                .or_default() += 1;
+        }
+        self.replacements.extend(
+            replacements
+                .into_iter()
+                .map(|replacement| (replacement.start, replacement)),
+        );
+    }
+
+    /// Queue the module-wide renames of private names. A rename whose occurrences can no
+    /// longer all be located is skipped under its original name.
+    fn rename_module_privates(&mut self, suite: &[ast::Stmt], renames: &[RenameEntry]) {
+        if renames.is_empty() {
+            return;
+        }
+        let names: HashSet<&str> = renames
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect();
+        let mut sites = module_private_sites(suite, self.source, &names);
+        for entry in renames {
+            let Some(ranges) = sites.remove(&entry.original) else {
+                self.skip(&entry.original, BailoutReason::SourceMismatch);
+                continue;
+            };
+            let mut replacements: Vec<Replacement> = ranges
+                .into_iter()
+                .map(|(start, end)| Replacement {
+                    start,
+                    end,
+                    text: entry.renamed.clone(),
+                })
+                .collect();
+            replacements.sort_by_key(|replacement| replacement.start);
+            if !self.accepts(&replacements) {
+                self.skip(&entry.original, BailoutReason::OverlappingReplacements);
+                continue;
+            }
+            self.occurrences
+                .insert((String::new(), entry.original.clone()), replacements.len());
+            self.replacements.extend(
+                replacements
+                    .into_iter()
+                    .map(|replacement| (replacement.start, replacement)),
+            );
+        }
+    }
+
+    /// Whether a function's `replacements`, sorted by start, lie on character boundaries
+    /// and overlap neither each other nor anything already accepted.
+    fn accepts(&self, replacements: &[Replacement]) -> bool {
+        let disjoint = replacements
+            .windows(2)
+            .all(|pair| pair[0].end <= pair[1].start);
+        disjoint
+            && replacements.iter().all(|replacement| {
+                // Accepted replacements never overlap, so only the last one starting before
+                // this one ends can reach into it.
+                let overlaps_accepted = self
+                    .replacements
+                    .range(..replacement.end)
+                    .next_back()
+                    .is_some_and(|(_, accepted)| accepted.end > replacement.start);
+                replacement.start < replacement.end
+                    && self.source.is_char_boundary(replacement.start)
+                    && self.source.is_char_boundary(replacement.end)
+                    && !overlaps_accepted
+            })
+    }
+
+    /// Whether the accepted replacements, in start order, are pairwise disjoint. `accepts`
+    /// should already guarantee this; splicing overlapping edits would corrupt the file, so
+    /// it is checked once more before applying.
+    fn replacements_disjoint(&self) -> bool {
+        self.replacements
+            .values()
+            .zip(self.replacements.values().skip(1))
+            .all(|(prev, next)| prev.end <= next.start)
+    }
+
+    fn apply(self) -> String {
+        // An inserted comment sorts before a rename starting at the same offset.
+        let mut edits: Vec<&Replacement> = self.replacements.values().collect();
+        edits.extend(self.annotations.iter().map(|(_, annotation)| annotation));
+        edits.sort_by_key(|edit| (edit.start, edit.end));
+        splice_replacements(self.source, edits)
+    }
+}
+
+/// The `# tsrs: renamed=original, ...` comment for a function whose accepted
+/// `replacements` are known, as an edit placing it, indented like `body`, on the line after
+/// the header or after a leading docstring and replacing any annotation already there.
+/// `None` when the body starts on the `def` line.
+fn rename_annotation(
+    source: &str,
+    plan: &FunctionPlan,
+    replacements: &[Replacement],
+    body: &[ast::Stmt],
+    width: usize,
+) -> Option<Replacement> {
+    let first = body.first()?;
+    let first_start = usize::from(first.range().start());
+    let line_start = source[..first_start]
+        .rfind('\n')
+        .map_or(0, |index| index + 1);
+    let indent = &source[line_start..first_start];
+    if !indent.chars().all(|c| c == ' ' || c == '\t') {
+        return None;
+    }
+
+    let start = if is_docstring_stmt(first) {
+        let end = usize::from(first.range().end());
+        end + source[end..].find('\n')? + 1
+    } else {
+        header_end(source, line_start)
+    };
+    let end = start
+        + source[start..]
+            .split_inclusive('\n')
+            .take_while(|line| is_rename_annotation(line))
+            .map(str::len)
+            .sum::<usize>();
+
+    let renamed: HashSet<&str> = replacements
+        .iter()
+        .map(|replacement| &source[replacement.start..replacement.end])
+        .collect();
+    let pairs: Vec<String> = plan
+        .renames
+        .iter()
+        .filter(|entry| renamed.contains(entry.original.as_str()))
+        .map(|entry| format!("{}={}", entry.renamed, entry.original))
+        .collect();
+    if pairs.is_empty() {
+        return None;
+    }
+    Some(Replacement {
+        start,
+        end,
+        text: wrap_annotation(indent, &pairs, width),
+    })
+}
+
+/// Start of the line after a function header, given the start of the line holding the
+/// first body statement; blank and comment-only lines in between are stepped back over.
+fn header_end(source: &str, mut line_start: usize) -> usize {
+    while line_start > 0 {
+        let previous = source[..line_start - 1]
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        let line = source[previous..line_start].trim();
+        if !(line.is_empty() || line.starts_with('#')) {
+            break;
+        }
+        line_start = previous;
+    }
+    line_start
+}
+
+/// Whether `line` is a comment written by [`RewriteOptions::annotate_renames`].
+fn is_rename_annotation(line: &str) -> bool {
+    let Some(pairs) = line.trim().strip_prefix("# tsrs: ") else {
+        return false;
+    };
+    pairs.trim_end_matches(',').split(", ").all(|pair| {
+        pair.split_once('=').is_some_and(|(renamed, original)| {
+            is_valid_identifier(renamed) && is_valid_identifier(original)
+        })
+    })
+}
+
+/// `# tsrs: ` comment lines listing `pairs`, indented by `indent` and starting a new line
+/// before a pair would run past `width` columns.
+fn wrap_annotation(indent: &str, pairs: &[String], width: usize) -> String {
+    let prefix = format!("{indent}# tsrs: ");
+    let mut lines = vec![prefix.clone()];
+    for (index, pair) in pairs.iter().enumerate() {
+        let item = if index + 1 < pairs.len() {
+            format!("{pair},")
+        } else {
+            pair.clone()
+        };
+        let line = lines.last_mut().expect("starts with one line");
+        if line.len() > prefix.len() {
+            if line.chars().count() + 1 + item.chars().count() > width {
+                lines.push(format!("{prefix}{item}"));
+                continue;
+            }
+            line.push(' ');
+        }
+        line.push_str(&item);
+    }
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+/// An import statement considered by [`Minifier::remove_unused_imports_detailed`].
+struct ImportStatement<'a> {
+    stmt: &'a ast::Stmt,
+    /// Index into [`ImportScan::suite_lens`]; `None` at module level.
+    suite: Option<usize>,
+    /// Inside a `try` that catches `ImportError`, so never edited.
+    guarded: bool,
+}
+
+#[derive(Default)]
+struct ImportScan<'a> {
+    imports: Vec<ImportStatement<'a>>,
+    /// Statement count of each nested suite, indexed by [`ImportStatement::suite`].
+    suite_lens: Vec<usize>,
+}
+
+impl<'a> ImportScan<'a> {
+    fn visit_suite(&mut self, suite: &'a [ast::Stmt], nested: bool, guarded: bool) {
+        let suite_id = nested.then(|| {
+            self.suite_lens.push(suite.len());
+            self.suite_lens.len() - 1
+        });
+
+        for stmt in suite {
+            match stmt {
+                ast::Stmt::Import(_) | ast::Stmt::ImportFrom(_) => {
+                    self.imports.push(ImportStatement {
+                        stmt,
+                        suite: suite_id,
+                        guarded,
+                    });
+                }
+                ast::Stmt::FunctionDef(func) => self.visit_suite(&func.body, true, guarded),
+                ast::Stmt::AsyncFunctionDef(func) => self.visit_suite(&func.body, true, guarded),
+                ast::Stmt::ClassDef(class_def) => {
+                    self.visit_suite(&class_def.body, true, guarded);
+                }
+                ast::Stmt::For(for_stmt) => {
+                    self.visit_suite(&for_stmt.body, true, guarded);
+                    self.visit_suite(&for_stmt.orelse, true, guarded);
+                }
+                ast::Stmt::AsyncFor(for_stmt) => {
+                    self.visit_suite(&for_stmt.body, true, guarded);
+                    self.visit_suite(&for_stmt.orelse, true, guarded);
+                }
+                ast::Stmt::While(while_stmt) => {
+                    self.visit_suite(&while_stmt.body, true, guarded);
+                    self.visit_suite(&while_stmt.orelse, true, guarded);
+                }
+                ast::Stmt::If(if_stmt) => {
+                    self.visit_suite(&if_stmt.body, true, guarded);
+                    self.visit_suite(&if_stmt.orelse, true, guarded);
+                }
+                ast::Stmt::With(with_stmt) => self.visit_suite(&with_stmt.body, true, guarded),
+                ast::Stmt::AsyncWith(with_stmt) => {
+                    self.visit_suite(&with_stmt.body, true, guarded);
+                }
+                ast::Stmt::Try(try_stmt) => {
+                    self.visit_try(
+                        &try_stmt.body,
+                        &try_stmt.handlers,
+                        &try_stmt.orelse,
+                        &try_stmt.finalbody,
+                        guarded,
+                    );
+                }
+                ast::Stmt::TryStar(try_stmt) => {
+                    self.visit_try(
+                        &try_stmt.body,
+                        &try_stmt.handlers,
+                        &try_stmt.orelse,
+                        &try_stmt.finalbody,
+                        guarded,
+                    );
+                }
+                ast::Stmt::Match(match_stmt) => {
+                    for case in &match_stmt.cases {
+                        self.visit_suite(&case.body, true, guarded);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn visit_try(
+        &mut self,
+        body: &'a [ast::Stmt],
+        handlers: &'a [ast::ExceptHandler],
+        orelse: &'a [ast::Stmt],
+        finalbody: &'a [ast::Stmt],
+        guarded: bool,
+    ) {
+        let guarded = guarded || handlers.iter().any(catches_import_error);
+        self.visit_suite(body, true, guarded);
+        for handler in handlers {
+            let ast::ExceptHandler::ExceptHandler(handler) = handler;
+            self.visit_suite(&handler.body, true, guarded);
+        }
+        self.visit_suite(orelse, true, guarded);
+        self.visit_suite(finalbody, true, guarded);
+    }
+}
+
+/// Whether the handler would catch a failed import (a bare `except` included).
+fn catches_import_error(handler: &ast::ExceptHandler) -> bool {
+    let ast::ExceptHandler::ExceptHandler(handler) = handler;
+    let Some(type_) = &handler.type_ else {
+        return true;
+    };
+    let is_import_error = |expr: &ast::Expr| {
+        matches!(
+            expr,
+            ast::Expr::Name(name)
+                if matches!(name.id.as_str(), "ImportError" | "ModuleNotFoundError" | "Exception" | "BaseException")
+        )
+    };
+    match type_.as_ref() {
+        ast::Expr::Tuple(tuple) => tuple.elts.iter().any(is_import_error),
+        expr => is_import_error(expr),
+    }
+}
+
+/// Each alias of an import statement paired with the name it binds, or `None` for
+/// star and `__future__` imports, which are never removed.
+fn import_bindings(stmt: &ast::Stmt) -> Option<Vec<(&ast::Alias, String)>> {
+    match stmt {
+        ast::Stmt::Import(import_stmt) => Some(
+            import_stmt
+                .names
+                .iter()
+                .map(|alias| {
+                    let binding = alias.asname.as_ref().map_or_else(
+                        || {
+                            let module = alias.name.as_str();
+                            module.split('.').next().unwrap_or(module).to_string()
+                        },
+                        ToString::to_string,
+                    );
+                    (alias, binding)
+                })
+                .collect(),
+        ),
+        ast::Stmt::ImportFrom(import_from) => {
+            let is_future = import_from.level.as_ref().map_or(0, ast::Int::to_u32) == 0
+                && import_from
+                    .module
+                    .as_ref()
+                    .is_some_and(|module| module.as_str() == "__future__");
+            if is_future
+                || import_from
+                    .names
+                    .iter()
+                    .any(|alias| alias.name.as_str() == "*")
+            {
+                return None;
+            }
+            Some(
+                import_from
+                    .names
+                    .iter()
+                    .map(|alias| {
+                        let binding = alias.asname.as_ref().unwrap_or(&alias.name).to_string();
+                        (alias, binding)
+                    })
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Re-render an import statement keeping only `aliases`.
+fn render_import(stmt: &ast::Stmt, aliases: &[&ast::Alias]) -> String {
+    let names = aliases
+        .iter()
+        .map(|alias| match &alias.asname {
+            Some(asname) => format!("{} as {asname}", alias.name),
+            None => alias.name.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match stmt {
+        ast::Stmt::ImportFrom(import_from) => {
+            let level = import_from.level.as_ref().map_or(0, ast::Int::to_u32);
+            let module = import_from
+                .module
+                .as_ref()
+                .map_or("", |module| module.as_str());
+            format!("from {}{module} import {names}", ".".repeat(level as usize))
+        }
+        _ => format!("import {names}"),
+    }
+}
+
+/// Delete the statement at `start..end` together with its line when nothing else shares
+/// the line; otherwise replace it with `pass` so neighbouring statements stay intact.
+fn removal_replacement(source: &str, start: usize, end: usize) -> Replacement {
+    let line_start = source[..start].rfind('\n').map_or(0, |index| index + 1);
+    let line_end = source[end..]
+        .find('\n')
+        .map_or(source.len(), |index| end + index + 1);
+    let rest = source[end..line_end].trim();
+
+    if source[line_start..start].trim().is_empty() && (rest.is_empty() || rest.starts_with('#')) {
+        Replacement {
+            start: line_start,
+            end: line_end,
+            text: String::new(),
+        }
+    } else {
+        Replacement {
+            start,
+            end,
+            text: "pass".to_string(),
+        }
+    }
+}
+
+/// Whether evaluating `expr` only builds a value: names and literals, containers of them,
+/// and operators applied to literals. Operators on names could run user-defined dunder
+/// methods, and set members and dict keys are hashed, so those must be literals.
+fn is_pure_value(expr: &ast::Expr) -> bool {
+    match expr {
+        ast::Expr::Name(_) => true,
+        ast::Expr::Tuple(tuple) => tuple.elts.iter().all(is_pure_value),
+        ast::Expr::List(list) => list.elts.iter().all(is_pure_value),
+        ast::Expr::Dict(dict) => {
+            dict.keys
+                .iter()
+                .all(|key| key.as_ref().is_some_and(is_literal_value))
+                && dict.values.iter().all(is_pure_value)
+        }
+        _ => is_literal_value(expr),
+    }
+}
+
+/// Whether `expr` is built from constants alone, so evaluating it cannot run user code.
+fn is_literal_value(expr: &ast::Expr) -> bool {
+    match expr {
+        ast::Expr::Constant(_) => true,
+        ast::Expr::Tuple(tuple) => tuple.elts.iter().all(is_literal_value),
+        ast::Expr::List(list) => list.elts.iter().all(is_literal_value),
+        ast::Expr::Set(set) => set.elts.iter().all(is_literal_value),
+        ast::Expr::Dict(dict) => {
+            dict.keys
+                .iter()
+                .all(|key| key.as_ref().is_some_and(is_literal_value))
+                && dict.values.iter().all(is_literal_value)
+        }
+        ast::Expr::UnaryOp(op) => is_literal_value(&op.operand),
+        ast::Expr::BinOp(op) => is_literal_value(&op.left) && is_literal_value(&op.right),
+        ast::Expr::BoolOp(op) => op.values.iter().all(is_literal_value),
+        ast::Expr::Compare(compare) => {
+            is_literal_value(&compare.left) && compare.comparators.iter().all(is_literal_value)
+        }
+        _ => false,
+    }
+}
+
+/// The parts of a module Python requires at its top, in order: a `#!` line, a coding
+/// cookie on one of the first two lines, the docstring, then `__future__` imports.
+struct ModuleHeader<'a> {
+    /// First line when it starts with `#!`, line ending included
+    shebang: Option<&'a str>,
+    /// Comment line declaring the source encoding, line ending included
+    cookie: Option<&'a str>,
+    /// Features named by top-level `from __future__ import` statements
+    future_features: Vec<String>,
+    /// Whether every `__future__` import precedes all statements but the docstring
+    futures_first: bool,
+}
+
+impl<'a> ModuleHeader<'a> {
+    /// `None` when `source` does not parse.
+    fn of(module_name: &str, source: &'a str) -> Option<Self> {
+        let suite = ast::Suite::parse(source, module_name).ok()?;
+        let shebang = leading_lines(source, 1).find(|line| line.starts_with("#!"));
+        let cookie = leading_lines(source, 2).find(|line| is_coding_cookie(line));
+
+        let mut future_features = Vec::new();
+        let mut futures_first = true;
+        let mut in_header = true;
+        for (index, stmt) in suite.iter().enumerate() {
+            match stmt {
+                ast::Stmt::ImportFrom(import)
+                    if import
+                        .module
+                        .as_ref()
+                        .is_some_and(|module| module.as_str() == "__future__") =>
+                {
+                    futures_first &= in_header;
+                    future_features.extend(import.names.iter().map(|alias| alias.name.to_string()));
+                }
+                _ if index == 0 && is_docstring_stmt(stmt) => {}
+                _ => in_header = false,
+            }
+        }
+        Some(Self {
+            shebang,
+            cookie,
+            future_features,
+            futures_first,
+        })
+    }
+}
+
+/// The first `count` lines of `source`, line endings included.
+fn leading_lines(source: &str, count: usize) -> impl Iterator<Item = &str> {
+    source.split_inclusive('\n').take(count)
+}
+
+/// Whether `line` is a PEP 263 comment such as `# -*- coding: latin-1 -*-`.
+pub(crate) fn is_coding_cookie(line: &str) -> bool {
+    let Some(comment) = line.trim_start().strip_prefix('#') else {
+        return false;
+    };
+    comment.match_indices("coding").any(|(index, _)| {
+        let rest = &comment[index + "coding".len()..];
+        rest.starts_with([':', '='])
+            && rest[1..]
+                .trim_start()
+                .starts_with(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// Remove module, class, and function docstrings, returning the stripped source and how
+/// many were removed.
+pub(crate) fn strip_docstrings(module_name: &str, source: &str) -> Result<(String, usize)> {
+    let suite =
+        ast::Suite::parse(source, module_name).map_err(|err| TsrsError::parse(source, &err))?;
+
+    let mut ranges = Vec::new();
+    collect_docstrings_in_suite(source, &suite, false, &mut ranges);
+
+    if ranges.is_empty() {
+        return Ok((source.to_string(), 0));
+    }
+
+    ranges.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let removed = ranges.len();
+    let mut stripped = source.to_string();
+    for (start, end) in ranges {
+        stripped.replace_range(start..end, "");
+    }
+
+    Ok((stripped, removed))
+}
+
+/// Collect docstring ranges in `suite`; a docstring that is the only statement of a class
+/// or function body is kept so the body stays non-empty.
+fn collect_docstrings_in_suite(
+    source: &str,
+    suite: &[ast::Stmt],
+    nested: bool,
+    ranges: &mut Vec<(usize, usize)>,
+) {
+    if let Some(first) = suite.first() {
+        if is_docstring_stmt(first) && !(nested && suite.len() == 1) {
+            ranges.push(docstring_range(source, first));
+        }
+    }
+
+    for stmt in suite {
+        match stmt {
+            ast::Stmt::FunctionDef(func) => {
+                collect_docstrings_in_suite(source, &func.body, true, ranges)
+            }
+            ast::Stmt::AsyncFunctionDef(func) => {
+                collect_docstrings_in_suite(source, &func.body, true, ranges)
+            }
+            ast::Stmt::ClassDef(class_def) => {
+                collect_docstrings_in_suite(source, &class_def.body, true, ranges)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_docstring_stmt(stmt: &ast::Stmt) -> bool {
+    match stmt {
+        ast::Stmt::Expr(ast::StmtExpr { value, .. }) => {
+            matches!(
+                value.as_ref(),
+                ast::Expr::Constant(ast::ExprConstant {
+                    value: ast::Constant::Str { .. },
+                    ..
+                })
+            )
+        }
+        _ => false,
+    }
+}
+
+fn docstring_range(source: &str, stmt: &ast::Stmt) -> (usize, usize) {
+    let text_range = stmt.range();
+    let mut start = usize::from(text_range.start());
+    let mut end = usize::from(text_range.end());
+
+    let bytes = source.as_bytes();
+
+    while start > 0 {
+        let previous = bytes[start - 1];
+        if previous == b' ' || previous == b'\t' {
+            start -= 1;
+            continue;
+        }
+        if previous == b'\n' || previous == b'\r' {
+            break;
+        }
+        break;
+    }
+
+    while end < bytes.len() {
+        let current = bytes[end];
+        if current == b' ' || current == b'\t' {
+            end += 1;
+            continue;
+        }
+        if current == b'\r' {
+            end += 1;
+            if end < bytes.len() && bytes[end] == b'\n' {
+                end += 1;
+            }
+            break;
+        }
+        if current == b'\n' {
+            end += 1;
+            break;
+        }
+        break;
+    }
+
+    (start, end)
+}
+
+struct OccurrenceCollector<'a> {
+    source: &'a str,
+    function_range: &'a FunctionRange,
+    renames: HashMap<&'a str, &'a str>,
+    excluded: HashSet<&'a str>,
+    replacements: Vec<Replacement>,
+    in_annotation: bool,
+    abort: bool,
+    abort_reason: Option<BailoutReason>,
+}
+
+impl<'a> OccurrenceCollector<'a> {
+    fn new(
+        source: &'a str,
+        function_range: &'a FunctionRange,
+        renames: HashMap<&'a str, &'a str>,
+        excluded: HashSet<&'a str>,
+    ) -> Self {
+        Self {
+            source,
+            function_range,
+            renames,
+            excluded,
+            replacements: Vec::new(),
+            in_annotation: false,
+            abort: false,
+            abort_reason: None,
+        }
+    }
+
+    fn bail(&mut self, reason: BailoutReason) {
+        self.abort = true;
+        self.abort_reason.get_or_insert(reason);
+    }
+
+    /// The replacement for `name`, looked up the same way for every kind of occurrence so
+    /// a name is renamed everywhere in the function or nowhere; exclusion wins over a
+    /// rename, e.g. in a hand-edited plan listing the name under both.
+    fn renamed(&self, name: &str) -> Option<&'a str> {
+        if self.excluded.contains(name) {
+            return None;
+        }
+        match self.renames.get(name) {
+            Some(&new_name) if new_name != name => Some(new_name),
+            _ => None,
+        }
+    }
+
+    fn with_annotation<F>(&mut self, visitor: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let previous = self.in_annotation;
+        self.in_annotation = true;
+        visitor(self);
+        self.in_annotation = previous;
+    }
+
+    fn visit_arguments(&mut self, args: &ast::Arguments) {
+        for param in &args.posonlyargs {
+            self.record_arg(&param.def);
+            if let Some(default) = &param.default {
+                self.visit_expr(default);
+            }
+        }
+        for param in &args.args {
+            self.record_arg(&param.def);
+            if let Some(default) = &param.default {
+                self.visit_expr(default);
+            }
+        }
+        if let Some(vararg) = &args.vararg {
+            self.record_arg(vararg);
+        }
+        for param in &args.kwonlyargs {
+            self.record_arg(&param.def);
+            if let Some(default) = &param.default {
+                self.visit_expr(default);
+            }
+        }
+        if let Some(kwarg) = &args.kwarg {
+            self.record_arg(kwarg);
+        }
+    }
+
+    /// Visit the parts of a nested `def` that are evaluated in the enclosing scope:
+    /// decorators, parameter defaults, and annotations. The body belongs to its own plan.
+    fn visit_nested_signature(
+        &mut self,
+        decorators: &[ast::Expr],
+        args: &ast::Arguments,
+        returns: Option<&ast::Expr>,
+    ) {
+        for decorator in decorators {
+            self.visit_expr(decorator);
+        }
+        let with_defaults = args
+            .posonlyargs
+            .iter()
+            .chain(&args.args)
+            .chain(&args.kwonlyargs);
+        for param in with_defaults.clone() {
+            if let Some(default) = &param.default {
+                self.visit_expr(default);
+            }
+        }
+        let params = with_defaults
+            .map(|param| &param.def)
+            .chain(args.vararg.as_deref())
+            .chain(args.kwarg.as_deref());
+        for param in params {
+            if let Some(annotation) = &param.annotation {
+                self.with_annotation(|collector| collector.visit_expr(annotation));
+            }
+        }
+        if let Some(annotation) = returns {
+            self.with_annotation(|collector| collector.visit_expr(annotation));
+        }
+    }
+
+    fn visit_statements(&mut self, stmts: &[ast::Stmt]) {
+        for stmt in stmts {
+            self.visit_stmt(stmt);
+            if self.abort {
+                return;
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn visit_stmt(&mut self, stmt: &ast::Stmt) {
+        if self.abort {
+            return;
+        }
+
+        match stmt {
+            ast::Stmt::FunctionDef(func) => {
+                let range = header_range(range_from_node(func), &func.decorator_list);
+                if let Some((start, end)) =
+                    find_identifier_in_range(self.source, &range, func.name.as_ref())
+                {
+                    let name_range = FunctionRange {
+                        start,
+                        end,
+                        ..FunctionRange::default()
+                    };
+                    self.record_identifier(func.name.as_ref(), name_range);
+                } else {
+                    self.bail(BailoutReason::IdentifierNotFound);
+                }
+                // Skip body; handled in its own plan.
+                self.visit_nested_signature(
+                    &func.decorator_list,
+                    &func.args,
+                    func.returns.as_deref(),
+                );
+            }
+            ast::Stmt::AsyncFunctionDef(func) => {
+                let range = header_range(range_from_node(func), &func.decorator_list);
+                if let Some((start, end)) =
+                    find_identifier_in_range(self.source, &range, func.name.as_ref())
+                {
+                    let name_range = FunctionRange {
+                        start,
+                        end,
+                        ..FunctionRange::default()
+                    };
+                    self.record_identifier(func.name.as_ref(), name_range);
+                } else {
+                    self.bail(BailoutReason::IdentifierNotFound);
+                }
+                self.visit_nested_signature(
+                    &func.decorator_list,
+                    &func.args,
+                    func.returns.as_deref(),
+                );
+            }
+            ast::Stmt::ClassDef(class_def) => {
+                let range = header_range(range_from_node(class_def), &class_def.decorator_list);
+                if let Some((start, end)) =
+                    find_identifier_in_range(self.source, &range, class_def.name.as_ref())
+                {
+                    let name_range = FunctionRange {
+                        start,
+                        end,
+                        ..FunctionRange::default()
+                    };
+                    self.record_identifier(class_def.name.as_ref(), name_range);
+                } else {
+                    self.bail(BailoutReason::IdentifierNotFound);
+                }
+            }
+            ast::Stmt::Return(ret) => {
+                if let Some(value) = &ret.value {
+                    self.visit_expr(value);
+                }
+            }
+            ast::Stmt::Assign(assign) => {
+                for target in &assign.targets {
+                    self.visit_expr(target);
+                }
+                self.visit_expr(&assign.value);
+                if let [ast::Expr::Name(target)] = assign.targets.as_slice() {
+                    self.record_named_type_literal(target.id.as_ref(), &assign.value);
+                }
+            }
+            ast::Stmt::AnnAssign(assign) => {
+                self.visit_expr(&assign.target);
+                if let Some(value) = &assign.value {
+                    self.visit_expr(value);
+                }
+                self.with_annotation(|collector| collector.visit_expr(&assign.annotation));
+            }
+            ast::Stmt::AugAssign(assign) => {
+                self.visit_expr(&assign.target);
+                self.visit_expr(&assign.value);
+            }
+            ast::Stmt::For(stmt_for) => {
+                self.visit_expr(&stmt_for.target);
+                self.visit_expr(&stmt_for.iter);
+                self.visit_statements(&stmt_for.body);
+                self.visit_statements(&stmt_for.orelse);
+            }
+            ast::Stmt::AsyncFor(stmt_for) => {
+                self.visit_expr(&stmt_for.target);
+                self.visit_expr(&stmt_for.iter);
+                self.visit_statements(&stmt_for.body);
+                self.visit_statements(&stmt_for.orelse);
+            }
+            ast::Stmt::While(stmt_while) => {
+                self.visit_expr(&stmt_while.test);
+                self.visit_statements(&stmt_while.body);
+                self.visit_statements(&stmt_while.orelse);
+            }
+            ast::Stmt::If(stmt_if) => {
+                self.visit_expr(&stmt_if.test);
+                self.visit_statements(&stmt_if.body);
+                self.visit_statements(&stmt_if.orelse);
+            }
+            ast::Stmt::With(stmt_with) => {
+                for item in &stmt_with.items {
+                    self.visit_expr(&item.context_expr);
+                    if let Some(optional) = &item.optional_vars {
+                        self.visit_expr(optional);
+                    }
+                }
+                self.visit_statements(&stmt_with.body);
+            }
+            ast::Stmt::AsyncWith(stmt_with) => {
+                for item in &stmt_with.items {
+                    self.visit_expr(&item.context_expr);
+                    if let Some(optional) = &item.optional_vars {
+                        self.visit_expr(optional);
+                    }
+                }
+                self.visit_statements(&stmt_with.body);
+            }
+            ast::Stmt::Expr(expr_stmt) => {
+                self.visit_expr(&expr_stmt.value);
+            }
+            ast::Stmt::Try(stmt_try) => {
+                self.visit_statements(&stmt_try.body);
+                self.visit_statements(&stmt_try.orelse);
+                self.visit_statements(&stmt_try.finalbody);
+                for handler in &stmt_try.handlers {
+                    self.visit_except_handler(handler);
+                }
+            }
+            ast::Stmt::TryStar(stmt_try) => {
+                self.visit_statements(&stmt_try.body);
+                self.visit_statements(&stmt_try.orelse);
+                self.visit_statements(&stmt_try.finalbody);
+                for handler in &stmt_try.handlers {
+                    self.visit_except_handler(handler);
+                }
+            }
+            ast::Stmt::Raise(stmt_raise) => {
+                if let Some(exc) = &stmt_raise.exc {
+                    self.visit_expr(exc);
+                }
+                if let Some(cause) = &stmt_raise.cause {
+                    self.visit_expr(cause);
+                }
+            }
+            ast::Stmt::Assert(stmt_assert) => {
+                self.visit_expr(&stmt_assert.test);
+                if let Some(msg) = &stmt_assert.msg {
+                    self.visit_expr(msg);
+                }
+            }
+            ast::Stmt::Delete(stmt_delete) => {
+                for target in &stmt_delete.targets {
+                    self.visit_expr(target);
+                }
+            }
+            ast::Stmt::TypeAlias(type_alias) => {
+                self.with_annotation(|collector| collector.visit_expr(&type_alias.value));
+            }
+            ast::Stmt::Match(_) | ast::Stmt::Import(_) | ast::Stmt::ImportFrom(_) => {
+                // Imports introduce bindings; record alias targets conservatively.
+                self.visit_import(stmt);
+            }
+            _ => {}
+        }
+    }
+
+    fn visit_import(&mut self, stmt: &ast::Stmt) {
+        if self.abort {
+            return;
+        }
+
+        match stmt {
+            ast::Stmt::Import(import_stmt) => {
+                for alias in &import_stmt.names {
+                    let full_name = alias.name.to_string();
+                    let binding = alias
+                        .asname
+                        .as_ref()
+                        .map(std::string::ToString::to_string)
+                        .unwrap_or_else(|| {
+                            full_name
+                                .split('.')
+                                .next()
+                                .unwrap_or(&full_name)
+                                .to_string()
+                        });
+
+                    if alias.asname.is_some() {
+                        continue;
+                    }
+
+                    if let Some(new_name) = self.renamed(&binding) {
+                        let range = range_from_node(alias);
+                        if !full_name.contains('.') {
+                            let replacement = format!("{full_name} as {new_name}");
+                            self.replacements.push(Replacement {
+                                start: range.start,
+                                end: range.end,
+                                text: replacement,
+                            });
+                        }
+                    }
+                }
+            }
+            ast::Stmt::ImportFrom(import_from) => {
+                for alias in &import_from.names {
+                    if alias.name.to_string().as_str() == "*" {
+                        continue;
+                    }
+                    let binding = alias.asname.as_ref().map_or_else(
+                        || {
+                            let full = alias.name.to_string();
+                            full.split('.')
+                                .next()
+                                .map(std::string::ToString::to_string)
+                                .unwrap_or(full)
+                        },
+                        std::string::ToString::to_string,
+                    );
+
+                    if alias.asname.is_some() {
+                        continue;
+                    }
+
+                    if let Some(new_name) = self.renamed(&binding) {
+                        let range = range_from_node(alias);
+                        let module_text = alias.name.to_string();
+                        let replacement = format!("{module_text} as {new_name}");
+                        self.replacements.push(Replacement {
+                            start: range.start,
+                            end: range.end,
+                            text: replacement,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn visit_except_handler(&mut self, handler: &ast::ExceptHandler) {
+        if self.abort {
+            return;
+        }
+
+        match handler {
+            ast::ExceptHandler::ExceptHandler(ex_handler) => {
+                if let Some(type_) = &ex_handler.type_ {
+                    self.visit_expr(type_);
+                }
+                if let Some(name) = &ex_handler.name {
+                    self.record_except_name(ex_handler, name.as_ref());
+                }
+                self.visit_statements(&ex_handler.body);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn visit_expr(&mut self, expr: &ast::Expr) {
+        if self.abort {
+            return;
+        }
+
+        match expr {
+            ast::Expr::Name(expr_name) => {
+                let range = range_from_node(expr_name);
+                self.record_identifier(expr_name.id.as_ref(), range);
+            }
+            ast::Expr::BoolOp(expr_bool) => {
+                for value in &expr_bool.values {
+                    self.visit_expr(value);
+                }
+            }
+            ast::Expr::BinOp(expr_bin) => {
+                self.visit_expr(&expr_bin.left);
+                self.visit_expr(&expr_bin.right);
+            }
+            ast::Expr::UnaryOp(expr_unary) => {
+                self.visit_expr(&expr_unary.operand);
+            }
+            ast::Expr::Lambda(lambda) => {
+                // Only the defaults belong to this function's scope.
+                if lambda_free_names(lambda)
+                    .iter()
+                    .any(|name| self.renamed(name).is_some())
+                {
+                    self.bail(BailoutReason::Lambda);
+                    return;
+                }
+                for default in parameter_defaults(&lambda.args) {
+                    self.visit_expr(default);
+                }
+            }
+            ast::Expr::IfExp(expr_if) => {
+                self.visit_expr(&expr_if.test);
+                self.visit_expr(&expr_if.body);
+                self.visit_expr(&expr_if.orelse);
+            }
+            ast::Expr::List(expr_list) => {
+                for elt in &expr_list.elts {
+                    self.visit_expr(elt);
+                }
+            }
+            ast::Expr::Tuple(expr_tuple) => {
+                for elt in &expr_tuple.elts {
+                    self.visit_expr(elt);
+                }
+            }
+            ast::Expr::Set(expr_set) => {
+                for elt in &expr_set.elts {
+                    self.visit_expr(elt);
+                }
+            }
+            ast::Expr::Dict(expr_dict) => {
+                for key in expr_dict.keys.iter().flatten() {
+                    self.visit_expr(key);
+                }
+                for value in &expr_dict.values {
+                    self.visit_expr(value);
+                }
+            }
+            ast::Expr::ListComp(expr) => {
+                self.visit_expr(&expr.elt);
+                self.visit_comprehension_generators(&expr.generators);
+            }
+            ast::Expr::SetComp(expr) => {
+                self.visit_expr(&expr.elt);
+                self.visit_comprehension_generators(&expr.generators);
+            }
+            ast::Expr::DictComp(expr) => {
+                self.visit_expr(&expr.key);
+                self.visit_expr(&expr.value);
+                self.visit_comprehension_generators(&expr.generators);
+            }
+            ast::Expr::GeneratorExp(expr) => {
+                self.visit_expr(&expr.elt);
+                self.visit_comprehension_generators(&expr.generators);
+            }
+            ast::Expr::Await(expr_await) => {
+                self.visit_expr(&expr_await.value);
+            }
+            ast::Expr::Yield(expr_yield) => {
+                if let Some(value) = &expr_yield.value {
+                    self.visit_expr(value);
+                }
+            }
+            ast::Expr::YieldFrom(expr_yield) => {
+                self.visit_expr(&expr_yield.value);
+            }
+            ast::Expr::Compare(expr_compare) => {
+                self.visit_expr(&expr_compare.left);
+                for comp in &expr_compare.comparators {
+                    self.visit_expr(comp);
+                }
+            }
+            ast::Expr::Call(expr_call) => {
+                self.visit_expr(&expr_call.func);
+                for arg in &expr_call.args {
+                    self.visit_expr(arg);
+                }
+                for keyword in &expr_call.keywords {
+                    self.visit_expr(&keyword.value);
+                }
+            }
+            ast::Expr::Attribute(expr_attr) => {
+                self.visit_expr(&expr_attr.value);
+            }
+            ast::Expr::Subscript(expr_sub) => {
+                self.visit_expr(&expr_sub.value);
+                self.visit_expr(&expr_sub.slice);
+            }
+            ast::Expr::Starred(expr_star) => {
+                self.visit_expr(&expr_star.value);
+            }
+            ast::Expr::NamedExpr(expr_named) => {
+                self.visit_expr(&expr_named.target);
+                self.visit_expr(&expr_named.value);
+            }
+            ast::Expr::Slice(expr_slice) => {
+                if let Some(lower) = &expr_slice.lower {
+                    self.visit_expr(lower);
+                }
+                if let Some(upper) = &expr_slice.upper {
+                    self.visit_expr(upper);
+                }
+                if let Some(step) = &expr_slice.step {
+                    self.visit_expr(step);
+                }
+            }
+            ast::Expr::FormattedValue(expr_format) => {
+                self.visit_expr(&expr_format.value);
+                if let Some(format_spec) = &expr_format.format_spec {
+                    self.visit_expr(format_spec);
+                }
+            }
+            ast::Expr::JoinedStr(expr_joined) => {
+                for value in &expr_joined.values {
+                    self.visit_expr(value);
+                }
+            }
+            ast::Expr::Constant(_) => {}
+        }
+    }
+
+    fn visit_comprehension_generators(&mut self, generators: &[ast::Comprehension]) {
+        for generator in generators {
+            self.visit_expr(&generator.iter);
+            for condition in &generator.ifs {
+                self.visit_expr(condition);
+            }
+        }
+    }
+
+    fn record_arg(&mut self, arg: &ast::Arg) {
+        let name = arg.arg.as_ref();
+        let arg_range = range_from_node(arg);
+        let span = arg_name_span(self.source, &arg_range, name)
+            .or_else(|| find_identifier_in_range(self.source, &arg_range, name));
+        if let Some((start, end)) = span {
+            self.record_identifier(
+                name,
+                FunctionRange {
+                    start,
+                    end,
+                    ..FunctionRange::default()
+                },
+            );
+        } else {
+            self.bail(BailoutReason::IdentifierNotFound);
+            return;
+        }
+        if let Some(annotation) = &arg.annotation {
+            self.with_annotation(|collector| collector.visit_expr(annotation));
+        }
+    }
+
+    /// Rename the string in `T = TypeVar("T")` along with `T`, which the planner only
+    /// renames under `rename_typevars`, so the type keeps its variable's name.
+    fn record_named_type_literal(&mut self, target: &str, value: &ast::Expr) {
+        if self.abort {
+            return;
+        }
+        let Some((literal, name)) = named_type_literal(value) else {
+            return;
+        };
+        let new_name = match self.renamed(target) {
+            Some(new_name) if name == target => new_name,
+            _ => return,
+        };
+        let range = range_from_node(literal);
+        let quoted = self.source.get(range.start..range.end);
+        if quoted != Some(format!("\"{target}\"").as_str())
+            && quoted != Some(format!("'{target}'").as_str())
+        {
+            self.bail(BailoutReason::SourceMismatch);
+            return;
+        }
+        self.replacements.push(Replacement {
+            start: range.start + 1,
+            end: range.end - 1,
+            text: new_name.to_string(),
+        });
+    }
+
+    fn record_identifier(&mut self, name: &str, node_range: FunctionRange) {
+        if self.in_annotation {
+            return;
+        }
+        if self.abort {
+            return;
+        }
+
+        let Some(new_name) = self.renamed(name) else {
+            return;
+        };
+
+        if node_range.start < self.function_range.start || node_range.end > self.function_range.end
+        {
+            self.bail(BailoutReason::OutOfRange);
+            return;
+        }
+
+        let start = node_range.start;
+        let end = node_range.end;
+
+        if end > self.source.len() || start >= end {
+            self.bail(BailoutReason::OutOfRange);
+            return;
+        }
+
+        let slice = &self.source[start..end];
+        if slice != name {
+            self.bail(BailoutReason::SourceMismatch);
+            return;
+        }
+
+        self.replacements.push(Replacement {
+            start,
+            end,
+            text: new_name.to_string(),
+        });
+    }
+
+    fn record_except_name(&mut self, handler: &ast::ExceptHandlerExceptHandler, name: &str) {
+        if self.abort {
+            return;
+        }
+
+        let Some(new_name) = self.renamed(name) else {
+            return;
+        };
+
+        let handler_range = range_from_node(handler);
+        if let Some((start, end)) = find_except_name_range(self.source, &handler_range, name) {
+            self.replacements.push(Replacement {
+                start,
+                end,
+                text: new_name.to_string(),
+            });
+        } else {
+            self.bail(BailoutReason::IdentifierNotFound);
+        }
+    }
+}
+
+/// Span of a parameter's name taken from its node range, which starts at the name token;
+/// `None` when the range does not begin with exactly `name`.
+fn arg_name_span(source: &str, range: &FunctionRange, name: &str) -> Option<(usize, usize)> {
+    let end = range.start.checked_add(name.len())?;
+    let at_name = source.get(range.start..end) == Some(name)
+        && !source[end..].chars().next().is_some_and(is_identifier_char);
+    at_name.then_some((range.start, end))
+}
+
+fn find_identifier_in_range(
+    source: &str,
+    range: &FunctionRange,
+    name: &str,
+) -> Option<(usize, usize)> {
+    let start = range.start.min(source.len());
+    let end = range.end.min(source.len());
+    if start >= end {
+        return None;
+    }
+
+    let slice = &source[start..end];
+    let mut offset = 0usize;
+    while let Some(rel_idx) = slice[offset..].find(name) {
+        let idx = offset + rel_idx;
+        let before = slice[..idx].chars().next_back();
+        let after = slice[idx + name.len()..].chars().next();
+        if is_identifier_boundary(before, after) {
+            return Some((start + idx, start + idx + name.len()));
+        }
+        offset = idx + first_char_len(name);
+    }
+
+    None
+}
+
+fn find_except_name_range(
+    source: &str,
+    handler_range: &FunctionRange,
+    name: &str,
+) -> Option<(usize, usize)> {
+    let start = handler_range.start.min(source.len());
+    let end = handler_range.end.min(source.len());
+    if start >= end {
+        return None;
+    }
+
+    let slice = &source[start..end];
+    let mut offset = 0usize;
+    while let Some(rel_idx) = slice[offset..].find(name) {
+        let idx = offset + rel_idx;
+        let prefix = slice[..idx].trim_end();
+        if prefix.ends_with("as")
+            && is_identifier_boundary(
+                slice[..idx].chars().next_back(),
+                slice[idx + name.len()..].chars().next(),
+            )
+        {
+            return Some((start + idx, start + idx + name.len()));
+        }
+        offset = idx + first_char_len(name);
+    }
+
+    None
+}
+
+fn is_identifier_boundary(prev: Option<char>, next: Option<char>) -> bool {
+    let prev_ok = !prev.is_some_and(is_identifier_char);
+    let next_ok = !next.is_some_and(is_identifier_char);
+    prev_ok && next_ok
+}
+
+/// Whether `c` can continue an identifier. Outside strings and comments Python only
+/// allows non-ASCII characters in identifiers, so any of them joins the name next to it;
+/// inside strings this only makes a match less likely.
+fn is_identifier_char(c: char) -> bool {
+    c == '_' || c.is_ascii_alphanumeric() || !c.is_ascii()
+}
+
+/// Bytes to step past a match of `name` so the next search starts on a char boundary.
+fn first_char_len(name: &str) -> usize {
+    name.chars().next().map_or(1, char::len_utf8)
+}
+
+/// `name` as Python reads it: identifiers are compared after NFKC normalization, so `ſum`
+/// and `sum`, or `µ` and `μ`, name one variable.
+fn normalize_identifier(name: &str) -> Cow<'_, str> {
+    if name.is_ascii() || is_nfkc(name) {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(name.nfkc().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_errors_carry_line_and_column() {
+        let source = "def ok():\n    pass\n\ndef broken(:\n    pass\n";
+        match Minifier::plan_from_source("sample", source) {
+            Err(TsrsError::Parse {
+                file: None,
+                line,
+                column,
+                ..
+            }) => {
+                assert_eq!(line, 4);
+                assert!(column > 1);
+            }
+            other => panic!("expected parse error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn plans_parameters_and_locals() {
+        let source = r#"
+def outer(value, *, option=None):
+    temp = value + 1
+    for idx in range(3):
+        result = temp + idx
+    with context() as handle:
+        extra = handle.do()
+    return result + extra
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert_eq!(plan.functions.len(), 1);
+
+        let outer = &plan.functions[0];
+        assert_eq!(outer.qualified_name, "outer");
+        assert_eq!(
+            outer.locals,
+            vec!["value", "option", "temp", "idx", "result", "handle", "extra"]
+        );
+        assert_eq!(outer.renames.len(), outer.locals.len());
+        assert_eq!(outer.renames[0].renamed, "a");
+        assert_eq!(outer.renames[1].renamed, "b");
+        // ensure reserved names recorded when encountered
+        assert!(!outer.excluded.contains(&"context".to_string()));
+        assert!(!outer.has_nested_functions);
+        assert!(!outer.has_imports);
+        assert!(outer.range.is_some());
+    }
+
+    #[test]
+    fn generated_names_never_shadow_builtins() {
+        // Enough locals to run well past `id` (the first two-letter builtin) and into the
+        // range where sequential names used to include it.
+        let mut source = String::from("def busy():\n    v0 = 0\n");
+        for index in 1..300 {
+            source.push_str(&format!("    v{index} = v{} + 1\n", index - 1));
+        }
+        source.push_str("    return v299\n");
+
+        let plan = Minifier::plan_from_source("sample", &source).unwrap();
+        let renames = &plan.functions[0].renames;
+        assert_eq!(renames.len(), 300);
+        for entry in renames {
+            assert!(
+                !PYTHON_BUILTINS.contains(&entry.renamed.as_str()),
+                "{} renamed to builtin {}",
+                entry.original,
+                entry.renamed
+            );
+        }
+        assert!(renames.iter().all(|entry| entry.renamed != "id"));
+    }
+
+    #[test]
+    fn generated_names_avoid_attributes_of_renamed_locals() {
+        let source = r#"
+def read(value):
+    item = value
+    return item.a + item.b
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let renamed: Vec<&str> = plan.functions[0]
+            .renames
+            .iter()
+            .map(|entry| entry.renamed.as_str())
+            .collect();
+        assert_eq!(renamed, vec!["c", "d"]);
+    }
+
+    #[test]
+    fn plans_nested_functions() {
+        let source = r#"
+def outer():
+    x = 1
+    def inner(y):
+        z = y + x
+        return z
+    return inner(2)
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert_eq!(plan.functions.len(), 2);
+
+        let outer = &plan.functions[0];
+        assert_eq!(outer.qualified_name, "outer");
+        assert!(outer.locals.is_empty());
+        assert!(outer.renames.is_empty());
+        assert!(outer.has_nested_functions);
+        assert!(!outer.has_imports);
+        assert_eq!(outer.nested.len(), 1);
+        assert_eq!(outer.nested[0].qualified_name, "outer.inner");
+
+        let inner = &plan.functions[1];
+        assert_eq!(inner.qualified_name, "outer.inner");
+        assert_eq!(inner.locals, vec!["y", "z"]);
+        assert_eq!(inner.renames[0].renamed, "a");
+        assert_eq!(inner.renames[1].renamed, "b");
+        assert!(!inner.has_nested_functions);
+        assert!(!inner.has_imports);
+        assert!(inner.range.is_some());
+    }
+
+    #[test]
+    fn plan_records_globals_and_nonlocals() {
+        let source = r#"
+G = 0
+
+def outer():
+    global G
+    x = 1
+    def inner():
+        nonlocal x
+        return x
+    return x
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert_eq!(plan.functions.len(), 2);
+
+        let outer = plan
+            .functions
+            .iter()
+            .find(|f| f.qualified_name == "outer")
+            .expect("outer plan present");
+        assert!(outer.excluded.contains(&"G".to_string()));
+
+        let inner = plan
+            .functions
+            .iter()
+            .find(|f| f.qualified_name == "outer.inner")
+            .expect("inner plan present");
+        assert!(inner.excluded.contains(&"x".to_string()));
+    }
+
+    #[test]
+    fn rewrite_nested_functions_preserves_closure() {
+        let source = r#"
+def outer(value):
+    captured = value * 2
+    def inner(extra):
+        total = captured + extra
+        return total
+    result = inner(value)
+    return result
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("def outer(a):"));
+        assert!(rewritten.contains("def inner(a):"));
+        assert!(rewritten.contains("captured = a * 2"));
+        assert!(rewritten.contains("b = captured + a"));
+        assert!(rewritten.contains("b = inner(a)"));
+    }
+
+    #[test]
+    fn rewrite_plain_import_adds_alias() {
+        let source = r#"
+def loader(path):
+    import json
+    data = json.load(open(path))
+    return data
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("import json as b"));
+        assert!(rewritten.contains("b.load(open(a))"));
+    }
+
+    #[test]
+    fn rewrite_applies_simple_plan() {
+        let source = r#"
+def identity(value):
+    result = value + 1
+    return result
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        let expected = r#"
+def identity(a):
+    b = a + 1
+    return b
+"#;
+        assert_eq!(rewritten, expected);
+    }
+
+    #[test]
+    fn rewrite_with_plan_matches_rewrite_source() {
+        let source = r#"
+def identity(value):
+    result = value + 1
+    return result
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let via_source = Minifier::rewrite_source("sample", source).unwrap();
+        let via_plan = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert_eq!(via_source, via_plan);
+    }
+
+    #[test]
+    fn rewrite_function_touches_only_the_named_function() {
+        let source = r#"
+def untouched(value):
+    """Kept as is."""
+    result = value + 1
+    return result
+
+
+class Service:
+    def handle(self, request):
+        """Handle one request."""
+        payload = request.body
+        return payload
+"#;
+
+        let rewritten = Minifier::rewrite_function("api", source, "Service.handle").unwrap();
+        let (before, after) = source.split_once("class Service:").unwrap();
+        assert!(rewritten.starts_with(before));
+        let handle = rewritten.split_once("class Service:").unwrap().1;
+        assert_ne!(handle, after);
+        assert!(!handle.contains("request.body"));
+        assert!(!handle.contains("payload"));
+        assert!(handle.contains("\"\"\"Handle one request.\"\"\""));
+
+        let prefixed = Minifier::rewrite_function("api", source, "api.Service.handle").unwrap();
+        assert_eq!(prefixed, rewritten);
+
+        let plan = Minifier::plan_from_source("api", source)
+            .unwrap()
+            .only_functions(&["Service.handle"])
+            .unwrap();
+        assert_eq!(plan.targets, vec!["Service.handle".to_string()]);
+        let outcome = Minifier::rewrite_with_plan_detailed("api", source, &plan).unwrap();
+        assert_eq!(outcome.applied, vec!["Service.handle".to_string()]);
+    }
+
+    #[test]
+    fn rewrite_function_rejects_unknown_names() {
+        let source = "def first(a_value):\n    return a_value\n\n\ndef second(b_value):\n    return b_value\n";
+
+        let err = Minifier::rewrite_function("mod", source, "third").unwrap_err();
+        match &err {
+            TsrsError::UnknownFunction { name, available } => {
+                assert_eq!(name, "third");
+                assert_eq!(available, &vec!["first".to_string(), "second".to_string()]);
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+        assert!(err.to_string().contains("available: first, second"));
+    }
+
+    #[test]
+    fn planned_occurrences_predict_rewrite_size() {
+        let source = r#"
+def identity(value):
+    """Add one."""
+    result = value + 1
+    total = result * result
+    return total
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let renames = &plan.functions[0].renames;
+        let occurrences = |name: &str| {
+            renames
+                .iter()
+                .find(|entry| entry.original == name)
+                .map(|entry| entry.occurrences)
+        };
+        assert_eq!(occurrences("value"), Some(2));
+        assert_eq!(occurrences("result"), Some(3));
+        assert_eq!(occurrences("total"), Some(2));
+
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        let estimate =
+            plan.estimated_rename_savings() + Minifier::docstring_bytes("sample", source).unwrap();
+        assert_eq!(source.len() - rewritten.len(), estimate);
+    }
+
+    #[test]
+    fn rewrite_noop_with_nested_function() {
+        let source = r#"
+def wrapper(value):
+    def inner(x):
+        return x + value
+    return inner(value)
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("def wrapper(value):"));
+        assert!(rewritten.contains("def inner(a):"));
+        assert!(rewritten.contains("return a + value"));
+        assert!(rewritten.contains("return inner(value)"));
+    }
+
+    #[test]
+    fn rewrite_handles_import_alias() {
+        let source = r#"
+def loader(path):
+    import json as j
+    data = j.load(path)
+    return data
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.functions[0].excluded.contains(&"j".to_string()));
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("import json as j"));
+        assert!(rewritten.contains("j.load(a)"));
+    }
+
+    #[test]
+    fn rewrite_handles_from_import_without_alias() {
+        let source = r#"
+def join(parts):
+    from os import path
+    return path.join(*parts)
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("from os import path as b"));
+        assert!(rewritten.contains("return b.join(*a)"));
+    }
+
+    #[test]
+    fn rewrite_handles_from_import_alias() {
+        let source = r#"
+def normalize(parts):
+    from os.path import join as join_path
+    return join_path(*parts)
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.functions[0]
+            .excluded
+            .contains(&"join_path".to_string()));
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("from os.path import join as join_path"));
+        assert!(rewritten.contains("return join_path(*a)"));
+    }
+
+    #[test]
+    fn rewrite_handles_comprehension() {
+        let source = r#"
+def transform(data, offset):
+    threshold = offset - 1
+    return [value + offset for value in data if value > threshold]
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert_eq!(rewritten, source);
+    }
+
+    #[test]
+    fn rewrite_respects_global_and_nonlocal() {
+        let source = r#"
+counter = 0
+
+def outer(value):
+    global counter
+    total = value + counter
+    counter = total
+    def inner():
+        nonlocal total
+        total = total + 1
+        return total
+    return inner()
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("global counter"));
+        assert!(rewritten.contains("nonlocal total"));
+        assert!(rewritten.contains("total = a + counter"));
+        assert!(rewritten.contains("counter = total"));
+        assert!(rewritten.contains("total = total + 1"));
+        assert!(rewritten.contains("def inner():"));
+    }
+
+    #[test]
+    fn annassign_annotation_not_renamed() {
+        let source = r#"
+def assign(value):
+    data: value = value
+    return data
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("def assign(a):"));
+        assert!(rewritten.contains("b: value = a"));
+        assert!(rewritten.contains("return b"));
+    }
+
+    #[test]
+    fn arg_annotation_not_renamed() {
+        let source = r#"
+def annotate(value: value) -> value:
+    return value
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("def annotate(a: value) -> value:"));
+        assert!(rewritten.contains("return a"));
+    }
+
+    #[test]
+    fn rewrite_skips_from_import_star() {
+        let source = r#"
+from tools import *
+
+def outer(value):
+    helper = value + 1
+    return helper
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("from tools import *"));
+        assert!(rewritten.contains("def outer(a):"));
+        assert!(rewritten.contains("b = a + 1"));
+    }
+
+    #[test]
+    fn rewrite_handles_import_alias_mixture() {
+        let source = r#"
+def combine(a):
+    import json
+    import yaml as y
+    data = json.dumps(a)
+    return y.safe_load(data)
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.functions[0].excluded.contains(&"y".to_string()));
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("import json as b"));
+        assert!(rewritten.contains("import yaml as y"));
+        assert!(rewritten.contains("c = b.dumps(a)"));
+        assert!(rewritten.contains("return y.safe_load(c)"));
+    }
+
+    #[test]
+    fn rewrite_strips_module_and_function_docstrings() {
+        let source = "\"\"\"Module doc\"\"\"\n\n\ndef demo(value):\n    \"\"\"Function doc\"\"\"\n    return value\n";
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        let expected = r#"
+
+def demo(a):
+    return a
+"#;
+        assert_eq!(rewritten, expected);
+    }
+
+    #[test]
+    fn rewrite_strips_class_docstrings() {
+        let source = "\"\"\"Module doc\"\"\"\n\n\nclass Demo:\n    \"\"\"Class doc\"\"\"\n\n    def value(self):\n        \"\"\"Method doc\"\"\"\n        return 1\n";
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        let expected = r#"
+
+class Demo:
+
+    def value(self):
+        return 1
+"#;
+        assert_eq!(rewritten, expected);
+    }
+
+    #[test]
+    fn rewrite_preserves_non_docstring_literals() {
+        let source = r#"
+def demo():
+    helper = "keep me"
+    return helper
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("= \"keep me\""));
+    }
+
+    #[test]
+    fn rewrite_strips_nested_function_docstrings() {
+        let source = "def outer():\n    \"\"\"Outer doc\"\"\"\n    def inner():\n        \"\"\"Inner doc\"\"\"\n        return 1\n    return inner()\n";
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(!rewritten.contains("Outer doc"));
+        assert!(!rewritten.contains("Inner doc"));
+        assert!(rewritten.contains("def outer():"));
+        assert!(rewritten.contains("def inner():"));
+        assert!(rewritten.contains("return inner()"));
+    }
+
+    #[test]
+    fn rewrite_strips_async_function_docstrings() {
+        let source = "async def fetch():\n    \"\"\"Fetch doc\"\"\"\n    return 1\n";
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(!rewritten.contains("Fetch doc"));
+        assert!(rewritten.contains("async def fetch():"));
+        assert!(rewritten.contains("return 1"));
+    }
+
+    #[test]
+    fn rewrite_strips_docstrings_with_decorators() {
+        let source = "@decorator\nclass Demo:\n    \"\"\"Class doc\"\"\"\n\n    @property\n    def value(self):\n        \"\"\"Property doc\"\"\"\n        return 1\n";
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.starts_with("@decorator"));
+        assert!(rewritten.contains("@property"));
+        assert!(rewritten.contains("def value(self):"));
+        assert!(!rewritten.contains("Class doc"));
+        assert!(!rewritten.contains("Property doc"));
+    }
+
+    #[test]
+    fn rewrite_strips_multiline_docstrings_preserving_body() {
+        let source = "def describe():\n    \"\"\"Line one.\n\n    Line two.\n    \"\"\"\n    return \"ok\"\n";
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(!rewritten.contains("Line one"));
+        assert!(rewritten.contains("def describe():"));
+        assert!(rewritten.contains("return \"ok\""));
+    }
+
+    #[test]
+    fn rewrite_keeps_docstrings_that_are_the_whole_body() {
+        let source = "class Marker:\n    \"\"\"Marker doc\"\"\"\n\n\ndef stub():\n    \"\"\"Stub doc\"\"\"\n";
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert_eq!(rewritten, source);
+        assert!(ast::Suite::parse(&rewritten, "sample").is_ok());
+    }
+
+    #[test]
+    fn plans_comprehension_sets_flag() {
+        let source = r#"
+def build(nums):
+    return [n * 2 for n in nums]
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert_eq!(plan.functions.len(), 1);
+        assert!(plan.functions[0].has_comprehension);
+    }
+
+    #[test]
+    fn rewrite_bails_on_comprehension() {
+        let source = r#"
+def outer(values):
+    total = 0
+    def inner(items):
+        return [item * 2 for item in items]
+    total = total + len(values)
+    return total
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("def outer(a):"));
+        assert!(rewritten.contains("b = 0"));
+        assert!(rewritten.contains("def inner(items):"));
+        assert!(rewritten.contains("return [item * 2 for item in items]"));
+        assert!(rewritten.contains("b = b + len(a)"));
+    }
+
+    #[test]
+    fn rewrite_replacements_stable() {
+        let source = r#"
+def demo(alpha, beta):
+    alpha_beta_gamma = alpha + beta
+    alpha_beta = alpha + beta
+    return alpha_beta_gamma + alpha_beta
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        let expected = r#"
+def demo(a, b):
+    c = a + b
+    d = a + b
+    return c + d
+"#;
+        assert_eq!(rewritten, expected);
+    }
+
+    #[test]
+    fn rewrite_skips_dotted_import_without_alias() {
+        let source = r#"
+def make_path(parts):
+    import os.path
+    return os.path.join(*parts)
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.functions[0].excluded.contains(&"os".to_string()));
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("import os.path"));
+        assert!(rewritten.contains("return os.path.join(*a)"));
+    }
+
+    #[test]
+    fn rewrite_handles_from_import_multiple() {
+        let source = r#"
+def use_pkg(a, b):
+    from pkg import thing, another
+    return thing(a) + another(b)
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("from pkg import thing as c, another as d"));
+        assert!(rewritten.contains("return c(a) + d(b)"));
+    }
+
+    #[test]
+    fn rewrite_noop_with_match() {
+        let source = r#"
+def classify(value):
+    match value:
+        case 0:
+            return "zero"
+        case other:
+            return other
+    temp = value + 1
+    return temp
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert_eq!(rewritten, source);
+    }
+
+    #[test]
+    fn comprehensions_preserve_outer_names() {
+        let source = r#"
+def make_lists(values):
+    total = 0
+    squares = [total + num for num in values]
+    return squares, total
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.functions[0].has_comprehension);
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert_eq!(rewritten, source);
+    }
+
+    #[test]
+    fn string_annotation_names_are_excluded() {
+        let source = r#"
+def build(value, parent: "Optional[Alias]") -> "Alias":
+    Alias = int
+    result: "Alias" = value
+    return result
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let build = &plan.functions[0];
+        assert!(build.excluded.contains(&"Alias".to_string()));
+        assert!(!build.locals.contains(&"Alias".to_string()));
+        assert!(build.locals.contains(&"result".to_string()));
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("    Alias = int\n"));
+        assert!(rewritten.contains(": \"Alias\" = "));
+    }
+
+    #[test]
+    fn format_spec_locals_renamed_with_expression() {
+        let source = r#"
+def render(value, width):
+    return f"{value:>{width}}"
+"#;
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        let expected = r#"
+def render(a, b):
+    return f"{a:>{b}}"
+"#;
+        assert_eq!(rewritten, expected);
+    }
+
+    #[test]
+    fn module_global_read_in_else_branch_is_not_shadowed() {
+        let source = r#"
+a = 1
+
+def choose(flag, value):
+    if flag:
+        result = value
+    else:
+        result = a
+    return result
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let function = &plan.functions[0];
+        assert!(function.renames.iter().all(|entry| entry.renamed != "a"));
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        let expected = r#"
+a = 1
+
+def choose(b, c):
+    if b:
+        d = c
+    else:
+        d = a
+    return d
+"#;
+        assert_eq!(rewritten, expected);
+    }
+
+    #[test]
+    fn module_bindings_in_compound_statements_are_reserved() {
+        let source = r#"
+def helper(value):
+    inner = value
+    return inner
+
+if True:
+    b = 2
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        for function in &plan.functions {
+            assert!(function.renames.iter().all(|entry| entry.renamed != "b"));
+        }
+    }
+
+    #[test]
+    fn conditional_module_bindings_are_reserved_in_every_plan() {
+        // Sequential naming hands out `a`, `b`, `c`, ... first, so each would collide.
+        let source = r#"
+from __future__ import annotations
+from typing import TYPE_CHECKING
+
+if TYPE_CHECKING:
+    from models import a
+
+try:
+    from fast import b
+except ImportError:
+    b = None
+
+match CONFIG:
+    case {"mode": c}:
+        pass
+
+if (d := load()) is not None:
+    pass
+
+def configure():
+    global e
+    e = 1
+
+class Loader:
+    def read(self, first, second):
+        total = first + second
+        return total
+
+def run(value: a, extra):
+    result = value + extra
+    return result
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let renamed: Vec<(&str, &str)> =
+            plan.functions
+                .iter()
+                .flat_map(|function| {
+                    function.renames.iter().map(move |entry| {
+                        (function.qualified_name.as_str(), entry.renamed.as_str())
+                    })
+                })
+                .collect();
+        assert!(renamed.iter().any(|(function, _)| *function == "run"));
+        assert!(renamed
+            .iter()
+            .any(|(function, _)| *function == "Loader.read"));
+        for (function, short) in renamed {
+            assert!(
+                !["a", "b", "c", "d", "e"].contains(&short),
+                "{function} renamed a local to module-level `{short}`"
+            );
+        }
+    }
+
+    #[test]
+    fn plan_records_bailout_reason() {
+        let source = r#"
+def collect(items):
+    return [item for item in items]
+
+def dispatch(command):
+    match command:
+        case "go":
+            return 1
+    return 0
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let reasons: HashMap<&str, Option<BailoutReason>> = plan
+            .functions
+            .iter()
+            .map(|function| (function.qualified_name.as_str(), function.bailout_reason))
+            .collect();
+        assert_eq!(reasons["collect"], Some(BailoutReason::Comprehension));
+        assert_eq!(reasons["dispatch"], Some(BailoutReason::MatchStatement));
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["functions"][0]["bailout_reason"], "comprehension");
+    }
+
+    #[test]
+    fn conditional_redefinitions_are_planned_and_renamed_independently() {
+        let source = r#"
+import sys
+
+if sys.platform == "win32":
+    def get_path(name):
+        drive = "C:"
+        result = drive + "\\" + name
+        return result
+else:
+    def get_path(name, default=None):
+        prefix = "/usr"
+        joined = prefix + "/" + name
+        return joined or default
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let names: Vec<&str> = plan
+            .functions
+            .iter()
+            .map(|function| function.qualified_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["get_path", "get_path#2"]);
+        let originals = |index: usize| -> Vec<&str> {
+            plan.functions[index]
+                .renames
+                .iter()
+                .map(|entry| entry.original.as_str())
+                .collect()
+        };
+        assert!(originals(0).contains(&"result"));
+        assert!(!originals(0).contains(&"joined"));
+        assert!(originals(1).contains(&"joined"));
+        assert!(!originals(1).contains(&"result"));
+        assert_ne!(plan.functions[0].range, plan.functions[1].range);
+
+        let outcome = Minifier::rewrite_with_plan_detailed("sample", source, &plan).unwrap();
+        assert_eq!(
+            outcome.applied,
+            vec!["get_path".to_string(), "get_path#2".to_string()]
+        );
+        for local in ["drive", "result", "prefix", "joined"] {
+            assert!(!outcome.source.contains(local), "{}", outcome.source);
+        }
+        assert_eq!(outcome.source.matches("def get_path(").count(), 2);
+        ast::Suite::parse(&outcome.source, "sample").unwrap();
+    }
+
+    #[test]
+    fn detailed_rewrite_reports_applied_and_skipped() {
+        let source = r#"
+def collect(items):
+    return [item for item in items]
+
+def shift(amount):
+    total = amount + 1
+    return total
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let outcome = Minifier::rewrite_with_plan_detailed("sample", source, &plan).unwrap();
+        assert_eq!(outcome.applied, vec!["shift".to_string()]);
+        assert_eq!(
+            outcome.skipped,
+            vec![("collect".to_string(), "comprehension".to_string())]
+        );
+        assert!(outcome.source.contains("def shift(a):"));
+        assert_eq!(
+            outcome.source,
+            Minifier::rewrite_with_plan("sample", source, &plan).unwrap()
+        );
+    }
+
+    #[test]
+    fn detailed_rewrite_reports_per_function_size() {
+        let source = r#"
+def shift(amount):
+    total = amount + 1
+    return total
+
+def scale(factor):
+    return factor * 2
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let outcome = Minifier::rewrite_with_plan_detailed("sample", source, &plan).unwrap();
+        let names: Vec<&str> = outcome
+            .functions
+            .iter()
+            .map(|f| f.qualified_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["shift", "scale"]);
+
+        let shift = &outcome.functions[0];
+        assert_eq!(shift.replacements, 4);
+        let range = plan.functions[0].range.unwrap();
+        assert_eq!(shift.bytes_before, range.end - range.start);
+        // amount -> a and total -> b, twice each
+        assert_eq!(shift.bytes_before - shift.bytes_after, 2 * 5 + 2 * 4);
+
+        let saved: usize = outcome
+            .functions
+            .iter()
+            .map(|f| f.bytes_before - f.bytes_after)
+            .sum();
+        assert_eq!(source.len() - outcome.source.len(), saved);
+    }
+
+    #[test]
+    fn function_ranges_report_lines_and_character_columns() {
+        let source = "# héllo wörld ✓\nGREETING = \"naïve café\"\n\nclass Box:\n    def label(self, text):\n        suffix = text.strip()\n        return suffix + \"→\"\n";
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let range = plan.functions[0].range.unwrap();
+        assert_eq!(range.start, source.find("def label").unwrap());
+        assert_eq!((range.start_line, range.start_col), (5, 5));
+        assert_eq!((range.end_line, range.end_col), (7, 28));
+        assert_eq!(range.end, source.len() - 1);
+
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert!(rewritten.ends_with(" + \"→\"\n"));
+    }
+
+    #[test]
+    fn functions_after_cjk_comments_keep_byte_offsets() {
+        let source = "# 这是一个测试注释，包含多字节字符\n# 第二行\ndef total(values):\n    result = values + 1\n    return result\n";
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let range = plan.functions[0].range.unwrap();
+        assert_eq!(range.start, source.find("def total").unwrap());
+        assert_eq!((range.start_line, range.start_col), (3, 1));
+
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "# 这是一个测试注释，包含多字节字符\n# 第二行\ndef total(a):\n    b = a + 1\n    return b\n"
+        );
+    }
+
+    #[test]
+    fn non_ascii_locals_are_renamed() {
+        let source = "def messe(größe, λ_):\n    fläche = größe * λ_\n    return fläche\n";
+
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert_eq!(rewritten, "def messe(a, b):\n    c = a * b\n    return c\n");
+    }
+
+    #[test]
+    fn generated_names_avoid_nfkc_forms_of_module_globals() {
+        // Python reads the fullwidth `ａ` as `a`.
+        let source = "ａ = 1\n\ndef shift(value):\n    return value + ａ\n";
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert_eq!(plan.functions[0].renames[0].renamed, "b");
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert_eq!(rewritten, "ａ = 1\n\ndef shift(b):\n    return b + ａ\n");
+    }
+
+    #[test]
+    fn locals_read_under_another_nfkc_spelling_are_kept() {
+        let source = "def count(s):\n    return ſ + s\n";
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.functions[0].renames.is_empty());
+        assert!(plan.functions[0].excluded.contains(&"s".to_string()));
+
+        // The micro sign folds to the Greek letter, outside the ASCII range.
+        let source = "def scale(μs):\n    return µs * 2\n";
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.functions[0].renames.is_empty());
+        assert!(plan.functions[0].excluded.contains(&"μs".to_string()));
+    }
+
+    #[test]
+    fn identifier_search_skips_ascii_inside_unicode_names() {
+        let source = "größe = e\nxλ_ = λ_\n";
+        let whole = FunctionRange {
+            start: 0,
+            end: source.len(),
+            ..FunctionRange::default()
+        };
+
+        let e = source.rfind('e').unwrap();
+        assert_eq!(
+            find_identifier_in_range(source, &whole, "e"),
+            Some((e, e + 1))
+        );
+        let lambda = source.rfind("λ_").unwrap();
+        assert_eq!(
+            find_identifier_in_range(source, &whole, "λ_"),
+            Some((lambda, lambda + "λ_".len()))
+        );
+    }
+
+    #[test]
+    fn identifiers_normalize_compatibility_characters_to_ascii() {
+        assert_eq!(normalize_identifier("ſum"), "sum");
+        assert_eq!(normalize_identifier("ﬁle"), "file");
+        assert_eq!(normalize_identifier("ｘ１"), "x1");
+        assert_eq!(normalize_identifier("\u{1D431}"), "x");
+        assert_eq!(normalize_identifier("Ⅻ"), "XII");
+        assert_eq!(normalize_identifier("µs"), "μs");
+        assert_eq!(normalize_identifier("ℌ"), "H");
+        assert!(matches!(
+            normalize_identifier("größe"),
+            Cow::Borrowed("größe")
+        ));
+    }
+
+    #[test]
+    fn detailed_rewrite_reports_abort_reason() {
+        let source = r#"
+def handler(event):
+    callback = lambda: event
+    return callback
+"#;
 
-    fn record_arg(&mut self, arg: &ast::Arg) {
-        let name = arg.arg.as_ref();
-        let arg_range = range_from_node(arg);
-        if let Some((start, end)) = find_identifier_in_range(self.source, &arg_range, name) {
-            self.record_identifier(name, FunctionRange { start, end });
-        } else {
-            self.abort = true;
-            return;
-        }
-        if let Some(annotation) = &arg.annotation {
-            self.with_annotation(|collector| collector.visit_expr(annotation));
-        }
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let outcome = Minifier::rewrite_with_plan_detailed("sample", source, &plan).unwrap();
+        assert_eq!(outcome.source, source);
+        assert!(outcome.applied.is_empty());
+        assert_eq!(
+            outcome.skipped,
+            vec![("handler".to_string(), "lambda".to_string())]
+        );
     }
 
-    fn record_identifier(&mut self, name: &str, node_range: FunctionRange) {
-        if self.in_annotation {
-            return;
-        }
-        if self.abort {
-            return;
-        }
+    #[test]
+    fn non_capturing_lambda_keeps_enclosing_locals_renamed() {
+        let source = r#"
+def by_name(records):
+    ordered = sorted(records, key=lambda item: item.name)
+    return ordered
+"#;
 
-        if self.excluded.contains(name) {
-            return;
-        }
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let function = &plan.functions[0];
+        assert!(!function.has_nested_functions);
+        assert_eq!(function.locals, vec!["records", "ordered"]);
+        assert!(function.excluded.contains(&"item".to_string()));
 
-        let new_name = match self.renames.get(name) {
-            Some(new_name) if name != *new_name => *new_name,
-            _ => return,
-        };
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "\ndef by_name(a):\n    b = sorted(a, key=lambda item: item.name)\n    return b\n"
+        );
+    }
 
-        if node_range.start < self.function_range.start || node_range.end > self.function_range.end
-        {
-            self.abort = true;
-            return;
-        }
+    #[test]
+    fn lambda_reading_an_enclosing_local_still_bails() {
+        let source = r#"
+def scaled(values, factor):
+    result = sorted(values, key=lambda value: value * factor)
+    return result
+"#;
 
-        let start = node_range.start;
-        let end = node_range.end;
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.functions[0].has_nested_functions);
+        let outcome = Minifier::rewrite_with_plan_detailed("sample", source, &plan).unwrap();
+        assert_eq!(outcome.source, source);
+        assert_eq!(
+            outcome.skipped,
+            vec![("scaled".to_string(), "lambda".to_string())]
+        );
+    }
 
-        if end > self.source.len() || start >= end {
-            self.abort = true;
-            return;
-        }
+    #[test]
+    fn off_directive_excludes_decorated_function() {
+        let source = r#"
+import functools
 
-        let slice = &self.source[start..end];
-        if slice != name {
-            self.abort = true;
-            return;
-        }
+# tsrs: off
+@functools.lru_cache()
+def cached(value):
+    result = value * 2
+    return result
 
-        self.replacements.push(Replacement {
-            start,
-            end,
-            text: new_name.to_string(),
-        });
-    }
+@functools.lru_cache()
+def traced(value):  # tsrs: off
+    result = value + 1
+    return result
 
-    fn record_except_name(&mut self, handler: &ast::ExceptHandlerExceptHandler, name: &str) {
-        if self.abort {
-            return;
-        }
+def plain(value):
+    result = value - 1
+    return result
+"#;
 
-        let new_name = match self.renames.get(name) {
-            Some(new_name) if name != *new_name => *new_name,
-            _ => return,
-        };
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let names: Vec<&str> = plan
+            .functions
+            .iter()
+            .map(|function| function.qualified_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["plain"]);
 
-        let handler_range = range_from_node(handler);
-        if let Some((start, end)) = find_except_name_range(self.source, &handler_range, name) {
-            self.replacements.push(Replacement {
-                start,
-                end,
-                text: new_name.to_string(),
-            });
-        } else {
-            self.abort = true;
-        }
+        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
+        assert!(rewritten.contains("def cached(value):"));
+        assert!(rewritten.contains("def traced(value):  # tsrs: off"));
+        assert!(rewritten.contains("def plain(a):"));
     }
-}
 
-fn find_identifier_in_range(
-    source: &str,
-    range: &FunctionRange,
-    name: &str,
-) -> Option<(usize, usize)> {
-    let start = range.start.min(source.len());
-    let end = range.end.min(source.len());
-    if start >= end {
-        return None;
+    #[test]
+    fn directives_apply_to_async_defs() {
+        let source = r#"
+async def fetch(url, retries):  # tsrs: keep-names url, payload
+    payload = await get(url, retries)
+    return payload
+
+# tsrs: off
+async def send(message):
+    body = message
+    return body
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert_eq!(plan.functions.len(), 1);
+        let fetch = &plan.functions[0];
+        assert_eq!(fetch.qualified_name, "fetch");
+        assert!(fetch.excluded.contains(&"url".to_string()));
+        assert!(fetch.excluded.contains(&"payload".to_string()));
+        let renamed: Vec<&str> = fetch
+            .renames
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect();
+        assert_eq!(renamed, vec!["retries"]);
     }
 
-    let slice = &source[start..end];
-    let mut offset = 0usize;
-    while let Some(rel_idx) = slice[offset..].find(name) {
-        let idx = offset + rel_idx;
-        let before = slice[..idx].chars().next_back();
-        let after = slice[idx + name.len()..].chars().next();
-        if is_identifier_boundary(before, after) {
-            return Some((start + idx, start + idx + name.len()));
-        }
-        offset = idx + 1;
+    #[test]
+    fn skip_file_directive_leaves_module_untouched() {
+        let source = r#"#!/usr/bin/env python
+# tsrs: skip-file
+"""Module docstring."""
+
+def compute(value):
+    """Docstring."""
+    result = value + 1
+    return result
+"#;
+
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.skip_file);
+        assert!(plan.functions.is_empty());
+        assert_eq!(Minifier::rewrite_source("sample", source).unwrap(), source);
     }
 
-    None
-}
+    #[test]
+    fn skip_file_directive_ignored_after_header() {
+        let mut source = "\n".repeat(SKIP_FILE_DIRECTIVE_LINES);
+        source.push_str("# tsrs: skip-file\ndef compute(value):\n    return value\n");
 
-fn find_except_name_range(
-    source: &str,
-    handler_range: &FunctionRange,
-    name: &str,
-) -> Option<(usize, usize)> {
-    let start = handler_range.start.min(source.len());
-    let end = handler_range.end.min(source.len());
-    if start >= end {
-        return None;
+        let plan = Minifier::plan_from_source("sample", &source).unwrap();
+        assert!(!plan.skip_file);
+        assert_eq!(plan.functions.len(), 1);
     }
 
-    let slice = &source[start..end];
-    let mut offset = 0usize;
-    while let Some(rel_idx) = slice[offset..].find(name) {
-        let idx = offset + rel_idx;
-        let prefix = slice[..idx].trim_end();
-        if prefix.ends_with("as")
-            && is_identifier_boundary(
-                slice[..idx].chars().next_back(),
-                slice[idx + name.len()..].chars().next(),
-            )
-        {
-            return Some((start + idx, start + idx + name.len()));
-        }
-        offset = idx + 1;
+    fn rename_map(plan: &MinifyPlan) -> HashMap<String, String> {
+        plan.functions[0]
+            .renames
+            .iter()
+            .map(|entry| (entry.original.clone(), entry.renamed.clone()))
+            .collect()
     }
 
-    None
-}
+    #[test]
+    fn stable_names_survive_unrelated_local() {
+        let before = "def compute(value, scale):\n    total = value * scale\n    result = total + 1\n    return result\n";
+        let after = "def compute(value, scale):\n    extra = 0\n    total = value * scale\n    result = total + 1\n    return result + extra\n";
+
+        let first =
+            Minifier::plan_from_source_with_naming("sample", before, NamingMode::Stable).unwrap();
+        let second =
+            Minifier::plan_from_source_with_naming("sample", after, NamingMode::Stable).unwrap();
+        assert_eq!(first.naming, NamingMode::Stable);
+        assert_eq!(second.naming, NamingMode::Stable);
+
+        let old = rename_map(&first);
+        let mut new = rename_map(&second);
+        assert!(new.remove("extra").is_some());
+        assert_eq!(new, old);
+        assert!(!old.is_empty());
+
+        let sequential = Minifier::plan_from_source("sample", after).unwrap();
+        assert_eq!(sequential.naming, NamingMode::Sequential);
+        assert_ne!(
+            rename_map(&sequential).get("total"),
+            rename_map(&Minifier::plan_from_source("sample", before).unwrap()).get("total")
+        );
+    }
 
-fn is_identifier_boundary(prev: Option<char>, next: Option<char>) -> bool {
-    let prev_ok = !prev.is_some_and(is_identifier_char);
-    let next_ok = !next.is_some_and(is_identifier_char);
-    prev_ok && next_ok
-}
+    #[test]
+    fn naming_mode_is_recorded_in_plan_json() {
+        let source = "def compute(value):\n    total = value + 1\n    return total\n";
+        let plan =
+            Minifier::plan_from_source_with_naming("sample", source, NamingMode::Stable).unwrap();
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["naming"], "stable");
+
+        let restored: MinifyPlan = serde_json::from_value(json).unwrap();
+        assert_eq!(restored.naming, NamingMode::Stable);
+        assert_eq!(rename_map(&restored), rename_map(&plan));
+        assert_eq!(
+            Minifier::rewrite_with_plan("sample", source, &restored).unwrap(),
+            Minifier::rewrite_with_plan("sample", source, &plan).unwrap()
+        );
+    }
 
-fn is_identifier_char(c: char) -> bool {
-    c == '_' || c.is_ascii_alphanumeric()
-}
+    #[test]
+    fn naming_config_spells_generated_names() {
+        let source = "def compute(value, scale):\n    total = value * scale\n    result = total + 1\n    return result\n";
+        let naming_config = NamingConfig::new(Some("_t"), Some("xyz")).unwrap();
+        let options = PlanOptions {
+            naming_config: naming_config.clone(),
+            ..PlanOptions::default()
+        };
+        let plan = Minifier::plan_from_source_with_options("sample", source, options).unwrap();
+        let mut renamed: Vec<String> = rename_map(&plan).into_values().collect();
+        renamed.sort();
+        assert_eq!(renamed, vec!["_tx", "_txx", "_ty", "_tz"]);
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["naming_config"]["prefix"], "_t");
+        assert_eq!(json["naming_config"]["alphabet"], "xyz");
+        let restored: MinifyPlan = serde_json::from_value(json).unwrap();
+        assert_eq!(restored.naming_config, naming_config);
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &restored).unwrap();
+        assert!(rewritten.contains("def compute(_tx, _ty):"));
+
+        let stable = PlanOptions {
+            naming: NamingMode::Stable,
+            naming_config: naming_config.clone(),
+            ..PlanOptions::default()
+        };
+        let plan = Minifier::plan_from_source_with_options("sample", source, stable).unwrap();
+        assert!(rename_map(&plan)
+            .values()
+            .all(|name| naming_config.matches(name)));
+
+        let default_plan = Minifier::plan_from_source("sample", source).unwrap();
+        let json = serde_json::to_value(&default_plan).unwrap();
+        assert!(json.get("naming_config").is_none());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn naming_config_rejects_names_that_are_not_identifiers() {
+        let error = |prefix: Option<&str>, alphabet: Option<&str>| {
+            NamingConfig::new(prefix, alphabet).unwrap_err().to_string()
+        };
+        assert!(error(Some("t-"), None).contains("contains `-`"));
+        assert!(error(Some("1t"), None).contains("starts with a digit"));
+        assert!(error(Some("__t"), None).contains("mangles"));
+        assert!(error(None, Some("")).contains("empty"));
+        assert!(error(None, Some("ab_")).contains("contains `_`"));
+        assert!(error(None, Some("abca")).contains("repeats `a`"));
+        assert!(error(None, Some("ab01")).contains("need a rename prefix"));
+        assert!(NamingConfig::new(Some("v"), Some("ab01")).is_ok());
+        assert!(NamingConfig::new(None, None).unwrap().is_default());
+    }
 
     #[test]
-    fn plans_parameters_and_locals() {
+    fn validate_enforces_a_declared_naming_scheme() {
+        let source = "def compute(value):\n    total = value + 1\n    return total\n";
+        let options = PlanOptions {
+            naming_config: NamingConfig::new(Some("_t"), None).unwrap(),
+            ..PlanOptions::default()
+        };
+        let mut plan = Minifier::plan_from_source_with_options("sample", source, options).unwrap();
+        plan.validate(source).unwrap();
+        plan.functions[0].renames[0].renamed = "q".to_string();
+        let err = plan.validate(source).unwrap_err().to_string();
+        assert!(err.contains("prefix `_t`"), "{err}");
+
+        let mut plain = Minifier::plan_from_source("sample", source).unwrap();
+        plain.functions[0].renames[0].renamed = "q_1".to_string();
+        plain.validate(source).unwrap();
+    }
+
+    #[test]
+    fn annotate_renames_follow_docstrings_async_defs_and_methods() {
         let source = r#"
-def outer(value, *, option=None):
-    temp = value + 1
-    for idx in range(3):
-        result = temp + idx
-    with context() as handle:
-        extra = handle.do()
-    return result + extra
-"#;
+def compute(value):
+    """Add one."""
+    total = value + 1
+    return total
 
+
+async def fetch(url):
+    # fetch it
+    body = await get(url)
+    return body
+
+
+class Model:
+    def save(self, payload):
+        data = dict(payload)
+        return data
+"#;
         let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert_eq!(plan.functions.len(), 1);
+        let options = RewriteOptions {
+            keep_docstrings: true,
+            annotate_renames: Some(88),
+            ..RewriteOptions::default()
+        };
+        let outcome =
+            Minifier::rewrite_with_plan_options("sample", source, &plan, options).unwrap();
+        let rewritten = &outcome.source;
+        assert!(rewritten.contains(
+            "def compute(a):\n    \"\"\"Add one.\"\"\"\n    # tsrs: a=value, b=total\n    b = a + 1\n"
+        ));
+        assert!(
+            rewritten.contains("async def fetch(a):\n    # tsrs: a=url, b=body\n    # fetch it\n")
+        );
+        assert!(rewritten.contains(
+            "    def save(self, a):\n        # tsrs: a=payload, b=data\n        b = dict(a)\n"
+        ));
+        Minifier::check_syntax("sample", rewritten).unwrap();
+        let annotated: usize = outcome.functions.iter().map(|f| f.annotation_bytes).sum();
+        assert_eq!(annotated, outcome.annotation_bytes);
+        assert_eq!(
+            outcome.annotation_bytes,
+            "    # tsrs: a=value, b=total\n".len()
+                + "    # tsrs: a=url, b=body\n".len()
+                + "        # tsrs: a=payload, b=data\n".len()
+        );
 
-        let outer = &plan.functions[0];
-        assert_eq!(outer.qualified_name, "outer");
+        let narrow = RewriteOptions {
+            annotate_renames: Some(20),
+            ..RewriteOptions::default()
+        };
+        let wrapped = Minifier::rewrite_with_plan_options("sample", source, &plan, narrow)
+            .unwrap()
+            .source;
+        assert!(wrapped.contains(
+            "def compute(a):\n    # tsrs: a=value,\n    # tsrs: b=total\n    b = a + 1\n"
+        ));
+        assert!(!wrapped.contains("Add one."));
+        assert!(!Minifier::rewrite_with_plan("sample", source, &plan)
+            .unwrap()
+            .contains("# tsrs:"));
+    }
+
+    #[test]
+    fn annotate_renames_replace_an_existing_annotation() {
+        let plain = "def compute(value):\n    total = value + 1\n    return total\n";
+        let stale = "def compute(value):\n    # tsrs: a=old, b=older\n    total = value + 1\n    return total\n";
+        let options = RewriteOptions {
+            annotate_renames: Some(88),
+            ..RewriteOptions::default()
+        };
+        let rewrite = |source: &str| {
+            let plan = Minifier::plan_from_source("sample", source).unwrap();
+            Minifier::rewrite_with_plan_options("sample", source, &plan, options)
+                .unwrap()
+                .source
+        };
+
+        let expected =
+            "def compute(a):\n    # tsrs: a=value, b=total\n    b = a + 1\n    return b\n";
+        assert_eq!(rewrite(plain), expected);
+        assert_eq!(rewrite(stale), expected);
+
+        // The comment from a previous run is recognised and rewritten in place.
+        let annotated = expected
+            .replace("(a)", "(value)")
+            .replace("b = a", "total = value");
+        let annotated = annotated.replace("return b", "return total");
+        assert_eq!(rewrite(&annotated), expected);
+        assert_eq!(rewrite(&annotated).matches("# tsrs:").count(), 1);
+    }
+
+    #[test]
+    fn fuzzy_ranges_follow_a_function_shifted_after_planning() {
+        let planned = "def compute(value):\n    total = value + 1\n    return total\n";
+        let plan = Minifier::plan_from_source("sample", planned).unwrap();
+        let shifted = format!("# added after planning\n{planned}");
+
+        let strict = Minifier::rewrite_with_plan_detailed("sample", &shifted, &plan).unwrap();
+        assert_eq!(strict.source, shifted);
+        assert!(strict.fuzzy_matched.is_empty());
+
+        let options = RewriteOptions {
+            fuzzy_ranges: true,
+            ..RewriteOptions::default()
+        };
+        let fuzzy =
+            Minifier::rewrite_with_plan_options("sample", &shifted, &plan, options).unwrap();
+        assert!(fuzzy
+            .source
+            .starts_with("# added after planning\ndef compute(a):"));
+        assert_eq!(fuzzy.fuzzy_matched, vec!["compute"]);
+
+        let exact = Minifier::rewrite_with_plan_options("sample", planned, &plan, options).unwrap();
+        assert!(exact.fuzzy_matched.is_empty());
         assert_eq!(
-            outer.locals,
-            vec!["value", "option", "temp", "idx", "result", "handle", "extra"]
+            exact.source,
+            Minifier::rewrite_with_plan("sample", planned, &plan).unwrap()
         );
-        assert_eq!(outer.renames.len(), outer.locals.len());
-        assert_eq!(outer.renames[0].renamed, "a");
-        assert_eq!(outer.renames[1].renamed, "b");
-        // ensure reserved names recorded when encountered
-        assert!(!outer.excluded.contains(&"context".to_string()));
-        assert!(!outer.has_nested_functions);
-        assert!(!outer.has_imports);
-        assert!(outer.range.is_some());
+
+        // Two definitions under the same name cannot be told apart.
+        let twice = format!("# added\n{planned}\n{planned}");
+        let ambiguous =
+            Minifier::rewrite_with_plan_options("sample", &twice, &plan, options).unwrap();
+        assert_eq!(ambiguous.source, twice);
+        assert!(ambiguous.fuzzy_matched.is_empty());
+    }
+
+    #[test]
+    fn keep_parameters_renames_only_locals() {
+        let source = "def area(width, height, *, scale=1):\n    total = width * height\n    return total * scale\n";
+        let options = PlanOptions {
+            keep_parameters: true,
+            ..PlanOptions::default()
+        };
+        let plan = Minifier::plan_from_source_with_options("shapes", source, options).unwrap();
+        assert!(plan.keep_parameters);
+
+        let area = &plan.functions[0];
+        let renamed: Vec<&str> = area
+            .renames
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect();
+        assert_eq!(renamed, vec!["total"]);
+        for param in ["width", "height", "scale"] {
+            assert!(area.excluded.contains(&param.to_string()));
+        }
+    }
+
+    #[test]
+    fn preserve_annotated_locals_keeps_annotated_targets() {
+        let source = "def build(value):\n    count: int = value + 1\n    scratch = count * 2\n    if scratch:\n        label: str\n        label = str(scratch)\n        return label\n    return scratch\n";
+        let options = PlanOptions {
+            preserve_annotated_locals: true,
+            ..PlanOptions::default()
+        };
+        let plan = Minifier::plan_from_source_with_options("typed", source, options).unwrap();
+        assert!(plan.preserve_annotated_locals);
+
+        let build = &plan.functions[0];
+        let mut renamed: Vec<&str> = build
+            .renames
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect();
+        renamed.sort_unstable();
+        assert_eq!(renamed, vec!["scratch", "value"]);
+        for name in ["count", "label"] {
+            assert!(build.excluded.contains(&name.to_string()));
+        }
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["preserve_annotated_locals"], true);
+        let restored: MinifyPlan = serde_json::from_value(json).unwrap();
+        let rewritten = Minifier::rewrite_with_plan("typed", source, &restored).unwrap();
+        assert_eq!(
+            rewritten,
+            Minifier::rewrite_with_plan("typed", source, &plan).unwrap()
+        );
+        assert!(rewritten.contains("count: int ="));
+        assert!(rewritten.contains("label: str"));
+        assert!(!rewritten.contains("scratch"));
+
+        let default_plan = Minifier::plan_from_source("typed", source).unwrap();
+        assert!(!default_plan.preserve_annotated_locals);
+        assert!(default_plan.functions[0]
+            .renames
+            .iter()
+            .any(|entry| entry.original == "count"));
     }
 
     #[test]
-    fn plans_nested_functions() {
-        let source = r#"
-def outer():
-    x = 1
-    def inner(y):
-        z = y + x
-        return z
-    return inner(2)
-"#;
+    fn min_name_length_keeps_short_locals() {
+        let source = "def load(db, value):\n    ok = db.ping()\n    result = []\n    for i in range(value):\n        result.append(db.get(i))\n    return result if ok else None\n";
+        let options = PlanOptions {
+            min_name_length: 3,
+            ..PlanOptions::default()
+        };
+        let plan = Minifier::plan_from_source_with_options("store", source, options).unwrap();
+        assert_eq!(plan.min_name_length, 3);
 
-        let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert_eq!(plan.functions.len(), 2);
+        let load = &plan.functions[0];
+        let mut renamed: Vec<&str> = load
+            .renames
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect();
+        renamed.sort_unstable();
+        assert_eq!(renamed, vec!["result", "value"]);
+        for name in ["i", "ok", "db"] {
+            assert!(load.excluded.contains(&name.to_string()));
+            assert!(load.renames.iter().all(|entry| entry.renamed != name));
+        }
 
-        let outer = &plan.functions[0];
-        assert_eq!(outer.qualified_name, "outer");
-        assert!(outer.locals.is_empty());
-        assert!(outer.renames.is_empty());
-        assert!(outer.has_nested_functions);
-        assert!(!outer.has_imports);
-        assert_eq!(outer.nested.len(), 1);
-        assert_eq!(outer.nested[0].qualified_name, "outer.inner");
+        let rewritten = Minifier::rewrite_with_plan("store", source, &plan).unwrap();
+        assert!(rewritten.contains("ok = db.ping()"));
+        assert!(rewritten.contains("for i in range("));
+        assert!(!rewritten.contains("result"));
 
-        let inner = &plan.functions[1];
-        assert_eq!(inner.qualified_name, "outer.inner");
-        assert_eq!(inner.locals, vec!["y", "z"]);
-        assert_eq!(inner.renames[0].renamed, "a");
-        assert_eq!(inner.renames[1].renamed, "b");
-        assert!(!inner.has_nested_functions);
-        assert!(!inner.has_imports);
-        assert!(inner.range.is_some());
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["min_name_length"], 3);
+        let default_json =
+            serde_json::to_value(Minifier::plan_from_source("store", source).unwrap()).unwrap();
+        assert!(default_json.get("min_name_length").is_none());
     }
 
     #[test]
-    fn plan_records_globals_and_nonlocals() {
-        let source = r#"
-G = 0
+    fn skip_single_use_keeps_locals_read_once() {
+        let source = "def total(prices, rate):\n    subtotal = sum(prices)\n    taxed = subtotal + subtotal * rate\n    return taxed\n";
+        let options = PlanOptions {
+            skip_single_use: true,
+            ..PlanOptions::default()
+        };
+        let plan = Minifier::plan_from_source_with_options("tax", source, options).unwrap();
+        assert!(plan.skip_single_use);
 
-def outer():
-    global G
-    x = 1
-    def inner():
-        nonlocal x
-        return x
-    return x
-"#;
+        let function = &plan.functions[0];
+        let renamed: Vec<(&str, &str)> = function
+            .renames
+            .iter()
+            .map(|entry| (entry.original.as_str(), entry.renamed.as_str()))
+            .collect();
+        assert_eq!(renamed, vec![("subtotal", "a")]);
+        assert_eq!(function.renames[0].occurrences, 3);
+        for name in ["prices", "rate", "taxed"] {
+            assert!(function.excluded.contains(&name.to_string()));
+        }
+    }
 
-        let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert_eq!(plan.functions.len(), 2);
+    #[test]
+    fn except_name_shares_the_rename_of_a_preassigned_local() {
+        let source = "def load(path):\n    err = None\n    try:\n        data = read(path)\n    except ValueError as err:\n        log(err)\n    return err\n";
+        let mut plan = Minifier::plan_from_source("loader", source).unwrap();
+        let rewritten = Minifier::rewrite_with_plan("loader", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "def load(a):\n    b = None\n    try:\n        c = read(a)\n    except ValueError as b:\n        log(b)\n    return b\n"
+        );
 
-        let outer = plan
-            .functions
+        // A plan listing the name as both renamed and excluded leaves every occurrence,
+        // the handler binding included, under its original name.
+        plan.functions[0].excluded.push("err".to_string());
+        let rewritten = Minifier::rewrite_with_plan("loader", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "def load(a):\n    err = None\n    try:\n        c = read(a)\n    except ValueError as err:\n        log(err)\n    return err\n"
+        );
+    }
+
+    #[test]
+    fn typevar_locals_keep_their_names_unless_renamed_in_lockstep() {
+        let source = "def pair(items):\n    T = TypeVar(\"T\")\n    P = typing.ParamSpec('P')\n    return [T, P, items]\n";
+        let plan = Minifier::plan_from_source("generic", source).unwrap();
+        let function = &plan.functions[0];
+        let renamed: Vec<&str> = function
+            .renames
             .iter()
-            .find(|f| f.qualified_name == "outer")
-            .expect("outer plan present");
-        assert!(outer.excluded.contains(&"G".to_string()));
+            .map(|entry| entry.original.as_str())
+            .collect();
+        assert_eq!(renamed, vec!["items"]);
+        for name in ["P", "T"] {
+            assert!(function.excluded.contains(&name.to_string()));
+        }
+        assert!(function.warnings.is_empty());
+        let rewritten = Minifier::rewrite_with_plan("generic", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "def pair(a):\n    T = TypeVar(\"T\")\n    P = typing.ParamSpec('P')\n    return [T, P, a]\n"
+        );
 
-        let inner = plan
-            .functions
+        let options = PlanOptions {
+            rename_typevars: true,
+            ..PlanOptions::default()
+        };
+        let plan = Minifier::plan_from_source_with_options("generic", source, options).unwrap();
+        assert!(plan.rename_typevars);
+        let t = plan.functions[0]
+            .renames
             .iter()
-            .find(|f| f.qualified_name == "outer.inner")
-            .expect("inner plan present");
-        assert!(inner.excluded.contains(&"x".to_string()));
+            .find(|entry| entry.original == "T")
+            .unwrap();
+        assert_eq!((t.renamed.as_str(), t.occurrences), ("b", 3));
+        let rewritten = Minifier::rewrite_with_plan("generic", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "def pair(a):\n    b = TypeVar(\"b\")\n    c = typing.ParamSpec('c')\n    return [b, c, a]\n"
+        );
     }
 
     #[test]
-    fn rewrite_nested_functions_preserves_closure() {
-        let source = r#"
-def outer(value):
-    captured = value * 2
-    def inner(extra):
-        total = captured + extra
-        return total
-    result = inner(value)
-    return result
-"#;
+    fn locals_spelled_in_strings_are_kept_only_next_to_dynamic_lookups() {
+        let source = "def connect(obj):\n    timeout = obj.default\n    log(\"timeout\")\n    return getattr(obj, \"timeout\", timeout)\n";
+        let plan = Minifier::plan_from_source("net", source).unwrap();
+        let function = &plan.functions[0];
+        let renamed: Vec<&str> = function
+            .renames
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect();
+        assert_eq!(renamed, vec!["obj"]);
+        assert!(function.excluded.contains(&"timeout".to_string()));
+        assert_eq!(
+            function.warnings,
+            vec![
+                "`timeout` is spelled in a string the function may look up by name; left as written"
+                    .to_string()
+            ]
+        );
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("def outer(a):"));
-        assert!(rewritten.contains("def inner(a):"));
-        assert!(rewritten.contains("captured = a * 2"));
-        assert!(rewritten.contains("b = captured + a"));
-        assert!(rewritten.contains("b = inner(a)"));
+        let source = "def connect(obj):\n    timeout = obj.default\n    log(\"timeout\")\n    return timeout\n";
+        let plan = Minifier::plan_from_source("net", source).unwrap();
+        let function = &plan.functions[0];
+        assert_eq!(function.renames.len(), 2);
+        assert!(function.warnings.is_empty());
+
+        let source = "def scope(obj):\n    count = 1\n    return locals()[\"count\"]\n";
+        let plan = Minifier::plan_from_source("net", source).unwrap();
+        assert!(plan.functions[0].excluded.contains(&"count".to_string()));
     }
 
     #[test]
-    fn rewrite_plain_import_adds_alias() {
-        let source = r#"
-def loader(path):
-    import json
-    data = json.load(open(path))
-    return data
-"#;
+    fn bare_locals_call_bails_out_of_the_function() {
+        for source in [
+            "def scope(value):\n    count = value + 1\n    return locals()\n",
+            "def scope(value):\n    count = value + 1\n    return render(**locals())\n",
+            "def scope(value):\n    count = value + 1\n    return vars()\n",
+        ] {
+            let plan = Minifier::plan_from_source("net", source).unwrap();
+            let function = &plan.functions[0];
+            assert!(function.renames.is_empty(), "{source}");
+            assert_eq!(function.excluded, vec!["count", "value"], "{source}");
+            assert_eq!(function.bailout_reason, Some(BailoutReason::LocalsCall));
+
+            let rewritten = Minifier::rewrite_with_plan("net", source, &plan).unwrap();
+            assert_eq!(rewritten, source);
+        }
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("import json as b"));
-        assert!(rewritten.contains("b.load(open(a))"));
+        let source = "def scope(obj):\n    count = 1\n    return vars(obj), count\n";
+        let plan = Minifier::plan_from_source("net", source).unwrap();
+        assert_eq!(plan.functions[0].renames.len(), 2);
+        assert_eq!(plan.functions[0].bailout_reason, None);
     }
 
     #[test]
-    fn rewrite_applies_simple_plan() {
-        let source = r#"
-def identity(value):
-    result = value + 1
-    return result
-"#;
-
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        let expected = r#"
-def identity(a):
-    b = a + 1
-    return b
-"#;
-        assert_eq!(rewritten, expected);
+    fn namedtuple_under_another_name_is_renamed_with_a_warning() {
+        let source = "def build():\n    Point = collections.namedtuple(\"Pt\", [\"x\", \"y\"])\n    return Point(1, 2)\n";
+        let options = PlanOptions {
+            rename_typevars: true,
+            ..PlanOptions::default()
+        };
+        for options in [PlanOptions::default(), options] {
+            let plan = Minifier::plan_from_source_with_options("shapes", source, options).unwrap();
+            let function = &plan.functions[0];
+            assert_eq!(function.renames.len(), 1);
+            assert_eq!(function.renames[0].original, "Point");
+            assert_eq!(
+                function.warnings,
+                vec![
+                    "`Point` holds a type named \"Pt\"; the string is left as written".to_string()
+                ]
+            );
+            let rewritten = Minifier::rewrite_with_plan("shapes", source, &plan).unwrap();
+            assert_eq!(
+                rewritten,
+                "def build():\n    a = collections.namedtuple(\"Pt\", [\"x\", \"y\"])\n    return a(1, 2)\n"
+            );
+        }
     }
 
     #[test]
-    fn rewrite_with_plan_matches_rewrite_source() {
-        let source = r#"
-def identity(value):
-    result = value + 1
-    return result
-"#;
+    fn name_collisions_flag_generated_names_used_as_attributes() {
+        let source = "def tally(items):\n    total = 0\n    for item in items:\n        total += item\n    settings.a = total\n    return total\n";
+        let plan = Minifier::plan_from_source("tally", source).unwrap();
+        let original = plan.functions[0]
+            .renames
+            .iter()
+            .find(|entry| entry.renamed == "a")
+            .map(|entry| entry.original.clone())
+            .unwrap();
 
-        let plan = Minifier::plan_from_source("sample", source).unwrap();
-        let via_source = Minifier::rewrite_source("sample", source).unwrap();
-        let via_plan = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
-        assert_eq!(via_source, via_plan);
+        let collisions = plan.name_collisions(source);
+        assert_eq!(
+            collisions,
+            vec![NameCollision {
+                function: "tally".to_string(),
+                name: "a".to_string(),
+                original,
+                kind: CollisionKind::Attribute,
+                line: 5,
+            }]
+        );
+        let json = serde_json::to_value(&collisions[0]).unwrap();
+        assert_eq!(json["kind"], "attribute");
+
+        let quiet = "def tally(items):\n    total = 0\n    for item in items:\n        total += item\n    return total\n";
+        let plan = Minifier::plan_from_source("tally", quiet).unwrap();
+        assert!(plan.name_collisions(quiet).is_empty());
+    }
+
+    /// `source` with every name token replaced by `_`, so two sources that differ only in
+    /// identifiers compare equal.
+    fn mask_names(source: &str) -> String {
+        let mut masked = String::with_capacity(source.len());
+        let mut last = 0;
+        for (token, range) in lexer::lex(source, Mode::Module).map_while(|result| result.ok()) {
+            if let Tok::Name { .. } = token {
+                let (start, end) = (usize::from(range.start()), usize::from(range.end()));
+                masked.push_str(&source[last..start]);
+                masked.push('_');
+                last = end;
+            }
+        }
+        masked.push_str(&source[last..]);
+        masked
     }
 
     #[test]
-    fn rewrite_noop_with_nested_function() {
-        let source = r#"
-def wrapper(value):
-    def inner(x):
-        return x + value
-    return inner(value)
-"#;
+    fn renaming_parameters_preserves_signature_formatting() {
+        let source = "def configure(pos_only: int, /, plain, mapping: dict[str, int] = {}, *args: tuple[int, ...], flag: bool=False, limit = 10, **extra: \"dict[str, object]\") -> dict[str, int]:\n    total = pos_only + plain + len(args) + limit\n    mapping [ \"total\" ] = total\n    if flag :\n        return { **mapping , **extra }\n    return mapping\n";
+        let plan = Minifier::plan_from_source("signature", source).unwrap();
+        let rewritten = Minifier::rewrite_with_plan("signature", source, &plan).unwrap();
+
+        for name in [
+            "pos_only", "plain", "mapping", "args", "flag", "limit", "extra",
+        ] {
+            assert!(
+                !rewritten.contains(name),
+                "{name} was not renamed:\n{rewritten}"
+            );
+        }
+        assert_eq!(mask_names(&rewritten), mask_names(source));
+    }
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("def wrapper(value):"));
-        assert!(rewritten.contains("def inner(a):"));
-        assert!(rewritten.contains("return a + value"));
-        assert!(rewritten.contains("return inner(value)"));
+    /// Force `original` to be renamed in `function`, as a hand-curated plan might.
+    fn force_rename(plan: &mut MinifyPlan, function: &str, original: &str, renamed: &str) {
+        let target = plan
+            .functions
+            .iter_mut()
+            .find(|func| func.qualified_name == function)
+            .expect("function planned");
+        target.excluded.retain(|name| name != original);
+        target.renames.retain(|entry| entry.original != original);
+        target.renames.push(RenameEntry {
+            original: original.to_string(),
+            renamed: renamed.to_string(),
+            occurrences: 0,
+        });
     }
 
     #[test]
-    fn rewrite_handles_import_alias() {
-        let source = r#"
-def loader(path):
-    import json as j
-    data = j.load(path)
-    return data
-"#;
-
+    fn default_reading_a_name_bound_in_the_body_is_not_renamed() {
+        let source = "class Client:\n    DEFAULT = 30\n\n    def wait(self, timeout=DEFAULT):\n        DEFAULT = 5\n        return timeout + DEFAULT\n";
         let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert!(plan.functions[0].excluded.contains(&"j".to_string()));
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("import json as j"));
-        assert!(rewritten.contains("j.load(a)"));
+        let wait = &plan.functions[0];
+        assert_eq!(wait.qualified_name, "Client.wait");
+        assert!(wait.excluded.contains(&"DEFAULT".to_string()));
+
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert!(
+            rewritten.contains("def wait(self, a=DEFAULT):"),
+            "{rewritten}"
+        );
+        assert!(rewritten.contains("        DEFAULT = 5\n"), "{rewritten}");
+        assert!(rewritten.contains("return a + DEFAULT"), "{rewritten}");
     }
 
     #[test]
-    fn rewrite_handles_from_import_without_alias() {
-        let source = r#"
-def join(parts):
-    from os import path
-    return path.join(*parts)
-"#;
-
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("from os import path as b"));
-        assert!(rewritten.contains("return b.join(*a)"));
+    fn decorator_argument_sharing_a_local_name_is_not_renamed() {
+        let source = "from functools import lru_cache\n\nclass Service:\n    size = 3\n\n    @lru_cache(size)\n    def run(self, items):\n        size = len(items)\n        return size\n";
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let run = plan
+            .functions
+            .iter()
+            .find(|func| func.qualified_name == "Service.run")
+            .expect("run planned");
+        assert!(run.excluded.contains(&"size".to_string()));
+        assert!(run.renames.iter().all(|entry| entry.original != "size"));
+
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert!(rewritten.contains("    @lru_cache(size)\n"), "{rewritten}");
+        assert!(rewritten.contains("        size = len(a)\n"), "{rewritten}");
     }
 
     #[test]
-    fn rewrite_handles_from_import_alias() {
-        let source = r#"
-def normalize(parts):
-    from os.path import join as join_path
-    return join_path(*parts)
-"#;
-
-        let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert!(plan.functions[0]
-            .excluded
-            .contains(&"join_path".to_string()));
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("from os.path import join as join_path"));
-        assert!(rewritten.contains("return join_path(*a)"));
+    fn nested_decorator_argument_follows_outer_rename() {
+        let source = "from functools import lru_cache\n\ndef build():\n    maxsize = 10\n\n    @lru_cache(maxsize=maxsize)\n    def cached(key):\n        return key\n\n    return cached\n";
+        let mut plan = Minifier::plan_from_source("sample", source).unwrap();
+        force_rename(&mut plan, "build", "maxsize", "m");
+
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert!(rewritten.contains("    m = 10\n"), "{rewritten}");
+        assert!(rewritten.contains("@lru_cache(maxsize=m)"), "{rewritten}");
     }
 
     #[test]
-    fn rewrite_handles_comprehension() {
-        let source = r#"
-def transform(data, offset):
-    threshold = offset - 1
-    return [value + offset for value in data if value > threshold]
-"#;
+    fn nested_default_value_follows_outer_rename() {
+        let source = "def outer(limit):\n    step = limit * 2\n\n    def inner(value=step, *, scale=step):\n        return value * scale\n\n    return inner\n";
+        let mut plan = Minifier::plan_from_source("sample", source).unwrap();
+        force_rename(&mut plan, "outer", "step", "s");
+
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert!(!rewritten.contains("step"), "{rewritten}");
+        assert!(rewritten.contains("s = "), "{rewritten}");
+        assert_eq!(rewritten.matches("=s").count(), 2, "{rewritten}");
+    }
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert_eq!(rewritten, source);
+    fn invalid_plan_detail(plan: &MinifyPlan, source: &str) -> String {
+        match Minifier::rewrite_with_plan("sample", source, plan) {
+            Err(TsrsError::InvalidPlan { function, detail }) => {
+                assert_eq!(function, "clamp");
+                detail
+            }
+            other => panic!("expected InvalidPlan, got {other:?}"),
+        }
     }
 
     #[test]
-    fn rewrite_respects_global_and_nonlocal() {
-        let source = r#"
-counter = 0
+    fn rewrite_with_plan_rejects_invalid_renames() {
+        let source = "LIMIT = 10\n\ndef clamp(value):\n    total = value + LIMIT\n    return min(total, LIMIT)\n";
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        assert!(plan.validate(source).is_ok());
 
-def outer(value):
-    global counter
-    total = value + counter
-    counter = total
-    def inner():
-        nonlocal total
-        total = total + 1
-        return total
-    return inner()
-"#;
+        let mut typo = plan.clone();
+        typo.functions[0].renames[0].original = "vlaue".to_string();
+        let detail = invalid_plan_detail(&typo, source);
+        assert!(detail.contains("`vlaue` is not a local"), "{detail}");
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("global counter"));
-        assert!(rewritten.contains("nonlocal total"));
-        assert!(rewritten.contains("total = a + counter"));
-        assert!(rewritten.contains("counter = total"));
-        assert!(rewritten.contains("total = total + 1"));
-        assert!(rewritten.contains("def inner():"));
+        let mut duplicate = plan.clone();
+        for entry in &mut duplicate.functions[0].renames {
+            entry.renamed = "a".to_string();
+        }
+        let detail = invalid_plan_detail(&duplicate, source);
+        assert!(
+            detail.contains("`a` is already the target for `value`"),
+            "{detail}"
+        );
+
+        let mut shadowing = plan.clone();
+        shadowing.functions[0].renames[1].renamed = "LIMIT".to_string();
+        let detail = invalid_plan_detail(&shadowing, source);
+        assert!(detail.contains("`LIMIT` is already used"), "{detail}");
+
+        let mut keyword = plan;
+        keyword.functions[0].renames[1].renamed = "lambda".to_string();
+        let detail = invalid_plan_detail(&keyword, source);
+        assert!(detail.contains("is a keyword"), "{detail}");
     }
 
     #[test]
-    fn annassign_annotation_not_renamed() {
-        let source = r#"
-def assign(value):
-    data: value = value
-    return data
-"#;
+    fn pruning_invalid_renames_applies_the_rest() {
+        let source = "def clamp(value):\n    total = value + 1\n    return total\n";
+        let mut plan = Minifier::plan_from_source("sample", source).unwrap();
+        for entry in &mut plan.functions[0].renames {
+            entry.renamed = "a".to_string();
+        }
+
+        let dropped = plan.prune_invalid_renames(source);
+        assert_eq!(dropped.len(), 1);
+        assert!(matches!(dropped[0], TsrsError::InvalidPlan { .. }));
+        assert_eq!(plan.functions[0].renames.len(), 1);
+
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "def clamp(a):\n    total = a + 1\n    return total\n"
+        );
+    }
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("def assign(a):"));
-        assert!(rewritten.contains("b: value = a"));
-        assert!(rewritten.contains("return b"));
+    #[test]
+    fn remove_unused_imports_drops_only_unused_aliases() {
+        let source = "import os, sys as system\nfrom collections import OrderedDict, defaultdict as dd\nimport json\n\nprint(system.argv, dd(list))\n";
+        let cleanup = Minifier::remove_unused_imports_detailed("sample", source).unwrap();
+        assert_eq!(
+            cleanup.source,
+            "import sys as system\nfrom collections import defaultdict as dd\n\nprint(system.argv, dd(list))\n"
+        );
+        assert_eq!(cleanup.removed, vec!["os", "OrderedDict", "json"]);
     }
 
     #[test]
-    fn arg_annotation_not_renamed() {
-        let source = r#"
-def annotate(value: value) -> value:
-    return value
-"#;
+    fn remove_unused_imports_keeps_all_star_guarded_and_noqa_imports() {
+        let source = r#"from __future__ import annotations
+from os.path import *
+import re  # noqa: F401
+from . import helpers
+from .models import User, Group
+
+try:
+    import ujson as json
+except ImportError:
+    import json
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("def annotate(a: value) -> value:"));
-        assert!(rewritten.contains("return a"));
+__all__ = ["User"]
+"#;
+        let cleanup = Minifier::remove_unused_imports_detailed("pkg.sample", source).unwrap();
+        assert_eq!(cleanup.removed, vec!["helpers", "Group"]);
+        assert!(cleanup
+            .source
+            .contains("from __future__ import annotations\n"));
+        assert!(cleanup.source.contains("from os.path import *\n"));
+        assert!(cleanup.source.contains("import re  # noqa: F401\n"));
+        assert!(cleanup.source.contains("from .models import User\n"));
+        assert!(!cleanup.source.contains("helpers"));
+        assert!(cleanup.source.contains("    import ujson as json\n"));
+        Minifier::check_syntax("pkg.sample", &cleanup.source).unwrap();
     }
 
     #[test]
-    fn rewrite_skips_from_import_star() {
-        let source = r#"
-from tools import *
+    fn remove_unused_imports_never_leaves_an_empty_block() {
+        let source = "def load():\n    import csv\n    import io\n\nif True: import os\nimport a; import b\nb.run()\n";
+        let cleaned = Minifier::remove_unused_imports("sample", source).unwrap();
+        assert_eq!(
+            cleaned,
+            "def load():\n    pass\n\nif True: pass\npass; import b\nb.run()\n"
+        );
+        Minifier::check_syntax("sample", &cleaned).unwrap();
+    }
 
-def outer(value):
-    helper = value + 1
-    return helper
-"#;
+    #[test]
+    fn remove_unused_imports_counts_dotted_and_string_uses() {
+        let source = "import os.path\nimport typing\n\ndef f(x: \"typing.Any\"):\n    return os.path.join(x)\n";
+        let cleanup = Minifier::remove_unused_imports_detailed("sample", source).unwrap();
+        assert!(cleanup.removed.is_empty());
+        assert_eq!(cleanup.source, source);
+    }
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("from tools import *"));
-        assert!(rewritten.contains("def outer(a):"));
-        assert!(rewritten.contains("b = a + 1"));
+    #[test]
+    fn remove_dead_assignments_drops_an_unreferenced_generated_table() {
+        let table: String = (0..50)
+            .map(|code| format!("    {code}: \"entry{code}\",\n"))
+            .collect();
+        let kept = "NAMES = (\"a\", \"b\")\nSHIFT = 1 << 4\n__version__ = \"1.0\"\n\ndef lookup(key):\n    return SHIFT + key\n";
+        let source = format!(
+            "\"\"\"Generated.\"\"\"\n__all__ = [\"lookup\"]\n_CODES = {{\n{table}}}\n{kept}"
+        );
+        let cleanup =
+            Minifier::remove_dead_assignments("tables", &source, &|name| name == "NAMES").unwrap();
+        assert_eq!(cleanup.removed, ["_CODES"]);
+        assert_eq!(
+            cleanup.source,
+            format!("\"\"\"Generated.\"\"\"\n__all__ = [\"lookup\"]\n{kept}")
+        );
     }
 
     #[test]
-    fn rewrite_handles_import_alias_mixture() {
-        let source = r#"
-def combine(a):
-    import json
-    import yaml as y
-    data = json.dumps(a)
-    return y.safe_load(data)
-"#;
+    fn remove_dead_assignments_keeps_side_effects_exports_and_guarded_names() {
+        let source = "from typing import TYPE_CHECKING\nimport registry\n\nHANDLE = registry.open()\nFIRST = load()[0]\nSCALED = BASE * 2\nLABEL = f\"{BASE}\"\nEXPORTED = 1\nALIAS = BASE\n__all__ = [\"EXPORTED\"]\nif TYPE_CHECKING:\n    HINT = int\n";
+        let cleanup = Minifier::remove_dead_assignments("sample", source, &|_| false).unwrap();
+        assert_eq!(cleanup.removed, ["ALIAS"]);
+        assert_eq!(cleanup.source, source.replace("ALIAS = BASE\n", ""));
+
+        let dynamic = "CACHE = {}\n\ndef get(name):\n    return globals()[name]\n";
+        let cleanup = Minifier::remove_dead_assignments("sample", dynamic, &|_| false).unwrap();
+        assert!(cleanup.removed.is_empty());
+        assert_eq!(cleanup.source, dynamic);
+    }
 
-        let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert!(plan.functions[0].excluded.contains(&"y".to_string()));
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("import json as b"));
-        assert!(rewritten.contains("import yaml as y"));
-        assert!(rewritten.contains("c = b.dumps(a)"));
-        assert!(rewritten.contains("return y.safe_load(c)"));
+    /// The pre-splice application: repeated `replace_range` from the end of the source.
+    fn apply_by_replace_range(source: &str, replacements: &[Replacement]) -> String {
+        let mut ordered: Vec<&Replacement> = replacements.iter().collect();
+        ordered.sort_by_key(|replacement| std::cmp::Reverse(replacement.start));
+        let mut result = source.to_string();
+        for replacement in ordered {
+            result.replace_range(replacement.start..replacement.end, &replacement.text);
+        }
+        result
     }
 
     #[test]
-    fn rewrite_strips_module_and_function_docstrings() {
-        let source = "\"\"\"Module doc\"\"\"\n\n\ndef demo(value):\n    \"\"\"Function doc\"\"\"\n    return value\n";
+    fn splice_matches_repeated_replace_range() {
+        let source =
+            "def f(alpha, beta):\n    gamma = alpha + beta  # é\n    return gamma\n".repeat(50);
+        // Deterministic pseudo-random disjoint ranges on character boundaries.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut replacements = Vec::new();
+        let mut cursor = 0;
+        while cursor < source.len() {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1);
+            let start = cursor + (state >> 60) as usize;
+            let end = start + ((state >> 56) & 0x7) as usize;
+            if end > source.len() {
+                break;
+            }
+            if source.is_char_boundary(start) && source.is_char_boundary(end) {
+                replacements.push(Replacement {
+                    start,
+                    end,
+                    text: "x".repeat(((state >> 52) & 0x3) as usize),
+                });
+            }
+            cursor = end.max(start + 1);
+        }
+        assert!(replacements.len() > 100);
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        let expected = r#"
+        assert_eq!(
+            splice_replacements(&source, &replacements),
+            apply_by_replace_range(&source, &replacements)
+        );
+        assert_eq!(splice_replacements(&source, &[]), source);
+    }
 
-def demo(a):
-    return a
-"#;
-        assert_eq!(rewritten, expected);
+    #[test]
+    fn large_rewrite_matches_replace_range_application() {
+        let source = (0..400)
+            .map(|index| {
+                format!(
+                    "def compute_{index}(first, second):\n    total = first + second\n    return total * {index}\n\n"
+                )
+            })
+            .collect::<String>();
+        let plan = Minifier::plan_from_source("sample", &source).unwrap();
+        let rewritten = Minifier::rewrite_with_plan("sample", &source, &plan).unwrap();
+
+        let plans = plans_by_name(&plan.functions);
+        let suite = ast::Suite::parse(&source, "sample").unwrap();
+        let mut rewriter = FunctionRewriter::new(&source, &plans, RewriteOptions::default());
+        rewriter.visit_suite(&suite, &mut Vec::new()).unwrap();
+        assert!(rewriter.skipped.is_empty());
+        let replacements: Vec<Replacement> = std::mem::take(&mut rewriter.replacements)
+            .into_values()
+            .collect();
+        assert!(replacements.len() >= 400 * 2);
+        assert_eq!(rewritten, apply_by_replace_range(&source, &replacements));
     }
 
     #[test]
-    fn rewrite_strips_class_docstrings() {
-        let source = "\"\"\"Module doc\"\"\"\n\n\nclass Demo:\n    \"\"\"Class doc\"\"\"\n\n    def value(self):\n        \"\"\"Method doc\"\"\"\n        return 1\n";
+    fn overlapping_replacements_bail_out_per_function() {
+        let source = "def f(alpha):\n    return alpha\n";
+        let plans = HashMap::new();
+        let mut rewriter = FunctionRewriter::new(source, &plans, RewriteOptions::default());
+        let replacement = |start: usize, end: usize| Replacement {
+            start,
+            end,
+            text: "a".to_string(),
+        };
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        let expected = r#"
+        assert!(rewriter.accepts(&[replacement(6, 11), replacement(25, 30)]));
+        rewriter.replacements.insert(6, replacement(6, 11));
 
-class Demo:
+        // Overlapping each other, an accepted replacement, or splitting a character.
+        assert!(!rewriter.accepts(&[replacement(25, 30), replacement(28, 31)]));
+        assert!(!rewriter.accepts(&[replacement(0, 7)]));
+        assert!(!rewriter.accepts(&[replacement(10, 12)]));
+        assert!(!rewriter.accepts(&[replacement(25, 40)]));
+        assert!(rewriter.accepts(&[replacement(11, 12), replacement(25, 30)]));
 
-    def value(self):
-        return 1
-"#;
-        assert_eq!(rewritten, expected);
+        let accented = "é = 1\n";
+        let rewriter = FunctionRewriter::new(accented, &plans, RewriteOptions::default());
+        assert!(!rewriter.accepts(&[replacement(1, 2)]));
     }
 
     #[test]
-    fn rewrite_preserves_non_docstring_literals() {
-        let source = r#"
-def demo():
-    helper = "keep me"
-    return helper
-"#;
+    fn overlapping_accepted_replacements_abort_the_file() {
+        let source = "def f(alpha):\n    return alpha\n";
+        let plans = HashMap::new();
+        let mut rewriter = FunctionRewriter::new(source, &plans, RewriteOptions::default());
+        for (start, end) in [(6, 11), (8, 12)] {
+            rewriter.replacements.insert(
+                start,
+                Replacement {
+                    start,
+                    end,
+                    text: "a".to_string(),
+                },
+            );
+        }
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("= \"keep me\""));
+        let outcome = rewriter.rewrite(&[]).unwrap();
+        assert_eq!(outcome.source, source);
+        assert_eq!(
+            outcome.skipped,
+            vec![(
+                "<module>".to_string(),
+                "overlapping_replacements".to_string()
+            )]
+        );
     }
 
     #[test]
-    fn rewrite_strips_nested_function_docstrings() {
-        let source = "def outer():\n    \"\"\"Outer doc\"\"\"\n    def inner():\n        \"\"\"Inner doc\"\"\"\n        return 1\n    return inner()\n";
+    fn decorator_naming_the_defined_function_is_not_its_definition() {
+        let source = "class Registry:\n    def build(self, value, handler):\n        @handler.register(int)\n        def handler(item):\n            return item + 1\n\n        return handler(value)\n\n    def lookup(self, value):\n        handler = self.build(value, None)\n        return handler\n";
+        let mut plan = Minifier::plan_from_source("sample", source).unwrap();
+        // The planner keeps nested `def` names; a hand-edited plan may still rename one.
+        let build = plan
+            .functions
+            .iter_mut()
+            .find(|function| function.qualified_name == "Registry.build")
+            .unwrap();
+        build.excluded.retain(|name| name != "handler");
+        build.renames.push(RenameEntry {
+            original: "handler".to_string(),
+            renamed: "hd".to_string(),
+            occurrences: 0,
+        });
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(!rewritten.contains("Outer doc"));
-        assert!(!rewritten.contains("Inner doc"));
-        assert!(rewritten.contains("def outer():"));
-        assert!(rewritten.contains("def inner():"));
-        assert!(rewritten.contains("return inner()"));
+        let outcome = Minifier::rewrite_with_plan_detailed("sample", source, &plan).unwrap();
+        assert!(outcome.skipped.is_empty(), "{:?}", outcome.skipped);
+        assert!(outcome.applied.contains(&"Registry.build".to_string()));
+        assert!(outcome
+            .source
+            .contains("@hd.register(int)\n        def hd("));
+        assert!(outcome.source.contains("return hd("));
+        assert!(!outcome.source.contains("def handler("));
+        ast::Suite::parse(&outcome.source, "sample").unwrap();
     }
 
-    #[test]
-    fn rewrite_strips_async_function_docstrings() {
-        let source = "async def fetch():\n    \"\"\"Fetch doc\"\"\"\n    return 1\n";
-
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(!rewritten.contains("Fetch doc"));
-        assert!(rewritten.contains("async def fetch():"));
-        assert!(rewritten.contains("return 1"));
+    fn plan_module_privates(source: &str) -> MinifyPlan {
+        let options = PlanOptions {
+            rename_module_privates: true,
+            ..PlanOptions::default()
+        };
+        Minifier::plan_from_source_with_options("pkg.mod", source, options).unwrap()
     }
 
-    #[test]
-    fn rewrite_strips_docstrings_with_decorators() {
-        let source = "@decorator\nclass Demo:\n    \"\"\"Class doc\"\"\"\n\n    @property\n    def value(self):\n        \"\"\"Property doc\"\"\"\n        return 1\n";
+    const SCRIPT_WITH_HEADER: &str = "#!/usr/bin/env python3\n# -*- coding: utf-8 -*-\n\"\"\"Command-line tool.\"\"\"\nfrom __future__ import annotations\n\nimport sys\n\n\ndef unused_helper(value):\n    doubled = value * 2\n    return doubled\n\n\ndef main(argv):\n    count = len(argv)\n    return count\n\n\nif __name__ == \"__main__\":\n    sys.exit(main(sys.argv))\n";
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.starts_with("@decorator"));
-        assert!(rewritten.contains("@property"));
-        assert!(rewritten.contains("def value(self):"));
-        assert!(!rewritten.contains("Class doc"));
-        assert!(!rewritten.contains("Property doc"));
+    #[test]
+    fn module_header_survives_docstring_stripping() {
+        let rewritten = Minifier::rewrite_source("tool", SCRIPT_WITH_HEADER).unwrap();
+        assert!(rewritten.starts_with(
+            "#!/usr/bin/env python3\n# -*- coding: utf-8 -*-\nfrom __future__ import annotations\n"
+        ));
+        assert!(!rewritten.contains("Command-line tool"));
+        assert!(rewritten.contains(" = len(a)\n"));
     }
 
     #[test]
-    fn rewrite_strips_multiline_docstrings_preserving_body() {
-        let source = "def describe():\n    \"\"\"Line one.\n\n    Line two.\n    \"\"\"\n    return \"ok\"\n";
+    fn module_header_is_restored_or_the_rewrite_refused() {
+        let body = SCRIPT_WITH_HEADER
+            .split_once("\"\"\"Command-line tool.\"\"\"\n")
+            .unwrap()
+            .1;
+        let preserve = |rewritten: &str| {
+            Minifier::preserve_module_header("tool", SCRIPT_WITH_HEADER, rewritten)
+        };
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(!rewritten.contains("Line one"));
-        assert!(rewritten.contains("def describe():"));
-        assert!(rewritten.contains("return \"ok\""));
+        // A lost shebang and cookie are put back in order
+        assert_eq!(
+            preserve(body).unwrap(),
+            format!("#!/usr/bin/env python3\n# -*- coding: utf-8 -*-\n{body}")
+        );
+        assert!(is_coding_cookie("# vim: set fileencoding=latin-1 :\n"));
+        assert!(!is_coding_cookie("# decoding is hard\n"));
+
+        // A statement ahead of the future import, a dropped future import, or a shebang
+        // that moved down cannot be repaired
+        let misplaced = SCRIPT_WITH_HEADER.replace(
+            "from __future__ import annotations\n",
+            "DEBUG = False\nfrom __future__ import annotations\n",
+        );
+        assert_eq!(preserve(&misplaced), None);
+        let dropped = SCRIPT_WITH_HEADER.replace("from __future__ import annotations\n", "");
+        assert_eq!(preserve(&dropped), None);
+        let moved = format!("import os\n{SCRIPT_WITH_HEADER}");
+        assert_eq!(preserve(&moved), None);
     }
 
     #[test]
-    fn plans_comprehension_sets_flag() {
-        let source = r#"
-def build(nums):
-    return [n * 2 for n in nums]
-"#;
+    fn module_privates_are_renamed_at_every_reference() {
+        let source = "_DEFAULT_SCALE = 3\n\n\ndef _scale_value(value):\n    return value * _DEFAULT_SCALE\n\n\nclass _Helper:\n    def run(self, value: \"int\") -> int:\n        return _scale_value(value)\n\n\ndef public(value):\n    helper = _Helper()\n    return helper.run(value)\n";
+        let plan = plan_module_privates(source);
+        let renames: Vec<(&str, &str)> = plan
+            .module_renames
+            .iter()
+            .map(|entry| (entry.original.as_str(), entry.renamed.as_str()))
+            .collect();
+        assert_eq!(
+            renames
+                .iter()
+                .map(|(original, _)| *original)
+                .collect::<Vec<_>>(),
+            vec!["_DEFAULT_SCALE", "_scale_value", "_Helper"]
+        );
+        assert!(renames.iter().all(|(_, renamed)| renamed.starts_with('_')));
+        plan.validate(source).unwrap();
+
+        let rewritten = Minifier::rewrite_with_plan("pkg.mod", source, &plan).unwrap();
+        for (original, renamed) in &renames {
+            assert!(
+                !rewritten.contains(original),
+                "{original} left in {rewritten}"
+            );
+            assert!(rewritten.contains(renamed));
+        }
+        assert!(rewritten.contains("def public("));
+        ast::Suite::parse(&rewritten, "pkg.mod").unwrap();
 
-        let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert_eq!(plan.functions.len(), 1);
-        assert!(plan.functions[0].has_comprehension);
+        let json = serde_json::to_string(&plan).unwrap();
+        let roundtrip: MinifyPlan = serde_json::from_str(&json).unwrap();
+        assert_eq!(roundtrip.module_renames, plan.module_renames);
     }
 
     #[test]
-    fn rewrite_bails_on_comprehension() {
-        let source = r#"
-def outer(values):
-    total = 0
-    def inner(items):
-        return [item * 2 for item in items]
-    total = total + len(values)
-    return total
-"#;
-
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("def outer(a):"));
-        assert!(rewritten.contains("b = 0"));
-        assert!(rewritten.contains("def inner(items):"));
-        assert!(rewritten.contains("return [item * 2 for item in items]"));
-        assert!(rewritten.contains("b = b + len(a)"));
+    fn module_privates_mentioned_dynamically_are_kept() {
+        let cases = [
+            "__all__ = [\"_exported_helper\"]\n\ndef _exported_helper():\n    return 1\n",
+            "import sys\n\ndef _lookup_helper():\n    return 1\n\nvalue = getattr(sys.modules[__name__], \"_lookup_helper\")()\n",
+            "def _global_helper():\n    return 1\n\nvalue = globals()[\"_\" + \"global_helper\"]()\n",
+            "def _shadowed_helper():\n    return 1\n\ndef use(_shadowed_helper):\n    return _shadowed_helper\n",
+            "def _rebound_helper():\n    return 1\n\ndef use():\n    _rebound_helper = 2\n    return _rebound_helper\n",
+            "from other import _imported_helper\n\ndef use():\n    return _imported_helper()\n",
+            "class _Tagged:\n    pass\n\nclass Host:\n    _Tagged = _Tagged\n",
+        ];
+        for source in cases {
+            let plan = plan_module_privates(source);
+            assert!(plan.module_renames.is_empty(), "{source}");
+        }
     }
 
     #[test]
-    fn rewrite_replacements_stable() {
-        let source = r#"
-def demo(alpha, beta):
-    alpha_beta_gamma = alpha + beta
-    alpha_beta = alpha + beta
-    return alpha_beta_gamma + alpha_beta
-"#;
+    fn module_privates_are_left_alone_by_default() {
+        let source = "_HELPER_CONSTANT = 1\n\ndef use():\n    return _HELPER_CONSTANT\n";
+        let plan = Minifier::plan_from_source("pkg.mod", source).unwrap();
+        assert!(plan.module_renames.is_empty());
+        assert!(!serde_json::to_string(&plan)
+            .unwrap()
+            .contains("module_renames"));
+    }
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        let expected = r#"
-def demo(a, b):
-    c = a + b
-    d = a + b
-    return c + d
-"#;
-        assert_eq!(rewritten, expected);
+    #[test]
+    fn invalid_module_renames_are_rejected_or_pruned() {
+        let source = "_HELPER_CONSTANT = 1\n_OTHER_CONSTANT = 2\nvalue = _HELPER_CONSTANT + _OTHER_CONSTANT\n";
+        let mut plan = plan_module_privates(source);
+        assert_eq!(plan.module_renames.len(), 2);
+        plan.module_renames[1].renamed = "value".to_string();
+        assert!(matches!(
+            plan.validate(source),
+            Err(TsrsError::InvalidPlan { .. })
+        ));
+
+        let dropped = plan.prune_invalid_renames(source);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(plan.module_renames.len(), 1);
+        let rewritten = Minifier::rewrite_with_plan("pkg.mod", source, &plan).unwrap();
+        assert!(rewritten.contains("_OTHER_CONSTANT"));
+        assert!(!rewritten.contains("_HELPER_CONSTANT"));
     }
 
     #[test]
-    fn rewrite_skips_dotted_import_without_alias() {
-        let source = r#"
-def make_path(parts):
-    import os.path
-    return os.path.join(*parts)
-"#;
+    fn rewrite_stream_round_trips_utf16le_with_bom_and_crlf() {
+        let source = "def foo(value):\r\n    temp = value + 1\r\n    return temp\r\n";
+        let mut input = vec![0xFF, 0xFE];
+        input.extend(source.encode_utf16().flat_map(u16::to_le_bytes));
+
+        let mut output = Vec::new();
+        let summary = Minifier::rewrite_stream(
+            "example",
+            input.as_slice(),
+            &mut output,
+            &StreamOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(summary.outcome.applied, vec!["foo".to_string()]);
+        assert_eq!(summary.bytes_read, input.len());
+        assert_eq!(summary.bytes_written, output.len());
+
+        let expected = Minifier::rewrite_source("example", &source.replace("\r\n", "\n")).unwrap();
+        let mut expected_bytes = vec![0xFF, 0xFE];
+        expected_bytes.extend(
+            expected
+                .replace('\n', "\r\n")
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes),
+        );
+        assert_eq!(output, expected_bytes);
+        assert!(!expected.contains("temp"));
+    }
 
-        let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert!(plan.functions[0].excluded.contains(&"os".to_string()));
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("import os.path"));
-        assert!(rewritten.contains("return os.path.join(*a)"));
+    #[test]
+    fn function_globs_match_qualified_names() {
+        for (pattern, name) in [
+            ("*.__*__", "pkg.models.User.__init__"),
+            ("pkg.*", "pkg.utils.helper"),
+            ("*.User.sav?", "models.User.save"),
+            ("*.[a-c]*", "mod.build"),
+            ("*.[!a-c]*", "mod.parse"),
+        ] {
+            assert!(glob_matches(pattern, name), "{pattern} vs {name}");
+        }
+        for (pattern, name) in [
+            ("*.__*__", "pkg.models.User.save"),
+            ("pkg.*", "other.pkg.helper"),
+            ("*.[!a-c]*", "mod.build"),
+        ] {
+            assert!(!glob_matches(pattern, name), "{pattern} vs {name}");
+        }
+        assert!(matches!(
+            FunctionFilter::new(vec!["mod.[ab".to_string()], Vec::new()),
+            Err(TsrsError::ConfigError(_))
+        ));
     }
 
     #[test]
-    fn rewrite_handles_from_import_multiple() {
-        let source = r#"
-def use_pkg(a, b):
-    from pkg import thing, another
-    return thing(a) + another(b)
-"#;
+    fn function_filter_leaves_unselected_functions_without_a_plan() {
+        let source = "class User:\n    def __init__(self, name):\n        label = name.title()\n        self.name = label\n\n    def save(self, store):\n        payload = dict(name=self.name)\n        store.put(payload)\n\n\ndef helper(value):\n    temp = value + 1\n    return temp\n";
+        let plan_with = |include: &[&str], exclude: &[&str]| {
+            let filter = FunctionFilter::new(
+                include.iter().map(ToString::to_string).collect(),
+                exclude.iter().map(ToString::to_string).collect(),
+            )
+            .unwrap();
+            let options = PlanOptions {
+                function_filter: filter,
+                ..PlanOptions::default()
+            };
+            Minifier::plan_from_source_with_options("sample", source, options).unwrap()
+        };
+        let names = |plan: &MinifyPlan| {
+            plan.functions
+                .iter()
+                .map(|function| function.qualified_name.clone())
+                .collect::<Vec<_>>()
+        };
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert!(rewritten.contains("from pkg import thing as c, another as d"));
-        assert!(rewritten.contains("return c(a) + d(b)"));
+        let plan = plan_with(&[], &["*.__*__"]);
+        assert_eq!(names(&plan), vec!["User.save", "helper"]);
+        assert_eq!(plan.function_filter.exclude, vec!["*.__*__"]);
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert!(rewritten.contains("label = name.title()"));
+        assert!(!rewritten.contains("payload"));
+
+        let plan = plan_with(&["sample.User.*"], &[]);
+        assert_eq!(names(&plan), vec!["User.__init__", "User.save"]);
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert!(rewritten.contains("def helper(value):\n    temp = value + 1"));
+        assert!(!rewritten.contains("label"));
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(json.contains("\"function_filter\":{\"include\":[\"sample.User.*\"]}"));
+        assert!(!serde_json::to_string(&plan_with(&[], &[]))
+            .unwrap()
+            .contains("function_filter"));
     }
 
     #[test]
-    fn rewrite_noop_with_match() {
-        let source = r#"
-def classify(value):
-    match value:
-        case 0:
-            return "zero"
-        case other:
-            return other
-    temp = value + 1
-    return temp
-"#;
+    fn plan_limits_stop_planning_oversized_modules() {
+        let source: String = (0..200)
+            .map(|index| {
+                format!("def f{index}(value):\n    total = value + 1\n    return total\n\n")
+            })
+            .collect();
+        let plan_with = |limits: PlanLimits| {
+            Minifier::plan_from_source_with_options(
+                "generated",
+                &source,
+                PlanOptions {
+                    limits,
+                    ..PlanOptions::default()
+                },
+            )
+        };
 
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert_eq!(rewritten, source);
+        let err = plan_with(PlanLimits {
+            max_renames: Some(10),
+            max_functions: None,
+        })
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            TsrsError::PlanLimit {
+                limit: PlanLimit::Renames,
+                max: 10
+            }
+        ));
+        assert_eq!(err.to_string(), "plan has more than 10 renames");
+        assert!(matches!(
+            plan_with(PlanLimits {
+                max_renames: None,
+                max_functions: Some(5),
+            }),
+            Err(TsrsError::PlanLimit {
+                limit: PlanLimit::Functions,
+                max: 5
+            })
+        ));
+
+        let limits = PlanLimits {
+            max_renames: Some(400),
+            max_functions: Some(200),
+        };
+        let plan = plan_with(limits).unwrap();
+        assert_eq!(plan.functions.len(), 200);
+        assert_eq!(limits.exceeded_by(&plan), None);
+        assert_eq!(
+            PlanLimits {
+                max_renames: Some(399),
+                ..limits
+            }
+            .exceeded_by(&plan),
+            Some(PlanLimit::Renames)
+        );
     }
 
     #[test]
-    fn comprehensions_preserve_outer_names() {
-        let source = r#"
-def make_lists(values):
-    total = 0
-    squares = [total + num for num in values]
-    return squares, total
-"#;
-
+    fn walrus_in_conditions_binds_renamed_locals() {
+        let source = "def scan(stream):\n    while (chunk := stream.read()) is not None:\n        if (size := len(chunk)) > 10:\n            stream.log(size)\n    with open(path := stream.name) as handle:\n        assert (line := handle.readline()), line\n    return chunk, path\n";
         let plan = Minifier::plan_from_source("sample", source).unwrap();
-        assert!(plan.functions[0].has_comprehension);
-        let rewritten = Minifier::rewrite_source("sample", source).unwrap();
-        assert_eq!(rewritten, source);
+        let originals: HashSet<&str> = plan.functions[0]
+            .renames
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect();
+        for name in ["chunk", "size", "path", "handle", "line"] {
+            assert!(originals.contains(name), "{name} not renamed");
+        }
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "def scan(a):\n    while (b := a.read()) is not None:\n        if (c := len(b)) > 10:\n            a.log(c)\n    with open(d := a.name) as e:\n        assert (f := e.readline()), f\n    return b, d\n"
+        );
+    }
+
+    #[test]
+    fn walrus_locals_are_never_shadowed_by_generated_names() {
+        // `a` is too short to rename, so every generated name must steer clear of it.
+        let source = "def pick(items):\n    total = 0\n    if (a := len(items)) > 1:\n        total = a * 2\n    return total + a\n";
+        let options = PlanOptions {
+            min_name_length: 2,
+            ..PlanOptions::default()
+        };
+        let plan = Minifier::plan_from_source_with_options("sample", source, options).unwrap();
+        let function = &plan.functions[0];
+        assert!(function.renames.iter().all(|entry| entry.original != "a"));
+        assert!(function.renames.iter().all(|entry| entry.renamed != "a"));
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        assert_eq!(
+            rewritten,
+            "def pick(b):\n    c = 0\n    if (a := len(b)) > 1:\n        c = a * 2\n    return c + a\n"
+        );
     }
 }
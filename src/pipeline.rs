@@ -0,0 +1,673 @@
+//! Ordered source-to-source transforms run before renaming, and the `--level` presets
+//! built from them.
+
+use crate::error::{Result, TsrsError};
+use crate::minify::{identifiers_in_text, is_coding_cookie, strip_docstrings, Minifier};
+use rustpython_parser::{ast, lexer, Mode, Parse, Tok};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// The file a transform is rewriting.
+#[derive(Debug, Clone, Copy)]
+pub struct TransformContext<'a> {
+    /// Module name used in parse errors.
+    pub module_name: &'a str,
+    /// The file is a package `__init__.py`, whose imports are re-exports.
+    pub is_package_init: bool,
+}
+
+/// Result of one transform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformOutput {
+    /// Transformed source (the input unchanged when there was nothing to do).
+    pub source: String,
+    /// Number of items the transform removed, e.g. import bindings or comments.
+    pub changes: usize,
+}
+
+/// One stage of a [`TransformPipeline`].
+pub trait Transform: Send + Sync {
+    /// Stage name used in per-stage stats.
+    fn name(&self) -> &'static str;
+
+    /// Transform `source`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source cannot be parsed.
+    fn apply(&self, context: &TransformContext<'_>, source: &str) -> Result<TransformOutput>;
+}
+
+/// What one stage did, for one file or summed over a run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StageStats {
+    /// [`Transform::name`] of the stage.
+    pub stage: String,
+    /// Files the stage changed.
+    pub files: usize,
+    /// Items the stage removed.
+    pub changes: usize,
+    /// Bytes the stage removed.
+    pub bytes_saved: usize,
+}
+
+/// Add per-stage stats to running totals, keeping stages in pipeline order.
+pub fn merge_stage_stats(totals: &mut Vec<StageStats>, stages: &[StageStats]) {
+    for stage in stages {
+        match totals.iter_mut().find(|total| total.stage == stage.stage) {
+            Some(total) => {
+                total.files += stage.files;
+                total.changes += stage.changes;
+                total.bytes_saved += stage.bytes_saved;
+            }
+            None => totals.push(stage.clone()),
+        }
+    }
+}
+
+/// Result of [`TransformPipeline::run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineOutput {
+    /// Source after every stage.
+    pub source: String,
+    /// Stats of each stage, in order.
+    pub stages: Vec<StageStats>,
+}
+
+impl PipelineOutput {
+    /// Items removed by the stage named `stage` (0 when it did not run).
+    #[must_use]
+    pub fn changes(&self, stage: &str) -> usize {
+        self.stages
+            .iter()
+            .find(|stats| stats.stage == stage)
+            .map_or(0, |stats| stats.changes)
+    }
+}
+
+/// Transforms applied in order, each to the output of the one before.
+#[derive(Default)]
+pub struct TransformPipeline {
+    stages: Vec<Box<dyn Transform>>,
+}
+
+impl TransformPipeline {
+    /// A pipeline with no stages.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `stage` after the existing ones.
+    #[must_use]
+    pub fn then(mut self, stage: impl Transform + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Whether the pipeline has no stages.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Stage names in order.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.stages.iter().map(|stage| stage.name())
+    }
+
+    /// Run every stage over `source`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first stage error, e.g. when the source cannot be parsed.
+    pub fn run(&self, context: &TransformContext<'_>, source: &str) -> Result<PipelineOutput> {
+        self.run_after(None, context, source)
+    }
+
+    /// Run `first`, when given, and then every stage over `source`, for a stage that is
+    /// only known once the files of a run have been collected.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first stage error, e.g. when the source cannot be parsed.
+    pub fn run_after(
+        &self,
+        first: Option<&dyn Transform>,
+        context: &TransformContext<'_>,
+        source: &str,
+    ) -> Result<PipelineOutput> {
+        let mut current = source.to_string();
+        let mut stages = Vec::with_capacity(self.stages.len() + 1);
+        for stage in first
+            .into_iter()
+            .chain(self.stages.iter().map(|stage| stage.as_ref()))
+        {
+            let output = stage.apply(context, &current)?;
+            stages.push(StageStats {
+                stage: stage.name().to_string(),
+                files: usize::from(output.changes > 0),
+                changes: output.changes,
+                bytes_saved: current.len().saturating_sub(output.source.len()),
+            });
+            current = output.source;
+        }
+        Ok(PipelineOutput {
+            source: current,
+            stages,
+        })
+    }
+}
+
+impl std::fmt::Debug for TransformPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Drop imports nothing in the module references; see
+/// [`Minifier::remove_unused_imports_detailed`]. Package `__init__` files are left alone.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RemoveUnusedImports;
+
+impl RemoveUnusedImports {
+    pub const NAME: &'static str = "remove-unused-imports";
+}
+
+impl Transform for RemoveUnusedImports {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn apply(&self, context: &TransformContext<'_>, source: &str) -> Result<TransformOutput> {
+        if context.is_package_init {
+            return Ok(unchanged(source));
+        }
+        let cleanup = Minifier::remove_unused_imports_detailed(context.module_name, source)?;
+        Ok(TransformOutput {
+            changes: cleanup.removed.len(),
+            source: cleanup.source,
+        })
+    }
+}
+
+/// Which names the modules of a run mention, for transforms that must not remove a
+/// definition another module uses.
+#[derive(Debug, Clone, Default)]
+pub struct NameUsageIndex {
+    /// Number of modules mentioning each name, as an identifier or inside a string.
+    modules: HashMap<String, usize>,
+    /// Last dotted segment of every module some file imports with `from ... import *`.
+    star_imported: HashSet<String>,
+}
+
+impl NameUsageIndex {
+    /// An index with no modules.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the names one module mentions and the modules it star-imports. The source
+    /// is tokenized, not parsed, so a module with syntax errors still counts.
+    pub fn add_module(&mut self, source: &str) {
+        let mut names = HashSet::new();
+        let mut from_module: Option<Option<String>> = None;
+        let mut pending_star: Option<String> = None;
+        for (token, _) in lexer::lex(source, Mode::Module).map_while(|result| result.ok()) {
+            if let Some(module) = pending_star.take() {
+                if matches!(token, Tok::Star) {
+                    self.star_imported.insert(module);
+                }
+            }
+            match token {
+                Tok::From => from_module = Some(None),
+                Tok::Import => {
+                    pending_star = from_module.take().flatten();
+                }
+                Tok::Name { name } => {
+                    if let Some(module) = from_module.as_mut() {
+                        *module = Some(name.clone());
+                    }
+                    names.insert(name);
+                }
+                Tok::String { value, .. } => {
+                    names.extend(identifiers_in_text(&value).map(str::to_string));
+                }
+                _ => {}
+            }
+        }
+        for name in names {
+            *self.modules.entry(name).or_default() += 1;
+        }
+    }
+
+    /// Number of recorded modules that mention `name`.
+    #[must_use]
+    pub fn modules_mentioning(&self, name: &str) -> usize {
+        self.modules.get(name).copied().unwrap_or(0)
+    }
+
+    /// Whether some recorded module star-imports a module whose name ends in the segment
+    /// `module_name` ends in.
+    #[must_use]
+    pub fn is_star_imported(&self, module_name: &str) -> bool {
+        let last = module_name.rsplit('.').next().unwrap_or(module_name);
+        self.star_imported.contains(last)
+    }
+}
+
+/// Drop module-level constants that no module of the run references; see
+/// [`Minifier::remove_dead_assignments`].
+///
+/// The module defining a name always mentions it, so a name counts as used elsewhere when
+/// more than one module of the index mentions it. Public names of a module some file
+/// star-imports are kept, since the star import re-exports them. Package `__init__` files
+/// are left alone.
+#[derive(Debug, Clone, Default)]
+pub struct RemoveDeadAssignments {
+    usage: Arc<NameUsageIndex>,
+}
+
+impl RemoveDeadAssignments {
+    pub const NAME: &'static str = "remove-dead-assignments";
+
+    /// A stage checking references against `usage`, which must cover every module of
+    /// the run.
+    #[must_use]
+    pub fn new(usage: Arc<NameUsageIndex>) -> Self {
+        Self { usage }
+    }
+}
+
+impl Transform for RemoveDeadAssignments {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn apply(&self, context: &TransformContext<'_>, source: &str) -> Result<TransformOutput> {
+        if context.is_package_init {
+            return Ok(unchanged(source));
+        }
+        let star_imported = self.usage.is_star_imported(context.module_name);
+        let used_elsewhere = |name: &str| {
+            self.usage.modules_mentioning(name) > 1 || (star_imported && !name.starts_with('_'))
+        };
+        let cleanup =
+            Minifier::remove_dead_assignments(context.module_name, source, &used_elsewhere)?;
+        Ok(TransformOutput {
+            changes: cleanup.removed.len(),
+            source: cleanup.source,
+        })
+    }
+}
+
+/// Remove module, class, and function docstrings, keeping one that is a whole body.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StripDocstrings;
+
+impl StripDocstrings {
+    pub const NAME: &'static str = "strip-docstrings";
+}
+
+impl Transform for StripDocstrings {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn apply(&self, context: &TransformContext<'_>, source: &str) -> Result<TransformOutput> {
+        let (source, changes) = strip_docstrings(context.module_name, source)?;
+        Ok(TransformOutput { source, changes })
+    }
+}
+
+/// Remove comments, keeping a shebang, a coding cookie, and `# tsrs:` directives.
+///
+/// A comment on a line of its own is removed with its line; a trailing comment is removed
+/// with the whitespace before it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StripComments;
+
+impl StripComments {
+    pub const NAME: &'static str = "strip-comments";
+}
+
+impl Transform for StripComments {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn apply(&self, context: &TransformContext<'_>, source: &str) -> Result<TransformOutput> {
+        ensure_parses(context.module_name, source)?;
+
+        let mut removals = Vec::new();
+        for (token, range) in lexer::lex(source, Mode::Module).map_while(|result| result.ok()) {
+            let Tok::Comment(text) = token else {
+                continue;
+            };
+            let start = usize::from(range.start());
+            let end = usize::from(range.end());
+            let line_start = source[..start].rfind('\n').map_or(0, |index| index + 1);
+            let line = source[..line_start].matches('\n').take(2).count();
+            let kept = (line == 0 && text.starts_with("#!"))
+                || (line < 2 && is_coding_cookie(&text))
+                || text
+                    .trim_start_matches('#')
+                    .trim_start()
+                    .starts_with("tsrs:");
+            if kept {
+                continue;
+            }
+
+            let prefix = &source[line_start..start];
+            if prefix.trim().is_empty() {
+                let line_end = source[end..]
+                    .find('\n')
+                    .map_or(source.len(), |index| end + index + 1);
+                removals.push((line_start, line_end));
+            } else {
+                removals.push((line_start + prefix.trim_end().len(), end));
+            }
+        }
+
+        let changes = removals.len();
+        let mut stripped = String::with_capacity(source.len());
+        let mut copied = 0;
+        for (start, end) in removals {
+            stripped.push_str(&source[copied..start]);
+            copied = end;
+        }
+        stripped.push_str(&source[copied..]);
+        Ok(TransformOutput {
+            source: stripped,
+            changes,
+        })
+    }
+}
+
+/// Remove blank lines outside string literals.
+///
+/// A blank line that ends a backslash continuation is kept, since it also ends the
+/// statement.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactBlankLines;
+
+impl CompactBlankLines {
+    pub const NAME: &'static str = "compact-blank-lines";
+}
+
+impl Transform for CompactBlankLines {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn apply(&self, context: &TransformContext<'_>, source: &str) -> Result<TransformOutput> {
+        ensure_parses(context.module_name, source)?;
+
+        let strings: Vec<(usize, usize)> = lexer::lex(source, Mode::Module)
+            .map_while(|result| result.ok())
+            .filter(|(token, _)| matches!(token, Tok::String { .. }))
+            .map(|(_, range)| (usize::from(range.start()), usize::from(range.end())))
+            .collect();
+        let in_string = |offset: usize| {
+            let next = strings.partition_point(|(start, _)| *start < offset);
+            next > 0 && offset < strings[next - 1].1
+        };
+
+        let mut compacted = String::with_capacity(source.len());
+        let mut changes = 0;
+        let mut offset = 0;
+        let mut continued = false;
+        for line in source.split_inclusive('\n') {
+            let start = offset;
+            offset += line.len();
+            if line.trim().is_empty() && !continued && !in_string(start) {
+                changes += 1;
+                continue;
+            }
+            compacted.push_str(line);
+            continued = line.trim_end_matches(['\n', '\r']).ends_with('\\');
+        }
+        Ok(TransformOutput {
+            source: compacted,
+            changes,
+        })
+    }
+}
+
+fn ensure_parses(module_name: &str, source: &str) -> Result<()> {
+    ast::Suite::parse(source, module_name)
+        .map(drop)
+        .map_err(|err| TsrsError::parse(source, &err))
+}
+
+fn unchanged(source: &str) -> TransformOutput {
+    TransformOutput {
+        source: source.to_string(),
+        changes: 0,
+    }
+}
+
+/// Preset for `--level`: how much a minify run does beyond validating the plan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MinifyLevel {
+    /// 0: plan and report without rewriting.
+    Validate,
+    /// 1: local renames only.
+    #[default]
+    Rename,
+    /// 2: renames, unused-import removal, docstring and comment stripping, and blank-line
+    /// compaction.
+    Compact,
+    /// 3: level 2 plus module-private renames and dead-code removal.
+    Aggressive,
+}
+
+impl MinifyLevel {
+    /// The level numbered `level`, if it is 0–3.
+    #[must_use]
+    pub fn from_number(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(Self::Validate),
+            1 => Some(Self::Rename),
+            2 => Some(Self::Compact),
+            3 => Some(Self::Aggressive),
+            _ => None,
+        }
+    }
+
+    /// Components enabled at this level; individual flags may change them afterwards.
+    #[must_use]
+    pub fn settings(self) -> LevelSettings {
+        let compact = matches!(self, Self::Compact | Self::Aggressive);
+        let aggressive = matches!(self, Self::Aggressive);
+        LevelSettings {
+            rewrite: !matches!(self, Self::Validate),
+            remove_unused_imports: compact,
+            strip_docstrings: compact,
+            strip_comments: compact,
+            compact_blank_lines: compact,
+            rename_module_privates: aggressive,
+            remove_dead_code: aggressive,
+            keep_docstrings: false,
+        }
+    }
+}
+
+/// The components of a [`MinifyLevel`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LevelSettings {
+    /// Write rewritten files; off for plan-only validation.
+    pub rewrite: bool,
+    pub remove_unused_imports: bool,
+    /// Strip docstrings from every file, not only from files that get renames.
+    pub strip_docstrings: bool,
+    pub strip_comments: bool,
+    pub compact_blank_lines: bool,
+    pub rename_module_privates: bool,
+    pub remove_dead_code: bool,
+    /// Leave docstrings in rewritten files as well, as
+    /// [`RewriteOptions::keep_docstrings`](crate::RewriteOptions::keep_docstrings) does.
+    pub keep_docstrings: bool,
+}
+
+impl LevelSettings {
+    /// The enabled source transforms, in the order they run before renaming.
+    #[must_use]
+    pub fn pipeline(&self) -> TransformPipeline {
+        let mut pipeline = TransformPipeline::new();
+        if self.remove_unused_imports {
+            pipeline = pipeline.then(RemoveUnusedImports);
+        }
+        if self.strip_docstrings {
+            pipeline = pipeline.then(StripDocstrings);
+        }
+        if self.strip_comments {
+            pipeline = pipeline.then(StripComments);
+        }
+        if self.compact_blank_lines {
+            pipeline = pipeline.then(CompactBlankLines);
+        }
+        pipeline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(pipeline: &TransformPipeline, source: &str) -> PipelineOutput {
+        let context = TransformContext {
+            module_name: "sample",
+            is_package_init: false,
+        };
+        pipeline.run(&context, source).unwrap()
+    }
+
+    #[test]
+    fn level_two_runs_every_stage_in_order_with_stats() {
+        let source = "#!/usr/bin/env python\n# -*- coding: utf-8 -*-\n\"\"\"Module doc.\"\"\"\nimport os\nimport sys\n\n\n# helper below\ndef main():  # entry point\n    \"\"\"Run.\"\"\"\n    text = \"\"\"a\n\nb\"\"\"\n    return sys.argv, text  # tsrs: keep-names text\n";
+        let pipeline = MinifyLevel::Compact.settings().pipeline();
+        assert_eq!(
+            pipeline.names().collect::<Vec<_>>(),
+            [
+                RemoveUnusedImports::NAME,
+                StripDocstrings::NAME,
+                StripComments::NAME,
+                CompactBlankLines::NAME,
+            ]
+        );
+
+        let output = run(&pipeline, source);
+        assert_eq!(
+            output.source,
+            "#!/usr/bin/env python\n# -*- coding: utf-8 -*-\nimport sys\ndef main():\n    text = \"\"\"a\n\nb\"\"\"\n    return sys.argv, text  # tsrs: keep-names text\n"
+        );
+        let changes: Vec<_> = output
+            .stages
+            .iter()
+            .map(|stats| (stats.stage.as_str(), stats.changes))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (RemoveUnusedImports::NAME, 1),
+                (StripDocstrings::NAME, 2),
+                (StripComments::NAME, 2),
+                (CompactBlankLines::NAME, 2),
+            ]
+        );
+        let saved: usize = output.stages.iter().map(|stats| stats.bytes_saved).sum();
+        assert_eq!(saved, source.len() - output.source.len());
+    }
+
+    #[test]
+    fn flags_turn_off_components_of_a_level() {
+        let mut settings = MinifyLevel::Compact.settings();
+        settings.strip_docstrings = false;
+        let output = run(
+            &settings.pipeline(),
+            "def f():\n    \"\"\"Doc.\"\"\"\n\n    return 1  # one\n",
+        );
+        assert_eq!(
+            output.source,
+            "def f():\n    \"\"\"Doc.\"\"\"\n    return 1\n"
+        );
+        assert_eq!(output.changes(StripDocstrings::NAME), 0);
+
+        assert!(MinifyLevel::Rename.settings().pipeline().is_empty());
+        assert!(!MinifyLevel::Validate.settings().rewrite);
+        assert!(MinifyLevel::Aggressive.settings().remove_dead_code);
+        assert_eq!(MinifyLevel::from_number(4), None);
+    }
+
+    #[test]
+    fn blank_line_after_a_backslash_continuation_is_kept() {
+        let source = "x = 1 + \\\n    2\n\ny = x \\\n\nz = 3\n";
+        let output = run(&TransformPipeline::new().then(CompactBlankLines), source);
+        assert_eq!(output.source, "x = 1 + \\\n    2\ny = x \\\n\nz = 3\n");
+    }
+
+    #[test]
+    fn package_init_keeps_its_imports() {
+        let context = TransformContext {
+            module_name: "pkg",
+            is_package_init: true,
+        };
+        let output = TransformPipeline::new()
+            .then(RemoveUnusedImports)
+            .run(&context, "from .core import run\n")
+            .unwrap();
+        assert_eq!(output.source, "from .core import run\n");
+        assert_eq!(output.stages[0].files, 0);
+    }
+
+    #[test]
+    fn dead_assignments_consult_other_modules_and_star_imports() {
+        let tables = "LOOKUP = {1: 2}\nSPARE = (1, 2)\n_PRIVATE = 3\n";
+        let mut usage = NameUsageIndex::new();
+        usage.add_module(tables);
+        usage.add_module("from pkg.tables import LOOKUP\n");
+        let context = TransformContext {
+            module_name: "pkg.tables",
+            is_package_init: false,
+        };
+        let output = RemoveDeadAssignments::new(Arc::new(usage.clone()))
+            .apply(&context, tables)
+            .unwrap();
+        assert_eq!(output.source, "LOOKUP = {1: 2}\n");
+        assert_eq!(output.changes, 2);
+
+        usage.add_module("from .tables import *\n");
+        let output = RemoveDeadAssignments::new(Arc::new(usage))
+            .apply(&context, tables)
+            .unwrap();
+        assert_eq!(output.source, "LOOKUP = {1: 2}\nSPARE = (1, 2)\n");
+    }
+
+    #[test]
+    fn stage_stats_merge_by_name() {
+        let mut totals = Vec::new();
+        let stats = |stage: &str, changes| StageStats {
+            stage: stage.to_string(),
+            files: 1,
+            changes,
+            bytes_saved: changes * 10,
+        };
+        merge_stage_stats(&mut totals, &[stats("a", 1), stats("b", 2)]);
+        merge_stage_stats(&mut totals, &[stats("a", 3)]);
+        assert_eq!(
+            totals,
+            [
+                StageStats {
+                    stage: "a".to_string(),
+                    files: 2,
+                    changes: 4,
+                    bytes_saved: 40,
+                },
+                stats("b", 2),
+            ]
+        );
+    }
+}
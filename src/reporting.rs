@@ -4,9 +4,13 @@
 //! - JSON: Machine-readable format for tool integration
 //! - HTML: Human-readable report with styling
 //! - Graphviz DOT: Call graph visualization
+//! - SARIF 2.1.0: Findings for code-review tooling
 
 use serde::{Deserialize, Serialize};
 
+/// Rule for functions unreachable from any entry point.
+pub const DEAD_FUNCTION_RULE: &str = "tsrs/dead-function";
+
 /// Dead code analysis report
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeadCodeReport {
@@ -22,6 +26,13 @@ pub struct DeadCodeReport {
     pub entry_points: Vec<String>,
     /// Public exports (from `__all__`)
     pub public_exports: Vec<String>,
+    /// Unreachable functions spared by a `# tsrs: keep` comment
+    #[serde(default)]
+    pub suppressed: Vec<DeadFunction>,
+    /// Functions nothing analyzed calls that are live as declared roots, such as
+    /// `__init__.py` re-exports; `reason` says which root
+    #[serde(default)]
+    pub roots: Vec<DeadFunction>,
 }
 
 /// A dead code function
@@ -31,6 +42,107 @@ pub struct DeadFunction {
     pub name: String,
     /// Why it's considered dead
     pub reason: String,
+    /// Where the function is defined, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<FindingLocation>,
+    /// How safe the function is to delete
+    #[serde(default)]
+    pub confidence: Confidence,
+    /// Source to delete to remove the function, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removal_span: Option<RemovalSpan>,
+}
+
+/// How likely a dead function really is unused
+///
+/// Ordered from `Low` to `High`, so `confidence >= minimum` keeps the surer findings.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Confidence {
+    /// Its name appears in a string literal, so it may be looked up dynamically
+    Low,
+    /// Public, decorated, or defined in an `__init__.py`
+    #[default]
+    Medium,
+    /// Private (`_name`), undecorated, and never named in a string
+    High,
+}
+
+impl Confidence {
+    /// Parse `high`, `medium`, or `low`
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "high" => Some(Confidence::High),
+            "medium" => Some(Confidence::Medium),
+            "low" => Some(Confidence::Low),
+            _ => None,
+        }
+    }
+
+    /// Lowercase name, as serialized
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Confidence::High => "high",
+            Confidence::Medium => "medium",
+            Confidence::Low => "low",
+        }
+    }
+
+    /// One level less sure, bottoming out at `Low`
+    #[must_use]
+    pub fn lowered(self) -> Self {
+        match self {
+            Confidence::High => Confidence::Medium,
+            Confidence::Medium | Confidence::Low => Confidence::Low,
+        }
+    }
+}
+
+/// Whole lines of a function definition, from its first decorator through the end of its
+/// body
+///
+/// Deleting `start_byte..end_byte` of the analyzed source removes the definition,
+/// including the trailing newline of its last line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RemovalSpan {
+    /// Byte offset of the start of the first line
+    pub start_byte: usize,
+    /// Byte offset just past the last line
+    pub end_byte: usize,
+    /// 1-based first line
+    pub start_line: usize,
+    /// 1-based last line, inclusive
+    pub end_line: usize,
+}
+
+/// A position in a source file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FindingLocation {
+    /// File path, preferably relative to the analyzed directory
+    pub file: String,
+    /// 1-based line; zero points at the file as a whole
+    pub line: usize,
+    /// 1-based column; zero when unknown
+    #[serde(default)]
+    pub column: usize,
+}
+
+/// One SARIF result: a rule, a message, and optionally where it applies
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SarifFinding {
+    /// Rule identifier such as `tsrs/dead-function`
+    pub rule_id: String,
+    /// Human-readable message
+    pub message: String,
+    /// Source position the finding points at
+    pub location: Option<FindingLocation>,
+    /// Extra data for the SARIF `properties` bag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<serde_json::Value>,
 }
 
 impl DeadCodeReport {
@@ -46,7 +158,13 @@ impl DeadCodeReport {
     ) -> Self {
         let dead = dead_functions
             .into_iter()
-            .map(|(name, reason)| DeadFunction { name, reason })
+            .map(|(name, reason)| DeadFunction {
+                name,
+                reason,
+                location: None,
+                confidence: Confidence::default(),
+                removal_span: None,
+            })
             .collect();
 
         Self {
@@ -56,7 +174,79 @@ impl DeadCodeReport {
             live_functions,
             entry_points,
             public_exports,
+            suppressed: Vec::new(),
+            roots: Vec::new(),
+        }
+    }
+
+    /// Record functions that would be dead but were kept by `# tsrs: keep`
+    #[must_use]
+    pub fn with_suppressed(mut self, suppressed: Vec<(String, String)>) -> Self {
+        self.suppressed = suppressed
+            .into_iter()
+            .map(|(name, reason)| DeadFunction {
+                name,
+                reason,
+                location: None,
+                confidence: Confidence::default(),
+                removal_span: None,
+            })
+            .collect();
+        self
+    }
+
+    /// Record live functions that nothing calls, with why each counts as live
+    #[must_use]
+    pub fn with_roots(mut self, roots: Vec<(String, String)>) -> Self {
+        self.roots = roots
+            .into_iter()
+            .map(|(name, reason)| DeadFunction {
+                name,
+                reason,
+                location: None,
+                confidence: Confidence::default(),
+                removal_span: None,
+            })
+            .collect();
+        self
+    }
+
+    /// Attach source locations to `dead_functions`, given in the same order
+    #[must_use]
+    pub fn with_dead_locations(mut self, locations: Vec<Option<FindingLocation>>) -> Self {
+        for (dead, location) in self.dead_functions.iter_mut().zip(locations) {
+            dead.location = location;
         }
+        self
+    }
+
+    /// Attach confidence levels and removal spans to `dead_functions`, given in the same
+    /// order
+    #[must_use]
+    pub fn with_dead_details(mut self, details: Vec<(Confidence, Option<RemovalSpan>)>) -> Self {
+        for (dead, (confidence, removal_span)) in self.dead_functions.iter_mut().zip(details) {
+            dead.confidence = confidence;
+            dead.removal_span = removal_span;
+        }
+        self
+    }
+
+    /// Export dead functions as a SARIF 2.1.0 log, one `tsrs/dead-function` result each
+    ///
+    /// Each result carries `confidence` and, when known, `removalSpan` in its properties.
+    #[must_use]
+    pub fn to_sarif(&self) -> String {
+        let findings: Vec<SarifFinding> = self
+            .dead_functions
+            .iter()
+            .map(|dead| SarifFinding {
+                rule_id: DEAD_FUNCTION_RULE.to_string(),
+                message: format!("`{}` is dead code: {}", dead.name, dead.reason),
+                location: dead.location.clone(),
+                properties: Some(dead_function_properties(dead)),
+            })
+            .collect();
+        sarif_log(&findings)
     }
 
     /// Export as JSON
@@ -88,6 +278,18 @@ impl DeadCodeReport {
             })
             .collect::<String>();
 
+        let root_rows = self
+            .roots
+            .iter()
+            .map(|f| {
+                format!(
+                    "    <tr><td>{}</td><td>{}</td></tr>\n",
+                    escape_html(&f.name),
+                    escape_html(&f.reason)
+                )
+            })
+            .collect::<String>();
+
         let entry_points = self
             .entry_points
             .iter()
@@ -164,6 +366,20 @@ impl DeadCodeReport {
         </table>
     </div>
 
+    <div class="section">
+        <h2>Declared Roots (Live, Never Called)</h2>
+        <table>
+            <thead>
+                <tr>
+                    <th>Function Name</th>
+                    <th>Reason</th>
+                </tr>
+            </thead>
+            <tbody>
+{}            </tbody>
+        </table>
+    </div>
+
     <div class="section">
         <h2>Entry Points (Live Code)</h2>
         <ul>
@@ -184,6 +400,7 @@ impl DeadCodeReport {
             coverage,
             self.total_functions,
             dead_rows,
+            root_rows,
             entry_points,
             exports
         )
@@ -269,6 +486,96 @@ impl CallGraphDot {
     }
 }
 
+/// Render `findings` as a SARIF 2.1.0 log with a single `tsrs` run
+///
+/// Every rule used by a finding is listed under `tool.driver.rules`; results are
+/// reported at `warning` level.
+#[must_use]
+pub fn sarif_log(findings: &[SarifFinding]) -> String {
+    let mut rule_ids: Vec<&str> = findings.iter().map(|f| f.rule_id.as_str()).collect();
+    rule_ids.sort_unstable();
+    rule_ids.dedup();
+    let rules: Vec<serde_json::Value> = rule_ids
+        .iter()
+        .map(|id| {
+            serde_json::json!({
+                "id": id,
+                "shortDescription": { "text": rule_description(id) },
+            })
+        })
+        .collect();
+
+    let results: Vec<serde_json::Value> = findings
+        .iter()
+        .map(|finding| {
+            let mut result = serde_json::json!({
+                "ruleId": finding.rule_id,
+                "level": "warning",
+                "message": { "text": finding.message },
+            });
+            if let Some(properties) = &finding.properties {
+                result["properties"] = properties.clone();
+            }
+            if let Some(location) = &finding.location {
+                let mut physical = serde_json::json!({
+                    "artifactLocation": { "uri": location.file.replace('\\', "/") },
+                });
+                if location.line > 0 {
+                    let mut region = serde_json::json!({ "startLine": location.line });
+                    if location.column > 0 {
+                        region["startColumn"] = location.column.into();
+                    }
+                    physical["region"] = region;
+                }
+                result["locations"] = serde_json::json!([{ "physicalLocation": physical }]);
+            }
+            result
+        })
+        .collect();
+
+    let log = serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "tsrs",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/GeorgePearse/tsrs",
+                    "rules": rules,
+                }
+            },
+            "results": results,
+        }],
+    });
+    serde_json::to_string_pretty(&log).unwrap_or_default()
+}
+
+/// SARIF properties of a dead function: its confidence and removal span
+fn dead_function_properties(dead: &DeadFunction) -> serde_json::Value {
+    let mut properties = serde_json::json!({ "confidence": dead.confidence.as_str() });
+    if let Some(span) = &dead.removal_span {
+        properties["removalSpan"] = serde_json::json!({
+            "startByte": span.start_byte,
+            "endByte": span.end_byte,
+            "startLine": span.start_line,
+            "endLine": span.end_line,
+        });
+    }
+    properties
+}
+
+/// Short description for a `tsrs/...` rule id
+fn rule_description(rule_id: &str) -> String {
+    if rule_id == DEAD_FUNCTION_RULE {
+        return "Function is unreachable from any entry point".to_string();
+    }
+    match rule_id.strip_prefix("tsrs/bailout-") {
+        Some(reason) => format!("Minification skipped ({})", reason.replace('-', " ")),
+        None => rule_id.to_string(),
+    }
+}
+
 /// Escape HTML special characters
 fn escape_html(s: &str) -> String {
     s.replace('&', "&amp;")
@@ -307,6 +614,45 @@ mod tests {
         assert!(json.contains("5"));
     }
 
+    #[test]
+    fn test_dead_code_report_json_lists_suppressed() {
+        let report = DeadCodeReport::new(
+            "test_package".to_string(),
+            3,
+            vec![(
+                "unused_func".to_string(),
+                "Unreachable from entry points".to_string(),
+            )],
+            vec![],
+            vec![],
+            vec![],
+        )
+        .with_suppressed(vec![(
+            "template_hook".to_string(),
+            "Kept by # tsrs: keep".to_string(),
+        )]);
+
+        let value: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(value["suppressed"][0]["name"], "template_hook");
+        assert_eq!(value["dead_functions"][0]["name"], "unused_func");
+    }
+
+    #[test]
+    fn test_dead_code_report_lists_roots_with_reasons() {
+        let report = DeadCodeReport::new("mypkg".to_string(), 2, vec![], vec![], vec![], vec![])
+            .with_roots(vec![(
+                "Engine.run".to_string(),
+                "root: __init__ re-export".to_string(),
+            )]);
+
+        let value: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(value["roots"][0]["name"], "Engine.run");
+        assert_eq!(value["roots"][0]["reason"], "root: __init__ re-export");
+        assert!(report
+            .to_html()
+            .contains("<td>Engine.run</td><td>root: __init__ re-export</td>"));
+    }
+
     #[test]
     fn test_dead_code_report_html() {
         let report = DeadCodeReport::new(
@@ -328,6 +674,108 @@ mod tests {
         assert!(html.contains("Dead Code Analysis Report"));
     }
 
+    #[test]
+    fn test_dead_code_report_sarif() {
+        let report = DeadCodeReport::new(
+            "test_package".to_string(),
+            3,
+            vec![
+                (
+                    "Service.unused".to_string(),
+                    "Unreachable from entry points".to_string(),
+                ),
+                (
+                    "orphan".to_string(),
+                    "Unreachable from entry points".to_string(),
+                ),
+            ],
+            vec![],
+            vec![],
+            vec![],
+        )
+        .with_dead_locations(vec![
+            Some(FindingLocation {
+                file: "pkg\\service.py".to_string(),
+                line: 12,
+                column: 5,
+            }),
+            None,
+        ])
+        .with_dead_details(vec![
+            (
+                Confidence::Medium,
+                Some(RemovalSpan {
+                    start_byte: 140,
+                    end_byte: 190,
+                    start_line: 11,
+                    end_line: 13,
+                }),
+            ),
+            (Confidence::Low, None),
+        ]);
+
+        let sarif: serde_json::Value = serde_json::from_str(&report.to_sarif()).unwrap();
+        assert_eq!(sarif["version"], "2.1.0");
+        assert!(sarif["$schema"].as_str().unwrap().contains("sarif-2.1.0"));
+        let run = &sarif["runs"][0];
+        assert_eq!(run["tool"]["driver"]["name"], "tsrs");
+        assert_eq!(run["tool"]["driver"]["rules"][0]["id"], DEAD_FUNCTION_RULE);
+
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_eq!(result["ruleId"], DEAD_FUNCTION_RULE);
+            assert!(result["message"]["text"].is_string());
+        }
+        assert!(results[0]["message"]["text"]
+            .as_str()
+            .unwrap()
+            .contains("Service.unused"));
+        let location = &results[0]["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "pkg/service.py");
+        assert_eq!(location["region"]["startLine"], 12);
+        assert_eq!(location["region"]["startColumn"], 5);
+        assert!(results[1].get("locations").is_none());
+        let properties = &results[0]["properties"];
+        assert_eq!(properties["confidence"], "medium");
+        assert_eq!(properties["removalSpan"]["startLine"], 11);
+        assert_eq!(properties["removalSpan"]["endByte"], 190);
+        assert_eq!(results[1]["properties"]["confidence"], "low");
+        assert!(results[1]["properties"].get("removalSpan").is_none());
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        let dead = &json["dead_functions"][0];
+        assert_eq!(dead["confidence"], "medium");
+        assert_eq!(dead["removal_span"]["end_line"], 13);
+        assert_eq!(json["dead_functions"][1]["confidence"], "low");
+    }
+
+    #[test]
+    fn test_sarif_log_lists_each_rule_once() {
+        let finding = |rule: &str| SarifFinding {
+            rule_id: rule.to_string(),
+            message: "skipped".to_string(),
+            location: None,
+            properties: None,
+        };
+        let log = sarif_log(&[
+            finding("tsrs/bailout-match-statement"),
+            finding("tsrs/bailout-match-statement"),
+            finding("tsrs/bailout-nested-scopes"),
+        ]);
+
+        let sarif: serde_json::Value = serde_json::from_str(&log).unwrap();
+        let rules = sarif["runs"][0]["tool"]["driver"]["rules"]
+            .as_array()
+            .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            rules[0]["shortDescription"]["text"],
+            "Minification skipped (match statement)"
+        );
+        assert_eq!(sarif["runs"][0]["results"].as_array().unwrap().len(), 3);
+    }
+
     #[test]
     fn test_dead_code_report_dot() {
         let report = DeadCodeReport::new(
@@ -1,17 +1,297 @@
 //! Virtual environment slimming functionality
 
-use crate::error::{Result, TsrsError};
-use crate::imports::ImportCollector;
-use crate::venv::VenvAnalyzer;
-use std::fs;
-use std::path::{Path, PathBuf};
+use crate::archive::{record_hash, record_path};
+use crate::error::{IoResultExt, Result, TsrsError};
+use crate::imports::{ImportCollector, ImportSet};
+use crate::stdlib::PythonVersion;
+use crate::venv::{
+    normalize_package_name, requires_dist, EnvironmentLayout, PackageInfo, PackageMetadata,
+    VenvAnalyzer, VenvInfo,
+};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
 /// Creates slim versions of virtual environments
 pub struct VenvSlimmer {
-    code_directory: PathBuf,
+    code_directories: Vec<PathBuf>,
     source_venv: PathBuf,
     output_venv: PathBuf,
+    options: SlimOptions,
+}
+
+/// Optional post-processing applied to the slim venv after packages are copied
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SlimOptions {
+    /// Run `compileall` on the kept packages with the venv's own interpreter (venv output only)
+    pub compile_bytecode: bool,
+    /// Replace `.py` files that have compiled bytecode with sourceless `.pyc` files
+    pub strip_sources: bool,
+    /// Globs (matched against the package name or the path inside site-packages) whose
+    /// sources are never stripped, e.g. packages that read data files next to their code
+    pub strip_sources_except: Vec<String>,
+    /// Globs (matched against the path relative to each code directory, or the file name)
+    /// whose files are left out of the import scan, e.g. `tests/**` or `conftest.py`
+    pub code_exclude: Vec<String>,
+    /// Rewrite script shebangs to `#!/usr/bin/env python3` instead of the slim venv's
+    /// absolute interpreter path, so the venv can be moved after it is created
+    pub relocatable: bool,
+    /// Write a venv directory or a single zipapp
+    pub format: SlimFormat,
+    /// Zipapp entry point as `module:function`; without it a code directory must
+    /// provide `__main__.py`
+    pub main: Option<String>,
+    /// Packages kept or dropped regardless of what the import scan finds
+    pub policy: PackagePolicy,
+    /// Top-level module names that belong to the scanned code, in addition to the ones
+    /// discovered under each code directory; imports of them never keep a venv package
+    pub first_party: Vec<String>,
+    /// Count imports under `if TYPE_CHECKING:` too; they never run, so by default they
+    /// keep nothing
+    pub keep_type_checking_imports: bool,
+    /// Python version whose standard library imports are never looked up in the venv;
+    /// defaults to the venv's own version, or the newest supported one
+    pub python_version: Option<PythonVersion>,
+    /// Threads parsing code files during the import scan; 0 or 1 scans on the calling
+    /// thread
+    pub jobs: usize,
+    /// Import every kept top-level module with the slim venv's interpreter afterwards
+    /// (venv output only)
+    pub verify_imports: bool,
+    /// Top-level modules left out of the import check, e.g. ones that need a display
+    /// or a GPU to import
+    pub verify_skip: Vec<String>,
+    /// License names or SPDX ids (e.g. `GPL-3.0`) that stop the run before anything is
+    /// written when a kept distribution's license matches one; see
+    /// [`PackageMetadata::matches_license`]
+    pub fail_on_license: Vec<String>,
+}
+
+/// How long the import check may run before its interpreter is killed
+const VERIFY_IMPORTS_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Imports each module named on the command line and prints one JSON line per module,
+/// flushed right away so a crash or timeout still leaves the earlier results
+const VERIFY_IMPORTS_SCRIPT: &str = "\
+import importlib, json, sys, traceback
+for name in sys.argv[1:]:
+    try:
+        importlib.import_module(name)
+        error = None
+    except BaseException as exc:
+        error = ''.join(traceback.format_exception_only(type(exc), exc)).strip()
+    print(json.dumps({'module': name, 'error': error}), flush=True)
+";
+
+/// Package names or globs forced into or out of the slim output, applied after the import
+/// scan. Matching ignores case and treats `-`, `_` and `.` alike.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PackagePolicy {
+    /// Packages kept even when nothing imports them, e.g. plugins loaded by name
+    #[serde(default)]
+    pub keep: Vec<String>,
+    /// Packages left out even when imported; takes precedence over `keep`
+    #[serde(default)]
+    pub drop: Vec<String>,
+}
+
+impl PackagePolicy {
+    /// Load `keep = [...]` and `drop = [...]` lists from a TOML policy file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not a valid policy.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_path(path)?;
+        toml::from_str(&contents)
+            .map_err(|e| TsrsError::ConfigError(format!("{}: {e}", path.display())))
+    }
+}
+
+/// What [`VenvSlimmer::slim`] writes to the output path
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlimFormat {
+    /// A venv directory whose scripts and `pyvenv.cfg` point at the new location
+    #[default]
+    Venv,
+    /// A `.pyz` archive holding the kept packages and the code directories
+    Zipapp,
+}
+
+/// Summary of a slimming run
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SlimReport {
+    /// Code directories that were scanned for imports
+    pub code_roots: Vec<PathBuf>,
+    /// Packages copied into the slim venv
+    pub kept: Vec<KeptPackage>,
+    /// Post-processing steps that were requested, in the order they ran
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<SlimStep>,
+    /// Why each package in the source venv was kept or dropped
+    #[serde(default)]
+    pub decisions: Vec<PackageDecision>,
+    /// Consistency problems left in the slim venv, such as a kept distribution whose
+    /// `Requires-Dist` names one that was dropped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Top-level modules treated as part of the scanned code rather than the venv
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub first_party: Vec<String>,
+    /// Imported standard library modules, which no venv package provides
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stdlib: Vec<String>,
+    /// Code files that could not be read or parsed, so their imports keep nothing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scan_errors: Vec<String>,
+    /// Imported modules that no package in the source venv provides, so the slim venv
+    /// cannot satisfy them either
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unresolved: Vec<String>,
+    /// Result of importing the kept modules in the slim venv, when requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<ImportVerification>,
+    /// Licenses of the kept distributions
+    #[serde(default, skip_serializing_if = "LicenseSummary::is_empty")]
+    pub licenses: LicenseSummary,
+}
+
+/// Licenses of the distributions kept in the slim venv, from their dist-info metadata
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LicenseSummary {
+    /// License -> number of kept distributions under it
+    pub counts: BTreeMap<String, usize>,
+    /// Kept dist-info directories whose metadata names no license, or only `UNKNOWN`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unknown: Vec<String>,
+}
+
+impl LicenseSummary {
+    /// Whether no kept distribution was found
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty() && self.unknown.is_empty()
+    }
+}
+
+/// Outcome of importing each kept top-level module with the slim venv's interpreter
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ImportVerification {
+    /// Modules that imported cleanly
+    pub passed: Vec<String>,
+    /// Modules whose import raised, or that never ran because the interpreter failed
+    pub failed: Vec<FailedImport>,
+    /// Modules left out with `verify_skip`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+}
+
+/// A kept module that could not be imported from the slim venv
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FailedImport {
+    /// Top-level module name
+    pub module: String,
+    /// The exception line, e.g. `ModuleNotFoundError: No module named 'six'`
+    pub error: String,
+}
+
+/// One line printed by [`VERIFY_IMPORTS_SCRIPT`]
+#[derive(Deserialize)]
+struct ImportOutcome {
+    module: String,
+    error: Option<String>,
+}
+
+/// How a package in the source venv was treated
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlimDecision {
+    /// The scanned code imports it
+    KeptByImport,
+    /// Nothing imports it, but a `keep` pattern matches it
+    KeptByPolicy,
+    /// A `drop` pattern matches it, whether or not it is imported
+    DroppedByPolicy,
+    /// Nothing imports it and no pattern keeps it
+    DroppedUnused,
+}
+
+/// The slim decision for one package in the source venv
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PackageDecision {
+    /// Package name as found in site-packages
+    pub name: String,
+    /// Whether and why the package was kept
+    pub decision: SlimDecision,
+    /// The `keep` or `drop` pattern that decided it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// Kept packages that depend on this dropped one, directly or transitively
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_by: Vec<String>,
+}
+
+/// Size effect of a post-processing step on the slim site-packages
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SlimStep {
+    /// Step name (`compile-bytecode` or `strip-sources`)
+    pub step: String,
+    /// Bytes removed by the step; negative when the step added files
+    pub bytes_saved: i64,
+    /// Why the step was skipped or only partly applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// A package copied into the slim venv
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeptPackage {
+    /// Package name as found in site-packages
+    pub name: String,
+    /// Code roots whose imports caused the package to be kept
+    pub roots: Vec<PathBuf>,
+    /// An import statement that caused the package to be kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported_at: Option<ImportSite>,
+    /// Editable install: the slim venv keeps its `.pth` file, so the code is still
+    /// imported from `editable_target` (a zipapp bundles that directory instead)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub editable: bool,
+    /// Where the editable install's code lives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub editable_target: Option<PathBuf>,
+    /// Every distribution contributing to a namespace package, all of which are kept
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespace_distributions: Vec<String>,
+    /// License of a kept dist-info directory, as [`PackageMetadata::license_name`] reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+}
+
+/// Location of an import statement in the scanned code
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ImportSite {
+    /// File containing the import
+    pub file: PathBuf,
+    /// 1-indexed line of the import statement
+    pub line: usize,
+}
+
+impl fmt::Display for ImportSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
 }
 
 impl VenvSlimmer {
@@ -21,34 +301,15 @@ impl VenvSlimmer {
     ///
     /// Returns an error if either path does not exist.
     pub fn new<P: AsRef<Path>>(code_directory: P, source_venv: P) -> Result<Self> {
-        let code_dir = code_directory.as_ref().to_path_buf();
         let source = source_venv.as_ref().to_path_buf();
 
-        if !code_dir.exists() {
-            return Err(TsrsError::InvalidVenvPath(format!(
-                "Code directory does not exist: {}",
-                code_dir.display()
-            )));
-        }
-
-        if !source.exists() {
-            return Err(TsrsError::InvalidVenvPath(format!(
-                "Source venv does not exist: {}",
-                source.display()
-            )));
-        }
-
         // Default output is .venv-slim next to the source venv
         let mut output = source
             .parent()
             .map_or_else(|| PathBuf::from("."), Path::to_path_buf);
         output.push(".venv-slim");
 
-        Ok(VenvSlimmer {
-            code_directory: code_dir,
-            source_venv: source,
-            output_venv: output,
-        })
+        Self::new_with_roots(&[code_directory.as_ref().to_path_buf()], &source, &output)
     }
 
     /// Create a new venv slimmer with custom output path
@@ -61,15 +322,39 @@ impl VenvSlimmer {
         source_venv: P,
         output_venv: P,
     ) -> Result<Self> {
-        let code_dir = code_directory.as_ref().to_path_buf();
+        Self::new_with_roots(
+            &[code_directory.as_ref().to_path_buf()],
+            source_venv,
+            output_venv,
+        )
+    }
+
+    /// Create a venv slimmer that keeps packages imported by any of `code_directories`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no code directory is given or any path does not exist.
+    pub fn new_with_roots<P: AsRef<Path>>(
+        code_directories: &[PathBuf],
+        source_venv: P,
+        output_venv: P,
+    ) -> Result<Self> {
         let source = source_venv.as_ref().to_path_buf();
         let output = output_venv.as_ref().to_path_buf();
 
-        if !code_dir.exists() {
-            return Err(TsrsError::InvalidVenvPath(format!(
-                "Code directory does not exist: {}",
-                code_dir.display()
-            )));
+        if code_directories.is_empty() {
+            return Err(TsrsError::InvalidVenvPath(
+                "At least one code directory is required".to_string(),
+            ));
+        }
+
+        for code_dir in code_directories {
+            if !code_dir.exists() {
+                return Err(TsrsError::InvalidVenvPath(format!(
+                    "Code directory does not exist: {}",
+                    code_dir.display()
+                )));
+            }
         }
 
         if !source.exists() {
@@ -80,20 +365,36 @@ impl VenvSlimmer {
         }
 
         Ok(VenvSlimmer {
-            code_directory: code_dir,
+            code_directories: code_directories.to_vec(),
             source_venv: source,
             output_venv: output,
+            options: SlimOptions::default(),
         })
     }
 
+    /// Enable post-processing steps such as byte-compilation and source stripping
+    #[must_use]
+    pub fn with_options(mut self, options: SlimOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Code directories scanned for imports
+    #[must_use]
+    pub fn code_directories(&self) -> &[PathBuf] {
+        &self.code_directories
+    }
+
     /// Create a slim venv by analyzing code imports and copying only used packages
     ///
     /// # Errors
     ///
     /// Returns an error if the analysis or copying fails.
-    pub fn slim(&self) -> Result<()> {
+    pub fn slim(&self) -> Result<SlimReport> {
         tracing::info!("Starting venv slimming");
-        tracing::info!("  Code directory: {}", self.code_directory.display());
+        for code_directory in &self.code_directories {
+            tracing::info!("  Code directory: {}", code_directory.display());
+        }
         tracing::info!("  Source venv: {}", self.source_venv.display());
         tracing::info!("  Output venv: {}", self.output_venv.display());
 
@@ -102,53 +403,413 @@ impl VenvSlimmer {
         let venv_info = analyzer.analyze()?;
         tracing::info!("Found {} packages in source venv", venv_info.packages.len());
 
-        // Collect all imports from the code directory
-        let mut import_collector = ImportCollector::new();
-        self.collect_imports_from_code(&mut import_collector);
-        let used_imports = import_collector.get_imports();
+        // Modules the code defines itself are never satisfied from the venv
+        let mut first_party = first_party_modules(&self.code_directories);
+        first_party.extend(self.options.first_party.iter().cloned());
+        if !first_party.is_empty() {
+            tracing::info!(
+                "First-party modules: {}",
+                first_party.iter().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+
+        // Collect imports from every code root, remembering which roots use each one and
+        // where it was first imported
+        let code_exclude = build_globset(&self.options.code_exclude)?;
+        let pool = match self.options.jobs {
+            0 | 1 => None,
+            jobs => Some(
+                ThreadPoolBuilder::new()
+                    .num_threads(jobs)
+                    .build()
+                    .map_err(|e| {
+                        TsrsError::AnalysisError(format!(
+                            "failed to start {jobs} scan threads: {e}"
+                        ))
+                    })?,
+            ),
+        };
+        let mut used_imports = ImportSet::new();
+        let mut import_roots: BTreeMap<String, BTreeSet<PathBuf>> = BTreeMap::new();
+        let mut import_sites: BTreeMap<String, ImportSite> = BTreeMap::new();
+        let mut scan_errors = Vec::new();
+        for code_directory in &self.code_directories {
+            let (sites, errors) = Self::collect_imports_from_code(
+                code_directory,
+                &code_exclude,
+                self.options.keep_type_checking_imports,
+                pool.as_ref(),
+            );
+            scan_errors.extend(errors);
+            for (import, site) in sites {
+                if first_party.contains(&import) {
+                    continue;
+                }
+                import_roots
+                    .entry(import.clone())
+                    .or_default()
+                    .insert(code_directory.clone());
+                import_sites.entry(import.clone()).or_insert(site);
+                used_imports.add(import);
+            }
+        }
         tracing::info!(
             "Found {} unique imports in code",
             used_imports.imports.len()
         );
+        if !scan_errors.is_empty() {
+            tracing::warn!(
+                "{} code files could not be parsed; their imports keep nothing:",
+                scan_errors.len()
+            );
+            for error in &scan_errors {
+                tracing::warn!("  {error}");
+            }
+        }
+
+        // The standard library shadows site-packages, so its modules are never looked up
+        let python_version = self
+            .options
+            .python_version
+            .or_else(|| {
+                venv_info
+                    .python_version
+                    .as_deref()
+                    .and_then(PythonVersion::parse)
+            })
+            .unwrap_or_default();
+        let stdlib = used_imports.remove_stdlib(python_version);
+        tracing::info!(
+            "Skipping {} standard library imports (Python {python_version})",
+            stdlib.len()
+        );
+
+        let (selected, decisions) = select_packages(
+            &venv_info,
+            &used_imports,
+            &import_sites,
+            &self.options.policy,
+        )?;
+        let unresolved = unresolved_imports(&venv_info, &used_imports);
+        if !unresolved.is_empty() {
+            tracing::warn!(
+                "No package in the source venv provides: {}",
+                unresolved.join(", ")
+            );
+        }
+
+        // Checked before anything is written, so a rejected license leaves no output
+        check_licenses(
+            &self.options.fail_on_license,
+            &selected,
+            &decisions,
+            &import_sites,
+        )?;
+        let licenses = license_summary(&selected);
+
+        if self.options.format == SlimFormat::Zipapp {
+            let kept = self.write_zipapp(&selected, &import_roots, &import_sites, &code_exclude)?;
+            tracing::info!("Successfully created zipapp");
+            return Ok(SlimReport {
+                code_roots: self.code_directories.clone(),
+                kept,
+                steps: Vec::new(),
+                decisions,
+                warnings: Vec::new(),
+                first_party: first_party.into_iter().collect(),
+                stdlib,
+                scan_errors,
+                unresolved,
+                verification: None,
+                licenses,
+            });
+        }
+
+        // Create base structure, mirroring the source layout
+        let layout = analyzer.layout();
+        let site_packages = output_site_packages(layout, &self.output_venv);
+        self.create_venv_structure(layout, &site_packages)?;
+
+        // Copy only packages that match imports or the keep policy
+        let kept =
+            self.copy_used_packages(&selected, &site_packages, &import_roots, &import_sites)?;
+
+        // Scripts and pyvenv.cfg still name the source venv
+        if layout.root().is_some() {
+            self.relocate_venv()?;
+        }
+
+        let mut steps = Vec::new();
+        if self.options.compile_bytecode || self.options.strip_sources {
+            let output_dirs: Vec<&Path> =
+                site_packages.iter().map(|(_, dst)| dst.as_path()).collect();
+            if self.options.compile_bytecode {
+                steps.push(self.compile_bytecode(&output_dirs, &venv_info)?);
+            }
+            if self.options.strip_sources {
+                steps.push(self.strip_sources(&output_dirs)?);
+            }
+        }
 
-        // Create base structure
-        self.create_venv_structure()?;
+        // Last, so RECORD files describe the final contents
+        let warnings = self.prune_dist_metadata(layout, &site_packages)?;
 
-        // Copy only packages that match imports
-        self.copy_used_packages(&venv_info, &used_imports)?;
+        let verification = self.options.verify_imports.then(|| {
+            self.verify_imports(
+                kept_imports(&venv_info, &used_imports, &selected),
+                &venv_info,
+            )
+        });
 
         tracing::info!("Successfully created slim venv");
-        Ok(())
+        Ok(SlimReport {
+            code_roots: self.code_directories.clone(),
+            kept,
+            steps,
+            decisions,
+            warnings,
+            first_party: first_party.into_iter().collect(),
+            stdlib,
+            scan_errors,
+            unresolved,
+            verification,
+            licenses,
+        })
+    }
+
+    /// Locate the interpreter of `venv` (`bin/pythonX.Y`, `bin/python3`, `bin/python`,
+    /// or the Windows `Scripts` equivalents), named after the source venv's version
+    fn find_interpreter(venv: &Path, venv_info: &VenvInfo) -> Option<PathBuf> {
+        let mut names: Vec<String> = Vec::new();
+        if let Some(version) = &venv_info.python_version {
+            names.push(version.clone());
+        }
+        names.extend(["python3", "python", "python.exe"].map(str::to_string));
+
+        ["bin", "Scripts"]
+            .iter()
+            .flat_map(|dir| names.iter().map(move |name| venv.join(dir).join(name)))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Byte-compile the slim site-packages directories with `python -m compileall`
+    fn compile_bytecode(&self, site_packages: &[&Path], venv_info: &VenvInfo) -> Result<SlimStep> {
+        let step = "compile-bytecode".to_string();
+        let Some(interpreter) = Self::find_interpreter(&self.source_venv, venv_info) else {
+            tracing::warn!(
+                "No Python interpreter found in {}; skipping byte-compilation",
+                self.source_venv.display()
+            );
+            return Ok(SlimStep {
+                step,
+                bytes_saved: 0,
+                note: Some("no interpreter found".to_string()),
+            });
+        };
+
+        let before = total_size(site_packages);
+        let note = match Command::new(&interpreter)
+            .args(["-m", "compileall", "-q"])
+            .args(site_packages)
+            .status()
+        {
+            Ok(status) if status.success() => None,
+            Ok(status) => {
+                tracing::warn!("compileall exited with {status}; some modules were not compiled");
+                Some(format!("compileall exited with {status}"))
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to run {}: {}; skipping byte-compilation",
+                    interpreter.display(),
+                    e
+                );
+                Some(format!("failed to run interpreter: {e}"))
+            }
+        };
+
+        Ok(SlimStep {
+            step,
+            bytes_saved: size_delta(before, total_size(site_packages)),
+            note,
+        })
+    }
+
+    /// Import each of `modules`, minus `verify_skip`, with the slim venv's own interpreter
+    /// in a subprocess that is killed after [`VERIFY_IMPORTS_TIMEOUT`]
+    fn verify_imports(&self, modules: Vec<String>, venv_info: &VenvInfo) -> ImportVerification {
+        let (skipped, modules): (Vec<String>, Vec<String>) = modules
+            .into_iter()
+            .partition(|module| self.options.verify_skip.contains(module));
+        let mut verification = ImportVerification {
+            skipped,
+            ..ImportVerification::default()
+        };
+        if modules.is_empty() {
+            return verification;
+        }
+
+        let (mut results, failure) = match Self::find_interpreter(&self.output_venv, venv_info) {
+            Some(interpreter) => run_import_check(&interpreter, &modules),
+            None => (
+                BTreeMap::new(),
+                Some(format!(
+                    "no interpreter found in {}",
+                    self.output_venv.display()
+                )),
+            ),
+        };
+        for module in modules {
+            match results.remove(&module) {
+                Some(None) => verification.passed.push(module),
+                Some(Some(error)) => verification.failed.push(FailedImport { module, error }),
+                None => verification.failed.push(FailedImport {
+                    module,
+                    error: failure
+                        .clone()
+                        .unwrap_or_else(|| "the interpreter reported no result".to_string()),
+                }),
+            }
+        }
+        for failed in &verification.failed {
+            tracing::warn!("{} fails to import: {}", failed.module, failed.error);
+        }
+        verification
+    }
+
+    /// Replace each `.py` file that has a `__pycache__` entry with a sourceless `.pyc`
+    /// next to it, which is the only layout Python imports without the source present.
+    fn strip_sources(&self, site_packages: &[&Path]) -> Result<SlimStep> {
+        let keep = build_globset(&self.options.strip_sources_except)?;
+        let before = total_size(site_packages);
+        let mut stripped = 0usize;
+
+        for &dir in site_packages {
+            let sources: Vec<PathBuf> = WalkDir::new(dir)
+                .into_iter()
+                .filter_map(std::result::Result::ok)
+                .filter(|e| e.file_type().is_file())
+                .map(walkdir::DirEntry::into_path)
+                .filter(|path| path.extension().is_some_and(|ext| ext == "py"))
+                .collect();
+
+            for source in sources {
+                let Ok(relative) = source.strip_prefix(dir) else {
+                    continue;
+                };
+                if is_strip_exempt(&keep, relative) {
+                    continue;
+                }
+                let Some(cached) = cached_bytecode(&source) else {
+                    continue;
+                };
+                fs::rename(&cached, source.with_extension("pyc")).with_path(&cached)?;
+                fs::remove_file(&source).with_path(&source)?;
+                stripped += 1;
+            }
+        }
+
+        tracing::info!("Stripped {} source files", stripped);
+        Ok(SlimStep {
+            step: "strip-sources".to_string(),
+            bytes_saved: size_delta(before, total_size(site_packages)),
+            note: None,
+        })
     }
 
-    /// Collect all imports from Python files in the code directory
+    /// Collect the top-level modules imported by Python files in a code directory that
+    /// `exclude` does not match, with the first place (in path order) each is imported,
+    /// plus an error for each file that could not be read or parsed.
+    /// Imports under `if TYPE_CHECKING:` only count with `keep_type_checking`. Files are
+    /// parsed on `pool` when given and merged in path order, so the result does not
+    /// depend on scheduling.
     #[allow(clippy::redundant_closure_for_method_calls)]
-    fn collect_imports_from_code(&self, collector: &mut ImportCollector) {
-        for entry in WalkDir::new(&self.code_directory)
+    fn collect_imports_from_code(
+        code_directory: &Path,
+        exclude: &GlobSet,
+        keep_type_checking: bool,
+        pool: Option<&ThreadPool>,
+    ) -> (BTreeMap<String, ImportSite>, Vec<String>) {
+        let files: Vec<PathBuf> = WalkDir::new(code_directory)
+            .sort_by_file_name()
             .into_iter()
             .filter_map(|e| e.ok())
             .filter(|e| e.path().extension().is_some_and(|ext| ext == "py"))
-        {
-            if let Err(e) = collector.collect_from_file(entry.path()) {
-                tracing::warn!("Failed to parse {}: {}", entry.path().display(), e);
+            .filter(|entry| {
+                let excluded = is_code_excluded(exclude, code_directory, entry.path());
+                if excluded {
+                    tracing::debug!("Excluded from import scan: {}", entry.path().display());
+                }
+                !excluded
+            })
+            .map(walkdir::DirEntry::into_path)
+            .collect();
+
+        let scan = |path: &PathBuf| -> Result<Vec<(String, usize)>> {
+            let mut collector = ImportCollector::new();
+            collector.collect_from_file(path)?;
+            Ok(collector
+                .get_detailed_imports()
+                .into_iter()
+                .filter(|import| keep_type_checking || !import.type_checking)
+                .filter_map(|import| {
+                    let top_level = import.module.split('.').next().unwrap_or_default();
+                    (!top_level.is_empty()).then(|| (top_level.to_string(), import.lineno))
+                })
+                .collect())
+        };
+        let scanned: Vec<Result<Vec<(String, usize)>>> = match pool {
+            Some(pool) => pool.install(|| files.par_iter().map(scan).collect()),
+            None => files.iter().map(scan).collect(),
+        };
+
+        let mut sites = BTreeMap::new();
+        let mut errors = Vec::new();
+        for (path, imports) in files.iter().zip(scanned) {
+            match imports {
+                Ok(imports) => {
+                    for (top_level, line) in imports {
+                        sites.entry(top_level).or_insert_with(|| ImportSite {
+                            file: path.clone(),
+                            line,
+                        });
+                    }
+                }
+                Err(e) => errors.push(e.to_string()),
             }
         }
+        (sites, errors)
     }
 
-    /// Create the base venv structure
-    fn create_venv_structure(&self) -> Result<()> {
-        // Create lib/pythonX.Y/site-packages structure
-        fs::create_dir_all(&self.output_venv)?;
+    /// Create the output environment with the same layout as the source: a venv gets its
+    /// scripts and `pyvenv.cfg`, a conda env everything outside site-packages (the
+    /// interpreter, standard library, and `conda-meta`), and a bare site-packages directory
+    /// nothing but itself
+    fn create_venv_structure(
+        &self,
+        layout: &EnvironmentLayout,
+        site_packages: &[(PathBuf, PathBuf)],
+    ) -> Result<()> {
+        fs::create_dir_all(&self.output_venv).with_path(&self.output_venv)?;
+        for (_, dst) in site_packages {
+            fs::create_dir_all(dst).with_path(dst)?;
+        }
 
-        // Copy basic venv files
-        self.copy_venv_basics()?;
+        match layout {
+            EnvironmentLayout::Venv { .. } => self.copy_venv_basics()?,
+            EnvironmentLayout::Conda {
+                root,
+                site_packages: skip,
+            } => self.copy_dir_except(root, &self.output_venv, skip)?,
+            EnvironmentLayout::SitePackages(_) => {}
+        }
 
         Ok(())
     }
 
     /// Copy basic venv structure (bin, etc)
     fn copy_venv_basics(&self) -> Result<()> {
-        let dirs_to_copy = ["bin", "pyvenv.cfg"];
+        let dirs_to_copy = ["bin", "Scripts", "pyvenv.cfg"];
 
         for dir in &dirs_to_copy {
             let src = self.source_venv.join(dir);
@@ -158,7 +819,7 @@ impl VenvSlimmer {
                 if src.is_dir() {
                     self.copy_dir_recursive(&src, &dst)?;
                 } else {
-                    fs::copy(&src, &dst)?;
+                    fs::copy(&src, &dst).with_path(&src)?;
                 }
             }
         }
@@ -166,107 +827,331 @@ impl VenvSlimmer {
         Ok(())
     }
 
-    /// Copy used packages to slim venv
+    /// Copy the selected packages to slim venv, each into the output site-packages
+    /// matching the one it was installed in
     fn copy_used_packages(
         &self,
-        venv_info: &crate::venv::VenvInfo,
-        used_imports: &crate::imports::ImportSet,
-    ) -> Result<()> {
-        // Find destination site-packages
-        let dst_site_packages = self.find_or_create_site_packages(&self.output_venv)?;
+        selected: &[(&PackageInfo, String)],
+        site_packages: &[(PathBuf, PathBuf)],
+        import_roots: &BTreeMap<String, BTreeSet<PathBuf>>,
+        import_sites: &BTreeMap<String, ImportSite>,
+    ) -> Result<Vec<KeptPackage>> {
+        for (_, dst) in site_packages {
+            tracing::info!("Copying packages to {}", dst.display());
+        }
 
-        tracing::info!("Copying packages to {}", dst_site_packages.display());
+        let mut kept = Vec::new();
 
         // Copy each used package
-        for package in &venv_info.packages {
-            let mut package_name = package
-                .name
-                .split('-')
-                .next()
-                .unwrap_or(&package.name)
-                .to_string();
-            if package_name.ends_with(".py") {
-                package_name = package_name.trim_end_matches(".py").to_string();
-            }
-
-            if used_imports.imports.contains(&package_name) {
-                let src = &package.path;
-                let dst = if src.is_dir() {
-                    dst_site_packages.join(&package.name)
-                } else {
-                    let filename = src
-                        .file_name()
-                        .map(|os| os.to_string_lossy().to_string())
-                        .unwrap_or_else(|| package.name.clone());
-                    dst_site_packages.join(filename)
-                };
+        for (package, package_name) in selected {
+            let src = &package.path;
+            let dst_site_packages = site_packages
+                .iter()
+                .find(|(src_site_packages, _)| src.starts_with(src_site_packages))
+                .or_else(|| site_packages.first())
+                .map_or(self.output_venv.as_path(), |(_, dst)| dst.as_path());
+            let dst = dst_site_packages.join(site_packages_entry(package));
 
-                tracing::debug!("Copying package: {}", package.name);
-                if src.is_dir() {
-                    self.copy_dir_recursive(src, &dst)?;
+            tracing::debug!("Copying package: {}", package.name);
+            warn_special_package(package);
+            if src.is_dir() {
+                self.copy_dir_recursive(src, &dst)?;
+            } else {
+                fs::copy(src, &dst).with_path(src)?;
+            }
+            for support in &package.support_paths {
+                let Some(name) = support.file_name() else {
+                    continue;
+                };
+                let dst = dst_site_packages.join(name);
+                if support.is_dir() {
+                    self.copy_dir_recursive(support, &dst)?;
                 } else {
-                    fs::copy(src, &dst)?;
+                    fs::copy(support, &dst).with_path(support)?;
+                }
+            }
+
+            kept.push(kept_package(
+                package,
+                package_name,
+                import_roots,
+                import_sites,
+            ));
+        }
+
+        Ok(kept)
+    }
+
+    /// Point the copied scripts and `pyvenv.cfg` at the slim venv instead of the source
+    /// venv, or at whatever `python3` is on `PATH` when the venv is relocatable
+    fn relocate_venv(&self) -> Result<()> {
+        // Scripts record the venv path they were installed through, which may be a
+        // symlink to (or a relative spelling of) the canonical path
+        let mut sources =
+            vec![dunce::canonicalize(&self.source_venv).with_path(&self.source_venv)?];
+        if self.source_venv.is_absolute() {
+            sources.push(self.source_venv.clone());
+        } else if let Ok(cwd) = std::env::current_dir() {
+            sources.push(cwd.join(&self.source_venv));
+        }
+        sources.dedup();
+        let sources: Vec<&Path> = sources.iter().map(PathBuf::as_path).collect();
+        let output = dunce::canonicalize(&self.output_venv).with_path(&self.output_venv)?;
+        let mut rewritten = 0usize;
+
+        for dir in ["bin", "Scripts"] {
+            let scripts = self.output_venv.join(dir);
+            if !scripts.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&scripts).with_path(&scripts)? {
+                let path = entry.with_path(&scripts)?.path();
+                if !path.is_file() {
+                    continue;
                 }
+                let contents = fs::read(&path).with_path(&path)?;
+                let Some(updated) = rewrite_shebang(
+                    &contents,
+                    &sources,
+                    &output.join(dir),
+                    self.options.relocatable,
+                ) else {
+                    continue;
+                };
+                fs::write(&path, updated).with_path(&path)?;
+                rewritten += 1;
+            }
+        }
+        tracing::info!("Rewrote {} script shebangs", rewritten);
+
+        let cfg = self.output_venv.join("pyvenv.cfg");
+        if cfg.is_file() {
+            let contents = fs::read_to_string(&cfg).with_path(&cfg)?;
+            let updated =
+                rewrite_pyvenv_cfg(&contents, &sources, &output, self.options.relocatable);
+            if updated != contents {
+                fs::write(&cfg, updated).with_path(&cfg)?;
             }
         }
 
         Ok(())
     }
 
-    /// Find site-packages directory
-    fn find_site_packages(venv_path: &Path) -> Result<PathBuf> {
-        let lib_path = venv_path.join("lib");
+    /// Bring the slim venv's packaging metadata in line with what was copied: remove the
+    /// scripts that dropped distributions installed, trim each kept `RECORD` to the files
+    /// present, delete empty directories, and check kept distributions' requirements
+    ///
+    /// A bare site-packages directory has no scripts, so only its metadata is pruned.
+    fn prune_dist_metadata(
+        &self,
+        layout: &EnvironmentLayout,
+        site_packages: &[(PathBuf, PathBuf)],
+    ) -> Result<Vec<String>> {
+        let mut kept = Vec::new();
+        let mut dropped = Vec::new();
+        // A script both a kept and a dropped distribution install stays
+        let mut claimed = BTreeSet::new();
+        for (src_site_packages, dst_site_packages) in site_packages {
+            let kept_here = dist_info_dirs(dst_site_packages)?;
+            let kept_names: BTreeSet<&std::ffi::OsStr> = kept_here
+                .iter()
+                .filter_map(|dist| dist.file_name())
+                .collect();
+            dropped.extend(
+                dist_info_dirs(src_site_packages)?
+                    .into_iter()
+                    .filter(|dist| {
+                        dist.file_name()
+                            .is_some_and(|name| !kept_names.contains(name))
+                    })
+                    .map(|dist| (dist, src_site_packages)),
+            );
+            claimed.extend(
+                kept_here
+                    .iter()
+                    .flat_map(|dist| dist_scripts(dist, dst_site_packages, &self.output_venv)),
+            );
+            kept.extend(kept_here);
+        }
 
-        for entry in fs::read_dir(&lib_path)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                let name = path.file_name().unwrap_or_default().to_string_lossy();
-                if name.starts_with("python") {
-                    let site_packages = path.join("site-packages");
-                    if site_packages.exists() {
-                        return Ok(site_packages);
+        if layout.root().is_some() {
+            let mut removed = 0usize;
+            for (dist, src_site_packages) in &dropped {
+                for script in dist_scripts(dist, src_site_packages, &self.source_venv) {
+                    let path = self.output_venv.join(&script);
+                    if claimed.contains(&script) || !path.is_file() {
+                        continue;
                     }
+                    tracing::debug!(
+                        "Removing {} (installed by a dropped distribution)",
+                        path.display()
+                    );
+                    fs::remove_file(&path).with_path(&path)?;
+                    removed += 1;
                 }
             }
+            tracing::info!("Removed {} scripts of dropped distributions", removed);
+        }
+
+        let mut emptied = 0usize;
+        for (_, dst_site_packages) in site_packages {
+            for dist in kept
+                .iter()
+                .filter(|dist| dist.starts_with(dst_site_packages))
+            {
+                rewrite_record(dist, dst_site_packages)?;
+            }
+            emptied += remove_empty_dirs(dst_site_packages)?;
         }
+        tracing::info!("Removed {} empty directories", emptied);
 
-        Err(TsrsError::InvalidVenvPath(
-            "Could not find site-packages".to_string(),
-        ))
+        Ok(unsatisfied_requirements(&kept))
     }
 
-    /// Find or create site-packages directory in output venv
-    fn find_or_create_site_packages(&self, venv_path: &Path) -> Result<PathBuf> {
-        // Copy the Python version from source
-        let src_site_packages = Self::find_site_packages(&self.source_venv)?;
-        let python_dir = src_site_packages
-            .parent()
-            .and_then(|p| p.file_name())
-            .map(|n| n.to_string_lossy().to_string())
-            .ok_or_else(|| {
-                TsrsError::InvalidVenvPath("Could not determine Python version".to_string())
+    /// Write the kept packages and the code directories into a zipapp at the output path
+    fn write_zipapp(
+        &self,
+        selected: &[(&PackageInfo, String)],
+        import_roots: &BTreeMap<String, BTreeSet<PathBuf>>,
+        import_sites: &BTreeMap<String, ImportSite>,
+        code_exclude: &GlobSet,
+    ) -> Result<Vec<KeptPackage>> {
+        let main = match &self.options.main {
+            Some(entry_point) => Some(zipapp_main(entry_point)?),
+            None => {
+                if !self
+                    .code_directories
+                    .iter()
+                    .any(|dir| dir.join("__main__.py").is_file())
+                {
+                    return Err(TsrsError::ConfigError(
+                        "a zipapp needs an entry point: pass `module:function` or add \
+                         __main__.py to a code directory"
+                            .to_string(),
+                    ));
+                }
+                None
+            }
+        };
+
+        let output = &self.output_venv;
+        if let Some(parent) = output.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).with_path(parent)?;
+        }
+        tracing::info!("Writing zipapp to {}", output.display());
+
+        // The interpreter line goes before the archive; zip readers find the central
+        // directory from the end of the file
+        let mut file = File::create(output).with_path(output)?;
+        file.write_all(b"#!/usr/bin/env python3\n")
+            .with_path(output)?;
+        let mut archive = ZipAppWriter::new(file, output);
+
+        if let Some(main) = main {
+            archive.add_bytes("__main__.py", main.as_bytes())?;
+        }
+
+        let mut kept = Vec::new();
+        for (package, package_name) in selected {
+            tracing::debug!("Adding package: {}", package.name);
+            warn_special_package(package);
+            if is_editable_pth(package) {
+                // A .pth file does nothing inside a zipapp, so bundle the code it points at
+                match package.editable_target.as_deref() {
+                    Some(target) if target.is_dir() => {
+                        archive.add_dir(target, &package.name, |_| true)?;
+                    }
+                    Some(target) if target.is_file() => {
+                        let name = target
+                            .file_name()
+                            .map(|n| n.to_string_lossy().to_string())
+                            .unwrap_or_else(|| format!("{}.py", package.name));
+                        archive.add_file(target, &name)?;
+                    }
+                    _ => {}
+                }
+            } else {
+                let entry = site_packages_entry(package);
+                if package.path.is_dir() {
+                    archive.add_dir(&package.path, &entry, |_| true)?;
+                } else {
+                    archive.add_file(&package.path, &entry)?;
+                }
+                for support in &package.support_paths {
+                    let Some(name) = support.file_name().map(|n| n.to_string_lossy().to_string())
+                    else {
+                        continue;
+                    };
+                    if support.is_dir() {
+                        archive.add_dir(support, &name, |_| true)?;
+                    } else {
+                        archive.add_file(support, &name)?;
+                    }
+                }
+            }
+            kept.push(kept_package(
+                package,
+                package_name,
+                import_roots,
+                import_sites,
+            ));
+        }
+
+        // Code directories go at the archive root so `module:function` resolves against
+        // them, leaving out hidden directories (such as an in-tree `.venv`), excluded files,
+        // and the zipapp itself when it is written inside a code directory
+        let canonical_output = dunce::canonicalize(output).ok();
+        let is_output = |path: &Path| {
+            path.file_name() == output.file_name()
+                && dunce::canonicalize(path).ok() == canonical_output
+        };
+        for code_directory in &self.code_directories {
+            archive.add_dir(code_directory, "", |path| {
+                !is_output(path) && !is_code_excluded(code_exclude, code_directory, path)
             })?;
+        }
+
+        archive.finish()?;
 
-        let lib_path = venv_path.join("lib");
-        fs::create_dir_all(&lib_path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(output, fs::Permissions::from_mode(0o755)).with_path(output)?;
+        }
+
+        Ok(kept)
+    }
 
-        let python_path = lib_path.join(python_dir);
-        fs::create_dir_all(&python_path)?;
+    /// Recursively copy a directory, leaving out the `skip` directories
+    #[allow(clippy::only_used_in_recursion)]
+    fn copy_dir_except(&self, src: &Path, dst: &Path, skip: &[PathBuf]) -> Result<()> {
+        fs::create_dir_all(dst).with_path(dst)?;
+
+        for entry in fs::read_dir(src).with_path(src)? {
+            let entry = entry.with_path(src)?;
+            let path = entry.path();
+            if skip.contains(&path) {
+                continue;
+            }
+            let dst_path = dst.join(entry.file_name());
 
-        let site_packages = python_path.join("site-packages");
-        fs::create_dir_all(&site_packages)?;
+            if path.is_dir() {
+                self.copy_dir_except(&path, &dst_path, skip)?;
+            } else {
+                fs::copy(&path, &dst_path).with_path(&path)?;
+            }
+        }
 
-        Ok(site_packages)
+        Ok(())
     }
 
     /// Recursively copy a directory
     #[allow(clippy::only_used_in_recursion)]
     fn copy_dir_recursive(&self, src: &Path, dst: &Path) -> Result<()> {
-        fs::create_dir_all(dst)?;
+        fs::create_dir_all(dst).with_path(dst)?;
 
-        for entry in fs::read_dir(src)? {
-            let entry = entry?;
+        for entry in fs::read_dir(src).with_path(src)? {
+            let entry = entry.with_path(src)?;
             let path = entry.path();
             let file_name = entry.file_name();
             let dst_path = dst.join(&file_name);
@@ -274,10 +1159,2268 @@ impl VenvSlimmer {
             if path.is_dir() {
                 self.copy_dir_recursive(&path, &dst_path)?;
             } else {
-                fs::copy(&path, &dst_path)?;
+                fs::copy(&path, &dst_path).with_path(&path)?;
             }
         }
 
         Ok(())
     }
 }
+
+/// Top-level modules defined by the code directories: packages (directories with an
+/// `__init__.py`) and single-module `.py` files directly under each directory or its
+/// `src/` subdirectory, plus a code directory that is itself a package
+fn first_party_modules(code_directories: &[PathBuf]) -> BTreeSet<String> {
+    let mut modules = BTreeSet::new();
+    for code_directory in code_directories {
+        if code_directory.join("__init__.py").is_file() {
+            if let Some(name) = code_directory.file_name().and_then(|n| n.to_str()) {
+                modules.insert(name.to_string());
+            }
+        }
+        for base in [code_directory.clone(), code_directory.join("src")] {
+            let Ok(entries) = fs::read_dir(&base) else {
+                continue;
+            };
+            for entry in entries.filter_map(std::result::Result::ok) {
+                let path = entry.path();
+                let name = if path.is_dir() && path.join("__init__.py").is_file() {
+                    path.file_name().and_then(|n| n.to_str())
+                } else if path.extension().is_some_and(|ext| ext == "py") {
+                    path.file_stem().and_then(|n| n.to_str())
+                } else {
+                    None
+                };
+                if let Some(name) = name.filter(|name| is_module_name(name)) {
+                    modules.insert(name.to_string());
+                }
+            }
+        }
+    }
+    modules
+}
+
+/// Whether `name` can be imported: an identifier other than `__init__` or `__main__`
+fn is_module_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first == '_' || first.is_alphabetic())
+        && chars.all(|c| c == '_' || c.is_alphanumeric())
+        && name != "__init__"
+        && name != "__main__"
+}
+
+/// Name a package is imported by: a dist-info directory's distribution name, or a
+/// module file without its `.py`
+fn import_name(package: &PackageInfo) -> String {
+    let mut package_name = package
+        .name
+        .split('-')
+        .next()
+        .unwrap_or(&package.name)
+        .to_string();
+    if package_name.ends_with(".py") {
+        package_name = package_name.trim_end_matches(".py").to_string();
+    }
+    package_name
+}
+
+/// Kept packages paired with the import that selected them
+type SelectedPackages<'a> = Vec<(&'a PackageInfo, String)>;
+
+/// Packages to keep, paired with the import that selected them: those the code imports,
+/// directly or through another module their distribution installs (`import PIL` keeps
+/// `Pillow`'s dist-info), plus `keep` matches, minus `drop` matches. Also returns the
+/// decision made for every package.
+fn select_packages<'a>(
+    venv_info: &'a VenvInfo,
+    used_imports: &ImportSet,
+    import_sites: &BTreeMap<String, ImportSite>,
+    policy: &PackagePolicy,
+) -> Result<(SelectedPackages<'a>, Vec<PackageDecision>)> {
+    let keep = PolicyPatterns::new(&policy.keep)?;
+    let drop = PolicyPatterns::new(&policy.drop)?;
+
+    let mut selected = Vec::new();
+    let mut decisions = Vec::new();
+    let mut normalized_names = Vec::new();
+
+    // normalized distribution -> first import, in name order, that it provides
+    let mut imports: Vec<&String> = used_imports.imports.iter().collect();
+    imports.sort();
+    let mut imported_distributions: BTreeMap<String, &str> = BTreeMap::new();
+    for import in imports {
+        if let Some(distribution) = venv_info.import_map.get(import) {
+            imported_distributions
+                .entry(normalize_package_name(distribution))
+                .or_insert(import);
+        }
+    }
+
+    for package in &venv_info.packages {
+        let package_name = import_name(package);
+        let normalized = normalize_package_name(&package_name);
+        let distribution = if package.name.ends_with(".dist-info") {
+            Some(normalized.clone())
+        } else {
+            venv_info
+                .import_map
+                .get(&package_name)
+                .map(|distribution| normalize_package_name(distribution))
+        };
+        let matched_import = if used_imports.imports.contains(&package_name) {
+            Some(package_name.clone())
+        } else {
+            distribution
+                .and_then(|distribution| imported_distributions.get(&distribution))
+                .map(|import| (*import).to_string())
+        };
+        let imported = matched_import.is_some();
+        let package_name = matched_import.unwrap_or(package_name);
+        let (decision, rule) = if let Some(rule) = drop.matching(&normalized) {
+            if let Some(site) = import_sites.get(&package_name) {
+                tracing::warn!(
+                    "{} is imported at {} but dropped by policy `{}`",
+                    package.name,
+                    site,
+                    rule
+                );
+            }
+            (SlimDecision::DroppedByPolicy, Some(rule))
+        } else if imported {
+            (SlimDecision::KeptByImport, None)
+        } else if let Some(rule) = keep.matching(&normalized) {
+            (SlimDecision::KeptByPolicy, Some(rule))
+        } else {
+            (SlimDecision::DroppedUnused, None)
+        };
+        if matches!(
+            decision,
+            SlimDecision::KeptByImport | SlimDecision::KeptByPolicy
+        ) {
+            selected.push((package, package_name));
+        }
+        decisions.push(PackageDecision {
+            name: package.name.clone(),
+            decision,
+            rule: rule.map(str::to_string),
+            required_by: Vec::new(),
+        });
+        normalized_names.push(normalized);
+    }
+
+    if decisions
+        .iter()
+        .any(|d| d.decision == SlimDecision::DroppedByPolicy)
+    {
+        mark_required_drops(venv_info, &selected, &normalized_names, &mut decisions);
+    }
+    Ok((selected, decisions))
+}
+
+/// Imports that no package in the source venv provides, either under its own name or
+/// through a distribution's installed top-level modules
+fn unresolved_imports(venv_info: &VenvInfo, used_imports: &ImportSet) -> Vec<String> {
+    let provided: BTreeSet<String> = venv_info.packages.iter().map(import_name).collect();
+    let mut unresolved: Vec<String> = used_imports
+        .imports
+        .iter()
+        .filter(|import| !provided.contains(*import) && !venv_info.import_map.contains_key(*import))
+        .cloned()
+        .collect();
+    unresolved.sort();
+    unresolved
+}
+
+/// Imported top-level modules that a kept package provides, in name order
+fn kept_imports(
+    venv_info: &VenvInfo,
+    used_imports: &ImportSet,
+    selected: &[(&PackageInfo, String)],
+) -> Vec<String> {
+    let kept: BTreeSet<String> = selected
+        .iter()
+        .map(|(package, _)| normalize_package_name(&import_name(package)))
+        .collect();
+    let mut modules: Vec<String> = used_imports
+        .imports
+        .iter()
+        .filter(|import| {
+            kept.contains(&normalize_package_name(import))
+                || venv_info
+                    .import_map
+                    .get(*import)
+                    .is_some_and(|distribution| {
+                        kept.contains(&normalize_package_name(distribution))
+                    })
+        })
+        .cloned()
+        .collect();
+    modules.sort();
+    modules
+}
+
+/// Run [`VERIFY_IMPORTS_SCRIPT`] on `modules`, returning each reported module's error (if
+/// any) and why the interpreter stopped early, when it did
+fn run_import_check(
+    interpreter: &Path,
+    modules: &[String],
+) -> (BTreeMap<String, Option<String>>, Option<String>) {
+    let mut results = BTreeMap::new();
+    // -I keeps the working directory and user site-packages off sys.path
+    let mut child = match Command::new(interpreter)
+        .args(["-I", "-c", VERIFY_IMPORTS_SCRIPT])
+        .args(modules)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            let failure = format!("failed to run {}: {e}", interpreter.display());
+            return (results, Some(failure));
+        }
+    };
+
+    // Drain stdout on another thread so a full pipe never stalls the child
+    let reader = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stdout.read_to_string(&mut output);
+            output
+        })
+    });
+    let deadline = Instant::now() + VERIFY_IMPORTS_TIMEOUT;
+    let failure = loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break None,
+            Ok(Some(status)) => break Some(format!("interpreter exited with {status}")),
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break Some(format!(
+                    "import check timed out after {}s",
+                    VERIFY_IMPORTS_TIMEOUT.as_secs()
+                ));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => break Some(format!("failed to wait for the interpreter: {e}")),
+        }
+    };
+
+    let output = reader
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+    for line in output.lines() {
+        // Anything else on stdout was printed by the imported modules themselves
+        if let Ok(outcome) = serde_json::from_str::<ImportOutcome>(line) {
+            results.insert(outcome.module, outcome.error);
+        }
+    }
+    (results, failure)
+}
+
+/// Fill `required_by` for packages dropped by policy that a kept package still depends
+/// on through dist-info `Requires-Dist` metadata, warning about each one.
+/// `normalized_names` holds the normalized import name of each decision's package.
+fn mark_required_drops(
+    venv_info: &VenvInfo,
+    selected: &[(&PackageInfo, String)],
+    normalized_names: &[String],
+    decisions: &mut [PackageDecision],
+) {
+    let requires: BTreeMap<String, Vec<String>> = venv_info
+        .packages
+        .iter()
+        .filter(|package| package.name.ends_with(".dist-info"))
+        .map(|package| {
+            (
+                normalize_package_name(&import_name(package)),
+                requires_dist(&package.path),
+            )
+        })
+        .collect();
+
+    // dependency -> kept packages that need it
+    let mut required_by: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let kept: BTreeSet<String> = selected
+        .iter()
+        .map(|(package, _)| normalize_package_name(&import_name(package)))
+        .collect();
+    for root in &kept {
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![root.clone()];
+        while let Some(name) = pending.pop() {
+            for dependency in requires.get(&name).into_iter().flatten() {
+                if dependency != root && seen.insert(dependency.clone()) {
+                    required_by
+                        .entry(dependency.clone())
+                        .or_default()
+                        .insert(root.clone());
+                    pending.push(dependency.clone());
+                }
+            }
+        }
+    }
+
+    let mut warned = BTreeSet::new();
+    for (decision, normalized) in decisions.iter_mut().zip(normalized_names) {
+        if decision.decision != SlimDecision::DroppedByPolicy {
+            continue;
+        }
+        let Some(dependents) = required_by.get(normalized) else {
+            continue;
+        };
+        decision.required_by = dependents.iter().cloned().collect();
+        if warned.insert(normalized) {
+            tracing::warn!(
+                "{} is dropped by policy but kept packages depend on it: {}",
+                decision.name,
+                decision.required_by.join(", ")
+            );
+        }
+    }
+}
+
+/// Compiled `keep` or `drop` patterns, matched against normalized package names
+struct PolicyPatterns<'a> {
+    patterns: &'a [String],
+    globs: GlobSet,
+}
+
+impl<'a> PolicyPatterns<'a> {
+    fn new(patterns: &'a [String]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = GlobBuilder::new(&normalize_policy_pattern(pattern))
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| {
+                    TsrsError::ConfigError(format!("invalid package pattern `{pattern}`: {e}"))
+                })?;
+            builder.add(glob);
+        }
+        let globs = builder
+            .build()
+            .map_err(|e| TsrsError::ConfigError(e.to_string()))?;
+        Ok(PolicyPatterns { patterns, globs })
+    }
+
+    /// The first pattern matching `normalized`
+    fn matching(&self, normalized: &str) -> Option<&'a str> {
+        let index = self.globs.matches(normalized).into_iter().min()?;
+        Some(self.patterns[index].as_str())
+    }
+}
+
+/// Apply [`normalize_package_name`] to the literal parts of a glob, leaving `[...]`
+/// classes (which may contain ranges like `a-z`) alone
+fn normalize_policy_pattern(pattern: &str) -> String {
+    let mut normalized = String::with_capacity(pattern.len());
+    let mut literal = String::new();
+    let mut in_class = false;
+    for c in pattern.chars() {
+        match c {
+            '[' if !in_class => {
+                normalized.push_str(&normalize_package_name(&literal));
+                literal.clear();
+                in_class = true;
+                normalized.push(c);
+            }
+            ']' if in_class => {
+                in_class = false;
+                normalized.push(c);
+            }
+            _ if in_class => normalized.push(c),
+            _ => literal.push(c),
+        }
+    }
+    normalized.push_str(&normalize_package_name(&literal));
+    normalized
+}
+
+/// Each site-packages directory of `layout` paired with its place in the output: the same
+/// path relative to the environment root, or `output` itself for a bare site-packages
+fn output_site_packages(layout: &EnvironmentLayout, output: &Path) -> Vec<(PathBuf, PathBuf)> {
+    layout
+        .site_packages()
+        .iter()
+        .map(|site_packages| {
+            let dst = layout
+                .root()
+                .and_then(|root| site_packages.strip_prefix(root).ok())
+                .map_or_else(|| output.to_path_buf(), |relative| output.join(relative));
+            (site_packages.clone(), dst)
+        })
+        .collect()
+}
+
+/// Name of a package's directory or module file inside site-packages
+fn site_packages_entry(package: &PackageInfo) -> String {
+    if package.path.is_dir() {
+        package.name.clone()
+    } else {
+        package
+            .path
+            .file_name()
+            .map(|os| os.to_string_lossy().to_string())
+            .unwrap_or_else(|| package.name.clone())
+    }
+}
+
+fn kept_package(
+    package: &PackageInfo,
+    package_name: &str,
+    import_roots: &BTreeMap<String, BTreeSet<PathBuf>>,
+    import_sites: &BTreeMap<String, ImportSite>,
+) -> KeptPackage {
+    KeptPackage {
+        name: package.name.clone(),
+        roots: import_roots
+            .get(package_name)
+            .map(|roots| roots.iter().cloned().collect())
+            .unwrap_or_default(),
+        imported_at: import_sites.get(package_name).cloned(),
+        editable: package.editable,
+        editable_target: package.editable_target.clone(),
+        namespace_distributions: package.namespace_distributions.clone(),
+        license: package
+            .metadata
+            .as_ref()
+            .and_then(PackageMetadata::license_name),
+    }
+}
+
+/// Kept dist-info directories counted by license
+fn license_summary(selected: &[(&PackageInfo, String)]) -> LicenseSummary {
+    let mut summary = LicenseSummary::default();
+    for (package, _) in selected {
+        if !package.name.ends_with(".dist-info") {
+            continue;
+        }
+        match package
+            .metadata
+            .as_ref()
+            .and_then(PackageMetadata::license_name)
+        {
+            Some(license) => *summary.counts.entry(license).or_default() += 1,
+            None => summary.unknown.push(package.name.clone()),
+        }
+    }
+    summary
+}
+
+/// Fail when a kept distribution's license matches one of `patterns`, naming the import
+/// or policy rule that kept each one
+fn check_licenses(
+    patterns: &[String],
+    selected: &[(&PackageInfo, String)],
+    decisions: &[PackageDecision],
+    import_sites: &BTreeMap<String, ImportSite>,
+) -> Result<()> {
+    if patterns.is_empty() {
+        return Ok(());
+    }
+    let mut packages = Vec::new();
+    for (package, import) in selected {
+        let Some(metadata) = &package.metadata else {
+            continue;
+        };
+        let Some(pattern) = patterns
+            .iter()
+            .find(|pattern| metadata.matches_license(pattern))
+        else {
+            continue;
+        };
+        let license = metadata.license_name().unwrap_or_else(|| pattern.clone());
+        let rule = decisions
+            .iter()
+            .find(|decision| decision.name == package.name)
+            .and_then(|decision| decision.rule.as_deref());
+        let reason = match (rule, import_sites.get(import)) {
+            (Some(rule), _) => format!("kept by policy `{rule}`"),
+            (None, Some(site)) => format!("imported as `{import}` at {site}"),
+            (None, None) => format!("imported as `{import}`"),
+        };
+        packages.push(format!(
+            "{} ({license}, matches `{pattern}`): {reason}",
+            package.name
+        ));
+    }
+    if packages.is_empty() {
+        Ok(())
+    } else {
+        Err(TsrsError::LicenseDenied { packages })
+    }
+}
+
+/// An editable install found through its `.pth` file, as opposed to its dist-info
+fn is_editable_pth(package: &PackageInfo) -> bool {
+    package.editable && package.path.is_file()
+}
+
+/// Explain kept packages whose code is not simply a directory in site-packages
+fn warn_special_package(package: &PackageInfo) {
+    if is_editable_pth(package) {
+        match &package.editable_target {
+            Some(target) if target.exists() => tracing::warn!(
+                "{} is an editable install; its code stays at {}",
+                package.name,
+                target.display()
+            ),
+            Some(target) => tracing::warn!(
+                "{} is an editable install whose source {} does not exist",
+                package.name,
+                target.display()
+            ),
+            None => tracing::warn!(
+                "{} is an editable install with an unknown source location",
+                package.name
+            ),
+        }
+    }
+    if !package.namespace_distributions.is_empty() {
+        tracing::info!(
+            "{} is a namespace package; keeping all {} distributions: {}",
+            package.name,
+            package.namespace_distributions.len(),
+            package.namespace_distributions.join(", ")
+        );
+    }
+}
+
+/// `*.dist-info` directories directly inside `site_packages`, sorted
+fn dist_info_dirs(site_packages: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(site_packages).with_path(site_packages)? {
+        let path = entry.with_path(site_packages)?.path();
+        if path.is_dir()
+            && path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().ends_with(".dist-info"))
+        {
+            dirs.push(path);
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Scripts a distribution installs, relative to `venv`: its `console_scripts` and
+/// `gui_scripts` entry points plus any `RECORD` row that lands in `bin/` or `Scripts/`
+fn dist_scripts(dist_info: &Path, site_packages: &Path, venv: &Path) -> BTreeSet<PathBuf> {
+    let mut scripts = BTreeSet::new();
+    if let Ok(entry_points) = fs::read_to_string(dist_info.join("entry_points.txt")) {
+        for name in script_entry_points(&entry_points) {
+            scripts.insert(Path::new("bin").join(name));
+            scripts.insert(Path::new("Scripts").join(format!("{name}.exe")));
+            scripts.insert(Path::new("Scripts").join(format!("{name}-script.py")));
+        }
+    }
+    if let Ok(record) = fs::read_to_string(dist_info.join("RECORD")) {
+        for row in record.lines() {
+            let (path, _) = record_path(row);
+            let resolved = resolve_record_path(site_packages, &path);
+            let Ok(relative) = resolved.strip_prefix(venv) else {
+                continue;
+            };
+            if relative.components().count() == 2
+                && (relative.starts_with("bin") || relative.starts_with("Scripts"))
+            {
+                scripts.insert(relative.to_path_buf());
+            }
+        }
+    }
+    scripts
+}
+
+/// Names declared in the `[console_scripts]` and `[gui_scripts]` sections of an
+/// `entry_points.txt`
+fn script_entry_points(contents: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut in_scripts = false;
+    for line in contents.lines().map(str::trim) {
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_scripts = matches!(section.trim(), "console_scripts" | "gui_scripts");
+        } else if in_scripts && !line.starts_with(['#', ';']) {
+            if let Some((name, _)) = line.split_once('=') {
+                if !name.trim().is_empty() {
+                    names.push(name.trim());
+                }
+            }
+        }
+    }
+    names
+}
+
+/// Join a `RECORD` path (relative to site-packages, possibly climbing out with `..`)
+/// onto `site_packages` without touching the filesystem
+fn resolve_record_path(site_packages: &Path, record_path: &str) -> PathBuf {
+    let mut resolved = site_packages.to_path_buf();
+    for component in Path::new(record_path).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(part) => resolved.push(part),
+            _ => {}
+        }
+    }
+    resolved
+}
+
+/// Drop `RECORD` rows for files missing from the slim venv and refresh the hash and
+/// size of the rest; rows written without a hash (the `RECORD` itself, `.pyc` files)
+/// keep that form
+fn rewrite_record(dist_info: &Path, site_packages: &Path) -> Result<()> {
+    let record = dist_info.join("RECORD");
+    let Ok(contents) = fs::read_to_string(&record) else {
+        return Ok(());
+    };
+    let mut rewritten = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        let row = line.trim_end_matches(['\r', '\n']);
+        if row.is_empty() {
+            continue;
+        }
+        let (path, field) = record_path(row);
+        let file = resolve_record_path(site_packages, &path);
+        if !file.is_file() {
+            continue;
+        }
+        let hashed = row[field.len()..]
+            .trim_start_matches(',')
+            .split(',')
+            .next()
+            .is_some_and(|hash| !hash.is_empty());
+        if hashed {
+            let data = fs::read(&file).with_path(&file)?;
+            rewritten.push_str(&format!("{},{},{}", field, record_hash(&data), data.len()));
+            rewritten.push_str(&line[row.len()..]);
+        } else {
+            rewritten.push_str(line);
+        }
+    }
+    if rewritten != contents {
+        fs::write(&record, rewritten).with_path(&record)?;
+    }
+    Ok(())
+}
+
+/// Remove directories under `root` that are (or became) empty, deepest first
+fn remove_empty_dirs(root: &Path) -> Result<usize> {
+    let mut removed = 0;
+    for entry in WalkDir::new(root).min_depth(1).contents_first(true) {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let path = entry.path();
+        if entry.file_type().is_dir() && fs::read_dir(path).with_path(path)?.next().is_none() {
+            fs::remove_dir(path).with_path(path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// A warning for each `Requires-Dist` of a kept distribution that no kept distribution
+/// satisfies, as `pip check` would report
+fn unsatisfied_requirements(kept: &[PathBuf]) -> Vec<String> {
+    let dist_name = |dist: &Path| {
+        let name = dist.file_name().unwrap_or_default().to_string_lossy();
+        normalize_package_name(name.split('-').next().unwrap_or_default())
+    };
+    let available: BTreeSet<String> = kept.iter().map(|dist| dist_name(dist)).collect();
+    let mut warnings = Vec::new();
+    for dist in kept {
+        for requirement in requires_dist(dist) {
+            if available.contains(&requirement) {
+                continue;
+            }
+            let warning = format!(
+                "{} requires {}, which is not in the slim venv",
+                dist.file_name().unwrap_or_default().to_string_lossy(),
+                requirement
+            );
+            tracing::warn!("{}", warning);
+            warnings.push(warning);
+        }
+    }
+    warnings
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Replace a shebang whose interpreter lives under one of `sources` with the same
+/// interpreter in `scripts`, or with `/usr/bin/env python3` when `relocatable`.
+/// Returns `None` for binaries and scripts that do not name the source venv.
+fn rewrite_shebang(
+    contents: &[u8],
+    sources: &[&Path],
+    scripts: &Path,
+    relocatable: bool,
+) -> Option<Vec<u8>> {
+    if !contents.starts_with(b"#!") {
+        return None;
+    }
+    let line_end = contents
+        .iter()
+        .position(|&b| b == b'\n')
+        .unwrap_or(contents.len());
+    let line = std::str::from_utf8(&contents[2..line_end]).ok()?;
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let interpreter = line.split_whitespace().next()?;
+    let interpreter_path = Path::new(interpreter);
+    if !sources
+        .iter()
+        .any(|source| interpreter_path.starts_with(source))
+    {
+        return None;
+    }
+
+    let rest = &line[line.find(interpreter)? + interpreter.len()..];
+    let shebang = if relocatable {
+        format!("#!/usr/bin/env python3{rest}")
+    } else {
+        let name = interpreter_path.file_name()?;
+        format!("#!{}{rest}", scripts.join(name).display())
+    };
+
+    let mut updated = shebang.into_bytes();
+    if contents[..line_end].ends_with(b"\r") {
+        updated.push(b'\r');
+    }
+    updated.extend_from_slice(&contents[line_end..]);
+    Some(updated)
+}
+
+/// Replace paths under the source venv in `pyvenv.cfg` (such as the `command` that created
+/// it) with the slim venv path. A relocatable venv drops `command` instead, since no fixed
+/// path stays correct once the venv is moved.
+fn rewrite_pyvenv_cfg(
+    contents: &str,
+    sources: &[&Path],
+    output: &Path,
+    relocatable: bool,
+) -> String {
+    let output = output.display().to_string();
+    let mut updated = String::with_capacity(contents.len());
+    for line in contents.lines() {
+        let key = line.split('=').next().unwrap_or_default().trim();
+        if relocatable && key == "command" {
+            continue;
+        }
+        let mut line = line.to_string();
+        for source in sources {
+            let source = source.display().to_string();
+            if line.contains(&source) {
+                line = line.replace(&source, &output);
+                break;
+            }
+        }
+        updated.push_str(&line);
+        updated.push('\n');
+    }
+    updated
+}
+
+/// Python's zipapp `__main__.py` for a `module:function` entry point
+fn zipapp_main(entry_point: &str) -> Result<String> {
+    let is_dotted_name = |name: &str| {
+        !name.is_empty()
+            && name.split('.').all(|part| {
+                part.chars()
+                    .next()
+                    .is_some_and(|c| c.is_alphabetic() || c == '_')
+                    && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+            })
+    };
+    match entry_point.split_once(':') {
+        Some((module, function)) if is_dotted_name(module) && is_dotted_name(function) => Ok(
+            format!("# -*- coding: utf-8 -*-\nimport {module}\n{module}.{function}()\n"),
+        ),
+        _ => Err(TsrsError::ConfigError(format!(
+            "invalid zipapp entry point `{entry_point}`: expected `module:function`"
+        ))),
+    }
+}
+
+/// Zip writer that skips entries already in the archive, so a code directory cannot
+/// shadow a package (or the generated `__main__.py`) with a second copy
+struct ZipAppWriter<'a> {
+    zip: zip::ZipWriter<File>,
+    path: &'a Path,
+    names: BTreeSet<String>,
+}
+
+impl<'a> ZipAppWriter<'a> {
+    fn new(file: File, path: &'a Path) -> Self {
+        ZipAppWriter {
+            zip: zip::ZipWriter::new(file),
+            path,
+            names: BTreeSet::new(),
+        }
+    }
+
+    fn options() -> zip::write::SimpleFileOptions {
+        zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .unix_permissions(0o644)
+    }
+
+    fn claim(&mut self, name: &str) -> bool {
+        if self.names.insert(name.to_string()) {
+            true
+        } else {
+            tracing::warn!("Skipping duplicate zipapp entry: {}", name);
+            false
+        }
+    }
+
+    fn add_bytes(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        if !self.claim(name) {
+            return Ok(());
+        }
+        self.zip
+            .start_file(name.to_string(), Self::options())
+            .map_err(std::io::Error::from)
+            .with_path(self.path)?;
+        self.zip.write_all(contents).with_path(self.path)
+    }
+
+    fn add_file(&mut self, src: &Path, name: &str) -> Result<()> {
+        if !self.claim(name) {
+            return Ok(());
+        }
+        self.zip
+            .start_file(name.to_string(), Self::options())
+            .map_err(std::io::Error::from)
+            .with_path(self.path)?;
+        let mut source = File::open(src).with_path(src)?;
+        std::io::copy(&mut source, &mut self.zip).with_path(src)?;
+        Ok(())
+    }
+
+    /// Add the files under `dir` below `prefix`, skipping hidden entries, `__pycache__`,
+    /// and files `include` rejects
+    fn add_dir(&mut self, dir: &Path, prefix: &str, include: impl Fn(&Path) -> bool) -> Result<()> {
+        let walker = WalkDir::new(dir)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0 || {
+                    let name = entry.file_name().to_string_lossy();
+                    !name.starts_with('.') && name != "__pycache__"
+                }
+            });
+        for entry in walker {
+            let entry = entry.map_err(std::io::Error::from).with_path(dir)?;
+            if !entry.file_type().is_file() || !include(entry.path()) {
+                continue;
+            }
+            let Ok(relative) = entry.path().strip_prefix(dir) else {
+                continue;
+            };
+            let relative: Vec<String> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            let name = if prefix.is_empty() {
+                relative.join("/")
+            } else if relative.is_empty() {
+                prefix.to_string()
+            } else {
+                format!("{prefix}/{}", relative.join("/"))
+            };
+            self.add_file(entry.path(), &name)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        self.zip
+            .finish()
+            .map_err(std::io::Error::from)
+            .with_path(self.path)?;
+        Ok(())
+    }
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|e| TsrsError::ConfigError(format!("invalid glob `{pattern}`: {e}")))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| TsrsError::ConfigError(e.to_string()))
+}
+
+/// A code file is excluded when the glob set matches its path relative to the code
+/// directory or its file name
+fn is_code_excluded(exclude: &GlobSet, code_directory: &Path, path: &Path) -> bool {
+    if exclude.is_empty() {
+        return false;
+    }
+    let relative = path.strip_prefix(code_directory).unwrap_or(path);
+    exclude.is_match(relative) || path.file_name().is_some_and(|name| exclude.is_match(name))
+}
+
+/// A source is exempt when the glob set matches its top-level package or its relative path
+fn is_strip_exempt(keep: &GlobSet, relative: &Path) -> bool {
+    if keep.is_empty() {
+        return false;
+    }
+    let package = relative
+        .components()
+        .next()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .unwrap_or_default();
+    let package = package.trim_end_matches(".py");
+    keep.is_match(package) || keep.is_match(relative)
+}
+
+/// Find `__pycache__/<stem>.<tag>.pyc` for a source file, preferring unoptimized bytecode
+fn cached_bytecode(source: &Path) -> Option<PathBuf> {
+    let stem = source.file_stem()?.to_string_lossy().to_string();
+    let cache_dir = source.parent()?.join("__pycache__");
+    let prefix = format!("{stem}.");
+    let mut candidates: Vec<PathBuf> = fs::read_dir(cache_dir)
+        .ok()?
+        .filter_map(std::result::Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy())
+                .is_some_and(|name| {
+                    name.len() > prefix.len() + 4
+                        && name.starts_with(&prefix)
+                        && name.ends_with(".pyc")
+                        && !name[prefix.len()..name.len() - 4].contains('.')
+                })
+        })
+        .collect();
+    candidates.sort();
+    candidates.pop()
+}
+
+fn directory_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(std::result::Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn total_size(dirs: &[&Path]) -> u64 {
+    dirs.iter().copied().map(directory_size).sum()
+}
+
+fn size_delta(before: u64, after: u64) -> i64 {
+    before as i64 - after as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::venv::EnvironmentKind;
+    use tempfile::tempdir;
+
+    fn make_venv(root: &Path, packages: &[&str]) -> PathBuf {
+        let venv = root.join(".venv");
+        let site_packages = venv.join("lib").join("python3.11").join("site-packages");
+        for package in packages {
+            let dir = site_packages.join(package);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("__init__.py"), "").unwrap();
+        }
+        venv
+    }
+
+    #[test]
+    fn multiple_roots_union_imports_and_attribute_packages() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha", "beta", "gamma"]);
+
+        let service = tmp.path().join("service");
+        let worker = tmp.path().join("worker");
+        fs::create_dir_all(&service).unwrap();
+        fs::create_dir_all(&worker).unwrap();
+        fs::write(service.join("app.py"), "import alpha\nimport beta\n").unwrap();
+        fs::write(worker.join("job.py"), "from beta import run\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        let slimmer =
+            VenvSlimmer::new_with_roots(&[service.clone(), worker.clone()], &venv, &output)
+                .unwrap();
+        let report = slimmer.slim().unwrap();
+
+        let kept: Vec<&str> = report.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(kept, vec!["alpha", "beta"]);
+        assert_eq!(report.kept[0].roots, vec![service.clone()]);
+        let mut beta_roots = report.kept[1].roots.clone();
+        beta_roots.sort();
+        let mut expected = vec![service, worker];
+        expected.sort();
+        assert_eq!(beta_roots, expected);
+
+        let site_packages = output.join("lib").join("python3.11").join("site-packages");
+        assert!(site_packages.join("beta").join("__init__.py").exists());
+        assert!(!site_packages.join("gamma").exists());
+    }
+
+    #[test]
+    fn code_exclude_drops_test_only_imports_and_reports_import_sites() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["pytest", "requests"]);
+
+        let project = tmp.path().join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::create_dir_all(project.join("tests")).unwrap();
+        fs::write(
+            project.join("src").join("app.py"),
+            "import os\n\nimport requests\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("tests").join("test_app.py"),
+            "import pytest\nfrom requests import Session\n",
+        )
+        .unwrap();
+        fs::write(project.join("conftest.py"), "import pytest\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        let report = VenvSlimmer::new_with_roots(std::slice::from_ref(&project), &venv, &output)
+            .unwrap()
+            .with_options(SlimOptions {
+                code_exclude: vec!["tests/**".to_string(), "conftest.py".to_string()],
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap();
+
+        let kept: Vec<&str> = report.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(kept, vec!["requests"]);
+        assert_eq!(
+            report.kept[0].imported_at,
+            Some(ImportSite {
+                file: project.join("src").join("app.py"),
+                line: 3,
+            })
+        );
+
+        let unfiltered = VenvSlimmer::new_with_roots(&[project], &venv, &tmp.path().join("all"))
+            .unwrap()
+            .slim()
+            .unwrap();
+        let kept: Vec<&str> = unfiltered.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(kept, vec!["pytest", "requests"]);
+    }
+
+    /// A src-layout project: `mypkg` imports one dependency, one relative module, its own
+    /// subpackage by absolute name, and `pandas` only for type checking
+    fn src_layout_project(root: &Path) -> PathBuf {
+        let project = root.join("project");
+        let package = project.join("src").join("mypkg");
+        fs::create_dir_all(package.join("models")).unwrap();
+        fs::write(package.join("__init__.py"), "").unwrap();
+        fs::write(package.join("utils.py"), "def helper():\n    pass\n").unwrap();
+        fs::write(
+            package.join("models").join("__init__.py"),
+            "class Model:\n    pass\n",
+        )
+        .unwrap();
+        fs::write(
+            package.join("core.py"),
+            "from typing import TYPE_CHECKING\n\nimport requests\n\nfrom .utils import helper\nfrom mypkg.models import Model\n\nif TYPE_CHECKING:\n    import pandas\n",
+        )
+        .unwrap();
+        fs::write(project.join("src").join("settings.py"), "import requests\n").unwrap();
+        project
+    }
+
+    #[test]
+    fn src_layout_first_party_and_type_checking_imports_keep_nothing() {
+        let tmp = tempdir().unwrap();
+        // A stale installed copy of the project itself must not be kept either
+        let venv = make_venv(tmp.path(), &["mypkg", "pandas", "requests"]);
+        let project = src_layout_project(tmp.path());
+
+        for root in [project.join("src"), project.clone()] {
+            let report = VenvSlimmer::new_with_roots(&[root], &venv, &tmp.path().join("slim"))
+                .unwrap()
+                .slim()
+                .unwrap();
+            let kept: Vec<&str> = report.kept.iter().map(|p| p.name.as_str()).collect();
+            assert_eq!(kept, vec!["requests"]);
+            assert_eq!(report.first_party, vec!["mypkg", "settings"]);
+            fs::remove_dir_all(tmp.path().join("slim")).unwrap();
+        }
+
+        let report = VenvSlimmer::new_with_roots(
+            &[project.join("src").join("mypkg")],
+            &venv,
+            &tmp.path().join("typed"),
+        )
+        .unwrap()
+        .with_options(SlimOptions {
+            keep_type_checking_imports: true,
+            ..SlimOptions::default()
+        })
+        .slim()
+        .unwrap();
+        let kept: Vec<&str> = report.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(kept, vec!["pandas", "requests"]);
+        assert!(report.first_party.contains(&"mypkg".to_string()));
+    }
+
+    #[test]
+    fn first_party_override_covers_namespace_packages() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["corp", "requests"]);
+        let code = tmp.path().join("code");
+        // `corp` is a namespace package: no `__init__.py` to discover it by
+        fs::create_dir_all(code.join("corp").join("billing")).unwrap();
+        fs::write(
+            code.join("corp").join("billing").join("invoice.py"),
+            "import requests\nfrom corp.billing import tax\n",
+        )
+        .unwrap();
+
+        let slim = |first_party: Vec<String>, output: &str| {
+            let report = VenvSlimmer::new_with_roots(
+                std::slice::from_ref(&code),
+                &venv,
+                &tmp.path().join(output),
+            )
+            .unwrap()
+            .with_options(SlimOptions {
+                first_party,
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap();
+            report
+                .kept
+                .iter()
+                .map(|p| p.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(slim(Vec::new(), "default"), vec!["corp", "requests"]);
+        assert_eq!(slim(vec!["corp".to_string()], "override"), vec!["requests"]);
+    }
+
+    #[test]
+    fn new_with_roots_requires_a_root() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &[]);
+        let output = tmp.path().join("slim");
+        assert!(VenvSlimmer::new_with_roots(&[], &venv, &output).is_err());
+    }
+
+    #[test]
+    fn unreadable_lib_directory_is_an_io_error() {
+        let tmp = tempdir().unwrap();
+        let venv = tmp.path().join(".venv");
+        fs::create_dir_all(&venv).unwrap();
+        fs::write(venv.join("lib"), "not a directory").unwrap();
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+
+        let slimmer =
+            VenvSlimmer::new_with_roots(&[code], &venv, &tmp.path().join("slim")).unwrap();
+        match slimmer.slim() {
+            Err(TsrsError::Io { path, .. }) => assert_eq!(path, venv.join("lib")),
+            other => panic!("expected IO error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn strip_sources_moves_cached_bytecode_and_honours_exceptions() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha", "certifi"]);
+        let site_packages = venv.join("lib").join("python3.11").join("site-packages");
+        for package in ["alpha", "certifi"] {
+            let dir = site_packages.join(package);
+            fs::write(dir.join("core.py"), "VALUE = 1\n".repeat(50)).unwrap();
+            fs::create_dir_all(dir.join("__pycache__")).unwrap();
+            fs::write(dir.join("__pycache__").join("core.cpython-311.pyc"), "pyc").unwrap();
+            fs::write(
+                dir.join("__pycache__").join("core.cpython-311.opt-1.pyc"),
+                "opt",
+            )
+            .unwrap();
+        }
+
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "import alpha\nimport certifi\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        let report = VenvSlimmer::new_with_roots(&[code], &venv, &output)
+            .unwrap()
+            .with_options(SlimOptions {
+                strip_sources: true,
+                strip_sources_except: vec!["certifi".to_string()],
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap();
+
+        let out_site = output.join("lib").join("python3.11").join("site-packages");
+        assert!(!out_site.join("alpha").join("core.py").exists());
+        assert_eq!(
+            fs::read_to_string(out_site.join("alpha").join("core.pyc")).unwrap(),
+            "pyc"
+        );
+        // No bytecode for __init__.py, so it stays
+        assert!(out_site.join("alpha").join("__init__.py").exists());
+        assert!(out_site.join("certifi").join("core.py").exists());
+
+        assert_eq!(report.steps.len(), 1);
+        assert_eq!(report.steps[0].step, "strip-sources");
+        assert!(report.steps[0].bytes_saved > 0);
+    }
+
+    fn slim_with_scripts(relocatable: bool) -> (tempfile::TempDir, PathBuf) {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha"]);
+        fs::create_dir_all(venv.join("bin")).unwrap();
+        fs::write(
+            venv.join("bin").join("tool"),
+            format!(
+                "#!{} -E\nimport sys\n",
+                venv.join("bin").join("python3.11").display()
+            ),
+        )
+        .unwrap();
+        fs::write(venv.join("bin").join("activate.sh"), "#!/bin/sh\necho hi\n").unwrap();
+        fs::write(
+            venv.join("pyvenv.cfg"),
+            format!(
+                "home = /usr/bin\ncommand = /usr/bin/python3 -m venv {}\n",
+                venv.display()
+            ),
+        )
+        .unwrap();
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "import alpha\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        VenvSlimmer::new_with_roots(&[code], &venv, &output)
+            .unwrap()
+            .with_options(SlimOptions {
+                relocatable,
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap();
+        (tmp, output)
+    }
+
+    #[test]
+    fn shebangs_and_pyvenv_cfg_point_at_the_slim_venv() {
+        let (_tmp, output) = slim_with_scripts(false);
+        let output = dunce::canonicalize(output).unwrap();
+
+        let tool = fs::read_to_string(output.join("bin").join("tool")).unwrap();
+        assert_eq!(
+            tool,
+            format!(
+                "#!{} -E\nimport sys\n",
+                output.join("bin").join("python3.11").display()
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("bin").join("activate.sh")).unwrap(),
+            "#!/bin/sh\necho hi\n"
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("pyvenv.cfg")).unwrap(),
+            format!(
+                "home = /usr/bin\ncommand = /usr/bin/python3 -m venv {}\n",
+                output.display()
+            )
+        );
+    }
+
+    #[test]
+    fn relocatable_shebangs_use_env_python3() {
+        let (_tmp, output) = slim_with_scripts(true);
+
+        assert_eq!(
+            fs::read_to_string(output.join("bin").join("tool")).unwrap(),
+            "#!/usr/bin/env python3 -E\nimport sys\n"
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("pyvenv.cfg")).unwrap(),
+            "home = /usr/bin\n"
+        );
+    }
+
+    #[test]
+    fn zipapp_bundles_kept_packages_code_and_entry_point() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha", "beta"]);
+        let code = tmp.path().join("code");
+        fs::create_dir_all(code.join("tests")).unwrap();
+        fs::create_dir_all(code.join(".cache")).unwrap();
+        fs::write(
+            code.join("app.py"),
+            "import alpha\n\ndef main():\n    pass\n",
+        )
+        .unwrap();
+        fs::write(code.join("tests").join("test_app.py"), "import beta\n").unwrap();
+        fs::write(code.join(".cache").join("state"), "x").unwrap();
+
+        let output = tmp.path().join("app.pyz");
+        let report = VenvSlimmer::new_with_roots(&[code], &venv, &output)
+            .unwrap()
+            .with_options(SlimOptions {
+                format: SlimFormat::Zipapp,
+                main: Some("app:main".to_string()),
+                code_exclude: vec!["tests/**".to_string()],
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap();
+
+        let kept: Vec<&str> = report.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(kept, vec!["alpha"]);
+        assert!(fs::read(&output)
+            .unwrap()
+            .starts_with(b"#!/usr/bin/env python3\n"));
+
+        let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let mut names: Vec<String> = archive.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, vec!["__main__.py", "alpha/__init__.py", "app.py"]);
+
+        let mut main = String::new();
+        std::io::Read::read_to_string(&mut archive.by_name("__main__.py").unwrap(), &mut main)
+            .unwrap();
+        assert!(main.ends_with("import app\napp.main()\n"));
+    }
+
+    #[test]
+    fn zipapp_requires_an_entry_point() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha"]);
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "").unwrap();
+
+        for main in [None, Some("app".to_string()), Some("app:1main".to_string())] {
+            let result = VenvSlimmer::new_with_roots(
+                std::slice::from_ref(&code),
+                &venv,
+                &tmp.path().join("app.pyz"),
+            )
+            .unwrap()
+            .with_options(SlimOptions {
+                format: SlimFormat::Zipapp,
+                main,
+                ..SlimOptions::default()
+            })
+            .slim();
+            assert!(matches!(result, Err(TsrsError::ConfigError(_))));
+        }
+    }
+
+    #[test]
+    fn editable_installs_keep_their_pth_files_and_are_flagged() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha"]);
+        let site_packages = venv.join("lib").join("python3.11").join("site-packages");
+
+        // Path-style .pth adding a src layout to sys.path
+        let mypkg_src = tmp.path().join("mypkg").join("src");
+        fs::create_dir_all(mypkg_src.join("mypkg")).unwrap();
+        fs::write(mypkg_src.join("mypkg").join("__init__.py"), "").unwrap();
+        fs::write(
+            site_packages.join("__editable__.mypkg-0.1.pth"),
+            format!("{}\n", mypkg_src.display()),
+        )
+        .unwrap();
+        let dist_info = site_packages.join("mypkg-0.1.dist-info");
+        fs::create_dir_all(&dist_info).unwrap();
+        fs::write(
+            dist_info.join("direct_url.json"),
+            format!(
+                r#"{{"url": "file://{}", "dir_info": {{"editable": true}}}}"#,
+                tmp.path().join("mypkg").display()
+            ),
+        )
+        .unwrap();
+
+        // setuptools finder-style .pth
+        let othpkg = tmp.path().join("other").join("othpkg");
+        fs::create_dir_all(&othpkg).unwrap();
+        fs::write(othpkg.join("__init__.py"), "").unwrap();
+        fs::write(
+            site_packages.join("__editable__.othpkg-2.0.pth"),
+            "import __editable___othpkg_2_0_finder; __editable___othpkg_2_0_finder.install()\n",
+        )
+        .unwrap();
+        fs::write(
+            site_packages.join("__editable___othpkg_2_0_finder.py"),
+            format!(
+                "import sys\nMAPPING: dict[str, str] = {{'othpkg': '{}'}}\n",
+                othpkg.display()
+            ),
+        )
+        .unwrap();
+
+        let info = VenvAnalyzer::new(&venv).unwrap().analyze().unwrap();
+        let mypkg = info.packages.iter().find(|p| p.name == "mypkg").unwrap();
+        assert!(mypkg.editable);
+        assert_eq!(mypkg.version.as_deref(), Some("0.1"));
+        assert_eq!(mypkg.editable_target, Some(mypkg_src.join("mypkg")));
+        let mypkg_dist = info
+            .packages
+            .iter()
+            .find(|p| p.name == "mypkg-0.1.dist-info")
+            .unwrap();
+        assert!(mypkg_dist.editable);
+        assert_eq!(mypkg_dist.editable_target, Some(tmp.path().join("mypkg")));
+
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "import mypkg\nimport othpkg\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        let report = VenvSlimmer::new_with_roots(std::slice::from_ref(&code), &venv, &output)
+            .unwrap()
+            .slim()
+            .unwrap();
+
+        let kept: Vec<&str> = report.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(kept, vec!["mypkg", "mypkg-0.1.dist-info", "othpkg"]);
+        assert!(report.kept.iter().all(|p| p.editable));
+        assert_eq!(report.kept[2].editable_target, Some(othpkg.clone()));
+
+        let out_site = output.join("lib").join("python3.11").join("site-packages");
+        assert!(out_site.join("__editable__.mypkg-0.1.pth").is_file());
+        assert!(out_site.join("__editable__.othpkg-2.0.pth").is_file());
+        assert!(out_site.join("__editable___othpkg_2_0_finder.py").is_file());
+        assert!(out_site.join("mypkg-0.1.dist-info").is_dir());
+        assert!(!out_site.join("alpha").exists());
+
+        // A zipapp cannot use the .pth files, so it bundles the source directories
+        fs::write(code.join("__main__.py"), "import app\n").unwrap();
+        let pyz = tmp.path().join("app.pyz");
+        VenvSlimmer::new_with_roots(&[code], &venv, &pyz)
+            .unwrap()
+            .with_options(SlimOptions {
+                format: SlimFormat::Zipapp,
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap();
+        let archive = zip::ZipArchive::new(File::open(&pyz).unwrap()).unwrap();
+        let names: BTreeSet<&str> = archive.file_names().collect();
+        assert!(names.contains("mypkg/__init__.py"));
+        assert!(names.contains("othpkg/__init__.py"));
+        assert!(!names.iter().any(|name| name.ends_with(".pth")));
+    }
+
+    #[test]
+    fn namespace_packages_keep_every_contributing_distribution() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &[]);
+        let site_packages = venv.join("lib").join("python3.11").join("site-packages");
+        for (package, dist, nspkg) in [
+            ("cloud/storage", "google_cloud_storage-2.10.0", true),
+            ("api_core", "google_api_core-2.15.0", false),
+        ] {
+            let dir = site_packages.join("google").join(package);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("__init__.py"), "").unwrap();
+
+            let dist_info = site_packages.join(format!("{dist}.dist-info"));
+            fs::create_dir_all(&dist_info).unwrap();
+            let mut record = format!("google/{package}/__init__.py,,\n{dist}.dist-info/RECORD,,\n");
+            if nspkg {
+                let pth = format!("{dist}-py3.11-nspkg.pth");
+                fs::write(site_packages.join(&pth), "import sys\n").unwrap();
+                record.push_str(&format!("{pth},,\n"));
+            }
+            fs::write(dist_info.join("RECORD"), record).unwrap();
+        }
+        fs::create_dir_all(site_packages.join("requests")).unwrap();
+        fs::write(site_packages.join("requests").join("__init__.py"), "").unwrap();
+
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "from google.cloud import storage\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        let report = VenvSlimmer::new_with_roots(&[code], &venv, &output)
+            .unwrap()
+            .slim()
+            .unwrap();
+
+        assert_eq!(report.kept.len(), 1);
+        assert_eq!(report.kept[0].name, "google");
+        assert_eq!(
+            report.kept[0].namespace_distributions,
+            vec!["google_api_core", "google_cloud_storage"]
+        );
+
+        let out_site = output.join("lib").join("python3.11").join("site-packages");
+        assert!(out_site
+            .join("google")
+            .join("cloud")
+            .join("storage")
+            .join("__init__.py")
+            .exists());
+        assert!(out_site.join("google").join("api_core").exists());
+        assert!(out_site
+            .join("google_cloud_storage-2.10.0.dist-info")
+            .is_dir());
+        assert!(out_site.join("google_api_core-2.15.0.dist-info").is_dir());
+        assert!(out_site
+            .join("google_cloud_storage-2.10.0-py3.11-nspkg.pth")
+            .is_file());
+        assert!(!out_site.join("requests").exists());
+    }
+
+    #[test]
+    fn dropped_distributions_lose_their_scripts_and_record_rows() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha", "beta"]);
+        let site_packages = venv.join("lib").join("python3.11").join("site-packages");
+        let bin = venv.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(bin.join("alpha-cli"), "#!/bin/sh\nexec alpha\n").unwrap();
+        fs::write(bin.join("beta-cli"), "#!/bin/sh\nexec beta\n").unwrap();
+        fs::create_dir_all(site_packages.join("alpha").join("_vendor")).unwrap();
+
+        let alpha_info = site_packages.join("alpha-1.0.dist-info");
+        fs::create_dir_all(&alpha_info).unwrap();
+        fs::write(
+            alpha_info.join("METADATA"),
+            "Metadata-Version: 2.1\nName: alpha\nVersion: 1.0\nRequires-Dist: beta>=2\n",
+        )
+        .unwrap();
+        fs::write(
+            alpha_info.join("RECORD"),
+            "alpha/__init__.py,sha256=stale,9\n\
+             beta/__init__.py,sha256=stale,0\n\
+             ../../../bin/alpha-cli,sha256=stale,1\n\
+             alpha-1.0.dist-info/RECORD,,\n",
+        )
+        .unwrap();
+        let beta_info = site_packages.join("beta-2.0.dist-info");
+        fs::create_dir_all(&beta_info).unwrap();
+        fs::write(
+            beta_info.join("entry_points.txt"),
+            "[console_scripts]\nbeta-cli = beta.cli:main\n",
+        )
+        .unwrap();
+        fs::write(
+            beta_info.join("RECORD"),
+            "beta/__init__.py,,\n../../../bin/beta-cli,,\nbeta-2.0.dist-info/RECORD,,\n",
+        )
+        .unwrap();
+
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "import alpha\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        let report = VenvSlimmer::new_with_roots(&[code], &venv, &output)
+            .unwrap()
+            .slim()
+            .unwrap();
+
+        assert!(output.join("bin").join("alpha-cli").is_file());
+        assert!(!output.join("bin").join("beta-cli").exists());
+
+        let out_site = output.join("lib").join("python3.11").join("site-packages");
+        assert!(!out_site.join("beta-2.0.dist-info").exists());
+        assert!(!out_site.join("alpha").join("_vendor").exists());
+        let record =
+            fs::read_to_string(out_site.join("alpha-1.0.dist-info").join("RECORD")).unwrap();
+        assert_eq!(
+            record,
+            format!(
+                "alpha/__init__.py,{},0\n../../../bin/alpha-cli,{},{}\nalpha-1.0.dist-info/RECORD,,\n",
+                record_hash(b""),
+                record_hash(b"#!/bin/sh\nexec alpha\n"),
+                "#!/bin/sh\nexec alpha\n".len()
+            )
+        );
+
+        assert_eq!(
+            report.warnings,
+            vec!["alpha-1.0.dist-info requires beta, which is not in the slim venv"]
+        );
+    }
+
+    #[test]
+    fn compile_bytecode_without_interpreter_is_skipped() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha"]);
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "import alpha\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        let report = VenvSlimmer::new_with_roots(&[code], &venv, &output)
+            .unwrap()
+            .with_options(SlimOptions {
+                compile_bytecode: true,
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap();
+
+        assert_eq!(report.steps.len(), 1);
+        assert_eq!(report.steps[0].bytes_saved, 0);
+        assert_eq!(
+            report.steps[0].note.as_deref(),
+            Some("no interpreter found")
+        );
+    }
+
+    #[test]
+    fn verify_imports_reports_a_package_missing_its_dependency() {
+        let tmp = tempdir().unwrap();
+        let venv = tmp.path().join(".venv");
+        let created = Command::new("python3")
+            .args(["-m", "venv", "--without-pip"])
+            .arg(&venv)
+            .status();
+        if !created.is_ok_and(|status| status.success()) {
+            eprintln!("skipping: python3 -m venv not available");
+            return;
+        }
+        let site_packages = fs::read_dir(venv.join("lib"))
+            .unwrap()
+            .filter_map(std::result::Result::ok)
+            .map(|entry| entry.path().join("site-packages"))
+            .find(|path| path.is_dir())
+            .unwrap();
+        // Nothing imports `helper` directly, so the slim venv leaves out what `broken` needs
+        for (package, source) in [("good", ""), ("broken", "import helper\n"), ("helper", "")] {
+            let dir = site_packages.join(package);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("__init__.py"), source).unwrap();
+        }
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(
+            code.join("app.py"),
+            "import os\nimport good\nimport broken\n",
+        )
+        .unwrap();
+
+        let verify = |verify_skip: Vec<String>, output: &str| {
+            VenvSlimmer::new_with_roots(
+                std::slice::from_ref(&code),
+                &venv,
+                &tmp.path().join(output),
+            )
+            .unwrap()
+            .with_options(SlimOptions {
+                verify_imports: true,
+                verify_skip,
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap()
+            .verification
+            .unwrap()
+        };
+
+        let verification = verify(Vec::new(), "slim");
+        assert_eq!(verification.passed, vec!["good"]);
+        assert_eq!(verification.failed.len(), 1);
+        assert_eq!(verification.failed[0].module, "broken");
+        assert!(
+            verification.failed[0]
+                .error
+                .contains("No module named 'helper'"),
+            "{}",
+            verification.failed[0].error
+        );
+
+        let verification = verify(vec!["broken".to_string()], "slim-skip");
+        assert_eq!(verification.passed, vec!["good"]);
+        assert!(verification.failed.is_empty());
+        assert_eq!(verification.skipped, vec!["broken"]);
+    }
+
+    #[test]
+    fn parallel_import_scan_matches_sequential_scan() {
+        let tmp = tempdir().unwrap();
+        let packages = ["alpha", "beta", "gamma", "delta", "unused"];
+        let venv = make_venv(tmp.path(), &packages);
+        let code = tmp.path().join("code");
+        for index in 0..300 {
+            let dir = code.join(format!("mod_{}", index % 7));
+            fs::create_dir_all(&dir).unwrap();
+            let package = packages[index % 4];
+            fs::write(
+                dir.join(format!("file_{index}.py")),
+                format!("import os\n\nfrom {package}.sub import thing{index}\n"),
+            )
+            .unwrap();
+        }
+        fs::write(code.join("mod_3").join("broken.py"), "def broken(:\n").unwrap();
+
+        let slim_with = |output: &str, jobs: usize| {
+            VenvSlimmer::new_with_roots(
+                std::slice::from_ref(&code),
+                &venv,
+                &tmp.path().join(output),
+            )
+            .unwrap()
+            .with_options(SlimOptions {
+                jobs,
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap()
+        };
+        let sequential = slim_with("slim-1", 1);
+        let parallel = slim_with("slim-4", 4);
+
+        let kept: Vec<&str> = parallel.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(kept, vec!["alpha", "beta", "delta", "gamma"]);
+        assert_eq!(parallel.kept, sequential.kept);
+        assert_eq!(parallel.decisions, sequential.decisions);
+        assert_eq!(parallel.scan_errors, sequential.scan_errors);
+        assert_eq!(parallel.scan_errors.len(), 1);
+        assert!(parallel.scan_errors[0].contains("broken.py"));
+        // The first import in path order wins, however the files were scheduled
+        let alpha = parallel.kept[0].imported_at.as_ref().unwrap();
+        assert_eq!(alpha.file, code.join("mod_0").join("file_0.py"));
+        assert_eq!(alpha.line, 3);
+    }
+
+    #[test]
+    fn stdlib_imports_follow_the_venv_python_version() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(tmp.path(), &["alpha", "tomllib"]);
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(
+            code.join("app.py"),
+            "import os.path\nimport tomllib\nimport alpha\n",
+        )
+        .unwrap();
+
+        let slim_with = |output: &str, python_version: Option<PythonVersion>| {
+            VenvSlimmer::new_with_roots(
+                std::slice::from_ref(&code),
+                &venv,
+                &tmp.path().join(output),
+            )
+            .unwrap()
+            .with_options(SlimOptions {
+                python_version,
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap()
+        };
+        let kept = |report: &SlimReport| -> Vec<String> {
+            report.kept.iter().map(|p| p.name.clone()).collect()
+        };
+
+        // The venv is python3.11, where tomllib is stdlib and shadows the package
+        let report = slim_with("slim-311", None);
+        assert_eq!(report.stdlib, vec!["os", "tomllib"]);
+        assert_eq!(kept(&report), vec!["alpha"]);
+
+        let report = slim_with("slim-310", Some(PythonVersion::new(3, 10)));
+        assert_eq!(report.stdlib, vec!["os"]);
+        assert_eq!(kept(&report), vec!["alpha", "tomllib"]);
+    }
+
+    #[test]
+    fn policy_keeps_and_drops_packages_and_reports_decisions() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(
+            tmp.path(),
+            &[
+                "alpha",
+                "beta",
+                "pip",
+                "pkg_resources",
+                "setuptools",
+                "wheel",
+            ],
+        );
+        let dist_info = venv
+            .join("lib")
+            .join("python3.11")
+            .join("site-packages")
+            .join("alpha-1.0.dist-info");
+        fs::create_dir_all(&dist_info).unwrap();
+        fs::write(
+            dist_info.join("METADATA"),
+            "Name: alpha\nRequires-Dist: pip>=23\nRequires-Dist: beta; extra == \"docs\"\n\nBody\n",
+        )
+        .unwrap();
+
+        let project = tmp.path().join("project");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(project.join("app.py"), "import alpha\nimport wheel\n").unwrap();
+
+        let output = tmp.path().join("slim");
+        let report = VenvSlimmer::new_with_roots(&[project], &venv, &output)
+            .unwrap()
+            .with_options(SlimOptions {
+                policy: PackagePolicy {
+                    keep: vec!["SETUPTOOLS".to_string(), "pkg-resources".to_string()],
+                    drop: vec!["wh*".to_string(), "pip".to_string()],
+                },
+                ..SlimOptions::default()
+            })
+            .slim()
+            .unwrap();
+
+        let kept: Vec<&str> = report.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            kept,
+            vec![
+                "alpha",
+                "alpha-1.0.dist-info",
+                "pkg_resources",
+                "setuptools"
+            ]
+        );
+        let site_packages = output.join("lib").join("python3.11").join("site-packages");
+        assert!(site_packages.join("setuptools").exists());
+        assert!(!site_packages.join("wheel").exists());
+
+        let decisions: Vec<(&str, SlimDecision, Option<&str>)> = report
+            .decisions
+            .iter()
+            .map(|d| (d.name.as_str(), d.decision, d.rule.as_deref()))
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                ("alpha", SlimDecision::KeptByImport, None),
+                ("alpha-1.0.dist-info", SlimDecision::KeptByImport, None),
+                ("beta", SlimDecision::DroppedUnused, None),
+                ("pip", SlimDecision::DroppedByPolicy, Some("pip")),
+                (
+                    "pkg_resources",
+                    SlimDecision::KeptByPolicy,
+                    Some("pkg-resources")
+                ),
+                ("setuptools", SlimDecision::KeptByPolicy, Some("SETUPTOOLS")),
+                ("wheel", SlimDecision::DroppedByPolicy, Some("wh*")),
+            ]
+        );
+        // beta is only an optional extra of alpha, pip a real requirement
+        assert_eq!(report.decisions[2].required_by, Vec::<String>::new());
+        assert_eq!(report.decisions[3].required_by, vec!["alpha".to_string()]);
+
+        let json = serde_json::to_value(&report.decisions[3]).unwrap();
+        assert_eq!(json["decision"], "dropped-by-policy");
+    }
+
+    #[test]
+    fn imports_keep_distributions_with_other_names_and_unknown_imports_are_reported() {
+        let tmp = tempdir().unwrap();
+        let venv = make_venv(
+            tmp.path(),
+            &["PIL", "pkg_resources", "requests", "setuptools", "yaml"],
+        );
+        let site_packages = venv.join("lib").join("python3.11").join("site-packages");
+        for (dist, top_level, record) in [
+            (
+                "Pillow-10.0.0",
+                None,
+                "PIL/__init__.py,,\nPillow.libs/libz.so,,\nPillow-10.0.0.dist-info/RECORD,,\n",
+            ),
+            (
+                "setuptools-69.0.0",
+                Some("_distutils_hack\npkg_resources\nsetuptools\n"),
+                "setuptools/__init__.py,,\npkg_resources/__init__.py,,\n\
+                 distutils-precedence.pth,,\nsetuptools-69.0.0.dist-info/RECORD,,\n",
+            ),
+            ("PyYAML-6.0", Some("_yaml\nyaml\n"), ""),
+        ] {
+            let dist_info = site_packages.join(format!("{dist}.dist-info"));
+            fs::create_dir_all(&dist_info).unwrap();
+            if let Some(top_level) = top_level {
+                fs::write(dist_info.join("top_level.txt"), top_level).unwrap();
+            }
+            if !record.is_empty() {
+                fs::write(dist_info.join("RECORD"), record).unwrap();
+            }
+        }
+
+        let analyzer = VenvAnalyzer::new(&venv).unwrap();
+        assert_eq!(
+            analyzer.distribution_for_import("PIL.Image").unwrap(),
+            Some("Pillow".to_string())
+        );
+        assert_eq!(
+            analyzer.distribution_for_import("pkg_resources").unwrap(),
+            Some("setuptools".to_string())
+        );
+        assert_eq!(analyzer.distribution_for_import("requests").unwrap(), None);
+        let info = analyzer.analyze().unwrap();
+        assert_eq!(
+            info.import_map.get("yaml").map(String::as_str),
+            Some("PyYAML")
+        );
+        assert!(!info.import_map.contains_key("Pillow"));
+
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(
+            code.join("app.py"),
+            "from PIL import Image\nimport pkg_resources\nimport yaml\nimport missing_mod\n",
+        )
+        .unwrap();
+
+        let output = tmp.path().join("slim");
+        let report = VenvSlimmer::new_with_roots(std::slice::from_ref(&code), &venv, &output)
+            .unwrap()
+            .slim()
+            .unwrap();
+
+        let kept: Vec<&str> = report.kept.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            kept,
+            vec![
+                "PIL",
+                "Pillow-10.0.0.dist-info",
+                "PyYAML-6.0.dist-info",
+                "pkg_resources",
+                "setuptools",
+                "setuptools-69.0.0.dist-info",
+                "yaml",
+            ]
+        );
+        assert_eq!(report.kept[1].roots, vec![code]);
+        assert_eq!(
+            report.kept[1].imported_at.as_ref().map(|site| site.line),
+            Some(1)
+        );
+        assert_eq!(report.unresolved, vec!["missing_mod".to_string()]);
+
+        let out_site = output.join("lib").join("python3.11").join("site-packages");
+        assert!(out_site
+            .join("Pillow-10.0.0.dist-info")
+            .join("RECORD")
+            .is_file());
+        assert!(out_site.join("setuptools").join("__init__.py").is_file());
+        assert!(!out_site.join("requests").exists());
+    }
+
+    #[test]
+    fn policy_file_and_patterns_are_normalized() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("policy.toml");
+        fs::write(&path, "keep = [\"Typing-Ext*\"]\ndrop = [\"[a-c]-docs\"]\n").unwrap();
+        let policy = PackagePolicy::from_file(&path).unwrap();
+        assert_eq!(policy.keep, vec!["Typing-Ext*".to_string()]);
+
+        let keep = PolicyPatterns::new(&policy.keep).unwrap();
+        assert_eq!(
+            keep.matching(&normalize_package_name("typing_extensions")),
+            Some("Typing-Ext*")
+        );
+        let drop = PolicyPatterns::new(&policy.drop).unwrap();
+        assert_eq!(drop.matching("b_docs"), Some("[a-c]-docs"));
+        assert_eq!(drop.matching("d_docs"), None);
+
+        fs::write(&path, "keep = []\nexclude = [\"pip\"]\n").unwrap();
+        assert!(matches!(
+            PackagePolicy::from_file(&path),
+            Err(TsrsError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn conda_env_is_slimmed_with_its_interpreter_and_metadata() {
+        let tmp = tempdir().unwrap();
+        let env = tmp.path().join("env");
+        let lib = env.join("lib").join("python3.12");
+        for package in ["alpha", "beta"] {
+            let dir = lib.join("site-packages").join(package);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("__init__.py"), "").unwrap();
+        }
+        fs::write(lib.join("os.py"), "").unwrap();
+        fs::create_dir_all(env.join("bin")).unwrap();
+        fs::write(env.join("bin").join("python3.12"), "").unwrap();
+        let conda_meta = env.join("conda-meta");
+        fs::create_dir_all(&conda_meta).unwrap();
+        fs::write(
+            conda_meta.join("python-dateutil-2.9.0-pyhd8ed1ab_0.json"),
+            "{}",
+        )
+        .unwrap();
+        fs::write(conda_meta.join("python-3.12.1-hab00c5b_0.json"), "{}").unwrap();
+
+        let info = VenvAnalyzer::new(&env).unwrap().analyze().unwrap();
+        assert_eq!(info.layout, EnvironmentKind::Conda);
+        assert_eq!(info.python_version.as_deref(), Some("python3.12"));
+        assert_eq!(info.site_packages, vec![lib.join("site-packages")]);
+
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "import alpha\n").unwrap();
+        let output = tmp.path().join("slim");
+        VenvSlimmer::new_with_roots(&[code], &env, &output)
+            .unwrap()
+            .slim()
+            .unwrap();
+
+        let out_lib = output.join("lib").join("python3.12");
+        assert!(out_lib.join("site-packages").join("alpha").is_dir());
+        assert!(!out_lib.join("site-packages").join("beta").exists());
+        assert!(out_lib.join("os.py").is_file());
+        assert!(output.join("bin").join("python3.12").is_file());
+        assert!(output
+            .join("conda-meta")
+            .join("python-3.12.1-hab00c5b_0.json")
+            .is_file());
+    }
+
+    #[test]
+    fn bare_site_packages_is_slimmed_into_a_site_packages_directory() {
+        let tmp = tempdir().unwrap();
+        let dist_packages = tmp
+            .path()
+            .join("layer")
+            .join("usr")
+            .join("lib")
+            .join("python3")
+            .join("dist-packages");
+        for package in ["alpha", "beta"] {
+            let dir = dist_packages.join(package);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("__init__.py"), "").unwrap();
+            let dist_info = dist_packages.join(format!("{package}-1.0.dist-info"));
+            fs::create_dir_all(&dist_info).unwrap();
+            fs::write(
+                dist_info.join("RECORD"),
+                format!("{package}/__init__.py,,\n{package}-1.0.dist-info/RECORD,,\n"),
+            )
+            .unwrap();
+        }
+
+        let info = VenvAnalyzer::new(&dist_packages)
+            .unwrap()
+            .analyze()
+            .unwrap();
+        assert_eq!(info.layout, EnvironmentKind::SitePackages);
+        // `python3` names no minor version
+        assert_eq!(info.python_version, None);
+        assert_eq!(info.site_packages, vec![dist_packages.clone()]);
+
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "import alpha\n").unwrap();
+        let output = tmp.path().join("slim-site-packages");
+        VenvSlimmer::new_with_roots(&[code], &dist_packages, &output)
+            .unwrap()
+            .slim()
+            .unwrap();
+
+        assert!(output.join("alpha").join("__init__.py").is_file());
+        assert!(output.join("alpha-1.0.dist-info").is_dir());
+        assert!(!output.join("beta").exists());
+        assert!(!output.join("lib").exists());
+    }
+
+    #[test]
+    fn windows_venv_version_comes_from_pyvenv_cfg() {
+        let tmp = tempdir().unwrap();
+        let venv = tmp.path().join(".venv");
+        let site_packages = venv.join("Lib").join("site-packages");
+        fs::create_dir_all(site_packages.join("alpha")).unwrap();
+        fs::write(site_packages.join("alpha").join("__init__.py"), "").unwrap();
+        fs::write(
+            venv.join("pyvenv.cfg"),
+            "home = C:\\Python310\nversion = 3.10.4\n",
+        )
+        .unwrap();
+
+        let info = VenvAnalyzer::new(&venv).unwrap().analyze().unwrap();
+        assert_eq!(info.layout, EnvironmentKind::Venv);
+        assert_eq!(info.python_version.as_deref(), Some("python3.10"));
+        assert_eq!(info.site_packages, vec![site_packages]);
+        assert_eq!(info.packages.len(), 1);
+    }
+
+    /// `modern` uses PEP 639, `classic` a short `License` with classifiers, `legacy` the
+    /// whole license text with a classifier, and `mystery` only `UNKNOWN`
+    fn licensed_venv(root: &Path) -> PathBuf {
+        let venv = make_venv(root, &["classic", "legacy", "modern", "mystery"]);
+        let site_packages = venv.join("lib").join("python3.11").join("site-packages");
+        for (dist, metadata) in [
+            (
+                "modern-1.0",
+                "Metadata-Version: 2.4\nName: modern\nVersion: 1.0\n\
+                 License-Expression: GPL-3.0-or-later\nAuthor-email: Dev <dev@example.org>\n\n\
+                 License: ignored description text\n",
+            ),
+            (
+                "classic-2.1",
+                "Metadata-Version: 2.1\nName: classic\nVersion: 2.1\nLicense: MIT\n\
+                 Author: Jane Doe\nHome-page: https://example.org/classic\n\
+                 Project-URL: Source, https://example.org/classic/src\n\
+                 Classifier: Programming Language :: Python\n\
+                 Classifier: License :: OSI Approved :: MIT License\n",
+            ),
+            (
+                "legacy-0.9",
+                "Metadata-Version: 1.1\nName: legacy\nVersion: 0.9\n\
+                 License: Copyright (c) 2010, Legacy Authors\n        \n\
+                 \x20       Redistribution and use in source and binary forms are permitted.\n\
+                 Classifier: License :: OSI Approved :: BSD License\n",
+            ),
+            (
+                "mystery-0.1",
+                "Metadata-Version: 2.1\nName: mystery\nVersion: 0.1\nLicense: UNKNOWN\n\
+                 Author: UNKNOWN\n",
+            ),
+        ] {
+            let dist_info = site_packages.join(format!("{dist}.dist-info"));
+            fs::create_dir_all(&dist_info).unwrap();
+            fs::write(dist_info.join("METADATA"), metadata).unwrap();
+            let name = dist.split('-').next().unwrap();
+            fs::write(dist_info.join("top_level.txt"), format!("{name}\n")).unwrap();
+        }
+        venv
+    }
+
+    #[test]
+    fn dist_info_metadata_feeds_the_license_summary() {
+        let tmp = tempdir().unwrap();
+        let venv = licensed_venv(tmp.path());
+
+        let info = VenvAnalyzer::new(&venv).unwrap().analyze().unwrap();
+        let metadata = |name: &str| {
+            info.packages
+                .iter()
+                .find(|package| package.name == name)
+                .and_then(|package| package.metadata.clone())
+                .unwrap()
+        };
+        let classic = metadata("classic-2.1.dist-info");
+        assert_eq!(classic.license.as_deref(), Some("MIT"));
+        assert_eq!(
+            classic.license_classifiers,
+            vec!["OSI Approved :: MIT License".to_string()]
+        );
+        assert_eq!(classic.author.as_deref(), Some("Jane Doe"));
+        assert_eq!(
+            classic.home_page.as_deref(),
+            Some("https://example.org/classic")
+        );
+        assert_eq!(
+            classic.project_urls,
+            vec!["Source, https://example.org/classic/src".to_string()]
+        );
+        let modern = metadata("modern-1.0.dist-info");
+        assert_eq!(modern.license, None);
+        assert_eq!(modern.author.as_deref(), Some("Dev <dev@example.org>"));
+        let legacy = metadata("legacy-0.9.dist-info");
+        assert_eq!(
+            legacy.license.as_deref(),
+            Some("Copyright (c) 2010, Legacy Authors")
+        );
+        assert_eq!(legacy.license_name().as_deref(), Some("BSD License"));
+        let mystery = metadata("mystery-0.1.dist-info");
+        assert_eq!((mystery.license, mystery.author), (None, None));
+        assert!(info
+            .packages
+            .iter()
+            .all(|package| package.name.ends_with(".dist-info") || package.metadata.is_none()));
+
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(
+            code.join("app.py"),
+            "import classic\nimport legacy\nimport modern\nimport mystery\n",
+        )
+        .unwrap();
+        let report = VenvSlimmer::new_with_roots(&[code], &venv, &tmp.path().join("slim"))
+            .unwrap()
+            .slim()
+            .unwrap();
+        let counts: Vec<(&str, usize)> = report
+            .licenses
+            .counts
+            .iter()
+            .map(|(license, count)| (license.as_str(), *count))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("BSD License", 1),
+                ("GPL-3.0-or-later", 1),
+                ("MIT License", 1)
+            ]
+        );
+        assert_eq!(report.licenses.unknown, vec!["mystery-0.1.dist-info"]);
+        let kept = report
+            .kept
+            .iter()
+            .find(|package| package.name == "modern-1.0.dist-info")
+            .unwrap();
+        assert_eq!(kept.license.as_deref(), Some("GPL-3.0-or-later"));
+    }
+
+    #[test]
+    fn fail_on_license_stops_before_writing_and_names_the_import() {
+        let tmp = tempdir().unwrap();
+        let venv = licensed_venv(tmp.path());
+        let code = tmp.path().join("code");
+        fs::create_dir_all(&code).unwrap();
+        fs::write(code.join("app.py"), "import classic\nimport modern\n").unwrap();
+        let output = tmp.path().join("slim");
+
+        let slim = |fail_on_license: &[&str]| {
+            VenvSlimmer::new_with_roots(std::slice::from_ref(&code), &venv, &output)
+                .unwrap()
+                .with_options(SlimOptions {
+                    fail_on_license: fail_on_license.iter().map(ToString::to_string).collect(),
+                    ..SlimOptions::default()
+                })
+                .slim()
+        };
+
+        match slim(&["LGPL", "gpl-3.0"]) {
+            Err(TsrsError::LicenseDenied { packages }) => assert_eq!(
+                packages,
+                vec![format!(
+                    "modern-1.0.dist-info (GPL-3.0-or-later, matches `gpl-3.0`): imported as \
+                     `modern` at {}:2",
+                    code.join("app.py").display()
+                )]
+            ),
+            other => panic!("expected LicenseDenied, got {other:?}"),
+        }
+        assert!(!output.exists());
+
+        let report = slim(&["LGPL", "Apache"]).unwrap();
+        assert_eq!(report.licenses.counts.len(), 2);
+        assert!(output.exists());
+    }
+}
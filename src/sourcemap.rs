@@ -0,0 +1,433 @@
+//! Source maps linking minified names back to their originals.
+
+use crate::error::{Result, TsrsError};
+use crate::minify::MinifyPlan;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Current source map format version.
+pub const SOURCE_MAP_VERSION: u32 = 1;
+
+/// JSON Schema describing the source map format.
+pub const SOURCE_MAP_SCHEMA: &str = r##"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "$id": "https://github.com/GeorgePearse/tsrs/schemas/source-map-v1.json",
+  "title": "tsrs source map",
+  "description": "Maps renamed locals in minified Python files back to their original names. Only renames that were applied to the written output are recorded.",
+  "type": "object",
+  "required": ["version", "files"],
+  "properties": {
+    "version": {
+      "description": "Format version; currently 1.",
+      "type": "integer",
+      "const": 1
+    },
+    "files": {
+      "type": "array",
+      "items": {
+        "type": "object",
+        "required": ["path", "content_hash", "functions"],
+        "properties": {
+          "path": {
+            "description": "Path of the original source file, relative to the input directory, using '/' separators.",
+            "type": "string"
+          },
+          "content_hash": {
+            "description": "Hash of the minified output in the form 'fnv1a64:<16 hex digits>', computed over the UTF-8 text.",
+            "type": "string",
+            "pattern": "^fnv1a64:[0-9a-f]{16}$"
+          },
+          "functions": {
+            "description": "Qualified function name (e.g. 'Class.method') to a map of renamed identifier -> original identifier.",
+            "type": "object",
+            "additionalProperties": {
+              "type": "object",
+              "additionalProperties": { "type": "string" }
+            }
+          }
+        }
+      }
+    }
+  }
+}
+"##;
+
+/// Collection of per-file rename maps.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SourceMap {
+    pub version: u32,
+    pub files: Vec<FileSourceMap>,
+}
+
+/// Renames applied to a single file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileSourceMap {
+    /// Original path of the source file (`/` separated).
+    pub path: String,
+    /// Hash of the minified output, see [`content_hash`].
+    pub content_hash: String,
+    /// Qualified function name → (renamed → original).
+    pub functions: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl FileSourceMap {
+    /// Build a map from the renames a plan applies to `rewritten`.
+    ///
+    /// Functions the rewriter leaves untouched (no range, comprehensions, match
+    /// statements) are omitted so the map only covers applied renames.
+    #[must_use]
+    pub fn from_plan(path: &str, plan: &MinifyPlan, rewritten: &str) -> Self {
+        let mut functions = BTreeMap::new();
+        for function in &plan.functions {
+            if function.renames.is_empty()
+                || function.range.is_none()
+                || function.has_comprehension
+                || function.has_match_statement
+            {
+                continue;
+            }
+            let renames: BTreeMap<String, String> = function
+                .renames
+                .iter()
+                .map(|entry| (entry.renamed.clone(), entry.original.clone()))
+                .collect();
+            functions.insert(function.qualified_name.clone(), renames);
+        }
+
+        Self {
+            path: path.replace('\\', "/"),
+            content_hash: content_hash(rewritten),
+            functions,
+        }
+    }
+
+    /// Like [`FileSourceMap::from_plan`], but limited to the functions a rewrite reports
+    /// in [`RewriteOutcome::applied`](crate::minify::RewriteOutcome::applied), so functions
+    /// whose renames bailed out are left out.
+    #[must_use]
+    pub fn from_applied(
+        path: &str,
+        plan: &MinifyPlan,
+        applied: &[String],
+        rewritten: &str,
+    ) -> Self {
+        let mut map = Self::from_plan(path, plan, rewritten);
+        map.functions
+            .retain(|qualified, _| applied.contains(qualified));
+        map
+    }
+
+    fn matches_file(&self, frame_file: &str) -> bool {
+        let frame_file = frame_file.replace('\\', "/");
+        let path = self.path.trim_start_matches("./");
+        frame_file == path || frame_file.ends_with(&format!("/{path}"))
+    }
+
+    /// Rename map for a traceback frame's function name.
+    ///
+    /// Tracebacks only show the bare function name, so every qualified name ending in
+    /// it contributes; renamed names that map to different originals are dropped.
+    fn renames_for_function(&self, function: &str) -> BTreeMap<String, String> {
+        let suffix = format!(".{function}");
+        let mut merged: BTreeMap<String, String> = BTreeMap::new();
+        let mut conflicting = Vec::new();
+        for (qualified, renames) in &self.functions {
+            if qualified != function && !qualified.ends_with(&suffix) {
+                continue;
+            }
+            for (renamed, original) in renames {
+                match merged.get(renamed) {
+                    Some(existing) if existing != original => conflicting.push(renamed.clone()),
+                    _ => {
+                        merged.insert(renamed.clone(), original.clone());
+                    }
+                }
+            }
+        }
+        for name in conflicting {
+            merged.remove(&name);
+        }
+        merged
+    }
+}
+
+impl SourceMap {
+    /// Create a source map for the given files.
+    #[must_use]
+    pub fn new(files: Vec<FileSourceMap>) -> Self {
+        Self {
+            version: SOURCE_MAP_VERSION,
+            files,
+        }
+    }
+
+    /// Parse a source map from JSON.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the JSON is malformed or the version is unsupported.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let map: Self = serde_json::from_str(json)?;
+        if map.version > SOURCE_MAP_VERSION {
+            return Err(TsrsError::InvalidMetadata(format!(
+                "unsupported source map version {} (max supported {SOURCE_MAP_VERSION})",
+                map.version
+            )));
+        }
+        Ok(map)
+    }
+
+    /// Serialize the source map as pretty-printed JSON.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if serialization fails.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Append the files of another map.
+    pub fn extend(&mut self, other: SourceMap) {
+        self.files.extend(other.files);
+    }
+
+    /// Rewrite minified names in a Python traceback back to their originals.
+    ///
+    /// Frames are matched by file path suffix and function name; the source line shown
+    /// under a matched frame is rewritten, as are quoted names in the final exception
+    /// message (using the innermost matched frame).
+    #[must_use]
+    pub fn unmangle_traceback(&self, traceback: &str) -> String {
+        let mut output = String::with_capacity(traceback.len());
+        let mut frame_renames: Option<BTreeMap<String, String>> = None;
+        let mut last_renames: Option<BTreeMap<String, String>> = None;
+
+        for line in traceback.split_inclusive('\n') {
+            let (body, newline) = match line.strip_suffix('\n') {
+                Some(body) => (body, "\n"),
+                None => (line, ""),
+            };
+
+            if let Some(captures) = frame_regex().captures(body) {
+                let file = &captures["file"];
+                let function = &captures["function"];
+                frame_renames = self
+                    .files
+                    .iter()
+                    .find(|entry| entry.matches_file(file))
+                    .map(|entry| entry.renames_for_function(function))
+                    .filter(|renames| !renames.is_empty());
+                last_renames.clone_from(&frame_renames);
+                output.push_str(body);
+            } else if body.starts_with(char::is_whitespace) {
+                match &frame_renames {
+                    Some(renames) => output.push_str(&replace_code_identifiers(body, renames)),
+                    None => output.push_str(body),
+                }
+            } else {
+                frame_renames = None;
+                match &last_renames {
+                    Some(renames) if !body.starts_with("Traceback ") => {
+                        output.push_str(&replace_quoted_identifiers(body, renames));
+                    }
+                    _ => output.push_str(body),
+                }
+            }
+            output.push_str(newline);
+        }
+
+        output
+    }
+}
+
+/// Stable FNV-1a 64-bit hash of `text`, formatted as `fnv1a64:<hex>`.
+#[must_use]
+pub fn content_hash(text: &str) -> String {
+    format!("fnv1a64:{:016x}", crate::minify::fnv1a64(text.as_bytes()))
+}
+
+fn frame_regex() -> &'static Regex {
+    static FRAME: OnceLock<Regex> = OnceLock::new();
+    FRAME.get_or_init(|| {
+        Regex::new(r#"^\s*File "(?P<file>[^"]+)", line \d+, in (?P<function>\S+)"#)
+            .expect("valid traceback frame regex")
+    })
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || c.is_alphabetic()
+}
+
+fn is_identifier_continue(c: char) -> bool {
+    c == '_' || c.is_alphanumeric()
+}
+
+/// Replace identifiers in a source line, leaving string literals alone.
+fn replace_code_identifiers(line: &str, renames: &BTreeMap<String, String>) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut chars = line.char_indices().peekable();
+    let mut quote: Option<char> = None;
+
+    while let Some((start, c)) = chars.next() {
+        if let Some(q) = quote {
+            result.push(c);
+            if c == '\\' {
+                if let Some((_, escaped)) = chars.next() {
+                    result.push(escaped);
+                }
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        if c == '\'' || c == '"' {
+            quote = Some(c);
+            result.push(c);
+        } else if c == '#' {
+            result.push_str(&line[start..]);
+            break;
+        } else if is_identifier_start(c) {
+            let mut end = start + c.len_utf8();
+            while let Some(&(idx, next)) = chars.peek() {
+                if !is_identifier_continue(next) {
+                    break;
+                }
+                end = idx + next.len_utf8();
+                chars.next();
+            }
+            let word = &line[start..end];
+            // Attribute names (`obj.attr`) are never locals.
+            let is_attribute = line[..start].trim_end().ends_with('.');
+            match renames.get(word) {
+                Some(original) if !is_attribute => result.push_str(original),
+                _ => result.push_str(word),
+            }
+        } else if c.is_ascii_digit() {
+            let mut end = start + 1;
+            while let Some(&(idx, next)) = chars.peek() {
+                if !is_identifier_continue(next) {
+                    break;
+                }
+                end = idx + next.len_utf8();
+                chars.next();
+            }
+            result.push_str(&line[start..end]);
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+/// Replace `'name'` occurrences in an exception message.
+fn replace_quoted_identifiers(line: &str, renames: &BTreeMap<String, String>) -> String {
+    let mut result = line.to_string();
+    for (renamed, original) in renames {
+        result = result.replace(&format!("'{renamed}'"), &format!("'{original}'"));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minify::Minifier;
+
+    fn sample_map() -> (SourceMap, String) {
+        let source =
+            "def compute(value, factor):\n    total = value * factor\n    return total + missing\n";
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let rewritten = Minifier::rewrite_with_plan("sample", source, &plan).unwrap();
+        let entry = FileSourceMap::from_plan("pkg/sample.py", &plan, &rewritten);
+        (SourceMap::new(vec![entry]), rewritten)
+    }
+
+    #[test]
+    fn from_plan_records_reverse_mapping() {
+        let (map, rewritten) = sample_map();
+        let entry = &map.files[0];
+        assert_eq!(entry.content_hash, content_hash(&rewritten));
+        let renames = &entry.functions["compute"];
+        assert_eq!(renames.len(), 3);
+        assert!(renames.values().any(|original| original == "total"));
+    }
+
+    #[test]
+    fn from_applied_keeps_only_applied_functions() {
+        let source = "def first(value):\n    doubled = value * 2\n    return doubled\n\ndef second(value):\n    halved = value / 2\n    return halved\n";
+        let plan = Minifier::plan_from_source("sample", source).unwrap();
+        let outcome = Minifier::rewrite_with_plan_detailed("sample", source, &plan).unwrap();
+        assert_eq!(outcome.applied.len(), 2);
+        let applied = vec!["first".to_string()];
+        let entry = FileSourceMap::from_applied("sample.py", &plan, &applied, &outcome.source);
+        assert_eq!(entry.functions.keys().collect::<Vec<_>>(), ["first"]);
+        assert_eq!(entry.content_hash, content_hash(&outcome.source));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let (map, _) = sample_map();
+        let json = map.to_json().unwrap();
+        let parsed = SourceMap::from_json(&json).unwrap();
+        assert_eq!(parsed, map);
+    }
+
+    #[test]
+    fn rejects_future_version() {
+        let json = r#"{"version": 99, "files": []}"#;
+        assert!(SourceMap::from_json(json).is_err());
+    }
+
+    #[test]
+    fn unmangle_round_trips_traceback() {
+        let (map, rewritten) = sample_map();
+        let renames = &map.files[0].functions["compute"];
+        let short = |original: &str| {
+            renames
+                .iter()
+                .find(|(_, o)| o.as_str() == original)
+                .map(|(renamed, _)| renamed.clone())
+                .unwrap()
+        };
+        let return_line = rewritten
+            .lines()
+            .find(|line| line.contains("return"))
+            .unwrap()
+            .to_string();
+        assert!(return_line.contains(&short("total")));
+
+        let traceback = format!(
+            "Traceback (most recent call last):\n  File \"/srv/app/pkg/sample.py\", line 3, in compute\n{return_line}\nNameError: name '{}' is not defined\n",
+            short("total")
+        );
+        let restored = map.unmangle_traceback(&traceback);
+        assert!(
+            restored.contains("    return total + missing\n"),
+            "{restored}"
+        );
+        assert!(restored.contains("NameError: name 'total' is not defined"));
+    }
+
+    #[test]
+    fn unmangle_ignores_unmatched_frames_and_strings() {
+        let mut functions = BTreeMap::new();
+        functions.insert(
+            "Widget.render".to_string(),
+            BTreeMap::from([("a".to_string(), "template".to_string())]),
+        );
+        let map = SourceMap::new(vec![FileSourceMap {
+            path: "ui/widget.py".to_string(),
+            content_hash: content_hash(""),
+            functions,
+        }]);
+
+        let traceback = "  File \"other/widget.py\", line 1, in render\n    return a\n  File \"ui/widget.py\", line 9, in render\n    return a + self.a + 'a'\n";
+        let restored = map.unmangle_traceback(traceback);
+        assert!(restored.contains("other/widget.py\", line 1, in render\n    return a\n"));
+        assert!(restored.contains("    return template + self.a + 'a'\n"));
+    }
+}
@@ -0,0 +1,127 @@
+//! Standard library module names by Python version
+
+mod tables;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A Python `major.minor` version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct PythonVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl PythonVersion {
+    /// Oldest version with its own module table; older versions use this one
+    pub const OLDEST: Self = Self::new(3, 8);
+    /// Newest version with its own module table; newer versions use this one
+    pub const NEWEST: Self = Self::new(3, 13);
+
+    /// Version `major.minor`
+    #[must_use]
+    pub const fn new(major: u8, minor: u8) -> Self {
+        Self { major, minor }
+    }
+
+    /// Parse `3.11`, `3.11.4`, or an interpreter or `lib/` directory name such as
+    /// `python3.11`
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let version = text.strip_prefix("python").unwrap_or(text);
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some(Self::new(major, minor))
+    }
+}
+
+/// The newest supported version, used when a venv does not reveal its own
+impl Default for PythonVersion {
+    fn default() -> Self {
+        Self::NEWEST
+    }
+}
+
+impl fmt::Display for PythonVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Sorted top-level standard library modules of `version`, using the nearest supported
+/// version outside 3.8–3.13
+#[must_use]
+pub fn modules(version: PythonVersion) -> &'static [&'static str] {
+    match version
+        .clamp(PythonVersion::OLDEST, PythonVersion::NEWEST)
+        .minor
+    {
+        8 => tables::PY3_8,
+        9 => tables::PY3_9,
+        10 => tables::PY3_10,
+        11 => tables::PY3_11,
+        12 => tables::PY3_12,
+        _ => tables::PY3_13,
+    }
+}
+
+/// Whether the top-level package of `name` ships with the standard library of
+/// `python_version`
+#[must_use]
+pub fn is_stdlib(name: &str, python_version: PythonVersion) -> bool {
+    let top_level = name.split('.').next().unwrap_or(name);
+    modules(python_version).binary_search(&top_level).is_ok()
+}
+
+/// Whether the top-level package of `name` ships with the standard library of any
+/// supported version, including modules removed in later releases
+#[must_use]
+pub fn is_stdlib_in_any_version(name: &str) -> bool {
+    (PythonVersion::OLDEST.minor..=PythonVersion::NEWEST.minor)
+        .any(|minor| is_stdlib(name, PythonVersion::new(3, minor)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tomllib_is_stdlib_from_3_11() {
+        assert!(!is_stdlib("tomllib", PythonVersion::new(3, 10)));
+        assert!(is_stdlib("tomllib", PythonVersion::new(3, 11)));
+        assert!(is_stdlib("tomllib", PythonVersion::new(3, 13)));
+        assert!(is_stdlib("distutils.core", PythonVersion::new(3, 11)));
+        assert!(!is_stdlib("distutils", PythonVersion::new(3, 12)));
+    }
+
+    #[test]
+    fn numpy_is_never_stdlib() {
+        for minor in 6..=15 {
+            assert!(!is_stdlib("numpy", PythonVersion::new(3, minor)));
+        }
+        assert!(!is_stdlib_in_any_version("numpy.linalg"));
+        assert!(is_stdlib_in_any_version("asyncore"));
+    }
+
+    #[test]
+    fn versions_parse_from_venv_names_and_clamp_to_the_tables() {
+        assert_eq!(
+            PythonVersion::parse("python3.11"),
+            Some(PythonVersion::new(3, 11))
+        );
+        assert_eq!(
+            PythonVersion::parse("3.9.18"),
+            Some(PythonVersion::new(3, 9))
+        );
+        assert_eq!(PythonVersion::parse("3"), None);
+        assert_eq!(PythonVersion::parse("pypy"), None);
+        assert_eq!(modules(PythonVersion::new(3, 7)), tables::PY3_8);
+        assert_eq!(modules(PythonVersion::new(3, 14)), tables::PY3_13);
+        for minor in 8..=13 {
+            let names = modules(PythonVersion::new(3, minor));
+            assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+}
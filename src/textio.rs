@@ -0,0 +1,298 @@
+//! Decoding Python source bytes and writing rewritten text back the same way
+
+use crate::error::{Result, TsrsError};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use std::borrow::Cow;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Line terminator of a decoded source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+/// How a source was stored, so [`encode_python`] can write rewritten text back alike
+#[derive(Clone, Copy, Debug)]
+pub struct TextMetadata {
+    /// Encoding from a BOM, coding cookie, or override; `None` means UTF-8 by default
+    pub encoding: Option<&'static Encoding>,
+    pub line_ending: LineEnding,
+    pub had_trailing_newline: bool,
+    pub had_bom: bool,
+    /// Undecodable bytes were replaced with U+FFFD; the text is then written back as
+    /// UTF-8.
+    pub lossy: bool,
+}
+
+/// Encoding declared by a byte order mark or, failing that, a PEP 263 coding cookie
+#[must_use]
+pub fn detect_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    if bytes.starts_with(b"\xEF\xBB\xBF") {
+        Some(UTF_8)
+    } else if bytes.starts_with(&[0xFF, 0xFE]) {
+        Some(UTF_16LE)
+    } else if bytes.starts_with(&[0xFE, 0xFF]) {
+        Some(UTF_16BE)
+    } else {
+        detect_pep263_encoding(bytes)
+    }
+}
+
+/// Decode Python source, detecting its encoding with [`detect_encoding`] unless `forced`
+/// is given, and normalize CRLF line endings to `\n`
+///
+/// With `lossy`, undecodable bytes become U+FFFD and the text is marked to be written back
+/// as UTF-8 with its coding cookie updated to match. `label` names the source in errors.
+///
+/// # Errors
+///
+/// Returns [`TsrsError::Encoding`] if the bytes are not valid in the encoding and `lossy`
+/// is off.
+pub fn decode_python_bytes(
+    bytes: &[u8],
+    label: &str,
+    forced: Option<&'static Encoding>,
+    lossy: bool,
+) -> Result<(String, TextMetadata)> {
+    let encoding = forced.or_else(|| detect_encoding(bytes));
+
+    let effective = encoding.unwrap_or(UTF_8);
+    let (decoded, had_errors) = effective.decode_without_bom_handling(bytes);
+    if had_errors && !lossy {
+        return Err(TsrsError::Encoding {
+            path: PathBuf::from(label),
+            encoding: effective.name(),
+        });
+    }
+
+    let had_bom = match encoding {
+        Some(enc) if enc == UTF_8 && bytes.starts_with(b"\xEF\xBB\xBF") => true,
+        Some(enc) if enc == UTF_16LE && bytes.starts_with(&[0xFF, 0xFE]) => true,
+        Some(enc) if enc == UTF_16BE && bytes.starts_with(&[0xFE, 0xFF]) => true,
+        _ => false,
+    };
+
+    // The BOM is recorded in the metadata and written back by `encode_python`.
+    let mut content = if had_bom {
+        decoded
+            .strip_prefix('\u{feff}')
+            .unwrap_or(&decoded)
+            .to_string()
+    } else {
+        decoded.into_owned()
+    };
+    if had_errors && detect_pep263_encoding(bytes).is_some_and(|declared| declared != UTF_8) {
+        content = rewrite_coding_cookie(&content, "utf-8");
+    }
+
+    let mut has_crlf = false;
+    let mut has_plain_lf = false;
+    let bytes_view = content.as_bytes();
+    let mut i = 0;
+    while i < bytes_view.len() {
+        if bytes_view[i] == b'\r' {
+            if i + 1 < bytes_view.len() && bytes_view[i + 1] == b'\n' {
+                has_crlf = true;
+                i += 1;
+            } else {
+                has_plain_lf = true;
+            }
+        } else if bytes_view[i] == b'\n' && (i == 0 || bytes_view[i - 1] != b'\r') {
+            has_plain_lf = true;
+        }
+        i += 1;
+    }
+
+    let line_ending = if has_crlf && !has_plain_lf {
+        LineEnding::Crlf
+    } else {
+        LineEnding::Lf
+    };
+
+    if matches!(line_ending, LineEnding::Crlf) {
+        content = content.replace("\r\n", "\n");
+    }
+
+    let had_trailing_newline = content.ends_with('\n');
+
+    // Replacement characters only round-trip through UTF-8.
+    let metadata = TextMetadata {
+        encoding: if had_errors { Some(UTF_8) } else { encoding },
+        line_ending,
+        had_trailing_newline,
+        had_bom: had_bom && !had_errors,
+        lossy: had_errors,
+    };
+
+    Ok((content, metadata))
+}
+
+/// Encode `content` with the encoding, BOM, line endings, and trailing newline recorded
+/// in `metadata`
+///
+/// # Errors
+///
+/// Returns [`TsrsError::Encode`] if `content` has characters the encoding cannot
+/// represent.
+pub fn encode_python(content: &str, metadata: &TextMetadata, label: &str) -> Result<Vec<u8>> {
+    let mut adjusted = content.replace("\r\n", "\n");
+    if matches!(metadata.line_ending, LineEnding::Crlf) {
+        adjusted = adjusted.replace("\n", "\r\n");
+    }
+
+    let newline = match metadata.line_ending {
+        LineEnding::Lf => "\n",
+        LineEnding::Crlf => "\r\n",
+    };
+
+    if metadata.had_trailing_newline {
+        if !adjusted.ends_with(newline) {
+            while adjusted.ends_with('\n') || adjusted.ends_with('\r') {
+                adjusted.pop();
+            }
+            adjusted.push_str(newline);
+        }
+    } else if matches!(metadata.line_ending, LineEnding::Crlf) {
+        if adjusted.ends_with("\r\n") {
+            adjusted.truncate(adjusted.len() - 2);
+        } else if adjusted.ends_with('\n') {
+            adjusted.pop();
+        }
+    } else {
+        while adjusted.ends_with('\n') || adjusted.ends_with('\r') {
+            adjusted.pop();
+        }
+    }
+
+    let encoder = metadata.encoding.unwrap_or(UTF_8);
+    let mut output: Vec<u8> = Vec::new();
+    if std::ptr::eq(encoder, UTF_16LE) || std::ptr::eq(encoder, UTF_16BE) {
+        if metadata.had_bom {
+            if std::ptr::eq(encoder, UTF_16LE) {
+                output.extend_from_slice(&[0xFF, 0xFE]);
+            } else {
+                output.extend_from_slice(&[0xFE, 0xFF]);
+            }
+        }
+        for unit in adjusted.encode_utf16() {
+            let bytes = if std::ptr::eq(encoder, UTF_16LE) {
+                unit.to_le_bytes()
+            } else {
+                unit.to_be_bytes()
+            };
+            output.extend_from_slice(&bytes);
+        }
+        return Ok(output);
+    }
+
+    let (encoded, output_encoding, had_errors) = encoder.encode(&adjusted);
+    if had_errors || !std::ptr::eq(output_encoding, encoder) {
+        return Err(TsrsError::Encode {
+            path: PathBuf::from(label),
+            encoding: encoder.name(),
+        });
+    }
+
+    if metadata.had_bom && std::ptr::eq(encoder, UTF_8) {
+        output.extend_from_slice(b"\xEF\xBB\xBF");
+    }
+    match encoded {
+        Cow::Borrowed(bytes) => output.extend_from_slice(bytes),
+        Cow::Owned(buffer) => output.extend_from_slice(&buffer),
+    }
+    Ok(output)
+}
+
+fn detect_pep263_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    fn extract(line: &str) -> Option<&'static Encoding> {
+        let trimmed = &line[coding_label_span(line)?];
+        if let Some(enc) = Encoding::for_label(trimmed.as_bytes()) {
+            return Some(enc);
+        }
+        let fallback: String = trimmed.chars().filter(|c| *c != '-' && *c != '_').collect();
+        if !fallback.is_empty() {
+            if let Some(enc) = Encoding::for_label(fallback.as_bytes()) {
+                return Some(enc);
+            }
+        }
+        None
+    }
+
+    let mut lines = bytes.split(|&b| b == b'\n');
+    for _ in 0..2 {
+        if let Some(line_bytes) = lines.next() {
+            if let Ok(line_str) = std::str::from_utf8(line_bytes) {
+                if let Some(enc) = extract(line_str) {
+                    return Some(enc);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Byte range of the encoding label in a PEP 263 coding comment such as
+/// `# -*- coding: latin-1 -*-`.
+fn coding_label_span(line: &str) -> Option<Range<usize>> {
+    if !line.trim_start().starts_with('#') {
+        return None;
+    }
+    let idx = line.to_ascii_lowercase().find("coding")?;
+    let rest = line[idx + "coding".len()..].trim_start_matches([' ', '\t', ':', '=', '-', '*']);
+    let start = line.len() - rest.len();
+    let len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(rest.len());
+    (len > 0).then(|| start..start + len)
+}
+
+/// Point a coding cookie in the first two lines at `label`, so text re-encoded in another
+/// encoding still declares the one it is written in.
+fn rewrite_coding_cookie(content: &str, label: &str) -> String {
+    let mut offset = 0;
+    for line in content.split_inclusive('\n').take(2) {
+        if let Some(span) = coding_label_span(line) {
+            return format!(
+                "{}{}{}",
+                &content[..offset + span.start],
+                label,
+                &content[offset + span.end..]
+            );
+        }
+        offset += line.len();
+    }
+    content.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crlf_bom_and_missing_trailing_newline_round_trip() {
+        let bytes = b"\xEF\xBB\xBFx = 1\r\ny = 2";
+        let (text, metadata) = decode_python_bytes(bytes, "mod.py", None, false).unwrap();
+        assert_eq!(text, "x = 1\ny = 2");
+        assert_eq!(metadata.line_ending, LineEnding::Crlf);
+        assert!(metadata.had_bom);
+        assert!(!metadata.had_trailing_newline);
+        assert_eq!(encode_python(&text, &metadata, "mod.py").unwrap(), bytes);
+    }
+
+    #[test]
+    fn undecodable_bytes_fail_unless_lossy() {
+        let bytes = b"# coding: utf-8\nmsg = '\xe9'\n";
+        match decode_python_bytes(bytes, "legacy.py", None, false) {
+            Err(TsrsError::Encoding { path, encoding }) => {
+                assert_eq!(path, PathBuf::from("legacy.py"));
+                assert_eq!(encoding, "UTF-8");
+            }
+            other => panic!("expected Encoding error, got {other:?}"),
+        }
+        let (text, metadata) = decode_python_bytes(bytes, "legacy.py", None, true).unwrap();
+        assert_eq!(text, "# coding: utf-8\nmsg = '\u{fffd}'\n");
+        assert!(metadata.lossy);
+    }
+}
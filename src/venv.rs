@@ -1,8 +1,12 @@
 //! Virtual environment analysis
 
-use crate::error::{Result, TsrsError};
+use crate::error::{IoResultExt, Result, TsrsError};
+use crate::stdlib::PythonVersion;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 /// Information about a Python virtual environment
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,32 +15,304 @@ pub struct VenvInfo {
     pub path: PathBuf,
     /// Python version (if detectable)
     pub python_version: Option<String>,
+    /// Kind of environment found at `path`
+    #[serde(default)]
+    pub layout: EnvironmentKind,
+    /// Site-packages directories the packages were read from, in lookup order
+    #[serde(default)]
+    pub site_packages: Vec<PathBuf>,
     /// List of installed packages
     pub packages: Vec<PackageInfo>,
+    /// Top-level import name -> distribution installing it, from each dist-info's
+    /// `top_level.txt` and `RECORD` (`PIL` -> `Pillow`, `yaml` -> `PyYAML`); names more
+    /// than one distribution installs, such as namespace packages, are left out
+    #[serde(default)]
+    pub import_map: HashMap<String, String>,
+}
+
+/// Kind of Python environment an analyzed path is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnvironmentKind {
+    /// A `venv` or virtualenv tree with `lib/pythonX.Y/site-packages`
+    #[default]
+    Venv,
+    /// A conda environment root, recognized by its `conda-meta/` directory
+    Conda,
+    /// A bare `site-packages` or `dist-packages` directory, such as one extracted from a
+    /// container layer
+    SitePackages,
+}
+
+/// Where an environment keeps its packages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EnvironmentLayout {
+    Venv {
+        root: PathBuf,
+        site_packages: PathBuf,
+    },
+    /// Conda can have more than one site-packages, e.g. `lib/python3.11/site-packages`
+    /// and the Windows `Lib/site-packages`
+    Conda {
+        root: PathBuf,
+        site_packages: Vec<PathBuf>,
+    },
+    SitePackages(PathBuf),
+}
+
+impl EnvironmentLayout {
+    /// Recognize a conda env root, a site-packages directory, or a classic venv, in
+    /// that order
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `path` is none of them.
+    pub(crate) fn detect(path: &Path) -> Result<Self> {
+        if path.join("conda-meta").is_dir() {
+            let site_packages = lib_site_packages(path)?;
+            if site_packages.is_empty() {
+                return Err(TsrsError::InvalidVenvPath(format!(
+                    "No site-packages directory found in conda env {}",
+                    path.display()
+                )));
+            }
+            return Ok(EnvironmentLayout::Conda {
+                root: path.to_path_buf(),
+                site_packages,
+            });
+        }
+        if is_site_packages_dir(path) {
+            return Ok(EnvironmentLayout::SitePackages(path.to_path_buf()));
+        }
+        match lib_site_packages(path)?.into_iter().next() {
+            Some(site_packages) => Ok(EnvironmentLayout::Venv {
+                root: path.to_path_buf(),
+                site_packages,
+            }),
+            None => Err(TsrsError::InvalidVenvPath(format!(
+                "Could not find site-packages directory in {} (expected a venv, a conda env, \
+                 or a site-packages directory)",
+                path.display()
+            ))),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> EnvironmentKind {
+        match self {
+            EnvironmentLayout::Venv { .. } => EnvironmentKind::Venv,
+            EnvironmentLayout::Conda { .. } => EnvironmentKind::Conda,
+            EnvironmentLayout::SitePackages(_) => EnvironmentKind::SitePackages,
+        }
+    }
+
+    /// Directory holding the interpreter, scripts, and metadata; `None` for a bare
+    /// site-packages directory
+    pub(crate) fn root(&self) -> Option<&Path> {
+        match self {
+            EnvironmentLayout::Venv { root, .. } | EnvironmentLayout::Conda { root, .. } => {
+                Some(root)
+            }
+            EnvironmentLayout::SitePackages(_) => None,
+        }
+    }
+
+    /// Site-packages directories in lookup order
+    pub(crate) fn site_packages(&self) -> &[PathBuf] {
+        match self {
+            EnvironmentLayout::Venv { site_packages, .. }
+            | EnvironmentLayout::SitePackages(site_packages) => std::slice::from_ref(site_packages),
+            EnvironmentLayout::Conda { site_packages, .. } => site_packages,
+        }
+    }
+
+    /// Interpreter name such as `python3.11`: from the `lib/pythonX.Y` directory (or a
+    /// venv's `pyvenv.cfg`), the `python` record in a conda env's `conda-meta/`, or the
+    /// directory a bare site-packages sits in
+    pub(crate) fn python_version(&self) -> Option<String> {
+        let from_lib = self.site_packages().iter().find_map(|site_packages| {
+            let name = site_packages.parent()?.file_name()?.to_string_lossy();
+            name.starts_with("python").then(|| name.to_string())
+        });
+        match self {
+            EnvironmentLayout::Venv { root, .. } => {
+                from_lib.or_else(|| pyvenv_cfg_version(&root.join("pyvenv.cfg")))
+            }
+            EnvironmentLayout::Conda { root, .. } => {
+                conda_python_version(&root.join("conda-meta")).or(from_lib)
+            }
+            EnvironmentLayout::SitePackages(_) => {
+                from_lib.filter(|name| PythonVersion::parse(name).is_some())
+            }
+        }
+    }
 }
 
 /// Information about an installed package
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct PackageInfo {
     /// Package name
     pub name: String,
     /// Package version
     pub version: Option<String>,
-    /// Path to the package
+    /// Path to the package; for an editable install, the `.pth` file (or dist-info
+    /// directory) that points at its source tree
     pub path: PathBuf,
+    /// Installed in editable (development) mode, so the code lives outside site-packages
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub editable: bool,
+    /// Where an editable install's code lives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub editable_target: Option<PathBuf>,
+    /// Distributions that install files under this namespace package, when more than one does
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespace_distributions: Vec<String>,
+    /// Other site-packages entries needed alongside `path`: an editable install's finder
+    /// module, or the dist-info directories and `-nspkg.pth` files of namespace contributors
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub support_paths: Vec<PathBuf>,
+    /// License and project details from a dist-info directory's `METADATA`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<PackageMetadata>,
+}
+
+/// License and project details from a dist-info `METADATA` file
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct PackageMetadata {
+    /// SPDX expression from `License-Expression` (PEP 639), e.g. `MIT OR Apache-2.0`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_expression: Option<String>,
+    /// First line of the free-form `License` field, which older tools fill with the whole
+    /// license text; `UNKNOWN` is left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    /// `License ::` trove classifiers without that prefix, e.g. `OSI Approved :: MIT License`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub license_classifiers: Vec<String>,
+    /// `Author`, or `Author-email` when there is no `Author`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home_page: Option<String>,
+    /// `Project-URL` entries as written, e.g. `Source, https://github.com/psf/requests`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub project_urls: Vec<String>,
+}
+
+impl PackageMetadata {
+    /// Read the `METADATA` file of a dist-info directory, or `None` when it has none
+    #[must_use]
+    pub fn read(dist_info: &Path) -> Option<Self> {
+        let text = std::fs::read_to_string(dist_info.join("METADATA")).ok()?;
+        Some(Self::parse(&text))
+    }
+
+    /// Parse the header fields of a core metadata file; the description after the first
+    /// blank line is ignored
+    #[must_use]
+    pub fn parse(text: &str) -> Self {
+        // Field name and value, with any indented continuation lines appended
+        let mut fields: Vec<(&str, String)> = Vec::new();
+        for line in text.lines().take_while(|line| !line.is_empty()) {
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push('\n');
+                    value.push_str(line.trim_start().trim_start_matches('|'));
+                }
+            } else if let Some((name, value)) = line.split_once(':') {
+                fields.push((name.trim(), value.trim().to_string()));
+            }
+        }
+
+        let mut metadata = Self::default();
+        let mut author_email = None;
+        for (name, value) in fields {
+            let first_line = value
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .filter(|line| !line.eq_ignore_ascii_case("unknown"))
+                .map(str::to_string);
+            match name.to_ascii_lowercase().as_str() {
+                "license-expression" => metadata.license_expression = first_line,
+                "license" => metadata.license = first_line,
+                "classifier" => {
+                    if let Some(classifier) = value.strip_prefix("License ::") {
+                        metadata
+                            .license_classifiers
+                            .push(classifier.trim().to_string());
+                    }
+                }
+                "author" => metadata.author = first_line,
+                "author-email" => author_email = first_line,
+                "home-page" => metadata.home_page = first_line,
+                "project-url" => metadata.project_urls.extend(first_line),
+                _ => {}
+            }
+        }
+        metadata.author = metadata.author.or(author_email);
+        metadata
+    }
+
+    /// The license to report: the `License-Expression`, else the last part of each license
+    /// classifier (`MIT License`), else the `License` field
+    #[must_use]
+    pub fn license_name(&self) -> Option<String> {
+        if let Some(expression) = &self.license_expression {
+            return Some(expression.clone());
+        }
+        let mut names: Vec<&str> = self
+            .license_classifiers
+            .iter()
+            .filter_map(|classifier| classifier.rsplit("::").next())
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && *name != "OSI Approved")
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        if names.is_empty() {
+            self.license.clone()
+        } else {
+            Some(names.join(" OR "))
+        }
+    }
+
+    /// Whether a license field names `pattern`, an SPDX id or license name matched ignoring
+    /// case where it starts a word: `GPL-3.0` matches `GPL-3.0-or-later` but not
+    /// `LGPL-3.0-only`
+    #[must_use]
+    pub fn matches_license(&self, pattern: &str) -> bool {
+        let pattern = pattern.trim().to_lowercase();
+        !pattern.is_empty()
+            && self
+                .license_expression
+                .iter()
+                .chain(&self.license)
+                .chain(&self.license_classifiers)
+                .any(|field| {
+                    let field = field.to_lowercase();
+                    field.match_indices(&pattern).any(|(index, _)| {
+                        !field[..index]
+                            .chars()
+                            .next_back()
+                            .is_some_and(char::is_alphanumeric)
+                    })
+                })
+    }
 }
 
 /// Analyzes Python virtual environments
 pub struct VenvAnalyzer {
     venv_path: PathBuf,
+    layout: EnvironmentLayout,
+    import_map: OnceLock<HashMap<String, String>>,
 }
 
 impl VenvAnalyzer {
-    /// Create a new venv analyzer
+    /// Create an analyzer for a venv, a conda env, or a bare site-packages directory
     ///
     /// # Errors
     ///
-    /// Returns an error if the path does not exist.
+    /// Returns an error if the path does not exist or is none of those.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
         let venv_path = path.as_ref().to_path_buf();
 
@@ -48,7 +324,17 @@ impl VenvAnalyzer {
             )));
         }
 
-        Ok(VenvAnalyzer { venv_path })
+        let layout = EnvironmentLayout::detect(&venv_path)?;
+        Ok(VenvAnalyzer {
+            venv_path,
+            layout,
+            import_map: OnceLock::new(),
+        })
+    }
+
+    /// The layout detected by [`VenvAnalyzer::new`]
+    pub(crate) fn layout(&self) -> &EnvironmentLayout {
+        &self.layout
     }
 
     /// Analyze the venv and collect package information
@@ -57,53 +343,62 @@ impl VenvAnalyzer {
     ///
     /// Returns an error if the analysis fails.
     pub fn analyze(&self) -> Result<VenvInfo> {
-        let site_packages_path = self.find_site_packages()?;
-        let packages = Self::discover_packages(&site_packages_path)?;
+        let packages = self.discover_all_packages()?;
+        let import_map = import_map(&packages);
+        let _ = self.import_map.set(import_map.clone());
 
         Ok(VenvInfo {
             path: self.venv_path.clone(),
-            python_version: self.detect_python_version(),
+            python_version: self.layout.python_version(),
+            layout: self.layout.kind(),
+            site_packages: self.layout.site_packages().to_vec(),
             packages,
+            import_map,
         })
     }
 
-    /// Find the site-packages directory
-    fn find_site_packages(&self) -> Result<PathBuf> {
-        let lib_path = self.venv_path.join("lib");
-
-        if !lib_path.exists() {
-            return Err(TsrsError::InvalidVenvPath(
-                "No lib directory found in venv".to_string(),
-            ));
+    /// Distribution that installs the top-level module of `import`, such as `Pillow`
+    /// for `PIL.Image`, or `None` when no single dist-info claims it
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the venv's site-packages cannot be read.
+    pub fn distribution_for_import(&self, import: &str) -> Result<Option<String>> {
+        if self.import_map.get().is_none() {
+            let packages = self.discover_all_packages()?;
+            let _ = self.import_map.set(import_map(&packages));
         }
+        let top_level = import.split('.').next().unwrap_or(import);
+        Ok(self
+            .import_map
+            .get()
+            .and_then(|map| map.get(top_level))
+            .cloned())
+    }
 
-        // Look for pythonX.Y/site-packages
-        for entry in std::fs::read_dir(&lib_path)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                let name = path.file_name().unwrap_or_default().to_string_lossy();
-                if name.starts_with("python") {
-                    let site_packages = path.join("site-packages");
-                    if site_packages.exists() {
-                        return Ok(site_packages);
-                    }
+    /// Packages of every site-packages directory of the layout; a package in an earlier
+    /// directory shadows one of the same name in a later one
+    fn discover_all_packages(&self) -> Result<Vec<PackageInfo>> {
+        let mut packages = Vec::new();
+        let mut seen = HashSet::new();
+        for site_packages in self.layout.site_packages() {
+            for package in Self::discover_packages(site_packages)? {
+                if seen.insert(package.name.clone()) {
+                    packages.push(package);
                 }
             }
         }
-
-        Err(TsrsError::InvalidVenvPath(
-            "Could not find site-packages directory".to_string(),
-        ))
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(packages)
     }
 
     /// Discover all installed packages
     fn discover_packages(site_packages: &Path) -> Result<Vec<PackageInfo>> {
         let mut packages = Vec::new();
-        let mut seen = std::collections::HashSet::new();
+        let mut seen = HashSet::new();
 
-        for entry in std::fs::read_dir(site_packages)? {
-            let entry = entry?;
+        for entry in std::fs::read_dir(site_packages).with_path(site_packages)? {
+            let entry = entry.with_path(site_packages)?;
             let path = entry.path();
             let name = path
                 .file_name()
@@ -123,10 +418,17 @@ impl VenvAnalyzer {
                     || directory_contains_python(&path)?
                 {
                     let version = Self::extract_version(&name);
+                    let metadata = if name.ends_with(".dist-info") {
+                        PackageMetadata::read(&path)
+                    } else {
+                        None
+                    };
                     packages.push(PackageInfo {
                         name: name.clone(),
                         version,
                         path: path.clone(),
+                        metadata,
+                        ..PackageInfo::default()
                     });
                     seen.insert(name);
                 }
@@ -138,15 +440,180 @@ impl VenvAnalyzer {
                     name: name.clone(),
                     version: None,
                     path: path.clone(),
+                    ..PackageInfo::default()
                 });
                 seen.insert(name);
             }
         }
 
+        Self::mark_editable_dist_info(&mut packages);
+        Self::mark_namespace_packages(site_packages, &mut packages);
+
+        // Editable installs have no package directory; a module already installed
+        // normally shadows the editable one, as it does at import time
+        for package in Self::discover_editable_packages(site_packages)? {
+            if seen.insert(package.name.clone()) {
+                packages.push(package);
+            }
+        }
+
         packages.sort_by(|a, b| a.name.cmp(&b.name));
         Ok(packages)
     }
 
+    /// Find editable installs through the `.pth` files that put them on `sys.path`: either
+    /// a directory outside site-packages, or an import of a setuptools `__editable__` finder
+    /// whose `MAPPING` names each package's source directory
+    fn discover_editable_packages(site_packages: &Path) -> Result<Vec<PackageInfo>> {
+        let mut pth_files: Vec<PathBuf> = std::fs::read_dir(site_packages)
+            .with_path(site_packages)?
+            .filter_map(std::result::Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "pth"))
+            .collect();
+        pth_files.sort();
+
+        let finder_module = Regex::new(r"__editable___\w+_finder").expect("valid regex");
+        let mut packages = Vec::new();
+        for pth in pth_files {
+            let contents = match std::fs::read_to_string(&pth) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    tracing::warn!("Failed to read {}: {}", pth.display(), e);
+                    continue;
+                }
+            };
+            let version = editable_pth_version(&pth);
+
+            for line in contents.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                if line.starts_with("import ") || line.starts_with("import\t") {
+                    let modules: BTreeSet<&str> =
+                        finder_module.find_iter(line).map(|m| m.as_str()).collect();
+                    for module in modules {
+                        let finder = site_packages.join(format!("{module}.py"));
+                        let Ok(source) = std::fs::read_to_string(&finder) else {
+                            continue;
+                        };
+                        for (name, target) in finder_mapping(&source) {
+                            packages.push(PackageInfo {
+                                name,
+                                version: version.clone(),
+                                path: pth.clone(),
+                                editable: true,
+                                editable_target: Some(target),
+                                support_paths: vec![finder.clone()],
+                                ..PackageInfo::default()
+                            });
+                        }
+                    }
+                    continue;
+                }
+
+                let directory = site_packages.join(line);
+                if directory.starts_with(site_packages) || !directory.is_dir() {
+                    continue;
+                }
+                for (name, target) in top_level_modules(&directory)? {
+                    packages.push(PackageInfo {
+                        name,
+                        version: version.clone(),
+                        path: pth.clone(),
+                        editable: true,
+                        editable_target: Some(target),
+                        ..PackageInfo::default()
+                    });
+                }
+            }
+        }
+        Ok(packages)
+    }
+
+    /// Flag dist-info directories whose `direct_url.json` (PEP 610) records an editable
+    /// install, with the source directory it was installed from
+    fn mark_editable_dist_info(packages: &mut [PackageInfo]) {
+        for package in packages
+            .iter_mut()
+            .filter(|p| p.name.ends_with(".dist-info"))
+        {
+            let Ok(contents) = std::fs::read_to_string(package.path.join("direct_url.json")) else {
+                continue;
+            };
+            let Ok(direct_url) = serde_json::from_str::<serde_json::Value>(&contents) else {
+                continue;
+            };
+            if direct_url["dir_info"]["editable"].as_bool() != Some(true) {
+                continue;
+            }
+            package.editable = true;
+            package.editable_target = direct_url["url"]
+                .as_str()
+                .and_then(|url| url.strip_prefix("file://"))
+                .map(PathBuf::from);
+        }
+    }
+
+    /// Flag top-level packages that more than one distribution's `RECORD` installs files
+    /// under (e.g. `google` from `google-cloud-storage` and `google-api-core`), recording
+    /// every contributor so a slim venv keeps all of their metadata
+    fn mark_namespace_packages(site_packages: &Path, packages: &mut [PackageInfo]) {
+        // top-level name -> (distribution, dist-info directory, -nspkg.pth files)
+        let mut contributors: BTreeMap<String, Vec<(String, PathBuf, Vec<PathBuf>)>> =
+            BTreeMap::new();
+        for package in packages.iter().filter(|p| p.name.ends_with(".dist-info")) {
+            let Ok(record) = std::fs::read_to_string(package.path.join("RECORD")) else {
+                continue;
+            };
+            let distribution = distribution_name(&package.name).to_string();
+
+            let mut top_levels = BTreeSet::new();
+            let mut nspkg_pth = Vec::new();
+            for line in record.lines() {
+                let file = line.split(',').next().unwrap_or_default().trim_matches('"');
+                match file.split_once('/') {
+                    Some((top, _))
+                        if top != ".." && top != "__pycache__" && !top.ends_with(".dist-info") =>
+                    {
+                        top_levels.insert(top.to_string());
+                    }
+                    Some(_) => {}
+                    None if file.ends_with("-nspkg.pth") => {
+                        nspkg_pth.push(site_packages.join(file));
+                    }
+                    None => {}
+                }
+            }
+            for top in top_levels {
+                contributors.entry(top).or_default().push((
+                    distribution.clone(),
+                    package.path.clone(),
+                    nspkg_pth.clone(),
+                ));
+            }
+        }
+
+        for package in packages.iter_mut() {
+            let Some(dists) = contributors.get(&package.name) else {
+                continue;
+            };
+            if dists.len() < 2 {
+                continue;
+            }
+            package.namespace_distributions =
+                dists.iter().map(|(name, _, _)| name.clone()).collect();
+            for (_, dist_info, nspkg_pth) in dists {
+                package.support_paths.push(dist_info.clone());
+                package
+                    .support_paths
+                    .extend(nspkg_pth.iter().filter(|pth| pth.is_file()).cloned());
+            }
+            package.namespace_distributions.sort();
+            package.support_paths.sort();
+        }
+    }
+
     /// Extract version from dist-info directory name
     fn extract_version(name: &str) -> Option<String> {
         if name.ends_with(".dist-info") {
@@ -157,25 +624,237 @@ impl VenvAnalyzer {
         }
         None
     }
+}
+
+/// `lib/pythonX.Y/site-packages` directories under `root`, sorted, followed by the
+/// Windows `Lib/site-packages`
+fn lib_site_packages(root: &Path) -> Result<Vec<PathBuf>> {
+    let lib_path = root.join("lib");
+    let mut found = Vec::new();
+    if lib_path.exists() {
+        for entry in std::fs::read_dir(&lib_path).with_path(&lib_path)? {
+            let entry = entry.with_path(&lib_path)?;
+            let site_packages = entry.path().join("site-packages");
+            if entry.file_name().to_string_lossy().starts_with("python") && site_packages.is_dir() {
+                found.push(site_packages);
+            }
+        }
+    }
+    found.sort();
+    let windows = root.join("Lib").join("site-packages");
+    if windows.is_dir() && !found.contains(&windows) {
+        found.push(windows);
+    }
+    Ok(found)
+}
+
+/// Whether `path` is itself a site-packages directory: named `site-packages` or
+/// `dist-packages`, or holding `.dist-info` directories without a `lib/` of its own
+fn is_site_packages_dir(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if name == "site-packages" || name == "dist-packages" {
+        return true;
+    }
+    if path.join("lib").is_dir() || path.join("Lib").is_dir() {
+        return false;
+    }
+    std::fs::read_dir(path).is_ok_and(|entries| {
+        entries
+            .filter_map(std::result::Result::ok)
+            .any(|entry| entry.file_name().to_string_lossy().ends_with(".dist-info"))
+    })
+}
+
+/// `pythonX.Y` from the `version` (or `version_info`) line of a venv's `pyvenv.cfg`
+fn pyvenv_cfg_version(cfg: &Path) -> Option<String> {
+    let contents = std::fs::read_to_string(cfg).ok()?;
+    contents.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if !matches!(key.trim(), "version" | "version_info") {
+            return None;
+        }
+        let version = PythonVersion::parse(value)?;
+        Some(format!("python{version}"))
+    })
+}
+
+/// `pythonX.Y` from the `python-X.Y.Z-<build>.json` record in a conda env's `conda-meta/`
+fn conda_python_version(conda_meta: &Path) -> Option<String> {
+    std::fs::read_dir(conda_meta)
+        .ok()?
+        .filter_map(std::result::Result::ok)
+        .find_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let version = name.strip_prefix("python-")?.split('-').next()?;
+            let version = PythonVersion::parse(version)?;
+            Some(format!("python{version}"))
+        })
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Version from a setuptools `__editable__.<name>-<version>.pth` file name
+fn editable_pth_version(pth: &Path) -> Option<String> {
+    let stem = pth.file_stem()?.to_string_lossy();
+    let (_, version) = stem.strip_prefix("__editable__.")?.rsplit_once('-')?;
+    Some(version.to_string())
+}
 
-    /// Try to detect the Python version from the venv
-    fn detect_python_version(&self) -> Option<String> {
-        let lib_path = self.venv_path.join("lib");
-        if let Ok(entries) = std::fs::read_dir(&lib_path) {
-            for entry in entries.flatten() {
-                let name = entry.file_name().to_string_lossy().to_string();
-                if name.starts_with("python") {
-                    return Some(name);
+/// Package name to source directory pairs from a setuptools editable finder's `MAPPING`
+fn finder_mapping(source: &str) -> Vec<(String, PathBuf)> {
+    let mapping = Regex::new(r"(?m)^MAPPING\b[^=]*=\s*\{([^}]*)\}").expect("valid regex");
+    let entry = Regex::new(r#"['"]([^'"]+)['"]\s*:\s*['"]([^'"]+)['"]"#).expect("valid regex");
+    let Some(body) = mapping.captures(source).and_then(|c| c.get(1)) else {
+        return Vec::new();
+    };
+    entry
+        .captures_iter(body.as_str())
+        .map(|c| (c[1].to_string(), PathBuf::from(c[2].replace("\\\\", "\\"))))
+        .collect()
+}
+
+/// Distribution name of a `<name>-<version>.dist-info` directory
+fn distribution_name(dist_info: &str) -> &str {
+    dist_info
+        .trim_end_matches(".dist-info")
+        .rsplit_once('-')
+        .map_or(dist_info, |(name, _)| name)
+}
+
+/// Top-level import name -> distribution, for names exactly one dist-info installs
+fn import_map(packages: &[PackageInfo]) -> HashMap<String, String> {
+    let mut providers: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+    for package in packages.iter().filter(|p| p.name.ends_with(".dist-info")) {
+        let distribution = distribution_name(&package.name);
+        for name in installed_top_levels(&package.path) {
+            providers.entry(name).or_default().insert(distribution);
+        }
+    }
+    providers
+        .into_iter()
+        .filter(|(_, distributions)| distributions.len() == 1)
+        .filter_map(|(name, distributions)| {
+            let distribution = distributions.into_iter().next()?;
+            Some((name, distribution.to_string()))
+        })
+        .collect()
+}
+
+/// Top-level modules a dist-info directory says its distribution installs: the lines
+/// of `top_level.txt` plus the top-level packages and modules listed in `RECORD`
+fn installed_top_levels(dist_info: &Path) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    if let Ok(top_level) = std::fs::read_to_string(dist_info.join("top_level.txt")) {
+        for line in top_level.lines() {
+            let name = line.trim().split('/').next().unwrap_or_default();
+            if is_import_name(name) {
+                names.insert(name.to_string());
+            }
+        }
+    }
+    if let Ok(record) = std::fs::read_to_string(dist_info.join("RECORD")) {
+        for line in record.lines() {
+            let file = line.split(',').next().unwrap_or_default().trim_matches('"');
+            let name = match file.split_once('/') {
+                Some((top, _)) => top,
+                None if [".py", ".so", ".pyd"].iter().any(|ext| file.ends_with(ext)) => {
+                    file.split('.').next().unwrap_or_default()
                 }
+                None => continue,
+            };
+            if is_import_name(name) && name != "__pycache__" {
+                names.insert(name.to_string());
             }
         }
-        None
     }
+    names
+}
+
+/// Whether `name` is a valid Python identifier, so it can be imported
+fn is_import_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first == '_' || first.is_alphabetic())
+        && chars.all(|c| c == '_' || c.is_alphanumeric())
+}
+
+/// Lowercase a distribution or import name and fold runs of `-`, `_` and `.` into `_`,
+/// so `Typing-Extensions`, `typing.extensions` and `typing_extensions` compare equal
+pub(crate) fn normalize_package_name(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !normalized.ends_with('_') {
+                normalized.push('_');
+            }
+        } else {
+            normalized.extend(c.to_lowercase());
+        }
+    }
+    normalized
+}
+
+/// Normalized names of the distributions a dist-info directory's `METADATA` requires,
+/// leaving out requirements that only apply to an extra
+pub(crate) fn requires_dist(dist_info: &Path) -> Vec<String> {
+    let Ok(metadata) = std::fs::read_to_string(dist_info.join("METADATA")) else {
+        return Vec::new();
+    };
+    let mut requires: Vec<String> = metadata
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.strip_prefix("Requires-Dist:"))
+        .filter(|requirement| {
+            !requirement
+                .split_once(';')
+                .is_some_and(|(_, marker)| marker.contains("extra"))
+        })
+        .filter_map(|requirement| {
+            let name: String = requirement
+                .trim()
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                .collect();
+            (!name.is_empty()).then(|| normalize_package_name(&name))
+        })
+        .collect();
+    requires.sort();
+    requires.dedup();
+    requires
+}
+
+/// Importable top-level packages and modules in a directory added to `sys.path`
+fn top_level_modules(directory: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut modules = Vec::new();
+    for entry in std::fs::read_dir(directory).with_path(directory)? {
+        let path = entry.with_path(directory)?.path();
+        let Some(name) = path.file_name().map(|n| n.to_string_lossy().to_string()) else {
+            continue;
+        };
+        if name.starts_with('.') || name.starts_with('_') {
+            continue;
+        }
+        if path.is_dir() {
+            if name.chars().all(|c| c.is_alphanumeric() || c == '_')
+                && directory_contains_python(&path)?
+            {
+                modules.push((name, path));
+            }
+        } else if let Some(stem) = name.strip_suffix(".py") {
+            modules.push((stem.to_string(), path));
+        }
+    }
+    modules.sort();
+    Ok(modules)
 }
 
 fn directory_contains_python(path: &Path) -> Result<bool> {
-    for entry in std::fs::read_dir(path)? {
-        let entry = entry?;
+    for entry in std::fs::read_dir(path).with_path(path)? {
+        let entry = entry.with_path(path)?;
         let child_path = entry.path();
         if (child_path.is_file() && child_path
             .extension()
@@ -0,0 +1,3 @@
+async def fetch(a, b):
+    c = await get(a, b)
+    return c
@@ -0,0 +1,3 @@
+async def fetch(url, retries):
+    payload = await get(url, retries)
+    return payload
@@ -0,0 +1,4 @@
+# self-test: bail lambda
+def handler(event):
+    callback = lambda: event
+    return callback
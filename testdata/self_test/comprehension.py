@@ -0,0 +1,3 @@
+# self-test: bail comprehension
+def collect(items):
+    return [item for item in items]
@@ -0,0 +1,13 @@
+import sys
+
+if sys.platform == "win32":
+
+    def home(user):
+        base = "C:/Users/"
+        return base + user
+
+else:
+
+    def home(user):
+        base = "/home/"
+        return base + user
@@ -0,0 +1,7 @@
+import functools
+
+
+@functools.lru_cache(maxsize=128)
+def scaled(value, factor):
+    product = value * factor
+    return product
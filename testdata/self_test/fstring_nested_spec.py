@@ -0,0 +1,2 @@
+def render(value, width):
+    return f"{value:>{width}}"
@@ -0,0 +1,14 @@
+counter = 0
+
+
+def outer(a):
+    global counter
+    total = a + counter
+    counter = total
+
+    def inner():
+        nonlocal total
+        total = total + 1
+        return total
+
+    return inner()
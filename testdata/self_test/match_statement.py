@@ -0,0 +1,7 @@
+# self-test: bail match_statement
+def describe(command):
+    match command:
+        case ["go", direction]:
+            return direction
+        case _:
+            return "unknown"
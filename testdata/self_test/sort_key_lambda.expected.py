@@ -0,0 +1,3 @@
+def by_name(a):
+    b = sorted(a, key=lambda item: item.name)
+    return b
@@ -0,0 +1,3 @@
+def by_name(records):
+    ordered = sorted(records, key=lambda item: item.name)
+    return ordered
@@ -0,0 +1,3 @@
+def greet(a):
+    b = "bonjour " + a
+    return b
@@ -0,0 +1,3 @@
+def greet(prénom):
+    salutation = "bonjour " + prénom
+    return salutation
@@ -0,0 +1,4 @@
+def first_long(words, limit):
+    if (size := len(words)) > limit:
+        return size
+    return limit
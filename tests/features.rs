@@ -0,0 +1,52 @@
+//! What each cargo feature combination exposes.
+//!
+//! Run under several combinations, e.g. `cargo test --test features --no-default-features`
+//! and `--features batch`; `scripts/check_features.py` runs them all and also checks that the
+//! lean build compiles for `wasm32-unknown-unknown`.
+
+use tsrs::{Minifier, MinifyPlan, TsrsError};
+
+const SOURCE: &str = "def total(values):\n    running = 0\n    for value in values:\n        running += value\n    return running\n";
+
+#[test]
+fn minify_plans_and_rewrites_without_any_feature() {
+    let plan = Minifier::plan_from_source("sample", SOURCE).unwrap();
+    let json = serde_json::to_string(&plan).unwrap();
+    let plan: MinifyPlan = serde_json::from_str(&json).unwrap();
+
+    let rewritten = Minifier::rewrite_with_plan("sample", SOURCE, &plan).unwrap();
+    assert!(!rewritten.contains("running"), "{rewritten}");
+    assert!(rewritten.starts_with("def total("));
+}
+
+#[test]
+fn parse_errors_surface_without_any_feature() {
+    let err = Minifier::plan_from_source("broken", "def f(:\n").unwrap_err();
+    assert!(matches!(err, TsrsError::Parse { .. }), "{err:?}");
+}
+
+#[cfg(feature = "batch")]
+#[test]
+fn batch_exposes_dead_code_analysis() {
+    let mut analyzer = tsrs::CallGraphAnalyzer::new();
+    analyzer
+        .analyze_source(
+            "pkg",
+            "def used():\n    pass\n\ndef unused():\n    pass\n\nused()\n",
+        )
+        .unwrap();
+    let dead: Vec<String> = analyzer
+        .find_dead_code()
+        .into_iter()
+        .map(|(_, name)| name)
+        .collect();
+    assert_eq!(dead, vec!["unused".to_string()]);
+}
+
+#[cfg(feature = "venv")]
+#[test]
+fn venv_exposes_analysis_and_slimming() {
+    assert!(tsrs::VenvAnalyzer::new("/nonexistent/tsrs-venv").is_err());
+    let options = tsrs::SlimOptions::default();
+    assert_eq!(options.format, tsrs::SlimFormat::Venv);
+}
@@ -62,12 +62,32 @@ struct PlanBundle {
     files: Vec<PlanFile>,
 }
 
+#[derive(Debug, Deserialize)]
+struct PlanBundleHeader {
+    version: u32,
+}
+
 #[derive(Debug, Deserialize)]
 struct PlanFile {
     path: String,
     plan: MinifyPlan,
 }
 
+/// Read a v2 (JSON Lines) plan bundle: a header line, then one file plan per line.
+fn read_plan_bundle(path: &Path) -> Result<PlanBundle> {
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines();
+    let header: PlanBundleHeader =
+        serde_json::from_str(lines.next().context("plan bundle is empty")?)?;
+    let files = lines
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<Vec<PlanFile>>>()?;
+    Ok(PlanBundle {
+        version: header.version,
+        files,
+    })
+}
+
 fn fixture_path(relative: &str) -> PathBuf {
     Path::new(env!("CARGO_MANIFEST_DIR"))
         .join("test_packages/test_minify")
@@ -195,10 +215,9 @@ fn minify_plan_dir_outputs_bundle_with_expected_files() -> Result<()> {
         String::from_utf8_lossy(&output.stderr)
     );
 
-    let bundle_contents = fs::read_to_string(&plan_path)?;
-    let bundle: PlanBundle = serde_json::from_str(&bundle_contents)?;
+    let bundle = read_plan_bundle(&plan_path)?;
 
-    assert_eq!(bundle.version, 1);
+    assert_eq!(bundle.version, 2);
 
     let file_set: HashSet<&str> = bundle.files.iter().map(|file| file.path.as_str()).collect();
     assert!(file_set.contains("simple_module.py"));
@@ -338,7 +357,7 @@ fn minify_plan_dir_hidden_files_behavior() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let bundle: PlanBundle = serde_json::from_slice(&fs::read(&plan_path)?)?;
+    let bundle = read_plan_bundle(&plan_path)?;
     assert!(!bundle
         .files
         .iter()
@@ -359,7 +378,7 @@ fn minify_plan_dir_hidden_files_behavior() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let bundle_hidden: PlanBundle = serde_json::from_slice(&fs::read(&hidden_plan_path)?)?;
+    let bundle_hidden = read_plan_bundle(&hidden_plan_path)?;
     assert!(bundle_hidden
         .files
         .iter()
@@ -395,7 +414,7 @@ fn minify_plan_dir_respects_gitignore() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let bundle: PlanBundle = serde_json::from_slice(&fs::read(&plan_path)?)?;
+    let bundle = read_plan_bundle(&plan_path)?;
     assert!(bundle
         .files
         .iter()
@@ -416,7 +435,7 @@ fn minify_plan_dir_respects_gitignore() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let bundle_respect: PlanBundle = serde_json::from_slice(&fs::read(&respect_path)?)?;
+    let bundle_respect = read_plan_bundle(&respect_path)?;
     assert!(!bundle_respect
         .files
         .iter()
@@ -453,7 +472,7 @@ fn minify_plan_dir_follows_symlinks_when_enabled() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let default_bundle: PlanBundle = serde_json::from_slice(&fs::read(&default_plan)?)?;
+    let default_bundle = read_plan_bundle(&default_plan)?;
     assert!(
         !default_bundle
             .files
@@ -477,7 +496,7 @@ fn minify_plan_dir_follows_symlinks_when_enabled() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let follow_bundle: PlanBundle = serde_json::from_slice(&fs::read(&follow_plan)?)?;
+    let follow_bundle = read_plan_bundle(&follow_plan)?;
     assert!(
         follow_bundle
             .files
@@ -517,7 +536,7 @@ fn minify_plan_dir_max_depth_limits() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let baseline_bundle: PlanBundle = serde_json::from_slice(&fs::read(&baseline_path)?)?;
+    let baseline_bundle = read_plan_bundle(&baseline_path)?;
     assert!(baseline_bundle
         .files
         .iter()
@@ -539,7 +558,7 @@ fn minify_plan_dir_max_depth_limits() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let depth_bundle: PlanBundle = serde_json::from_slice(&fs::read(&depth_path)?)?;
+    let depth_bundle = read_plan_bundle(&depth_path)?;
     assert!(depth_bundle
         .files
         .iter()
@@ -722,7 +741,7 @@ fn minify_plan_dir_respects_include_exclude_globs() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let include_bundle: PlanBundle = serde_json::from_slice(&fs::read(&include_path)?)?;
+    let include_bundle = read_plan_bundle(&include_path)?;
     let included_paths: HashSet<&str> = include_bundle
         .files
         .iter()
@@ -746,7 +765,7 @@ fn minify_plan_dir_respects_include_exclude_globs() -> Result<()> {
         output.status,
         String::from_utf8_lossy(&output.stderr)
     );
-    let exclude_bundle: PlanBundle = serde_json::from_slice(&fs::read(&exclude_path)?)?;
+    let exclude_bundle = read_plan_bundle(&exclude_path)?;
     let excluded_paths: HashSet<&str> = exclude_bundle
         .files
         .iter()